@@ -0,0 +1,61 @@
+//! Benchmarks of the packet encoding/decoding (run by `cargo bench -p akasa-core`)
+//!
+//! Publish is the hot packet of the server, it is encoded once for every
+//! receiver (except the shared v3 QoS 0 packet) and decoded for every
+//! incoming message.
+
+use std::hint::black_box;
+
+use akasa_core::mqtt_proto::{v3, v5, QosPid, TopicName};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const PAYLOAD_SIZES: [usize; 3] = [16, 256, 4096];
+
+fn topic_name() -> TopicName {
+    TopicName::try_from("site/42/device/126/status".to_owned()).unwrap()
+}
+
+fn bench_v3_publish(c: &mut Criterion) {
+    let mut group = c.benchmark_group("v3_publish");
+    for size in PAYLOAD_SIZES {
+        let packet = v3::Packet::Publish(v3::Publish::new(
+            QosPid::Level0,
+            topic_name(),
+            vec![b'x'; size].into(),
+        ));
+        let data = packet.encode().unwrap().as_ref().to_vec();
+        assert_eq!(v3::Packet::decode(&data).unwrap(), Some(packet.clone()));
+
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode", size), &size, |b, _| {
+            b.iter(|| black_box(black_box(&packet).encode().unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("decode", size), &size, |b, _| {
+            b.iter(|| black_box(v3::Packet::decode(black_box(&data)).unwrap()))
+        });
+    }
+    group.finish();
+}
+
+fn bench_v5_publish(c: &mut Criterion) {
+    let mut group = c.benchmark_group("v5_publish");
+    for size in PAYLOAD_SIZES {
+        let mut publish = v5::Publish::new(QosPid::Level0, topic_name(), vec![b'x'; size].into());
+        publish.properties.message_expiry_interval = Some(60);
+        let packet = v5::Packet::Publish(publish);
+        let data = packet.encode().unwrap().as_ref().to_vec();
+        assert_eq!(v5::Packet::decode(&data).unwrap(), Some(packet.clone()));
+
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode", size), &size, |b, _| {
+            b.iter(|| black_box(black_box(&packet).encode().unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("decode", size), &size, |b, _| {
+            b.iter(|| black_box(v5::Packet::decode(black_box(&data)).unwrap()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_v3_publish, bench_v5_publish);
+criterion_main!(benches);
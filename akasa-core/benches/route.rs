@@ -0,0 +1,107 @@
+//! Benchmarks of the subscription matching (run by `cargo bench -p akasa-core`)
+//!
+//! The route table is a trie of topic levels, matching a topic name only
+//! visits the nodes of its levels (and the `+`/`#` nodes), the cost is
+//! compared with scanning all the topic filters.
+
+use std::hint::black_box;
+
+use akasa_core::mqtt_proto::{QoS, TopicFilter, TopicName};
+use akasa_core::{ClientId, RouteTable};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const SIZES: [usize; 3] = [10_000, 100_000, 200_000];
+
+// Every device has an exact subscription and a wildcard subscription, every
+// 100 devices share a `+` subscription of the group.
+fn topic_filters(size: usize) -> Vec<String> {
+    (0..size)
+        .map(|idx| match idx % 3 {
+            0 => format!("site/{}/device/{}/status", idx % 100, idx),
+            1 => format!("site/{}/device/{}/#", idx % 100, idx),
+            _ => format!("site/{}/device/+/alarm", idx % 100),
+        })
+        .collect()
+}
+
+fn route_table(filters: &[String]) -> RouteTable {
+    let table = RouteTable::default();
+    for (idx, filter) in filters.iter().enumerate() {
+        let topic_filter = TopicFilter::try_from(filter.clone()).unwrap();
+        table.subscribe(&topic_filter, ClientId::new(idx as u64), QoS::Level1);
+    }
+    table
+}
+
+// The baseline: match the topic name with every topic filter
+fn scan_matches<'a>(filters: &'a [String], topic_name: &str) -> Vec<&'a String> {
+    filters
+        .iter()
+        .filter(|filter| filter_matches(filter, topic_name))
+        .collect()
+}
+
+fn filter_matches(filter: &str, topic_name: &str) -> bool {
+    let mut filter_items = filter.split('/');
+    let mut topic_items = topic_name.split('/');
+    loop {
+        match (filter_items.next(), topic_items.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_item), Some(topic_item)) if filter_item == topic_item => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn bench_match(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route_match");
+    for size in SIZES {
+        let filters = topic_filters(size);
+        let table = route_table(&filters);
+        let topic_name = format!("site/{}/device/{}/status", 42, 42 * 3);
+        let topic_name = TopicName::try_from(topic_name).unwrap();
+        assert_eq!(
+            table.get_matches(&topic_name).len(),
+            scan_matches(&filters, &topic_name).len()
+        );
+
+        group.bench_with_input(BenchmarkId::new("trie", size), &size, |b, _| {
+            b.iter(|| black_box(table.get_matches(black_box(&topic_name))))
+        });
+        group.bench_with_input(BenchmarkId::new("scan", size), &size, |b, _| {
+            b.iter(|| black_box(scan_matches(&filters, black_box(&topic_name))))
+        });
+    }
+    group.finish();
+}
+
+// Many clients subscribed to the same topic filter, the match result is one
+// node with all the subscribers.
+fn bench_fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route_fan_out");
+    for size in SIZES {
+        let table = RouteTable::default();
+        let topic_filter = TopicFilter::try_from("broadcast/#".to_owned()).unwrap();
+        for idx in 0..size {
+            table.subscribe(&topic_filter, ClientId::new(idx as u64), QoS::Level0);
+        }
+        let topic_name = TopicName::try_from("broadcast/all".to_owned()).unwrap();
+
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("trie", size), &size, |b, _| {
+            b.iter(|| {
+                let mut count = 0;
+                for content in table.get_matches(black_box(&topic_name)) {
+                    count += content.read().clients.len();
+                }
+                black_box(count)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_match, bench_fan_out);
+criterion_main!(benches);
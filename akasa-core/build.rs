@@ -0,0 +1,36 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    grpc_service();
+}
+
+// Generate the server of the gRPC service, the messages are defined in
+// `server::grpc` (so protoc is not required)
+#[cfg(feature = "grpc")]
+fn grpc_service() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let service = Service::builder()
+        .name("Broker")
+        .package("akasa")
+        .method(
+            Method::builder()
+                .name("publish")
+                .route_name("Publish")
+                .input_type("super::PublishRequest")
+                .output_type("super::PublishResponse")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            Method::builder()
+                .name("subscribe")
+                .route_name("Subscribe")
+                .input_type("super::SubscribeRequest")
+                .output_type("super::Message")
+                .codec_path("tonic::codec::ProstCodec")
+                .server_streaming()
+                .build(),
+        )
+        .build();
+    Builder::new().build_client(false).compile(&[service]);
+}
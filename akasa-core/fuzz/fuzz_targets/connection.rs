@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The arbitrary bytes sent by a client, from the fixed header of CONNECT
+fuzz_target!(|data: &[u8]| {
+    akasa_core::fuzz::run_connections(&[data]);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use akasa_core_fuzz::Connection;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|connections: Vec<Connection>| {
+    let connections: Vec<_> = connections.iter().map(Connection::encode_v5).collect();
+    akasa_core::fuzz::run_connections(&connections);
+});
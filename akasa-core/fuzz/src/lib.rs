@@ -0,0 +1,230 @@
+//! The arbitrary packet sequences of the `packets_v3` and `packets_v5` targets.
+//! Every connection starts with a valid CONNECT, and the client identifiers and
+//! the topics are picked from small tables, so the sessions are resumed and the
+//! subscriptions are matched by the publishes.
+
+use std::sync::Arc;
+
+use akasa_core::mqtt_proto::{v3, v5, Pid, QoS, QosPid, TopicFilter, TopicName};
+use arbitrary::Arbitrary;
+use bytes::Bytes;
+
+const CLIENT_IDENTIFIERS: [&str; 2] = ["client-0", "client-1"];
+const TOPIC_NAMES: [&str; 5] = ["a", "a/b", "a/b/c", "b/b", "$SYS/a"];
+const TOPIC_FILTERS: [&str; 7] = ["a", "a/+", "a/#", "#", "+/b", "$SYS/#", "$share/g/a/#"];
+/// The session expiry interval of the v5.x clients when `clean_session=0`
+const SESSION_EXPIRY_SECS: u32 = 60;
+
+#[derive(Debug, Arbitrary)]
+pub struct Connection {
+    pub client: u8,
+    pub clean_session: bool,
+    pub keep_alive: u16,
+    pub packets: Vec<Packet>,
+}
+
+#[derive(Debug, Arbitrary)]
+pub enum Packet {
+    Publish {
+        qos: u8,
+        pid: u16,
+        dup: bool,
+        retain: bool,
+        topic_name: u8,
+        payload: Vec<u8>,
+    },
+    Puback(u16),
+    Pubrec(u16),
+    Pubrel(u16),
+    Pubcomp(u16),
+    Subscribe {
+        pid: u16,
+        topic_filters: Vec<(u8, u8)>,
+    },
+    Unsubscribe {
+        pid: u16,
+        topic_filters: Vec<u8>,
+    },
+    Pingreq,
+    Disconnect,
+}
+
+impl Connection {
+    /// The bytes sent by a v3.1.1 client, the packets can't be encoded (the
+    /// packet identifier is 0) are skipped.
+    pub fn encode_v3(&self) -> Vec<u8> {
+        let mut connect = v3::Connect::new(client_identifier(self.client), self.keep_alive);
+        connect.clean_session = self.clean_session;
+        let mut packets: Vec<v3::Packet> = vec![connect.into()];
+        packets.extend(self.packets.iter().filter_map(Packet::to_v3));
+        let mut data = Vec::new();
+        for packet in packets {
+            if let Ok(encoded) = packet.encode() {
+                data.extend_from_slice(encoded.as_ref());
+            }
+        }
+        data
+    }
+
+    /// The bytes sent by a v5.0 client, the packets can't be encoded (the
+    /// packet identifier is 0) are skipped.
+    pub fn encode_v5(&self) -> Vec<u8> {
+        let mut connect = v5::Connect::new(client_identifier(self.client), self.keep_alive);
+        connect.clean_start = self.clean_session;
+        if !self.clean_session {
+            connect.properties.session_expiry_interval = Some(SESSION_EXPIRY_SECS);
+        }
+        let mut packets: Vec<v5::Packet> = vec![connect.into()];
+        packets.extend(self.packets.iter().filter_map(Packet::to_v5));
+        let mut data = Vec::new();
+        for packet in packets {
+            if let Ok(encoded) = packet.encode() {
+                data.extend_from_slice(encoded.as_ref());
+            }
+        }
+        data
+    }
+}
+
+impl Packet {
+    fn to_v3(&self) -> Option<v3::Packet> {
+        let packet = match self {
+            Packet::Publish {
+                qos,
+                pid,
+                dup,
+                retain,
+                topic_name: name,
+                payload,
+            } => {
+                let mut publish = v3::Publish::new(
+                    qos_pid(*qos, *pid)?,
+                    topic_name(*name),
+                    Bytes::from(payload.clone()),
+                );
+                publish.dup = *dup;
+                publish.retain = *retain;
+                publish.into()
+            }
+            Packet::Puback(value) => v3::Packet::Puback(pid(*value)?),
+            Packet::Pubrec(value) => v3::Packet::Pubrec(pid(*value)?),
+            Packet::Pubrel(value) => v3::Packet::Pubrel(pid(*value)?),
+            Packet::Pubcomp(value) => v3::Packet::Pubcomp(pid(*value)?),
+            Packet::Subscribe {
+                pid: value,
+                topic_filters,
+            } => {
+                let topics = topic_filters
+                    .iter()
+                    .map(|(filter, level)| (topic_filter(*filter), qos(*level)))
+                    .collect();
+                v3::Subscribe::new(pid(*value)?, topics).into()
+            }
+            Packet::Unsubscribe {
+                pid: value,
+                topic_filters,
+            } => {
+                let topics = topic_filters
+                    .iter()
+                    .map(|filter| topic_filter(*filter))
+                    .collect();
+                v3::Unsubscribe::new(pid(*value)?, topics).into()
+            }
+            Packet::Pingreq => v3::Packet::Pingreq,
+            Packet::Disconnect => v3::Packet::Disconnect,
+        };
+        Some(packet)
+    }
+
+    fn to_v5(&self) -> Option<v5::Packet> {
+        let packet = match self {
+            Packet::Publish {
+                qos,
+                pid,
+                dup,
+                retain,
+                topic_name: name,
+                payload,
+            } => {
+                let mut publish = v5::Publish::new(
+                    qos_pid(*qos, *pid)?,
+                    topic_name(*name),
+                    Bytes::from(payload.clone()),
+                );
+                publish.dup = *dup;
+                publish.retain = *retain;
+                publish.into()
+            }
+            Packet::Puback(value) => v5::Puback::new_success(pid(*value)?).into(),
+            Packet::Pubrec(value) => v5::Pubrec::new_success(pid(*value)?).into(),
+            Packet::Pubrel(value) => v5::Pubrel::new_success(pid(*value)?).into(),
+            Packet::Pubcomp(value) => v5::Pubcomp::new_success(pid(*value)?).into(),
+            Packet::Subscribe {
+                pid: value,
+                topic_filters,
+            } => {
+                let topics = topic_filters
+                    .iter()
+                    .map(|(filter, level)| {
+                        (
+                            topic_filter(*filter),
+                            v5::SubscriptionOptions::new(qos(*level)),
+                        )
+                    })
+                    .collect();
+                v5::Subscribe::new(pid(*value)?, topics).into()
+            }
+            Packet::Unsubscribe {
+                pid: value,
+                topic_filters,
+            } => {
+                let topics = topic_filters
+                    .iter()
+                    .map(|filter| topic_filter(*filter))
+                    .collect();
+                v5::Unsubscribe::new(pid(*value)?, topics).into()
+            }
+            Packet::Pingreq => v5::Packet::Pingreq,
+            Packet::Disconnect => {
+                v5::Disconnect::new(v5::DisconnectReasonCode::NormalDisconnection).into()
+            }
+        };
+        Some(packet)
+    }
+}
+
+fn client_identifier(idx: u8) -> Arc<String> {
+    let value = CLIENT_IDENTIFIERS[idx as usize % CLIENT_IDENTIFIERS.len()];
+    Arc::new(value.to_owned())
+}
+
+fn topic_name(idx: u8) -> TopicName {
+    let value = TOPIC_NAMES[idx as usize % TOPIC_NAMES.len()];
+    TopicName::try_from(value.to_owned()).expect("topic name")
+}
+
+fn topic_filter(idx: u8) -> TopicFilter {
+    let value = TOPIC_FILTERS[idx as usize % TOPIC_FILTERS.len()];
+    TopicFilter::try_from(value.to_owned()).expect("topic filter")
+}
+
+fn qos(value: u8) -> QoS {
+    match value % 3 {
+        0 => QoS::Level0,
+        1 => QoS::Level1,
+        _ => QoS::Level2,
+    }
+}
+
+fn pid(value: u16) -> Option<Pid> {
+    Pid::try_from(value).ok()
+}
+
+fn qos_pid(qos_value: u8, pid_value: u16) -> Option<QosPid> {
+    let qos_pid = match qos(qos_value) {
+        QoS::Level0 => QosPid::Level0,
+        QoS::Level1 => QosPid::Level1(pid(pid_value)?),
+        QoS::Level2 => QosPid::Level2(pid(pid_value)?),
+    };
+    Some(qos_pid)
+}
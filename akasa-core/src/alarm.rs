@@ -0,0 +1,180 @@
+//! Threshold alarms (see `Config.alarms`)
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mqtt_proto::{QoS, TopicName};
+use openssl::asn1::Asn1Time;
+use openssl::x509::X509;
+
+use crate::config::Config;
+use crate::health::Alarm;
+use crate::state::GlobalState;
+
+pub(crate) async fn run(global: Arc<GlobalState>) {
+    loop {
+        // Read the interval every time, since the config can be reloaded
+        let interval = global.config().alarms.interval;
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        check_alarms(&global);
+    }
+}
+
+/// Check all the thresholds, publish the raised/cleared alarms to `$SYS/alarms/{name}`
+pub(crate) fn check_alarms(global: &GlobalState) {
+    let config = global.config();
+    let alarms = &config.alarms;
+
+    let connections = global.online_clients_count();
+    update(
+        global,
+        "connections",
+        connections,
+        alarms.max_connections,
+        |max| connections > max,
+        |max| format!("online connections {connections} > {max}"),
+    );
+
+    // Only read the memory usage when the threshold is set
+    let memory = alarms.max_memory.and_then(|_| memory_usage());
+    update(
+        global,
+        "memory",
+        memory.unwrap_or(0),
+        memory.and(alarms.max_memory),
+        |max| memory.unwrap_or(0) > max,
+        |max| {
+            format!(
+                "resident memory {} bytes > {max} bytes",
+                memory.unwrap_or(0)
+            )
+        },
+    );
+
+    let queue_depth = global.max_queue_depth() as u64;
+    update(
+        global,
+        "queue_depth",
+        queue_depth,
+        alarms.max_queue_depth.map(|max| max as u64),
+        |max| queue_depth > max,
+        |max| format!("client message queue depth {queue_depth} > {max}"),
+    );
+
+    let cert_expiry = alarms
+        .cert_expiry_days
+        .and_then(|_| min_cert_remaining_days(&config));
+    let (days, path) = cert_expiry.clone().unwrap_or_default();
+    update(
+        global,
+        "cert_expiry",
+        days,
+        cert_expiry.and(alarms.cert_expiry_days.map(u64::from)),
+        |min| days < min,
+        |min| format!("certificate {path} expires in {days} days (< {min} days)"),
+    );
+}
+
+// The alarm is cleared if the threshold is removed by config reload
+fn update<E, M>(
+    global: &GlobalState,
+    name: &'static str,
+    value: u64,
+    threshold: Option<u64>,
+    exceeded: E,
+    message: M,
+) where
+    E: Fn(u64) -> bool,
+    M: Fn(u64) -> String,
+{
+    let (triggered, threshold) = match threshold {
+        Some(threshold) => (exceeded(threshold), threshold),
+        None => (false, 0),
+    };
+    let message = if triggered {
+        message(threshold)
+    } else {
+        String::new()
+    };
+    if let Some(alarm) = global
+        .health
+        .update_alarm(name, value, threshold, triggered, message)
+    {
+        if alarm.active {
+            tracing::warn!("alarm raised: {}", alarm.message);
+        } else {
+            tracing::info!("alarm cleared: {}", name);
+        }
+        publish_alarm(global, &alarm);
+    }
+}
+
+fn publish_alarm(global: &GlobalState, alarm: &Alarm) {
+    let topic_name =
+        TopicName::try_from(format!("$SYS/alarms/{}", alarm.name)).expect("topic name");
+    let payload = match serde_json::to_vec(alarm) {
+        Ok(payload) => payload,
+        Err(err) => {
+            tracing::error!("encode alarm error: {}", err);
+            return;
+        }
+    };
+    // Retained, so the subscribers can get the current state of the alarm
+    if let Err(err) = global.publish(
+        topic_name,
+        QoS::Level0,
+        true,
+        payload.into(),
+        Default::default(),
+    ) {
+        tracing::warn!("publish alarm error: {}", err);
+    }
+}
+
+/// The resident memory (bytes) of current process
+#[cfg(target_os = "linux")]
+pub(crate) fn memory_usage() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    // VmRSS:     1234 kB
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn memory_usage() -> Option<u64> {
+    None
+}
+
+// The minimal remaining days of the TLS listener certificates, with the
+// certificate file path.
+fn min_cert_remaining_days(config: &Config) -> Option<(u64, String)> {
+    let listeners = &config.listeners;
+    [listeners.mqtts.as_ref(), listeners.wss.as_ref()]
+        .into_iter()
+        .flatten()
+        .filter_map(|listener| {
+            let days = cert_remaining_days(&listener.cert_file)?;
+            Some((days, listener.cert_file.display().to_string()))
+        })
+        .min()
+}
+
+fn cert_remaining_days(path: &Path) -> Option<u64> {
+    let cert = match fs::read(path)
+        .ok()
+        .and_then(|content| X509::from_pem(&content).ok())
+    {
+        Some(cert) => cert,
+        None => {
+            tracing::warn!("read certificate {:?} failed", path);
+            return None;
+        }
+    };
+    let now = Asn1Time::days_from_now(0).ok()?;
+    let diff = now.diff(cert.not_after()).ok()?;
+    // Expired certificate has negative days
+    Some(diff.days.max(0) as u64)
+}
@@ -0,0 +1,296 @@
+//! Bridge the messages between MQTT and an AMQP 1.0 broker (like Azure Service
+//! Bus or ActiveMQ) by the fe2o3-amqp client (the `amqp` feature), see
+//! `Config.amqp`.
+//!
+//! The messages published to the topics matched by `Config.amqp.forward` are
+//! sent to the AMQP addresses pre-settled (at most once). The messages received
+//! from the addresses of `Config.amqp.consume` are published to the MQTT topics
+//! and accepted after published. Only plain TCP with SASL PLAIN or ANONYMOUS is
+//! supported, the bridge reconnects when the connection is lost or the config
+//! is changed.
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use fe2o3_amqp::link::receiver::CreditMode;
+use fe2o3_amqp::sasl_profile::SaslProfile;
+use fe2o3_amqp::types::definitions::SenderSettleMode;
+use fe2o3_amqp::types::messaging::{AmqpValue, ApplicationProperties, Body, Message};
+use fe2o3_amqp::types::primitives::{Binary, Value};
+use fe2o3_amqp::{Connection, Receiver, Sender, Session};
+use flume::{bounded, Receiver as QueueReceiver, Sender as QueueSender};
+use hashbrown::HashMap;
+use mqtt_proto::TopicName;
+use tokio::task::JoinSet;
+use tokio::time::{interval, sleep, timeout};
+
+use crate::config::{qos_from_value, AmqpConfig, AmqpConsumeRule};
+use crate::metrics::Metrics;
+use crate::protocols::mqtt::topic_match;
+use crate::state::GlobalState;
+
+const QUEUE_SIZE: usize = 65536;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Drop a message if the sender link has no credit for this long
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+// The application property of the MQTT topic name
+const TOPIC_PROPERTY: &str = "mqtt_topic";
+
+struct AmqpMessage {
+    address: String,
+    topic_name: String,
+    payload: Bytes,
+}
+
+/// The messages waiting to be sent to the AMQP broker
+pub(crate) struct AmqpQueue {
+    sender: QueueSender<AmqpMessage>,
+    receiver: QueueReceiver<AmqpMessage>,
+}
+
+impl Default for AmqpQueue {
+    fn default() -> AmqpQueue {
+        let (sender, receiver) = bounded(QUEUE_SIZE);
+        AmqpQueue { sender, receiver }
+    }
+}
+
+/// Forward the message by the first matched rule of `Config.amqp.forward`,
+/// return false if not matched.
+pub(crate) fn forward(global: &GlobalState, topic_name: &str, payload: &Bytes) -> bool {
+    let config = global.config();
+    let amqp = &config.amqp;
+    if amqp.addr.is_none() {
+        return false;
+    }
+    let rule = match amqp
+        .forward
+        .iter()
+        .find(|rule| topic_match(&rule.topic_filter, topic_name))
+    {
+        Some(rule) => rule,
+        None => return false,
+    };
+    let message = AmqpMessage {
+        address: rule.address.clone(),
+        topic_name: topic_name.to_owned(),
+        payload: payload.clone(),
+    };
+    if global.amqp_queue.sender.try_send(message).is_err() {
+        tracing::warn!("amqp queue is full, message dropped: {}", topic_name);
+        Metrics::incr(&global.metrics.amqp_dropped);
+    }
+    true
+}
+
+/// Keep the connection to the AMQP broker
+pub(crate) async fn run(global: Arc<GlobalState>) {
+    loop {
+        let amqp = global.config().amqp.clone();
+        let addr = match amqp.addr.as_ref() {
+            Some(addr) => addr,
+            None => {
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        match bridge(&global, &amqp).await {
+            Ok(()) => tracing::info!("amqp config changed, reconnect"),
+            Err(err) => {
+                tracing::warn!("amqp bridge to {} error: {}", addr, err);
+                sleep(Duration::from_secs(amqp.reconnect_interval)).await;
+            }
+        }
+    }
+}
+
+/// Bridge the messages until the connection is lost (Err) or the config is
+/// changed (Ok)
+async fn bridge(global: &Arc<GlobalState>, config: &AmqpConfig) -> io::Result<()> {
+    let addr = config.addr.as_deref().unwrap_or_default();
+    let hostname = match config.hostname.as_ref() {
+        Some(hostname) => hostname.as_str(),
+        None => addr.rsplit_once(':').map_or(addr, |(host, _)| host),
+    };
+    let sasl_profile = match config.username.as_ref() {
+        Some(username) => SaslProfile::Plain {
+            username: username.clone(),
+            password: config.password.clone().unwrap_or_default(),
+        },
+        None => SaslProfile::Anonymous,
+    };
+    let open = Connection::builder()
+        .container_id(config.container_id.clone())
+        .hostname(hostname)
+        .sasl_profile(sasl_profile)
+        .open(format!("amqp://{addr}").as_str());
+    let mut connection = match timeout(HANDSHAKE_TIMEOUT, open).await {
+        Ok(result) => result.map_err(amqp_error)?,
+        Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+    };
+    let mut session = Session::begin(&mut connection).await.map_err(amqp_error)?;
+
+    let mut senders: HashMap<String, Sender> = HashMap::new();
+    for rule in &config.forward {
+        if senders.contains_key(&rule.address) {
+            continue;
+        }
+        let sender = Sender::builder()
+            .name(format!("sender:{}", rule.address))
+            .target(rule.address.clone())
+            .sender_settle_mode(SenderSettleMode::Settled)
+            .attach(&mut session)
+            .await
+            .map_err(amqp_error)?;
+        senders.insert(rule.address.clone(), sender);
+    }
+    // The receivers are served by their own tasks, aborted when dropped
+    let mut consumers = JoinSet::new();
+    for rule in &config.consume {
+        let receiver = Receiver::builder()
+            .name(format!("receiver:{}", rule.address))
+            .source(rule.address.clone())
+            .credit_mode(CreditMode::Auto(config.credit))
+            .attach(&mut session)
+            .await
+            .map_err(amqp_error)?;
+        consumers.spawn(consume(Arc::clone(global), rule.clone(), receiver));
+    }
+    tracing::info!("amqp bridge connected to {}", addr);
+
+    let queue = &global.amqp_queue.receiver;
+    let mut ticker = interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            result = queue.recv_async() => {
+                if let Ok(message) = result {
+                    match senders.get_mut(&message.address) {
+                        Some(sender) => send(global, sender, message).await?,
+                        // Added to the config after connected
+                        None => Metrics::incr(&global.metrics.amqp_dropped),
+                    }
+                }
+            }
+            Some(result) = consumers.join_next() => {
+                return match result {
+                    Ok(result) => result,
+                    Err(err) => Err(amqp_error(err)),
+                };
+            }
+            _ = ticker.tick() => {
+                if global.config().amqp != *config {
+                    break;
+                }
+            }
+        }
+    }
+    consumers.shutdown().await;
+    for (_, sender) in senders {
+        let _ = sender.close().await;
+    }
+    let _ = session.end().await;
+    let _ = connection.close().await;
+    Ok(())
+}
+
+// Send a message pre-settled, dropped if the link has no credit in time
+async fn send(global: &GlobalState, sender: &mut Sender, message: AmqpMessage) -> io::Result<()> {
+    let topic_name = message.topic_name;
+    let amqp_message = Message::builder()
+        .application_properties(
+            ApplicationProperties::builder()
+                .insert(TOPIC_PROPERTY, topic_name.clone())
+                .build(),
+        )
+        .data(Binary::from(message.payload.to_vec()))
+        .build();
+    match timeout(SEND_TIMEOUT, sender.send(amqp_message)).await {
+        Ok(Ok(_)) => Metrics::incr(&global.metrics.amqp_sent),
+        Ok(Err(err)) => {
+            Metrics::incr(&global.metrics.amqp_dropped);
+            return Err(amqp_error(err));
+        }
+        Err(_) => {
+            tracing::warn!(
+                "no credit of amqp {}, dropped: {}",
+                message.address,
+                topic_name
+            );
+            Metrics::incr(&global.metrics.amqp_dropped);
+        }
+    }
+    Ok(())
+}
+
+// Publish the messages received from the address, accepted after published
+async fn consume(
+    global: Arc<GlobalState>,
+    rule: AmqpConsumeRule,
+    mut receiver: Receiver,
+) -> io::Result<()> {
+    // Validated by `Config::is_valid`
+    let topic_name = TopicName::try_from(rule.topic.clone())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let qos = qos_from_value(rule.qos);
+    loop {
+        let delivery = receiver.recv::<Body<Value>>().await.map_err(amqp_error)?;
+        let published = match message_body(delivery.body()) {
+            Some(body) => global
+                .publish(topic_name.clone(), qos, false, body, Default::default())
+                .map_err(|err| {
+                    tracing::warn!("publish message from amqp {} error: {}", rule.address, err)
+                })
+                .is_ok(),
+            None => {
+                tracing::warn!("message without body from amqp {}", rule.address);
+                false
+            }
+        };
+        if published {
+            Metrics::incr(&global.metrics.amqp_received);
+            receiver.accept(&delivery).await.map_err(amqp_error)?;
+        } else {
+            Metrics::incr(&global.metrics.amqp_dropped);
+            receiver.reject(&delivery, None).await.map_err(amqp_error)?;
+        }
+    }
+}
+
+// The concatenated data sections or the binary/string value of the message
+fn message_body(body: &Body<Value>) -> Option<Bytes> {
+    match body {
+        Body::Data(sections) => Some(
+            sections
+                .iter()
+                .flat_map(|data| data.0.iter().copied())
+                .collect::<Vec<u8>>()
+                .into(),
+        ),
+        Body::Value(AmqpValue(Value::Binary(value))) => Some(Bytes::copy_from_slice(value)),
+        Body::Value(AmqpValue(Value::String(value))) => {
+            Some(Bytes::copy_from_slice(value.as_bytes()))
+        }
+        _ => None,
+    }
+}
+
+fn amqp_error(err: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_body() {
+        let body = Body::Value(AmqpValue(Value::String("text".to_owned())));
+        assert_eq!(message_body(&body).unwrap(), "text");
+        let body = Body::Value(AmqpValue(Value::Binary(Binary::from(b"data".to_vec()))));
+        assert_eq!(message_body(&body).unwrap(), "data");
+        assert!(message_body(&Body::Value(AmqpValue(Value::Null))).is_none());
+    }
+}
@@ -0,0 +1,157 @@
+//! The append-only audit log (see `Config.audit`)
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flume::{Receiver, Sender};
+use serde::Serialize;
+
+/// The audited events, encoded as one JSON object per line with an `event`
+/// field as the event type.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Connect {
+        client_identifier: String,
+        username: Option<String>,
+        peer: SocketAddr,
+        protocol: String,
+        success: bool,
+        /// The reason code of the rejected connect
+        reason: Option<String>,
+    },
+    Disconnect {
+        client_identifier: String,
+        username: Option<String>,
+        peer: SocketAddr,
+        reason: String,
+    },
+    AclDenied {
+        client_identifier: String,
+        username: Option<String>,
+        peer: SocketAddr,
+        /// publish/subscribe/will
+        action: String,
+        /// The topic name or topic filters
+        topics: Vec<String>,
+    },
+    AdminAction {
+        peer: SocketAddr,
+        action: String,
+        detail: String,
+    },
+    ConfigReload {
+        success: bool,
+        applied: Vec<String>,
+        restart_required: Vec<String>,
+        error: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    /// Unix timestamp in milliseconds
+    time: u64,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// The audit log file, the lines are written by a dedicated thread so the
+/// connection tasks never wait for the file.
+#[derive(Default)]
+pub struct AuditLog {
+    // Started by the first line
+    writer: OnceLock<Sender<AuditCommand>>,
+}
+
+enum AuditCommand {
+    Append(PathBuf, Vec<u8>),
+    // Reply when the lines queued before are written
+    Flush(Sender<()>),
+}
+
+impl AuditEvent {
+    /// Encode the event as a JSON line
+    pub fn encode(&self) -> serde_json::Result<Vec<u8>> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+        let mut line = serde_json::to_vec(&AuditRecord { time, event: self })?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+impl AuditLog {
+    /// Queue a line to append to the file, the errors are logged by the
+    /// writer thread.
+    pub(crate) fn append(&self, path: &Path, line: Vec<u8>) -> io::Result<()> {
+        self.sender()?
+            .send(AuditCommand::Append(path.to_path_buf(), line))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "audit log writer stopped"))
+    }
+
+    /// Wait for the queued lines written
+    pub(crate) fn flush(&self) {
+        let sender = match self.writer.get() {
+            Some(sender) => sender,
+            None => return,
+        };
+        let (done, done_receiver) = flume::bounded(1);
+        if sender.send(AuditCommand::Flush(done)).is_ok() {
+            let _ = done_receiver.recv();
+        }
+    }
+
+    fn sender(&self) -> io::Result<&Sender<AuditCommand>> {
+        if let Some(sender) = self.writer.get() {
+            return Ok(sender);
+        }
+        let (sender, receiver) = flume::unbounded();
+        // Another thread may start the writer at the same time, only the
+        // sender set first is used and the other writer stops at once.
+        if self.writer.set(sender).is_ok() {
+            thread::Builder::new()
+                .name("audit-log".to_owned())
+                .spawn(move || write_lines(receiver))?;
+        }
+        Ok(self.writer.get().expect("audit log writer"))
+    }
+}
+
+fn write_lines(receiver: Receiver<AuditCommand>) {
+    // The file is reopened when the path changed by config reload
+    let mut file_opt: Option<(PathBuf, File)> = None;
+    while let Ok(command) = receiver.recv() {
+        let (path, line) = match command {
+            AuditCommand::Append(path, line) => (path, line),
+            AuditCommand::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        if file_opt.as_ref().map(|(current, _)| current) != Some(&path) {
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => file_opt = Some((path.clone(), file)),
+                Err(err) => {
+                    tracing::error!("open audit log {:?} error: {}", path, err);
+                    continue;
+                }
+            }
+        }
+        let (_, file) = file_opt.as_mut().expect("audit log file");
+        // The line is written by a single write call so the records will
+        // not interleave with other writers of the file.
+        if let Err(err) = file.write_all(&line) {
+            tracing::error!("write audit log to {:?} error: {}", path, err);
+            // Reopen the file next time (it may be removed or rotated)
+            file_opt = None;
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! The banned clients by IP, client identifier or username, managed by the
+//! admin API and saved to `Config.ban_file`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use hashbrown::HashMap;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::protocols::mqtt::get_unix_ts;
+use crate::state::GlobalState;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BanKind {
+    Ip,
+    ClientId,
+    Username,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Ban {
+    pub kind: BanKind,
+    pub value: String,
+    pub reason: Option<String>,
+    /// The unix timestamp (seconds) the ban expires, banned forever if not set
+    pub expire_at: Option<u64>,
+}
+
+#[derive(Default)]
+pub(crate) struct BanList {
+    bans: RwLock<HashMap<(BanKind, String), Ban>>,
+}
+
+impl BanKind {
+    pub(crate) fn parse(value: &str) -> Option<BanKind> {
+        match value {
+            "ip" => Some(BanKind::Ip),
+            "client_id" => Some(BanKind::ClientId),
+            "username" => Some(BanKind::Username),
+            _ => None,
+        }
+    }
+}
+
+impl Ban {
+    fn expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|expire_at| expire_at <= now)
+    }
+}
+
+impl BanList {
+    /// Load the bans saved before
+    pub(crate) fn load(&self, path: &Path) -> io::Result<()> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let bans: Vec<Ban> = serde_json::from_slice(&content)?;
+        *self.bans.write() = bans
+            .into_iter()
+            .map(|ban| ((ban.kind, ban.value.clone()), ban))
+            .collect();
+        Ok(())
+    }
+
+    /// All the bans not expired, ordered by kind and value
+    pub(crate) fn list(&self) -> Vec<Ban> {
+        let now = get_unix_ts();
+        let mut bans: Vec<Ban> = self
+            .bans
+            .read()
+            .values()
+            .filter(|ban| !ban.expired(now))
+            .cloned()
+            .collect();
+        bans.sort_by(|a, b| (a.kind, &a.value).cmp(&(b.kind, &b.value)));
+        bans
+    }
+
+    /// Add or replace a ban, the expired bans are removed
+    pub(crate) fn add(&self, ban: Ban) {
+        let now = get_unix_ts();
+        let mut bans = self.bans.write();
+        bans.retain(|_, old| !old.expired(now));
+        bans.insert((ban.kind, ban.value.clone()), ban);
+    }
+
+    /// Remove a ban, return false if not exists
+    pub(crate) fn remove(&self, kind: BanKind, value: &str) -> bool {
+        self.bans
+            .write()
+            .remove(&(kind, value.to_owned()))
+            .is_some()
+    }
+
+    pub(crate) fn is_banned(&self, kind: BanKind, value: &str) -> bool {
+        let bans = self.bans.read();
+        if bans.is_empty() {
+            return false;
+        }
+        bans.get(&(kind, value.to_owned()))
+            .is_some_and(|ban| !ban.expired(get_unix_ts()))
+    }
+
+    /// If the client is banned by the client identifier or the username
+    pub(crate) fn is_client_banned(&self, client_identifier: &str, username: Option<&str>) -> bool {
+        self.is_banned(BanKind::ClientId, client_identifier)
+            || username.is_some_and(|username| self.is_banned(BanKind::Username, username))
+    }
+
+    // Write to a temporary file first, so the saved file is never partial
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let content = serde_json::to_vec_pretty(&self.list())?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+/// Save the bans to `Config.ban_file` after changed
+pub(crate) fn save_bans(global: &GlobalState) -> io::Result<()> {
+    match global.config().ban_file.as_ref() {
+        Some(path) => global.bans.save(path),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(kind: BanKind, value: &str, expire_at: Option<u64>) -> Ban {
+        Ban {
+            kind,
+            value: value.to_owned(),
+            reason: None,
+            expire_at,
+        }
+    }
+
+    #[test]
+    fn test_ban_list() {
+        let bans = BanList::default();
+        assert!(!bans.is_banned(BanKind::Ip, "10.0.0.1"));
+        bans.add(ban(BanKind::Ip, "10.0.0.1", None));
+        bans.add(ban(BanKind::Username, "user", Some(get_unix_ts() + 60)));
+        bans.add(ban(BanKind::ClientId, "expired", Some(get_unix_ts() - 1)));
+        assert!(bans.is_banned(BanKind::Ip, "10.0.0.1"));
+        assert!(!bans.is_banned(BanKind::ClientId, "10.0.0.1"));
+        assert!(bans.is_client_banned("client", Some("user")));
+        assert!(!bans.is_client_banned("client", None));
+        assert!(!bans.is_client_banned("expired", None));
+        assert_eq!(bans.list().len(), 2);
+
+        let path = std::env::temp_dir().join(format!("akasa-bans-{}.json", uuid::Uuid::new_v4()));
+        bans.save(&path).unwrap();
+        assert!(bans.remove(BanKind::Ip, "10.0.0.1"));
+        assert!(!bans.remove(BanKind::Ip, "10.0.0.1"));
+        assert!(!bans.is_banned(BanKind::Ip, "10.0.0.1"));
+
+        let loaded = BanList::default();
+        loaded.load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_banned(BanKind::Ip, "10.0.0.1"));
+        assert!(loaded.is_client_banned("client", Some("user")));
+    }
+}
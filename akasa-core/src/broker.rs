@@ -0,0 +1,429 @@
+//! Embed the broker in an application, without the `akasa` binary:
+//!
+//! ```no_run
+//! use akasa_core::{Broker, Config};
+//!
+//! let handle = Broker::builder()
+//!     .config(Config::new_allow_anonymous())
+//!     .mqtt("127.0.0.1:1883".parse().unwrap())
+//!     .build()?
+//!     .start()?;
+//! // ...
+//! handle.stop()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::fs;
+use std::future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use futures_lite::{FutureExt, Stream};
+
+use crate::config::{Config, Listener, Listeners};
+use crate::events::ClientEvent;
+use crate::hook::{Hook, NoopHook};
+use crate::protocols::mqtt::load_passwords;
+use crate::server::rt::{self, ConfigLoader};
+use crate::state::{AuthPassword, GlobalState};
+
+/// The time to wait for the clients disconnected by `BrokerHandle::stop`
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The broker built by `Broker::builder`, not started yet
+pub struct Broker<H = NoopHook> {
+    hook_handler: H,
+    global: Arc<GlobalState>,
+    config_loader: Option<ConfigLoader>,
+    shutdown: ShutdownHandle,
+    shutdown_requests: flume::Receiver<Duration>,
+    stopped: flume::Sender<()>,
+}
+
+impl Broker {
+    /// The builder starts with `Config::default()` and the `NoopHook`
+    pub fn builder() -> BrokerBuilder {
+        BrokerBuilder {
+            config: Config::default(),
+            hook_handler: NoopHook,
+            auth_passwords: None,
+            config_loader: None,
+        }
+    }
+}
+
+impl<H> Broker<H>
+where
+    H: Hook + Clone + Send + Sync + 'static,
+{
+    /// The global state, for publishing messages, querying the sessions and
+    /// the metrics.
+    pub fn global(&self) -> &Arc<GlobalState> {
+        &self.global
+    }
+
+    /// The client events, subscribe before started to receive all of them
+    pub fn events(&self) -> impl Stream<Item = ClientEvent> {
+        self.global.events()
+    }
+
+    /// Stop the broker started by `run` or `start` gracefully
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Run the broker in current thread, it returns when failed to start or
+    /// stopped by a `ShutdownHandle`.
+    pub fn run(self) -> io::Result<()> {
+        let shutdown = shutdown_requested(self.shutdown_requests);
+        let result = rt::run_until(self.hook_handler, self.global, self.config_loader, shutdown);
+        drop(self.stopped);
+        result
+    }
+
+    /// Run the broker in a new thread, it's stopped by the returned handle.
+    pub fn start(self) -> io::Result<BrokerHandle> {
+        let Broker {
+            hook_handler,
+            global,
+            config_loader,
+            shutdown,
+            shutdown_requests,
+            stopped,
+        } = self;
+        let (dropped_sender, dropped_receiver) = flume::bounded::<()>(1);
+        let thread_global = Arc::clone(&global);
+        let thread = thread::Builder::new()
+            .name("akasa-broker".to_owned())
+            .spawn(move || {
+                // Also stopped when the handle is dropped
+                let dropped = async move {
+                    let _ = dropped_receiver.recv_async().await;
+                    DEFAULT_DRAIN_TIMEOUT
+                };
+                let shutdown = shutdown_requested(shutdown_requests).or(dropped);
+                let result = rt::run_until(hook_handler, thread_global, config_loader, shutdown);
+                drop(stopped);
+                result
+            })?;
+        Ok(BrokerHandle {
+            global,
+            shutdown,
+            dropped_sender,
+            thread,
+        })
+    }
+}
+
+// Resolved with the drain timeout when a shutdown is requested
+async fn shutdown_requested(requests: flume::Receiver<Duration>) -> Duration {
+    match requests.recv_async().await {
+        Ok(drain_timeout) => drain_timeout,
+        // Never requested after all the shutdown handles are dropped
+        Err(_) => future::pending().await,
+    }
+}
+
+/// Stop the broker gracefully: close the listeners, disconnect the clients
+/// (wait at most the drain timeout for the will messages and the after
+/// disconnect hooks), save the quotas and the bans, then stop the background
+/// tasks.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requests: flume::Sender<Duration>,
+    stopped: flume::Receiver<()>,
+}
+
+impl ShutdownHandle {
+    /// Start the shutdown without waiting, see `stopped`
+    pub fn trigger(&self, drain_timeout: Duration) {
+        let _ = self.requests.try_send(drain_timeout);
+    }
+
+    /// Resolved when the broker is fully stopped, or it's dropped without
+    /// started.
+    pub async fn stopped(&self) {
+        let _ = self.stopped.recv_async().await;
+    }
+
+    pub async fn shutdown(&self, drain_timeout: Duration) {
+        self.trigger(drain_timeout);
+        self.stopped().await
+    }
+}
+
+/// The handle of a started broker, the broker is stopped when this is dropped
+pub struct BrokerHandle {
+    global: Arc<GlobalState>,
+    shutdown: ShutdownHandle,
+    dropped_sender: flume::Sender<()>,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+impl BrokerHandle {
+    pub fn global(&self) -> &Arc<GlobalState> {
+        &self.global
+    }
+
+    pub fn events(&self) -> impl Stream<Item = ClientEvent> {
+        self.global.events()
+    }
+
+    /// Stop the broker without blocking, the handle can be dropped then
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Stop the broker gracefully with `DEFAULT_DRAIN_TIMEOUT`, then wait for
+    /// the broker thread exited.
+    pub fn stop(self) -> io::Result<()> {
+        self.shutdown.trigger(DEFAULT_DRAIN_TIMEOUT);
+        self.join()
+    }
+
+    /// Wait for the broker thread exited, without stopping it
+    pub fn join(self) -> io::Result<()> {
+        let BrokerHandle {
+            dropped_sender,
+            thread,
+            ..
+        } = self;
+        let result = thread
+            .join()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "broker thread panicked"))?;
+        drop(dropped_sender);
+        result
+    }
+}
+
+pub struct BrokerBuilder<H = NoopHook> {
+    config: Config,
+    hook_handler: H,
+    auth_passwords: Option<DashMap<String, AuthPassword>>,
+    config_loader: Option<ConfigLoader>,
+}
+
+impl<H> BrokerBuilder<H> {
+    /// Replace the whole config, the methods below override the fields of it
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn listeners(mut self, listeners: Listeners) -> Self {
+        self.config.listeners = listeners;
+        self
+    }
+
+    /// Listen for plain MQTT connections, other listeners are kept
+    pub fn mqtt(mut self, addr: SocketAddr) -> Self {
+        self.config.listeners.mqtt = Some(plain_listener(addr));
+        self
+    }
+
+    /// Listen for MQTT over WebSocket connections, other listeners are kept
+    pub fn ws(mut self, addr: SocketAddr) -> Self {
+        self.config.listeners.ws = Some(plain_listener(addr));
+        self
+    }
+
+    /// The number of dedicated executor threads, 0 means the connections are
+    /// handled by the main runtime.
+    pub fn executors(mut self, count: usize) -> Self {
+        self.config.executors = count;
+        self
+    }
+
+    /// The passwords used when `auth.enable` is true, if not given they are
+    /// loaded from `auth.password_file`.
+    pub fn auth_passwords(mut self, passwords: DashMap<String, AuthPassword>) -> Self {
+        self.auth_passwords = Some(passwords);
+        self
+    }
+
+    /// Reload the config by this loader when the process received SIGHUP
+    pub fn config_loader(mut self, config_loader: ConfigLoader) -> Self {
+        self.config_loader = Some(config_loader);
+        self
+    }
+
+    pub fn hook<H2>(self, hook_handler: H2) -> BrokerBuilder<H2> {
+        BrokerBuilder {
+            config: self.config,
+            hook_handler,
+            auth_passwords: self.auth_passwords,
+            config_loader: self.config_loader,
+        }
+    }
+
+    /// Validate the config and create the global state. There is no storage
+    /// to configure, the sessions and the retained messages are in memory.
+    pub fn build(self) -> io::Result<Broker<H>> {
+        if !self.config.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid config",
+            ));
+        }
+        let auth_passwords = match self.auth_passwords {
+            Some(passwords) => passwords,
+            None if self.config.auth.enable => {
+                let path = self.config.auth.password_file.as_ref().expect("pass file");
+                let file = fs::File::open(path)
+                    .map_err(|err| io::Error::new(err.kind(), format!("load passwords: {err}")))?;
+                load_passwords(file)?
+            }
+            None => DashMap::new(),
+        };
+        let mut global = GlobalState::new(self.config);
+        global.auth_passwords = auth_passwords;
+        let (requests, shutdown_requests) = flume::bounded(1);
+        let (stopped, stopped_receiver) = flume::bounded(0);
+        Ok(Broker {
+            hook_handler: self.hook_handler,
+            global: Arc::new(global),
+            config_loader: self.config_loader,
+            shutdown: ShutdownHandle {
+                requests,
+                stopped: stopped_receiver,
+            },
+            shutdown_requests,
+            stopped,
+        })
+    }
+}
+
+fn plain_listener(addr: SocketAddr) -> Listener {
+    Listener {
+        addr,
+        reuse_port: false,
+        proxy_mode: None,
+        max_qos: None,
+        server_keep_alive: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future::block_on;
+    use std::net::{TcpListener, TcpStream};
+
+    use crate::server::LocalClient;
+
+    #[test]
+    fn test_build() {
+        // The default password file not exists
+        let err = Broker::builder().build().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let mut config = Config::new_allow_anonymous();
+        config.max_packet_size_client = 0;
+        let err = Broker::builder().config(config).build().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let broker = Broker::builder()
+            .config(Config::new_allow_anonymous())
+            .mqtt("127.0.0.1:1884".parse().unwrap())
+            .executors(2)
+            .build()
+            .unwrap();
+        let config = broker.global().config();
+        assert_eq!(config.executors, 2);
+        assert_eq!(config.listeners.mqtt.as_ref().unwrap().addr.port(), 1884);
+    }
+
+    fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    // The listeners are started asynchronously
+    fn connectable(addr: SocketAddr) -> bool {
+        for _ in 0..50 {
+            if TcpStream::connect(addr).is_ok() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn test_start_stop() {
+        let addr = free_addr();
+        let handle = Broker::builder()
+            .config(Config::new_allow_anonymous())
+            .mqtt(addr)
+            .executors(1)
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        assert!(connectable(addr));
+
+        handle.stop().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_handle() {
+        let addr = free_addr();
+        let handle = Broker::builder()
+            .config(Config::new_allow_anonymous())
+            .mqtt(addr)
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        assert!(connectable(addr));
+
+        // The client is kicked out when draining
+        let mut client = block_on(LocalClient::new(handle.global())).unwrap();
+        let client_thread = thread::spawn(move || block_on(client.recv()).is_none());
+        let shutdown = handle.shutdown_handle();
+        block_on(shutdown.shutdown(Duration::from_secs(5)));
+        assert!(client_thread.join().unwrap());
+        assert_eq!(handle.global().online_clients_count(), 0);
+        assert!(TcpStream::connect(addr).is_err());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_update_listeners() {
+        let mqtt_addr = free_addr();
+        let ws_addr = free_addr();
+        let broker = Broker::builder()
+            .config(Config::new_allow_anonymous())
+            .mqtt(mqtt_addr)
+            .build()
+            .unwrap();
+        let listeners = Listeners {
+            mqtt: None,
+            mqtts: None,
+            ws: Some(plain_listener(ws_addr)),
+            wss: None,
+        };
+        let err = block_on(broker.global().update_listeners(listeners.clone())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+        let handle = broker.start().unwrap();
+        assert!(connectable(mqtt_addr));
+        let mut invalid = listeners.clone();
+        invalid.ws.as_mut().unwrap().max_qos = Some(3);
+        let err = block_on(handle.global().update_listeners(invalid)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        block_on(handle.global().update_listeners(listeners.clone())).unwrap();
+        assert_eq!(handle.global().config().listeners, listeners);
+        assert!(connectable(ws_addr));
+        assert!(TcpStream::connect(mqtt_addr).is_err());
+        handle.stop().unwrap();
+    }
+}
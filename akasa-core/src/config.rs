@@ -1,15 +1,47 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::str::FromStr;
 
-use mqtt_proto::QoS;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mqtt_proto::{QoS, TopicFilter, TopicName};
 use scram::server::{AuthenticationProvider, PasswordInfo};
 use serde::{Deserialize, Serialize};
+use tracing::level_filters::LevelFilter;
+
+use crate::rule::{render_fields, Query};
+use crate::schema::compile as compile_schema;
+use crate::server::http::HttpUrl;
 
 pub const DEFAULT_MAX_PACKET_SIZE: u32 = 5 + 268_435_455;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// The fields only take effect after server restart (read when the server or
+/// its workers are started). All other fields are read when they are used, so
+/// new connections will see the new value after reload (established
+/// connections keep the negotiated values). The tasks of the data integrations
+/// (`kafka`, `amqp`, `webhook_forward`, `tsdb`) read their config for every
+/// message, and reconnect when the client settings changed.
+const RESTART_REQUIRED_FIELDS: &[&str] = &[
+    "max_in_db_pending_messages",
+    "admin",
+    "grpc",
+    "executors",
+    "fan_out.workers",
+    "fan_out.max_queued_jobs",
+    "ban_file",
+    "quotas.state_file",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Config {
+    /// The log level, can be: [off, error, warn, info, debug, trace].
+    /// This option is ignored when `RUST_LOG` environment variable is set.
+    pub log_level: Option<String>,
+    /// The count of single-threaded executors handling the connections, every
+    /// executor accepts the connections on its own socket (SO_REUSEPORT, unix
+    /// only), the clients are pinned to an executor by the hash of the peer
+    /// IP. 0 means all connections are handled by the multi-thread executor.
+    pub executors: usize,
     pub listeners: Listeners,
     pub auth: AuthConfig,
     // FIXME: replace it with outter data: { username => PasswordInfo }
@@ -17,20 +49,38 @@ pub struct Config {
     pub sasl_mechanisms: HashSet<SaslMechanism>,
     /// It seems all populte MQTT server(broker) not check this.
     pub check_v310_client_id_length: bool,
+    /// How to handle the zero-length client identifier
+    pub empty_client_id: EmptyClientIdConfig,
+    /// What to do when a client connects with the client identifier of an
+    /// online session
+    pub takeover_policy: TakeoverPolicy,
 
     pub shared_subscription_mode: SharedSubscriptionMode,
 
     /// max allowed qos, allowed values: [0, 1, 2], default: 2
     pub max_allowed_qos: u8,
 
-    /// Timeout seconds to re-send inflight pending messages
-    pub inflight_timeout: u64,
+    /// How to re-send the inflight pending messages not acknowledged. The
+    /// deprecated `inflight_timeout` (seconds) is accepted as a fixed timeout.
+    #[serde(
+        default,
+        alias = "inflight_timeout",
+        deserialize_with = "deserialize_retransmit"
+    )]
+    pub retransmit: RetransmitConfig,
     /// max inflight pending messages for client default value
     pub max_inflight_client: u16,
     /// max inflight pending messages the server will handle
     pub max_inflight_server: u16,
     /// max allowed pending messages in memory, default: 256
     pub max_in_mem_pending_messages: usize,
+    /// max allowed pending messages payload bytes in memory, 0 means unlimited
+    pub max_in_mem_pending_bytes: usize,
+    /// What to do when the pending messages of a client reached the limits
+    pub pending_overflow_policy: OverflowPolicy,
+    /// Override the pending messages limits for the matched clients, the
+    /// first matched rule is used
+    pub pending_limits: Vec<PendingLimit>,
     /// max allowed pending messages in database, default: 65536
     pub max_in_db_pending_messages: usize,
 
@@ -38,6 +88,10 @@ pub struct Config {
     pub max_keep_alive: u16,
     pub multiple_subscription_id_in_publish: bool,
 
+    /// (v5.x) The session expiry interval requested by the client (in CONNECT
+    /// or DISCONNECT) is clamped to [min, max], the clamped value is returned in
+    /// CONNACK. The value 0 (session ends when the connection is closed) is kept.
+    pub min_session_expiry_interval: u32,
     pub max_session_expiry_interval: u32,
     /// max packet size given by client (to limit server)
     pub max_packet_size_client: u32,
@@ -48,6 +102,118 @@ pub struct Config {
     pub shared_subscription_available: bool,
     pub subscription_id_available: bool,
     pub wildcard_subscription_available: bool,
+    /// Limit the topic names and topic filters from clients
+    pub topic_limits: TopicLimitsConfig,
+    /// The topic prefixes starting with `$` the clients can publish to, only
+    /// by the exception clients
+    pub reserved_topics: ReservedTopicsConfig,
+    /// Max topic filters a session can subscribe, 0 means unlimited
+    pub max_subscriptions: usize,
+    /// Limit the payload size of the publishes by the topic, the first matched
+    /// rule is applied
+    pub payload_limits: Vec<PayloadLimit>,
+    /// Validate the payloads by the topic, the first matched schema is
+    /// applied
+    pub payload_schemas: Vec<PayloadSchema>,
+    /// How to handle the publish with invalid topic name. Only the topic
+    /// name checks of the server are covered: the invalid UTF-8 strings and
+    /// the payloads violated the payload format indicator are malformed
+    /// packets rejected by the decoder in both modes.
+    pub validation_mode: ValidationMode,
+    /// Limit the retained messages store
+    pub retain_limits: RetainLimitsConfig,
+    /// The prefix of the Response Information returned to the v5.x clients
+    /// requested it, the client gets `{prefix}/{client identifier}` and the
+    /// authorization of the topics under it is skipped (the other hooks are
+    /// still called). Disabled if not set (the default).
+    pub response_topic_prefix: Option<String>,
+
+    pub delayed_publish: DelayedPublishConfig,
+
+    /// Subscribe the matched clients to the topic filters when connected
+    pub auto_subscriptions: Vec<AutoSubscription>,
+
+    pub slow_consumer: SlowConsumerConfig,
+
+    pub write_batch: WriteBatchConfig,
+
+    pub buffer_pool: BufferPoolConfig,
+
+    pub broadcast: BroadcastConfig,
+
+    pub channels: ChannelsConfig,
+
+    pub qos0_shedding: Qos0SheddingConfig,
+
+    pub memory_limit: MemoryLimitConfig,
+
+    pub fan_out: FanOutConfig,
+
+    pub throttle: ThrottleConfig,
+
+    pub inbound_limit: InboundLimitConfig,
+
+    pub connect_limit: ConnectLimitConfig,
+
+    pub quotas: QuotasConfig,
+
+    /// Save the bans (managed by the admin API) to this file, loaded when
+    /// started. The bans are lost after restart if not set.
+    pub ban_file: Option<PathBuf>,
+
+    pub flapping: FlappingConfig,
+
+    /// Record the latency histograms of the published messages in the server
+    /// (route, enqueue, write), exported by the admin metrics API
+    pub latency_metrics: bool,
+
+    /// Record the MQTT specification rule violated by a client (packet type,
+    /// field, reason) when it's disconnected by a protocol error, to the log
+    /// (target `akasa::diagnostics`) and `$SYS/diagnostics`
+    pub strict_diagnostics: bool,
+
+    pub topic_stats: TopicStatsConfig,
+
+    pub top_talkers: TopTalkersConfig,
+
+    pub alarms: AlarmsConfig,
+
+    /// Redirect all the new connections to another server (for migration or
+    /// maintenance), disabled if not set
+    pub redirect: Option<RedirectConfig>,
+
+    /// The admin HTTP server (health check endpoints), disabled if not set
+    pub admin: Option<AdminConfig>,
+
+    /// The gRPC publish/subscribe API for the backend services, disabled if
+    /// not set
+    pub grpc: Option<GrpcConfig>,
+
+    /// The audit log (connects, ACL denials, admin actions, config reloads),
+    /// disabled if not set
+    pub audit: Option<AuditConfig>,
+
+    /// The client lifecycle events
+    pub events: EventsConfig,
+
+    /// Forward the matched messages to Kafka
+    pub kafka: KafkaConfig,
+
+    /// Bridge the messages with an AMQP 1.0 broker
+    pub amqp: AmqpConfig,
+
+    /// POST the matched messages to HTTP endpoints
+    pub webhook_forward: WebhookForwardConfig,
+
+    /// Write the matched messages to a time-series database
+    pub tsdb: TsdbConfig,
+
+    /// Track the Sparkplug B edge nodes and devices
+    pub sparkplug: SparkplugConfig,
+
+    /// The rules of the rule engine, all the matched rules are applied in
+    /// order
+    pub rules: Vec<RuleConfig>,
 
     pub hook: HookConfig,
 }
@@ -70,6 +236,13 @@ pub struct Listener {
     pub reuse_port: bool,
     /// The proxy protocol v2 mode
     pub proxy_mode: Option<ProxyMode>,
+    /// Override `max_allowed_qos` for the clients connected to this listener
+    pub max_qos: Option<u8>,
+    /// Override the keep alive of the v5.x clients connected to this listener
+    /// (by Server Keep Alive property), v3.x clients with a greater keep alive
+    /// are rejected by `Server unavailable`. 0 disables the keep alive of both
+    /// v5.x and v3.x clients.
+    pub server_keep_alive: Option<u16>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
@@ -85,6 +258,13 @@ pub struct TlsListener {
     pub cert_file: PathBuf,
     pub verify_peer: bool,
     pub fail_if_no_peer_cert: bool,
+    /// Override `max_allowed_qos` for the clients connected to this listener
+    pub max_qos: Option<u8>,
+    /// Override the keep alive of the v5.x clients connected to this listener
+    /// (by Server Keep Alive property), v3.x clients with a greater keep alive
+    /// are rejected by `Server unavailable`. 0 disables the keep alive of both
+    /// v5.x and v3.x clients.
+    pub server_keep_alive: Option<u16>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
@@ -102,6 +282,8 @@ impl Default for Listeners {
                 addr: (Ipv4Addr::LOCALHOST, 1883).into(),
                 proxy_mode: None,
                 reuse_port: true,
+                max_qos: None,
+                server_keep_alive: None,
             }),
             mqtts: None,
             ws: None,
@@ -135,16 +317,1038 @@ pub struct ScramPasswordInfo {
 pub struct AuthConfig {
     pub enable: bool,
     pub password_file: Option<PathBuf>,
+    /// The clients following the conventions of a profile are authenticated
+    /// by the profile instead of the password file, the first matched
+    /// profile is used
+    pub cloud_profiles: Vec<CloudAuthProfile>,
+}
+
+/// Authenticate the devices written for the cloud IoT platforms
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum CloudAuthProfile {
+    /// Azure IoT Hub style: the username is
+    /// `{host_name}/{device_id}/?api-version=...`, the client identifier is
+    /// the device id, and the password is a SAS token
+    /// (`SharedAccessSignature sr=...&sig=...&se=...[&skn=...]`).
+    AzureIotHub {
+        host_name: String,
+        /// The base64 encoded key signed the tokens
+        key: String,
+        /// The shared access policy name (`skn`) of the key, the key is a
+        /// device key if not set
+        key_name: Option<String>,
+    },
+    /// Google Cloud IoT style: the client identifier is
+    /// `projects/{project_id}/locations/{region}/registries/{registry_id}/devices/{device_id}`,
+    /// the username is ignored, and the password is a JWT (RS256 or ES256)
+    /// with the project id as the audience.
+    GoogleCloudIot {
+        project_id: String,
+        /// The public key of a device is read from
+        /// `{public_keys_dir}/{registry_id}/{device_id}.pem`
+        public_keys_dir: PathBuf,
+        /// Max seconds between `iat` and `exp` of the JWT
+        max_lifetime: u64,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SharedSubscriptionMode {
     Random,
+    /// Dispatch to the group members in turn
+    RoundRobin,
+    /// Sticky by publisher: messages from the same client go to the same member
     HashClientId,
     HashTopicName,
+    /// Dispatch to the member with the least queued messages
+    LeastPending,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct SlowConsumerConfig {
+    /// Max outbound queued messages of a client (pending messages + channel
+    /// messages + encoded packets not written yet), 0 means no limit.
+    pub max_queue_depth: usize,
+    /// Max seconds the outbound queue can stay deeper than `max_queue_depth`,
+    /// 0 means detected immediately.
+    pub max_queue_duration: u64,
+    /// Max seconds the write to client connection can be stalled, 0 means no limit.
+    pub max_write_stall: u64,
+    /// Disconnect the slow consumer (v5.0 client will receive a DISCONNECT
+    /// packet with QuotaExceeded reason code).
+    pub disconnect: bool,
+}
+
+impl Default for SlowConsumerConfig {
+    fn default() -> SlowConsumerConfig {
+        SlowConsumerConfig {
+            max_queue_depth: 1024,
+            max_queue_duration: 0,
+            max_write_stall: 60,
+            disconnect: false,
+        }
+    }
+}
+
+/// The outbound packets are encoded into one buffer and written to the client
+/// connection by one write (and one flush per poll).
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct WriteBatchConfig {
+    /// Max queued outbound packets of a connection, stop reading more
+    /// messages when reached.
+    pub max_packets: usize,
+    /// Max bytes of the encoded packets written by one write.
+    pub max_bytes: usize,
+}
+
+impl Default for WriteBatchConfig {
+    fn default() -> WriteBatchConfig {
+        WriteBatchConfig {
+            max_packets: 64,
+            max_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// The buffers for writing the encoded packets are reused, every executor
+/// (thread) has its own pool.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct BufferPoolConfig {
+    /// Max buffers in the pool of one executor
+    pub max_buffers: usize,
+    /// The buffer larger than this (bytes) is not put back to the pool
+    pub max_buffer_size: usize,
+}
+
+impl Default for BufferPoolConfig {
+    fn default() -> BufferPoolConfig {
+        BufferPoolConfig {
+            max_buffers: 256,
+            max_buffer_size: 64 * 1024,
+        }
+    }
+}
+
+/// The messages published by a client are queued per receiver before sent
+/// to the receivers' channels.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct BroadcastConfig {
+    /// Max queued messages of all receivers, stop reading from the publisher
+    /// when reached.
+    pub max_messages: usize,
+    /// Max queued messages of one receiver, the new messages to the receiver
+    /// are dropped when reached, so a slow receiver will not block the
+    /// publisher.
+    pub max_messages_per_receiver: usize,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> BroadcastConfig {
+        BroadcastConfig {
+            max_messages: 1024,
+            max_messages_per_receiver: 64,
+        }
+    }
+}
+
+/// The capacities of the channels of every client, only applied to the
+/// clients connected after changed.
+///
+/// The hook requests are not queued, a connection stops reading until its
+/// hook request finished.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ChannelsConfig {
+    /// The messages published to the client by other clients. When full, the
+    /// messages are kept in the publishers' broadcast queues (see
+    /// `Config.broadcast`), and the messages published by the server itself
+    /// (`$SYS` topics, admin API) are dropped.
+    pub normal: usize,
+    /// The control messages (take over, kick, inspect...) to the client. When
+    /// full, the take over and inspect wait, the kick fails.
+    pub control: usize,
+}
+
+impl Default for ChannelsConfig {
+    fn default() -> ChannelsConfig {
+        ChannelsConfig {
+            normal: 8,
+            control: 1,
+        }
+    }
+}
+
+/// Drop the new QoS 0 messages (counted by the `qos0_shed` metric) instead of
+/// queuing them when overloaded, so the QoS 1/2 flows are not stalled.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Qos0SheddingConfig {
+    /// Drop the QoS 0 messages to a client when the messages queued for it
+    /// (pending messages, channel and write queue) reach this, 0 means
+    /// disabled.
+    pub max_queue_depth: usize,
+    /// Drop the QoS 0 messages to all the clients when the resident memory
+    /// (bytes) of the server exceeds this, checked every second (only
+    /// supported on Linux). Disabled if not set.
+    pub max_memory: Option<u64>,
+}
+
+/// The limit of the memory accounted to the payloads of the pending packets,
+/// the broadcast queues and the retained messages. When the pressure
+/// threshold is reached, the new QoS 0 messages are dropped (counted by the
+/// `qos0_shed` metric) and the new connections are refused with ServerBusy
+/// (Server Unavailable in v3.x).
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct MemoryLimitConfig {
+    /// The max accounted bytes, disabled if not set
+    pub max_bytes: Option<u64>,
+    /// Apply the pressure when the accounted bytes reach this percent of
+    /// `max_bytes`, so the server is not pushed right to the limit.
+    pub pressure_percent: u8,
+}
+
+impl Default for MemoryLimitConfig {
+    fn default() -> MemoryLimitConfig {
+        MemoryLimitConfig {
+            max_bytes: None,
+            pressure_percent: 90,
+        }
+    }
+}
+
+impl MemoryLimitConfig {
+    /// If the accounted bytes reached the pressure threshold
+    pub fn under_pressure(&self, accounted: usize) -> bool {
+        match self.max_bytes {
+            Some(max_bytes) => {
+                accounted as u128 * 100 >= max_bytes as u128 * self.pressure_percent as u128
+            }
+            None => false,
+        }
+    }
+}
+
+/// Enqueue the messages matched too many receivers by a worker pool, so the
+/// publisher's connection is not blocked by the fan out. The messages from a
+/// publisher to a receiver are kept in order.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct FanOutConfig {
+    /// Use the workers when a message matched at least this many receivers, 0
+    /// means disabled.
+    pub min_receivers: usize,
+    /// The number of the workers, started when first used (changes are
+    /// applied after restart).
+    pub workers: usize,
+    /// The messages queued for every worker, the message is enqueued by the
+    /// publisher when full.
+    pub max_queued_jobs: usize,
+}
+
+impl Default for FanOutConfig {
+    fn default() -> FanOutConfig {
+        FanOutConfig {
+            min_receivers: 0,
+            workers: 4,
+            max_queued_jobs: 64,
+        }
+    }
+}
+
+/// Limit the bytes rate of every client connection, so a single client can
+/// not flood the server with large payloads. Only applied to the clients
+/// connected after changed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ThrottleConfig {
+    /// Max bytes per second read from a client, 0 means unlimited
+    pub inbound_bytes_per_sec: u64,
+    /// Max bytes per second written to a client, 0 means unlimited
+    pub outbound_bytes_per_sec: u64,
+    /// Max bytes read or written at once after idle, 0 means the bytes of one
+    /// second
+    pub burst_bytes: u64,
+}
+
+/// Limit the PUBLISH packets received by the whole server, so a burst from
+/// many clients degrades gracefully instead of saturating the fan out. When
+/// the limit is reached the clients wait in a queue and are served in turn.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct InboundLimitConfig {
+    /// Max PUBLISH packets per second received by the server, 0 means
+    /// unlimited (enabled or disabled only for the clients connected after
+    /// changed)
+    pub max_messages_per_sec: u64,
+    /// Max PUBLISH packets received at once after idle, 0 means the packets
+    /// of one second
+    pub burst_messages: u64,
+}
+
+/// Limit the rate of the new connections by leaky buckets of every source IP
+/// (after the proxy protocol) and the whole server, so the reconnect storm
+/// after a network outage is spread. The refused connections are closed
+/// before the TLS handshake.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConnectLimitConfig {
+    /// Max new connections per second from a source IP, 0 means unlimited
+    pub per_ip_per_sec: u64,
+    /// Max new connections at once from a source IP (many clients may be
+    /// behind a NAT), 0 means the connections of one second
+    pub per_ip_burst: u64,
+    /// Max new connections per second of the server, 0 means unlimited
+    pub global_per_sec: u64,
+    /// Max new connections at once of the server, 0 means the connections of
+    /// one second
+    pub global_burst: u64,
+}
+
+/// The cumulative daily quotas per username. When exceeded the message is
+/// refused with QuotaExceeded (MQTT v5.0, dropped in v3.x).
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct QuotasConfig {
+    /// The first matched rule of the username is applied, the clients
+    /// without a username are not limited.
+    pub rules: Vec<QuotaRule>,
+    /// The hour (UTC, 0 to 23) the daily usage is reset
+    pub reset_hour: u8,
+    /// Save the usage to this file (loaded when started), so the usage is
+    /// kept across restarts. Not saved if not set.
+    pub state_file: Option<PathBuf>,
+    /// Seconds between two saves of the usage
+    pub save_interval: u64,
+}
+
+impl Default for QuotasConfig {
+    fn default() -> QuotasConfig {
+        QuotasConfig {
+            rules: Vec::new(),
+            reset_hour: 0,
+            state_file: None,
+            save_interval: 60,
+        }
+    }
+}
+
+/// Ban the clients connected too many times in a short time (like a
+/// crash-looping firmware) by the client identifier, the ban time is doubled
+/// every time the client is detected again.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct FlappingConfig {
+    /// Max connects of a client identifier in the window, 0 means disabled
+    pub max_connects: usize,
+    /// The window (seconds) of the connects
+    pub window: u64,
+    /// The ban time (seconds) of the first detection
+    pub ban_secs: u64,
+    /// The max ban time (seconds), a client quiet longer than the window plus
+    /// this time is forgiven (the ban time is reset)
+    pub max_ban_secs: u64,
+}
+
+impl Default for FlappingConfig {
+    fn default() -> FlappingConfig {
+        FlappingConfig {
+            max_connects: 0,
+            window: 60,
+            ban_secs: 60,
+            max_ban_secs: 3600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct QuotaRule {
+    /// The username pattern (`*` matches any characters), match all usernames
+    /// if not set
+    pub username: Option<String>,
+    /// Max messages published per day, not limited if not set
+    pub max_messages_per_day: Option<u64>,
+    /// Max payload bytes published per day, not limited if not set
+    pub max_bytes_per_day: Option<u64>,
+    /// Max topics of the retained messages published by the username, not
+    /// limited if not set
+    pub max_retained_topics: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct TopicStatsConfig {
+    /// Aggregate the traffic by these topic name prefixes (the longest
+    /// matched prefix is used, by whole topic levels), empty means disabled.
+    pub prefixes: Vec<String>,
+    /// Seconds between two publishes to `$SYS/topics/{prefix}`, also the
+    /// rolling window of the rates, 0 means not publish.
+    pub interval: u64,
+}
+
+impl Default for TopicStatsConfig {
+    fn default() -> TopicStatsConfig {
+        TopicStatsConfig {
+            prefixes: Vec::new(),
+            interval: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct TopTalkersConfig {
+    /// How many clients in the top talkers report, 0 means disabled
+    pub count: usize,
+    /// Seconds of the interval the report computed from
+    pub interval: u64,
+}
+
+impl Default for TopTalkersConfig {
+    fn default() -> TopTalkersConfig {
+        TopTalkersConfig {
+            count: 0,
+            interval: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct AlarmsConfig {
+    /// Seconds between two checks
+    pub interval: u64,
+    /// Raise an alarm when online connections more than this value
+    pub max_connections: Option<u64>,
+    /// Raise an alarm when the resident memory (bytes) more than this value,
+    /// only supported on Linux.
+    pub max_memory: Option<u64>,
+    /// Raise an alarm when the message queue of any client longer than this value
+    pub max_queue_depth: Option<usize>,
+    /// Raise an alarm when a TLS listener certificate expires in this many days
+    pub cert_expiry_days: Option<u32>,
+}
+
+impl Default for AlarmsConfig {
+    fn default() -> AlarmsConfig {
+        AlarmsConfig {
+            interval: 10,
+            max_connections: None,
+            max_memory: None,
+            max_queue_depth: None,
+            cert_expiry_days: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct TopicLimitsConfig {
+    /// Max length (bytes) of the topic name or topic filter, 0 means unlimited
+    pub max_length: usize,
+    /// Max levels of the topic name or topic filter, 0 means unlimited
+    pub max_levels: usize,
+    /// Max wildcards (`+` and `#`) in the topic filter, 0 means unlimited
+    pub max_wildcards: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ReservedTopicsConfig {
+    /// The reserved topic name prefixes, each must start with `$`
+    pub prefixes: Vec<String>,
+    /// The clients (like the superusers and the bridges) allowed to publish
+    /// to the reserved topics
+    pub exceptions: Vec<ReservedTopicException>,
+}
+
+impl Default for ReservedTopicsConfig {
+    fn default() -> ReservedTopicsConfig {
+        ReservedTopicsConfig {
+            prefixes: vec!["$SYS/".to_owned(), "$CONTROL/".to_owned()],
+            exceptions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ReservedTopicException {
+    /// The client identifier pattern (`*` matches any characters), match all
+    /// clients if not set
+    pub client_id: Option<String>,
+    /// The username pattern (`*` matches any characters), required since the
+    /// client identifiers are chosen by the clients
+    pub username: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct PayloadLimit {
+    /// The topic filter matched by the topic name of the publish
+    pub topic_filter: String,
+    /// Max payload size (bytes) of the matched publishes, 0 means unlimited
+    pub max_payload_size: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct PayloadSchema {
+    /// The topic filter matched by the topic name of the publish
+    pub topic_filter: String,
+    pub format: SchemaFormat,
+    /// Publish the invalid messages to this topic (with the original topic
+    /// and the reason in the user properties) instead of rejecting them
+    pub dead_letter_topic: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum SchemaFormat {
+    /// A JSON Schema, the references and the regular expressions are not
+    /// supported
+    JsonSchema(serde_json::Value),
+    /// A message type of a protobuf `FileDescriptorSet` (`protoc
+    /// --include_imports --descriptor_set_out`)
+    Protobuf {
+        /// The base64 encoded descriptor set
+        descriptor_set: String,
+        /// The full name of the message type (`{package}.{message}`)
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValidationMode {
+    /// Close the connection, v5.x clients get a DISCONNECT with TopicNameInvalid
+    Strict,
+    /// Drop the message and log it, v5.x clients get a PUBACK/PUBREC with
+    /// TopicNameInvalid
+    Lenient,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct RetainLimitsConfig {
+    /// Max number of retained messages, 0 means unlimited (replace an exists
+    /// retained message is always allowed)
+    pub max_messages: usize,
+    /// Max payload size (bytes) of a retained message, 0 means unlimited
+    pub max_payload_size: usize,
+    /// What to do with the retained message exceeded the limits
+    pub policy: RetainLimitPolicy,
+}
+
+impl Default for RetainLimitsConfig {
+    fn default() -> RetainLimitsConfig {
+        RetainLimitsConfig {
+            max_messages: 0,
+            max_payload_size: 0,
+            policy: RetainLimitPolicy::Drop,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RetainLimitPolicy {
+    /// Reject the publish, v5.x clients get the QuotaExceeded reason code
+    /// (MQTT v3.x can't reject the publish, the message is dropped)
+    Reject,
+    /// Silently not store the message, it is still published to the subscribers
+    Drop,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct DelayedPublishConfig {
+    /// Publish to `$delayed/{seconds}/{topic}` will publish the message to
+    /// `{topic}` after the delay (disabled by default). The publish hooks
+    /// check `{topic}` instead of the `$delayed/...` topic name.
+    pub enable: bool,
+    /// Max delay seconds
+    pub max_delay: u32,
+    /// Max scheduled messages, the new delayed messages are rejected when
+    /// reached
+    pub max_messages: usize,
+}
+
+impl Default for DelayedPublishConfig {
+    fn default() -> DelayedPublishConfig {
+        DelayedPublishConfig {
+            enable: false,
+            max_delay: 4294967,
+            max_messages: 100000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest messages not sent yet
+    DropOldest,
+    /// Drop the new message
+    DropNewest,
+    /// Drop the new message and disconnect the client
+    Disconnect,
+}
+
+/// The inflight pending message is re-sent when not acknowledged in the
+/// timeout, the timeout grows exponentially after every re-send. The default
+/// is a fixed timeout of 15 seconds (the old `inflight_timeout`).
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct RetransmitConfig {
+    /// Timeout seconds to re-send the message the first time
+    pub initial_timeout: u64,
+    /// The timeout is multiplied by this factor after every re-send, 1 means
+    /// a fixed timeout
+    pub backoff_factor: u32,
+    /// The max timeout seconds
+    pub max_timeout: u64,
+    /// Disconnect the client when a message is still not acknowledged after
+    /// re-sent this many times, 0 means unlimited
+    pub max_retries: u32,
+}
+
+impl Default for RetransmitConfig {
+    fn default() -> RetransmitConfig {
+        RetransmitConfig {
+            initial_timeout: 15,
+            backoff_factor: 1,
+            max_timeout: 120,
+            max_retries: 0,
+        }
+    }
+}
+
+impl RetransmitConfig {
+    /// The timeout seconds to re-send a message already re-sent `retries` times
+    pub fn timeout(&self, retries: u32) -> u64 {
+        let factor = u64::from(self.backoff_factor).saturating_pow(retries);
+        self.initial_timeout
+            .saturating_mul(factor)
+            .min(self.max_timeout)
+    }
+
+    /// If the message already re-sent `retries` times can not be re-sent again
+    pub fn exhausted(&self, retries: u32) -> bool {
+        self.max_retries > 0 && retries >= self.max_retries
+    }
+
+    /// A fixed timeout, as the deprecated `inflight_timeout`
+    pub fn fixed(timeout: u64) -> RetransmitConfig {
+        RetransmitConfig {
+            initial_timeout: timeout,
+            backoff_factor: 1,
+            max_timeout: timeout,
+            max_retries: 0,
+        }
+    }
+}
+
+// Accept the deprecated `inflight_timeout: <seconds>` (the field alias) as well
+fn deserialize_retransmit<'de, D>(deserializer: D) -> Result<RetransmitConfig, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Retransmit {
+        Timeout(u64),
+        Config(RetransmitConfig),
+    }
+    Ok(match Retransmit::deserialize(deserializer)? {
+        Retransmit::Timeout(timeout) => {
+            tracing::warn!("`inflight_timeout` is deprecated, use `retransmit` instead");
+            RetransmitConfig::fixed(timeout)
+        }
+        Retransmit::Config(retransmit) => retransmit,
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct PendingLimit {
+    /// The client identifier pattern (`*` matches any characters), match all
+    /// clients if not set
+    pub client_id: Option<String>,
+    /// The username pattern (`*` matches any characters), match all clients
+    /// if not set
+    pub username: Option<String>,
+    /// Override `max_in_mem_pending_messages`
+    pub max_messages: Option<usize>,
+    /// Override `max_in_mem_pending_bytes`
+    pub max_bytes: Option<usize>,
+    /// Override `pending_overflow_policy`
+    pub overflow_policy: Option<OverflowPolicy>,
+    /// Limit the inflight messages (sent but not acknowledged), also limited
+    /// by the Receive Maximum of v5.x clients. 1 for the strict ordering: a
+    /// message is sent after the previous one is acknowledged.
+    pub max_inflight: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct AutoSubscription {
+    /// The client identifier pattern (`*` matches any characters), match all
+    /// clients if not set
+    pub client_id: Option<String>,
+    /// The username pattern (`*` matches any characters), match all clients
+    /// if not set
+    pub username: Option<String>,
+    /// The topic filters, `%c` is replaced by the client identifier, `%u` is
+    /// replaced by the username and `%{key}` is replaced by the session
+    /// attribute
+    pub topics: Vec<AutoSubscribeTopic>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct AutoSubscribeTopic {
+    pub topic_filter: String,
+    pub qos: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TakeoverPolicy {
+    /// Disconnect the online client, v5.x clients get a DISCONNECT with
+    /// SessionTakenOver
+    KickOld,
+    /// Reject the new client
+    RejectNew,
+    /// Reject the new client if it's from the same IP address as the online
+    /// client, otherwise disconnect the online client
+    RejectNewIfSameIp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct EmptyClientIdConfig {
+    /// Assign a generated client identifier to the client, otherwise reject
+    /// the client with `Client Identifier not valid` (v5.0) or `Identifier
+    /// rejected` (v3.x)
+    pub assign: bool,
+    /// The prefix of the generated client identifier
+    pub prefix: String,
+    pub format: ClientIdFormat,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClientIdFormat {
+    /// Hyphenated UUID v4: `67e55044-10b1-426f-9247-bb680e5fe0c8`
+    Uuid,
+    /// UUID v4 without hyphens: `67e5504410b1426f9247bb680e5fe0c8`
+    Simple,
+}
+
+impl Default for EmptyClientIdConfig {
+    fn default() -> EmptyClientIdConfig {
+        EmptyClientIdConfig {
+            assign: true,
+            prefix: String::new(),
+            format: ClientIdFormat::Uuid,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct RedirectConfig {
+    /// The Server Reference returned to v5.x clients (v3.x clients only get
+    /// `Server unavailable`)
+    pub server_reference: String,
+    /// Use `Server moved` instead of `Use another server` as the reason code
+    pub permanent: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct AdminConfig {
+    pub addr: SocketAddr,
+    /// Required as `authorization: Bearer <token>` by the endpoints changing
+    /// the state (except `POST /api/v1/publish`), they are disabled if not set
+    pub token: Option<String>,
+    /// Required as `authorization: Bearer <token>` by `POST /api/v1/publish`,
+    /// the endpoint is disabled if not set
+    pub publish_token: Option<String>,
+    /// Required by `GET /api/v1/stream` as `authorization: Bearer <token>` or
+    /// the `token` query parameter, the endpoint is disabled if not set
+    pub stream_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct GrpcConfig {
+    pub addr: SocketAddr,
+    /// Required as `authorization: Bearer <token>` in the calls if set
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct EventsConfig {
+    /// Publish the events to `$SYS/events/{event}` topics
+    pub topic: bool,
+    /// POST the events (JSON) to this url, only `http://` is supported
+    pub webhook: Option<String>,
+}
+
+impl EventsConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.topic || self.webhook.is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct KafkaConfig {
+    /// The bootstrap brokers (`host:port`), disabled if empty
+    pub brokers: Vec<String>,
+    /// The client id sent to the brokers
+    pub client_id: String,
+    /// The messages are forwarded by the first matched rule
+    pub rules: Vec<KafkaRule>,
+    /// Max messages sent in one batch
+    pub batch_size: usize,
+    /// Milliseconds to wait for more messages before sending a batch
+    pub linger_ms: u64,
+    /// Timeout seconds of the requests, a message not acknowledged after
+    /// this is failed
+    pub timeout: u64,
+    /// Send the PUBACK of a QoS 1 message after it's acknowledged by Kafka
+    pub wait_ack: bool,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> KafkaConfig {
+        KafkaConfig {
+            brokers: Vec::new(),
+            client_id: "akasa".to_owned(),
+            rules: Vec::new(),
+            batch_size: 1000,
+            linger_ms: 5,
+            timeout: 10,
+            wait_ack: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct KafkaRule {
+    /// The topic filter matched by the topic name of the publish
+    pub topic_filter: String,
+    /// The Kafka topic, `%1`..`%9` are replaced by the levels of the topic name
+    pub kafka_topic: String,
+    /// The message key, `%1`..`%9` are replaced by the levels of the topic
+    /// name, no key if not set
+    pub key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct AmqpConfig {
+    /// The broker (`host:port`), disabled if not set
+    pub addr: Option<String>,
+    /// The hostname sent to the broker (the virtual host), the host of `addr`
+    /// if not set
+    pub hostname: Option<String>,
+    /// Authenticated by SASL PLAIN if set, otherwise SASL ANONYMOUS
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The container id sent to the broker
+    pub container_id: String,
+    /// The published messages are sent to the address of the first matched rule
+    pub forward: Vec<AmqpForwardRule>,
+    /// The messages received from the addresses are published to MQTT
+    pub consume: Vec<AmqpConsumeRule>,
+    /// The link credit of a consumed address (the messages in flight)
+    pub credit: u32,
+    /// Seconds to wait before reconnecting
+    pub reconnect_interval: u64,
+}
+
+impl Default for AmqpConfig {
+    fn default() -> AmqpConfig {
+        AmqpConfig {
+            addr: None,
+            hostname: None,
+            username: None,
+            password: None,
+            container_id: "akasa".to_owned(),
+            forward: Vec::new(),
+            consume: Vec::new(),
+            credit: 100,
+            reconnect_interval: 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct AmqpForwardRule {
+    /// The topic filter matched by the topic name of the publish
+    pub topic_filter: String,
+    /// The AMQP address (queue or topic)
+    pub address: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct WebhookForwardConfig {
+    /// The messages are forwarded by the first matched rule
+    pub rules: Vec<WebhookForwardRule>,
+    /// Max messages posted in one request (as a JSON array)
+    pub batch_size: usize,
+    /// Milliseconds to wait for more messages before posting a batch
+    pub linger_ms: u64,
+    /// Retry times of a failed request, the 4xx status (except 408 and 429)
+    /// is not retried
+    pub max_retries: u32,
+    /// Milliseconds to wait before the first retry, doubled every retry
+    pub retry_backoff_ms: u64,
+    /// Publish the failed batches to this topic, dropped if not set
+    pub dead_letter_topic: Option<String>,
+}
+
+impl Default for WebhookForwardConfig {
+    fn default() -> WebhookForwardConfig {
+        WebhookForwardConfig {
+            rules: Vec::new(),
+            batch_size: 100,
+            linger_ms: 100,
+            max_retries: 3,
+            retry_backoff_ms: 1000,
+            dead_letter_topic: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct WebhookForwardRule {
+    /// The topic filter matched by the topic name of the publish
+    pub topic_filter: String,
+    /// Only `http://` is supported, `%1`..`%9` are replaced by the
+    /// (percent-encoded) levels of the topic name
+    pub url: String,
+    /// The extra headers, `%1`..`%9` in the values are replaced by the levels
+    /// of the topic name
+    pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct AmqpConsumeRule {
+    /// The AMQP address (queue or subscription)
+    pub address: String,
+    /// Publish the received messages to this topic
+    pub topic: String,
+    pub qos: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct TsdbConfig {
+    /// The database, disabled if not set
+    pub sink: Option<TsdbSink>,
+    /// The messages are written by the first matched rule
+    pub rules: Vec<TsdbRule>,
+    /// Max points written in one request
+    pub batch_size: usize,
+    /// Milliseconds to wait for more points before writing a batch
+    pub linger_ms: u64,
+    /// Max points waiting to be written, the new points are dropped when
+    /// exceeded
+    pub max_pending: usize,
+    /// Seconds to wait before retrying a batch failed by a network error (or
+    /// a 5xx, 408 or 429 status of InfluxDB)
+    pub retry_interval: u64,
+}
+
+impl Default for TsdbConfig {
+    fn default() -> TsdbConfig {
+        TsdbConfig {
+            sink: None,
+            rules: Vec::new(),
+            batch_size: 1000,
+            linger_ms: 100,
+            max_pending: 100_000,
+            retry_interval: 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum TsdbSink {
+    /// InfluxDB 2.x (`/api/v2/write`), or 1.x (`/write`, the bucket is the
+    /// database) if `org` is not set. Only `http://` is supported, the token
+    /// is sent as `Authorization: Token <token>`.
+    Influx {
+        url: String,
+        org: Option<String>,
+        bucket: String,
+        token: Option<String>,
+    },
+    /// TimescaleDB (PostgreSQL), the points are inserted into the tables
+    /// named by the measurements (the columns are `time`, the tags and the
+    /// fields). The password is sent by SCRAM-SHA-256, MD5 or cleartext as
+    /// the server requires.
+    Timescale {
+        addr: String,
+        user: String,
+        password: Option<String>,
+        database: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct TsdbRule {
+    /// The topic filter matched by the topic name of the publish
+    pub topic_filter: String,
+    pub format: TsdbFormat,
+    /// The measurement of the JSON payloads, `%1`..`%9` are replaced by the
+    /// levels of the topic name
+    pub measurement: String,
+    /// The keys of the JSON payloads written as tags, the other numbers,
+    /// booleans and strings are fields
+    pub tags: Vec<String>,
+    /// The key of the timestamp (unix milliseconds) in the JSON payloads, the
+    /// received time if not set
+    pub timestamp_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TsdbFormat {
+    /// A JSON object
+    Json,
+    /// InfluxDB line protocol, the timestamps are in nanoseconds
+    LineProtocol,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct SparkplugConfig {
+    pub enable: bool,
+    /// The retained online states are published to
+    /// `{status_prefix}/{group_id}/{edge_node_id}[/{device_id}]`, not
+    /// published if not set
+    pub status_prefix: Option<String>,
+    /// Drop the NDATA/DDATA/DBIRTH/DDEATH messages out of sequence
+    pub drop_out_of_sequence: bool,
+    /// Send a `Node Control/Rebirth` NCMD to the edge node out of sequence
+    pub request_rebirth: bool,
+}
+
+impl Default for SparkplugConfig {
+    fn default() -> SparkplugConfig {
+        SparkplugConfig {
+            enable: false,
+            status_prefix: Some("$SYS/sparkplug".to_owned()),
+            drop_out_of_sequence: false,
+            request_rebirth: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct RuleConfig {
+    /// `SELECT <fields> FROM "<topic filter>"[, ...] [WHERE <condition>]`
+    pub sql: String,
+    /// The actions of the output (the selected fields as a JSON object)
+    pub actions: Vec<RuleAction>,
+}
+
+/// `${name}` in the templates is replaced by the field of the output
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub enum RuleAction {
+    /// Publish the output to the topic (a template)
+    Republish {
+        topic: String,
+        qos: u8,
+        retain: bool,
+    },
+    /// Post the output to the url (a template, the fields are
+    /// percent-encoded), in batches like `webhook_forward`
+    Webhook { url: String },
+    /// Send the output to the Kafka topic (a template) with the key (a
+    /// template, no key if not set)
+    Kafka { topic: String, key: Option<String> },
+    /// Drop the message, it's not delivered to the subscribers or forwarded
+    Drop,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct AuditConfig {
+    /// Append the records (JSON lines) to this file
+    pub file: Option<PathBuf>,
+    /// Publish the records to this topic (QoS 0)
+    pub topic: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct HookConfig {
     pub enable_before_connect: bool,
     pub enable_after_connect: bool,
@@ -152,6 +1356,10 @@ pub struct HookConfig {
     pub enable_publish: bool,
     pub enable_subscribe: bool,
     pub enable_unsubscribe: bool,
+    /// The packets of a connection queued while a hook request is in flight
+    /// (PINGREQ and acks are handled immediately), the connection stops
+    /// reading when full. 0 means stop reading until the hook finished.
+    pub max_queued_packets: usize,
 }
 
 impl Default for HookConfig {
@@ -163,6 +1371,7 @@ impl Default for HookConfig {
             enable_publish: true,
             enable_subscribe: true,
             enable_unsubscribe: true,
+            max_queued_packets: 16,
         }
     }
 }
@@ -170,10 +1379,13 @@ impl Default for HookConfig {
 impl Default for Config {
     fn default() -> Config {
         let config = Config {
+            log_level: Some("info".to_owned()),
+            executors: 0,
             listeners: Listeners::default(),
             auth: AuthConfig {
                 enable: true,
                 password_file: Some(PathBuf::from("/path/to/passwords/file")),
+                cloud_profiles: Vec::new(),
             },
             scram_users: vec![("user", (b"***", 4096, b"salt"))]
                 .into_iter()
@@ -191,15 +1403,21 @@ impl Default for Config {
             sasl_mechanisms: vec![SaslMechanism::ScramSha256].into_iter().collect(),
             shared_subscription_mode: SharedSubscriptionMode::Random,
             check_v310_client_id_length: false,
+            empty_client_id: EmptyClientIdConfig::default(),
+            takeover_policy: TakeoverPolicy::KickOld,
             max_allowed_qos: 2,
-            inflight_timeout: 15,
+            retransmit: RetransmitConfig::default(),
             max_inflight_client: 10,
             max_inflight_server: 10,
             max_in_mem_pending_messages: 256,
+            max_in_mem_pending_bytes: 0,
+            pending_overflow_policy: OverflowPolicy::DropNewest,
+            pending_limits: Vec::new(),
             max_in_db_pending_messages: 65536,
             min_keep_alive: 10,
             max_keep_alive: u16::max_value(),
             multiple_subscription_id_in_publish: false,
+            min_session_expiry_interval: 0,
             max_session_expiry_interval: u32::max_value(),
             max_packet_size_client: DEFAULT_MAX_PACKET_SIZE,
             max_packet_size_server: DEFAULT_MAX_PACKET_SIZE,
@@ -208,6 +1426,50 @@ impl Default for Config {
             shared_subscription_available: true,
             subscription_id_available: true,
             wildcard_subscription_available: true,
+            topic_limits: TopicLimitsConfig::default(),
+            reserved_topics: ReservedTopicsConfig::default(),
+            max_subscriptions: 0,
+            payload_limits: Vec::new(),
+            payload_schemas: Vec::new(),
+            validation_mode: ValidationMode::Strict,
+            retain_limits: RetainLimitsConfig::default(),
+            response_topic_prefix: None,
+
+            delayed_publish: DelayedPublishConfig::default(),
+            auto_subscriptions: Vec::new(),
+
+            slow_consumer: SlowConsumerConfig::default(),
+            write_batch: WriteBatchConfig::default(),
+            buffer_pool: BufferPoolConfig::default(),
+            broadcast: BroadcastConfig::default(),
+            channels: ChannelsConfig::default(),
+            qos0_shedding: Qos0SheddingConfig::default(),
+            memory_limit: MemoryLimitConfig::default(),
+            fan_out: FanOutConfig::default(),
+            throttle: ThrottleConfig::default(),
+            inbound_limit: InboundLimitConfig::default(),
+            connect_limit: ConnectLimitConfig::default(),
+            quotas: QuotasConfig::default(),
+            ban_file: None,
+            flapping: FlappingConfig::default(),
+            latency_metrics: false,
+            strict_diagnostics: false,
+
+            topic_stats: TopicStatsConfig::default(),
+            top_talkers: TopTalkersConfig::default(),
+            alarms: AlarmsConfig::default(),
+            redirect: None,
+
+            admin: None,
+            grpc: None,
+            audit: None,
+            events: EventsConfig::default(),
+            kafka: KafkaConfig::default(),
+            amqp: AmqpConfig::default(),
+            webhook_forward: WebhookForwardConfig::default(),
+            tsdb: TsdbConfig::default(),
+            sparkplug: SparkplugConfig::default(),
+            rules: Vec::new(),
 
             hook: HookConfig::default(),
         };
@@ -222,6 +1484,7 @@ impl Config {
             auth: AuthConfig {
                 enable: false,
                 password_file: None,
+                cloud_profiles: Vec::new(),
             },
             ..Default::default()
         }
@@ -229,28 +1492,117 @@ impl Config {
 
     /// Check if the config is valid
     pub fn is_valid(&self) -> bool {
+        if let Some(level) = self.log_level.as_ref() {
+            if LevelFilter::from_str(level).is_err() {
+                tracing::error!("invalid log_level: {}", level);
+                return false;
+            }
+        }
         if self.auth.enable && self.auth.password_file.is_none() {
-            log::error!("when authentication enabled, `password_file` must be provided");
+            tracing::error!("when authentication enabled, `password_file` must be provided");
             return false;
         }
+        for profile in &self.auth.cloud_profiles {
+            let valid = match profile {
+                CloudAuthProfile::AzureIotHub { host_name, key, .. } => {
+                    !host_name.is_empty() && STANDARD.decode(key).is_ok_and(|key| !key.is_empty())
+                }
+                CloudAuthProfile::GoogleCloudIot {
+                    project_id,
+                    max_lifetime,
+                    ..
+                } => !project_id.is_empty() && *max_lifetime > 0,
+            };
+            if !valid {
+                tracing::error!("invalid auth cloud profile: {:?}", profile);
+                return false;
+            }
+        }
         if self.max_allowed_qos > 2 {
-            log::error!(
+            tracing::error!(
                 "invalid max_allowed_qos: {}, allowed values: [0, 1, 2]",
                 self.max_allowed_qos
             );
             return false;
         }
+        if self.min_session_expiry_interval > self.max_session_expiry_interval {
+            tracing::error!(
+                "invalid session expiry interval range: [{}, {}]",
+                self.min_session_expiry_interval,
+                self.max_session_expiry_interval
+            );
+            return false;
+        }
+        let retransmit = &self.retransmit;
+        if retransmit.initial_timeout == 0
+            || retransmit.backoff_factor == 0
+            || retransmit.max_timeout < retransmit.initial_timeout
+        {
+            tracing::error!(
+                "invalid retransmit: {:?}, the timeouts and backoff_factor must not be 0, and max_timeout must not less than initial_timeout",
+                retransmit
+            );
+            return false;
+        }
+        let listeners = &self.listeners;
+        let listener_max_qos = [
+            listeners.mqtt.as_ref().and_then(|l| l.max_qos),
+            listeners.mqtts.as_ref().and_then(|l| l.max_qos),
+            listeners.ws.as_ref().and_then(|l| l.max_qos),
+            listeners.wss.as_ref().and_then(|l| l.max_qos),
+        ];
+        if let Some(value) = listener_max_qos.into_iter().flatten().find(|v| *v > 2) {
+            tracing::error!(
+                "invalid listener max_qos: {}, allowed values: [0, 1, 2]",
+                value
+            );
+            return false;
+        }
         if self.max_packet_size_client == 0 {
-            log::error!("invalid client max_packet_size, 0 is not allowed");
+            tracing::error!("invalid client max_packet_size, 0 is not allowed");
             return false;
         }
         if self.max_packet_size_server == 0 {
-            log::error!("invalid server max_packet_size, 0 is not allowed");
+            tracing::error!("invalid server max_packet_size, 0 is not allowed");
+            return false;
+        }
+        if self.write_batch.max_packets == 0 || self.write_batch.max_bytes == 0 {
+            tracing::error!("invalid write_batch, 0 is not allowed");
+            return false;
+        }
+        if self.broadcast.max_messages == 0 || self.broadcast.max_messages_per_receiver == 0 {
+            tracing::error!("invalid broadcast, 0 is not allowed");
+            return false;
+        }
+        if self.channels.normal == 0 || self.channels.control == 0 {
+            tracing::error!("invalid channels, 0 is not allowed");
+            return false;
+        }
+        if self.quotas.reset_hour > 23 || self.quotas.save_interval == 0 {
+            tracing::error!(
+                "invalid quotas, reset_hour must be in 0..=23 and save_interval can't be 0"
+            );
+            return false;
+        }
+        if self.flapping.max_connects > 0
+            && (self.flapping.window == 0
+                || self.flapping.ban_secs == 0
+                || self.flapping.max_ban_secs < self.flapping.ban_secs)
+        {
+            tracing::error!("invalid flapping, window and ban_secs can't be 0, max_ban_secs can't be less than ban_secs");
+            return false;
+        }
+        if self.fan_out.workers == 0 || self.fan_out.max_queued_jobs == 0 {
+            tracing::error!("invalid fan_out, 0 is not allowed");
+            return false;
+        }
+        if self.memory_limit.pressure_percent == 0 || self.memory_limit.pressure_percent > 100 {
+            tracing::error!("invalid memory_limit.pressure_percent, must be in 1..=100");
             return false;
         }
         for mechanism in &self.sasl_mechanisms {
             if mechanism != &SaslMechanism::ScramSha256 {
-                log::error!("invalid sasl_mechanism, only `SCRAM-SHA-256` is allowed");
+                tracing::error!("invalid sasl_mechanism, only `SCRAM-SHA-256` is allowed");
                 return false;
             }
         }
@@ -259,10 +1611,286 @@ impl Config {
                 // RFC-7677: For the SCRAM-SHA-256 and SCRAM-SHA-256-PLUS SASL
                 // mechanisms, the hash iteration-count announced by a server
                 // SHOULD be at least 4096.
-                log::error!("scram_users password iterations must >= 4096 (see RFC-7677)");
+                tracing::error!("scram_users password iterations must >= 4096 (see RFC-7677)");
+                return false;
+            }
+        }
+        for prefix in &self.topic_stats.prefixes {
+            if prefix.is_empty() || prefix.starts_with('$') || prefix.contains(['+', '#']) {
+                tracing::error!("invalid topic_stats prefix: {:?}", prefix);
+                return false;
+            }
+        }
+        if self.top_talkers.count > 0 && self.top_talkers.interval == 0 {
+            tracing::error!("top_talkers interval must be greater than 0");
+            return false;
+        }
+        if self.alarms.interval == 0 {
+            tracing::error!("alarms interval must be greater than 0");
+            return false;
+        }
+        if let Some(prefix) = self.response_topic_prefix.as_ref() {
+            if prefix.is_empty()
+                || prefix.starts_with('$')
+                || prefix.ends_with('/')
+                || TopicName::try_from(prefix.clone()).is_err()
+            {
+                tracing::error!("invalid response_topic_prefix: {:?}", prefix);
+                return false;
+            }
+        }
+        for limit in &self.payload_limits {
+            if TopicFilter::try_from(limit.topic_filter.clone()).is_err() {
+                tracing::error!(
+                    "invalid payload limit topic filter: {:?}",
+                    limit.topic_filter
+                );
+                return false;
+            }
+        }
+        for schema in &self.payload_schemas {
+            if TopicFilter::try_from(schema.topic_filter.clone()).is_err() {
+                tracing::error!(
+                    "invalid payload schema topic filter: {:?}",
+                    schema.topic_filter
+                );
+                return false;
+            }
+            if let Some(topic) = schema.dead_letter_topic.as_ref() {
+                if TopicName::try_from(topic.clone()).is_err() {
+                    tracing::error!("invalid payload schema dead_letter_topic: {:?}", topic);
+                    return false;
+                }
+            }
+            if let Err(err) = compile_schema(&schema.format) {
+                tracing::error!(
+                    "invalid payload schema of {:?}: {}",
+                    schema.topic_filter,
+                    err
+                );
+                return false;
+            }
+        }
+        for topic in self
+            .auto_subscriptions
+            .iter()
+            .flat_map(|rule| rule.topics.iter())
+        {
+            let topic_filter = topic.topic_filter.replace("%c", "c").replace("%u", "u");
+            if TopicFilter::try_from(topic_filter).is_err() {
+                tracing::error!(
+                    "invalid auto subscription topic filter: {:?}",
+                    topic.topic_filter
+                );
+                return false;
+            }
+            if topic.qos > 2 {
+                tracing::error!("invalid auto subscription qos: {}", topic.qos);
+                return false;
+            }
+        }
+        if self
+            .pending_limits
+            .iter()
+            .any(|rule| rule.max_messages == Some(0) || rule.max_inflight == Some(0))
+        {
+            tracing::error!("pending_limits max_messages and max_inflight must be greater than 0");
+            return false;
+        }
+        if let Some(prefix) = self
+            .reserved_topics
+            .prefixes
+            .iter()
+            .find(|prefix| !prefix.starts_with('$') || prefix.len() < 2)
+        {
+            tracing::error!("invalid reserved topic prefix: {:?}", prefix);
+            return false;
+        }
+        if self
+            .reserved_topics
+            .exceptions
+            .iter()
+            .any(|rule| rule.username.as_ref().map_or(true, String::is_empty))
+        {
+            tracing::error!("reserved topic exception requires username");
+            return false;
+        }
+        if let Some(redirect) = self.redirect.as_ref() {
+            if redirect.server_reference.is_empty() {
+                tracing::error!("redirect server_reference is empty");
+                return false;
+            }
+        }
+        if let Some(admin) = self.admin.as_ref() {
+            for (name, token) in [
+                ("token", &admin.token),
+                ("publish_token", &admin.publish_token),
+                ("stream_token", &admin.stream_token),
+            ] {
+                if let Some(token) = token {
+                    if token.is_empty() || token.contains(|c: char| c.is_control()) {
+                        tracing::error!("invalid admin {}", name);
+                        return false;
+                    }
+                }
+            }
+        }
+        if cfg!(not(feature = "grpc")) && self.grpc.is_some() {
+            tracing::error!("grpc requires the `grpc` feature");
+            return false;
+        }
+        if let Some(token) = self.grpc.as_ref().and_then(|grpc| grpc.token.as_ref()) {
+            if token.is_empty() || token.contains(|c: char| c.is_control()) {
+                tracing::error!("invalid grpc token");
+                return false;
+            }
+        }
+        if let Some(audit) = self.audit.as_ref() {
+            if audit.file.is_none() && audit.topic.is_none() {
+                tracing::error!("audit log enabled, but neither `file` nor `topic` is provided");
+                return false;
+            }
+            if let Some(topic) = audit.topic.as_ref() {
+                if TopicName::try_from(topic.clone()).is_err() {
+                    tracing::error!("invalid audit topic: {:?}", topic);
+                    return false;
+                }
+            }
+        }
+        if let Some(webhook) = self.events.webhook.as_ref() {
+            if HttpUrl::parse(webhook).is_none() {
+                tracing::error!("invalid events webhook url: {:?}", webhook);
+                return false;
+            }
+        }
+        let kafka = &self.kafka;
+        if cfg!(not(feature = "kafka")) && !kafka.brokers.is_empty() {
+            tracing::error!("kafka requires the `kafka` feature");
+            return false;
+        }
+        if !kafka.brokers.is_empty() && (kafka.batch_size == 0 || kafka.timeout == 0) {
+            tracing::error!("invalid kafka, batch_size and timeout can't be 0");
+            return false;
+        }
+        for rule in &kafka.rules {
+            if TopicFilter::try_from(rule.topic_filter.clone()).is_err() {
+                tracing::error!("invalid kafka rule topic filter: {:?}", rule.topic_filter);
+                return false;
+            }
+        }
+        if cfg!(not(feature = "amqp")) && self.amqp.addr.is_some() {
+            tracing::error!("amqp requires the `amqp` feature");
+            return false;
+        }
+        if self.amqp.addr.is_some() && self.amqp.credit == 0 {
+            tracing::error!("invalid amqp credit, can't be 0");
+            return false;
+        }
+        for rule in &self.amqp.forward {
+            if TopicFilter::try_from(rule.topic_filter.clone()).is_err() || rule.address.is_empty()
+            {
+                tracing::error!("invalid amqp forward rule: {:?}", rule);
                 return false;
             }
         }
+        for rule in &self.amqp.consume {
+            if TopicName::try_from(rule.topic.clone()).is_err()
+                || rule.address.is_empty()
+                || rule.qos > 2
+            {
+                tracing::error!("invalid amqp consume rule: {:?}", rule);
+                return false;
+            }
+        }
+        let webhook_forward = &self.webhook_forward;
+        if cfg!(not(feature = "webhook")) && !webhook_forward.rules.is_empty() {
+            tracing::error!("webhook_forward requires the `webhook` feature");
+            return false;
+        }
+        if webhook_forward.batch_size == 0 {
+            tracing::error!("invalid webhook_forward batch_size, can't be 0");
+            return false;
+        }
+        for rule in &webhook_forward.rules {
+            let valid_headers = rule.headers.iter().all(|(name, value)| {
+                !name.is_empty()
+                    && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+                    && !value.contains(|c: char| c.is_control())
+            });
+            if TopicFilter::try_from(rule.topic_filter.clone()).is_err()
+                || HttpUrl::parse(&rule.url).is_none()
+                || !valid_headers
+            {
+                tracing::error!("invalid webhook_forward rule: {:?}", rule);
+                return false;
+            }
+        }
+        if let Some(topic) = webhook_forward.dead_letter_topic.as_ref() {
+            if TopicName::try_from(topic.clone()).is_err() {
+                tracing::error!("invalid webhook_forward dead_letter_topic: {:?}", topic);
+                return false;
+            }
+        }
+        let tsdb = &self.tsdb;
+        if cfg!(not(feature = "tsdb")) && tsdb.sink.is_some() {
+            tracing::error!("tsdb requires the `tsdb` feature");
+            return false;
+        }
+        let valid_sink = match tsdb.sink.as_ref() {
+            Some(TsdbSink::Influx { url, bucket, .. }) => {
+                HttpUrl::parse(url).is_some() && !bucket.is_empty()
+            }
+            Some(TsdbSink::Timescale {
+                addr,
+                user,
+                database,
+                ..
+            }) => !addr.is_empty() && !user.is_empty() && !database.is_empty(),
+            None => true,
+        };
+        if !valid_sink || tsdb.batch_size == 0 || tsdb.max_pending == 0 {
+            tracing::error!("invalid tsdb, invalid sink or batch_size/max_pending is 0");
+            return false;
+        }
+        for rule in &tsdb.rules {
+            if TopicFilter::try_from(rule.topic_filter.clone()).is_err()
+                || (rule.format == TsdbFormat::Json && rule.measurement.is_empty())
+            {
+                tracing::error!("invalid tsdb rule: {:?}", rule);
+                return false;
+            }
+        }
+        if let Some(prefix) = self.sparkplug.status_prefix.as_ref() {
+            if prefix.is_empty() || TopicName::try_from(format!("{}/x", prefix)).is_err() {
+                tracing::error!("invalid sparkplug status_prefix: {:?}", prefix);
+                return false;
+            }
+        }
+        for rule in &self.rules {
+            if let Err(err) = Query::parse(&rule.sql) {
+                tracing::error!("invalid rule {:?}: {}", rule.sql, err);
+                return false;
+            }
+            for action in &rule.actions {
+                // Validate the templates with placeholder fields
+                let valid = match action {
+                    RuleAction::Republish { topic, qos, .. } => {
+                        *qos <= 2
+                            && TopicName::try_from(render_fields(topic, |_| "x".to_owned())).is_ok()
+                    }
+                    RuleAction::Webhook { url } => {
+                        cfg!(feature = "webhook")
+                            && HttpUrl::parse(&render_fields(url, |_| "x".to_owned())).is_some()
+                    }
+                    RuleAction::Kafka { .. } => !self.kafka.brokers.is_empty(),
+                    RuleAction::Drop => true,
+                };
+                if !valid {
+                    tracing::error!("invalid action of rule {:?}: {:?}", rule.sql, action);
+                    return false;
+                }
+            }
+        }
         if let Listeners {
             mqtt: None,
             mqtts: None,
@@ -270,18 +1898,160 @@ impl Config {
             wss: None,
         } = self.listeners
         {
-            log::error!("No listen address found");
+            tracing::error!("No listen address found");
             return false;
         }
         true
     }
 
+    /// Compare current config with the new config, return the changed fields.
+    pub fn changes(&self, new: &Config) -> ConfigChanges {
+        macro_rules! changed_fields {
+            ($($field:ident),* $(,)?) => {
+                [$((stringify!($field), self.$field != new.$field)),*]
+            };
+        }
+        let mut changes = ConfigChanges::default();
+        for (name, changed) in changed_fields!(
+            log_level,
+            executors,
+            listeners,
+            auth,
+            scram_users,
+            sasl_mechanisms,
+            check_v310_client_id_length,
+            empty_client_id,
+            takeover_policy,
+            shared_subscription_mode,
+            max_allowed_qos,
+            retransmit,
+            max_inflight_client,
+            max_inflight_server,
+            max_in_mem_pending_messages,
+            max_in_mem_pending_bytes,
+            pending_overflow_policy,
+            pending_limits,
+            max_in_db_pending_messages,
+            min_keep_alive,
+            max_keep_alive,
+            multiple_subscription_id_in_publish,
+            min_session_expiry_interval,
+            max_session_expiry_interval,
+            max_packet_size_client,
+            max_packet_size_server,
+            topic_alias_max,
+            retain_available,
+            shared_subscription_available,
+            subscription_id_available,
+            wildcard_subscription_available,
+            topic_limits,
+            reserved_topics,
+            max_subscriptions,
+            payload_limits,
+            payload_schemas,
+            validation_mode,
+            retain_limits,
+            response_topic_prefix,
+            delayed_publish,
+            auto_subscriptions,
+            slow_consumer,
+            write_batch,
+            buffer_pool,
+            broadcast,
+            channels,
+            qos0_shedding,
+            memory_limit,
+            throttle,
+            inbound_limit,
+            connect_limit,
+            ban_file,
+            flapping,
+            latency_metrics,
+            strict_diagnostics,
+            topic_stats,
+            top_talkers,
+            alarms,
+            redirect,
+            admin,
+            grpc,
+            audit,
+            events,
+            kafka,
+            amqp,
+            webhook_forward,
+            tsdb,
+            sparkplug,
+            rules,
+            hook,
+        ) {
+            if changed {
+                changes.push(name);
+            }
+        }
+        // The fields partly read when started are compared by their fields
+        macro_rules! changed_nested_fields {
+            ($parent:ident: $($field:ident),* $(,)?) => {
+                [$((
+                    concat!(stringify!($parent), ".", stringify!($field)),
+                    self.$parent.$field != new.$parent.$field,
+                )),*]
+            };
+        }
+        for (name, changed) in
+            changed_nested_fields!(fan_out: min_receivers, workers, max_queued_jobs)
+                .into_iter()
+                .chain(changed_nested_fields!(quotas: rules, reset_hour, state_file, save_interval))
+        {
+            if changed {
+                changes.push(name);
+            }
+        }
+        changes
+    }
+
     pub fn max_allowed_qos(&self) -> QoS {
-        match self.max_allowed_qos {
-            0 => QoS::Level0,
-            1 => QoS::Level1,
-            2 => QoS::Level2,
-            value => panic!("invalid Config.max_allowed_qos: {value}"),
+        qos_from_value(self.max_allowed_qos)
+    }
+
+    /// The published messages may be forwarded to Kafka, the AMQP broker,
+    /// the webhooks or the time-series database
+    pub fn has_forwarders(&self) -> bool {
+        (!self.kafka.brokers.is_empty() && !self.kafka.rules.is_empty())
+            || (self.amqp.addr.is_some() && !self.amqp.forward.is_empty())
+            || !self.webhook_forward.rules.is_empty()
+            || (self.tsdb.sink.is_some() && !self.tsdb.rules.is_empty())
+    }
+}
+
+/// Convert a validated QoS config value
+pub(crate) fn qos_from_value(value: u8) -> QoS {
+    match value {
+        0 => QoS::Level0,
+        1 => QoS::Level1,
+        2 => QoS::Level2,
+        value => panic!("invalid QoS config value: {value}"),
+    }
+}
+
+/// The result of a config reload
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigChanges {
+    /// Changed fields already applied
+    pub applied: Vec<&'static str>,
+    /// Changed fields only take effect after restart
+    pub restart_required: Vec<&'static str>,
+}
+
+impl ConfigChanges {
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.restart_required.is_empty()
+    }
+
+    fn push(&mut self, name: &'static str) {
+        if RESTART_REQUIRED_FIELDS.contains(&name) {
+            self.restart_required.push(name);
+        } else {
+            self.applied.push(name);
         }
     }
 }
@@ -312,3 +2082,40 @@ impl SaslMechanism {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_retransmit() {
+        let mut value = serde_json::to_value(Config::default()).unwrap();
+        let fields = value.as_object_mut().unwrap();
+        fields.remove("retransmit");
+        let config: Config = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(config.retransmit, RetransmitConfig::default());
+        assert_eq!(config.retransmit.timeout(3), 15);
+
+        let fields = value.as_object_mut().unwrap();
+        fields.insert("inflight_timeout".to_owned(), 30.into());
+        let config: Config = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(config.retransmit, RetransmitConfig::fixed(30));
+        assert_eq!(config.retransmit.timeout(3), 30);
+        assert!(config.is_valid());
+
+        let fields = value.as_object_mut().unwrap();
+        fields.remove("inflight_timeout");
+        fields.insert(
+            "retransmit".to_owned(),
+            serde_json::json!({
+                "initial_timeout": 10,
+                "backoff_factor": 2,
+                "max_timeout": 30,
+                "max_retries": 3,
+            }),
+        );
+        let config: Config = serde_json::from_value(value).unwrap();
+        assert_eq!(config.retransmit.timeout(1), 20);
+        assert_eq!(config.retransmit.timeout(2), 30);
+    }
+}
@@ -0,0 +1,181 @@
+//! Delayed publish (`$delayed/{seconds}/{topic}`, see `Config.delayed_publish`)
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mqtt_proto::TopicName;
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use crate::publish::{self, ServerMessage};
+use crate::state::GlobalState;
+
+pub(crate) const DELAYED_PREFIX: &str = "$delayed/";
+
+/// A message waiting to be published to the real topic
+#[derive(Debug, Clone)]
+pub(crate) struct DelayedMessage {
+    pub message: ServerMessage,
+    // The Message Expiry Interval is reduced by the time waited since received
+    pub received_at: Instant,
+}
+
+/// The scheduled messages ordered by the publish time
+#[derive(Default)]
+pub(crate) struct DelayedQueue {
+    // (next sequence, (publish time, sequence) => message)
+    messages: Mutex<(u64, BTreeMap<(Instant, u64), DelayedMessage>)>,
+    // Wake up the publish task when an earlier message is scheduled
+    notify: Notify,
+}
+
+impl DelayedQueue {
+    /// Schedule a message, return false if the queue is full
+    pub(crate) fn push(&self, delay: u32, message: DelayedMessage, max_messages: usize) -> bool {
+        let publish_time = Instant::now() + Duration::from_secs(delay as u64);
+        let earliest = {
+            let mut guard = self.messages.lock();
+            let (next_seq, messages) = &mut *guard;
+            if messages.len() >= max_messages {
+                return false;
+            }
+            let earliest = match messages.keys().next() {
+                Some((time, _)) => publish_time < *time,
+                None => true,
+            };
+            messages.insert((publish_time, *next_seq), message);
+            *next_seq += 1;
+            earliest
+        };
+        if earliest {
+            self.notify.notify_one();
+        }
+        true
+    }
+
+    // Take the due messages, also return the publish time of the next message
+    fn pop_due(&self, now: Instant) -> (Vec<DelayedMessage>, Option<Instant>) {
+        let mut guard = self.messages.lock();
+        let messages = &mut guard.1;
+        let pending = messages.split_off(&(now, u64::MAX));
+        let due = mem::replace(messages, pending);
+        let next_time = messages.keys().next().map(|(time, _)| *time);
+        (due.into_values().collect(), next_time)
+    }
+}
+
+/// Parse `$delayed/{seconds}/{topic}` as the delay seconds and the real topic name
+pub(crate) fn parse_delayed_topic(topic_name: &str, max_delay: u32) -> Option<(u32, TopicName)> {
+    let (delay, topic) = topic_name.strip_prefix(DELAYED_PREFIX)?.split_once('/')?;
+    let delay = delay
+        .parse::<u32>()
+        .ok()
+        .filter(|delay| *delay <= max_delay)?;
+    if topic.is_empty() || topic.starts_with('$') {
+        return None;
+    }
+    let topic_name = TopicName::try_from(topic.to_owned()).ok()?;
+    Some((delay, topic_name))
+}
+
+/// The real topic of a delayed publish, the publish hooks check it instead of
+/// the `$delayed/{seconds}/{topic}` topic name.
+pub(crate) fn target_topic(global: &GlobalState, topic_name: &str) -> Option<TopicName> {
+    let config = global.config();
+    if !config.delayed_publish.enable || !topic_name.starts_with(DELAYED_PREFIX) {
+        return None;
+    }
+    parse_delayed_topic(topic_name, config.delayed_publish.max_delay).map(|(_, topic)| topic)
+}
+
+/// Publish the delayed messages when they are due
+pub(crate) async fn run(global: Arc<GlobalState>) {
+    let queue = &global.delayed_queue;
+    loop {
+        let (due, next_time) = queue.pop_due(Instant::now());
+        for delayed in due {
+            let mut message = delayed.message;
+            if let Some(interval) = message.properties.message_expiry_interval {
+                let waited = delayed.received_at.elapsed().as_secs();
+                if waited >= interval as u64 {
+                    tracing::debug!("delayed message expired: {}", message.topic_name);
+                    continue;
+                }
+                message.properties.message_expiry_interval = Some(interval - waited as u32);
+            }
+            if let Err(err) = publish::publish(&global, message).await {
+                tracing::warn!("publish delayed message error: {}", err);
+            }
+        }
+        // The permit is kept if a message is scheduled before waiting
+        let notified = queue.notify.notified();
+        match next_time {
+            Some(time) => {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(time.into()) => {}
+                    _ = notified => {}
+                }
+            }
+            None => notified.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use mqtt_proto::QoS;
+
+    use super::*;
+
+    fn message(topic: &str) -> DelayedMessage {
+        DelayedMessage {
+            message: ServerMessage {
+                client_identifier: Arc::new("client".to_owned()),
+                username: None,
+                topic_name: TopicName::try_from(topic.to_owned()).unwrap(),
+                qos: QoS::Level0,
+                retain: false,
+                payload: Bytes::new(),
+                properties: Default::default(),
+            },
+            received_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_parse_delayed_topic() {
+        let (delay, topic_name) = parse_delayed_topic("$delayed/10/a/b", 60).unwrap();
+        assert_eq!(delay, 10);
+        assert_eq!(&*topic_name, "a/b");
+        assert!(parse_delayed_topic("$delayed/61/a/b", 60).is_none());
+        assert!(parse_delayed_topic("$delayed/x/a/b", 60).is_none());
+        assert!(parse_delayed_topic("$delayed/10", 60).is_none());
+        assert!(parse_delayed_topic("$delayed/10/", 60).is_none());
+        assert!(parse_delayed_topic("$delayed/10/$SYS/a", 60).is_none());
+        assert!(parse_delayed_topic("$delayed/10/a/+", 60).is_none());
+        assert!(parse_delayed_topic("a/b", 60).is_none());
+    }
+
+    #[test]
+    fn test_delayed_queue() {
+        let queue = DelayedQueue::default();
+        assert!(queue.push(20, message("a/2"), 3));
+        assert!(queue.push(10, message("a/1"), 3));
+        assert!(queue.push(0, message("a/0"), 3));
+        assert!(!queue.push(0, message("a/3"), 3));
+
+        let (due, next_time) = queue.pop_due(Instant::now() + Duration::from_secs(15));
+        let topics: Vec<_> = due
+            .iter()
+            .map(|msg| msg.message.topic_name.to_string())
+            .collect();
+        assert_eq!(topics, vec!["a/0", "a/1"]);
+        assert!(next_time.is_some());
+        let (due, next_time) = queue.pop_due(Instant::now() + Duration::from_secs(30));
+        assert_eq!(due.len(), 1);
+        assert!(next_time.is_none());
+    }
+}
@@ -0,0 +1,281 @@
+//! The protocol conformance diagnostics (see `Config.strict_diagnostics`).
+//! When a client is disconnected by a protocol error, the violated MQTT
+//! specification rule is recorded to the log (target `akasa::diagnostics`) and
+//! published to `$SYS/diagnostics`, so the non-conforming clients can be
+//! debugged without capturing the traffic.
+
+use std::io;
+use std::net::SocketAddr;
+
+use mqtt_proto::{QoS, TopicName};
+use serde::Serialize;
+
+use crate::error::Error;
+use crate::state::GlobalState;
+
+/// The known protocol errors (by the reason): the packet type, the field and
+/// the normative statement of the specification (if there is one).
+const RULES: &[(&str, &str, &str, Option<&str>)] = &[
+    (
+        "first packet is not CONNECT",
+        "CONNECT",
+        "packet type",
+        Some("MQTT-3.1.0-1"),
+    ),
+    (
+        "invalid protocol name",
+        "CONNECT",
+        "protocol name",
+        Some("MQTT-3.1.2-1"),
+    ),
+    (
+        "unsupported protocol",
+        "CONNECT",
+        "protocol level",
+        Some("MQTT-3.1.2-2"),
+    ),
+    (
+        "empty will topic name",
+        "CONNECT",
+        "will topic",
+        Some("MQTT-4.7.3-1"),
+    ),
+    (
+        "will topic name starts with $",
+        "CONNECT",
+        "will topic",
+        None,
+    ),
+    (
+        "auth method not presented in CONNECT",
+        "AUTH",
+        "authentication method",
+        None,
+    ),
+    (
+        "auth method not same with CONNECT",
+        "AUTH",
+        "authentication method",
+        None,
+    ),
+    ("invalid auth reason code", "AUTH", "reason code", None),
+    (
+        "empty topic name",
+        "PUBLISH",
+        "topic name",
+        Some("MQTT-4.7.3-1"),
+    ),
+    ("invalid topic name", "PUBLISH", "topic name", None),
+    ("invalid delayed topic name", "PUBLISH", "topic name", None),
+    (
+        "invalid dup flag",
+        "PUBLISH",
+        "dup flag",
+        Some("MQTT-3.3.1-2"),
+    ),
+    (
+        "invalid dup flag in qos0 message",
+        "PUBLISH",
+        "dup flag",
+        Some("MQTT-3.3.1-2"),
+    ),
+    (
+        "dup flag must be true",
+        "PUBLISH",
+        "dup flag",
+        Some("MQTT-3.3.1-1"),
+    ),
+    (
+        "dup flag must be true for re-deliver packet",
+        "PUBLISH",
+        "dup flag",
+        Some("MQTT-3.3.1-1"),
+    ),
+    (
+        "packet identifier in use",
+        "PUBLISH",
+        "packet identifier",
+        None,
+    ),
+    (
+        "too many inflight qos2 message",
+        "PUBLISH",
+        "packet identifier",
+        None,
+    ),
+    (
+        "QoS is greater than the Maximum QoS",
+        "PUBLISH",
+        "qos",
+        None,
+    ),
+    (
+        "retained message is not supported",
+        "PUBLISH",
+        "retain",
+        None,
+    ),
+    (
+        "topic alias is 0",
+        "PUBLISH",
+        "topic alias",
+        Some("MQTT-3.3.2-8"),
+    ),
+    (
+        "topic alias too large",
+        "PUBLISH",
+        "topic alias",
+        Some("MQTT-3.3.2-9"),
+    ),
+    ("topic alias not found", "PUBLISH", "topic alias", None),
+    (
+        "subscription identifier can't in publish",
+        "PUBLISH",
+        "subscription identifier",
+        Some("MQTT-3.3.4-6"),
+    ),
+    (
+        "packet identifier not found",
+        "PUBREL",
+        "packet identifier",
+        None,
+    ),
+    (
+        "shared subscription not supported",
+        "SUBSCRIBE",
+        "topic filter",
+        None,
+    ),
+    (
+        "SessionExpiryInterval is 0 in CONNECT",
+        "DISCONNECT",
+        "session expiry interval",
+        None,
+    ),
+    ("Packet too large", "-", "remaining length", None),
+];
+
+/// A protocol violation of a client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Diagnostic {
+    /// Not known if the CONNECT packet is not accepted
+    pub client_identifier: Option<String>,
+    pub peer: SocketAddr,
+    pub protocol: String,
+    pub packet: &'static str,
+    pub field: &'static str,
+    pub rule: Option<&'static str>,
+    pub reason: String,
+}
+
+impl Diagnostic {
+    pub fn new(
+        client_identifier: Option<&str>,
+        peer: SocketAddr,
+        protocol: String,
+        reason: &str,
+    ) -> Diagnostic {
+        let (packet, field, rule) = RULES
+            .iter()
+            .find(|(known_reason, ..)| *known_reason == reason)
+            .map(|(_, packet, field, rule)| (*packet, *field, *rule))
+            .unwrap_or(("unknown", "unknown", None));
+        Diagnostic {
+            client_identifier: client_identifier.map(ToOwned::to_owned),
+            peer,
+            protocol,
+            packet,
+            field,
+            rule,
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+/// Record the violation if the diagnostics mode is enabled
+pub(crate) fn record(
+    global: &GlobalState,
+    client_identifier: Option<&str>,
+    peer: SocketAddr,
+    protocol: String,
+    reason: &str,
+) {
+    if !global.config().strict_diagnostics {
+        return;
+    }
+    let diagnostic = Diagnostic::new(client_identifier, peer, protocol, reason);
+    tracing::warn!(
+        target: "akasa::diagnostics",
+        client_identifier = diagnostic.client_identifier.as_deref().unwrap_or(""),
+        peer = %diagnostic.peer,
+        protocol = diagnostic.protocol,
+        packet = diagnostic.packet,
+        field = diagnostic.field,
+        rule = diagnostic.rule.unwrap_or(""),
+        "protocol violation: {}",
+        diagnostic.reason,
+    );
+    let payload = match serde_json::to_vec(&diagnostic) {
+        Ok(payload) => payload,
+        Err(err) => {
+            tracing::error!("encode diagnostic error: {}", err);
+            return;
+        }
+    };
+    let topic_name = TopicName::try_from("$SYS/diagnostics".to_owned()).expect("topic name");
+    if let Err(err) = global.publish(
+        topic_name,
+        QoS::Level0,
+        false,
+        payload.into(),
+        Default::default(),
+    ) {
+        tracing::warn!("publish diagnostic error: {}", err);
+    }
+}
+
+/// Record the error if it's a protocol violation
+pub(crate) fn record_error(
+    global: &GlobalState,
+    client_identifier: Option<&str>,
+    peer: SocketAddr,
+    protocol: String,
+    err: &io::Error,
+) {
+    match Error::from_io(err) {
+        Some(Error::Protocol(reason)) | Some(Error::MalformedPacket(reason)) => {
+            record(global, client_identifier, peer, protocol, reason);
+        }
+        Some(Error::PacketTooLarge(_)) => {
+            record(
+                global,
+                client_identifier,
+                peer,
+                protocol,
+                "Packet too large",
+            );
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnostic_rule() {
+        let peer = ([127, 0, 0, 1], 1883).into();
+        let diagnostic =
+            Diagnostic::new(Some("client"), peer, "V311".to_owned(), "invalid dup flag");
+        assert_eq!(diagnostic.packet, "PUBLISH");
+        assert_eq!(diagnostic.field, "dup flag");
+        assert_eq!(diagnostic.rule, Some("MQTT-3.3.1-2"));
+
+        let diagnostic = Diagnostic::new(None, peer, "V500".to_owned(), "invalid remaining length");
+        assert_eq!(diagnostic.client_identifier, None);
+        assert_eq!(diagnostic.packet, "unknown");
+        assert_eq!(diagnostic.rule, None);
+        assert_eq!(diagnostic.reason, "invalid remaining length");
+    }
+}
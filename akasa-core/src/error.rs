@@ -0,0 +1,91 @@
+//! The errors of the MQTT connections. They are converted to `io::Error` at
+//! the edge (the connection handlers still return `io::Result`), and can be
+//! recovered by `Error::from_io`.
+
+use std::io;
+
+use mqtt_proto::v5::DisconnectReasonCode;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Error {
+    #[error("malformed packet: {0}")]
+    MalformedPacket(String),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("packet too large: {0} bytes")]
+    PacketTooLarge(usize),
+    /// Rejected by the authentication or the before connect hook
+    #[error("connect rejected: {0}")]
+    ConnectRejected(String),
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("keep alive timeout")]
+    KeepAliveTimeout,
+    /// The CONNECT packet is not received in 5 seconds
+    #[error("connect timeout")]
+    ConnectTimeout,
+    #[error("slow consumer")]
+    SlowConsumer,
+    #[error("too many retransmissions")]
+    RetransmissionsExhausted,
+}
+
+impl Error {
+    /// Get the error back from the `io::Error` converted from it
+    pub fn from_io(err: &io::Error) -> Option<&Error> {
+        err.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+
+    /// The reason code of the DISCONNECT packet sent to v5.x clients
+    pub fn disconnect_reason_code(&self) -> DisconnectReasonCode {
+        match self {
+            Error::MalformedPacket(_) => DisconnectReasonCode::MalformedPacket,
+            Error::Protocol(_) => DisconnectReasonCode::ProtocolError,
+            Error::PacketTooLarge(_) => DisconnectReasonCode::PacketTooLarge,
+            Error::ConnectRejected(_) => DisconnectReasonCode::NotAuthorized,
+            Error::QuotaExceeded(_) | Error::SlowConsumer => DisconnectReasonCode::QuotaExceeded,
+            Error::KeepAliveTimeout => DisconnectReasonCode::KeepAliveTimeout,
+            Error::ConnectTimeout | Error::RetransmissionsExhausted => {
+                DisconnectReasonCode::UnspecifiedError
+            }
+        }
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::KeepAliveTimeout | Error::ConnectTimeout => io::ErrorKind::TimedOut,
+            Error::SlowConsumer | Error::RetransmissionsExhausted => io::ErrorKind::Other,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error() {
+        let err: io::Error = Error::PacketTooLarge(300).into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "packet too large: 300 bytes");
+        assert_eq!(Error::from_io(&err), Some(&Error::PacketTooLarge(300)));
+
+        let err: io::Error = Error::KeepAliveTimeout.into();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            Error::from_io(&err).map(Error::disconnect_reason_code),
+            Some(DisconnectReasonCode::KeepAliveTimeout)
+        );
+
+        let err = io::Error::from(io::ErrorKind::InvalidData);
+        assert_eq!(Error::from_io(&err), None);
+    }
+}
@@ -0,0 +1,176 @@
+//! Client lifecycle events (see `Config.events` and `GlobalState::events`)
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flume::{bounded, Receiver, Sender, TrySendError};
+use parking_lot::RwLock;
+use serde::Serialize;
+
+use crate::server::http::{post_json, HttpUrl};
+use crate::state::GlobalState;
+
+const WEBHOOK_QUEUE_SIZE: usize = 4096;
+const EVENT_STREAM_SIZE: usize = 1024;
+
+/// The events published to `$SYS/events/{event}` topics and posted to the
+/// webhook, encoded as a JSON object with an `event` field as the event type.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ClientEvent {
+    Connected {
+        client_identifier: String,
+        username: Option<String>,
+        peer: SocketAddr,
+        protocol: String,
+        clean_start: bool,
+        keep_alive: u16,
+    },
+    Disconnected {
+        client_identifier: String,
+        peer: SocketAddr,
+        reason: String,
+    },
+    Subscribed {
+        client_identifier: String,
+        topic_filter: String,
+        qos: u8,
+    },
+    Unsubscribed {
+        client_identifier: String,
+        topic_filter: String,
+    },
+    /// The client connected too many times in the window and is banned (see
+    /// `Config.flapping`)
+    Flapping {
+        client_identifier: String,
+        peer: SocketAddr,
+        connects: usize,
+        ban_secs: u64,
+    },
+    /// A message published by the client is routed, only sent to the event
+    /// streams (see `GlobalState::events`).
+    Published {
+        client_identifier: String,
+        topic_name: String,
+        qos: u8,
+        retain: bool,
+        payload_len: usize,
+        /// The count of the matched subscriptions
+        receivers: usize,
+    },
+}
+
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    /// Unix timestamp in milliseconds
+    time: u64,
+    #[serde(flatten)]
+    event: &'a ClientEvent,
+}
+
+/// The events waiting to be posted to the webhook
+pub(crate) struct WebhookQueue {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl ClientEvent {
+    /// The event type, also the last level of the topic name
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientEvent::Connected { .. } => "connected",
+            ClientEvent::Disconnected { .. } => "disconnected",
+            ClientEvent::Subscribed { .. } => "subscribed",
+            ClientEvent::Unsubscribed { .. } => "unsubscribed",
+            ClientEvent::Flapping { .. } => "flapping",
+            ClientEvent::Published { .. } => "published",
+        }
+    }
+
+    pub fn encode(&self) -> serde_json::Result<Vec<u8>> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+        serde_json::to_vec(&EventRecord { time, event: self })
+    }
+}
+
+impl Default for WebhookQueue {
+    fn default() -> WebhookQueue {
+        let (sender, receiver) = bounded(WEBHOOK_QUEUE_SIZE);
+        WebhookQueue { sender, receiver }
+    }
+}
+
+impl WebhookQueue {
+    /// Queue an encoded event, the event is dropped if the queue is full
+    /// (the webhook is too slow or not reachable).
+    pub(crate) fn push(&self, payload: Vec<u8>) {
+        if self.sender.try_send(payload).is_err() {
+            tracing::warn!("webhook queue is full, event dropped");
+        }
+    }
+}
+
+/// The senders of the event streams (see `GlobalState::events`)
+#[derive(Default)]
+pub(crate) struct EventStreams {
+    senders: RwLock<Vec<Sender<ClientEvent>>>,
+}
+
+impl EventStreams {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.senders.read().is_empty()
+    }
+
+    pub(crate) fn add(&self) -> Receiver<ClientEvent> {
+        let (sender, receiver) = bounded(EVENT_STREAM_SIZE);
+        self.senders.write().push(sender);
+        receiver
+    }
+
+    /// Send the event to all the streams, the event is dropped for a stream
+    /// not consumed in time. The dropped streams are removed.
+    pub(crate) fn send(&self, event: &ClientEvent) {
+        let mut closed = false;
+        for sender in self.senders.read().iter() {
+            match sender.try_send(event.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    tracing::warn!("event stream is full, event dropped")
+                }
+                Err(TrySendError::Disconnected(_)) => closed = true,
+            }
+        }
+        if closed {
+            self.senders
+                .write()
+                .retain(|sender| !sender.is_disconnected());
+        }
+    }
+}
+
+/// Post the queued events to the webhook one by one
+pub(crate) async fn run_webhook(global: Arc<GlobalState>) {
+    while let Ok(payload) = global.webhook_queue.receiver.recv_async().await {
+        // Read the url every time, since the config can be reloaded
+        let url = match global
+            .config()
+            .events
+            .webhook
+            .as_deref()
+            .and_then(HttpUrl::parse)
+        {
+            Some(url) => url,
+            None => continue,
+        };
+        match post_json(&url, &[], &payload).await {
+            Ok(status) if (200..300).contains(&status) => {}
+            Ok(status) => tracing::warn!("post event to webhook failed, status: {}", status),
+            Err(err) => tracing::warn!("post event to webhook error: {}", err),
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! The worker pool enqueue the messages matched too many receivers (see
+//! `Config.fan_out`), instead of the publisher's connection.
+
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::task::{Context, Poll};
+
+use ahash::AHasher;
+use flume::{bounded, r#async::SendSink, Receiver, Sender, TrySendError};
+use futures_sink::Sink;
+use futures_util::SinkExt;
+use mqtt_proto::{QoS, TopicFilter};
+
+use crate::config::FanOutConfig;
+use crate::metrics::{LatencyStage, Metrics};
+use crate::protocols::mqtt::BroadcastPackets;
+use crate::state::{ClientId, GlobalState, NormalMessage};
+
+/// A matched receiver: (client id, subscribe filter, subscribe QoS)
+pub(crate) type MatchedReceiver = (ClientId, TopicFilter, QoS);
+
+pub(crate) struct FanOutJob {
+    sender_id: ClientId,
+    // The message to the first receiver, cloned for the others
+    template: NormalMessage,
+    receivers: Vec<MatchedReceiver>,
+    unfinished: Unfinished,
+}
+
+// Count the unfinished jobs of a publisher, decreased when the job dropped
+struct Unfinished(Arc<AtomicUsize>);
+
+impl Unfinished {
+    fn new(count: &Arc<AtomicUsize>) -> Unfinished {
+        count.fetch_add(1, Ordering::AcqRel);
+        Unfinished(Arc::clone(count))
+    }
+}
+
+impl Drop for Unfinished {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The fan out jobs of a publisher waiting for the full worker queues, and
+/// the count of its jobs not finished by the workers.
+#[derive(Default)]
+pub(crate) struct FanOutQueue {
+    // (worker index, job) in the dispatched order
+    jobs: VecDeque<(usize, FanOutJob)>,
+    // The worker with a full queue, waiting for the job in the sink
+    blocked: Option<SendSink<'static, FanOutJob>>,
+    unfinished: Arc<AtomicUsize>,
+}
+
+#[derive(Default)]
+pub(crate) struct FanOutPool {
+    // The job queues of the workers, started by the first message
+    workers: OnceLock<Vec<Sender<FanOutJob>>>,
+}
+
+/// Send the message to the workers, the receivers still have messages queued
+/// by the publisher are returned (enqueued by the publisher after them).
+///
+/// A receiver is always handled by the same worker and the jobs waiting for a
+/// full worker queue are queued by the publisher, so the messages to it are
+/// kept in order. The publisher should keep dispatching the messages while
+/// `FanOutQueue::is_busy`, the messages sent by the workers are not
+/// overtaken by the ones sent by the publisher.
+pub(crate) fn dispatch(
+    global: &Arc<GlobalState>,
+    config: &FanOutConfig,
+    sender_id: ClientId,
+    template: NormalMessage,
+    receivers: Vec<MatchedReceiver>,
+    broadcast_packets: &mut BroadcastPackets,
+) -> Vec<MatchedReceiver> {
+    let workers = global.fan_out.workers.get_or_init(|| start(global, config));
+    let mut jobs: Vec<Vec<MatchedReceiver>> = workers.iter().map(|_| Vec::new()).collect();
+    let mut queued = Vec::new();
+    for receiver in receivers {
+        if broadcast_packets.is_queued(&receiver.0) {
+            queued.push(receiver);
+            continue;
+        }
+        let mut hasher = AHasher::default();
+        receiver.0.hash(&mut hasher);
+        jobs[hasher.finish() as usize % workers.len()].push(receiver);
+    }
+    let queue = &mut broadcast_packets.fan_out;
+    for (idx, receivers) in jobs.into_iter().enumerate() {
+        if receivers.is_empty() {
+            continue;
+        }
+        let job = FanOutJob {
+            sender_id,
+            template: template.clone(),
+            receivers,
+            unfinished: Unfinished::new(&queue.unfinished),
+        };
+        // Keep the order of the jobs
+        if !queue.is_empty() {
+            queue.jobs.push_back((idx, job));
+            continue;
+        }
+        match workers[idx].try_send(job) {
+            Ok(()) => Metrics::incr(&global.metrics.fan_out_jobs),
+            Err(TrySendError::Full(job)) => queue.jobs.push_back((idx, job)),
+            // The server is stopped
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+    queued
+}
+
+impl FanOutQueue {
+    /// The count of the jobs waiting for the full worker queues
+    pub(crate) fn len(&self) -> usize {
+        self.jobs.len() + usize::from(self.blocked.is_some())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.jobs.is_empty() && self.blocked.is_none()
+    }
+
+    /// If some messages of the publisher are not enqueued by the workers yet
+    pub(crate) fn is_busy(&self) -> bool {
+        self.unfinished.load(Ordering::Acquire) > 0
+    }
+
+    /// Send the waiting jobs to the workers until a worker queue is full.
+    /// Return the count of the sent jobs, and if some jobs are still waiting.
+    pub(crate) fn poll_send(
+        &mut self,
+        global: &GlobalState,
+        cx: &mut Context<'_>,
+    ) -> (usize, bool) {
+        let workers = match global.fan_out.workers.get() {
+            Some(workers) => workers,
+            None => return (0, false),
+        };
+        let mut sent = 0;
+        loop {
+            if let Some(sink) = self.blocked.as_mut() {
+                match Pin::new(sink).poll_flush(cx) {
+                    Poll::Pending => return (sent, true),
+                    Poll::Ready(result) => {
+                        self.blocked = None;
+                        if result.is_ok() {
+                            Metrics::incr(&global.metrics.fan_out_jobs);
+                            sent += 1;
+                        }
+                    }
+                }
+            }
+            let (idx, job) = match self.jobs.pop_front() {
+                Some(item) => item,
+                None => return (sent, false),
+            };
+            match workers[idx].try_send(job) {
+                Ok(()) => {
+                    Metrics::incr(&global.metrics.fan_out_jobs);
+                    sent += 1;
+                }
+                Err(TrySendError::Full(job)) => {
+                    let mut sink = workers[idx].clone().into_sink();
+                    // A new sink is always ready
+                    if Pin::new(&mut sink).start_send(job).is_ok() {
+                        self.blocked = Some(sink);
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+
+    /// Send all the waiting jobs, wait for the full worker queues (when the
+    /// session finished).
+    pub(crate) async fn send_all(&mut self, global: &GlobalState) {
+        if let Some(mut sink) = self.blocked.take() {
+            let _ = sink.flush().await;
+        }
+        let workers = match global.fan_out.workers.get() {
+            Some(workers) => workers,
+            None => return,
+        };
+        while let Some((idx, job)) = self.jobs.pop_front() {
+            if workers[idx].send_async(job).await.is_ok() {
+                Metrics::incr(&global.metrics.fan_out_jobs);
+            }
+        }
+    }
+}
+
+fn start(global: &Arc<GlobalState>, config: &FanOutConfig) -> Vec<Sender<FanOutJob>> {
+    tracing::info!("start {} fan out workers", config.workers);
+    (0..config.workers)
+        .map(|_| {
+            let (sender, receiver) = bounded(config.max_queued_jobs);
+            tokio::spawn(run_worker(Arc::downgrade(global), receiver));
+            sender
+        })
+        .collect()
+}
+
+async fn run_worker(global: Weak<GlobalState>, jobs: Receiver<FanOutJob>) {
+    while let Ok(job) = jobs.recv_async().await {
+        let global = match global.upgrade() {
+            Some(global) => global,
+            None => break,
+        };
+        let latency_metrics = global.config().latency_metrics;
+        let (qos, received_at) = (job.template.qos(), job.template.received_at());
+        // Wait for the full channels after tried all the receivers, so a
+        // slow receiver will not delay the others.
+        let mut waiting = Vec::new();
+        for (client_id, subscribe_filter, subscribe_qos) in job.receivers {
+            let sender = match global.get_client_normal_sender(&client_id) {
+                Some(sender) => sender,
+                None => continue,
+            };
+            let msg = job.template.for_receiver(subscribe_filter, subscribe_qos);
+            match sender.try_send((job.sender_id, msg)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(item)) => {
+                    waiting.push((client_id, sender, item));
+                    continue;
+                }
+                Err(TrySendError::Disconnected(_)) => continue,
+            }
+            if latency_metrics {
+                global
+                    .metrics
+                    .latency
+                    .record(LatencyStage::Enqueue, qos, received_at);
+            }
+        }
+        for (client_id, sender, item) in waiting {
+            if sender.send_async(item).await.is_err() {
+                tracing::debug!("send publish to disconnected client: {}", client_id);
+            } else if latency_metrics {
+                global
+                    .metrics
+                    .latency
+                    .record(LatencyStage::Enqueue, qos, received_at);
+            }
+        }
+        // All the messages of the job are enqueued
+        drop(job.unfinished);
+    }
+}
@@ -0,0 +1,165 @@
+//! Detect the clients connect too many times in a short time (see
+//! `Config.flapping`), like a crash-looping firmware. The flapping client is
+//! banned by the client identifier for a while, the ban time is doubled every
+//! time it's detected again.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+
+use crate::ban::{save_bans, Ban, BanKind};
+use crate::events::ClientEvent;
+use crate::metrics::Metrics;
+use crate::protocols::mqtt::get_unix_ts;
+use crate::state::GlobalState;
+
+// Remove the records of the quiet clients in this interval
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) struct FlappingDetector {
+    state: Mutex<FlappingState>,
+}
+
+struct FlappingState {
+    clients: HashMap<String, FlappingRecord>,
+    pruned_at: Instant,
+}
+
+struct FlappingRecord {
+    // The connect times in the window
+    connects: VecDeque<Instant>,
+    // The times detected as flapping, reset after quiet for a while
+    level: u32,
+    last_connect: Instant,
+}
+
+impl Default for FlappingDetector {
+    fn default() -> FlappingDetector {
+        FlappingDetector {
+            state: Mutex::new(FlappingState {
+                clients: HashMap::new(),
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+}
+
+/// Count a connect of the client, return true if the client is flapping (the
+/// client is banned).
+pub(crate) fn check_connect(
+    global: &GlobalState,
+    client_identifier: &str,
+    peer: SocketAddr,
+) -> bool {
+    let config = global.config();
+    let flapping = &config.flapping;
+    // The client identifier assigned by the server is always a new one
+    if flapping.max_connects == 0 || client_identifier.is_empty() {
+        return false;
+    }
+    let now = Instant::now();
+    let window = Duration::from_secs(flapping.window);
+    let (connects, ban_secs) = {
+        let mut state = global.flapping.state.lock();
+        if now.saturating_duration_since(state.pruned_at) >= PRUNE_INTERVAL {
+            // Forgiven after quiet longer than the window and the max ban time
+            let quiet = window + Duration::from_secs(flapping.max_ban_secs);
+            state
+                .clients
+                .retain(|_, record| now.saturating_duration_since(record.last_connect) < quiet);
+            state.pruned_at = now;
+        }
+        let record = state
+            .clients
+            .entry_ref(client_identifier)
+            .or_insert_with(|| FlappingRecord {
+                connects: VecDeque::new(),
+                level: 0,
+                last_connect: now,
+            });
+        record.last_connect = now;
+        while record
+            .connects
+            .front()
+            .is_some_and(|time| now.saturating_duration_since(*time) >= window)
+        {
+            record.connects.pop_front();
+        }
+        record.connects.push_back(now);
+        let connects = record.connects.len();
+        if connects <= flapping.max_connects {
+            return false;
+        }
+        record.connects.clear();
+        let ban_secs = flapping
+            .ban_secs
+            .saturating_mul(1u64 << record.level.min(32))
+            .min(flapping.max_ban_secs);
+        record.level += 1;
+        (connects, ban_secs)
+    };
+
+    tracing::warn!(
+        "client {} is flapping ({} connects in {}s), banned for {}s",
+        client_identifier,
+        connects,
+        flapping.window,
+        ban_secs
+    );
+    Metrics::incr(&global.metrics.flapping_bans);
+    global.bans.add(Ban {
+        kind: BanKind::ClientId,
+        value: client_identifier.to_owned(),
+        reason: Some("flapping".to_owned()),
+        expire_at: Some(get_unix_ts() + ban_secs),
+    });
+    if let Err(err) = save_bans(global) {
+        tracing::error!("save bans error: {}", err);
+    }
+    global.emit_event(ClientEvent::Flapping {
+        client_identifier: client_identifier.to_owned(),
+        peer,
+        connects,
+        ban_secs,
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::Ordering;
+
+    use crate::config::Config;
+
+    #[test]
+    fn test_flapping_ban_escalation() {
+        let mut config = Config::new_allow_anonymous();
+        config.flapping.max_connects = 2;
+        config.flapping.ban_secs = 10;
+        config.flapping.max_ban_secs = 25;
+        let global = GlobalState::new(config);
+        let peer = "127.0.0.1:1883".parse().unwrap();
+
+        let mut ban_times = Vec::new();
+        for _ in 0..3 {
+            assert!(!check_connect(&global, "client", peer));
+            assert!(!check_connect(&global, "client", peer));
+            assert!(check_connect(&global, "client", peer));
+            let bans = global.bans.list();
+            assert_eq!(bans.len(), 1);
+            ban_times.push(bans[0].expire_at.unwrap() - get_unix_ts());
+        }
+        assert!((9..=10).contains(&ban_times[0]));
+        assert!((19..=20).contains(&ban_times[1]));
+        assert!((24..=25).contains(&ban_times[2]));
+        assert_eq!(global.metrics.flapping_bans.load(Ordering::Relaxed), 3);
+
+        assert!(!check_connect(&global, "", peer));
+        assert!(!check_connect(&global, "other", peer));
+    }
+}
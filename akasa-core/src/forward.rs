@@ -0,0 +1,82 @@
+//! Forward the published messages to the data integrations (Kafka, the AMQP
+//! broker, the webhooks and the time-series database). The clients are only
+//! compiled with their cargo features, and the publish path only checks a
+//! flag when nothing is configured (see `GlobalState::has_forwarders`).
+
+use bytes::Bytes;
+use mqtt_proto::QoS;
+use tokio::sync::oneshot;
+
+#[cfg(feature = "amqp")]
+use crate::amqp;
+#[cfg(feature = "kafka")]
+use crate::kafka;
+use crate::state::GlobalState;
+#[cfg(feature = "tsdb")]
+use crate::tsdb;
+#[cfg(feature = "webhook")]
+use crate::webhook;
+
+/// How a published message is forwarded
+pub(crate) enum Forwarded {
+    /// Not matched by any rule
+    No,
+    /// Queued (or dropped when the queue is full)
+    Queued,
+    /// Queued to Kafka, the acknowledgement to the publisher must wait for
+    /// the receipt (an error or false means failed)
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    Wait(oneshot::Receiver<bool>),
+}
+
+/// A published message to forward
+#[cfg_attr(not(feature = "webhook"), allow(dead_code))]
+pub(crate) struct ForwardMessage<'a> {
+    /// Empty for the messages published by the server
+    pub client_identifier: &'a str,
+    pub username: Option<&'a str>,
+    pub topic_name: &'a str,
+    pub payload: &'a Bytes,
+    pub qos: QoS,
+}
+
+/// Forward the message to all the integrations matched it
+#[cfg_attr(
+    not(any(
+        feature = "kafka",
+        feature = "amqp",
+        feature = "webhook",
+        feature = "tsdb"
+    )),
+    allow(unused_variables)
+)]
+pub(crate) fn forward(global: &GlobalState, msg: &ForwardMessage<'_>) -> Forwarded {
+    if !global.has_forwarders() {
+        return Forwarded::No;
+    }
+    let forwarded = [
+        #[cfg(feature = "amqp")]
+        amqp::forward(global, msg.topic_name, msg.payload),
+        #[cfg(feature = "tsdb")]
+        tsdb::forward(global, msg.topic_name, msg.payload),
+        #[cfg(feature = "webhook")]
+        webhook::forward(
+            global,
+            msg.client_identifier,
+            msg.username,
+            msg.topic_name,
+            msg.payload,
+            msg.qos,
+        ),
+    ];
+    #[cfg(feature = "kafka")]
+    match kafka::forward(global, msg.topic_name, msg.payload, msg.qos) {
+        Forwarded::No => {}
+        forwarded => return forwarded,
+    }
+    if forwarded.contains(&true) {
+        Forwarded::Queued
+    } else {
+        Forwarded::No
+    }
+}
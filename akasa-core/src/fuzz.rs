@@ -0,0 +1,91 @@
+//! The entry points of the fuzz targets (see `akasa-core/fuzz`), only compiled
+//! with the `fuzzing` feature. The panics (also in the spawned tasks) abort the
+//! fuzzer process, the inconsistent global state is reported by assertions.
+
+use std::cmp;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::Config;
+use crate::hook::NoopHook;
+use crate::server::{handle_accept, ConnectionArgs};
+use crate::state::GlobalState;
+
+/// Run the connections one by one on the same global state, each one reads
+/// the given bytes as the data sent by the client then reaches EOF, the data
+/// sent by the server is discarded.
+///
+/// The client identifiers are kept in the sessions (when `clean_session=0`),
+/// so the later connections can resume or take over the earlier sessions.
+pub fn run_connections<D: AsRef<[u8]>>(connections: &[D]) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build tokio runtime");
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let peer = ([127, 0, 0, 1], 10000).into();
+    rt.block_on(async {
+        for data in connections {
+            let conn = FuzzConn {
+                data: data.as_ref(),
+            };
+            let conn_args = ConnectionArgs {
+                addr: ([127, 0, 0, 1], 1883).into(),
+                reuse_port: false,
+                proxy: false,
+                proxy_tls_termination: false,
+                websocket: false,
+                tls_acceptor: None,
+                max_qos: None,
+                server_keep_alive: None,
+            };
+            let _ = handle_accept(conn, conn_args, peer, NoopHook, Arc::clone(&global)).await;
+            assert_eq!(
+                global.online_clients_count(),
+                0,
+                "client still online after the connection closed"
+            );
+        }
+    });
+}
+
+struct FuzzConn<'a> {
+    data: &'a [u8],
+}
+
+impl AsyncRead for FuzzConn<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let amt = cmp::min(buf.remaining(), self.data.len());
+        let data = self.data;
+        let (head, tail) = data.split_at(amt);
+        buf.put_slice(head);
+        self.data = tail;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for FuzzConn<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,136 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::protocols::mqtt::get_unix_ts;
+
+/// The executor is considered dead if no heartbeat in this many seconds
+const HEARTBEAT_TIMEOUT_SECS: u64 = 5;
+
+/// Server health status, used by the health/readiness endpoints
+#[derive(Default)]
+pub struct Health {
+    // listen address => listening or not
+    listeners: DashMap<SocketAddr, bool>,
+    // The last unix timestamp (seconds) the executor heartbeat task ran, 0 means not started
+    heartbeat: AtomicU64,
+    // alarm name => the raised alarm (see `Config.alarms`)
+    alarms: DashMap<&'static str, Alarm>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct HealthReport {
+    pub ready: bool,
+    pub executor_alive: bool,
+    pub listeners: Vec<ListenerHealth>,
+    /// The storage backend status (only in-memory storage for now)
+    pub storage: &'static str,
+    /// The cluster membership (only standalone mode for now)
+    pub cluster: &'static str,
+    /// The names of the raised alarms
+    pub alarms: Vec<&'static str>,
+}
+
+/// A threshold alarm, published to `$SYS/alarms/{name}` when raised or cleared
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Alarm {
+    pub name: &'static str,
+    pub active: bool,
+    pub value: u64,
+    pub threshold: u64,
+    pub message: String,
+    /// The unix timestamp (seconds) when the alarm raised or cleared
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ListenerHealth {
+    pub addr: SocketAddr,
+    pub listening: bool,
+}
+
+impl Health {
+    pub(crate) fn set_listener(&self, addr: SocketAddr, listening: bool) {
+        self.listeners.insert(addr, listening);
+    }
+
+    pub(crate) fn remove_listener(&self, addr: &SocketAddr) {
+        self.listeners.remove(addr);
+    }
+
+    pub(crate) fn heartbeat(&self) {
+        self.heartbeat.store(get_unix_ts(), Ordering::Release);
+    }
+
+    /// Update the alarm state, return the alarm if it is raised or cleared
+    pub(crate) fn update_alarm(
+        &self,
+        name: &'static str,
+        value: u64,
+        threshold: u64,
+        triggered: bool,
+        message: String,
+    ) -> Option<Alarm> {
+        let alarm = Alarm {
+            name,
+            active: triggered,
+            value,
+            threshold,
+            message,
+            time: get_unix_ts(),
+        };
+        if triggered {
+            if self.alarms.contains_key(name) {
+                return None;
+            }
+            self.alarms.insert(name, alarm.clone());
+        } else {
+            self.alarms.remove(name)?;
+        }
+        Some(alarm)
+    }
+
+    /// The raised alarms
+    pub fn alarms(&self) -> Vec<Alarm> {
+        let mut alarms: Vec<_> = self
+            .alarms
+            .iter()
+            .map(|item| item.value().clone())
+            .collect();
+        alarms.sort_by_key(|alarm| alarm.name);
+        alarms
+    }
+
+    /// Liveness: the executor is still running tasks
+    pub fn executor_alive(&self) -> bool {
+        let last = self.heartbeat.load(Ordering::Acquire);
+        last > 0 && get_unix_ts().saturating_sub(last) <= HEARTBEAT_TIMEOUT_SECS
+    }
+
+    /// Readiness: the executor is alive and all listeners are listening
+    pub fn report(&self) -> HealthReport {
+        let mut listeners: Vec<_> = self
+            .listeners
+            .iter()
+            .map(|item| ListenerHealth {
+                addr: *item.key(),
+                listening: *item.value(),
+            })
+            .collect();
+        listeners.sort();
+        let executor_alive = self.executor_alive();
+        let ready = executor_alive
+            && !listeners.is_empty()
+            && listeners.iter().all(|listener| listener.listening);
+        HealthReport {
+            ready,
+            executor_alive,
+            listeners,
+            storage: "memory",
+            cluster: "standalone",
+            alarms: self.alarms().into_iter().map(|alarm| alarm.name).collect(),
+        }
+    }
+}
@@ -6,11 +6,18 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 
 use bytes::Bytes;
+use hashbrown::HashMap;
+#[cfg(feature = "v5")]
+use mqtt_proto::QosPid;
 use mqtt_proto::{
-    QoS, QosPid, TopicFilter, TopicName, {v3, v5},
+    QoS, TopicFilter, TopicName, {v3, v5},
 };
 use thiserror::Error;
+use tokio::sync::oneshot;
 
+use crate::audit::AuditEvent;
+use crate::delayed;
+#[cfg(feature = "v3")]
 use crate::protocols::mqtt::v3::{
     packet::{
         publish::handle_publish as v3_handle_publish,
@@ -20,6 +27,7 @@ use crate::protocols::mqtt::v3::{
     },
     Session as SessionV3,
 };
+#[cfg(feature = "v5")]
 use crate::protocols::mqtt::v5::{
     packet::{
         publish::handle_publish as v5_handle_publish,
@@ -29,7 +37,7 @@ use crate::protocols::mqtt::v5::{
     },
     Session as SessionV5,
 };
-use crate::protocols::mqtt::{OnlineSession, WritePacket};
+use crate::protocols::mqtt::{render_client_pattern, OnlineSession, WritePacket};
 use crate::state::GlobalState;
 
 // TODO:
@@ -38,6 +46,7 @@ use crate::state::GlobalState;
 //  [ ] handle disconnect event (takenover, by_server, by_client)
 
 pub trait Hook {
+    #[cfg(feature = "v5")]
     fn v5_before_connect(
         &self,
         _peer: SocketAddr,
@@ -46,6 +55,18 @@ pub trait Hook {
         future::ready(Ok(HookConnectCode::Success))
     }
 
+    /// The Server Reference sent with the `UseAnotherServer` and
+    /// `ServerMoved` codes returned by `v5_before_connect`
+    #[cfg(feature = "v5")]
+    fn v5_server_reference(
+        &self,
+        _peer: SocketAddr,
+        _connect: &v5::Connect,
+    ) -> Option<Arc<String>> {
+        None
+    }
+
+    #[cfg(feature = "v5")]
     fn v5_after_connect(
         &self,
         _session: &SessionV5,
@@ -54,6 +75,7 @@ pub trait Hook {
         future::ready(Ok(Vec::new()))
     }
 
+    #[cfg(feature = "v5")]
     fn v5_before_publish(
         &self,
         _session: &SessionV5,
@@ -65,6 +87,7 @@ pub trait Hook {
         future::ready(Ok(HookPublishCode::Success))
     }
 
+    #[cfg(feature = "v5")]
     fn v5_after_publish(
         &self,
         _session: &SessionV5,
@@ -76,6 +99,18 @@ pub trait Hook {
         future::ready(Ok(Vec::new()))
     }
 
+    /// Check the will message before it's published (the client disconnected
+    /// without a normal DISCONNECT), the will is dropped if not success.
+    #[cfg(feature = "v5")]
+    fn v5_before_will(
+        &self,
+        _session: &SessionV5,
+        _last_will: &v5::LastWill,
+    ) -> impl Future<Output = HookResult<HookPublishCode>> + Send {
+        future::ready(Ok(HookPublishCode::Success))
+    }
+
+    #[cfg(feature = "v5")]
     fn v5_before_subscribe(
         &self,
         _session: &SessionV5,
@@ -87,6 +122,7 @@ pub trait Hook {
         future::ready(Ok(HookSubscribeCode::Success))
     }
 
+    #[cfg(feature = "v5")]
     fn v5_after_subscribe(
         &self,
         _session: &SessionV5,
@@ -99,6 +135,7 @@ pub trait Hook {
         future::ready(Ok(Vec::new()))
     }
 
+    #[cfg(feature = "v5")]
     fn v5_before_unsubscribe(
         &self,
         _session: &SessionV5,
@@ -110,6 +147,7 @@ pub trait Hook {
         future::ready(Ok(HookUnsubscribeCode::Success))
     }
 
+    #[cfg(feature = "v5")]
     fn v5_after_unsubscribe(
         &self,
         _session: &SessionV5,
@@ -121,6 +159,7 @@ pub trait Hook {
         future::ready(Ok(Vec::new()))
     }
 
+    #[cfg(feature = "v5")]
     fn v5_after_disconnect(
         &self,
         _session: &SessionV5,
@@ -129,6 +168,7 @@ pub trait Hook {
         future::ready(Ok(()))
     }
 
+    #[cfg(feature = "v3")]
     fn v3_before_connect(
         &self,
         _peer: SocketAddr,
@@ -137,6 +177,7 @@ pub trait Hook {
         future::ready(Ok(HookConnectCode::Success))
     }
 
+    #[cfg(feature = "v3")]
     fn v3_after_connect(
         &self,
         _session: &SessionV3,
@@ -145,6 +186,7 @@ pub trait Hook {
         future::ready(Ok(Vec::new()))
     }
 
+    #[cfg(feature = "v3")]
     fn v3_before_publish(
         &self,
         _session: &SessionV3,
@@ -156,6 +198,7 @@ pub trait Hook {
         future::ready(Ok(HookPublishCode::Success))
     }
 
+    #[cfg(feature = "v3")]
     fn v3_after_publish(
         &self,
         _session: &SessionV3,
@@ -167,6 +210,18 @@ pub trait Hook {
         future::ready(Ok(Vec::new()))
     }
 
+    /// Check the will message before it's published (the client disconnected
+    /// without a normal DISCONNECT), the will is dropped if not success.
+    #[cfg(feature = "v3")]
+    fn v3_before_will(
+        &self,
+        _session: &SessionV3,
+        _last_will: &v3::LastWill,
+    ) -> impl Future<Output = HookResult<HookPublishCode>> + Send {
+        future::ready(Ok(HookPublishCode::Success))
+    }
+
+    #[cfg(feature = "v3")]
     fn v3_before_subscribe(
         &self,
         _session: &SessionV3,
@@ -178,6 +233,7 @@ pub trait Hook {
         future::ready(Ok(HookSubscribeCode::Success))
     }
 
+    #[cfg(feature = "v3")]
     fn v3_after_subscribe(
         &self,
         _session: &SessionV3,
@@ -190,6 +246,7 @@ pub trait Hook {
         future::ready(Ok(Vec::new()))
     }
 
+    #[cfg(feature = "v3")]
     fn v3_before_unsubscribe(
         &self,
         _session: &SessionV3,
@@ -201,6 +258,7 @@ pub trait Hook {
         future::ready(Ok(HookUnsubscribeCode::Success))
     }
 
+    #[cfg(feature = "v3")]
     fn v3_after_unsubscribe(
         &self,
         _session: &SessionV3,
@@ -212,6 +270,7 @@ pub trait Hook {
         future::ready(Ok(Vec::new()))
     }
 
+    #[cfg(feature = "v3")]
     fn v3_after_disconnect(
         &self,
         _session: &SessionV3,
@@ -221,6 +280,410 @@ pub trait Hook {
     }
 }
 
+/// The hook accepts everything and does nothing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopHook;
+
+impl Hook for NoopHook {}
+
+impl Authenticator for NoopHook {
+    fn authenticate(
+        &self,
+        _client: HookClient<'_>,
+        _password: Option<&[u8]>,
+    ) -> impl Future<Output = HookResult<HookConnectCode>> + Send {
+        future::ready(Ok(HookConnectCode::Success))
+    }
+}
+
+impl Authorizer for NoopHook {}
+
+impl MessageInterceptor for NoopHook {
+    fn intercept_publish(
+        &self,
+        _client: HookClient<'_>,
+        _topic_name: &TopicName,
+        _payload: &mut Bytes,
+    ) -> impl Future<Output = HookResult<bool>> + Send {
+        future::ready(Ok(false))
+    }
+}
+
+/// The client passed to the focused hook traits, same for v3.x and v5.x
+#[derive(Debug, Clone, Copy)]
+pub struct HookClient<'a> {
+    pub peer: SocketAddr,
+    pub client_identifier: &'a str,
+    pub username: Option<&'a str>,
+    /// The session attributes (empty in the before connect hook)
+    pub attributes: &'a HashMap<String, String>,
+}
+
+impl<'a> HookClient<'a> {
+    /// Replace `%c` in the pattern (of an ACL rule) by the client identifier,
+    /// `%u` by the username and `%{key}` by the session attribute, return None
+    /// if the username or the attribute is missing.
+    pub fn render(&self, pattern: &str) -> Option<String> {
+        render_client_pattern(
+            pattern,
+            self.client_identifier,
+            self.username,
+            self.attributes,
+        )
+    }
+
+    #[cfg(feature = "v5")]
+    fn from_v5(session: &'a SessionV5) -> HookClient<'a> {
+        HookClient {
+            peer: session.peer,
+            client_identifier: &session.client_identifier,
+            username: session.username.as_ref().map(|name| name.as_str()),
+            attributes: &session.attributes,
+        }
+    }
+
+    #[cfg(feature = "v3")]
+    fn from_v3(session: &'a SessionV3) -> HookClient<'a> {
+        HookClient {
+            peer: session.peer,
+            client_identifier: &session.client_identifier,
+            username: session.username.as_ref().map(|name| name.as_str()),
+            attributes: &session.attributes,
+        }
+    }
+}
+
+/// Check the connecting clients, used by `ComposedHook` as the before connect
+/// hook.
+pub trait Authenticator {
+    fn authenticate(
+        &self,
+        client: HookClient<'_>,
+        password: Option<&[u8]>,
+    ) -> impl Future<Output = HookResult<HookConnectCode>> + Send;
+
+    /// The Server Reference of the redirect codes returned by `authenticate`
+    fn server_reference(&self, _client: HookClient<'_>) -> Option<Arc<String>> {
+        None
+    }
+}
+
+/// Check the topics the clients publish or subscribe, used by `ComposedHook`
+/// as the before publish/subscribe hooks. Everything is allowed by default.
+pub trait Authorizer {
+    fn authorize_publish(
+        &self,
+        _client: HookClient<'_>,
+        _topic_name: &TopicName,
+    ) -> impl Future<Output = HookResult<bool>> + Send {
+        future::ready(Ok(true))
+    }
+
+    fn authorize_subscribe(
+        &self,
+        _client: HookClient<'_>,
+        _topic_filter: &TopicFilter,
+    ) -> impl Future<Output = HookResult<bool>> + Send {
+        future::ready(Ok(true))
+    }
+}
+
+/// Inspect or rewrite the payload of the authorized publish packets, used by
+/// `ComposedHook` as the before publish hook.
+pub trait MessageInterceptor {
+    /// Return true if the payload is changed
+    fn intercept_publish(
+        &self,
+        client: HookClient<'_>,
+        topic_name: &TopicName,
+        payload: &mut Bytes,
+    ) -> impl Future<Output = HookResult<bool>> + Send;
+}
+
+/// Adapt the focused traits into a `Hook` for both v3.x and v5.x, the parts
+/// not given are `NoopHook`:
+///
+/// ```ignore
+/// let hook = ComposedHook::new()
+///     .authenticator(MyAuthenticator)
+///     .authorizer(MyAcl);
+/// ```
+///
+/// The hooks still need to be enabled by `Config.hook` (`enable_before_connect`,
+/// `enable_publish` and `enable_subscribe`). A SUBSCRIBE packet is rejected if
+/// any of the topic filters is not authorized. A CONNECT packet is rejected
+/// (NotAuthorized) if the will topic is not authorized, the will is checked
+/// again when it's published.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ComposedHook<A = NoopHook, Z = NoopHook, I = NoopHook> {
+    authenticator: A,
+    authorizer: Z,
+    interceptor: I,
+}
+
+impl ComposedHook {
+    pub fn new() -> ComposedHook {
+        ComposedHook::default()
+    }
+}
+
+impl<A, Z, I> ComposedHook<A, Z, I> {
+    pub fn authenticator<A2>(self, authenticator: A2) -> ComposedHook<A2, Z, I> {
+        ComposedHook {
+            authenticator,
+            authorizer: self.authorizer,
+            interceptor: self.interceptor,
+        }
+    }
+
+    pub fn authorizer<Z2>(self, authorizer: Z2) -> ComposedHook<A, Z2, I> {
+        ComposedHook {
+            authenticator: self.authenticator,
+            authorizer,
+            interceptor: self.interceptor,
+        }
+    }
+
+    pub fn interceptor<I2>(self, interceptor: I2) -> ComposedHook<A, Z, I2> {
+        ComposedHook {
+            authenticator: self.authenticator,
+            authorizer: self.authorizer,
+            interceptor,
+        }
+    }
+}
+
+impl<A, Z, I> ComposedHook<A, Z, I>
+where
+    Z: Authorizer + Sync,
+    I: MessageInterceptor + Sync,
+{
+    // The authorizer is skipped when the topic is `authorized` already
+    async fn before_publish(
+        &self,
+        client: HookClient<'_>,
+        topic_name: &TopicName,
+        payload: &mut Bytes,
+        changed: &mut bool,
+        authorized: bool,
+    ) -> HookResult<HookPublishCode> {
+        if !authorized
+            && !self
+                .authorizer
+                .authorize_publish(client, topic_name)
+                .await?
+        {
+            return Ok(HookPublishCode::NotAuthorized);
+        }
+        if self
+            .interceptor
+            .intercept_publish(client, topic_name, payload)
+            .await?
+        {
+            *changed = true;
+        }
+        Ok(HookPublishCode::Success)
+    }
+
+    async fn before_will(
+        &self,
+        client: HookClient<'_>,
+        topic_name: &TopicName,
+    ) -> HookResult<HookPublishCode> {
+        if self
+            .authorizer
+            .authorize_publish(client, topic_name)
+            .await?
+        {
+            Ok(HookPublishCode::Success)
+        } else {
+            Ok(HookPublishCode::NotAuthorized)
+        }
+    }
+
+    async fn before_subscribe(
+        &self,
+        client: HookClient<'_>,
+        topic_filters: Vec<&TopicFilter>,
+    ) -> HookResult<HookSubscribeCode> {
+        for topic_filter in topic_filters {
+            if !self
+                .authorizer
+                .authorize_subscribe(client, topic_filter)
+                .await?
+            {
+                return Ok(HookSubscribeCode::NotAuthorized);
+            }
+        }
+        Ok(HookSubscribeCode::Success)
+    }
+}
+
+impl<A, Z, I> Hook for ComposedHook<A, Z, I>
+where
+    A: Authenticator + Sync,
+    Z: Authorizer + Sync,
+    I: MessageInterceptor + Sync,
+{
+    #[cfg(feature = "v5")]
+    async fn v5_before_connect(
+        &self,
+        peer: SocketAddr,
+        connect: &v5::Connect,
+    ) -> HookResult<HookConnectCode> {
+        let attributes = HashMap::new();
+        let client = HookClient {
+            peer,
+            client_identifier: &connect.client_id,
+            username: connect.username.as_ref().map(|name| name.as_str()),
+            attributes: &attributes,
+        };
+        let code = self
+            .authenticator
+            .authenticate(client, connect.password.as_deref())
+            .await?;
+        if let (HookConnectCode::Success, Some(last_will)) = (&code, connect.last_will.as_ref()) {
+            if self.before_will(client, &last_will.topic_name).await? != HookPublishCode::Success {
+                return Ok(HookConnectCode::NotAuthorized);
+            }
+        }
+        Ok(code)
+    }
+
+    #[cfg(feature = "v5")]
+    fn v5_server_reference(&self, peer: SocketAddr, connect: &v5::Connect) -> Option<Arc<String>> {
+        let attributes = HashMap::new();
+        let client = HookClient {
+            peer,
+            client_identifier: &connect.client_id,
+            username: connect.username.as_ref().map(|name| name.as_str()),
+            attributes: &attributes,
+        };
+        self.authenticator.server_reference(client)
+    }
+
+    #[cfg(feature = "v5")]
+    async fn v5_before_publish(
+        &self,
+        session: &SessionV5,
+        _encode_len: usize,
+        _packet_body: &[u8],
+        publish: &mut v5::Publish,
+        changed: &mut bool,
+    ) -> HookResult<HookPublishCode> {
+        let client = HookClient::from_v5(session);
+        // The client always can publish to its own response topics
+        let authorized = session.is_response_topic(&publish.topic_name);
+        self.before_publish(
+            client,
+            &publish.topic_name,
+            &mut publish.payload,
+            changed,
+            authorized,
+        )
+        .await
+    }
+
+    #[cfg(feature = "v5")]
+    async fn v5_before_will(
+        &self,
+        session: &SessionV5,
+        last_will: &v5::LastWill,
+    ) -> HookResult<HookPublishCode> {
+        let client = HookClient::from_v5(session);
+        self.before_will(client, &last_will.topic_name).await
+    }
+
+    #[cfg(feature = "v5")]
+    async fn v5_before_subscribe(
+        &self,
+        session: &SessionV5,
+        _encode_len: usize,
+        _packet_body: &[u8],
+        subscribe: &mut v5::Subscribe,
+        _changed: &mut bool,
+    ) -> HookResult<HookSubscribeCode> {
+        let client = HookClient::from_v5(session);
+        // The client always can subscribe to its own response topics
+        let topic_filters = subscribe
+            .topics
+            .iter()
+            .map(|(filter, _)| filter)
+            .filter(|filter| !session.is_response_topic(filter))
+            .collect();
+        self.before_subscribe(client, topic_filters).await
+    }
+
+    #[cfg(feature = "v3")]
+    async fn v3_before_connect(
+        &self,
+        peer: SocketAddr,
+        connect: &v3::Connect,
+    ) -> HookResult<HookConnectCode> {
+        let attributes = HashMap::new();
+        let client = HookClient {
+            peer,
+            client_identifier: &connect.client_id,
+            username: connect.username.as_ref().map(|name| name.as_str()),
+            attributes: &attributes,
+        };
+        let code = self
+            .authenticator
+            .authenticate(client, connect.password.as_deref())
+            .await?;
+        if let (HookConnectCode::Success, Some(last_will)) = (&code, connect.last_will.as_ref()) {
+            if self.before_will(client, &last_will.topic_name).await? != HookPublishCode::Success {
+                return Ok(HookConnectCode::NotAuthorized);
+            }
+        }
+        Ok(code)
+    }
+
+    #[cfg(feature = "v3")]
+    async fn v3_before_publish(
+        &self,
+        session: &SessionV3,
+        _encode_len: usize,
+        _packet_body: &[u8],
+        publish: &mut v3::Publish,
+        changed: &mut bool,
+    ) -> HookResult<HookPublishCode> {
+        let client = HookClient::from_v3(session);
+        self.before_publish(
+            client,
+            &publish.topic_name,
+            &mut publish.payload,
+            changed,
+            false,
+        )
+        .await
+    }
+
+    #[cfg(feature = "v3")]
+    async fn v3_before_will(
+        &self,
+        session: &SessionV3,
+        last_will: &v3::LastWill,
+    ) -> HookResult<HookPublishCode> {
+        let client = HookClient::from_v3(session);
+        self.before_will(client, &last_will.topic_name).await
+    }
+
+    #[cfg(feature = "v3")]
+    async fn v3_before_subscribe(
+        &self,
+        session: &SessionV3,
+        _encode_len: usize,
+        _packet_body: &[u8],
+        subscribe: &mut v3::Subscribe,
+        _changed: &mut bool,
+    ) -> HookResult<HookSubscribeCode> {
+        let client = HookClient::from_v3(session);
+        let topic_filters = subscribe.topics.iter().map(|(filter, _)| filter).collect();
+        self.before_subscribe(client, topic_filters).await
+    }
+}
+
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum HookError {
     #[error("internal error")]
@@ -245,6 +708,12 @@ pub enum HookConnectCode {
     ServerUnavailable,
     QuotaExceeded,
     ConnectionRateExceeded,
+    /// Redirect the client to the server temporarily (the Server Reference is
+    /// given by `Hook::v5_server_reference`)
+    UseAnotherServer,
+    /// Redirect the client to the server permanently (the Server Reference is
+    /// given by `Hook::v5_server_reference`)
+    ServerMoved,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -278,6 +747,14 @@ pub enum HookAction {
     Publish(PublishAction),
     Subscribe(SubscribeAction),
     Unsubscribe(UnsubscribeAction),
+    /// Override the inflight window of the session (the packets sent to the
+    /// client but not acknowledged), v5.x client is still limited by its
+    /// Receive Maximum. Mostly returned from the after connect hook.
+    SetMaxInflight(u16),
+    /// Set an attribute (key, value) of the session, like the tenant or the
+    /// device type. The attributes are kept with the session state, visible to
+    /// the later hook calls and replace `%{key}` in the auto subscriptions.
+    SetAttribute(String, String),
 }
 
 /// Publish a message
@@ -311,6 +788,8 @@ impl HookConnectCode {
             Self::ServerUnavailable => v5::ConnectReasonCode::ServerUnavailable,
             Self::QuotaExceeded => v5::ConnectReasonCode::QuotaExceeded,
             Self::ConnectionRateExceeded => v5::ConnectReasonCode::ConnectionRateExceeded,
+            Self::UseAnotherServer => v5::ConnectReasonCode::UseAnotherServer,
+            Self::ServerMoved => v5::ConnectReasonCode::ServerMoved,
         }
     }
 
@@ -411,119 +890,216 @@ pub enum HookResponse {
     BeforeConnect(io::Result<HookConnectCode>),
     AfterConnect(io::Result<Vec<HookAction>>),
     AfterDisconnect(io::Result<()>),
+    BeforeWill(io::Result<HookPublishCode>),
 }
 
 pub enum HookRequest {
     // Shutdown,
+    #[cfg(feature = "v5")]
     V5BeforeConnect {
         peer: SocketAddr,
         connect: v5::Connect,
     },
+    #[cfg(feature = "v5")]
     V5AfterConnect {
         context: LockedHookContext<SessionV5>,
         session_present: bool,
     },
+    #[cfg(feature = "v5")]
     V5Publish {
         context: LockedHookContext<SessionV5>,
         encode_len: usize,
         packet_body: Vec<MaybeUninit<u8>>,
         publish: v5::Publish,
     },
+    #[cfg(feature = "v5")]
     V5Subscribe {
         context: LockedHookContext<SessionV5>,
         encode_len: usize,
         packet_body: Vec<MaybeUninit<u8>>,
         subscribe: v5::Subscribe,
     },
+    #[cfg(feature = "v5")]
     V5Unsubscribe {
         context: LockedHookContext<SessionV5>,
         encode_len: usize,
         packet_body: Vec<MaybeUninit<u8>>,
         unsubscribe: v5::Unsubscribe,
     },
+    #[cfg(feature = "v5")]
     V5AfterDisconnect {
         context: LockedHookContext<SessionV5>,
         taken_over: bool,
     },
+    /// Check the will message of the session
+    #[cfg(feature = "v5")]
+    V5BeforeWill {
+        context: LockedHookContext<SessionV5>,
+    },
+    /// Write the PUBACK after the message is acknowledged by Kafka (see
+    /// `Config.kafka.wait_ack`)
+    #[cfg(feature = "v5")]
+    V5KafkaAck {
+        context: LockedHookContext<SessionV5>,
+        receipt: oneshot::Receiver<bool>,
+        packet: v5::Packet,
+    },
 
+    #[cfg(feature = "v3")]
     V3BeforeConnect {
         peer: SocketAddr,
         connect: v3::Connect,
     },
+    #[cfg(feature = "v3")]
     V3AfterConnect {
         context: LockedHookContext<SessionV3>,
         session_present: bool,
     },
+    #[cfg(feature = "v3")]
     V3Publish {
         context: LockedHookContext<SessionV3>,
         encode_len: usize,
         packet_body: Vec<MaybeUninit<u8>>,
         publish: v3::Publish,
     },
+    #[cfg(feature = "v3")]
     V3Subscribe {
         context: LockedHookContext<SessionV3>,
         encode_len: usize,
         packet_body: Vec<MaybeUninit<u8>>,
         subscribe: v3::Subscribe,
     },
+    #[cfg(feature = "v3")]
     V3Unsubscribe {
         context: LockedHookContext<SessionV3>,
         encode_len: usize,
         packet_body: Vec<MaybeUninit<u8>>,
         unsubscribe: v3::Unsubscribe,
     },
+    #[cfg(feature = "v3")]
     V3AfterDisconnect {
         context: LockedHookContext<SessionV3>,
         taken_over: bool,
     },
+    /// Check the will message of the session
+    #[cfg(feature = "v3")]
+    V3BeforeWill {
+        context: LockedHookContext<SessionV3>,
+    },
+    /// Write the PUBACK after the message is acknowledged by Kafka (see
+    /// `Config.kafka.wait_ack`)
+    #[cfg(feature = "v3")]
+    V3KafkaAck {
+        context: LockedHookContext<SessionV3>,
+        receipt: oneshot::Receiver<bool>,
+        packet: v3::Packet,
+    },
+}
+
+impl HookRequest {
+    /// The hook function name
+    pub fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "v5")]
+            HookRequest::V5BeforeConnect { .. } => "v5_before_connect",
+            #[cfg(feature = "v5")]
+            HookRequest::V5AfterConnect { .. } => "v5_after_connect",
+            #[cfg(feature = "v5")]
+            HookRequest::V5Publish { .. } => "v5_before_publish",
+            #[cfg(feature = "v5")]
+            HookRequest::V5Subscribe { .. } => "v5_before_subscribe",
+            #[cfg(feature = "v5")]
+            HookRequest::V5Unsubscribe { .. } => "v5_before_unsubscribe",
+            #[cfg(feature = "v5")]
+            HookRequest::V5AfterDisconnect { .. } => "v5_after_disconnect",
+            #[cfg(feature = "v5")]
+            HookRequest::V5BeforeWill { .. } => "v5_before_will",
+            #[cfg(feature = "v5")]
+            HookRequest::V5KafkaAck { .. } => "v5_kafka_ack",
+            #[cfg(feature = "v3")]
+            HookRequest::V3BeforeConnect { .. } => "v3_before_connect",
+            #[cfg(feature = "v3")]
+            HookRequest::V3AfterConnect { .. } => "v3_after_connect",
+            #[cfg(feature = "v3")]
+            HookRequest::V3Publish { .. } => "v3_before_publish",
+            #[cfg(feature = "v3")]
+            HookRequest::V3Subscribe { .. } => "v3_before_subscribe",
+            #[cfg(feature = "v3")]
+            HookRequest::V3Unsubscribe { .. } => "v3_before_unsubscribe",
+            #[cfg(feature = "v3")]
+            HookRequest::V3AfterDisconnect { .. } => "v3_after_disconnect",
+            #[cfg(feature = "v3")]
+            HookRequest::V3BeforeWill { .. } => "v3_before_will",
+            #[cfg(feature = "v3")]
+            HookRequest::V3KafkaAck { .. } => "v3_kafka_ack",
+        }
+    }
 }
 
+#[tracing::instrument(name = "hook", skip_all, fields(function = request.name()))]
 pub async fn handle_request<H: Hook + Send + Sync>(
     request: HookRequest,
     handler: H,
     global: Arc<GlobalState>,
 ) -> HookResponse {
     match request {
+        #[cfg(feature = "v5")]
         HookRequest::V5BeforeConnect { peer, connect } => {
-            log::debug!("got a v5 before connect request: {peer}, {connect:#?}");
+            tracing::debug!("got a v5 before connect request: {peer}, {connect:#?}");
             let result = handler
                 .v5_before_connect(peer, &connect)
                 .await
                 .map_err(Into::into);
             HookResponse::BeforeConnect(result)
         }
+        #[cfg(feature = "v5")]
         HookRequest::V5AfterConnect {
             context,
             session_present,
         } => {
             let session = context.session_ref();
-            log::debug!("got a v5 after connect request: {}", session.client_id());
+            tracing::debug!("got a v5 after connect request: {}", session.client_id());
             let result = handler
                 .v5_after_connect(session, session_present)
                 .await
                 .map_err(Into::into);
             HookResponse::AfterConnect(result)
         }
+        #[cfg(feature = "v5")]
         HookRequest::V5Publish {
             mut context,
             encode_len,
             packet_body,
             mut publish,
         } => {
-            log::debug!("got a v5 publish request: {publish:#?}");
+            tracing::debug!("got a v5 publish request: {publish:#?}");
             let (session, write_packets) = context.get_mut();
 
             let body: &[u8] = unsafe { mem::transmute(&packet_body[..]) };
             let mut changed = false;
+            // The hooks check the real topic of a delayed publish
+            let delayed_topic = match publish.properties.topic_alias {
+                Some(alias) if publish.topic_name.is_empty() => session.topic_aliases.get(&alias),
+                _ => Some(&publish.topic_name),
+            }
+            .and_then(|topic_name| delayed::target_topic(&global, topic_name));
+            let original_topic =
+                delayed_topic.map(|topic_name| mem::replace(&mut publish.topic_name, topic_name));
             let result = handler
                 .v5_before_publish(session, encode_len, body, &mut publish, &mut changed)
                 .await;
-            log::debug!("v5 before publish return code: {:?}", result);
+            if let Some(topic_name) = original_topic {
+                publish.topic_name = topic_name;
+            }
+            tracing::debug!("v5 before publish return code: {:?}", result);
             let receipt = match result {
                 Ok(HookPublishCode::Success) => {
                     match v5_handle_publish(session, publish.clone(), &global) {
                         Ok(packet_opt) => {
-                            if let Some(packet) = packet_opt {
+                            if let Some(mut packet) = packet_opt {
+                                if !kafka_acked(session.kafka_receipt.take()).await {
+                                    v5_kafka_failed(&mut packet);
+                                }
                                 write_packets.push_back(packet.into());
                             }
                             handler
@@ -538,6 +1114,16 @@ pub async fn handle_request<H: Hook + Send + Sync>(
                     }
                 }
                 Ok(code) => {
+                    if code == HookPublishCode::NotAuthorized {
+                        audit_acl_denied(
+                            &global,
+                            session.peer,
+                            &session.client_identifier,
+                            &session.username,
+                            "publish",
+                            vec![publish.topic_name.to_string()],
+                        );
+                    }
                     match publish.qos_pid {
                         QosPid::Level0 => {}
                         QosPid::Level1(pid) => {
@@ -565,6 +1151,7 @@ pub async fn handle_request<H: Hook + Send + Sync>(
             };
             HookResponse::Normal(receipt)
         }
+        #[cfg(feature = "v5")]
         HookRequest::V5Subscribe {
             mut context,
             encode_len,
@@ -601,6 +1188,20 @@ pub async fn handle_request<H: Hook + Send + Sync>(
                         .map_err(|err| Some(err.into()))
                 }
                 Ok(code) => {
+                    if code == HookSubscribeCode::NotAuthorized {
+                        audit_acl_denied(
+                            &global,
+                            session.peer,
+                            &session.client_identifier,
+                            &session.username,
+                            "subscribe",
+                            subscribe
+                                .topics
+                                .iter()
+                                .map(|(filter, _)| filter.to_string())
+                                .collect(),
+                        );
+                    }
                     let reason_code = code.to_v5_code();
                     let topics = vec![reason_code; subscribe.topics.len()];
                     let pkt: v5::Packet = v5::Suback::new(subscribe.pid, topics).into();
@@ -611,6 +1212,7 @@ pub async fn handle_request<H: Hook + Send + Sync>(
             };
             HookResponse::Normal(receipt)
         }
+        #[cfg(feature = "v5")]
         HookRequest::V5Unsubscribe {
             mut context,
             encode_len,
@@ -643,6 +1245,7 @@ pub async fn handle_request<H: Hook + Send + Sync>(
             };
             HookResponse::Normal(receipt)
         }
+        #[cfg(feature = "v5")]
         HookRequest::V5AfterDisconnect {
             context,
             taken_over,
@@ -652,62 +1255,123 @@ pub async fn handle_request<H: Hook + Send + Sync>(
                 .await;
             HookResponse::AfterDisconnect(result.map_err(Into::into))
         }
+        #[cfg(feature = "v5")]
+        HookRequest::V5BeforeWill { context } => {
+            let session = context.session_ref();
+            let result = match session.last_will.as_ref() {
+                Some(last_will) => {
+                    let result = handler.v5_before_will(session, last_will).await;
+                    if result == Ok(HookPublishCode::NotAuthorized) {
+                        audit_acl_denied(
+                            &global,
+                            session.peer,
+                            &session.client_identifier,
+                            &session.username,
+                            "will",
+                            vec![last_will.topic_name.to_string()],
+                        );
+                    }
+                    result
+                }
+                None => Ok(HookPublishCode::Success),
+            };
+            HookResponse::BeforeWill(result.map_err(Into::into))
+        }
+        #[cfg(feature = "v5")]
+        HookRequest::V5KafkaAck {
+            mut context,
+            receipt,
+            mut packet,
+        } => {
+            if !kafka_acked(Some(receipt)).await {
+                v5_kafka_failed(&mut packet);
+            }
+            let (_session, write_packets) = context.get_mut();
+            write_packets.push_back(packet.into());
+            HookResponse::Normal(Ok(Vec::new()))
+        }
 
+        #[cfg(feature = "v3")]
         HookRequest::V3BeforeConnect { peer, connect } => {
-            log::debug!("got a v3 before connect request: {peer}, {connect:#?}");
+            tracing::debug!("got a v3 before connect request: {peer}, {connect:#?}");
             let result = handler
                 .v3_before_connect(peer, &connect)
                 .await
                 .map_err(Into::into);
             HookResponse::BeforeConnect(result)
         }
+        #[cfg(feature = "v3")]
         HookRequest::V3AfterConnect {
             context,
             session_present,
         } => {
             let session = context.session_ref();
-            log::debug!("got a v3 after connect request: {}", session.client_id());
+            tracing::debug!("got a v3 after connect request: {}", session.client_id());
             let result = handler
                 .v3_after_connect(session, session_present)
                 .await
                 .map_err(Into::into);
             HookResponse::AfterConnect(result)
         }
+        #[cfg(feature = "v3")]
         HookRequest::V3Publish {
             mut context,
             encode_len,
             packet_body,
             mut publish,
         } => {
-            log::debug!("got a v3 publish request: {publish:#?}");
+            tracing::debug!("got a v3 publish request: {publish:#?}");
             let (session, write_packets) = context.get_mut();
             let body: &[u8] = unsafe { mem::transmute(&packet_body[..]) };
             let mut changed = false;
+            // The hooks check the real topic of a delayed publish
+            let original_topic = delayed::target_topic(&global, &publish.topic_name)
+                .map(|topic_name| mem::replace(&mut publish.topic_name, topic_name));
             let result = handler
                 .v3_before_publish(session, encode_len, body, &mut publish, &mut changed)
                 .await;
-            log::debug!("v3 before publish return code: {:?}", result);
+            if let Some(topic_name) = original_topic {
+                publish.topic_name = topic_name;
+            }
+            tracing::debug!("v3 before publish return code: {:?}", result);
             let receipt = match result {
                 Ok(HookPublishCode::Success) => {
                     match v3_handle_publish(session, publish.clone(), &global) {
                         Ok(packet_opt) => {
-                            if let Some(packet) = packet_opt {
-                                write_packets.push_back(packet.into());
+                            if kafka_acked(session.kafka_receipt.take()).await {
+                                if let Some(packet) = packet_opt {
+                                    write_packets.push_back(packet.into());
+                                }
+                                handler
+                                    .v3_after_publish(session, encode_len, body, &publish, changed)
+                                    .await
+                                    .map_err(|err| Some(err.into()))
+                            } else {
+                                Err(Some(v3_kafka_failed()))
                             }
-                            handler
-                                .v3_after_publish(session, encode_len, body, &publish, changed)
-                                .await
-                                .map_err(|err| Some(err.into()))
                         }
                         Err(err) => Err(Some(err)),
                     }
                 }
                 // TODO: return error or just ignore the packet?
-                Ok(_) => Err(Some(io::ErrorKind::InvalidData.into())),
+                Ok(code) => {
+                    if code == HookPublishCode::NotAuthorized {
+                        audit_acl_denied(
+                            &global,
+                            session.peer,
+                            &session.client_identifier,
+                            &session.username,
+                            "publish",
+                            vec![publish.topic_name.to_string()],
+                        );
+                    }
+                    Err(Some(io::ErrorKind::InvalidData.into()))
+                }
                 Err(err) => Err(Some(err.into())),
             };
             HookResponse::Normal(receipt)
         }
+        #[cfg(feature = "v3")]
         HookRequest::V3Subscribe {
             mut context,
             encode_len,
@@ -754,11 +1418,28 @@ pub async fn handle_request<H: Hook + Send + Sync>(
                     }
                 }
                 // TODO: return error or just ignore the packet?
-                Ok(_code) => Err(Some(io::ErrorKind::InvalidData.into())),
+                Ok(code) => {
+                    if code == HookSubscribeCode::NotAuthorized {
+                        audit_acl_denied(
+                            &global,
+                            session.peer,
+                            &session.client_identifier,
+                            &session.username,
+                            "subscribe",
+                            subscribe
+                                .topics
+                                .iter()
+                                .map(|(filter, _)| filter.to_string())
+                                .collect(),
+                        );
+                    }
+                    Err(Some(io::ErrorKind::InvalidData.into()))
+                }
                 Err(err) => Err(Some(err.into())),
             };
             HookResponse::Normal(receipt)
         }
+        #[cfg(feature = "v3")]
         HookRequest::V3Unsubscribe {
             mut context,
             encode_len,
@@ -786,6 +1467,7 @@ pub async fn handle_request<H: Hook + Send + Sync>(
             };
             HookResponse::Normal(receipt)
         }
+        #[cfg(feature = "v3")]
         HookRequest::V3AfterDisconnect {
             context,
             taken_over,
@@ -795,5 +1477,182 @@ pub async fn handle_request<H: Hook + Send + Sync>(
                 .await;
             HookResponse::AfterDisconnect(result.map_err(Into::into))
         }
+        #[cfg(feature = "v3")]
+        HookRequest::V3BeforeWill { context } => {
+            let session = context.session_ref();
+            let result = match session.last_will.as_ref() {
+                Some(last_will) => {
+                    let result = handler.v3_before_will(session, last_will).await;
+                    if result == Ok(HookPublishCode::NotAuthorized) {
+                        audit_acl_denied(
+                            &global,
+                            session.peer,
+                            &session.client_identifier,
+                            &session.username,
+                            "will",
+                            vec![last_will.topic_name.to_string()],
+                        );
+                    }
+                    result
+                }
+                None => Ok(HookPublishCode::Success),
+            };
+            HookResponse::BeforeWill(result.map_err(Into::into))
+        }
+        #[cfg(feature = "v3")]
+        HookRequest::V3KafkaAck {
+            mut context,
+            receipt,
+            packet,
+        } => {
+            if !kafka_acked(Some(receipt)).await {
+                return HookResponse::Normal(Err(Some(v3_kafka_failed())));
+            }
+            let (_session, write_packets) = context.get_mut();
+            write_packets.push_back(packet.into());
+            HookResponse::Normal(Ok(Vec::new()))
+        }
+    }
+}
+
+// Wait for the Kafka receipt of the publish if any (see `Config.kafka.wait_ack`)
+async fn kafka_acked(receipt: Option<oneshot::Receiver<bool>>) -> bool {
+    match receipt {
+        Some(receipt) => receipt.await.unwrap_or(false),
+        None => true,
+    }
+}
+
+// The QoS 1 message not acknowledged by Kafka is rejected
+#[cfg(feature = "v5")]
+fn v5_kafka_failed(packet: &mut v5::Packet) {
+    tracing::warn!("forward to kafka failed, publish rejected");
+    if let v5::Packet::Puback(puback) = packet {
+        puback.reason_code = v5::PubackReasonCode::UnspecifiedError;
+    }
+}
+
+// MQTT v3.x can't reject the publish, the connection is closed without the
+// PUBACK so the client will publish it again after reconnected
+#[cfg(feature = "v3")]
+fn v3_kafka_failed() -> io::Error {
+    tracing::warn!("forward to kafka failed, close the connection");
+    io::Error::new(io::ErrorKind::Other, "forward to kafka failed")
+}
+
+fn audit_acl_denied(
+    global: &GlobalState,
+    peer: SocketAddr,
+    client_identifier: &str,
+    username: &Option<Arc<String>>,
+    action: &str,
+    topics: Vec<String>,
+) {
+    global.audit(AuditEvent::AclDenied {
+        client_identifier: client_identifier.to_owned(),
+        username: username.as_ref().map(|name| name.to_string()),
+        peer,
+        action: action.to_owned(),
+        topics,
+    });
+}
+
+#[cfg(all(test, feature = "v5"))]
+mod tests {
+    use super::*;
+    use mqtt_proto::{v5::SubscriptionOptions, Pid};
+
+    use crate::config::Config;
+
+    struct Acl;
+
+    impl Authorizer for Acl {
+        async fn authorize_publish(
+            &self,
+            client: HookClient<'_>,
+            topic_name: &TopicName,
+        ) -> HookResult<bool> {
+            Ok(topic_name.starts_with(client.client_identifier))
+        }
+
+        async fn authorize_subscribe(
+            &self,
+            _client: HookClient<'_>,
+            topic_filter: &TopicFilter,
+        ) -> HookResult<bool> {
+            Ok(!topic_filter.starts_with('#'))
+        }
+    }
+
+    struct Uppercase;
+
+    impl MessageInterceptor for Uppercase {
+        async fn intercept_publish(
+            &self,
+            _client: HookClient<'_>,
+            _topic_name: &TopicName,
+            payload: &mut Bytes,
+        ) -> HookResult<bool> {
+            *payload = Bytes::from(payload.to_ascii_uppercase());
+            Ok(true)
+        }
+    }
+
+    fn publish(topic_name: &str) -> v5::Publish {
+        let topic_name = TopicName::try_from(topic_name.to_owned()).unwrap();
+        v5::Publish::new(QosPid::Level0, topic_name, Bytes::from_static(b"abc"))
+    }
+
+    fn subscribe(topic_filters: &[&str]) -> v5::Subscribe {
+        let topics = topic_filters
+            .iter()
+            .map(|filter| {
+                let filter = TopicFilter::try_from(filter.to_string()).unwrap();
+                (filter, SubscriptionOptions::new(QoS::Level0))
+            })
+            .collect();
+        v5::Subscribe::new(Pid::try_from(1).unwrap(), topics)
+    }
+
+    #[tokio::test]
+    async fn test_composed_hook() {
+        let hook = ComposedHook::new().authorizer(Acl).interceptor(Uppercase);
+        let peer = "127.0.0.1:1883".parse().unwrap();
+        let connect = v5::Connect::new(Arc::new("c1".to_owned()), 10);
+        let code = hook.v5_before_connect(peer, &connect).await.unwrap();
+        assert_eq!(code, HookConnectCode::Success);
+
+        let mut session = SessionV5::new(&Config::new_allow_anonymous(), peer);
+        session.client_identifier = Arc::new("c1".to_owned());
+        let mut allowed = publish("c1/a");
+        let mut changed = false;
+        let code = hook
+            .v5_before_publish(&session, 0, &[], &mut allowed, &mut changed)
+            .await
+            .unwrap();
+        assert_eq!(code, HookPublishCode::Success);
+        assert!(changed);
+        assert_eq!(allowed.payload.as_ref(), b"ABC");
+
+        let mut denied = publish("c2/a");
+        let mut changed = false;
+        let code = hook
+            .v5_before_publish(&session, 0, &[], &mut denied, &mut changed)
+            .await
+            .unwrap();
+        assert_eq!(code, HookPublishCode::NotAuthorized);
+        assert!(!changed);
+        assert_eq!(denied.payload.as_ref(), b"abc");
+
+        let code = hook
+            .v5_before_subscribe(&session, 0, &[], &mut subscribe(&["c2/#"]), &mut false)
+            .await
+            .unwrap();
+        assert_eq!(code, HookSubscribeCode::Success);
+        let code = hook
+            .v5_before_subscribe(&session, 0, &[], &mut subscribe(&["c2/#", "#"]), &mut false)
+            .await
+            .unwrap();
+        assert_eq!(code, HookSubscribeCode::NotAuthorized);
     }
 }
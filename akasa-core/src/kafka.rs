@@ -0,0 +1,269 @@
+//! Forward the messages matched by `Config.kafka.rules` to Kafka by the
+//! librdkafka producer (the `kafka` feature, plain TCP only).
+//!
+//! The messages are queued and produced by librdkafka in batches, the keyed
+//! messages are partitioned like the Java client. When
+//! `Config.kafka.wait_ack` is set, the PUBACK of a QoS 1 message waits for the
+//! acknowledgement of Kafka (at least once), a failed message is rejected with
+//! UnspecifiedError (v5.0) or by closing the connection (v3.x), so the client
+//! can publish it again.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use flume::{bounded, Receiver, Sender};
+use mqtt_proto::QoS;
+use rdkafka::error::{KafkaError, KafkaResult};
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::types::RDKafkaErrorCode;
+use rdkafka::ClientConfig;
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+
+use crate::config::KafkaConfig;
+use crate::forward::Forwarded;
+use crate::metrics::Metrics;
+use crate::protocols::mqtt::{render_topic_levels, topic_match};
+use crate::state::GlobalState;
+
+const QUEUE_SIZE: usize = 65536;
+// Wait for the queue of librdkafka when it's full
+const QUEUE_FULL_BACKOFF: Duration = Duration::from_millis(100);
+// The record header of the MQTT topic name
+const TOPIC_HEADER: &str = "mqtt_topic";
+
+struct KafkaMessage {
+    topic: String,
+    key: Option<Vec<u8>>,
+    value: Bytes,
+    mqtt_topic: String,
+    // Unix timestamp in milliseconds
+    timestamp: i64,
+    // Notified with true when acknowledged, dropped when failed
+    receipt: Option<oneshot::Sender<bool>>,
+}
+
+/// The messages waiting to be sent to Kafka
+pub(crate) struct KafkaQueue {
+    sender: Sender<KafkaMessage>,
+    receiver: Receiver<KafkaMessage>,
+}
+
+impl Default for KafkaQueue {
+    fn default() -> KafkaQueue {
+        let (sender, receiver) = bounded(QUEUE_SIZE);
+        KafkaQueue { sender, receiver }
+    }
+}
+
+/// Forward the message by the first matched rule of `Config.kafka.rules`
+pub(crate) fn forward(
+    global: &GlobalState,
+    topic_name: &str,
+    payload: &Bytes,
+    qos: QoS,
+) -> Forwarded {
+    let config = global.config();
+    let kafka = &config.kafka;
+    if kafka.brokers.is_empty() {
+        return Forwarded::No;
+    }
+    let rule = match kafka
+        .rules
+        .iter()
+        .find(|rule| topic_match(&rule.topic_filter, topic_name))
+    {
+        Some(rule) => rule,
+        None => return Forwarded::No,
+    };
+    let (receipt, waiting) = if kafka.wait_ack && qos == QoS::Level1 {
+        let (sender, receiver) = oneshot::channel();
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+    send(
+        global,
+        render_topic_levels(&rule.kafka_topic, topic_name),
+        rule.key
+            .as_ref()
+            .map(|key| render_topic_levels(key, topic_name).into_bytes()),
+        topic_name,
+        payload.clone(),
+        receipt,
+    );
+    match waiting {
+        Some(receiver) => Forwarded::Wait(receiver),
+        None => Forwarded::Queued,
+    }
+}
+
+/// Queue a message to the Kafka topic, dropped if the topic is invalid or the
+/// queue is full (the waiting publisher is failed by the dropped receipt).
+pub(crate) fn send(
+    global: &GlobalState,
+    topic: String,
+    key: Option<Vec<u8>>,
+    mqtt_topic: &str,
+    value: Bytes,
+    receipt: Option<oneshot::Sender<bool>>,
+) {
+    if !valid_topic(&topic) {
+        tracing::warn!("invalid kafka topic {:?} of {}", topic, mqtt_topic);
+        Metrics::incr(&global.metrics.kafka_dropped);
+        return;
+    }
+    let message = KafkaMessage {
+        topic,
+        key,
+        value,
+        mqtt_topic: mqtt_topic.to_owned(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as i64)
+            .unwrap_or(0),
+        receipt,
+    };
+    if global.kafka_queue.sender.try_send(message).is_err() {
+        tracing::warn!("kafka queue is full, message dropped: {}", mqtt_topic);
+        Metrics::incr(&global.metrics.kafka_dropped);
+    }
+}
+
+fn valid_topic(topic: &str) -> bool {
+    !matches!(topic, "" | "." | "..")
+        && topic.len() <= 249
+        && topic
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+}
+
+/// Send the queued messages to Kafka, the producer is created again when the
+/// client settings of `Config.kafka` changed.
+pub(crate) async fn run(global: Arc<GlobalState>) {
+    let receiver = &global.kafka_queue.receiver;
+    // The producer and the config it's created by
+    let mut current: Option<(KafkaConfig, FutureProducer)> = None;
+    while let Ok(message) = receiver.recv_async().await {
+        // Read the config every time, since the config can be reloaded
+        let config = global.config();
+        if !matches!(&current, Some((kafka, _)) if same_client(kafka, &config.kafka)) {
+            current = match create_producer(&config.kafka) {
+                Ok(producer) => Some((config.kafka.clone(), producer)),
+                Err(err) => {
+                    tracing::warn!("create kafka producer error: {}", err);
+                    Metrics::incr(&global.metrics.kafka_dropped);
+                    continue;
+                }
+            };
+        }
+        let (_, producer) = current.as_ref().expect("kafka producer");
+
+        let headers = OwnedHeaders::new().insert(Header {
+            key: TOPIC_HEADER,
+            value: Some(message.mqtt_topic.as_bytes()),
+        });
+        let mut record = FutureRecord::to(&message.topic)
+            .payload(message.value.as_ref())
+            .timestamp(message.timestamp)
+            .headers(headers);
+        if let Some(key) = message.key.as_ref() {
+            record = record.key(key.as_slice());
+        }
+        let delivery = loop {
+            match producer.send_result(record) {
+                Ok(delivery) => break Some(delivery),
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) => {
+                    record = returned;
+                    sleep(QUEUE_FULL_BACKOFF).await;
+                }
+                Err((err, _)) => {
+                    tracing::warn!("send to kafka topic {} error: {}", message.topic, err);
+                    break None;
+                }
+            }
+        };
+        let delivery = match delivery {
+            Some(delivery) => delivery,
+            None => {
+                Metrics::incr(&global.metrics.kafka_dropped);
+                continue;
+            }
+        };
+        // The delivery is reported after the batch acknowledged (or failed
+        // after the retries of librdkafka)
+        let global = Arc::clone(&global);
+        let receipt = message.receipt;
+        tokio::spawn(async move {
+            let acked = match delivery.await {
+                Ok(Ok(_)) => true,
+                Ok(Err((err, _))) => {
+                    tracing::warn!("send message to kafka failed, dropped: {}", err);
+                    false
+                }
+                // The producer is dropped (the config changed)
+                Err(_) => false,
+            };
+            if acked {
+                Metrics::incr(&global.metrics.kafka_sent);
+            } else {
+                Metrics::incr(&global.metrics.kafka_dropped);
+            }
+            if let Some(receipt) = receipt {
+                let _ = receipt.send(acked);
+            }
+        });
+    }
+}
+
+// If the producers of the two configs are the same (the rules are not used
+// by the producer)
+fn same_client(a: &KafkaConfig, b: &KafkaConfig) -> bool {
+    a.brokers == b.brokers
+        && a.client_id == b.client_id
+        && a.batch_size == b.batch_size
+        && a.linger_ms == b.linger_ms
+        && a.timeout == b.timeout
+}
+
+fn create_producer(config: &KafkaConfig) -> KafkaResult<FutureProducer> {
+    let timeout_ms = (config.timeout * 1000).to_string();
+    ClientConfig::new()
+        .set("bootstrap.servers", config.brokers.join(","))
+        .set("client.id", &config.client_id)
+        .set("batch.num.messages", config.batch_size.to_string())
+        .set("linger.ms", config.linger_ms.to_string())
+        .set("request.timeout.ms", &timeout_ms)
+        .set("message.timeout.ms", &timeout_ms)
+        // Wait for all the in-sync replicas
+        .set("acks", "all")
+        // The same as the default partitioner of the Java client
+        .set("partitioner", "murmur2_random")
+        .create()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        assert_eq!(
+            render_topic_levels("iot.%1", "sensors/room1/temp"),
+            "iot.sensors"
+        );
+        assert_eq!(
+            render_topic_levels("%2-%3", "sensors/room1/temp"),
+            "room1-temp"
+        );
+        assert_eq!(render_topic_levels("%4%%0", "sensors/room1"), "%%0");
+        assert!(valid_topic("iot.sensors_1-a"));
+        assert!(valid_topic("iot."));
+        assert!(!valid_topic(".."));
+        assert!(valid_topic(&render_topic_levels("iot.%1", "a")));
+        assert!(!valid_topic(&render_topic_levels("%2", "a")));
+        assert!(!valid_topic("iot/sensors"));
+    }
+}
@@ -1,25 +1,82 @@
+mod alarm;
+#[cfg(feature = "amqp")]
+mod amqp;
+mod audit;
+mod ban;
+mod broker;
 mod config;
+mod delayed;
+mod diagnostics;
+mod error;
+mod events;
+mod fanout;
+mod flapping;
+mod forward;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
+mod health;
 mod hook;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod limiter;
+mod memory;
+mod metrics;
+mod protobuf;
 mod protocols;
+mod publish;
+mod quota;
+mod rule;
+mod schema;
 pub mod server;
+mod sparkplug;
 mod state;
+mod stats;
 mod storage;
+mod sys;
+#[cfg(any(all(test, feature = "v3", feature = "v5"), feature = "testkit"))]
+pub mod testkit;
+mod timer;
+#[cfg(feature = "tsdb")]
+mod tsdb;
+#[cfg(feature = "webhook")]
+mod webhook;
 
-#[cfg(test)]
+#[cfg(not(any(feature = "v3", feature = "v5")))]
+compile_error!("at least one of the \"v3\" and \"v5\" features must be enabled");
+
+#[cfg(all(test, feature = "v3", feature = "v5"))]
 mod tests;
 
-pub use crate::config::Config;
+pub use crate::audit::AuditEvent;
+pub use crate::broker::{
+    Broker, BrokerBuilder, BrokerHandle, ShutdownHandle, DEFAULT_DRAIN_TIMEOUT,
+};
+pub use crate::config::{Config, Listener, Listeners, ProxyMode, TlsListener};
+pub use crate::error::Error;
+pub use crate::events::ClientEvent;
+pub use crate::health::{Alarm, Health, HealthReport, ListenerHealth};
 pub use crate::hook::{
-    Hook, HookAction, HookConnectCode, HookError, HookPublishCode, HookRequest, HookResponse,
-    HookResult, HookSubscribeCode, HookUnsubscribeCode, PublishAction, SubscribeAction,
+    Authenticator, Authorizer, ComposedHook, Hook, HookAction, HookClient, HookConnectCode,
+    HookError, HookPublishCode, HookRequest, HookResponse, HookResult, HookSubscribeCode,
+    HookUnsubscribeCode, MessageInterceptor, NoopHook, PublishAction, SubscribeAction,
     UnsubscribeAction,
 };
+pub use crate::memory::{MemoryCharge, MemoryUsage};
+pub use crate::metrics::{
+    AllocatorStats, LatencyHistograms, LatencyStage, LatencySummary, Metrics,
+};
+#[cfg(feature = "v3")]
+pub use crate::protocols::mqtt::v3::Session as SessionV3;
+#[cfg(feature = "v5")]
+pub use crate::protocols::mqtt::v5::{Session as SessionV5, SubscriptionData};
 pub use crate::protocols::mqtt::{
-    dump_passwords, hash_password, load_passwords,
-    v3::Session as SessionV3,
-    v5::{Session as SessionV5, SubscriptionData},
-    MIN_SALT_LEN,
+    dump_passwords, hash_password, load_packet_records, load_passwords, replay_packets,
+    PacketDirection, PacketRecord, PacketTraceOptions, PacketTracer, RouteTable, SessionInfo,
+    SubscriptionInfo, MIN_SALT_LEN,
 };
-pub use crate::state::{AuthPassword, GlobalState, HashAlgorithm};
+pub use crate::server::{LocalClient, SubscribedMessage};
+pub use crate::state::{AuthPassword, ClientId, GlobalState, HashAlgorithm, TopicMatch};
+pub use crate::stats::{ClientStats, ClientTraffic, TopTalkers, TopicStats, TopicTraffic};
 
 pub use mqtt_proto;
@@ -0,0 +1,294 @@
+//! The rate limiters of the server.
+//!
+//! The inbound publish rate limiter (see `Config.inbound_limit`): a client
+//! takes a permit before reading the next packet, the permit is used by a
+//! PUBLISH packet. When the permits are exhausted the clients wait in a queue
+//! and are served in turn, so a burst from many clients is shared fairly
+//! instead of saturating the fan out.
+//!
+//! The connection rate limiter (see `Config.connect_limit`): the new
+//! connections are refused when the leaky bucket of the source IP or the
+//! server is full, so the reconnect storm after a network outage is spread.
+
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use hashbrown::{HashMap, HashSet};
+use parking_lot::Mutex;
+
+use crate::config::{ConnectLimitConfig, InboundLimitConfig};
+use crate::metrics::Metrics;
+use crate::state::{ClientId, GlobalState};
+
+const TICK: Duration = Duration::from_millis(10);
+// Remove the drained buckets of the source IPs in this interval
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) struct InboundLimiter {
+    state: Mutex<LimiterState>,
+    // The task granting the permits to the waiting clients is started by the
+    // first waiting client
+    running: AtomicBool,
+}
+
+struct LimiterState {
+    tokens: f64,
+    updated_at: Instant,
+    // The waiting clients in order, a client is queued once
+    queue: VecDeque<ClientId>,
+    wakers: HashMap<ClientId, Waker>,
+    // The clients granted a permit, not taken yet
+    granted: HashSet<ClientId>,
+}
+
+impl Default for InboundLimiter {
+    fn default() -> InboundLimiter {
+        InboundLimiter {
+            state: Mutex::new(LimiterState {
+                tokens: 0.0,
+                updated_at: Instant::now(),
+                queue: VecDeque::new(),
+                wakers: HashMap::new(),
+                granted: HashSet::new(),
+            }),
+            running: AtomicBool::new(false),
+        }
+    }
+}
+
+impl InboundLimiter {
+    /// Remove the client from the queue (the client is disconnected)
+    pub(crate) fn cancel(&self, client_id: ClientId) {
+        let mut state = self.state.lock();
+        state.wakers.remove(&client_id);
+        state.granted.remove(&client_id);
+    }
+}
+
+impl LimiterState {
+    fn refill(&mut self, config: &InboundLimitConfig) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        let rate = config.max_messages_per_sec as f64;
+        let burst = if config.burst_messages == 0 {
+            rate
+        } else {
+            config.burst_messages as f64
+        };
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        self.updated_at = now;
+    }
+}
+
+/// Take a permit, the client is woken up when it is its turn
+pub(crate) fn poll_acquire(
+    global: &Arc<GlobalState>,
+    client_id: ClientId,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    let config = global.config();
+    if config.inbound_limit.max_messages_per_sec == 0 {
+        return Poll::Ready(());
+    }
+    let limiter = &global.inbound_limiter;
+    let mut state = limiter.state.lock();
+    if state.granted.remove(&client_id) {
+        return Poll::Ready(());
+    }
+    state.refill(&config.inbound_limit);
+    if state.queue.is_empty() && state.tokens >= 1.0 {
+        state.tokens -= 1.0;
+        return Poll::Ready(());
+    }
+    if state.wakers.insert(client_id, cx.waker().clone()).is_none() {
+        state.queue.push_back(client_id);
+        Metrics::incr(&global.metrics.inbound_throttled);
+    }
+    drop(state);
+    if !limiter.running.swap(true, Ordering::AcqRel) {
+        tokio::spawn(run(Arc::downgrade(global)));
+    }
+    Poll::Pending
+}
+
+async fn run(global: Weak<GlobalState>) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        let global = match global.upgrade() {
+            Some(global) => global,
+            None => break,
+        };
+        let config = global.config();
+        let mut wakers = Vec::new();
+        {
+            let mut state = global.inbound_limiter.state.lock();
+            state.refill(&config.inbound_limit);
+            // Disabled at runtime, release all the waiting clients
+            let unlimited = config.inbound_limit.max_messages_per_sec == 0;
+            while unlimited || state.tokens >= 1.0 {
+                let client_id = match state.queue.pop_front() {
+                    Some(client_id) => client_id,
+                    None => break,
+                };
+                // Already cancelled
+                let waker = match state.wakers.remove(&client_id) {
+                    Some(waker) => waker,
+                    None => continue,
+                };
+                if !unlimited {
+                    state.tokens -= 1.0;
+                }
+                state.granted.insert(client_id);
+                wakers.push(waker);
+            }
+        }
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+pub(crate) struct ConnectLimiter {
+    state: Mutex<ConnectLimiterState>,
+}
+
+struct ConnectLimiterState {
+    global: LeakyBucket,
+    per_ip: HashMap<IpAddr, LeakyBucket>,
+    pruned_at: Instant,
+}
+
+#[derive(Clone, Copy)]
+struct LeakyBucket {
+    // The accepted connections not leaked yet
+    level: f64,
+    updated_at: Instant,
+}
+
+impl Default for ConnectLimiter {
+    fn default() -> ConnectLimiter {
+        let now = Instant::now();
+        ConnectLimiter {
+            state: Mutex::new(ConnectLimiterState {
+                global: LeakyBucket::new(now),
+                per_ip: HashMap::new(),
+                pruned_at: now,
+            }),
+        }
+    }
+}
+
+impl ConnectLimiter {
+    /// Accept a new connection from the source IP, false means refused
+    pub(crate) fn check(&self, config: &ConnectLimitConfig, ip: IpAddr) -> bool {
+        if config.per_ip_per_sec == 0 && config.global_per_sec == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        if now.saturating_duration_since(state.pruned_at) >= PRUNE_INTERVAL {
+            let rate = config.per_ip_per_sec;
+            state
+                .per_ip
+                .retain(|_, bucket| rate > 0 && bucket.leak(now, rate) > 0.0);
+            state.pruned_at = now;
+        }
+        let mut per_ip = state
+            .per_ip
+            .get(&ip)
+            .copied()
+            .unwrap_or_else(|| LeakyBucket::new(now));
+        let accepted = per_ip.try_add(now, config.per_ip_per_sec, config.per_ip_burst)
+            && state
+                .global
+                .try_add(now, config.global_per_sec, config.global_burst);
+        if accepted && config.per_ip_per_sec > 0 {
+            state.per_ip.insert(ip, per_ip);
+        }
+        accepted
+    }
+}
+
+impl LeakyBucket {
+    fn new(now: Instant) -> LeakyBucket {
+        LeakyBucket {
+            level: 0.0,
+            updated_at: now,
+        }
+    }
+
+    fn leak(&mut self, now: Instant, rate: u64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.level = (self.level - elapsed.as_secs_f64() * rate as f64).max(0.0);
+        self.updated_at = now;
+        self.level
+    }
+
+    // Add a connection when the bucket is not full, rate 0 means unlimited
+    // and burst 0 means the connections of one second.
+    fn try_add(&mut self, now: Instant, rate: u64, burst: u64) -> bool {
+        if rate == 0 {
+            return true;
+        }
+        let burst = if burst == 0 { rate } else { burst };
+        if self.leak(now, rate) + 1.0 > burst as f64 {
+            return false;
+        }
+        self.level += 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(per_ip: u64, global: u64) -> ConnectLimitConfig {
+        ConnectLimitConfig {
+            per_ip_per_sec: per_ip,
+            per_ip_burst: 3,
+            global_per_sec: global,
+            global_burst: 5,
+        }
+    }
+
+    #[test]
+    fn test_connect_limit_per_ip() {
+        let limiter = ConnectLimiter::default();
+        let config = config(1, 0);
+        let ip1: IpAddr = [10, 0, 0, 1].into();
+        let ip2: IpAddr = [10, 0, 0, 2].into();
+        for _ in 0..3 {
+            assert!(limiter.check(&config, ip1));
+        }
+        assert!(!limiter.check(&config, ip1));
+        assert!(limiter.check(&config, ip2));
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(limiter.check(&config, ip1));
+        assert!(!limiter.check(&config, ip1));
+    }
+
+    #[test]
+    fn test_connect_limit_global() {
+        let limiter = ConnectLimiter::default();
+        let config = config(0, 1);
+        for idx in 0..5u8 {
+            assert!(limiter.check(&config, [10, 0, 0, idx].into()));
+        }
+        assert!(!limiter.check(&config, [10, 0, 0, 9].into()));
+        // A refused connection does not fill the bucket of the source IP
+        let config = ConnectLimitConfig {
+            per_ip_per_sec: 1,
+            per_ip_burst: 1,
+            ..config
+        };
+        assert!(!limiter.check(&config, [10, 0, 0, 9].into()));
+        assert!(limiter.state.lock().per_ip.is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+//! The memory accounting of the queued messages (see `Config.memory_limit`)
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The payload bytes held by the pending packets and the broadcast queues of
+/// all the sessions (the retained messages are counted by `RetainTable`).
+#[derive(Default)]
+pub struct MemoryUsage {
+    pending: Arc<AtomicUsize>,
+    broadcast: Arc<AtomicUsize>,
+}
+
+impl MemoryUsage {
+    /// The payload bytes of the pending packets not sent yet
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// The payload bytes of the messages waiting in the broadcast queues
+    pub fn broadcast(&self) -> usize {
+        self.broadcast.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn pending_charge(&self) -> MemoryCharge {
+        MemoryCharge::new(&self.pending)
+    }
+
+    pub(crate) fn broadcast_charge(&self) -> MemoryCharge {
+        MemoryCharge::new(&self.broadcast)
+    }
+}
+
+/// The bytes charged to a shared counter by a queue, released when dropped.
+/// The default charge is not counted anywhere.
+#[derive(Default)]
+pub struct MemoryCharge {
+    counter: Option<Arc<AtomicUsize>>,
+    bytes: usize,
+}
+
+impl MemoryCharge {
+    fn new(counter: &Arc<AtomicUsize>) -> MemoryCharge {
+        MemoryCharge {
+            counter: Some(Arc::clone(counter)),
+            bytes: 0,
+        }
+    }
+
+    /// The bytes charged by this queue
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn add(&mut self, size: usize) {
+        self.bytes += size;
+        if let Some(counter) = self.counter.as_ref() {
+            counter.fetch_add(size, Ordering::Relaxed);
+        }
+    }
+
+    pub fn sub(&mut self, size: usize) {
+        self.bytes -= size;
+        if let Some(counter) = self.counter.as_ref() {
+            counter.fetch_sub(size, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        let bytes = self.bytes;
+        self.sub(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_charge() {
+        let usage = MemoryUsage::default();
+        let mut charge = usage.pending_charge();
+        charge.add(10);
+        charge.add(5);
+        charge.sub(3);
+        let mut other = usage.pending_charge();
+        other.add(8);
+        assert_eq!(usage.pending(), 20);
+        drop(charge);
+        assert_eq!(usage.pending(), 8);
+        drop(other);
+        assert_eq!((usage.pending(), usage.broadcast()), (0, 0));
+
+        let mut detached = MemoryCharge::default();
+        detached.add(4);
+        assert_eq!((detached.bytes(), usage.pending()), (4, 0));
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use hdrhistogram::Histogram;
+use mqtt_proto::QoS;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+// The latency larger than this (microseconds) is recorded as this value
+const MAX_LATENCY_MICROS: u64 = 60_000_000;
+
+/// Server side counters
+#[derive(Default)]
+pub struct Metrics {
+    /// Slow consumers detected (see `Config.slow_consumer`)
+    pub slow_consumers: AtomicU64,
+    /// Messages dropped because the pending messages queue is full (see
+    /// `Config.pending_overflow_policy`)
+    pub pending_dropped: AtomicU64,
+    /// Clients disconnected because the pending messages queue is full
+    pub pending_overflow_disconnects: AtomicU64,
+    /// Clients disconnected because an inflight message is not acknowledged
+    /// after re-sent too many times (see `Config.retransmit`)
+    pub retransmit_disconnects: AtomicU64,
+    /// Retained messages not stored (dropped or rejected) because of the
+    /// limits (see `Config.retain_limits`)
+    pub retain_dropped: AtomicU64,
+    /// Publishes rejected because the payload is too large for the topic (see
+    /// `Config.payload_limits`)
+    pub payload_rejected: AtomicU64,
+    /// Publishes failed the payload schemas, rejected or dead lettered (see
+    /// `Config.payload_schemas`)
+    pub schema_rejected: AtomicU64,
+    /// Messages not sent to a receiver because too many messages are queued
+    /// for it (see `Config.broadcast`)
+    pub broadcast_dropped: AtomicU64,
+    /// Write buffers taken from the pool (see `Config.buffer_pool`)
+    pub buffer_pool_hits: AtomicU64,
+    /// Write buffers allocated because the pool is empty
+    pub buffer_pool_misses: AtomicU64,
+    /// QoS 0 messages dropped because of overloaded (see `Config.qos0_shedding`)
+    pub qos0_shed: AtomicU64,
+    /// Connections refused because of the memory pressure (see
+    /// `Config.memory_limit`)
+    pub memory_refused_connects: AtomicU64,
+    /// Jobs sent to the fan out workers (see `Config.fan_out`)
+    pub fan_out_jobs: AtomicU64,
+    /// Times a client waited for the inbound rate limit (see
+    /// `Config.inbound_limit`)
+    pub inbound_throttled: AtomicU64,
+    /// Connections refused by the connection rate limit (see
+    /// `Config.connect_limit`)
+    pub throttled_connects: AtomicU64,
+    /// Messages refused by the quotas (see `Config.quotas`)
+    pub quota_exceeded: AtomicU64,
+    /// Connections refused by the bans
+    pub banned_connects: AtomicU64,
+    /// Clients banned as flapping (see `Config.flapping`)
+    pub flapping_bans: AtomicU64,
+    /// Messages acknowledged by Kafka (see `Config.kafka`)
+    pub kafka_sent: AtomicU64,
+    /// Messages not sent to Kafka (the queue is full or failed)
+    pub kafka_dropped: AtomicU64,
+    /// Messages sent to the AMQP broker (see `Config.amqp`)
+    pub amqp_sent: AtomicU64,
+    /// Messages received from the AMQP broker and published
+    pub amqp_received: AtomicU64,
+    /// Messages not sent to the AMQP broker or not published
+    pub amqp_dropped: AtomicU64,
+    /// Messages posted to the endpoints (see `Config.webhook_forward`)
+    pub webhook_forward_sent: AtomicU64,
+    /// Messages failed permanently (published to the dead letter topic)
+    pub webhook_forward_failed: AtomicU64,
+    /// Messages not queued (the queue is full or invalid headers)
+    pub webhook_forward_dropped: AtomicU64,
+    /// Points written to the time-series database (see `Config.tsdb`)
+    pub tsdb_written: AtomicU64,
+    /// Points rejected by the time-series database
+    pub tsdb_failed: AtomicU64,
+    /// Invalid payloads and the points not queued (too many pending points)
+    pub tsdb_dropped: AtomicU64,
+    /// Messages matched by the rules (see `Config.rules`), counted by rule
+    pub rules_matched: AtomicU64,
+    /// The republish actions of the rules failed
+    pub rules_failed: AtomicU64,
+    /// Sparkplug B messages out of sequence (see `Config.sparkplug`)
+    pub sparkplug_sequence_errors: AtomicU64,
+    /// The latency of the published messages in the server (see
+    /// `Config.latency_metrics`)
+    pub latency: LatencyHistograms,
+    // Read the statistics of the global allocator (set by the server binary)
+    allocator_stats: OnceLock<fn() -> Option<AllocatorStats>>,
+}
+
+/// The memory statistics of the global allocator
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct AllocatorStats {
+    /// The allocator name
+    pub allocator: &'static str,
+    /// Bytes allocated by the server (committed bytes for mimalloc)
+    pub allocated: u64,
+    /// Bytes of the physical memory used by the allocator
+    pub resident: u64,
+}
+
+/// The stages of a published message in the server, the latency of every
+/// stage is measured from the time the message is received.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LatencyStage {
+    /// The matched subscriptions are found
+    Route,
+    /// The message is sent to the channel of the receiver
+    Enqueue,
+    /// The packet is put into the write queue of the receiver's connection,
+    /// the time after this is spent by the network (and the client).
+    Write,
+}
+
+/// The HDR histograms (in microseconds) of every stage and QoS level
+pub struct LatencyHistograms {
+    // [stage][qos]
+    histograms: [[Mutex<Histogram<u64>>; 3]; 3],
+}
+
+/// The latency summary (in microseconds) of a histogram
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+impl Metrics {
+    #[inline]
+    pub(crate) fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Set the function to read the statistics of the global allocator, since
+    /// the allocator is chosen by the server binary. Only the first call takes
+    /// effect.
+    pub fn set_allocator_stats(&self, stats: fn() -> Option<AllocatorStats>) {
+        let _ = self.allocator_stats.set(stats);
+    }
+
+    pub fn allocator_stats(&self) -> Option<AllocatorStats> {
+        self.allocator_stats.get().and_then(|stats| stats())
+    }
+}
+
+impl LatencyStage {
+    pub const ALL: [LatencyStage; 3] = [
+        LatencyStage::Route,
+        LatencyStage::Enqueue,
+        LatencyStage::Write,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LatencyStage::Route => "route",
+            LatencyStage::Enqueue => "enqueue",
+            LatencyStage::Write => "write",
+        }
+    }
+}
+
+impl Default for LatencyHistograms {
+    fn default() -> LatencyHistograms {
+        // 2 significant digits is enough for latency, and keeps the histograms small
+        let histograms = std::array::from_fn(|_| {
+            std::array::from_fn(|_| {
+                Mutex::new(Histogram::new_with_bounds(1, MAX_LATENCY_MICROS, 2).expect("histogram"))
+            })
+        });
+        LatencyHistograms { histograms }
+    }
+}
+
+impl LatencyHistograms {
+    /// Record the latency from the message received to now
+    pub(crate) fn record(&self, stage: LatencyStage, qos: QoS, received_at: Instant) {
+        let micros = received_at.elapsed().as_micros() as u64;
+        self.histograms[stage as usize][qos as usize]
+            .lock()
+            .saturating_record(micros);
+    }
+
+    /// The summaries by stage name and QoS level (`qos0`, `qos1`, `qos2`)
+    pub fn report(&self) -> BTreeMap<&'static str, BTreeMap<&'static str, LatencySummary>> {
+        LatencyStage::ALL
+            .iter()
+            .map(|stage| {
+                let summaries = ["qos0", "qos1", "qos2"]
+                    .into_iter()
+                    .zip(&self.histograms[*stage as usize])
+                    .map(|(qos, histogram)| {
+                        let histogram = histogram.lock();
+                        let summary = LatencySummary {
+                            count: histogram.len(),
+                            mean: histogram.mean(),
+                            p50: histogram.value_at_quantile(0.5),
+                            p90: histogram.value_at_quantile(0.9),
+                            p99: histogram.value_at_quantile(0.99),
+                            p999: histogram.value_at_quantile(0.999),
+                            max: histogram.max(),
+                        };
+                        (qos, summary)
+                    })
+                    .collect();
+                (stage.name(), summaries)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histograms() {
+        let latency = LatencyHistograms::default();
+        let received_at = Instant::now();
+        latency.record(LatencyStage::Route, QoS::Level0, received_at);
+        latency.record(LatencyStage::Write, QoS::Level1, received_at);
+        latency.record(LatencyStage::Write, QoS::Level1, received_at);
+
+        let report = latency.report();
+        assert_eq!(report.len(), 3);
+        assert_eq!(report["route"]["qos0"].count, 1);
+        assert_eq!(report["route"]["qos1"].count, 0);
+        assert_eq!(report["enqueue"]["qos0"].count, 0);
+        assert_eq!(report["write"]["qos1"].count, 2);
+        assert!(report["write"]["qos1"].p50 <= report["write"]["qos1"].max);
+    }
+}
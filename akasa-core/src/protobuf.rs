@@ -0,0 +1,137 @@
+//! A minimal protobuf codec for the hand-written messages (the gRPC API, the
+//! Sparkplug B payloads and the payload schemas), the groups are not
+//! supported.
+
+use bytes::{BufMut, BytesMut};
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed64(u64),
+    Fixed32(u32),
+}
+
+/// The fields of a protobuf message
+pub(crate) fn decode_fields(mut buf: &[u8]) -> Option<Vec<(u64, ProtoValue<'_>)>> {
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = get_varint(&mut buf)?;
+        let value = match key & 0x07 {
+            0 => ProtoValue::Varint(get_varint(&mut buf)?),
+            2 => {
+                let len = usize::try_from(get_varint(&mut buf)?).ok()?;
+                if len > buf.len() {
+                    return None;
+                }
+                let (value, rest) = buf.split_at(len);
+                buf = rest;
+                ProtoValue::Bytes(value)
+            }
+            1 => {
+                let (value, rest) = (buf.get(..8)?, &buf[8..]);
+                buf = rest;
+                ProtoValue::Fixed64(u64::from_le_bytes(value.try_into().ok()?))
+            }
+            5 => {
+                let (value, rest) = (buf.get(..4)?, &buf[4..]);
+                buf = rest;
+                ProtoValue::Fixed32(u32::from_le_bytes(value.try_into().ok()?))
+            }
+            _ => return None,
+        };
+        fields.push((key >> 3, value));
+    }
+    Some(fields)
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// If the bytes are the packed varints of a repeated field
+pub(crate) fn is_packed_varints(mut buf: &[u8]) -> bool {
+    while !buf.is_empty() {
+        if get_varint(&mut buf).is_none() {
+            return false;
+        }
+    }
+    true
+}
+
+fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+/// The default values are not encoded (proto3)
+pub(crate) fn put_varint_field(buf: &mut BytesMut, field: u64, value: u64) {
+    if value != 0 {
+        put_varint(buf, field << 3);
+        put_varint(buf, value);
+    }
+}
+
+pub(crate) fn put_bytes_field(buf: &mut BytesMut, field: u64, value: &[u8]) {
+    if !value.is_empty() {
+        put_varint(buf, (field << 3) | 2);
+        put_varint(buf, value.len() as u64);
+        buf.put_slice(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 127, 128, 300, u64::MAX] {
+            let mut buf = BytesMut::new();
+            put_varint(&mut buf, value);
+            let mut slice = &buf[..];
+            assert_eq!(get_varint(&mut slice), Some(value));
+            assert!(slice.is_empty());
+        }
+        let mut buf = BytesMut::new();
+        put_varint(&mut buf, 300);
+        assert_eq!(&buf[..], &[0xac, 0x02]);
+        assert_eq!(get_varint(&mut &[0x80][..]), None);
+        assert!(is_packed_varints(&[0xac, 0x02, 0x01]));
+        assert!(!is_packed_varints(&[0x01, 0x80]));
+    }
+
+    #[test]
+    fn test_decode_fields() {
+        let mut buf = BytesMut::new();
+        put_varint_field(&mut buf, 1, 150);
+        buf.put_u8((2 << 3) | 1);
+        buf.put_u64_le(7);
+        buf.put_u8((3 << 3) | 5);
+        buf.put_u32_le(9);
+        put_bytes_field(&mut buf, 4, b"abc");
+        assert_eq!(
+            decode_fields(&buf),
+            Some(vec![
+                (1, ProtoValue::Varint(150)),
+                (2, ProtoValue::Fixed64(7)),
+                (3, ProtoValue::Fixed32(9)),
+                (4, ProtoValue::Bytes(b"abc")),
+            ])
+        );
+        // Truncated
+        assert_eq!(decode_fields(&buf[..buf.len() - 1]), None);
+    }
+}
@@ -24,7 +24,7 @@ pub fn load_passwords<R: Read>(input: R) -> io::Result<DashMap<String, AuthPassw
                     if let Some(n) = NonZeroU32::new(raw) {
                         Ok(n)
                     } else {
-                        log::error!(
+                        tracing::error!(
                             "invalid hash algorithm iterations(line:#{}): {}",
                             line_num,
                             line
@@ -33,7 +33,7 @@ pub fn load_passwords<R: Read>(input: R) -> io::Result<DashMap<String, AuthPassw
                     }
                 }
                 Err(_) => {
-                    log::error!(
+                    tracing::error!(
                         "invalid hash algorithm iterations(line:#{}): {}",
                         line_num,
                         line
@@ -46,14 +46,14 @@ pub fn load_passwords<R: Read>(input: R) -> io::Result<DashMap<String, AuthPassw
             match STANDARD_NO_PAD.decode(s) {
                 Ok(salt) => {
                     if salt.len() < MIN_SALT_LEN {
-                        log::error!("password salt not enough(line#{}): {}", line_num, line);
+                        tracing::error!("password salt not enough(line#{}): {}", line_num, line);
                         Err(io::ErrorKind::InvalidData.into())
                     } else {
                         Ok(salt)
                     }
                 }
                 Err(_err) => {
-                    log::error!("invalid password salt(line#{}): {}", line_num, line);
+                    tracing::error!("invalid password salt(line#{}): {}", line_num, line);
                     Err(io::ErrorKind::InvalidData.into())
                 }
             }
@@ -62,18 +62,18 @@ pub fn load_passwords<R: Read>(input: R) -> io::Result<DashMap<String, AuthPassw
             match STANDARD_NO_PAD.decode(s) {
                 Ok(pass) => Ok(pass),
                 Err(_err) => {
-                    log::error!("invalid hashed password (line#{}): {}", line_num, line);
+                    tracing::error!("invalid hashed password (line#{}): {}", line_num, line);
                     Err(io::ErrorKind::InvalidData.into())
                 }
             }
         };
 
         if text.is_empty() {
-            log::debug!("skip empty password line: #{}", line_num);
+            tracing::debug!("skip empty password line: #{}", line_num);
         } else if let Some((username, password)) = text.rsplit_once(':') {
             let passwd_parts: Vec<_> = password.split(',').collect();
             if passwd_parts.len() < 3 {
-                log::error!("invalid password part (line#{}): {}", line_num, line);
+                tracing::error!("invalid password part (line#{}): {}", line_num, line);
                 return Err(io::ErrorKind::InvalidData.into());
             }
             let hash_algorithm = match passwd_parts[0] {
@@ -88,7 +88,7 @@ pub fn load_passwords<R: Read>(input: R) -> io::Result<DashMap<String, AuthPassw
                     HashAlgorithm::Sha512Pkbdf2 { iterations }
                 }
                 algo => {
-                    log::error!(
+                    tracing::error!(
                         "invalid password hash algorithm (line#{}): {}",
                         line_num,
                         algo
@@ -99,7 +99,7 @@ pub fn load_passwords<R: Read>(input: R) -> io::Result<DashMap<String, AuthPassw
             let (salt, hashed_password) = match hash_algorithm {
                 HashAlgorithm::Sha256 | HashAlgorithm::Sha512 => {
                     if passwd_parts.len() != 3 {
-                        log::error!("invalid password line(#{}): {}", line_num, line);
+                        tracing::error!("invalid password line(#{}): {}", line_num, line);
                         return Err(io::ErrorKind::InvalidData.into());
                     }
                     let salt = parse_salt(passwd_parts[1])?;
@@ -108,7 +108,7 @@ pub fn load_passwords<R: Read>(input: R) -> io::Result<DashMap<String, AuthPassw
                 }
                 HashAlgorithm::Sha256Pkbdf2 { .. } | HashAlgorithm::Sha512Pkbdf2 { .. } => {
                     if passwd_parts.len() != 4 {
-                        log::error!("invalid password line(#{}): {}", line_num, line);
+                        tracing::error!("invalid password line(#{}): {}", line_num, line);
                         return Err(io::ErrorKind::InvalidData.into());
                     }
                     let salt = parse_salt(passwd_parts[2])?;
@@ -123,7 +123,7 @@ pub fn load_passwords<R: Read>(input: R) -> io::Result<DashMap<String, AuthPassw
             };
             passwords.insert(username.to_owned(), item);
         } else {
-            log::error!("invalid password line(#{}): {}", line_num, line);
+            tracing::error!("invalid password line(#{}): {}", line_num, line);
             return Err(io::ErrorKind::InvalidData.into());
         }
     }
@@ -0,0 +1,71 @@
+//! The per-executor (thread local) pool of the buffers for writing the
+//! encoded packets (see `Config.buffer_pool`)
+
+use std::cell::RefCell;
+
+use crate::config::BufferPoolConfig;
+use crate::metrics::Metrics;
+
+thread_local! {
+    static BUFFERS: RefCell<Vec<Vec<u8>>> = RefCell::new(Vec::new());
+}
+
+/// Take an empty buffer from the pool, or allocate a new one
+pub(crate) fn take(metrics: &Metrics) -> Vec<u8> {
+    match BUFFERS.with(|buffers| buffers.borrow_mut().pop()) {
+        Some(buffer) => {
+            Metrics::incr(&metrics.buffer_pool_hits);
+            buffer
+        }
+        None => {
+            Metrics::incr(&metrics.buffer_pool_misses);
+            Vec::new()
+        }
+    }
+}
+
+/// Put the buffer back to the pool, the buffer is dropped if it's too large
+/// or the pool is full.
+pub(crate) fn put(mut buffer: Vec<u8>, config: &BufferPoolConfig) {
+    if buffer.capacity() == 0 || buffer.capacity() > config.max_buffer_size {
+        return;
+    }
+    buffer.clear();
+    BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        if buffers.len() < config.max_buffers {
+            buffers.push(buffer);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    #[test]
+    fn test_take_put() {
+        let metrics = Metrics::default();
+        let config = BufferPoolConfig {
+            max_buffers: 1,
+            max_buffer_size: 16,
+        };
+        let mut buffer = take(&metrics);
+        buffer.extend_from_slice(b"abc");
+        let capacity = buffer.capacity();
+        put(buffer, &config);
+        // The pool is full
+        put(Vec::with_capacity(8), &config);
+
+        let buffer = take(&metrics);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.capacity(), capacity);
+        // Too large buffer is dropped
+        put(Vec::with_capacity(32), &config);
+        assert_eq!(take(&metrics).capacity(), 0);
+        assert_eq!(metrics.buffer_pool_hits.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.buffer_pool_misses.load(Ordering::Relaxed), 2);
+    }
+}
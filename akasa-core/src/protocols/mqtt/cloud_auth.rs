@@ -0,0 +1,471 @@
+//! The authentication profiles of the cloud IoT platforms (see
+//! `Config.auth.cloud_profiles`), so the devices written for those platforms
+//! can connect without changes.
+
+use std::path::Path;
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey};
+use openssl::sign::Verifier;
+use ring::hmac;
+
+use crate::config::CloudAuthProfile;
+use crate::server::http::percent_decode;
+
+use super::get_unix_ts;
+
+// The accepted clock skew of the `iat` claim
+const CLOCK_SKEW_SECS: u64 = 300;
+
+struct Jwt<'a> {
+    alg: String,
+    // The `{header}.{claims}` part
+    signing_input: &'a [u8],
+    signature: Vec<u8>,
+    claims: serde_json::Value,
+}
+
+/// Authenticate the client by the first matched profile, return `None` if
+/// no profile matched the client.
+pub(crate) async fn check_cloud_auth(
+    profiles: &[CloudAuthProfile],
+    client_identifier: &str,
+    username: Option<&str>,
+    password: Option<&[u8]>,
+) -> Option<bool> {
+    for profile in profiles {
+        match profile {
+            CloudAuthProfile::AzureIotHub {
+                host_name,
+                key,
+                key_name,
+            } => {
+                let device_id =
+                    match username.and_then(|username| azure_device_id(host_name, username)) {
+                        Some(device_id) => device_id,
+                        None => continue,
+                    };
+                let valid = device_id == client_identifier
+                    && password.is_some_and(|password| {
+                        check_sas_token(
+                            host_name,
+                            device_id,
+                            key,
+                            key_name.as_deref(),
+                            password,
+                            get_unix_ts(),
+                        )
+                    });
+                return Some(valid);
+            }
+            CloudAuthProfile::GoogleCloudIot {
+                project_id,
+                public_keys_dir,
+                max_lifetime,
+            } => {
+                let (registry_id, device_id) = match google_device(project_id, client_identifier) {
+                    Some(ids) => ids,
+                    None => continue,
+                };
+                let jwt = match password.and_then(parse_jwt) {
+                    Some(jwt) => jwt,
+                    None => return Some(false),
+                };
+                if !check_claims(&jwt.claims, project_id, *max_lifetime, get_unix_ts()) {
+                    tracing::debug!("invalid JWT claims of device: {}", device_id);
+                    return Some(false);
+                }
+                let valid = match read_public_key(public_keys_dir, registry_id, device_id).await {
+                    Some(pem) => verify_signature(&jwt, &pem),
+                    None => false,
+                };
+                return Some(valid);
+            }
+        }
+    }
+    None
+}
+
+// `{host_name}/{device_id}/?api-version=...`
+fn azure_device_id<'a>(host_name: &str, username: &'a str) -> Option<&'a str> {
+    let (device_id, query) = username
+        .strip_prefix(host_name)?
+        .strip_prefix('/')?
+        .split_once('/')?;
+    if device_id.is_empty() || !(query.is_empty() || query.starts_with('?')) {
+        return None;
+    }
+    Some(device_id)
+}
+
+// `SharedAccessSignature sr={resource}&sig={signature}&se={expiry}[&skn={key_name}]`,
+// the signature is the HMAC-SHA256 of `{resource}\n{expiry}`.
+fn check_sas_token(
+    host_name: &str,
+    device_id: &str,
+    key: &str,
+    key_name: Option<&str>,
+    password: &[u8],
+    now: u64,
+) -> bool {
+    let token = match std::str::from_utf8(password)
+        .ok()
+        .and_then(|token| token.strip_prefix("SharedAccessSignature "))
+    {
+        Some(token) => token,
+        None => return false,
+    };
+    let (mut resource, mut signature, mut expiry, mut token_key_name) = (None, None, None, None);
+    for pair in token.split('&') {
+        match pair.split_once('=') {
+            Some(("sr", value)) => resource = Some(value),
+            Some(("sig", value)) => signature = Some(value),
+            Some(("se", value)) => expiry = Some(value),
+            Some(("skn", value)) => token_key_name = Some(value),
+            _ => {}
+        }
+    }
+    let (resource, signature, expiry) = match (resource, signature, expiry) {
+        (Some(resource), Some(signature), Some(expiry)) => (resource, signature, expiry),
+        _ => return false,
+    };
+    if token_key_name != key_name || !expiry.parse::<u64>().is_ok_and(|expiry| expiry > now) {
+        return false;
+    }
+    // The token of the device, or the token of the hub signed by a policy key
+    let device_resource = format!("{}/devices/{}", host_name, device_id);
+    let valid_resource = percent_decode(resource).is_some_and(|value| {
+        value.eq_ignore_ascii_case(&device_resource)
+            || (key_name.is_some() && value.eq_ignore_ascii_case(host_name))
+    });
+    if !valid_resource {
+        return false;
+    }
+    let (key, signature) = match (
+        STANDARD.decode(key),
+        percent_decode(signature).and_then(|value| STANDARD.decode(value).ok()),
+    ) {
+        (Ok(key), Some(signature)) => (key, signature),
+        _ => return false,
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &key);
+    let message = format!("{}\n{}", resource, expiry);
+    hmac::verify(&key, message.as_bytes(), &signature).is_ok()
+}
+
+// `projects/{project_id}/locations/{region}/registries/{registry_id}/devices/{device_id}`
+fn google_device<'a>(project_id: &str, client_identifier: &'a str) -> Option<(&'a str, &'a str)> {
+    let levels: Vec<_> = client_identifier.split('/').collect();
+    match levels.as_slice() {
+        ["projects", project, "locations", _, "registries", registry_id, "devices", device_id]
+            if *project == project_id =>
+        {
+            // The ids are used in the path of the public key
+            if is_valid_id(registry_id) && is_valid_id(device_id) {
+                Some((*registry_id, *device_id))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && !id.starts_with('.')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.+~%".contains(c))
+}
+
+fn parse_jwt(token: &[u8]) -> Option<Jwt<'_>> {
+    let token = std::str::from_utf8(token).ok()?;
+    let (signing_input, signature) = token.rsplit_once('.')?;
+    let (header, claims) = signing_input.split_once('.')?;
+    let header: serde_json::Value =
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+    Some(Jwt {
+        alg: header.get("alg")?.as_str()?.to_owned(),
+        signing_input: signing_input.as_bytes(),
+        signature: URL_SAFE_NO_PAD.decode(signature).ok()?,
+        claims: serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?,
+    })
+}
+
+fn check_claims(claims: &serde_json::Value, project_id: &str, max_lifetime: u64, now: u64) -> bool {
+    let valid_audience = match claims.get("aud") {
+        Some(serde_json::Value::String(audience)) => audience == project_id,
+        Some(serde_json::Value::Array(audiences)) => audiences
+            .iter()
+            .any(|audience| audience.as_str() == Some(project_id)),
+        _ => false,
+    };
+    let issued_at = claims.get("iat").and_then(serde_json::Value::as_u64);
+    let expires_at = claims.get("exp").and_then(serde_json::Value::as_u64);
+    match (issued_at, expires_at) {
+        (Some(issued_at), Some(expires_at)) => {
+            valid_audience
+                && issued_at <= now + CLOCK_SKEW_SECS
+                && expires_at > now
+                && expires_at
+                    .checked_sub(issued_at)
+                    .is_some_and(|lifetime| lifetime <= max_lifetime)
+        }
+        _ => false,
+    }
+}
+
+async fn read_public_key(
+    public_keys_dir: &Path,
+    registry_id: &str,
+    device_id: &str,
+) -> Option<Vec<u8>> {
+    let path = public_keys_dir
+        .join(registry_id)
+        .join(format!("{}.pem", device_id));
+    match tokio::fs::read(&path).await {
+        Ok(pem) => Some(pem),
+        Err(err) => {
+            tracing::debug!("read public key {:?} failed: {}", path, err);
+            None
+        }
+    }
+}
+
+fn verify_signature(jwt: &Jwt, pem: &[u8]) -> bool {
+    let key = match PKey::public_key_from_pem(pem) {
+        Ok(key) => key,
+        Err(err) => {
+            tracing::warn!("invalid public key: {}", err);
+            return false;
+        }
+    };
+    let signature = match (jwt.alg.as_str(), key.id()) {
+        ("RS256", Id::RSA) => jwt.signature.clone(),
+        // The JWS signature is `r || s`, OpenSSL expects the DER encoding
+        ("ES256", Id::EC) if jwt.signature.len() == 64 => {
+            let (r, s) = jwt.signature.split_at(32);
+            let der = BigNum::from_slice(r)
+                .and_then(|r| Ok((r, BigNum::from_slice(s)?)))
+                .and_then(|(r, s)| EcdsaSig::from_private_components(r, s))
+                .and_then(|signature| signature.to_der());
+            match der {
+                Ok(der) => der,
+                Err(_) => return false,
+            }
+        }
+        _ => return false,
+    };
+    Verifier::new(MessageDigest::sha256(), &key)
+        .and_then(|mut verifier| {
+            verifier.update(jwt.signing_input)?;
+            verifier.verify(&signature)
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::sign::Signer;
+
+    use super::*;
+
+    const HOST_NAME: &str = "hub1.azure-devices.net";
+    const PROJECT_ID: &str = "project1";
+
+    fn sas_token(resource: &str, key: &[u8], expiry: u64, key_name: Option<&str>) -> String {
+        let resource = resource.replace('/', "%2F");
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+        let tag = hmac::sign(&key, format!("{}\n{}", resource, expiry).as_bytes());
+        let signature = STANDARD
+            .encode(tag.as_ref())
+            .replace('+', "%2B")
+            .replace('/', "%2F")
+            .replace('=', "%3D");
+        let mut token = format!(
+            "SharedAccessSignature sr={}&sig={}&se={}",
+            resource, signature, expiry
+        );
+        if let Some(key_name) = key_name {
+            token.push_str(&format!("&skn={}", key_name));
+        }
+        token
+    }
+
+    fn es256_jwt(key: &PKey<openssl::pkey::Private>, claims: serde_json::Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"ES256","typ":"JWT"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signing_input = format!("{}.{}", header, claims);
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(signing_input.as_bytes()).unwrap();
+        let signature = EcdsaSig::from_der(&signer.sign_to_vec().unwrap()).unwrap();
+        let mut raw = signature.r().to_vec_padded(32).unwrap();
+        raw.extend(signature.s().to_vec_padded(32).unwrap());
+        format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(raw))
+    }
+
+    #[tokio::test]
+    async fn test_azure_iot_hub() {
+        let key = b"0123456789abcdef";
+        let profiles = vec![CloudAuthProfile::AzureIotHub {
+            host_name: HOST_NAME.to_owned(),
+            key: STANDARD.encode(key),
+            key_name: None,
+        }];
+        let username = format!("{}/device1/?api-version=2021-04-12", HOST_NAME);
+        let resource = format!("{}/devices/device1", HOST_NAME);
+        let expiry = get_unix_ts() + 3600;
+        let check = |client_identifier: &'static str, token: String| {
+            let profiles = profiles.clone();
+            let username = username.clone();
+            async move {
+                check_cloud_auth(
+                    &profiles,
+                    client_identifier,
+                    Some(&username),
+                    Some(token.as_bytes()),
+                )
+                .await
+            }
+        };
+
+        let token = sas_token(&resource, key, expiry, None);
+        assert_eq!(check("device1", token.clone()).await, Some(true));
+        // The client identifier must be the device id
+        assert_eq!(check("device2", token).await, Some(false));
+        let token = sas_token(&resource, key, get_unix_ts() - 1, None);
+        assert_eq!(check("device1", token).await, Some(false));
+        let token = sas_token(&resource, b"other key", expiry, None);
+        assert_eq!(check("device1", token).await, Some(false));
+        let token = sas_token(&resource, key, expiry, Some("iothubowner"));
+        assert_eq!(check("device1", token).await, Some(false));
+        let token = sas_token(&format!("{}/devices/device2", HOST_NAME), key, expiry, None);
+        assert_eq!(check("device1", token).await, Some(false));
+
+        // Not matched, authenticated by the password file
+        assert_eq!(
+            check_cloud_auth(&profiles, "device1", Some("device1"), Some(b"pass")).await,
+            None
+        );
+    }
+
+    #[test]
+    fn test_azure_policy_key() {
+        let key = b"0123456789abcdef";
+        let encoded_key = STANDARD.encode(key);
+        let expiry = get_unix_ts() + 3600;
+        let token = sas_token(HOST_NAME, key, expiry, Some("device"));
+        let check = |key_name: Option<&str>| {
+            check_sas_token(
+                HOST_NAME,
+                "device1",
+                &encoded_key,
+                key_name,
+                token.as_bytes(),
+                get_unix_ts(),
+            )
+        };
+        assert!(check(Some("device")));
+        assert!(!check(Some("service")));
+        // The hub token must be signed by a policy key
+        assert!(!check(None));
+    }
+
+    #[tokio::test]
+    async fn test_google_cloud_iot() {
+        let public_keys_dir =
+            std::env::temp_dir().join(format!("akasa-cloud-auth-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(public_keys_dir.join("registry1")).unwrap();
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        fs::write(
+            public_keys_dir.join("registry1").join("device1.pem"),
+            key.public_key_to_pem().unwrap(),
+        )
+        .unwrap();
+        let profiles = vec![CloudAuthProfile::GoogleCloudIot {
+            project_id: PROJECT_ID.to_owned(),
+            public_keys_dir: public_keys_dir.clone(),
+            max_lifetime: 86400,
+        }];
+        let client_identifier = |device_id: &str| {
+            format!(
+                "projects/{}/locations/us-central1/registries/registry1/devices/{}",
+                PROJECT_ID, device_id
+            )
+        };
+        let now = get_unix_ts();
+        let check = |client_identifier: String, token: String| {
+            let profiles = profiles.clone();
+            async move {
+                check_cloud_auth(
+                    &profiles,
+                    &client_identifier,
+                    Some("unused"),
+                    Some(token.as_bytes()),
+                )
+                .await
+            }
+        };
+
+        let token = es256_jwt(
+            &key,
+            serde_json::json!({"aud": PROJECT_ID, "iat": now, "exp": now + 3600}),
+        );
+        assert_eq!(
+            check(client_identifier("device1"), token.clone()).await,
+            Some(true)
+        );
+        // No public key
+        assert_eq!(
+            check(client_identifier("device2"), token).await,
+            Some(false)
+        );
+        for claims in [
+            serde_json::json!({"aud": "project2", "iat": now, "exp": now + 3600}),
+            serde_json::json!({"aud": PROJECT_ID, "iat": now - 7200, "exp": now - 3600}),
+            serde_json::json!({"aud": PROJECT_ID, "iat": now, "exp": now + 86401}),
+            serde_json::json!({"aud": PROJECT_ID, "exp": now + 3600}),
+        ] {
+            let token = es256_jwt(&key, claims.clone());
+            assert_eq!(
+                check(client_identifier("device1"), token).await,
+                Some(false),
+                "{claims}"
+            );
+        }
+        let other_key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let token = es256_jwt(
+            &other_key,
+            serde_json::json!({"aud": PROJECT_ID, "iat": now, "exp": now + 3600}),
+        );
+        assert_eq!(
+            check(client_identifier("device1"), token).await,
+            Some(false)
+        );
+
+        // Not matched
+        assert_eq!(
+            check(
+                "projects/project2/locations/l/registries/r/devices/d".to_owned(),
+                String::new()
+            )
+            .await,
+            None
+        );
+        assert_eq!(check(client_identifier(".."), String::new()).await, None);
+
+        fs::remove_dir_all(public_keys_dir).unwrap();
+    }
+}
@@ -2,9 +2,19 @@ use std::io;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use hashbrown::HashMap;
+use mqtt_proto::{QoS, TopicFilter};
 use parking_lot::RwLock;
 
-use crate::state::{ClientId, ControlMessage, GlobalState};
+use super::RetainContent;
+use crate::config::{
+    qos_from_value, ClientIdFormat, Config, EmptyClientIdConfig, OverflowPolicy, QuotaRule,
+    RetainLimitPolicy, TopicLimitsConfig,
+};
+use crate::hook::HookPublishCode;
+use crate::metrics::Metrics;
+use crate::state::{ClientId, GlobalState};
+use crate::timer::{self, TimerEvent};
 
 pub(crate) fn start_keep_alive_timer(
     keep_alive: u16,
@@ -15,41 +25,355 @@ pub(crate) fn start_keep_alive_timer(
     // FIXME: if kee_alive is zero, set a default keep_alive value from config
     if keep_alive > 0 {
         let half_interval = Duration::from_millis(keep_alive as u64 * 500);
-        log::debug!("{} keep alive: {:?}", client_id, half_interval * 2);
-        let last_packet_time = Arc::clone(last_packet_time);
-        let global = Arc::clone(global);
-        let action_gen = move || {
-            // Need clone twice: https://stackoverflow.com/a/68462908/1274372
-            let last_packet_time = Arc::clone(&last_packet_time);
-            let global = Arc::clone(&global);
-            async move {
-                {
-                    let last_packet_time = last_packet_time.read();
-                    if last_packet_time.elapsed() <= half_interval * 3 {
-                        return Some(half_interval);
-                    }
-                }
-                // timeout, kick it out
-                if let Some(sender) = global.get_client_control_sender(&client_id) {
-                    let msg = ControlMessage::Kick {
-                        reason: "timeout".to_owned(),
-                    };
-                    if let Err(err) = sender.send_async(msg).await {
-                        log::warn!(
-                            "send timeout kick message to {:?} error: {:?}",
-                            client_id,
-                            err
-                        );
-                    }
-                }
-                None
-            }
+        tracing::debug!("{} keep alive: {:?}", client_id, half_interval * 2);
+        let event = TimerEvent::KeepAlive {
+            client_id,
+            half_interval,
+            last_packet_time: Arc::clone(last_packet_time),
         };
-        tokio::spawn(async move {
-            while let Some(duration) = action_gen().await {
-                tokio::time::sleep(duration).await;
+        timer::schedule(global, half_interval, event);
+    }
+    Ok(())
+}
+
+/// The topic filters of the matched auto subscription rules in config
+pub(crate) fn auto_subscriptions(
+    config: &Config,
+    client_identifier: &str,
+    username: Option<&str>,
+    attributes: &HashMap<String, String>,
+) -> Vec<(TopicFilter, QoS)> {
+    let mut topics = Vec::new();
+    for rule in &config.auto_subscriptions {
+        if !rule_match(
+            rule.client_id.as_deref(),
+            rule.username.as_deref(),
+            client_identifier,
+            username,
+        ) {
+            continue;
+        }
+        for topic in &rule.topics {
+            let topic_filter = match render_client_pattern(
+                &topic.topic_filter,
+                client_identifier,
+                username,
+                attributes,
+            ) {
+                Some(topic_filter) => topic_filter,
+                None => continue,
+            };
+            // The client identifier or username may contain wildcards
+            match TopicFilter::try_from(topic_filter) {
+                Ok(filter) => topics.push((filter, qos_from_value(topic.qos))),
+                Err(err) => tracing::debug!("invalid auto subscription topic filter: {:?}", err),
             }
-        });
+        }
+    }
+    topics
+}
+
+/// Replace `%c` in the pattern by the client identifier, `%u` by the username
+/// and `%{key}` by the session attribute, return None if the username or the
+/// attribute is missing.
+pub(crate) fn render_client_pattern(
+    pattern: &str,
+    client_identifier: &str,
+    username: Option<&str>,
+    attributes: &HashMap<String, String>,
+) -> Option<String> {
+    let mut output = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(idx) = rest.find('%') {
+        output.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        if let Some(next) = rest.strip_prefix('c') {
+            output.push_str(client_identifier);
+            rest = next;
+        } else if let Some(next) = rest.strip_prefix('u') {
+            output.push_str(username?);
+            rest = next;
+        } else if let Some((key, next)) = rest.strip_prefix('{').and_then(|s| s.split_once('}')) {
+            output.push_str(attributes.get(key)?);
+            rest = next;
+        } else {
+            output.push('%');
+        }
+    }
+    output.push_str(rest);
+    Some(output)
+}
+
+/// Check the topic name or topic filter by `Config.topic_limits`, return the
+/// reason if the limit is exceeded
+pub(crate) fn check_topic_limits(
+    limits: &TopicLimitsConfig,
+    topic: &str,
+) -> Result<(), &'static str> {
+    if limits.max_length > 0 && topic.len() > limits.max_length {
+        return Err("topic is too long");
+    }
+    if limits.max_levels > 0 && topic.split('/').count() > limits.max_levels {
+        return Err("too many topic levels");
+    }
+    if limits.max_wildcards > 0
+        && topic
+            .split('/')
+            .filter(|level| *level == "+" || *level == "#")
+            .count()
+            > limits.max_wildcards
+    {
+        return Err("too many wildcards in topic filter");
     }
     Ok(())
 }
+
+/// Check the publish to a topic name starting with `$`, only the topics under
+/// `Config.reserved_topics.prefixes` are allowed, for the exception clients.
+/// Return the reason code if rejected.
+pub(crate) fn dollar_topic_rejected(
+    config: &Config,
+    topic_name: &str,
+    client_identifier: &str,
+    username: Option<&str>,
+) -> Option<HookPublishCode> {
+    let reserved = &config.reserved_topics;
+    if !reserved
+        .prefixes
+        .iter()
+        .any(|prefix| topic_name.starts_with(prefix.as_str()))
+    {
+        return Some(HookPublishCode::TopicNameInvalid);
+    }
+    if reserved.exceptions.iter().any(|rule| {
+        rule_match(
+            rule.client_id.as_deref(),
+            rule.username.as_deref(),
+            client_identifier,
+            username,
+        )
+    }) {
+        None
+    } else {
+        Some(HookPublishCode::NotAuthorized)
+    }
+}
+
+/// If the new topic filter exceeded `Config.max_subscriptions` of the session
+/// (subscribe an existing topic filter again is allowed)
+pub(crate) fn too_many_subscriptions<V>(
+    config: &Config,
+    subscribes: &HashMap<TopicFilter, V>,
+    filter: &TopicFilter,
+) -> bool {
+    config.max_subscriptions > 0
+        && subscribes.len() >= config.max_subscriptions
+        && !subscribes.contains_key(filter)
+}
+
+/// If the publish must be rejected by the first matched rule of
+/// `Config.payload_limits`
+pub(crate) fn payload_rejected(global: &GlobalState, topic_name: &str, payload_len: usize) -> bool {
+    let config = global.config();
+    let max_payload_size = match config
+        .payload_limits
+        .iter()
+        .find(|limit| topic_match(&limit.topic_filter, topic_name))
+    {
+        Some(limit) => limit.max_payload_size,
+        None => return false,
+    };
+    if max_payload_size == 0 || payload_len <= max_payload_size {
+        return false;
+    }
+    tracing::warn!(
+        "publish rejected, payload too large ({} > {} bytes): {}",
+        payload_len,
+        max_payload_size,
+        topic_name
+    );
+    Metrics::incr(&global.metrics.payload_rejected);
+    true
+}
+
+/// If the topic name is matched by the topic filter, "abc/#" also matches "abc"
+pub(crate) fn topic_match(topic_filter: &str, topic_name: &str) -> bool {
+    let mut names = topic_name.split('/');
+    for level in topic_filter.split('/') {
+        if level == "#" {
+            return true;
+        }
+        match names.next() {
+            Some(name) if level == "+" || level == name => {}
+            _ => return false,
+        }
+    }
+    names.next().is_none()
+}
+
+/// Replace `%1`..`%9` in the template by the levels of the topic name (empty
+/// if the level not exists)
+#[cfg(any(feature = "kafka", feature = "webhook", feature = "tsdb"))]
+pub(crate) fn render_topic_levels(template: &str, topic_name: &str) -> String {
+    let levels: Vec<&str> = topic_name.split('/').collect();
+    let mut output = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        let level = match (c, chars.peek().and_then(|next| next.to_digit(10))) {
+            ('%', Some(level)) if level > 0 => level as usize,
+            _ => {
+                output.push(c);
+                continue;
+            }
+        };
+        chars.next();
+        output.push_str(levels.get(level - 1).copied().unwrap_or(""));
+    }
+    output
+}
+
+/// Percent-encode the levels of the topic name, the separators are kept
+#[cfg(any(feature = "webhook", feature = "tsdb"))]
+pub(crate) fn percent_encode_levels(topic_name: &str) -> String {
+    let mut output = String::with_capacity(topic_name.len());
+    for byte in topic_name.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'/' | b'-' | b'.' | b'_' | b'~') {
+            output.push(byte as char);
+        } else {
+            output.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    output
+}
+
+/// If the retained message (not empty) must be rejected by `Config.retain_limits`
+pub(crate) fn retain_rejected(global: &GlobalState, topic_name: &str, payload_len: usize) -> bool {
+    let config = global.config();
+    if payload_len == 0 || config.retain_limits.policy != RetainLimitPolicy::Reject {
+        return false;
+    }
+    match global
+        .retain_table
+        .check_limits(&config.retain_limits, topic_name, payload_len)
+    {
+        Ok(()) => false,
+        Err(reason) => {
+            tracing::warn!("retain message rejected, {}: {}", reason, topic_name);
+            Metrics::incr(&global.metrics.retain_dropped);
+            true
+        }
+    }
+}
+
+/// Store the retained message if it's under `Config.retain_limits`, return
+/// the replaced retained message.
+pub(crate) fn store_retain(
+    global: &GlobalState,
+    content: RetainContent,
+) -> Option<Arc<RetainContent>> {
+    let config = global.config();
+    if let Err(reason) = global.retain_table.check_limits(
+        &config.retain_limits,
+        &content.topic_name,
+        content.payload.len(),
+    ) {
+        tracing::warn!("retain message dropped, {}: {}", reason, content.topic_name);
+        Metrics::incr(&global.metrics.retain_dropped);
+        return None;
+    }
+    global.retain_table.insert(Arc::new(content))
+}
+
+/// Generate the client identifier for the client connected with zero-length
+/// client identifier
+pub(crate) fn generate_client_identifier(config: &EmptyClientIdConfig) -> String {
+    let uuid = uuid::Uuid::new_v4();
+    match config.format {
+        ClientIdFormat::Uuid => format!("{}{}", config.prefix, uuid),
+        ClientIdFormat::Simple => format!("{}{}", config.prefix, uuid.simple()),
+    }
+}
+
+/// The quota rule of the username (see `Config.quotas`)
+pub(crate) fn quota_rule<'a>(config: &'a Config, username: &str) -> Option<&'a QuotaRule> {
+    config
+        .quotas
+        .rules
+        .iter()
+        .find(|rule| rule_match(None, rule.username.as_deref(), "", Some(username)))
+}
+
+/// The pending messages limits of the client: (max messages, max bytes,
+/// overflow policy, max inflight)
+pub(crate) fn pending_limits(
+    config: &Config,
+    client_identifier: &str,
+    username: Option<&str>,
+) -> (usize, usize, OverflowPolicy, Option<u16>) {
+    let rule = config.pending_limits.iter().find(|rule| {
+        rule_match(
+            rule.client_id.as_deref(),
+            rule.username.as_deref(),
+            client_identifier,
+            username,
+        )
+    });
+    match rule {
+        Some(rule) => (
+            rule.max_messages
+                .unwrap_or(config.max_in_mem_pending_messages),
+            rule.max_bytes.unwrap_or(config.max_in_mem_pending_bytes),
+            rule.overflow_policy
+                .unwrap_or(config.pending_overflow_policy),
+            rule.max_inflight,
+        ),
+        None => (
+            config.max_in_mem_pending_messages,
+            config.max_in_mem_pending_bytes,
+            config.pending_overflow_policy,
+            None,
+        ),
+    }
+}
+
+// Match the client by the client identifier pattern and the username pattern
+fn rule_match(
+    client_id_pattern: Option<&str>,
+    username_pattern: Option<&str>,
+    client_identifier: &str,
+    username: Option<&str>,
+) -> bool {
+    if let Some(pattern) = client_id_pattern {
+        if !glob_match(pattern, client_identifier) {
+            return false;
+        }
+    }
+    if let Some(pattern) = username_pattern {
+        if !username.is_some_and(|username| glob_match(pattern, username)) {
+            return false;
+        }
+    }
+    true
+}
+
+// Match the value by the pattern, `*` in the pattern matches any characters
+fn glob_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(value) => value,
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
@@ -0,0 +1,179 @@
+//! The packet flow validator of the debug builds: check the invariants of the
+//! packets written by the server in a connection, the violations are logged
+//! (target `akasa::flow`) and panic in the tests, to catch the regressions the
+//! black-box tests can't see.
+
+use hashbrown::{HashMap, HashSet};
+use mqtt_proto::{v3, v5, QosPid};
+
+/// The parts of a packet checked by the validator
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PacketFlow {
+    /// The packet identifier of a QoS 1/2 message
+    Publish {
+        pid: Option<u16>,
+        dup: bool,
+    },
+    /// PUBACK or PUBCOMP, the QoS 1/2 message is finished
+    Finish(u16),
+    Subscribe {
+        pid: u16,
+        filters: usize,
+    },
+    Suback {
+        pid: u16,
+        codes: usize,
+    },
+    Disconnect,
+    Other,
+}
+
+impl PacketFlow {
+    pub fn from_v3(packet: &v3::Packet) -> PacketFlow {
+        match packet {
+            v3::Packet::Publish(publish) => PacketFlow::Publish {
+                pid: qos_pid_value(publish.qos_pid),
+                dup: publish.dup,
+            },
+            v3::Packet::Puback(pid) | v3::Packet::Pubcomp(pid) => PacketFlow::Finish(pid.value()),
+            v3::Packet::Subscribe(subscribe) => PacketFlow::Subscribe {
+                pid: subscribe.pid.value(),
+                filters: subscribe.topics.len(),
+            },
+            v3::Packet::Suback(suback) => PacketFlow::Suback {
+                pid: suback.pid.value(),
+                codes: suback.topics.len(),
+            },
+            v3::Packet::Disconnect => PacketFlow::Disconnect,
+            _ => PacketFlow::Other,
+        }
+    }
+
+    pub fn from_v5(packet: &v5::Packet) -> PacketFlow {
+        match packet {
+            v5::Packet::Publish(publish) => PacketFlow::Publish {
+                pid: qos_pid_value(publish.qos_pid),
+                dup: publish.dup,
+            },
+            v5::Packet::Puback(puback) => PacketFlow::Finish(puback.pid.value()),
+            v5::Packet::Pubcomp(pubcomp) => PacketFlow::Finish(pubcomp.pid.value()),
+            v5::Packet::Subscribe(subscribe) => PacketFlow::Subscribe {
+                pid: subscribe.pid.value(),
+                filters: subscribe.topics.len(),
+            },
+            v5::Packet::Suback(suback) => PacketFlow::Suback {
+                pid: suback.pid.value(),
+                codes: suback.topics.len(),
+            },
+            v5::Packet::Disconnect(_) => PacketFlow::Disconnect,
+            _ => PacketFlow::Other,
+        }
+    }
+}
+
+fn qos_pid_value(qos_pid: QosPid) -> Option<u16> {
+    match qos_pid {
+        QosPid::Level0 => None,
+        QosPid::Level1(pid) | QosPid::Level2(pid) => Some(pid.value()),
+    }
+}
+
+/// The state of the packet flow in a connection
+#[derive(Debug, Default)]
+pub struct FlowValidator {
+    // The packet identifiers of the QoS 1/2 messages sent and not finished
+    inflight: HashSet<u16>,
+    // The SUBSCRIBE packets received: packet identifier => topic filters
+    subscribes: HashMap<u16, usize>,
+    disconnect_sent: bool,
+}
+
+impl FlowValidator {
+    /// A packet received from the client
+    pub fn incoming(&mut self, flow: PacketFlow) {
+        match flow {
+            PacketFlow::Finish(pid) => {
+                self.inflight.remove(&pid);
+            }
+            PacketFlow::Subscribe { pid, filters } => {
+                self.subscribes.insert(pid, filters);
+            }
+            _ => {}
+        }
+    }
+
+    /// A packet written to the client
+    pub fn outgoing(&mut self, flow: PacketFlow) -> Result<(), String> {
+        if self.disconnect_sent {
+            return Err(format!("{flow:?} written after DISCONNECT"));
+        }
+        match flow {
+            PacketFlow::Publish {
+                pid: Some(pid),
+                dup,
+            } => {
+                if !self.inflight.insert(pid) && !dup {
+                    return Err(format!("PUBLISH with the inflight packet identifier {pid}"));
+                }
+            }
+            PacketFlow::Suback { pid, codes } => {
+                if let Some(filters) = self.subscribes.remove(&pid) {
+                    if filters != codes {
+                        return Err(format!(
+                            "SUBACK {pid} has {codes} reason codes for {filters} topic filters"
+                        ));
+                    }
+                }
+            }
+            PacketFlow::Disconnect => self.disconnect_sent = true,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Report the violation
+pub fn check(client_identifier: &str, result: Result<(), String>) {
+    if let Err(violation) = result {
+        tracing::error!(
+            target: "akasa::flow",
+            "[{}] packet flow violation: {}",
+            client_identifier,
+            violation
+        );
+        if cfg!(test) {
+            panic!("[{client_identifier}] packet flow violation: {violation}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flow_validator() {
+        let mut validator = FlowValidator::default();
+        let publish = PacketFlow::Publish {
+            pid: Some(1),
+            dup: false,
+        };
+        assert!(validator.outgoing(publish).is_ok());
+        assert!(validator.outgoing(publish).is_err());
+        let retransmit = PacketFlow::Publish {
+            pid: Some(1),
+            dup: true,
+        };
+        assert!(validator.outgoing(retransmit).is_ok());
+        validator.incoming(PacketFlow::Finish(1));
+        assert!(validator.outgoing(publish).is_ok());
+
+        let subscribe = PacketFlow::Subscribe { pid: 2, filters: 2 };
+        validator.incoming(subscribe);
+        let suback = PacketFlow::Suback { pid: 2, codes: 1 };
+        assert!(validator.outgoing(suback).is_err());
+
+        assert!(validator.outgoing(PacketFlow::Disconnect).is_ok());
+        assert!(validator.outgoing(PacketFlow::Other).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::get_unix_ts;
+
+/// The session state for inspection (admin API)
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub client_identifier: String,
+    pub protocol: String,
+    pub online: bool,
+    /// The peer address of the last connection
+    pub peer: SocketAddr,
+    pub username: Option<String>,
+    pub keep_alive: u16,
+    /// `clean_session` in v3.x, `clean_start` in v5.0
+    pub clean_start: bool,
+    /// Packet ids of the messages sent to client but not acknowledged yet
+    pub inflight_pids: Vec<u16>,
+    /// Messages (include inflight messages) waiting to be acknowledged by client
+    pub pending_len: usize,
+    /// Packet ids of QoS2 messages received from client and waiting for PUBREL
+    pub incoming_qos2_pids: Vec<u16>,
+    pub subscriptions: Vec<SubscriptionInfo>,
+    /// v5.0 only
+    pub session_expiry_interval: Option<u32>,
+    /// Unix timestamp (seconds) when the session will expire (offline v5.0 session only)
+    pub session_expiry_at: Option<u64>,
+    pub has_will: bool,
+    /// The attributes set by the hooks, sorted by the key
+    pub attributes: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct SubscriptionInfo {
+    pub topic_filter: String,
+    pub qos: u8,
+    /// The fields below are v5.0 only
+    pub no_local: Option<bool>,
+    pub retain_as_published: Option<bool>,
+    pub retain_handling: Option<String>,
+    pub subscription_id: Option<u32>,
+}
+
+/// Convert an instant to unix timestamp (seconds)
+fn instant_to_unix_ts(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let now_ts = get_unix_ts();
+    if instant >= now {
+        now_ts + instant.duration_since(now).as_secs()
+    } else {
+        now_ts.saturating_sub(now.duration_since(instant).as_secs())
+    }
+}
+
+/// The session expiry deadline of a closed connection
+pub(crate) fn session_expiry_at(closed_time: Instant, session_expiry_interval: u32) -> Option<u64> {
+    if session_expiry_interval == u32::MAX {
+        // never expire
+        return None;
+    }
+    Some(instant_to_unix_ts(
+        closed_time + Duration::from_secs(session_expiry_interval as u64),
+    ))
+}
@@ -1,18 +1,43 @@
 mod auth;
+mod buffer_pool;
+mod cloud_auth;
 mod common;
+#[cfg(debug_assertions)]
+mod flow;
+mod inspect;
 mod online_loop;
 mod pending;
+mod qos2;
+mod replay;
 mod retain;
 mod route;
+mod throttle;
+mod trace;
 
+#[cfg(feature = "v3")]
 pub mod v3;
+#[cfg(feature = "v5")]
 pub mod v5;
 
-pub(crate) use common::start_keep_alive_timer;
+pub(crate) use cloud_auth::check_cloud_auth;
+#[cfg(any(feature = "webhook", feature = "tsdb"))]
+pub(crate) use common::percent_encode_levels;
+#[cfg(any(feature = "kafka", feature = "webhook", feature = "tsdb"))]
+pub(crate) use common::render_topic_levels;
+pub(crate) use common::{
+    auto_subscriptions, check_topic_limits, dollar_topic_rejected, generate_client_identifier,
+    payload_rejected, pending_limits, quota_rule, render_client_pattern, retain_rejected,
+    start_keep_alive_timer, store_retain, too_many_subscriptions, topic_match,
+};
+pub(crate) use inspect::session_expiry_at;
 pub(crate) use pending::get_unix_ts;
 
 pub use auth::{check_password, dump_passwords, hash_password, load_passwords, MIN_SALT_LEN};
-pub use online_loop::{BroadcastPackets, OnlineLoop, OnlineSession, WritePacket};
-pub use pending::{PendingPacketStatus, PendingPackets};
+pub use inspect::{SessionInfo, SubscriptionInfo};
+pub use online_loop::{BroadcastPackets, OnlineLoop, OnlineSession, SharedEncoded, WritePacket};
+pub use pending::{PendingPacketStatus, PendingPackets, PendingPush};
+pub use qos2::{IncomingQos2, Qos2Publish};
+pub use replay::{load_packet_records, replay_packets};
 pub use retain::{RetainContent, RetainTable};
-pub use route::RouteTable;
+pub use route::{RouteTable, SharedClients};
+pub use trace::{PacketDirection, PacketRecord, PacketTraceOptions, PacketTracer};
@@ -1,26 +1,50 @@
 use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::future::Future;
-use std::io;
-use std::mem::MaybeUninit;
+use std::io::{self, IoSlice};
+use std::mem::{self, MaybeUninit};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::task::{Context, Poll};
+use std::time::Duration;
 
+use bytes::Bytes;
 use flume::{
     r#async::{RecvStream, SendSink},
-    Sender,
+    Sender, TrySendError,
 };
 use futures_lite::Stream;
 use futures_sink::Sink;
+use futures_util::SinkExt;
 use hashbrown::HashMap;
 use mqtt_proto::{v3, v5, GenericPollPacket, GenericPollPacketState, PollHeader, QoS, VarBytes};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{sleep, Sleep};
 
+use crate::config::{
+    BufferPoolConfig, MemoryLimitConfig, Qos0SheddingConfig, SlowConsumerConfig, WriteBatchConfig,
+};
+use crate::error::Error;
+use crate::fanout::FanOutQueue;
 use crate::hook::{handle_request, Hook, HookAction, HookRequest, HookResponse};
+use crate::limiter;
+use crate::memory::MemoryCharge;
+use crate::metrics::{LatencyStage, Metrics};
 use crate::state::{ClientId, ClientReceiver, ControlMessage, GlobalState, NormalMessage};
 
-const WRITE_BATCH_SIZE: usize = 2048;
+#[cfg(debug_assertions)]
+use super::flow::{self, FlowValidator, PacketFlow};
+use super::throttle::Throttled;
+use super::{buffer_pool, PacketDirection};
+
+// The max slices of a vectored write (IOV_MAX of Linux)
+const MAX_IO_SLICES: usize = 1024;
+// The max time to wait for the DISCONNECT packet written before close
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+type HookFuture = Pin<Box<dyn Future<Output = HookResponse> + Send + 'static>>;
+// A decoded packet: (encode length, packet body, packet)
+type ReceivedPacket<P> = (usize, Vec<MaybeUninit<u8>>, P);
 
 pub struct OnlineLoop<'a, C, S, H, Hk>
 where
@@ -34,17 +58,43 @@ where
     receiver: &'a ClientReceiver,
     control_stream: RecvStream<'a, ControlMessage>,
     normal_stream: RecvStream<'a, (ClientId, NormalMessage)>,
-    conn: &'a mut C,
+    // The connection paced by `Config.throttle`
+    conn: Throttled<&'a mut C>,
     taken_over: &'a mut bool,
 
     read_unfinish: bool,
     normal_stream_unfinish: bool,
 
     packet_state: GenericPollPacketState<H>,
-    hook_fut: Option<Pin<Box<dyn Future<Output = HookResponse> + Send + 'static>>>,
+    hook_fut: Option<HookFuture>,
+    // The packets received while a hook request is in flight, handled in
+    // order after it finished (except PINGREQ and acks, see
+    // `OnlineSession::skip_hook_queue`).
+    hook_queue: VecDeque<ReceivedPacket<S::Packet>>,
+    max_hook_queue: usize,
     session_state_sender: Option<(SendSink<'static, S::SessionState>, bool)>,
-    write_packets_max: usize,
+    write_batch: WriteBatchConfig,
+    buffer_pool: BufferPoolConfig,
+    latency_metrics: bool,
     write_packets: VecDeque<WritePacket<S::Packet>>,
+    // Take a permit of `Config.inbound_limit` before reading a PUBLISH packet
+    inbound_limit: bool,
+    inbound_permit: bool,
+
+    slow_consumer: SlowConsumerConfig,
+    qos0_shedding: Qos0SheddingConfig,
+    memory_limit: MemoryLimitConfig,
+    // Fired when the write stalled too long
+    write_stall_timer: Option<Pin<Box<Sleep>>>,
+    // Fired when the outbound queue stayed too deep too long
+    queue_deep_timer: Option<Pin<Box<Sleep>>>,
+    // Already reported as a slow consumer (reset when recovered)
+    slow_consumer_reported: bool,
+    // The output of the loop and the timer, set when the server closes the
+    // connection after the DISCONNECT packet in `write_packets` written.
+    closing: Option<(Option<io::Error>, Pin<Box<Sleep>>)>,
+    #[cfg(debug_assertions)]
+    flow_validator: FlowValidator,
 }
 
 impl<'a, C, S, H, Hk> OnlineLoop<'a, C, S, H, Hk>
@@ -65,6 +115,14 @@ where
         taken_over: &'a mut bool,
         packet_state: GenericPollPacketState<H>,
     ) -> Self {
+        let slow_consumer = global.config().slow_consumer.clone();
+        let qos0_shedding = global.config().qos0_shedding.clone();
+        let memory_limit = global.config().memory_limit.clone();
+        let write_batch = global.config().write_batch.clone();
+        let buffer_pool = global.config().buffer_pool.clone();
+        let latency_metrics = global.config().latency_metrics;
+        let inbound_limit = global.config().inbound_limit.max_messages_per_sec > 0;
+        let max_hook_queue = global.config().hook.max_queued_packets;
         OnlineLoop {
             session,
             global,
@@ -72,15 +130,30 @@ where
             receiver,
             control_stream,
             normal_stream,
-            conn,
+            conn: Throttled::new(conn, &global.config().throttle),
             taken_over,
             packet_state,
             read_unfinish: false,
             normal_stream_unfinish: false,
-            write_packets_max: 16,
-            write_packets: VecDeque::with_capacity(16),
+            write_packets: VecDeque::with_capacity(write_batch.max_packets),
+            write_batch,
+            buffer_pool,
+            latency_metrics,
+            inbound_limit,
+            inbound_permit: false,
             session_state_sender: None,
             hook_fut: None,
+            hook_queue: VecDeque::new(),
+            max_hook_queue,
+            slow_consumer,
+            qos0_shedding,
+            memory_limit,
+            write_stall_timer: None,
+            queue_deep_timer: None,
+            slow_consumer_reported: false,
+            closing: None,
+            #[cfg(debug_assertions)]
+            flow_validator: FlowValidator::default(),
         }
     }
 }
@@ -125,32 +198,92 @@ where
             packet_state,
             session_state_sender,
             hook_fut,
-            write_packets_max,
+            hook_queue,
+            max_hook_queue,
+            write_batch,
+            buffer_pool,
+            latency_metrics,
             write_packets,
+            inbound_limit,
+            inbound_permit,
             taken_over,
+            slow_consumer,
+            qos0_shedding,
+            memory_limit,
+            write_stall_timer,
+            queue_deep_timer,
+            slow_consumer_reported,
+            closing,
+            #[cfg(debug_assertions)]
+            flow_validator,
         } = self.get_mut();
 
         let current_client_id = session.client_id();
-        log::trace!("@@@@ [{}] poll()", current_client_id);
+        tracing::trace!("@@@@ [{}] poll()", current_client_id);
 
-        if let Some(fut) = hook_fut.as_mut() {
-            let actions = match fut.as_mut().poll(cx) {
-                Poll::Ready(resp) => {
-                    *hook_fut = None;
-                    match resp {
-                        HookResponse::Normal(Ok(actions)) => actions,
-                        HookResponse::Normal(Err(err_opt)) => return Poll::Ready(err_opt),
-                        _ => panic!("invalid hook response"),
+        // Only write the queued packets when closing
+        if let Some((_, timer)) = closing.as_mut() {
+            let written = match write_to_conn(
+                conn,
+                &**session,
+                write_packets,
+                global,
+                write_batch,
+                buffer_pool,
+                #[cfg(debug_assertions)]
+                flow_validator,
+                cx,
+            ) {
+                Ok(_) if write_packets.is_empty() => Pin::new(&mut *conn).poll_flush(cx).is_ready(),
+                Ok((_, write_pending)) => {
+                    if !write_pending {
+                        cx.waker().wake_by_ref();
                     }
+                    false
                 }
-                Poll::Pending => return Poll::Pending,
+                Err(_) => true,
             };
-            for action in actions {
-                if let Err(err) = session.apply_action(action, global) {
-                    return Poll::Ready(Some(err));
+            if written || timer.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(closing.take().and_then(|(output, _)| output));
+            }
+            return Poll::Pending;
+        }
+
+        // The hook future and the packets are handled in this function, so
+        // the session is never accessed by them at the same time.
+        if let Some(fut) = hook_fut.as_mut() {
+            if let Poll::Ready(resp) = fut.as_mut().poll(cx) {
+                *hook_fut = None;
+                let actions = match resp {
+                    HookResponse::Normal(Ok(actions)) => actions,
+                    HookResponse::Normal(Err(err_opt)) => return Poll::Ready(err_opt),
+                    _ => panic!("invalid hook response"),
+                };
+                for action in actions {
+                    if let Err(err) = session.apply_action(action, global) {
+                        return Poll::Ready(Some(err));
+                    }
                 }
+                session.after_handle_packet(write_packets);
+            }
+        }
+        // Handle the queued packets until another hook request in flight
+        while hook_fut.is_none() {
+            let received = match hook_queue.pop_front() {
+                Some(received) => received,
+                None => break,
+            };
+            if let Err(err_opt) = handle_packet(
+                *session,
+                received,
+                write_packets,
+                hook_fut,
+                handler,
+                global,
+                cx,
+            ) {
+                return Poll::Ready(err_opt);
             }
-            session.after_handle_packet(write_packets);
         }
 
         // Send SessionState to new connection (been taken over)
@@ -161,7 +294,7 @@ where
                     Poll::Ready(Ok(())) => {}
                     Poll::Ready(Err(_)) => {
                         // channel disconnected, cancel takeover
-                        log::info!("[{}] The connection want take over current session already ended, process canceled", current_client_id);
+                        tracing::info!("[{}] The connection want take over current session already ended, process canceled", current_client_id);
                         cx.waker().wake_by_ref();
                         return Poll::Pending;
                     }
@@ -171,22 +304,24 @@ where
                         return Poll::Pending;
                     }
                 }
+                // The new connection may wait in the queue by the same client id
+                global.inbound_limiter.cancel(current_client_id);
                 let old_state = session.build_state(receiver.clone());
                 if Pin::new(&mut send_sink).start_send(old_state).is_err() {
                     // channel disconnected, cancel takeover
-                    log::info!("[{}] The connection want take over current session already ended, process canceled", current_client_id);
+                    tracing::info!("[{}] The connection want take over current session already ended, process canceled", current_client_id);
                     cx.waker().wake_by_ref();
                     return Poll::Pending;
                 }
             }
             return match Pin::new(&mut send_sink).poll_flush(cx) {
                 Poll::Ready(Ok(())) => {
-                    log::info!("[{}] current session been taken over", current_client_id);
+                    tracing::info!("[{}] current session been taken over", current_client_id);
                     **taken_over = true;
                     Poll::Ready(None)
                 }
                 Poll::Ready(Err(_)) => {
-                    log::info!("[{}] The connection want take over current session already ended, process canceled", current_client_id);
+                    tracing::info!("[{}] The connection want take over current session already ended, process canceled", current_client_id);
                     cx.waker().wake_by_ref();
                     Poll::Pending
                 }
@@ -199,9 +334,8 @@ where
         }
 
         let mut pendings = Pendings::default();
-        let mut have_write = false;
 
-        log::trace!(
+        tracing::trace!(
             "[{}] write_packets={}, broadcast_packets={}, ",
             current_client_id,
             write_packets.len(),
@@ -215,7 +349,7 @@ where
         // Read data from client connection
         //   * Produce to: [write_packets, broadcast_packets, external_request]
         loop {
-            if too_much_write_data(write_packets, *write_packets_max)
+            if too_much_write_data(write_packets, write_batch)
                 || session.broadcast_packets_cnt() >= session.broadcast_packets_max()
             {
                 *read_unfinish = true;
@@ -223,19 +357,36 @@ where
             } else {
                 *read_unfinish = false;
             }
+            // Woken up by the hook future when the queue is full
+            if hook_fut.is_some() && hook_queue.len() >= *max_hook_queue {
+                break;
+            }
 
-            log::trace!(
+            tracing::trace!(
                 "[{}] going to read, write_packets.len() = {}, broadcast_packets_cnt = {}",
                 current_client_id,
                 write_packets.len(),
                 session.broadcast_packets_cnt(),
             );
+            // The packet type is unknown before read, so the permit is taken
+            // before reading any packet and kept until a PUBLISH packet read.
+            if *inbound_limit
+                && !*inbound_permit
+                && limiter::poll_acquire(global, current_client_id, cx).is_pending()
+            {
+                tracing::trace!("[{}] inbound limit pending", current_client_id);
+                *read_unfinish = false;
+                pendings.read = true;
+                break;
+            }
+            *inbound_permit = true;
+
             // TODO: Decode Header first for more detailed error report.
             let mut poll_packet = GenericPollPacket::new(packet_state, conn);
             let packet_result = match Pin::new(&mut poll_packet).poll(cx) {
                 Poll::Ready(result) => result,
                 Poll::Pending => {
-                    log::trace!("[{}] read pending", current_client_id);
+                    tracing::trace!("[{}] read pending", current_client_id);
                     *read_unfinish = false;
                     pendings.read = true;
                     break;
@@ -243,42 +394,35 @@ where
             };
             match packet_result {
                 Ok((encode_len, packet_body, packet)) => {
-                    log::trace!("[{}] decode MQTT packet: {:?}", current_client_id, packet);
+                    tracing::trace!("[{}] decode MQTT packet: {:?}", current_client_id, packet);
                     *packet_state = GenericPollPacketState::default();
+                    if S::is_publish(&packet) {
+                        *inbound_permit = false;
+                    }
+                    global.packet_tracer.record(
+                        session.client_identifier(),
+                        PacketDirection::In,
+                        &packet,
+                    );
+                    #[cfg(debug_assertions)]
+                    flow_validator.incoming(packet.flow());
 
-                    match session.handle_packet(
-                        encode_len,
-                        packet_body,
-                        packet,
+                    let received = (encode_len, packet_body, packet);
+                    if hook_fut.is_some() && !S::skip_hook_queue(&received.2) {
+                        hook_queue.push_back(received);
+                        continue;
+                    }
+                    if let Err(err_opt) = handle_packet(
+                        *session,
+                        received,
                         write_packets,
+                        hook_fut,
+                        handler,
                         global,
+                        cx,
                     ) {
-                        Ok(Some(request)) => {
-                            let mut fut =
-                                Box::pin(handle_request(request, handler.clone(), global.clone()));
-                            let actions = match fut.as_mut().poll(cx) {
-                                Poll::Ready(resp) => match resp {
-                                    HookResponse::Normal(Ok(actions)) => actions,
-                                    HookResponse::Normal(Err(err_opt)) => {
-                                        return Poll::Ready(err_opt)
-                                    }
-                                    _ => panic!("invalid hook response"),
-                                },
-                                Poll::Pending => {
-                                    *hook_fut = Some(fut);
-                                    return Poll::Pending;
-                                }
-                            };
-                            for action in actions {
-                                if let Err(err) = session.apply_action(action, global) {
-                                    return Poll::Ready(Some(err));
-                                }
-                            }
-                        }
-                        Ok(None) => {}
-                        Err(err_opt) => return Poll::Ready(err_opt),
+                        return Poll::Ready(err_opt);
                     }
-                    session.after_handle_packet(write_packets);
                 }
                 Err(err) => {
                     if let Err(err_opt) = session.handle_decode_error(err, write_packets) {
@@ -290,12 +434,13 @@ where
 
         // Receive control messages
         //   * Produce to: [broadcast_packets, session_state_sender]
-        loop {
+        // The control messages (take over, kick) wait for the hook request
+        while hook_fut.is_none() {
             // send_will() function will insert broadcast_packets, but it's OK.
             let msg = match Pin::new(&mut *control_stream).poll_next(cx) {
                 Poll::Ready(Some(output)) => output,
                 Poll::Ready(None) => {
-                    log::error!("control senders all dropped by {}", current_client_id);
+                    tracing::error!("control senders all dropped by {}", current_client_id);
                     return Poll::Ready(Some(io::ErrorKind::InvalidData.into()));
                 }
                 Poll::Pending => {
@@ -303,21 +448,24 @@ where
                     break;
                 }
             };
-            log::trace!(
+            tracing::trace!(
                 "[{}] handling control message: {:?}",
                 current_client_id,
                 msg
             );
             let (stop, sender_opt) = session.handle_control(msg, global);
             if let Some(sender) = sender_opt {
-                log::debug!("[{}] yield because session take over", current_client_id);
+                tracing::debug!("[{}] yield because session take over", current_client_id);
                 *session_state_sender = Some((sender.into_sink(), false));
                 // Since it's high priority, we just return here so session start take over process.
                 cx.waker().wake_by_ref();
                 return Poll::Pending;
             }
             if stop {
-                return Poll::Ready(None);
+                let packet = session.control_disconnect();
+                start_close(write_packets, packet, None, closing);
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
             }
         }
 
@@ -325,7 +473,7 @@ where
         //   * Produce to: [write_packets]
         // FIXME: drop message when pending queue are full
         loop {
-            if too_much_write_data(write_packets, *write_packets_max) {
+            if too_much_write_data(write_packets, write_batch) {
                 *normal_stream_unfinish = true;
                 break;
             } else {
@@ -335,25 +483,46 @@ where
             let (sender_id, msg) = match Pin::new(&mut *normal_stream).poll_next(cx) {
                 Poll::Ready(Some(output)) => output,
                 Poll::Ready(None) => {
-                    log::error!("normal senders all dropped by {}", current_client_id);
+                    tracing::error!("normal senders all dropped by {}", current_client_id);
                     return Poll::Ready(Some(io::ErrorKind::InvalidData.into()));
                 }
                 Poll::Pending => {
-                    log::trace!("[{}] normal receiver is pending", current_client_id);
+                    tracing::trace!("[{}] normal receiver is pending", current_client_id);
                     pendings.normal_message = true;
                     break;
                 }
             };
 
-            log::trace!(
+            tracing::trace!(
                 "[{}] received a normal message from [{}], {:?}",
                 current_client_id,
                 sender_id,
                 msg,
             );
+            // Drop the new QoS 0 message instead of queuing it when overloaded
+            let queue_too_deep = qos0_shedding.max_queue_depth > 0
+                && session.pending_packets_len() + receiver.normal.len() + write_packets.len()
+                    >= qos0_shedding.max_queue_depth;
+            let overloaded =
+                global.overloaded() || memory_limit.under_pressure(global.accounted_memory());
+            if msg.qos() == QoS::Level0 && (queue_too_deep || overloaded) {
+                tracing::debug!(
+                    "[{}] QoS 0 message dropped by load shedding",
+                    current_client_id
+                );
+                Metrics::incr(&global.metrics.qos0_shed);
+                continue;
+            }
+            let (qos, received_at) = (msg.qos(), msg.received_at());
             if let Some((final_qos, packet_opt)) = session.handle_normal(sender_id, msg, global) {
+                if *latency_metrics {
+                    global
+                        .metrics
+                        .latency
+                        .record(LatencyStage::Write, qos, received_at);
+                }
                 if let Some(packet) = packet_opt {
-                    write_packets.push_back(packet.into());
+                    write_packets.push_back(packet);
                 }
                 if final_qos != QoS::Level0 {
                     let pending_packets = session.handle_pendings();
@@ -370,57 +539,27 @@ where
 
         // Write packets to client connection
         //   * Consume: [write_packets]
-        while !write_packets.is_empty() {
-            let (mut data_all, mut data_idx) = (Vec::new(), 0);
-            while let Some(write_packet) = write_packets.pop_front() {
-                log::trace!("[{}] encode packet: {:?}", current_client_id, write_packet);
-                match write_packet {
-                    // NOTE: this must be the first item
-                    WritePacket::Data((data, idx)) => {
-                        data_all = match data {
-                            VarBytes::Dynamic(d) => d,
-                            VarBytes::Fixed2(d) => d.to_vec(),
-                            VarBytes::Fixed4(d) => d.to_vec(),
-                        };
-                        data_idx = idx;
-                    }
-                    WritePacket::Packet(pkt) => match pkt.encode() {
-                        Ok(data) => data_all.extend(data.as_ref()),
-                        Err(err) => return Poll::Ready(Some(err)),
-                    },
-                }
-                // NOTE: For avoid potential memory leak
-                if data_all.len() >= WRITE_BATCH_SIZE {
-                    break;
-                }
-            }
-
-            match Pin::new(&mut *conn).poll_write(cx, &data_all[data_idx..]) {
-                Poll::Ready(Ok(size)) => {
-                    log::trace!("[{}] write {} bytes data", current_client_id, size);
-                    have_write = true;
-                    data_idx += size;
-                    if data_idx < data_all.len() {
-                        write_packets
-                            .push_front(WritePacket::Data((VarBytes::Dynamic(data_all), data_idx)));
-                        break;
-                    }
-                }
-                Poll::Ready(Err(err)) => return Poll::Ready(Some(err)),
-                Poll::Pending => {
-                    write_packets
-                        .push_front(WritePacket::Data((VarBytes::Dynamic(data_all), data_idx)));
-                    pendings.write = true;
-                    break;
-                }
-            }
-        }
+        let (have_write, write_pending) = match write_to_conn(
+            conn,
+            &**session,
+            write_packets,
+            global,
+            write_batch,
+            buffer_pool,
+            #[cfg(debug_assertions)]
+            flow_validator,
+            cx,
+        ) {
+            Ok(result) => result,
+            Err(err) => return Poll::Ready(Some(err)),
+        };
+        pendings.write = write_pending;
 
         if have_write
-            && write_packets.capacity() > (*write_packets_max) * 2
-            && write_packets.len() <= *write_packets_max
+            && write_packets.capacity() > write_batch.max_packets * 2
+            && write_packets.len() <= write_batch.max_packets
         {
-            write_packets.shrink_to(*write_packets_max);
+            write_packets.shrink_to(write_batch.max_packets);
         }
         if have_write && !pendings.write {
             match Pin::new(&mut *conn).poll_flush(cx) {
@@ -430,111 +569,83 @@ where
             }
         }
 
-        // Broadcast packets to matched sessions
-        //   * Consume from: [broadcast_packets]
-        log::trace!(
-            "[{}] broadcast_cnt={}, broadcast_packets.len() = {}",
-            current_client_id,
-            session.broadcast_packets_cnt(),
-            session.broadcast_packets().len(),
-        );
-        let old_cnt: usize = session
-            .broadcast_packets()
-            .values()
-            .map(|info| info.msgs.len())
-            .sum();
-        let mut consume_cnt = 0;
-        session.broadcast_packets().retain(|client_id, info| {
-            log::trace!(
-                "[{}] handling broadcast: flushed={}, msgs={:?}",
-                current_client_id,
-                info.flushed,
-                info.msgs
-            );
-            // `info.flushed` is for msgs is empty
-            if !info.flushed && info.msgs.is_empty() {
-                return match Pin::new(&mut info.sink).poll_flush(cx) {
-                    Poll::Ready(Ok(())) => {
-                        log::trace!(
-                            "[{}] broadcast to [{}] flush success",
-                            current_client_id,
-                            client_id,
-                        );
-                        false
-                    }
-                    Poll::Ready(Err(_)) => {
-                        consume_cnt += info.msgs.len();
-                        false
-                    }
-                    Poll::Pending => {
-                        log::trace!(
-                            "[{}] broadcast to [{}] retry not flushed",
-                            current_client_id,
-                            client_id,
-                        );
-                        true
-                    }
-                };
+        // Detect slow consumer
+        if pendings.write {
+            if write_stall_timer.is_none() && slow_consumer.max_write_stall > 0 {
+                let duration = Duration::from_secs(slow_consumer.max_write_stall);
+                *write_stall_timer = Some(Box::pin(sleep(duration)));
             }
-            while let Some(msg) = info.msgs.pop_front() {
-                consume_cnt += 1;
-                match Pin::new(&mut info.sink).poll_ready(cx) {
-                    Poll::Ready(Ok(())) => {}
-                    Poll::Ready(Err(_)) => {
-                        // channel disconnected
-                        consume_cnt += info.msgs.len();
-                        return false;
-                    }
-                    Poll::Pending => {
-                        // channel is full
-                        consume_cnt -= 1;
-                        info.msgs.push_front(msg);
-                        pendings.broadcast = true;
-                        log::trace!("target client channel is pending: [{}]", client_id);
-                        return true;
-                    }
+        } else if write_packets.is_empty() {
+            *write_stall_timer = None;
+        }
+        let write_stalled = write_stall_timer
+            .as_mut()
+            .map(|timer| timer.as_mut().poll(cx).is_ready())
+            .unwrap_or(false);
+        let queue_depth =
+            session.pending_packets_len() + receiver.normal.len() + write_packets.len();
+        let queue_too_deep =
+            if slow_consumer.max_queue_depth > 0 && queue_depth > slow_consumer.max_queue_depth {
+                if slow_consumer.max_queue_duration > 0 {
+                    let timer = queue_deep_timer.get_or_insert_with(|| {
+                        Box::pin(sleep(Duration::from_secs(slow_consumer.max_queue_duration)))
+                    });
+                    timer.as_mut().poll(cx).is_ready()
+                } else {
+                    true
                 }
-                log::trace!(
-                    "[{}] broadcast to [{}] {:?}",
-                    current_client_id,
-                    client_id,
-                    msg
+            } else {
+                *queue_deep_timer = None;
+                false
+            };
+        if write_stalled || queue_too_deep {
+            if !*slow_consumer_reported {
+                *slow_consumer_reported = true;
+                Metrics::incr(&global.metrics.slow_consumers);
+                tracing::warn!(
+                    client_id = %current_client_id,
+                    queue_depth,
+                    write_stalled,
+                    "slow consumer detected"
                 );
-                if Pin::new(&mut info.sink)
-                    .start_send((current_client_id, msg))
-                    .is_err()
-                {
-                    log::trace!("send publish to disconnected client: {}", client_id);
-                    consume_cnt += info.msgs.len();
-                    return false;
-                }
             }
-            match Pin::new(&mut info.sink).poll_flush(cx) {
-                Poll::Ready(_) => false,
-                Poll::Pending => {
-                    log::trace!(
-                        "[{}] broadcast to [{}] not flushed",
-                        current_client_id,
-                        client_id,
-                    );
-                    info.flushed = false;
-                    true
-                }
+            if slow_consumer.disconnect {
+                let packet = session.slow_consumer_disconnect();
+                let output = Some(Error::SlowConsumer.into());
+                start_close(write_packets, packet, output, closing);
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
             }
-        });
-        let new_cnt: usize = session
-            .broadcast_packets()
-            .values()
-            .map(|info| info.msgs.len())
-            .sum();
-        // TODO: make this as debug assert when ready
-        assert_eq!(
-            new_cnt + consume_cnt,
-            old_cnt,
-            "new:{new_cnt} + consume:{consume_cnt} != old:{old_cnt}",
+        } else {
+            *slow_consumer_reported = false;
+        }
+
+        if session.retries_exhausted() {
+            Metrics::incr(&global.metrics.retransmit_disconnects);
+            tracing::warn!(
+                client_id = %current_client_id,
+                "inflight message not acknowledged after too many retransmissions"
+            );
+            let packet = session.retries_exhausted_disconnect();
+            let output = Some(Error::RetransmissionsExhausted.into());
+            start_close(write_packets, packet, output, closing);
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        // Broadcast packets to matched sessions
+        //   * Consume from: [broadcast_packets]
+        tracing::trace!(
+            "[{}] broadcast_packets={}",
+            current_client_id,
+            session.broadcast_packets_cnt(),
         );
+        let (consume_cnt, broadcast_pending) =
+            session
+                .broadcast_packets()
+                .poll_send(current_client_id, global, *latency_metrics, cx);
+        pendings.broadcast = broadcast_pending;
         let have_broadcast = consume_cnt > 0;
-        session.consume_broadcast(consume_cnt);
 
         // FIXME: handle extension request here
 
@@ -543,14 +654,14 @@ where
         }
 
         // Check if all pending
-        log::trace!("[{}] {:?}", current_client_id, pendings);
-        log::trace!(
+        tracing::trace!("[{}] {:?}", current_client_id, pendings);
+        tracing::trace!(
             "[{}] write_packets={}, broadcast_packets={}, ",
             current_client_id,
             write_packets.len(),
             session.broadcast_packets_cnt(),
         );
-        log::trace!(
+        tracing::trace!(
             "[{}] read_unfinish={}, normal_stream_unfinish={}",
             current_client_id,
             read_unfinish,
@@ -558,32 +669,259 @@ where
         );
 
         if have_write && (*read_unfinish || *normal_stream_unfinish) {
-            log::debug!(
+            tracing::debug!(
                 "[{}] yield because write processed (producer unfinish)",
                 current_client_id
             );
             cx.waker().wake_by_ref();
         } else if have_broadcast && *read_unfinish {
-            log::debug!(
+            tracing::debug!(
                 "[{}] yield because broadcast processed (producer unfinish)",
                 current_client_id
             );
             cx.waker().wake_by_ref();
         } else {
-            log::debug!("[{}] NOT yield", current_client_id);
+            tracing::debug!("[{}] NOT yield", current_client_id);
         }
         Poll::Pending
     }
 }
 
+// Queue the DISCONNECT packet and close the connection after it written (or
+// timed out). The partial written data is kept so the DISCONNECT packet is not
+// interleaved with it, other queued packets are dropped (the inflight QoS 1/2
+// messages are still kept by the session).
+fn start_close<P>(
+    write_packets: &mut VecDeque<WritePacket<P>>,
+    packet: Option<P>,
+    output: Option<io::Error>,
+    closing: &mut Option<(Option<io::Error>, Pin<Box<Sleep>>)>,
+) {
+    let partial = match write_packets.pop_front() {
+        Some(data @ WritePacket::Data(_)) => Some(data),
+        _ => None,
+    };
+    write_packets.clear();
+    write_packets.extend(partial);
+    write_packets.extend(packet.map(WritePacket::Packet));
+    *closing = Some((output, Box::pin(sleep(CLOSE_TIMEOUT))));
+}
+
+// Write the packets to the connection until all written or the write is
+// pending, returns (written any data, write pending).
+#[allow(clippy::too_many_arguments)]
+fn write_to_conn<W, S>(
+    conn: &mut W,
+    session: &S,
+    write_packets: &mut VecDeque<WritePacket<S::Packet>>,
+    global: &GlobalState,
+    write_batch: &WriteBatchConfig,
+    buffer_pool: &BufferPoolConfig,
+    #[cfg(debug_assertions)] flow_validator: &mut FlowValidator,
+    cx: &mut Context<'_>,
+) -> io::Result<(bool, bool)>
+where
+    W: AsyncWrite + Unpin,
+    S: OnlineSession,
+    S::Packet: MqttPacket + Debug,
+{
+    let write_vectored = conn.is_write_vectored();
+    let (mut written_any, mut write_pending) = (false, false);
+    while !write_packets.is_empty() {
+        let (mut data_all, mut data_idx) = (buffer_pool::take(&global.metrics), 0);
+        // The shared encoded packets are not copied when the connection
+        // supports vectored write: (the offset in `data_all`, data).
+        let mut shared_data: Vec<(usize, Bytes)> = Vec::new();
+        let mut shared_len = 0;
+        while let Some(write_packet) = write_packets.pop_front() {
+            tracing::trace!(
+                "[{}] encode packet: {:?}",
+                session.client_id(),
+                write_packet
+            );
+            match write_packet {
+                // NOTE: this must be the first item. The rest of a partial
+                // write, the packets are recorded when they are encoded.
+                WritePacket::Data((data, idx)) => {
+                    let data = match data {
+                        VarBytes::Dynamic(d) => d,
+                        VarBytes::Fixed2(d) => d.to_vec(),
+                        VarBytes::Fixed4(d) => d.to_vec(),
+                    };
+                    buffer_pool::put(mem::replace(&mut data_all, data), buffer_pool);
+                    data_idx = idx;
+                }
+                WritePacket::Packet(pkt) => {
+                    global.packet_tracer.record(
+                        session.client_identifier(),
+                        PacketDirection::Out,
+                        &pkt,
+                    );
+                    #[cfg(debug_assertions)]
+                    flow::check(
+                        session.client_identifier(),
+                        flow_validator.outgoing(pkt.flow()),
+                    );
+                    data_all.extend(pkt.encode()?.as_ref());
+                }
+                WritePacket::Shared(pkt, data) => {
+                    global.packet_tracer.record(
+                        session.client_identifier(),
+                        PacketDirection::Out,
+                        &pkt,
+                    );
+                    #[cfg(debug_assertions)]
+                    flow::check(
+                        session.client_identifier(),
+                        flow_validator.outgoing(pkt.flow()),
+                    );
+                    if write_vectored {
+                        shared_len += data.len();
+                        shared_data.push((data_all.len(), data));
+                    } else {
+                        data_all.extend_from_slice(&data);
+                    }
+                }
+            }
+            // NOTE: For avoid potential memory leak
+            if data_all.len() + shared_len >= write_batch.max_bytes
+                || shared_data.len() * 2 + 1 >= MAX_IO_SLICES
+            {
+                break;
+            }
+        }
+
+        let poll_result = if shared_data.is_empty() {
+            Pin::new(&mut *conn).poll_write(cx, &data_all[data_idx..])
+        } else {
+            let segments = batch_segments(&data_all, data_idx, &shared_data);
+            let slices: Vec<IoSlice<'_>> = segments.into_iter().map(IoSlice::new).collect();
+            Pin::new(&mut *conn).poll_write_vectored(cx, &slices)
+        };
+        let written = match poll_result {
+            Poll::Ready(Ok(size)) => {
+                tracing::trace!("[{}] write {} bytes data", session.client_id(), size);
+                written_any = true;
+                if data_idx + size == data_all.len() + shared_len {
+                    buffer_pool::put(data_all, buffer_pool);
+                    continue;
+                }
+                size
+            }
+            Poll::Ready(Err(err)) => return Err(err),
+            Poll::Pending => {
+                write_pending = true;
+                0
+            }
+        };
+        let (data, idx) = unwritten_data(data_all, data_idx, &shared_data, written);
+        write_packets.push_front(WritePacket::Data((VarBytes::Dynamic(data), idx)));
+        break;
+    }
+    Ok((written_any, write_pending))
+}
+
+// Handle a received packet, the hook request (if any) is kept in `hook_fut`
+// when it's not ready.
+fn handle_packet<S, Hk>(
+    session: &mut S,
+    (encode_len, packet_body, packet): ReceivedPacket<S::Packet>,
+    write_packets: &mut VecDeque<WritePacket<S::Packet>>,
+    hook_fut: &mut Option<HookFuture>,
+    handler: &Hk,
+    global: &Arc<GlobalState>,
+    cx: &mut Context<'_>,
+) -> Result<(), Option<io::Error>>
+where
+    S: OnlineSession,
+    Hk: Hook + Clone + Send + Sync + 'static,
+{
+    if let Some(request) =
+        session.handle_packet(encode_len, packet_body, packet, write_packets, global)?
+    {
+        let mut fut: HookFuture =
+            Box::pin(handle_request(request, handler.clone(), global.clone()));
+        let actions = match fut.as_mut().poll(cx) {
+            Poll::Ready(HookResponse::Normal(result)) => result?,
+            Poll::Ready(_) => panic!("invalid hook response"),
+            Poll::Pending => {
+                *hook_fut = Some(fut);
+                return Ok(());
+            }
+        };
+        for action in actions {
+            session.apply_action(action, global).map_err(Some)?;
+        }
+    }
+    session.after_handle_packet(write_packets);
+    Ok(())
+}
+
+// The data of a write batch in order: the encoded data in `data_all` (from
+// `data_idx`) split by the shared data placed at their offsets.
+fn batch_segments<'d>(
+    data_all: &'d [u8],
+    data_idx: usize,
+    shared_data: &'d [(usize, Bytes)],
+) -> Vec<&'d [u8]> {
+    let mut segments = Vec::with_capacity(shared_data.len() * 2 + 1);
+    let mut start = data_idx;
+    for (offset, data) in shared_data {
+        segments.push(&data_all[start..*offset]);
+        segments.push(data.as_ref());
+        start = *offset;
+    }
+    segments.push(&data_all[start..]);
+    segments.retain(|segment| !segment.is_empty());
+    segments
+}
+
+// The data of a write batch not written yet, as (data, index). The data is
+// only copied when some shared data is not written.
+fn unwritten_data(
+    data_all: Vec<u8>,
+    data_idx: usize,
+    shared_data: &[(usize, Bytes)],
+    written: usize,
+) -> (Vec<u8>, usize) {
+    let head_len = match shared_data.last() {
+        Some((offset, _)) => {
+            offset - data_idx
+                + shared_data
+                    .iter()
+                    .map(|(_, data)| data.len())
+                    .sum::<usize>()
+        }
+        None => 0,
+    };
+    if written >= head_len {
+        let tail_idx = shared_data
+            .last()
+            .map(|(offset, _)| *offset)
+            .unwrap_or(data_idx);
+        return (data_all, tail_idx + written - head_len);
+    }
+    let mut skip = written;
+    let mut rest = Vec::new();
+    for segment in batch_segments(&data_all, data_idx, shared_data) {
+        if skip >= segment.len() {
+            skip -= segment.len();
+        } else {
+            rest.extend_from_slice(&segment[skip..]);
+            skip = 0;
+        }
+    }
+    (rest, 0)
+}
+
 fn too_much_write_data<P>(
     write_packets: &VecDeque<WritePacket<P>>,
-    write_packets_max: usize,
+    write_batch: &WriteBatchConfig,
 ) -> bool {
-    if write_packets.len() >= write_packets_max {
+    if write_packets.len() >= write_batch.max_packets {
         true
     } else if let Some(WritePacket::Data((VarBytes::Dynamic(data), _))) = write_packets.front() {
-        data.len() >= WRITE_BATCH_SIZE
+        data.len() >= write_batch.max_bytes
     } else {
         false
     }
@@ -604,6 +942,9 @@ struct Pendings {
 pub enum WritePacket<P> {
     Packet(P),
     Data((VarBytes, usize)),
+    /// The packet already encoded (shared with other receivers), the packet
+    /// itself is only for tracing.
+    Shared(P, Bytes),
 }
 
 impl<P> From<P> for WritePacket<P> {
@@ -612,25 +953,216 @@ impl<P> From<P> for WritePacket<P> {
     }
 }
 
+/// The encoded packet shared by all the receivers of a published message, so
+/// the packet is encoded once (by the first receiver) instead of per receiver.
+#[derive(Debug, Clone, Default)]
+pub struct SharedEncoded(Arc<OnceLock<Bytes>>);
+
+impl SharedEncoded {
+    /// The packet must be the same for all the receivers sharing the data
+    pub fn write_packet<P: MqttPacket>(&self, packet: P) -> WritePacket<P> {
+        let data = match self.0.get() {
+            Some(data) => data.clone(),
+            None => match packet.encode() {
+                Ok(data) => self
+                    .0
+                    .get_or_init(|| Bytes::copy_from_slice(data.as_ref()))
+                    .clone(),
+                // The error is returned when encode the packet again
+                Err(_) => return WritePacket::Packet(packet),
+            },
+        };
+        WritePacket::Shared(packet, data)
+    }
+}
+
+/// The messages published by a session and not sent to the receivers'
+/// channels yet. The senders are taken from the global clients registry when
+/// sending, only the receivers with a full channel hold a sink here.
+#[derive(Default)]
 pub struct BroadcastPackets {
-    pub sink: SendSink<'static, (ClientId, NormalMessage)>,
-    pub msgs: VecDeque<NormalMessage>,
-    pub flushed: bool,
+    // (receiver, message) in the published order
+    msgs: VecDeque<(ClientId, NormalMessage)>,
+    // The queued messages count of every receiver
+    receivers: HashMap<ClientId, usize>,
+    // The receivers with a full channel, waiting for the message in the sink
+    blocked: HashMap<ClientId, SendSink<'static, (ClientId, NormalMessage)>>,
+    // The payload bytes of `msgs`, released when sent or dropped
+    memory: MemoryCharge,
+    // The messages enqueued by the fan out workers (see `fanout::dispatch`)
+    pub(crate) fan_out: FanOutQueue,
+}
+
+impl BroadcastPackets {
+    /// The count of the queued messages (and the fan out jobs)
+    pub fn len(&self) -> usize {
+        self.msgs.len() + self.fan_out.len()
+    }
+
+    /// If some messages to the receiver are queued
+    pub(crate) fn is_queued(&self, receiver: &ClientId) -> bool {
+        self.receivers.contains_key(receiver) || self.blocked.contains_key(receiver)
+    }
+
+    /// Queue a message to the receiver, the message is dropped (return false)
+    /// when `max_per_receiver` messages already queued for the receiver.
+    pub fn push(
+        &mut self,
+        receiver: ClientId,
+        msg: NormalMessage,
+        max_per_receiver: usize,
+    ) -> bool {
+        let count = self.receivers.entry(receiver).or_insert(0);
+        if *count >= max_per_receiver {
+            return false;
+        }
+        *count += 1;
+        self.memory.add(msg.payload_len());
+        self.msgs.push_back((receiver, msg));
+        true
+    }
+
+    /// Charge the bytes of the queued messages to another counter (the global
+    /// memory usage), the bytes already queued are moved to it.
+    pub fn set_memory_charge(&mut self, mut charge: MemoryCharge) {
+        charge.add(self.memory.bytes());
+        self.memory = charge;
+    }
+
+    /// Send the queued messages to the receivers' channels until they are
+    /// full, so a slow receiver will not block the others. Return the count
+    /// of the consumed (sent or dropped) messages, and if some messages are
+    /// waiting for a full channel.
+    pub(crate) fn poll_send(
+        &mut self,
+        sender_id: ClientId,
+        global: &GlobalState,
+        latency_metrics: bool,
+        cx: &mut Context<'_>,
+    ) -> (usize, bool) {
+        // The blocked receiver is removed when the message in the sink is sent
+        // (or the channel is disconnected, the later messages are dropped).
+        self.blocked
+            .retain(|_, sink| Pin::new(sink).poll_flush(cx).is_pending());
+        let (fan_out_cnt, fan_out_pending) = self.fan_out.poll_send(global, cx);
+        if self.msgs.is_empty() {
+            return (fan_out_cnt, fan_out_pending);
+        }
+
+        let old_len = self.msgs.len();
+        let mut senders: HashMap<ClientId, Option<Sender<(ClientId, NormalMessage)>>> =
+            HashMap::new();
+        for (client_id, msg) in mem::take(&mut self.msgs) {
+            // Keep the order of the messages to a receiver
+            if self.blocked.contains_key(&client_id) {
+                self.msgs.push_back((client_id, msg));
+                continue;
+            }
+            if let Some(count) = self.receivers.get_mut(&client_id) {
+                *count -= 1;
+                if *count == 0 {
+                    self.receivers.remove(&client_id);
+                }
+            }
+            self.memory.sub(msg.payload_len());
+            let sender = senders
+                .entry(client_id)
+                .or_insert_with(|| global.get_client_normal_sender(&client_id));
+            let sender = match sender {
+                Some(sender) => sender,
+                None => continue,
+            };
+            tracing::trace!("[{}] broadcast to [{}] {:?}", sender_id, client_id, msg);
+            let (qos, received_at) = (msg.qos(), msg.received_at());
+            match sender.try_send((sender_id, msg)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(item)) => {
+                    tracing::trace!("target client channel is full: [{}]", client_id);
+                    let mut sink = sender.clone().into_sink();
+                    // A new sink is always ready
+                    if Pin::new(&mut sink).start_send(item).is_ok()
+                        && Pin::new(&mut sink).poll_flush(cx).is_pending()
+                    {
+                        self.blocked.insert(client_id, sink);
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    tracing::trace!("send publish to disconnected client: {}", client_id);
+                    senders.insert(client_id, None);
+                    continue;
+                }
+            }
+            if latency_metrics {
+                global
+                    .metrics
+                    .latency
+                    .record(LatencyStage::Enqueue, qos, received_at);
+            }
+        }
+        (
+            old_len - self.msgs.len() + fan_out_cnt,
+            !self.msgs.is_empty() || fan_out_pending,
+        )
+    }
+
+    /// Send all the queued messages, wait for the full channels (when the
+    /// session finished).
+    pub(crate) async fn send_all(&mut self, sender_id: ClientId, global: &GlobalState) {
+        self.fan_out.send_all(global).await;
+        for (client_id, mut sink) in self.blocked.drain() {
+            if let Err(err) = sink.flush().await {
+                tracing::warn!(
+                    "[{}] send broadcast message to {} failed: {:?}",
+                    sender_id,
+                    client_id,
+                    err
+                );
+            }
+        }
+        self.receivers.clear();
+        while let Some((client_id, msg)) = self.msgs.pop_front() {
+            self.memory.sub(msg.payload_len());
+            tracing::debug!("[{}] broadcast to [{}], {:?}", sender_id, client_id, msg);
+            let sender = match global.get_client_normal_sender(&client_id) {
+                Some(sender) => sender,
+                None => continue,
+            };
+            if let Err(err) = sender.send_async((sender_id, msg)).await {
+                tracing::warn!(
+                    "[{}] send broadcast message to {} failed: {:?}",
+                    sender_id,
+                    client_id,
+                    err
+                );
+            }
+        }
+    }
 }
 
 pub trait MqttPacket {
     fn encode(&self) -> Result<VarBytes, io::Error>;
+    /// Checked by the packet flow validator (debug builds only)
+    #[cfg(debug_assertions)]
+    fn flow(&self) -> PacketFlow;
 }
 
 impl MqttPacket for v3::Packet {
     fn encode(&self) -> Result<VarBytes, io::Error> {
         self.encode().map_err(io::Error::from)
     }
+    #[cfg(debug_assertions)]
+    fn flow(&self) -> PacketFlow {
+        PacketFlow::from_v3(self)
+    }
 }
 impl MqttPacket for v5::Packet {
     fn encode(&self) -> Result<VarBytes, io::Error> {
         self.encode().map_err(io::Error::from)
     }
+    #[cfg(debug_assertions)]
+    fn flow(&self) -> PacketFlow {
+        PacketFlow::from_v5(self)
+    }
 }
 
 pub trait OnlineSession {
@@ -639,13 +1171,20 @@ pub trait OnlineSession {
     type SessionState;
 
     fn client_id(&self) -> ClientId;
+    fn client_identifier(&self) -> &str;
     fn disconnected(&self) -> bool;
     fn build_state(&mut self, receiver: ClientReceiver) -> Self::SessionState;
 
-    fn consume_broadcast(&mut self, count: usize);
     fn broadcast_packets_cnt(&self) -> usize;
     fn broadcast_packets_max(&self) -> usize;
-    fn broadcast_packets(&mut self) -> &mut HashMap<ClientId, BroadcastPackets>;
+    fn broadcast_packets(&mut self) -> &mut BroadcastPackets;
+
+    /// The packet used a permit of `Config.inbound_limit`
+    fn is_publish(packet: &Self::Packet) -> bool;
+
+    /// The packet not ordered with the packets of the hook requests (PINGREQ
+    /// and acks), handled even when a hook request is in flight.
+    fn skip_hook_queue(packet: &Self::Packet) -> bool;
 
     fn handle_decode_error(
         &mut self,
@@ -673,6 +1212,44 @@ pub trait OnlineSession {
         sender: ClientId,
         msg: NormalMessage,
         global: &Arc<GlobalState>,
-    ) -> Option<(QoS, Option<Self::Packet>)>;
+    ) -> Option<(QoS, Option<WritePacket<Self::Packet>>)>;
     fn handle_pendings(&mut self) -> Vec<Self::Packet>;
+    fn pending_packets_len(&self) -> usize;
+    /// The packet send to client before disconnect a slow consumer
+    fn slow_consumer_disconnect(&mut self) -> Option<Self::Packet>;
+    /// The packet send to client before disconnect it by a control message
+    fn control_disconnect(&mut self) -> Option<Self::Packet>;
+    /// If an inflight message is not acknowledged after re-sent too many
+    /// times (see `Config.retransmit`)
+    fn retries_exhausted(&self) -> bool;
+    /// The packet send to client before disconnect it when retries exhausted
+    fn retries_exhausted_disconnect(&mut self) -> Option<Self::Packet>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unwritten_data() {
+        // "ab" + [cd] + "e" + [fg] + "hi"
+        let data_all = b"xxabehi".to_vec();
+        let shared_data = vec![
+            (4, Bytes::from_static(b"cd")),
+            (5, Bytes::from_static(b"fg")),
+        ];
+        let segments = batch_segments(&data_all, 2, &shared_data);
+        assert_eq!(segments.concat(), b"abcdefghi");
+
+        for written in 0..=9 {
+            let (data, idx) = unwritten_data(data_all.clone(), 2, &shared_data, written);
+            assert_eq!(&data[idx..], &b"abcdefghi"[written..]);
+        }
+        // No shared data left, the data is not copied
+        assert_eq!(
+            unwritten_data(data_all.clone(), 2, &shared_data, 8),
+            (data_all.clone(), 6)
+        );
+        assert_eq!(unwritten_data(data_all.clone(), 2, &[], 1), (data_all, 3));
+    }
 }
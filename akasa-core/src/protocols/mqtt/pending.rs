@@ -1,61 +1,128 @@
+//! The QoS 1/2 state of the outgoing messages. The current time is given by
+//! the caller, so the state machine is deterministic and can be tested alone.
 //!
 //! TODO: save packets in storage (rocksdb/sqlite3)
 //!
 use std::cmp;
 use std::collections::VecDeque;
 use std::fmt::Debug;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use mqtt_proto::{Pid, QoS};
 
+use crate::config::{OverflowPolicy, RetransmitConfig};
+use crate::memory::MemoryCharge;
+
 pub struct PendingPackets<P> {
     max_inflight: u16,
     max_packets: usize,
-    // The ack packet timeout, when reached resent the packet
-    timeout: u64,
+    // The max payload bytes of the packets not sent yet, 0 means unlimited
+    max_bytes: usize,
+    // The payload bytes of current `New` packets, also charged to the global
+    // memory usage (see `set_memory_charge`)
+    memory: MemoryCharge,
+    overflow_policy: OverflowPolicy,
+    // When the ack packet timeout reached, resent the packet
+    retransmit: RetransmitConfig,
+    // Re-send the inflight packets without waiting the timeout (after the
+    // client reconnected), so they are not overtaken by the new packets
+    resend_inflight: bool,
     packets: VecDeque<PendingPacketStatus<P>>,
 }
 
 impl<P: Debug> PendingPackets<P> {
-    pub fn new(max_inflight: u16, max_packets: usize, timeout: u64) -> PendingPackets<P> {
+    pub fn new(
+        max_inflight: u16,
+        max_packets: usize,
+        retransmit: RetransmitConfig,
+    ) -> PendingPackets<P> {
         PendingPackets {
             max_inflight,
             max_packets,
-            timeout,
+            max_bytes: 0,
+            memory: MemoryCharge::default(),
+            overflow_policy: OverflowPolicy::DropNewest,
+            retransmit,
+            resend_inflight: false,
             packets: VecDeque::new(),
         }
     }
 
-    /// Push a packet into queue, return if the queue is full.
-    pub fn push_back(&mut self, pid: Pid, packet: P) -> bool {
-        if self.packets.len() >= self.max_packets {
-            log::error!(
-                "drop packet {:?}, due to too many packets in the queue: {}",
-                packet,
-                self.packets.len()
-            );
-            return true;
+    /// Push a packet (`size` is the payload length) into queue, the overflow
+    /// policy is applied when the queue is full.
+    pub fn push_back(&mut self, pid: Pid, packet: P, size: usize) -> PendingPush {
+        let mut dropped = 0;
+        while self.is_full(size) {
+            let oldest_idx = match self.overflow_policy {
+                OverflowPolicy::DropOldest => self.packets.iter().position(|packet_status| {
+                    matches!(packet_status, PendingPacketStatus::New { last_sent: 0, .. })
+                }),
+                OverflowPolicy::DropNewest => None,
+                OverflowPolicy::Disconnect => {
+                    tracing::error!(
+                        "pending messages queue overflow, packets: {}, bytes: {}",
+                        self.packets.len(),
+                        self.memory.bytes(),
+                    );
+                    return PendingPush::Overflow;
+                }
+            };
+            // The packets already sent can not be dropped
+            let idx = match oldest_idx {
+                Some(idx) => idx,
+                None => {
+                    tracing::error!(
+                        "drop packet {:?}, due to too many packets in the queue: {}, bytes: {}",
+                        packet,
+                        self.packets.len(),
+                        self.memory.bytes(),
+                    );
+                    return PendingPush::Dropped(dropped);
+                }
+            };
+            if let Some(PendingPacketStatus::New { size, .. }) = self.packets.remove(idx) {
+                self.memory.sub(size);
+            }
+            dropped += 1;
+        }
+        if dropped > 0 {
+            tracing::warn!("drop {} oldest packets, due to queue is full", dropped);
         }
+        self.memory.add(size);
         self.packets.push_back(PendingPacketStatus::New {
-            added_at: get_unix_ts(),
+            added_at: Instant::now(),
             last_sent: 0,
             pid,
             packet,
             dup: false,
+            size,
+            retries: 0,
         });
-        false
+        PendingPush::Queued(dropped)
+    }
+
+    /// If the packet (`size` is the payload length) can not be pushed without
+    /// applying the overflow policy. A single packet is always allowed when no
+    /// bytes are pending, so that the large packet will not be dropped forever.
+    pub fn is_full(&self, size: usize) -> bool {
+        self.packets.len() >= self.max_packets
+            || (self.max_bytes > 0
+                && self.memory.bytes() > 0
+                && self.memory.bytes() + size > self.max_bytes)
     }
 
-    pub fn pubrec(&mut self, target_pid: Pid) -> bool {
+    pub fn pubrec(&mut self, target_pid: Pid, now_ts: u64) -> bool {
         let current_inflight = cmp::min(self.max_inflight as usize, self.packets.len());
         for idx in 0..current_inflight {
             let packet_status = self.packets.get_mut(idx).expect("packet");
             match packet_status {
-                PendingPacketStatus::New { pid, .. } => {
+                PendingPacketStatus::New { pid, size, .. } => {
                     if *pid == target_pid {
+                        self.memory.sub(*size);
                         *packet_status = PendingPacketStatus::Pubrec {
-                            last_sent: get_unix_ts(),
+                            last_sent: now_ts,
                             pid: target_pid,
+                            retries: 0,
                         };
                         return true;
                     }
@@ -72,8 +139,9 @@ impl<P: Debug> PendingPackets<P> {
         for idx in 0..current_inflight {
             let packet_status = self.packets.get_mut(idx).expect("packet");
             match packet_status {
-                PendingPacketStatus::New { pid, .. } if qos == QoS::Level1 => {
+                PendingPacketStatus::New { pid, size, .. } if qos == QoS::Level1 => {
                     if *pid == target_pid {
+                        self.memory.sub(*size);
                         *packet_status = PendingPacketStatus::Complete;
                         return true;
                     }
@@ -107,56 +175,188 @@ impl<P: Debug> PendingPackets<P> {
         }
     }
 
+    /// Get the next packet to send (not sent yet or the ack timeout reached),
+    /// the re-send is counted. The packet already re-sent `max_retries` times
+    /// is skipped (see `retries_exhausted`). The packets are always returned in
+    /// the queue order, so the messages of a topic are sent in the publish order.
     pub fn get_ready_packet(
         &mut self,
         start_idx: usize,
+        now_ts: u64,
     ) -> Option<(usize, &mut PendingPacketStatus<P>)> {
-        let now_ts = get_unix_ts();
         let current_inflight = cmp::min(self.max_inflight as usize, self.packets.len());
         let mut next_idx = None;
         for idx in start_idx..current_inflight {
             let packet_status = self.packets.get_mut(idx).expect("packet");
-            match packet_status {
-                PendingPacketStatus::New { last_sent, .. } => {
-                    if now_ts >= self.timeout + *last_sent {
-                        next_idx = Some(idx);
-                        break;
-                    }
+            let (last_sent, retries) = match packet_status {
+                PendingPacketStatus::New {
+                    last_sent, retries, ..
                 }
-                PendingPacketStatus::Pubrec { last_sent, .. } => {
-                    if now_ts >= self.timeout + *last_sent {
-                        next_idx = Some(idx);
-                        break;
-                    }
+                | PendingPacketStatus::Pubrec {
+                    last_sent, retries, ..
+                } => (*last_sent, retries),
+                PendingPacketStatus::Complete => continue,
+            };
+            if last_sent == 0 || self.resend_inflight {
+                next_idx = Some(idx);
+                break;
+            }
+            if now_ts >= last_sent + self.retransmit.timeout(*retries)
+                && !self.retransmit.exhausted(*retries)
+            {
+                *retries += 1;
+                next_idx = Some(idx);
+                break;
+            }
+        }
+        if next_idx.is_none() {
+            self.resend_inflight = false;
+        }
+        next_idx.map(|idx| (idx, self.packets.get_mut(idx).expect("packet")))
+    }
+
+    /// If an inflight packet reached the ack timeout after re-sent
+    /// `max_retries` times, the client should be disconnected.
+    pub fn retries_exhausted(&self, now_ts: u64) -> bool {
+        if self.retransmit.max_retries == 0 {
+            return false;
+        }
+        let current_inflight = cmp::min(self.max_inflight as usize, self.packets.len());
+        self.packets
+            .iter()
+            .take(current_inflight)
+            .any(|packet_status| match packet_status {
+                PendingPacketStatus::New {
+                    last_sent, retries, ..
+                }
+                | PendingPacketStatus::Pubrec {
+                    last_sent, retries, ..
+                } => {
+                    *last_sent > 0
+                        && self.retransmit.exhausted(*retries)
+                        && now_ts >= last_sent + self.retransmit.timeout(*retries)
                 }
+                PendingPacketStatus::Complete => false,
+            })
+    }
+
+    /// Count the re-sends from the beginning, called when the client
+    /// reconnected. The inflight packets are re-sent to the new connection at
+    /// once, before the packets not sent yet.
+    pub fn reset_retries(&mut self) {
+        self.resend_inflight = true;
+        for packet_status in self.packets.iter_mut() {
+            match packet_status {
+                PendingPacketStatus::New { retries, .. }
+                | PendingPacketStatus::Pubrec { retries, .. } => *retries = 0,
                 PendingPacketStatus::Complete => {}
             }
         }
-        next_idx.map(|idx| (idx, self.packets.get_mut(idx).expect("packet")))
     }
 
     pub fn len(&self) -> usize {
         self.packets.len()
     }
 
+    /// Remove the packets not sent yet that matched the predicate (called with
+    /// the added time and the packet), return the count of removed packets.
+    pub fn remove_unsent<F>(&mut self, mut predicate: F) -> usize
+    where
+        F: FnMut(Instant, &P) -> bool,
+    {
+        let old_len = self.packets.len();
+        let mut removed_bytes = 0;
+        self.packets.retain(|packet_status| match packet_status {
+            PendingPacketStatus::New {
+                added_at,
+                last_sent: 0,
+                packet,
+                size,
+                ..
+            } if predicate(*added_at, packet) => {
+                removed_bytes += size;
+                false
+            }
+            _ => true,
+        });
+        self.memory.sub(removed_bytes);
+        old_len - self.packets.len()
+    }
+
+    /// The packet ids of current inflight packets (sent but not acknowledged)
+    pub fn inflight_pids(&self) -> Vec<Pid> {
+        let current_inflight = cmp::min(self.max_inflight as usize, self.packets.len());
+        self.packets
+            .iter()
+            .take(current_inflight)
+            .filter_map(|packet_status| match packet_status {
+                PendingPacketStatus::New { pid, last_sent, .. } if *last_sent > 0 => Some(*pid),
+                PendingPacketStatus::Pubrec { pid, .. } => Some(*pid),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn set_max_inflight(&mut self, new_value: u16) {
         self.max_inflight = new_value;
     }
+
+    /// Charge the bytes of the `New` packets to another counter (the global
+    /// memory usage), the bytes already in the queue are moved to it.
+    pub fn set_memory_charge(&mut self, mut charge: MemoryCharge) {
+        charge.add(self.memory.bytes());
+        self.memory = charge;
+    }
+
+    /// Update the queue limits, the packets already in the queue are kept
+    pub fn set_limits(&mut self, max_packets: usize, max_bytes: usize, policy: OverflowPolicy) {
+        self.max_packets = max_packets;
+        self.max_bytes = max_bytes;
+        self.overflow_policy = policy;
+    }
+}
+
+/// The result of push a packet into the pending queue
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PendingPush {
+    /// The packet is queued, with the count of dropped oldest packets
+    Queued(usize),
+    /// The packet is dropped, with the count of dropped oldest packets
+    Dropped(usize),
+    /// The packet is dropped, and the client should be disconnected
+    Overflow,
+}
+
+impl PendingPush {
+    /// The total count of dropped packets
+    pub fn dropped(&self) -> usize {
+        match self {
+            PendingPush::Queued(dropped) => *dropped,
+            PendingPush::Dropped(dropped) => dropped + 1,
+            PendingPush::Overflow => 1,
+        }
+    }
 }
 
 pub enum PendingPacketStatus<P> {
     New {
-        added_at: u64,
+        added_at: Instant,
         // Last sent this packet timestamp as seconds
         last_sent: u64,
         pid: Pid,
         packet: P,
         dup: bool,
+        // The payload length, for limit the queue bytes
+        size: usize,
+        // The re-sent times
+        retries: u32,
     },
     Pubrec {
         // Last sent this packet timestamp as seconds
         last_sent: u64,
         pid: Pid,
+        // The re-sent times of the PUBREL packet
+        retries: u32,
         // v5.x only field
         // properties: Option<PubrecProperties>,
     },
@@ -170,3 +370,355 @@ pub(crate) fn get_unix_ts() -> u64 {
         Err(_) => panic!("SystemTime before UNIX EPOCH!"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashbrown::{HashMap, HashSet};
+    use proptest::prelude::*;
+
+    fn pending_pids(pending: &PendingPackets<u8>) -> Vec<u16> {
+        pending
+            .packets
+            .iter()
+            .filter_map(|packet_status| match packet_status {
+                PendingPacketStatus::New { pid, .. } => Some(pid.value()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_overflow_policy() {
+        let mut pending = PendingPackets::new(1, 3, RetransmitConfig::default());
+        pending.set_limits(4, 12, OverflowPolicy::DropOldest);
+        // The first packet is inflight
+        pending.packets.push_back(PendingPacketStatus::New {
+            added_at: Instant::now(),
+            last_sent: 1,
+            pid: Pid::try_from(1).unwrap(),
+            packet: 1,
+            dup: false,
+            size: 4,
+            retries: 0,
+        });
+        pending.memory.add(4);
+        for value in 2..=3 {
+            let pid = Pid::try_from(value).unwrap();
+            assert_eq!(
+                pending.push_back(pid, value as u8, 4),
+                PendingPush::Queued(0)
+            );
+        }
+        // bytes limit reached
+        assert_eq!(
+            pending.push_back(Pid::try_from(4).unwrap(), 4, 4),
+            PendingPush::Queued(1)
+        );
+        assert_eq!(pending_pids(&pending), vec![1, 3, 4]);
+        // only the inflight packet left, still not enough
+        assert_eq!(
+            pending.push_back(Pid::try_from(5).unwrap(), 5, 9),
+            PendingPush::Dropped(2)
+        );
+        assert_eq!(pending_pids(&pending), vec![1]);
+
+        pending.set_limits(1, 0, OverflowPolicy::DropNewest);
+        assert_eq!(
+            pending.push_back(Pid::try_from(6).unwrap(), 6, 1),
+            PendingPush::Dropped(0)
+        );
+        pending.set_limits(1, 0, OverflowPolicy::Disconnect);
+        assert_eq!(
+            pending.push_back(Pid::try_from(7).unwrap(), 7, 1),
+            PendingPush::Overflow
+        );
+
+        assert!(pending.complete(Pid::try_from(1).unwrap(), QoS::Level1));
+        pending.clean_complete();
+        assert_eq!(pending.memory.bytes(), 0);
+        // A single large packet is allowed
+        pending.set_limits(3, 10, OverflowPolicy::DropNewest);
+        assert_eq!(
+            pending.push_back(Pid::try_from(8).unwrap(), 8, 20),
+            PendingPush::Queued(0)
+        );
+    }
+
+    #[test]
+    fn test_remove_unsent() {
+        let mut pending = PendingPackets::new(1, 10, RetransmitConfig::default());
+        for value in 1..=4 {
+            let pid = Pid::try_from(value).unwrap();
+            assert_eq!(
+                pending.push_back(pid, value as u8, 2),
+                PendingPush::Queued(0)
+            );
+        }
+        // The first packet is inflight
+        if let Some(PendingPacketStatus::New { last_sent, .. }) = pending.packets.front_mut() {
+            *last_sent = 1;
+        }
+        assert_eq!(pending.remove_unsent(|_, packet| packet % 2 == 1), 1);
+        assert_eq!(pending_pids(&pending), vec![1, 2, 4]);
+        assert_eq!(pending.memory.bytes(), 6);
+        assert_eq!(pending.remove_unsent(|_, _| true), 2);
+        assert_eq!(pending_pids(&pending), vec![1]);
+        assert_eq!(pending.memory.bytes(), 2);
+    }
+
+    #[test]
+    fn test_retransmit_backoff() {
+        let retransmit = RetransmitConfig {
+            initial_timeout: 10,
+            backoff_factor: 2,
+            max_timeout: 30,
+            max_retries: 2,
+        };
+        let mut pending = PendingPackets::new(1, 10, retransmit);
+        for value in 1..=2 {
+            let pid = Pid::try_from(value).unwrap();
+            pending.push_back(pid, value as u8, 1);
+        }
+        let now_ts = 1000;
+        // Send the first packet, the second one is not inflight
+        assert_eq!(
+            pending.get_ready_packet(0, now_ts).map(|(idx, _)| idx),
+            Some(0)
+        );
+        let sent_before = |pending: &mut PendingPackets<u8>, secs: u64| {
+            if let Some(PendingPacketStatus::New { last_sent, .. }) = pending.packets.front_mut() {
+                *last_sent = now_ts - secs;
+            }
+            pending.get_ready_packet(0, now_ts).map(|(idx, _)| idx)
+        };
+        // timeouts: 10, 20, then exhausted
+        assert_eq!(sent_before(&mut pending, 0), None);
+        assert_eq!(sent_before(&mut pending, 10), Some(0));
+        assert_eq!(sent_before(&mut pending, 15), None);
+        assert_eq!(sent_before(&mut pending, 20), Some(0));
+        assert!(!pending.retries_exhausted(now_ts));
+        assert_eq!(sent_before(&mut pending, 25), None);
+        assert!(!pending.retries_exhausted(now_ts));
+        assert_eq!(sent_before(&mut pending, 30), None);
+        assert!(pending.retries_exhausted(now_ts));
+
+        pending.reset_retries();
+        assert!(!pending.retries_exhausted(now_ts));
+        assert_eq!(sent_before(&mut pending, 10), Some(0));
+    }
+
+    #[test]
+    fn test_resend_inflight_in_order() {
+        let mut pending = PendingPackets::new(4, 10, RetransmitConfig::default());
+        let now_ts = 1000;
+        let send_all = |pending: &mut PendingPackets<u8>| {
+            let mut sent = Vec::new();
+            let mut start_idx = 0;
+            while let Some((idx, packet_status)) = pending.get_ready_packet(start_idx, now_ts) {
+                start_idx = idx + 1;
+                if let PendingPacketStatus::New {
+                    last_sent, packet, ..
+                } = packet_status
+                {
+                    *last_sent = now_ts;
+                    sent.push(*packet);
+                }
+            }
+            sent
+        };
+        for value in 1..=2 {
+            pending.push_back(Pid::try_from(value).unwrap(), value as u8, 1);
+        }
+        assert_eq!(send_all(&mut pending), vec![1, 2]);
+        // The client reconnected before the acknowledgements
+        for value in 3..=4 {
+            pending.push_back(Pid::try_from(value).unwrap(), value as u8, 1);
+        }
+        pending.reset_retries();
+        assert_eq!(send_all(&mut pending), vec![1, 2, 3, 4]);
+        // Only re-sent once
+        assert_eq!(send_all(&mut pending), Vec::<u8>::new());
+    }
+
+    // The packet is the message id and the QoS
+    type Message = (u32, QoS);
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        // Push a QoS 2 message if true, otherwise QoS 1
+        Push(bool),
+        Send,
+        // Acknowledge an inflight packet (by the index)
+        Ack(usize),
+        // Acknowledge a packet identifier may not inflight
+        AckAny(u16),
+        Tick(u64),
+        Clean,
+    }
+
+    fn op_strategy() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            any::<bool>().prop_map(Op::Push),
+            Just(Op::Send),
+            any::<usize>().prop_map(Op::Ack),
+            (1..=u16::MAX).prop_map(Op::AckAny),
+            (0..40u64).prop_map(Op::Tick),
+            Just(Op::Clean),
+        ]
+    }
+
+    // The client side of the QoS flows
+    #[derive(Default)]
+    struct Model {
+        queued: HashSet<u32>,
+        // The QoS 2 messages received the PUBREC, by the packet identifier
+        released: HashMap<u16, u32>,
+        completed: HashSet<u32>,
+    }
+
+    /// The packets in the inflight window have been sent, with the message if
+    /// the PUBREC is not received yet.
+    fn inflight(pending: &PendingPackets<Message>) -> Vec<(Pid, Option<Message>)> {
+        pending
+            .packets
+            .iter()
+            .take(pending.max_inflight as usize)
+            .filter_map(|packet_status| match packet_status {
+                PendingPacketStatus::New {
+                    pid,
+                    last_sent,
+                    packet,
+                    ..
+                } if *last_sent > 0 => Some((*pid, Some(*packet))),
+                PendingPacketStatus::Pubrec { pid, .. } => Some((*pid, None)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn send(
+        pending: &mut PendingPackets<Message>,
+        model: &Model,
+        now_ts: u64,
+    ) -> Result<(), TestCaseError> {
+        let mut start_idx = 0;
+        while let Some((idx, packet_status)) = pending.get_ready_packet(start_idx, now_ts) {
+            start_idx = idx + 1;
+            match packet_status {
+                PendingPacketStatus::New {
+                    last_sent,
+                    dup,
+                    packet,
+                    ..
+                } => {
+                    prop_assert!(!model.completed.contains(&packet.0));
+                    *dup = true;
+                    *last_sent = now_ts;
+                }
+                PendingPacketStatus::Pubrec { last_sent, .. } => *last_sent = now_ts,
+                PendingPacketStatus::Complete => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    fn ack(
+        pending: &mut PendingPackets<Message>,
+        model: &mut Model,
+        pid: Pid,
+        message: Option<Message>,
+        now_ts: u64,
+    ) -> Result<(), TestCaseError> {
+        match message {
+            Some((msg_id, QoS::Level2)) => {
+                prop_assert!(pending.pubrec(pid, now_ts));
+                prop_assert_eq!(model.released.insert(pid.value(), msg_id), None);
+            }
+            Some((msg_id, _)) => {
+                prop_assert!(pending.complete(pid, QoS::Level1));
+                prop_assert!(model.completed.insert(msg_id));
+            }
+            None => {
+                prop_assert!(pending.complete(pid, QoS::Level2));
+                let msg_id = model.released.remove(&pid.value()).expect("released");
+                prop_assert!(model.completed.insert(msg_id));
+            }
+        }
+        Ok(())
+    }
+
+    proptest! {
+        // The packet identifiers are not reused while inflight, and every
+        // queued message is completed exactly once.
+        #[test]
+        fn prop_outgoing_qos(
+            max_inflight in 1..8u16,
+            ops in prop::collection::vec(op_strategy(), 0..200),
+        ) {
+            let mut pending = PendingPackets::new(max_inflight, 16, RetransmitConfig::default());
+            let mut model = Model::default();
+            // Wrap around the packet identifiers
+            let mut next_pid = Pid::try_from(u16::MAX - 8).unwrap();
+            let mut next_msg_id = 0;
+            let mut now_ts = 1000;
+            for op in ops {
+                match op {
+                    Op::Push(qos2) => {
+                        let qos = if qos2 { QoS::Level2 } else { QoS::Level1 };
+                        let message = (next_msg_id, qos);
+                        if let PendingPush::Queued(_) = pending.push_back(next_pid, message, 1) {
+                            model.queued.insert(next_msg_id);
+                        }
+                        next_pid += 1;
+                        next_msg_id += 1;
+                    }
+                    Op::Send => send(&mut pending, &model, now_ts)?,
+                    Op::Ack(idx) => {
+                        let packets = inflight(&pending);
+                        if !packets.is_empty() {
+                            let (pid, message) = packets[idx % packets.len()];
+                            ack(&mut pending, &mut model, pid, message, now_ts)?;
+                        }
+                    }
+                    Op::AckAny(value) => {
+                        let pid = Pid::try_from(value).unwrap();
+                        let in_window = pending
+                            .packets
+                            .iter()
+                            .take(max_inflight as usize)
+                            .any(|packet_status| match packet_status {
+                                PendingPacketStatus::New { pid: target, .. }
+                                | PendingPacketStatus::Pubrec { pid: target, .. } => *target == pid,
+                                PendingPacketStatus::Complete => false,
+                            });
+                        if !in_window {
+                            prop_assert!(!pending.pubrec(pid, now_ts));
+                            prop_assert!(!pending.complete(pid, QoS::Level1));
+                            prop_assert!(!pending.complete(pid, QoS::Level2));
+                        }
+                    }
+                    Op::Tick(secs) => now_ts += secs,
+                    Op::Clean => pending.clean_complete(),
+                }
+                let pids = pending.inflight_pids();
+                let unique: HashSet<_> = pids.iter().collect();
+                prop_assert_eq!(unique.len(), pids.len());
+            }
+
+            // Finish all the flows
+            let mut rounds = 0;
+            while pending.len() > 0 {
+                rounds += 1;
+                prop_assert!(rounds <= 100, "pending packets not finished");
+                send(&mut pending, &model, now_ts)?;
+                for (pid, message) in inflight(&pending) {
+                    ack(&mut pending, &mut model, pid, message, now_ts)?;
+                }
+                pending.clean_complete();
+            }
+            prop_assert!(model.released.is_empty());
+            prop_assert_eq!(model.completed, model.queued);
+        }
+    }
+}
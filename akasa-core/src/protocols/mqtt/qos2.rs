@@ -0,0 +1,141 @@
+//! The QoS 2 state of the incoming messages (the PUBLISH received, waiting for
+//! the PUBREL), there is no I/O inside so it can be tested alone.
+
+use hashbrown::HashMap;
+use mqtt_proto::Pid;
+
+/// The packet identifiers of the incoming QoS 2 messages, with the hash of the
+/// PUBLISH packet to detect the re-delivered ones.
+#[derive(Debug, Default)]
+pub struct IncomingQos2 {
+    pids: HashMap<Pid, u64>,
+}
+
+/// The result of receiving a QoS 2 PUBLISH packet
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Qos2Publish {
+    /// A new message, it should be published
+    New,
+    /// The message is re-delivered (dup=1), it's already published
+    Duplicate,
+    /// The packet identifier is used by another message
+    PidInUse,
+    /// The message is re-delivered without the dup flag
+    DupRequired,
+    /// Too many messages are waiting for the PUBREL
+    TooMany,
+}
+
+impl IncomingQos2 {
+    /// Receive a PUBLISH packet (`hash` is the hash of the packet), at most
+    /// `max_inflight` messages can wait for the PUBREL.
+    pub fn publish(&mut self, pid: Pid, hash: u64, dup: bool, max_inflight: usize) -> Qos2Publish {
+        match self.pids.get(&pid) {
+            // hash collision is acceptable here, since u16 packet identifier is a small range
+            Some(previous_hash) if *previous_hash != hash => Qos2Publish::PidInUse,
+            Some(_) if !dup => Qos2Publish::DupRequired,
+            Some(_) => Qos2Publish::Duplicate,
+            None if self.pids.len() >= max_inflight => Qos2Publish::TooMany,
+            None => {
+                self.pids.insert(pid, hash);
+                Qos2Publish::New
+            }
+        }
+    }
+
+    /// Finish the message when the PUBREL received (or the PUBLISH rejected),
+    /// return false if the packet identifier is not found.
+    pub fn release(&mut self, pid: Pid) -> bool {
+        self.pids.remove(&pid).is_some()
+    }
+
+    /// The sorted packet identifiers waiting for the PUBREL
+    pub fn pids(&self) -> Vec<u16> {
+        let mut pids: Vec<_> = self.pids.keys().map(|pid| pid.value()).collect();
+        pids.sort_unstable();
+        pids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        // The packet identifier, the message and the dup flag
+        Publish(u16, u8, bool),
+        Pubrel(u16),
+    }
+
+    fn op_strategy() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (1..6u16, 0..3u8, any::<bool>()).prop_map(|(pid, msg, dup)| Op::Publish(pid, msg, dup)),
+            (1..6u16).prop_map(Op::Pubrel),
+        ]
+    }
+
+    #[test]
+    fn test_incoming_qos2() {
+        let pid = Pid::try_from(1).unwrap();
+        let mut qos2 = IncomingQos2::default();
+        assert_eq!(qos2.publish(pid, 1, false, 1), Qos2Publish::New);
+        assert_eq!(qos2.publish(pid, 1, false, 1), Qos2Publish::DupRequired);
+        assert_eq!(qos2.publish(pid, 1, true, 1), Qos2Publish::Duplicate);
+        assert_eq!(qos2.publish(pid, 2, true, 1), Qos2Publish::PidInUse);
+        let pid2 = Pid::try_from(2).unwrap();
+        assert_eq!(qos2.publish(pid2, 1, false, 1), Qos2Publish::TooMany);
+        assert_eq!(qos2.pids(), vec![1]);
+        assert!(qos2.release(pid));
+        assert!(!qos2.release(pid));
+        assert_eq!(qos2.publish(pid2, 1, false, 1), Qos2Publish::New);
+    }
+
+    proptest! {
+        // Every message is published exactly once until it's released, and
+        // every release finishes exactly one published message.
+        #[test]
+        fn prop_exactly_once(
+            max_inflight in 1..6usize,
+            ops in prop::collection::vec(op_strategy(), 0..100),
+        ) {
+            let mut qos2 = IncomingQos2::default();
+            let mut model: HashMap<u16, u8> = HashMap::new();
+            let mut published = 0;
+            let mut released = 0;
+            for op in ops {
+                match op {
+                    Op::Publish(value, msg, dup) => {
+                        let pid = Pid::try_from(value).unwrap();
+                        let expected = match model.get(&value) {
+                            Some(previous) if *previous != msg => Qos2Publish::PidInUse,
+                            Some(_) if !dup => Qos2Publish::DupRequired,
+                            Some(_) => Qos2Publish::Duplicate,
+                            None if model.len() >= max_inflight => Qos2Publish::TooMany,
+                            None => {
+                                model.insert(value, msg);
+                                published += 1;
+                                Qos2Publish::New
+                            }
+                        };
+                        prop_assert_eq!(qos2.publish(pid, msg as u64, dup, max_inflight), expected);
+                    }
+                    Op::Pubrel(value) => {
+                        let pid = Pid::try_from(value).unwrap();
+                        let expected = model.remove(&value).is_some();
+                        if expected {
+                            released += 1;
+                        }
+                        prop_assert_eq!(qos2.release(pid), expected);
+                    }
+                }
+                prop_assert!(model.len() <= max_inflight);
+                prop_assert_eq!(published - released, model.len());
+                let mut pids: Vec<_> = model.keys().copied().collect();
+                pids.sort_unstable();
+                prop_assert_eq!(qos2.pids(), pids);
+            }
+        }
+    }
+}
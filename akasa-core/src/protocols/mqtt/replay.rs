@@ -0,0 +1,92 @@
+//! Replay a recorded packet trace (see `PacketTracer`, the trace file must be
+//! recorded with `raw_bytes`) against a server as a synthetic client, for
+//! reproducing the bugs reported from the field.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
+
+use futures_lite::future;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::sleep;
+
+use super::trace::{PacketDirection, PacketRecord};
+
+/// Load the records from a trace file, the invalid lines are skipped
+pub fn load_packet_records(path: &Path) -> io::Result<Vec<PacketRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        match PacketRecord::parse(&line) {
+            Some(record) => records.push(record),
+            None => tracing::warn!("invalid packet trace line: {}", line),
+        }
+    }
+    Ok(records)
+}
+
+/// Send the packets received by the server in the records (client to server)
+/// to the connection, with the recorded intervals divided by `speed`. The
+/// data sent by the server is discarded. Return the number of packets sent.
+pub async fn replay_packets<C: AsyncRead + AsyncWrite + Unpin>(
+    conn: C,
+    records: &[PacketRecord],
+    speed: f64,
+) -> io::Result<usize> {
+    let (mut reader, mut writer) = tokio::io::split(conn);
+    let count = future::or(
+        send_packets(&mut writer, records, speed),
+        discard_data(&mut reader),
+    )
+    .await?;
+    writer.shutdown().await?;
+    Ok(count)
+}
+
+async fn send_packets<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    records: &[PacketRecord],
+    speed: f64,
+) -> io::Result<usize> {
+    let mut last_time = None;
+    let mut count = 0;
+    for record in records {
+        if record.direction != PacketDirection::In {
+            continue;
+        }
+        let raw = match record.raw.as_ref() {
+            Some(raw) => raw,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "packet trace recorded without raw bytes",
+                ));
+            }
+        };
+        if let Some(last_time) = last_time {
+            let interval = record.time.saturating_sub(last_time);
+            sleep(Duration::from_secs_f64(interval as f64 / 1000.0 / speed)).await;
+        }
+        last_time = Some(record.time);
+        writer.write_all(raw).await?;
+        writer.flush().await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Never returns successfully, the server should not close the connection
+// before all the packets are sent.
+async fn discard_data<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<usize> {
+    let mut buf = [0u8; 4096];
+    loop {
+        if reader.read(&mut buf).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by the server",
+            ));
+        }
+    }
+}
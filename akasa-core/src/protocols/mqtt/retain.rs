@@ -1,6 +1,8 @@
 use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
+use ahash::RandomState;
 use bytes::Bytes;
 use hashbrown::HashMap;
 use mqtt_proto::{
@@ -10,10 +12,23 @@ use mqtt_proto::{
 use parking_lot::RwLock;
 
 use super::route::split_topic;
+use crate::config::RetainLimitsConfig;
 
-#[derive(Debug, Default)]
+/// The count of the root shards, the first topic level decides the shard.
+const SHARDS: usize = 32;
+
+/// The retained messages stored in a trie of topic levels. The root level is
+/// split into shards and every node has its own lock, so the lookups of
+/// different subscribers (and the updates of different topics) don't contend
+/// on a global lock.
+#[derive(Debug)]
 pub struct RetainTable {
-    inner: RetainNode,
+    hash_builder: RandomState,
+    shards: Vec<RetainNode>,
+    // The count of retained messages
+    count: AtomicUsize,
+    // The total payload bytes of retained messages
+    bytes: AtomicUsize,
 }
 
 #[derive(Debug, Default)]
@@ -41,20 +56,113 @@ impl RetainTable {
             topic_filter.starts_with(|c| c == MATCH_ONE_CHAR || c == MATCH_ALL_CHAR);
         let (filter_item, rest_items) = split_topic(topic_filter);
         let mut retains = Vec::new();
-        self.inner
-            .get_matches(filter_item, rest_items, wildcard_first, &mut retains);
+        if wildcard_first {
+            // Visit the shards one by one, only one shard is locked at a time
+            for shard in &self.shards {
+                shard.get_matches(filter_item, rest_items, wildcard_first, &mut retains);
+            }
+        } else {
+            self.shard(filter_item).get_matches(
+                filter_item,
+                rest_items,
+                wildcard_first,
+                &mut retains,
+            );
+        }
         retains
     }
 
+    fn shard(&self, topic_item: &str) -> &RetainNode {
+        let idx = self.hash_builder.hash_one(topic_item) as usize % self.shards.len();
+        &self.shards[idx]
+    }
+
     pub fn insert(&self, content: Arc<RetainContent>) -> Option<Arc<RetainContent>> {
         let content_clone = Arc::clone(&content);
         let (topic_item, rest_items) = split_topic(&content_clone.topic_name);
-        self.inner.insert(topic_item, rest_items, content)
+        // Counted before inserted, so the counters never underflow
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(content_clone.payload.len(), Ordering::Relaxed);
+        let old_content = self
+            .shard(topic_item)
+            .insert(topic_item, rest_items, content);
+        if let Some(old_content) = old_content.as_ref() {
+            self.uncount(old_content);
+        }
+        old_content
     }
 
     pub fn remove(&self, topic_name: &str) -> Option<Arc<RetainContent>> {
         let (topic_item, rest_items) = split_topic(topic_name);
-        self.inner.remove(topic_item, rest_items)
+        let old_content = self.shard(topic_item).remove(topic_item, rest_items);
+        if let Some(old_content) = old_content.as_ref() {
+            self.uncount(old_content);
+        }
+        old_content
+    }
+
+    fn uncount(&self, content: &RetainContent) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+        self.bytes
+            .fetch_sub(content.payload.len(), Ordering::Relaxed);
+    }
+
+    /// The count of retained messages
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The total payload bytes of retained messages
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Check if the retained message can be stored under the limits, return
+    /// the reason if not.
+    pub fn check_limits(
+        &self,
+        limits: &RetainLimitsConfig,
+        topic_name: &str,
+        payload_len: usize,
+    ) -> Result<(), &'static str> {
+        if limits.max_payload_size > 0 && payload_len > limits.max_payload_size {
+            return Err("retained message payload too large");
+        }
+        // Replace an exists retained message will not increase the count
+        if limits.max_messages > 0
+            && self.len() >= limits.max_messages
+            && !self
+                .get_matches(topic_name)
+                .iter()
+                .any(|content| &*content.topic_name == topic_name)
+        {
+            return Err("too many retained messages");
+        }
+        Ok(())
+    }
+
+    /// Remove all retained messages matched the topic filter, return the removed messages.
+    pub fn purge(&self, topic_filter: &str) -> Vec<Arc<RetainContent>> {
+        self.get_matches(topic_filter)
+            .into_iter()
+            .filter_map(|content| self.remove(&content.topic_name))
+            .collect()
+    }
+}
+
+impl Default for RetainTable {
+    fn default() -> RetainTable {
+        RetainTable {
+            hash_builder: RandomState::new(),
+            shards: (0..SHARDS).map(|_| RetainNode::default()).collect(),
+            count: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        }
     }
 }
 
@@ -429,4 +537,56 @@ mod tests {
             Query("#", vec![]),
         ]);
     }
+
+    #[test]
+    fn test_limits() {
+        let table = RetainTable::default();
+        let limits = RetainLimitsConfig {
+            max_messages: 2,
+            max_payload_size: 4,
+            ..Default::default()
+        };
+        table.insert(Arc::new(("abc", Level0, vec![1, 1], "3").into()));
+        table.insert(Arc::new(("abc/ijk", Level1, vec![2, 2, 2], "4").into()));
+        assert_eq!((table.len(), table.bytes()), (2, 5));
+        assert!(table.check_limits(&limits, "abc/ijk", 4).is_ok());
+        assert!(table.check_limits(&limits, "abc/ijk", 5).is_err());
+        assert!(table.check_limits(&limits, "abc/xyz", 1).is_err());
+
+        table.insert(Arc::new(("abc", Level0, vec![1], "3").into()));
+        assert_eq!((table.len(), table.bytes()), (2, 4));
+        table.remove("abc/ijk");
+        assert_eq!((table.len(), table.bytes()), (1, 1));
+        assert!(table.check_limits(&limits, "abc/xyz", 1).is_ok());
+        table.purge("#");
+        assert!(table.is_empty());
+        assert_eq!(table.bytes(), 0);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let table = Arc::new(RetainTable::default());
+        let threads: Vec<_> = (0..4)
+            .map(|idx| {
+                let table = Arc::clone(&table);
+                std::thread::spawn(move || {
+                    for n in 0..100 {
+                        let topic_name = format!("{}/{}", n, idx);
+                        table.insert(Arc::new((topic_name.as_str(), Level0, vec![1], "1").into()));
+                        assert!(!table.get_matches("#").is_empty());
+                        assert!(!table.get_matches(&format!("+/{}", idx)).is_empty());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(table.len(), 400);
+        assert_eq!(table.get_matches("#").len(), 400);
+        assert_eq!(table.get_matches("+/2").len(), 100);
+        assert_eq!(table.get_matches("42/#").len(), 4);
+        assert_eq!(table.purge("+/+").len(), 400);
+        assert!(table.is_empty());
+    }
 }
@@ -1,25 +1,41 @@
 use std::hash::Hash;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use ahash::RandomState;
+use arc_swap::ArcSwap;
 use hashbrown::HashMap;
-use mqtt_proto::{QoS, TopicFilter, TopicName, LEVEL_SEP, MATCH_ALL_STR, MATCH_ONE_STR};
-use parking_lot::RwLock;
+use mqtt_proto::{
+    QoS, TopicFilter, TopicName, LEVEL_SEP, MATCH_ALL_CHAR, MATCH_ALL_STR, MATCH_ONE_CHAR,
+    MATCH_ONE_STR,
+};
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
 
 use crate::state::ClientId;
 
+/// The subscriptions stored in a trie of topic levels. The publish path
+/// loads current tree without lock, the subscribe/unsubscribe copy the nodes
+/// of the path when the tree is changed (RCU). The children of a node are a
+/// persistent map, so copying a node shares the untouched children and only
+/// copies O(log n) of the map for the changed child. The subscribers of a
+/// node are shared by all versions of the tree and protected by the node's
+/// own lock.
 #[derive(Default)]
 pub struct RouteTable {
-    nodes: RwLock<HashMap<String, RouteNode>>,
+    root: ArcSwap<RouteNode>,
+    // Serialize the writers, so the updates will not be lost
+    write_lock: Mutex<()>,
 }
 
+#[derive(Clone, Default)]
 struct RouteNode {
     content: Arc<RwLock<RouteContent>>,
-    nodes: Arc<RwLock<HashMap<String, RouteNode>>>,
+    nodes: im::HashMap<Arc<str>, Arc<RouteNode>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct RouteContent {
     /// Returned RouteContent always have topic_filter
     pub topic_filter: Option<TopicFilter>,
@@ -27,11 +43,45 @@ pub struct RouteContent {
     pub groups: HashMap<String, SharedClients>,
 }
 
-#[derive(Debug, Clone, Default)]
+/// The structured dump of the route table (see `RouteTable::dump`)
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct RouteTableDump {
+    /// Count of all tree nodes (topic levels)
+    pub nodes: usize,
+    /// Count of the topic filters have subscribers
+    pub filters: usize,
+    /// Count of the topic filters contain wildcards
+    pub wildcard_filters: usize,
+    /// Count of all subscriptions (include the shared subscriptions)
+    pub subscriptions: usize,
+    /// The max depth (levels) of the tree
+    pub max_depth: usize,
+    pub tree: Vec<RouteNodeInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct RouteNodeInfo {
+    pub level: String,
+    /// The topic filter when there are subscribers at this node
+    pub topic_filter: Option<String>,
+    pub subscribers: usize,
+    pub shared_groups: Vec<SharedGroupInfo>,
+    pub children: Vec<RouteNodeInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct SharedGroupInfo {
+    pub group: String,
+    pub subscribers: usize,
+}
+
+#[derive(Debug, Default)]
 pub struct SharedClients {
     hash_builder: RandomState,
     items: Vec<(ClientId, QoS)>,
     index: HashMap<ClientId, usize>,
+    /// The next position of round-robin dispatching
+    next: AtomicUsize,
 }
 
 impl RouteTable {
@@ -39,15 +89,15 @@ impl RouteTable {
         let (topic_item, rest_items) = split_topic(topic_name.deref());
         let mut filters = Vec::new();
 
-        let nodes = self.nodes.read();
-        if let Some(node) = nodes.get(topic_item) {
+        let root = self.root.load();
+        if let Some(node) = root.nodes.get(topic_item) {
             node.get_matches(topic_item, rest_items, &mut filters);
         }
         // [MQTT-4.7.2-1] The Server MUST NOT match Topic Filters starting with a
         // wildcard character (# or +) with Topic Names beginning with a $ character
         if !topic_name.starts_with('$') {
             for item in [MATCH_ALL_STR, MATCH_ONE_STR] {
-                if let Some(node) = nodes.get(item) {
+                if let Some(node) = root.nodes.get(item) {
                     node.get_matches(item, rest_items, &mut filters);
                 }
             }
@@ -74,13 +124,19 @@ impl RouteTable {
         qos: QoS,
         group: Option<String>,
     ) {
-        let (filter_item, rest_items) = split_topic(topic_filter.deref());
-        // Since subscribe is not an frequent action, string clone here is acceptable.
-        self.nodes
-            .write()
-            .entry(filter_item.to_string())
-            .or_insert_with(RouteNode::new)
-            .insert(topic_filter, rest_items, id, qos, group);
+        let _guard = self.write_lock.lock();
+        let root = self.root.load_full();
+        let content = match root.find(topic_filter) {
+            Some(node) => Arc::clone(&node.content),
+            None => {
+                // Copy the path, the children maps are shared with current tree
+                let mut new_root = RouteNode::clone(&root);
+                let content = new_root.insert_path(Some(topic_filter.deref()));
+                self.root.store(Arc::new(new_root));
+                content
+            }
+        };
+        content.write().insert(topic_filter, id, qos, group);
     }
 
     pub fn unsubscribe(&self, topic_filter: &TopicFilter, id: ClientId) {
@@ -94,29 +150,75 @@ impl RouteTable {
             self.unsubscribe_shared(topic_filter, id, None);
         }
     }
-    fn unsubscribe_shared(&self, topic_filter: &TopicFilter, id: ClientId, group: Option<&str>) {
-        let (filter_item, rest_items) = split_topic(topic_filter.deref());
-        // bool variable is for resolve dead lock of access `self.nodes`
-        let mut remove_node = false;
-        let mut nodes = self.nodes.write();
-        if let Some(node) = nodes.get_mut(filter_item) {
-            remove_node = node.remove(rest_items, id, group);
+    /// Dump the tree with subscriber counts, the children are sorted by level.
+    ///
+    /// The tree is a consistent snapshot, but the subscribers of the nodes
+    /// are read one by one.
+    pub fn dump(&self) -> RouteTableDump {
+        let root = self.root.load_full();
+        let tree: Vec<_> = sorted_nodes(&root.nodes)
+            .into_iter()
+            .map(|(level, node)| node.dump(level))
+            .collect();
+        let mut dump = RouteTableDump::default();
+        for info in &tree {
+            info.summarize(1, &mut dump);
         }
+        dump.tree = tree;
+        dump
+    }
+
+    fn unsubscribe_shared(&self, topic_filter: &TopicFilter, id: ClientId, group: Option<&str>) {
+        let _guard = self.write_lock.lock();
+        let root = self.root.load_full();
+        let node = match root.find(topic_filter) {
+            Some(node) => node,
+            None => return,
+        };
+        let remove_node = {
+            let mut content = node.content.write();
+            content.remove(&id, group);
+            content.is_empty() && node.nodes.is_empty()
+        };
         if remove_node {
-            nodes.remove(filter_item);
+            let mut new_root = RouteNode::clone(&root);
+            new_root.remove_path(Some(topic_filter.deref()));
+            self.root.store(Arc::new(new_root));
         }
     }
 }
 
 impl RouteNode {
-    fn new() -> RouteNode {
-        RouteNode {
-            content: Arc::new(RwLock::new(RouteContent {
-                topic_filter: None,
-                clients: HashMap::new(),
-                groups: HashMap::new(),
-            })),
-            nodes: Arc::new(RwLock::new(HashMap::new())),
+    fn dump(&self, level: String) -> RouteNodeInfo {
+        let (topic_filter, subscribers, shared_groups) = {
+            let content = self.content.read();
+            let mut shared_groups: Vec<_> = content
+                .groups
+                .iter()
+                .map(|(group, shared_clients)| SharedGroupInfo {
+                    group: group.clone(),
+                    subscribers: shared_clients.items.len(),
+                })
+                .collect();
+            shared_groups.sort_by(|a, b| a.group.cmp(&b.group));
+            (
+                content
+                    .topic_filter
+                    .as_ref()
+                    .map(|filter| filter.to_string()),
+                content.clients.len(),
+                shared_groups,
+            )
+        };
+        RouteNodeInfo {
+            level,
+            topic_filter,
+            subscribers,
+            shared_groups,
+            children: sorted_nodes(&self.nodes)
+                .into_iter()
+                .map(|(level, node)| node.dump(level))
+                .collect(),
         }
     }
 
@@ -131,10 +233,9 @@ impl RouteNode {
                 filters.push(Arc::clone(&self.content));
             }
         } else if let Some(topic_items) = topic_items {
-            let nodes = self.nodes.read();
             let (topic_item, rest_items) = split_topic(topic_items);
             for item in [topic_item, MATCH_ALL_STR, MATCH_ONE_STR] {
-                if let Some(node) = nodes.get(item) {
+                if let Some(node) = self.nodes.get(item) {
                     node.get_matches(item, rest_items, filters);
                 }
             }
@@ -144,10 +245,7 @@ impl RouteNode {
             }
 
             // Topic name "abc" will match topic filter "abc/#", since "#" also represent parent level.
-            // NOTE: [locks]
-            //   * nodes read
-            //   * content read
-            if let Some(node) = self.nodes.read().get(MATCH_ALL_STR) {
+            if let Some(node) = self.nodes.get(MATCH_ALL_STR) {
                 if !node.content.read().is_empty() {
                     filters.push(Arc::clone(&node.content));
                 }
@@ -155,81 +253,78 @@ impl RouteNode {
         }
     }
 
-    fn insert(
-        &self,
-        topic_filter: &TopicFilter,
-        filter_items: Option<&str>,
-        id: ClientId,
-        qos: QoS,
-        group: Option<String>,
-    ) {
+    fn find(&self, topic_filter: &str) -> Option<&RouteNode> {
+        let mut node = self;
+        for item in topic_filter.split(LEVEL_SEP) {
+            node = node.nodes.get(item)?;
+        }
+        Some(node)
+    }
+
+    // Copy the nodes of the path (the content is shared), create the missing
+    // nodes, return the content of the last node.
+    fn insert_path(&mut self, filter_items: Option<&str>) -> Arc<RwLock<RouteContent>> {
         if let Some(filter_items) = filter_items {
             let (filter_item, rest_items) = split_topic(filter_items);
-            self.nodes
-                .write()
-                .entry(filter_item.to_string())
-                .or_insert_with(RouteNode::new)
-                .insert(topic_filter, rest_items, id, qos, group);
+            let node = self.nodes.entry(Arc::from(filter_item)).or_default();
+            Arc::make_mut(node).insert_path(rest_items)
         } else {
-            let mut content = self.content.write();
-            if content.topic_filter.is_none() {
-                content.topic_filter = Some(topic_filter.clone());
-            }
-            if let Some(name) = group {
-                content
-                    .groups
-                    .entry(name)
-                    .or_insert_with(SharedClients::default)
-                    .insert((id, qos));
-            } else {
-                content.clients.insert(id, qos);
-            }
+            Arc::clone(&self.content)
         }
     }
 
-    fn remove(&self, filter_items: Option<&str>, id: ClientId, group: Option<&str>) -> bool {
+    // Copy the nodes of the path and remove the empty nodes, return if
+    // current node is empty.
+    fn remove_path(&mut self, filter_items: Option<&str>) -> bool {
         if let Some(filter_items) = filter_items {
             let (filter_item, rest_items) = split_topic(filter_items);
-            // bool variables are for resolve dead lock of access `self.nodes`
-
-            // NOTE: [locks]
-            //   * nodes write
-            //   * content read
-            let mut nodes = self.nodes.write();
-            let mut remove_node = false;
-            if let Some(node) = nodes.get_mut(filter_item) {
-                if node.remove(rest_items, id, group) {
-                    remove_node = true;
+            if let Some(node) = self.nodes.get_mut(filter_item) {
+                if Arc::make_mut(node).remove_path(rest_items) {
+                    self.nodes.remove(filter_item);
                 }
             }
-            let remove_parent = if remove_node {
-                nodes.remove(filter_item);
-                // NOTE: careful lock order
-                self.content.read().is_empty() && nodes.is_empty()
-            } else {
-                false
-            };
-            return remove_parent;
-        } else {
-            let mut content = self.content.write();
-            if let Some(name) = group {
-                if let Some(shared_clients) = content.groups.get_mut(name) {
-                    shared_clients.remove(&id);
-                    if shared_clients.is_empty() {
-                        content.groups.remove(name);
-                    }
-                }
-            } else {
-                content.clients.remove(&id);
-            }
-            if content.is_empty() {
-                content.topic_filter = None;
-                if self.nodes.read().is_empty() {
-                    return true;
-                }
+        }
+        self.nodes.is_empty() && self.content.read().is_empty()
+    }
+}
+
+impl RouteNodeInfo {
+    fn summarize(&self, depth: usize, dump: &mut RouteTableDump) {
+        dump.nodes += 1;
+        dump.max_depth = dump.max_depth.max(depth);
+        if let Some(topic_filter) = self.topic_filter.as_ref() {
+            dump.filters += 1;
+            if topic_filter.contains([MATCH_ALL_CHAR, MATCH_ONE_CHAR]) {
+                dump.wildcard_filters += 1;
             }
         }
-        false
+        dump.subscriptions += self.subscribers;
+        for group in &self.shared_groups {
+            dump.subscriptions += group.subscribers;
+        }
+        for child in &self.children {
+            child.summarize(depth + 1, dump);
+        }
+    }
+}
+
+fn sorted_nodes(nodes: &im::HashMap<Arc<str>, Arc<RouteNode>>) -> Vec<(String, &RouteNode)> {
+    let mut nodes: Vec<_> = nodes
+        .iter()
+        .map(|(level, node)| (level.to_string(), node.as_ref()))
+        .collect();
+    nodes.sort_by(|a, b| a.0.cmp(&b.0));
+    nodes
+}
+
+impl Clone for SharedClients {
+    fn clone(&self) -> SharedClients {
+        SharedClients {
+            hash_builder: self.hash_builder.clone(),
+            items: self.items.clone(),
+            index: self.index.clone(),
+            next: AtomicUsize::new(self.next.load(Ordering::Relaxed)),
+        }
     }
 }
 
@@ -237,9 +332,50 @@ impl RouteContent {
     pub fn is_empty(&self) -> bool {
         self.clients.is_empty() && self.groups.is_empty()
     }
+
+    fn insert(
+        &mut self,
+        topic_filter: &TopicFilter,
+        id: ClientId,
+        qos: QoS,
+        group: Option<String>,
+    ) {
+        if self.topic_filter.is_none() {
+            self.topic_filter = Some(topic_filter.clone());
+        }
+        if let Some(name) = group {
+            self.groups
+                .entry(name)
+                .or_insert_with(SharedClients::default)
+                .insert((id, qos));
+        } else {
+            self.clients.insert(id, qos);
+        }
+    }
+
+    fn remove(&mut self, id: &ClientId, group: Option<&str>) {
+        if let Some(name) = group {
+            if let Some(shared_clients) = self.groups.get_mut(name) {
+                shared_clients.remove(id);
+                if shared_clients.is_empty() {
+                    self.groups.remove(name);
+                }
+            }
+        } else {
+            self.clients.remove(id);
+        }
+        if self.is_empty() {
+            self.topic_filter = None;
+        }
+    }
 }
 
 impl SharedClients {
+    /// The clients of the shared group with the subscription QoS
+    pub fn clients(&self) -> &[(ClientId, QoS)] {
+        &self.items
+    }
+
     pub fn get_by_hash<T: Hash>(&self, data: T) -> (ClientId, QoS) {
         let number = self.hash_builder.hash_one(data);
         self.get_by_number(number)
@@ -252,6 +388,23 @@ impl SharedClients {
         self.items[idx]
     }
 
+    pub fn get_by_round_robin(&self) -> (ClientId, QoS) {
+        let number = self.next.fetch_add(1, Ordering::Relaxed);
+        self.get_by_number(number as u64)
+    }
+
+    /// Get the client with the least pending messages, the ties are broken
+    /// in round-robin order.
+    pub fn get_by_least_pending<F: Fn(ClientId) -> usize>(&self, pending: F) -> (ClientId, QoS) {
+        debug_assert!(!self.items.is_empty());
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.items.len();
+        (0..len)
+            .map(|offset| self.items[(start + offset) % len])
+            .min_by_key(|(client_id, _)| pending(*client_id))
+            .expect("shared items")
+    }
+
     fn is_empty(&self) -> bool {
         self.items.is_empty()
     }
@@ -429,5 +582,119 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_dump() {
+        let table = RouteTable::default();
+        for (filter, id) in [
+            ("a/b", 1),
+            ("a/b", 2),
+            ("a/+", 1),
+            ("$share/g1/a/b", 3),
+            ("$share/g1/a/b", 4),
+            ("$share/g2/a/b", 5),
+            ("c", 1),
+            ("c/d/#", 2),
+        ] {
+            table.subscribe(
+                &TopicFilter::try_from(filter.to_owned()).unwrap(),
+                ClientId::new(id),
+                QoS::Level0,
+            );
+        }
+        let dump = table.dump();
+        assert_eq!(dump.nodes, 6);
+        assert_eq!(dump.filters, 4);
+        assert_eq!(dump.wildcard_filters, 2);
+        assert_eq!(dump.subscriptions, 8);
+        assert_eq!(dump.max_depth, 3);
+        let levels: Vec<_> = dump.tree.iter().map(|info| info.level.as_str()).collect();
+        assert_eq!(levels, vec!["a", "c"]);
+        let node_ab = &dump.tree[0].children[1];
+        assert_eq!(node_ab.topic_filter.as_deref(), Some("a/b"));
+        assert_eq!(node_ab.subscribers, 2);
+        assert_eq!(
+            node_ab.shared_groups,
+            vec![
+                SharedGroupInfo {
+                    group: "g1".to_owned(),
+                    subscribers: 2,
+                },
+                SharedGroupInfo {
+                    group: "g2".to_owned(),
+                    subscribers: 1,
+                },
+            ]
+        );
+
+        table.unsubscribe(
+            &TopicFilter::try_from("c/d/#".to_owned()).unwrap(),
+            ClientId::new(2),
+        );
+        let dump = table.dump();
+        assert_eq!(dump.nodes, 4);
+        assert_eq!(dump.max_depth, 2);
+    }
+
+    #[test]
+    fn test_wide_level_snapshot() {
+        let table = RouteTable::default();
+        let subscribe = |filter: String, id: u64| {
+            table.subscribe(
+                &TopicFilter::try_from(filter).unwrap(),
+                ClientId::new(id),
+                QoS::Level0,
+            );
+        };
+        for idx in 0..1000 {
+            subscribe(format!("wide/{idx}"), idx);
+        }
+        let snapshot = table.root.load_full();
+        subscribe("wide/new".to_owned(), 1000);
+        table.unsubscribe(
+            &TopicFilter::try_from("wide/0".to_owned()).unwrap(),
+            ClientId::new(0),
+        );
+
+        // The old version of the tree is not changed
+        let wide = snapshot.find("wide").unwrap();
+        assert_eq!(wide.nodes.len(), 1000);
+        assert!(wide.nodes.get("new").is_none());
+        let wide = table.root.load().find("wide").unwrap().nodes.len();
+        assert_eq!(wide, 1000);
+        let matches = table.get_matches(&TopicName::try_from("wide/new".to_owned()).unwrap());
+        assert_eq!(matches.len(), 1);
+    }
+
+    #[test]
+    fn test_shared_clients_dispatch() {
+        let mut shared_clients = SharedClients::default();
+        for id in 1..=3 {
+            shared_clients.insert((ClientId::new(id), QoS::Level0));
+        }
+        let round_robin: Vec<_> = (0..6)
+            .map(|_| shared_clients.get_by_round_robin().0)
+            .collect();
+        assert_eq!(round_robin[0..3], round_robin[3..6]);
+        assert_ne!(round_robin[0], round_robin[1]);
+        assert_ne!(round_robin[1], round_robin[2]);
+
+        // Client 2 has the least pending messages
+        let pending = |client_id: ClientId| if client_id == ClientId::new(2) { 1 } else { 5 };
+        for _ in 0..3 {
+            assert_eq!(
+                shared_clients.get_by_least_pending(pending).0,
+                ClientId::new(2)
+            );
+        }
+        // The ties are broken in round-robin order
+        let pending = |_client_id: ClientId| 0;
+        let least_pending: Vec<_> = (0..3)
+            .map(|_| shared_clients.get_by_least_pending(pending).0)
+            .collect();
+        assert_ne!(least_pending[0], least_pending[1]);
+        assert_ne!(least_pending[1], least_pending[2]);
+        assert_ne!(least_pending[0], least_pending[2]);
+    }
+
     // FIXME: add shared subscription tests
 }
@@ -0,0 +1,203 @@
+//! Limit the bytes rate of a client connection (see `Config.throttle`)
+
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+use crate::config::ThrottleConfig;
+
+/// The connection paced by the token buckets of both directions
+pub(crate) struct Throttled<C> {
+    inner: C,
+    read: Option<TokenBucket>,
+    write: Option<TokenBucket>,
+}
+
+struct TokenBucket {
+    // The bytes per second
+    rate: u64,
+    // The max tokens accumulated when idle
+    burst: u64,
+    // The available bytes, negative when the last read exceeded
+    tokens: f64,
+    updated_at: Instant,
+    // Wake up the connection when the tokens are available again
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<C> Throttled<C> {
+    pub(crate) fn new(inner: C, config: &ThrottleConfig) -> Throttled<C> {
+        Throttled {
+            inner,
+            read: TokenBucket::new(config.inbound_bytes_per_sec, config.burst_bytes),
+            write: TokenBucket::new(config.outbound_bytes_per_sec, config.burst_bytes),
+        }
+    }
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: u64) -> Option<TokenBucket> {
+        if rate == 0 {
+            return None;
+        }
+        let burst = if burst == 0 { rate } else { burst };
+        Some(TokenBucket {
+            rate,
+            burst,
+            tokens: burst as f64,
+            updated_at: Instant::now(),
+            delay: None,
+        })
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.burst as f64);
+        self.updated_at = now;
+    }
+
+    /// Ready with the available bytes, when `wanted` bytes (at most the
+    /// burst, at least 1) are available, so the data is not written in tiny
+    /// pieces.
+    fn poll_ready(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        let wanted = (wanted as u64).clamp(1, self.burst) as f64;
+        loop {
+            self.refill();
+            if self.tokens >= wanted {
+                self.delay = None;
+                return Poll::Ready(self.tokens as usize);
+            }
+            let wait = Duration::from_secs_f64((wanted - self.tokens) / self.rate as f64);
+            let delay = self.delay.get_or_insert_with(|| Box::pin(sleep(wait)));
+            delay.as_mut().reset(tokio::time::Instant::now() + wait);
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Throttled<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(bucket) = this.read.as_mut() {
+            if bucket.poll_ready(cx, 1).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        // The bytes more than the available tokens are charged to the next
+        // read, so the read buffer is not limited.
+        let old_len = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Some(bucket) = this.read.as_mut() {
+            bucket.consume(buf.filled().len() - old_len);
+        }
+        result
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Throttled<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let len = match this.write.as_mut() {
+            Some(bucket) => match bucket.poll_ready(cx, buf.len()) {
+                Poll::Ready(available) => available.min(buf.len()),
+                Poll::Pending => return Poll::Pending,
+            },
+            None => buf.len(),
+        };
+        let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..len]);
+        if let (Some(bucket), Poll::Ready(Ok(size))) = (this.write.as_mut(), &result) {
+            bucket.consume(*size);
+        }
+        result
+    }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        // Not vectored when limited (see `is_write_vectored`)
+        if self.write.is_some() {
+            let buf = bufs
+                .iter()
+                .find(|buf| !buf.is_empty())
+                .map_or(&[][..], |buf| &**buf);
+            return self.poll_write(cx, buf);
+        }
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+    fn is_write_vectored(&self) -> bool {
+        self.write.is_none() && self.inner.is_write_vectored()
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn config(inbound: u64, outbound: u64) -> ThrottleConfig {
+        ThrottleConfig {
+            inbound_bytes_per_sec: inbound,
+            outbound_bytes_per_sec: outbound,
+            burst_bytes: 100,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_throttled_write() {
+        let mut conn = Throttled::new(tokio::io::sink(), &config(0, 1000));
+        let start = Instant::now();
+        conn.write_all(&[0; 300]).await.unwrap();
+        // 100 bytes of the burst, then 100 bytes every 0.1 seconds
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(500), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn test_throttled_read() {
+        let data = [1u8; 300];
+        let mut conn = Throttled::new(&data[..], &config(1000, 0));
+        let start = Instant::now();
+        let mut received = Vec::new();
+        conn.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        // The 200 bytes more than the burst are waited by the next read
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(500), "{elapsed:?}");
+
+        let mut conn = Throttled::new(&data[..], &config(0, 0));
+        let start = Instant::now();
+        conn.read_to_end(&mut Vec::new()).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+}
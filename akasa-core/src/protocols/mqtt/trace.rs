@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Debug};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use flume::Sender;
+use parking_lot::Mutex;
+
+use super::online_loop::MqttPacket;
+
+/// Max length of the decoded packet summary
+const MAX_SUMMARY_LEN: usize = 1024;
+
+/// Record the packets to/from specific clients (by MQTT client identifier),
+/// for debugging misbehaving devices.
+#[derive(Default)]
+pub struct PacketTracer {
+    // Fast path: skip the map lookup when no trace is active
+    active: AtomicUsize,
+    traces: DashMap<String, Arc<PacketTrace>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketTraceOptions {
+    /// Max records keep in memory (the oldest record will be dropped)
+    pub capacity: usize,
+    /// Record the encoded packet bytes
+    pub raw_bytes: bool,
+    /// Also append the records to this file
+    pub file: Option<PathBuf>,
+}
+
+impl Default for PacketTraceOptions {
+    fn default() -> PacketTraceOptions {
+        PacketTraceOptions {
+            capacity: 1024,
+            raw_bytes: false,
+            file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// Client to server
+    In,
+    /// Server to client
+    Out,
+}
+
+#[derive(Debug, Clone)]
+pub struct PacketRecord {
+    /// Unix timestamp in milliseconds
+    pub time: u64,
+    pub direction: PacketDirection,
+    /// The decoded packet (Debug format)
+    pub summary: String,
+    pub raw: Option<Vec<u8>>,
+}
+
+struct PacketTrace {
+    options: PacketTraceOptions,
+    records: Mutex<VecDeque<PacketRecord>>,
+    // Taken when the trace stopped
+    file: Mutex<Option<TraceWriter>>,
+}
+
+// Append the records to the trace file in a dedicated thread, so the
+// connection tasks never wait for the file.
+struct TraceWriter {
+    lines: Sender<String>,
+    thread: JoinHandle<()>,
+}
+
+impl TraceWriter {
+    fn spawn(file: File) -> io::Result<TraceWriter> {
+        let (lines, receiver) = flume::unbounded::<String>();
+        let thread = thread::Builder::new()
+            .name("packet-trace".to_owned())
+            .spawn(move || {
+                let mut file = BufWriter::new(file);
+                while let Ok(line) = receiver.recv() {
+                    let mut result = writeln!(file, "{line}");
+                    // Flush when all the queued records are written
+                    if result.is_ok() && receiver.is_empty() {
+                        result = file.flush();
+                    }
+                    if let Err(err) = result {
+                        tracing::warn!("write packet trace file error: {}", err);
+                    }
+                }
+                if let Err(err) = file.flush() {
+                    tracing::warn!("flush packet trace file error: {}", err);
+                }
+            })?;
+        Ok(TraceWriter { lines, thread })
+    }
+
+    // Wait for the queued records written
+    fn close(self) {
+        drop(self.lines);
+        if self.thread.join().is_err() {
+            tracing::error!("packet trace writer thread panicked");
+        }
+    }
+}
+
+impl PacketTracer {
+    /// Start (or restart) tracing the client
+    pub fn start(&self, client_identifier: &str, options: PacketTraceOptions) -> io::Result<()> {
+        let file = options
+            .file
+            .as_ref()
+            .map(|path| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(TraceWriter::spawn)
+            })
+            .transpose()?;
+        let trace = Arc::new(PacketTrace {
+            records: Mutex::new(VecDeque::with_capacity(options.capacity.min(1024))),
+            options,
+            file: Mutex::new(file),
+        });
+        match self.traces.insert(client_identifier.to_owned(), trace) {
+            Some(old_trace) => old_trace.close(),
+            None => {
+                self.active.fetch_add(1, Ordering::AcqRel);
+            }
+        }
+        tracing::info!("start packet trace for client: {}", client_identifier);
+        Ok(())
+    }
+
+    /// Stop tracing the client, return the records in memory
+    pub fn stop(&self, client_identifier: &str) -> Option<Vec<PacketRecord>> {
+        let (_, trace) = self.traces.remove(client_identifier)?;
+        self.active.fetch_sub(1, Ordering::AcqRel);
+        tracing::info!("stop packet trace for client: {}", client_identifier);
+        trace.close();
+        let records = trace.records.lock().iter().cloned().collect();
+        Some(records)
+    }
+
+    /// Current records in memory of the client
+    pub fn records(&self, client_identifier: &str) -> Option<Vec<PacketRecord>> {
+        self.traces
+            .get(client_identifier)
+            .map(|trace| trace.records.lock().iter().cloned().collect())
+    }
+
+    /// All traced client identifiers
+    pub fn clients(&self) -> Vec<String> {
+        self.traces.iter().map(|item| item.key().clone()).collect()
+    }
+
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire) > 0
+    }
+
+    #[inline]
+    pub(crate) fn record<P: MqttPacket + Debug>(
+        &self,
+        client_identifier: &str,
+        direction: PacketDirection,
+        packet: &P,
+    ) {
+        if !self.is_active() {
+            return;
+        }
+        let trace = match self.traces.get(client_identifier) {
+            Some(trace) => Arc::clone(trace.value()),
+            None => return,
+        };
+        let mut summary = format!("{packet:?}");
+        if summary.len() > MAX_SUMMARY_LEN {
+            let mut end = MAX_SUMMARY_LEN;
+            while !summary.is_char_boundary(end) {
+                end -= 1;
+            }
+            summary.truncate(end);
+            summary.push_str("...");
+        }
+        let raw = if trace.options.raw_bytes {
+            packet.encode().ok().map(|data| data.as_ref().to_vec())
+        } else {
+            None
+        };
+        let record = PacketRecord {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            direction,
+            summary,
+            raw,
+        };
+        if let Some(file) = trace.file.lock().as_ref() {
+            let _ = file.lines.send(record.to_string());
+        }
+        if trace.options.capacity > 0 {
+            let mut records = trace.records.lock();
+            if records.len() >= trace.options.capacity {
+                records.pop_front();
+            }
+            records.push_back(record);
+        }
+    }
+}
+
+impl PacketTrace {
+    fn close(&self) {
+        let file = self.file.lock().take();
+        if let Some(file) = file {
+            file.close();
+        }
+    }
+}
+
+impl PacketRecord {
+    /// Parse a line of the trace file (see the `Display` implementation)
+    pub fn parse(line: &str) -> Option<PacketRecord> {
+        let (time, rest) = line.split_once(' ')?;
+        let (direction, summary) = rest.split_once(' ')?;
+        let direction = match direction {
+            "<-" => PacketDirection::In,
+            "->" => PacketDirection::Out,
+            _ => return None,
+        };
+        let (summary, raw) = match summary
+            .strip_suffix(']')
+            .and_then(|value| value.rsplit_once(" ["))
+            .and_then(|(head, data)| Some((head, hex::decode(data).ok()?)))
+        {
+            Some((head, raw)) => (head, Some(raw)),
+            None => (summary, None),
+        };
+        Some(PacketRecord {
+            time: time.parse().ok()?,
+            direction,
+            summary: summary.to_owned(),
+            raw,
+        })
+    }
+}
+
+impl fmt::Display for PacketRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            PacketDirection::In => "<-",
+            PacketDirection::Out => "->",
+        };
+        write!(f, "{} {} {}", self.time, direction, self.summary)?;
+        if let Some(raw) = self.raw.as_ref() {
+            write!(f, " [{}]", hex::encode(raw))?;
+        }
+        Ok(())
+    }
+}
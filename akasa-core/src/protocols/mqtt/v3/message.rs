@@ -3,6 +3,7 @@ use std::io;
 use std::mem::{self, MaybeUninit};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 
 use flume::{Receiver, Sender};
 use futures_lite::FutureExt;
@@ -15,14 +16,22 @@ use mqtt_proto::{
     Error, Pid, Protocol, QoS, QosPid,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{Instrument, Span};
 
+use crate::audit::AuditEvent;
+use crate::config::RetransmitConfig;
+use crate::diagnostics;
+use crate::error::Error as AkasaError;
+use crate::events::ClientEvent;
 use crate::hook::{
-    handle_request, Hook, HookAction, HookRequest, HookResponse, LockedHookContext, PublishAction,
-    SubscribeAction, UnsubscribeAction,
+    handle_request, Hook, HookAction, HookPublishCode, HookRequest, HookResponse,
+    LockedHookContext, PublishAction, SubscribeAction, UnsubscribeAction,
 };
 use crate::protocols::mqtt::{
-    BroadcastPackets, OnlineLoop, OnlineSession, PendingPackets, WritePacket,
+    auto_subscriptions, get_unix_ts, BroadcastPackets, IncomingQos2, OnlineLoop, OnlineSession,
+    PacketDirection, PendingPackets, WritePacket,
 };
+use crate::sparkplug;
 use crate::state::{ClientId, ClientReceiver, ControlMessage, GlobalState, NormalMessage};
 
 use super::{
@@ -47,7 +56,10 @@ pub async fn handle_connection<
     peer: SocketAddr,
     header: Header,
     protocol: Protocol,
+    bridge: bool,
     timeout_receiver: Receiver<()>,
+    listener_max_qos: Option<QoS>,
+    listener_keep_alive: Option<u16>,
     hook_handler: H,
     global: Arc<GlobalState>,
 ) -> io::Result<()> {
@@ -56,24 +68,27 @@ pub async fn handle_connection<
         peer,
         header,
         protocol,
+        bridge,
         timeout_receiver,
+        listener_max_qos,
+        listener_keep_alive,
         &hook_handler,
         &global,
     )
     .await
     {
         Ok(Some((session, receiver))) => {
-            log::info!(
+            tracing::info!(
                 "{}({}) go to offline, total {} clients ({} online)",
                 session.client_id,
                 peer,
                 global.clients_count(),
                 global.online_clients_count(),
             );
-            tokio::spawn(handle_offline(session, receiver, global));
+            tokio::spawn(handle_offline(session, receiver, global).in_current_span());
         }
         Ok(None) => {
-            log::info!(
+            tracing::info!(
                 "{} finished, total {} clients ({} online)",
                 peer,
                 global.clients_count(),
@@ -81,7 +96,7 @@ pub async fn handle_connection<
             );
         }
         Err(err) => {
-            log::info!(
+            tracing::info!(
                 "{} error: {}, total {} clients ({} online)",
                 peer,
                 err,
@@ -103,15 +118,21 @@ async fn handle_online<
     peer: SocketAddr,
     _header: Header,
     protocol: Protocol,
+    bridge: bool,
     timeout_receiver: Receiver<()>,
+    listener_max_qos: Option<QoS>,
+    listener_keep_alive: Option<u16>,
     hook_handler: &H,
     global: &Arc<GlobalState>,
 ) -> io::Result<Option<(Session, ClientReceiver)>> {
-    let mut session = Session::new(&global.config, peer);
+    let mut session = Session::new(&global.config(), peer);
+    session.listener_max_qos = listener_max_qos;
+    session.listener_keep_alive = listener_keep_alive;
+    session.bridge = bridge;
     let mut receiver = None;
 
     let timeout = async {
-        log::info!("connection timeout: {}", peer);
+        tracing::info!("connection timeout: {}", peer);
         let _ = timeout_receiver.recv_async().await;
         Err(Error::IoError(io::ErrorKind::TimedOut, String::new()))
     };
@@ -121,41 +142,101 @@ async fn handle_online<
     {
         Ok(packet) => packet,
         Err(err) => {
-            log::debug!("mqtt v3.x connect codec error: {}", err);
-            return Err(io::ErrorKind::InvalidData.into());
+            tracing::debug!("mqtt v3.x connect codec error: {}", err);
+            let err = AkasaError::MalformedPacket(err.to_string()).into();
+            diagnostics::record_error(global, None, peer, format!("{:?}", protocol), &err);
+            return Err(err);
         }
     };
     drop(timeout_receiver);
+    if global.packet_tracer.is_active() {
+        let connect = Packet::Connect(packet.clone());
+        global
+            .packet_tracer
+            .record(&packet.client_id, PacketDirection::In, &connect);
+    }
+    let client_identifier = Arc::clone(&packet.client_id);
+    let username = packet.username.clone();
 
     // Run before connect hook
-    if global.config.hook.enable_before_connect {
-        before_connect_hook(peer, &packet, hook_handler, global).await?;
+    if global.config().hook.enable_before_connect {
+        if let Err(err) =
+            before_connect_hook(&mut session, peer, &packet, hook_handler, global).await
+        {
+            audit_connect(&session, protocol, &client_identifier, &username, global);
+            return Err(err);
+        }
     }
 
-    let session_present =
-        handle_connect(&mut session, &mut receiver, packet, &mut conn, global).await?;
+    let session_present = handle_connect(&mut session, &mut receiver, packet, &mut conn, global)
+        .await
+        .map_err(|err| {
+            let protocol = format!("{:?}", protocol);
+            diagnostics::record_error(
+                global,
+                Some(client_identifier.as_str()),
+                peer,
+                protocol,
+                &err,
+            );
+            err
+        })?;
+    Span::current().record("client_id", session.client_identifier.as_str());
 
     if !session.connected {
-        log::info!("{} not connected", session.peer);
-        return Err(io::ErrorKind::InvalidData.into());
+        tracing::info!("{} not connected", session.peer);
+        audit_connect(&session, protocol, &client_identifier, &username, global);
+        let code = session.connect_error.map(|code| format!("{:?}", code));
+        return Err(AkasaError::ConnectRejected(code.unwrap_or_default()).into());
     }
 
     // Run after connect hook
-    if global.config.hook.enable_before_connect {
+    if global.config().hook.enable_before_connect {
         after_connect_hook(&mut session, session_present, hook_handler, global).await?;
     }
+    let topics = auto_subscriptions(
+        &global.config(),
+        &session.client_identifier,
+        session.username.as_ref().map(|name| name.as_str()),
+        &session.attributes,
+    );
+    if !topics.is_empty() {
+        session.apply_action(HookAction::Subscribe(SubscribeAction(topics)), global)?;
+    }
 
     for packet in after_handle_packet(&mut session) {
-        write_packet(session.client_id, &mut conn, &packet).await?;
+        write_packet(
+            session.client_id,
+            &session.client_identifier,
+            &mut conn,
+            &packet,
+            global,
+        )
+        .await?;
     }
 
     let receiver = receiver.expect("receiver");
-    log::info!(
+    tracing::info!(
         "{} connected, total {} clients ({} online) ",
         session.peer,
         global.clients_count(),
         global.online_clients_count(),
     );
+    audit_connect(
+        &session,
+        protocol,
+        &session.client_identifier,
+        &session.username,
+        global,
+    );
+    global.emit_event(ClientEvent::Connected {
+        client_identifier: session.client_identifier.to_string(),
+        username: session.username.as_ref().map(|name| name.to_string()),
+        peer: session.peer,
+        protocol: format!("{:?}", protocol),
+        clean_start: session.clean_session,
+        keep_alive: session.keep_alive,
+    });
 
     let mut taken_over = false;
     let online_loop = OnlineLoop::new(
@@ -170,7 +251,12 @@ async fn handle_online<
         PollPacketState::default(),
     );
     let io_error = online_loop.await;
-    if global.config.hook.enable_after_disconnect {
+    // Already cancelled by the online loop when taken over
+    if !taken_over {
+        global.inbound_limiter.cancel(session.client_id);
+    }
+    record_disconnect(&session, taken_over, io_error.as_ref(), global);
+    if global.config().hook.enable_after_disconnect {
         after_disconnect_hook(&mut session, taken_over, hook_handler, global).await?;
     }
     if taken_over {
@@ -179,26 +265,16 @@ async fn handle_online<
 
     // FIXME: check all place depend on session.disconnected
     if !session.disconnected {
-        log::debug!("[{}] handling will...", session.client_id);
-        handle_will(&mut session, global).await?;
-    }
-    for (target_id, info) in session.broadcast_packets.drain() {
-        for msg in info.msgs {
-            if let Err(err) = info
-                .sink
-                .sender()
-                .send_async((session.client_id, msg))
-                .await
-            {
-                log::warn!(
-                    "[{}] handle will, send broadcast message to {} failed: {:?}",
-                    session.client_id,
-                    target_id,
-                    err
-                )
-            }
+        tracing::debug!("[{}] handling will...", session.client_id);
+        if global.config().hook.enable_publish && session.last_will.is_some() {
+            before_will_hook(&mut session, hook_handler, global).await;
         }
+        handle_will(&mut session, global).await?;
     }
+    session
+        .broadcast_packets
+        .send_all(session.client_id, global)
+        .await;
     if session.clean_session {
         global.remove_client(session.client_id, session.subscribes.keys());
         if let Some(err) = io_error {
@@ -222,18 +298,19 @@ impl OnlineSession for Session {
     fn client_id(&self) -> ClientId {
         self.client_id
     }
+    fn client_identifier(&self) -> &str {
+        &self.client_identifier
+    }
     fn disconnected(&self) -> bool {
         self.disconnected
     }
     fn build_state(&mut self, receiver: ClientReceiver) -> Self::SessionState {
-        let mut pending_packets = PendingPackets::new(0, 0, 0);
-        let mut qos2_pids = HashMap::new();
+        let mut pending_packets = PendingPackets::new(0, 0, RetransmitConfig::default());
+        let mut qos2_pids = IncomingQos2::default();
         let mut subscribes = HashMap::new();
-        let mut broadcast_packets = HashMap::new();
         mem::swap(&mut self.pending_packets, &mut pending_packets);
         mem::swap(&mut self.qos2_pids, &mut qos2_pids);
         mem::swap(&mut self.subscribes, &mut subscribes);
-        mem::swap(&mut self.broadcast_packets, &mut broadcast_packets);
         SessionState {
             client_id: self.client_id,
             receiver,
@@ -243,30 +320,42 @@ impl OnlineSession for Session {
             pending_packets,
             qos2_pids,
             subscribes,
-            broadcast_packets_cnt: self.broadcast_packets_cnt,
-            broadcast_packets,
+            attributes: mem::take(&mut self.attributes),
+            broadcast_packets: mem::take(&mut self.broadcast_packets),
         }
     }
 
-    fn consume_broadcast(&mut self, count: usize) {
-        self.broadcast_packets_cnt -= count;
-    }
     fn broadcast_packets_cnt(&self) -> usize {
-        self.broadcast_packets_cnt
+        self.broadcast_packets.len()
     }
     fn broadcast_packets_max(&self) -> usize {
         self.broadcast_packets_max
     }
-    fn broadcast_packets(&mut self) -> &mut HashMap<ClientId, BroadcastPackets> {
+    fn broadcast_packets(&mut self) -> &mut BroadcastPackets {
         &mut self.broadcast_packets
     }
 
+    fn is_publish(packet: &Self::Packet) -> bool {
+        matches!(packet, Packet::Publish(_))
+    }
+
+    fn skip_hook_queue(packet: &Self::Packet) -> bool {
+        matches!(
+            packet,
+            Packet::Pingreq
+                | Packet::Puback(_)
+                | Packet::Pubrec(_)
+                | Packet::Pubrel(_)
+                | Packet::Pubcomp(_)
+        )
+    }
+
     fn handle_decode_error(
         &mut self,
         err: Self::Error,
         _write_packets: &mut VecDeque<WritePacket<Self::Packet>>,
     ) -> Result<(), Option<io::Error>> {
-        log::debug!("[{}] mqtt v3.x codec error: {}", self.client_id, err);
+        tracing::debug!("[{}] mqtt v3.x codec error: {}", self.client_id, err);
         if err.is_eof() {
             if !self.disconnected() {
                 Err(Some(io::ErrorKind::UnexpectedEof.into()))
@@ -274,7 +363,7 @@ impl OnlineSession for Session {
                 Err(None)
             }
         } else {
-            Err(Some(io::ErrorKind::InvalidData.into()))
+            Err(Some(AkasaError::MalformedPacket(err.to_string()).into()))
         }
     }
 
@@ -286,18 +375,19 @@ impl OnlineSession for Session {
         write_packets: &mut VecDeque<WritePacket<Self::Packet>>,
         global: &Arc<GlobalState>,
     ) -> Result<Option<HookRequest>, Option<io::Error>> {
-        if encode_len > global.config.max_packet_size_server as usize {
-            log::debug!(
+        tracing::trace!(encode_len, ?packet, "received packet");
+        if encode_len > global.config().max_packet_size_server as usize {
+            tracing::debug!(
                 "packet too large, size={}, max={}",
                 encode_len,
-                global.config.max_packet_size_server
+                global.config().max_packet_size_server
             );
-            return Err(Some(io::ErrorKind::InvalidData.into()));
+            return Err(Some(AkasaError::PacketTooLarge(encode_len).into()));
         }
         match packet {
             Packet::Disconnect => handle_disconnect(self),
             Packet::Publish(pkt) => {
-                if global.config.hook.enable_publish {
+                if global.config().hook.enable_publish {
                     let locked_hook_context = LockedHookContext::new(self, write_packets);
                     let hook_request = HookRequest::V3Publish {
                         context: locked_hook_context,
@@ -307,7 +397,19 @@ impl OnlineSession for Session {
                     };
                     return Ok(Some(hook_request));
                 } else if let Some(packet) = handle_publish(self, pkt, global).map_err(Some)? {
-                    write_packets.push_back(packet.into());
+                    match self.kafka_receipt.take() {
+                        // The PUBACK waits for the Kafka receipt like a hook request
+                        Some(receipt) => {
+                            let locked_hook_context = LockedHookContext::new(self, write_packets);
+                            let hook_request = HookRequest::V3KafkaAck {
+                                context: locked_hook_context,
+                                receipt,
+                                packet,
+                            };
+                            return Ok(Some(hook_request));
+                        }
+                        None => write_packets.push_back(packet.into()),
+                    }
                 }
             }
             Packet::Puback(pid) => handle_puback(self, pid),
@@ -315,7 +417,7 @@ impl OnlineSession for Session {
             Packet::Pubrel(pid) => write_packets.push_back(handle_pubrel(self, pid)?.into()),
             Packet::Pubcomp(pid) => handle_pubcomp(self, pid),
             Packet::Subscribe(pkt) => {
-                if global.config.hook.enable_subscribe {
+                if global.config().hook.enable_subscribe {
                     let locked_hook_context = LockedHookContext::new(self, write_packets);
                     let hook_request = HookRequest::V3Subscribe {
                         context: locked_hook_context,
@@ -330,7 +432,7 @@ impl OnlineSession for Session {
                 }
             }
             Packet::Unsubscribe(pkt) => {
-                if global.config.hook.enable_unsubscribe {
+                if global.config().hook.enable_unsubscribe {
                     let locked_hook_context = LockedHookContext::new(self, write_packets);
                     let hook_request = HookRequest::V3Unsubscribe {
                         context: locked_hook_context,
@@ -344,16 +446,18 @@ impl OnlineSession for Session {
                 }
             }
             Packet::Pingreq => {
-                log::debug!("{} received a ping packet", self.client_id);
+                tracing::debug!("{} received a ping packet", self.client_id);
                 write_packets.push_back(Packet::Pingresp.into())
             }
             _ => {
-                log::info!(
+                tracing::info!(
                     "[{}] received a invalid packet: {:?}",
                     self.client_id,
                     packet
                 );
-                return Err(Some(io::ErrorKind::InvalidData.into()));
+                return Err(Some(
+                    AkasaError::Protocol("invalid packet".to_owned()).into(),
+                ));
             }
         }
         Ok(None)
@@ -382,7 +486,7 @@ impl OnlineSession for Session {
                         payload: payload.clone(),
                     };
                     Packet::Publish(publish).encode_len().map_err(|_| {
-                        log::error!("action publish message too large");
+                        tracing::error!("action publish message too large");
                         io::Error::from(io::ErrorKind::InvalidData)
                     })?
                 };
@@ -394,6 +498,7 @@ impl OnlineSession for Session {
                         topic_name: &topic_name,
                         payload: &payload,
                         encode_len,
+                        received_at: Instant::now(),
                     },
                     global,
                 );
@@ -409,21 +514,31 @@ impl OnlineSession for Session {
                                     | SubscribeReturnCode::MaxLevel1
                                     | SubscribeReturnCode::MaxLevel2 => {}
                                     code => {
-                                        log::error!("action subscribe message error return code: {:?}, topics={:?}", code, topics,);
+                                        tracing::error!("action subscribe message error return code: {:?}, topics={:?}", code, topics,);
                                         break;
                                     }
                                 }
                             }
                         }
-                        _ => log::error!("action subscribe message invalid (retain included)"),
+                        _ => tracing::error!("action subscribe message invalid (retain included)"),
                     },
-                    Err(err) => log::error!("action subscribe message invalid: {:?}", err),
+                    Err(err) => tracing::error!("action subscribe message invalid: {:?}", err),
                 }
             }
             HookAction::Unsubscribe(UnsubscribeAction(topics)) => {
                 let unsubscribe = Unsubscribe::new(Pid::default(), topics);
                 let _unsuback = handle_unsubscribe(self, &unsubscribe, global);
             }
+            HookAction::SetMaxInflight(value) => {
+                if value == 0 {
+                    tracing::error!("action set max inflight to 0 is not allowed");
+                } else {
+                    self.pending_packets.set_max_inflight(value);
+                }
+            }
+            HookAction::SetAttribute(key, value) => {
+                self.attributes.insert(key, value);
+            }
         }
         Ok(())
     }
@@ -445,14 +560,33 @@ impl OnlineSession for Session {
         &mut self,
         sender: ClientId,
         msg: NormalMessage,
-        _global: &Arc<GlobalState>,
-    ) -> Option<(QoS, Option<Self::Packet>)> {
-        handle_normal(self, sender, msg)
+        global: &Arc<GlobalState>,
+    ) -> Option<(QoS, Option<WritePacket<Packet>>)> {
+        // Only QoS 0 packet is returned, it's the same for all v3.x receivers
+        let encoded = msg.encoded_v3().clone();
+        handle_normal(self, sender, msg, global)
+            .map(|(qos, packet_opt)| (qos, packet_opt.map(|packet| encoded.write_packet(packet))))
     }
 
     fn handle_pendings(&mut self) -> Vec<Packet> {
         handle_pendings(self)
     }
+    fn pending_packets_len(&self) -> usize {
+        self.pending_packets.len()
+    }
+    fn slow_consumer_disconnect(&mut self) -> Option<Packet> {
+        // There is no server side DISCONNECT packet in v3.x
+        None
+    }
+    fn control_disconnect(&mut self) -> Option<Packet> {
+        None
+    }
+    fn retries_exhausted(&self) -> bool {
+        self.pending_packets.retries_exhausted(get_unix_ts())
+    }
+    fn retries_exhausted_disconnect(&mut self) -> Option<Packet> {
+        None
+    }
 }
 
 async fn handle_offline(mut session: Session, receiver: ClientReceiver, global: Arc<GlobalState>) {
@@ -465,7 +599,7 @@ async fn handle_offline(mut session: Session, receiver: ClientReceiver, global:
                     if let Some(sender) = sender_opt {
                         let old_state = session.build_state(receiver);
                         if let Err(err) = sender.send_async(old_state).await {
-                            log::warn!("offline send session state failed: {err:?}");
+                            tracing::warn!("offline send session state failed: {err:?}");
                         } else {
                             taken_over = true;
                         }
@@ -476,16 +610,16 @@ async fn handle_offline(mut session: Session, receiver: ClientReceiver, global:
                     }
                 }
                 Err(err) => {
-                    log::warn!("offline client receive control message error: {:?}", err);
+                    tracing::warn!("offline client receive control message error: {:?}", err);
                     break;
                 }
             },
             result = receiver.normal.recv_async() => match result {
                 Ok((sender, msg)) => {
-                    let _ =  handle_normal(&mut session, sender, msg);
+                    let _ =  handle_normal(&mut session, sender, msg, &global);
                 }
                 Err(err) => {
-                    log::warn!("offline client receive normal message error: {:?}", err);
+                    tracing::warn!("offline client receive normal message error: {:?}", err);
                     break;
                 }
             }
@@ -494,12 +628,16 @@ async fn handle_offline(mut session: Session, receiver: ClientReceiver, global:
     if !taken_over {
         global.remove_client(session.client_id, session.subscribes.keys());
     }
-    log::debug!("offline client finished: {:?}", session.client_id());
+    tracing::debug!("offline client finished: {:?}", session.client_id());
 }
 
 #[inline]
 async fn handle_will(session: &mut Session, global: &Arc<GlobalState>) -> io::Result<()> {
     if let Some(last_will) = session.last_will.take() {
+        // The NDEATH of a Sparkplug B edge node is its will
+        if sparkplug::check(global, &last_will.topic_name, &last_will.message) {
+            return Ok(());
+        }
         let encode_len = {
             let qos_pid = match last_will.qos {
                 QoS::Level0 => QosPid::Level0,
@@ -525,6 +663,7 @@ async fn handle_will(session: &mut Session, global: &Arc<GlobalState>) -> io::Re
                 qos: last_will.qos,
                 payload: &last_will.message,
                 encode_len,
+                received_at: Instant::now(),
             },
             global,
         );
@@ -545,17 +684,36 @@ fn handle_control(
     let mut stop = false;
     match msg {
         ControlMessage::OnlineV3 { sender } => return (false, Some(sender)),
+        #[cfg(feature = "v5")]
         ControlMessage::OnlineV5 { .. } => {
-            log::info!("take over v3.x by v5.x client is not allowed");
+            tracing::info!("take over v3.x by v5.x client is not allowed");
+        }
+        ControlMessage::Redirect {
+            server_reference, ..
+        } => {
+            if offline {
+                tracing::info!(
+                    "ignore redirect message when client {} is offline",
+                    session.client_id
+                );
+            } else {
+                // There is no server side DISCONNECT packet in v3.x
+                tracing::info!(
+                    "redirect \"{}\" to {} (disconnect only)",
+                    session.client_id,
+                    server_reference,
+                );
+                stop = true;
+            }
         }
         ControlMessage::Kick { reason } => {
             if offline {
-                log::info!(
+                tracing::info!(
                     "ignore kick message when client {} is offline",
                     session.client_id
                 );
             } else {
-                log::info!(
+                tracing::info!(
                     "kick \"{}\", reason: {}, online: {}",
                     session.client_id,
                     reason,
@@ -564,6 +722,9 @@ fn handle_control(
                 stop = true;
             }
         }
+        ControlMessage::Inspect { sender } => {
+            let _ = sender.try_send(session.info(!offline));
+        }
         ControlMessage::WillDelayReached { .. } | ControlMessage::SessionExpired { .. } => {
             unreachable!();
         }
@@ -577,6 +738,7 @@ fn handle_normal(
     session: &mut Session,
     sender: ClientId,
     msg: NormalMessage,
+    global: &Arc<GlobalState>,
 ) -> Option<(QoS, Option<Packet>)> {
     match msg {
         NormalMessage::PublishV3 {
@@ -587,8 +749,10 @@ fn handle_normal(
             ref subscribe_filter,
             subscribe_qos,
             encode_len: _,
+            encoded_v3: _,
+            received_at: _,
         } => {
-            log::debug!(
+            tracing::debug!(
                 "{:?} received a v3.x publish message from {:?}",
                 session.client_id,
                 sender
@@ -603,6 +767,7 @@ fn handle_normal(
                     subscribe_filter,
                     subscribe_qos,
                 },
+                global,
             )
         }
         NormalMessage::PublishV5 {
@@ -614,8 +779,10 @@ fn handle_normal(
             subscribe_qos,
             properties: _,
             encode_len: _,
+            encoded_v3: _,
+            received_at: _,
         } => {
-            log::debug!(
+            tracing::debug!(
                 "{:?} received a v5.x publish message from {:?}",
                 session.client_id,
                 sender
@@ -630,12 +797,14 @@ fn handle_normal(
                     subscribe_filter,
                     subscribe_qos,
                 },
+                global,
             )
         }
     }
 }
 
 async fn before_connect_hook<H: Hook + Clone + Send + Sync>(
+    session: &mut Session,
     peer: SocketAddr,
     packet: &Connect,
     hook_handler: &H,
@@ -650,11 +819,63 @@ async fn before_connect_hook<H: Hook + Clone + Send + Sync>(
         _ => panic!("invalid response"),
     };
     if code != ConnectReturnCode::Accepted {
-        return Err(io::ErrorKind::InvalidData.into());
+        session.connect_error = Some(code);
+        return Err(AkasaError::ConnectRejected(format!("{:?}", code)).into());
     }
     Ok(())
 }
 
+fn audit_connect(
+    session: &Session,
+    protocol: Protocol,
+    client_identifier: &str,
+    username: &Option<Arc<String>>,
+    global: &GlobalState,
+) {
+    global.audit(AuditEvent::Connect {
+        client_identifier: client_identifier.to_owned(),
+        username: username.as_ref().map(|name| name.to_string()),
+        peer: session.peer,
+        protocol: format!("{:?}", protocol),
+        success: session.connected,
+        reason: session.connect_error.map(|code| format!("{:?}", code)),
+    });
+}
+
+// Record the disconnect to the audit log and the event stream
+fn record_disconnect(
+    session: &Session,
+    taken_over: bool,
+    io_error: Option<&io::Error>,
+    global: &GlobalState,
+) {
+    let reason = if taken_over {
+        "taken over".to_owned()
+    } else if session.disconnected {
+        "client disconnected".to_owned()
+    } else if let Some(err) = io_error {
+        err.to_string()
+    } else {
+        "server disconnected".to_owned()
+    };
+    if let Some(err) = io_error {
+        let protocol = format!("{:?}", session.protocol);
+        let client_identifier = Some(session.client_identifier.as_str());
+        diagnostics::record_error(global, client_identifier, session.peer, protocol, err);
+    }
+    global.audit(AuditEvent::Disconnect {
+        client_identifier: session.client_identifier.to_string(),
+        username: session.username.as_ref().map(|name| name.to_string()),
+        peer: session.peer,
+        reason: reason.clone(),
+    });
+    global.emit_event(ClientEvent::Disconnected {
+        client_identifier: session.client_identifier.to_string(),
+        peer: session.peer,
+        reason,
+    });
+}
+
 async fn after_connect_hook<H: Hook + Clone + Send + Sync>(
     session: &mut Session,
     session_present: bool,
@@ -693,3 +914,37 @@ async fn after_disconnect_hook<H: Hook + Clone + Send + Sync>(
     };
     Ok(())
 }
+
+// The will is dropped if it's not authorized (again) when published, or the
+// hook failed (the session is still cleaned up)
+async fn before_will_hook<H: Hook + Clone + Send + Sync>(
+    session: &mut Session,
+    hook_handler: &H,
+    global: &Arc<GlobalState>,
+) {
+    let locked_hook_context = LockedHookContext::new(session, &mut Default::default());
+    let hook_request = HookRequest::V3BeforeWill {
+        context: locked_hook_context,
+    };
+    let code = match handle_request(hook_request, hook_handler.clone(), global.clone()).await {
+        HookResponse::BeforeWill(Ok(code)) => code,
+        HookResponse::BeforeWill(Err(err)) => {
+            tracing::warn!(
+                "[{}] will dropped, before will hook error: {}",
+                session.client_id,
+                err
+            );
+            session.last_will = None;
+            return;
+        }
+        _ => panic!("invalid response"),
+    };
+    if code != HookPublishCode::Success {
+        tracing::info!(
+            "[{}] will dropped by before will hook: {:?}",
+            session.client_id,
+            code
+        );
+        session.last_will = None;
+    }
+}
@@ -7,8 +7,8 @@ use mqtt_proto::{
 };
 use tokio::io::AsyncWrite;
 
-use crate::protocols::mqtt::{get_unix_ts, PendingPacketStatus};
-use crate::state::ClientId;
+use crate::protocols::mqtt::{get_unix_ts, PacketDirection, PendingPacketStatus};
+use crate::state::{ClientId, GlobalState};
 
 use super::super::Session;
 
@@ -23,7 +23,10 @@ pub(crate) fn handle_pendings(session: &mut Session) -> Vec<Packet> {
     session.pending_packets.clean_complete();
     let mut packets = Vec::new();
     let mut start_idx = 0;
-    while let Some((idx, packet_status)) = session.pending_packets.get_ready_packet(start_idx) {
+    let now_ts = get_unix_ts();
+    while let Some((idx, packet_status)) =
+        session.pending_packets.get_ready_packet(start_idx, now_ts)
+    {
         start_idx = idx + 1;
         match packet_status {
             PendingPacketStatus::New {
@@ -46,11 +49,11 @@ pub(crate) fn handle_pendings(session: &mut Session) -> Vec<Packet> {
                     payload: packet.payload.clone(),
                 };
                 *dup = true;
-                *last_sent = get_unix_ts();
+                *last_sent = now_ts;
                 packets.push(rv_packet.into());
             }
             PendingPacketStatus::Pubrec { pid, last_sent, .. } => {
-                *last_sent = get_unix_ts();
+                *last_sent = now_ts;
                 packets.push(Packet::Pubrel(*pid));
             }
             PendingPacketStatus::Complete => unreachable!(),
@@ -60,13 +63,19 @@ pub(crate) fn handle_pendings(session: &mut Session) -> Vec<Packet> {
 }
 
 #[inline]
+/// Write a packet before the online loop started, it's recorded by the packet
+/// tracer as a packet of `client_identifier`
 pub(crate) async fn write_packet<T: AsyncWrite + Unpin>(
     client_id: ClientId,
+    client_identifier: &str,
     conn: &mut T,
     packet: &Packet,
+    global: &GlobalState,
 ) -> io::Result<()> {
-    log::debug!("write to {}: {:?}", client_id, packet);
+    tracing::debug!(%client_id, ?packet, "write packet");
+    global
+        .packet_tracer
+        .record(client_identifier, PacketDirection::Out, packet);
     packet.encode_async(conn).await?;
-    log::debug!("write to {}: {:?} FINISHED!!!", client_id, packet);
     Ok(())
 }
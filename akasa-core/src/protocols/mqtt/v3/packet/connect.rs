@@ -1,3 +1,4 @@
+use std::cmp;
 use std::io;
 use std::sync::Arc;
 use std::time::Instant;
@@ -8,7 +9,13 @@ use mqtt_proto::{
 };
 use tokio::io::AsyncWrite;
 
-use crate::protocols::mqtt::{check_password, start_keep_alive_timer};
+use crate::error::Error;
+use crate::flapping;
+use crate::metrics::Metrics;
+use crate::protocols::mqtt::{
+    check_cloud_auth, check_password, generate_client_identifier, pending_limits,
+    start_keep_alive_timer,
+};
 use crate::state::{AddClientReceipt, ClientReceiver, GlobalState};
 
 use super::super::Session;
@@ -21,7 +28,7 @@ pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
     conn: &mut T,
     global: &Arc<GlobalState>,
 ) -> io::Result<bool> {
-    log::debug!(
+    tracing::debug!(
         r#"{} received a connect packet:
      protocol : {}
     client_id : {}
@@ -40,30 +47,158 @@ clean session : {}
         packet.last_will,
     );
 
+    // There is no Server Reference in v3.x
+    if let Some(redirect) = global.config().redirect.as_ref() {
+        tracing::debug!(
+            "redirect client {} to {}",
+            packet.client_id,
+            redirect.server_reference
+        );
+        let rv_packet = Connack::new(false, ConnectReturnCode::ServerUnavailable);
+        session.connect_error = Some(ConnectReturnCode::ServerUnavailable);
+        write_packet(
+            session.client_id,
+            &packet.client_id,
+            conn,
+            &rv_packet.into(),
+            global,
+        )
+        .await?;
+        session.disconnected = true;
+        return Ok(false);
+    }
+
+    // There is no Server Busy in v3.x
+    if global
+        .config()
+        .memory_limit
+        .under_pressure(global.accounted_memory())
+    {
+        tracing::warn!("refuse client {}, due to memory pressure", packet.client_id);
+        Metrics::incr(&global.metrics.memory_refused_connects);
+        let rv_packet = Connack::new(false, ConnectReturnCode::ServerUnavailable);
+        session.connect_error = Some(ConnectReturnCode::ServerUnavailable);
+        write_packet(
+            session.client_id,
+            &packet.client_id,
+            conn,
+            &rv_packet.into(),
+            global,
+        )
+        .await?;
+        session.disconnected = true;
+        return Ok(false);
+    }
+
+    // There is no Banned in v3.x
+    if global.bans.is_client_banned(
+        &packet.client_id,
+        packet.username.as_ref().map(|name| name.as_str()),
+    ) || flapping::check_connect(global, &packet.client_id, session.peer)
+    {
+        tracing::info!("refuse banned client {}", packet.client_id);
+        Metrics::incr(&global.metrics.banned_connects);
+        let rv_packet = Connack::new(false, ConnectReturnCode::NotAuthorized);
+        session.connect_error = Some(ConnectReturnCode::NotAuthorized);
+        write_packet(
+            session.client_id,
+            &packet.client_id,
+            conn,
+            &rv_packet.into(),
+            global,
+        )
+        .await?;
+        session.disconnected = true;
+        return Ok(false);
+    }
+
     if packet.protocol == Protocol::V310
         && (packet.client_id.is_empty()
-            || global.config.check_v310_client_id_length && packet.client_id.len() > 23)
+            || global.config().check_v310_client_id_length && packet.client_id.len() > 23)
     {
-        log::info!("invalid v3.1 client id length: {}", packet.client_id.len());
+        tracing::info!("invalid v3.1 client id length: {}", packet.client_id.len());
         let rv_packet = Connack::new(false, ConnectReturnCode::IdentifierRejected);
-        write_packet(session.client_id, conn, &rv_packet.into()).await?;
+        session.connect_error = Some(ConnectReturnCode::IdentifierRejected);
+        write_packet(
+            session.client_id,
+            &packet.client_id,
+            conn,
+            &rv_packet.into(),
+            global,
+        )
+        .await?;
         session.disconnected = true;
         return Ok(false);
     }
 
     // v3.1.1 [MQTT-3.1.3-8]
-    if packet.protocol == Protocol::V311 && packet.client_id.is_empty() && !packet.clean_session {
-        log::info!("empty v3.1.1 client id, clean session is 0");
+    if packet.protocol == Protocol::V311
+        && packet.client_id.is_empty()
+        && (!packet.clean_session || !global.config().empty_client_id.assign)
+    {
+        tracing::info!(
+            "empty v3.1.1 client id, clean session: {}",
+            packet.clean_session
+        );
         let rv_packet = Connack::new(false, ConnectReturnCode::IdentifierRejected);
-        write_packet(session.client_id, conn, &rv_packet.into()).await?;
+        session.connect_error = Some(ConnectReturnCode::IdentifierRejected);
+        write_packet(
+            session.client_id,
+            &packet.client_id,
+            conn,
+            &rv_packet.into(),
+            global,
+        )
+        .await?;
+        session.disconnected = true;
+        return Ok(false);
+    }
+
+    // There is no Server Keep Alive in v3.x, reject the client exceeded the
+    // max keep alive (0 means no keep alive) by `Server unavailable`. The
+    // keep alive is not checked if the listener disabled it (same as v5.x).
+    let max_keep_alive = match session.listener_keep_alive {
+        Some(0) => u16::MAX,
+        Some(value) => cmp::min(value, global.config().max_keep_alive),
+        None => global.config().max_keep_alive,
+    };
+    if max_keep_alive < u16::MAX && (packet.keep_alive == 0 || packet.keep_alive > max_keep_alive) {
+        tracing::info!(
+            "keep alive {} exceeded the max keep alive {}",
+            packet.keep_alive,
+            max_keep_alive
+        );
+        let rv_packet = Connack::new(false, ConnectReturnCode::ServerUnavailable);
+        session.connect_error = Some(ConnectReturnCode::ServerUnavailable);
+        write_packet(
+            session.client_id,
+            &packet.client_id,
+            conn,
+            &rv_packet.into(),
+            global,
+        )
+        .await?;
         session.disconnected = true;
         return Ok(false);
     }
 
     let mut return_code = ConnectReturnCode::Accepted;
-    if global.config.auth.enable {
-        if packet.username.is_none() || packet.password.is_none() {
-            log::debug!(
+    let config = global.config();
+    if config.auth.enable {
+        let cloud_auth = check_cloud_auth(
+            &config.auth.cloud_profiles,
+            &packet.client_id,
+            packet.username.as_ref().map(|name| name.as_str()),
+            packet.password.as_deref(),
+        )
+        .await;
+        if let Some(valid) = cloud_auth {
+            if !valid {
+                tracing::debug!("cloud profile authentication failed: {}", packet.client_id);
+                return_code = ConnectReturnCode::BadUserNameOrPassword;
+            }
+        } else if packet.username.is_none() || packet.password.is_none() {
+            tracing::debug!(
                 "username or password not set for client: {}",
                 packet.client_id
             );
@@ -72,7 +207,7 @@ clean session : {}
             let username = packet.username.as_ref().unwrap();
             let password = packet.password.as_ref().unwrap();
             if !check_password(&global.auth_passwords, username, password) {
-                log::debug!("incorrect password for user: {}", username);
+                tracing::debug!("incorrect password for user: {}", username);
                 return_code = ConnectReturnCode::BadUserNameOrPassword;
             }
         }
@@ -80,7 +215,15 @@ clean session : {}
     // FIXME: permission check and return "not authorized"
     if return_code != ConnectReturnCode::Accepted {
         let rv_packet = Connack::new(false, return_code);
-        write_packet(session.client_id, conn, &rv_packet.into()).await?;
+        session.connect_error = Some(return_code);
+        write_packet(
+            session.client_id,
+            &packet.client_id,
+            conn,
+            &rv_packet.into(),
+            global,
+        )
+        .await?;
         session.disconnected = true;
         return Ok(false);
     }
@@ -91,41 +234,54 @@ clean session : {}
     session.clean_session = packet.clean_session;
     session.client_identifier = if packet.client_id.is_empty() {
         session.assigned_client_id = true;
-        Arc::new(uuid::Uuid::new_v4().to_string())
+        Arc::new(generate_client_identifier(&global.config().empty_client_id))
     } else {
         Arc::clone(&packet.client_id)
     };
     session.username = packet.username.map(|name| Arc::clone(&name));
-    session.keep_alive = packet.keep_alive;
+    session.keep_alive = match session.listener_keep_alive {
+        // Disabled by the listener
+        Some(0) => 0,
+        _ => packet.keep_alive,
+    };
 
-    if let Some(last_will) = packet.last_will {
+    if let Some(mut last_will) = packet.last_will {
         if last_will.topic_name.is_empty() {
-            return Err(io::ErrorKind::InvalidData.into());
+            return Err(Error::Protocol("empty will topic name".to_owned()).into());
         }
         if last_will.topic_name.starts_with('$') {
-            return Err(io::ErrorKind::InvalidData.into());
+            return Err(Error::Protocol("will topic name starts with $".to_owned()).into());
         }
+        let config = global.config();
+        last_will.qos = cmp::min(last_will.qos, session.max_qos(&config));
+        last_will.retain = last_will.retain && config.retain_available;
         session.last_will = Some(last_will);
     }
 
     let mut session_present = false;
     match global
-        .add_client(session.client_identifier.as_str(), session.protocol)
+        .add_client(
+            session.client_identifier.as_str(),
+            session.protocol,
+            Some(session.peer.ip()),
+        )
         .await?
     {
         AddClientReceipt::PresentV3(old_state) => {
-            log::debug!("Got exists session for {}", old_state.client_id);
+            tracing::debug!("Got exists session for {}", old_state.client_id);
             session.client_id = old_state.client_id;
             *receiver = Some(old_state.receiver);
             // TODO: if protocol level is compatiable, copy the session state?
             if !session.clean_session && session.protocol == old_state.protocol {
                 session.server_packet_id = old_state.server_packet_id;
                 session.pending_packets = old_state.pending_packets;
+                session.pending_packets.reset_retries();
                 session.qos2_pids = old_state.qos2_pids;
                 session.subscribes = old_state.subscribes;
+                session.attributes = old_state.attributes;
                 session_present = true;
             } else {
-                log::info!(
+                tracing::info!(
                     "{} session state removed due to reconnect with a different protocol version, new: {}, old: {}, or clean session: {}",
                     old_state.pending_packets.len(),
                     session.protocol,
@@ -136,12 +292,28 @@ clean session : {}
             }
         }
         // not allowed, so this is dead branch.
+        #[cfg(feature = "v5")]
         AddClientReceipt::PresentV5(_) => unreachable!(),
+        AddClientReceipt::Rejected => {
+            tracing::info!("session is online: {}", session.client_identifier);
+            let rv_packet = Connack::new(false, ConnectReturnCode::IdentifierRejected);
+            session.connect_error = Some(ConnectReturnCode::IdentifierRejected);
+            write_packet(
+                session.client_id,
+                &session.client_identifier,
+                conn,
+                &rv_packet.into(),
+                global,
+            )
+            .await?;
+            session.disconnected = true;
+            return Ok(false);
+        }
         AddClientReceipt::New {
             client_id,
             receiver: new_receiver,
         } => {
-            log::debug!("Create new session for {}", client_id);
+            tracing::debug!("Create new session for {}", client_id);
             session.client_id = client_id;
             *receiver = Some(new_receiver);
         }
@@ -149,6 +321,25 @@ clean session : {}
 
     // FIXME: early return after add_client will cause memory leak
 
+    // Also applied to the restored session state
+    let (max_packets, max_bytes, policy, max_inflight) = pending_limits(
+        &global.config(),
+        &session.client_identifier,
+        session.username.as_ref().map(|name| name.as_str()),
+    );
+    session
+        .pending_packets
+        .set_max_inflight(max_inflight.unwrap_or(global.config().max_inflight_client));
+    session
+        .pending_packets
+        .set_limits(max_packets, max_bytes, policy);
+    session
+        .pending_packets
+        .set_memory_charge(global.memory.pending_charge());
+    session
+        .broadcast_packets
+        .set_memory_charge(global.memory.broadcast_charge());
+
     start_keep_alive_timer(
         session.keep_alive,
         session.client_id,
@@ -156,10 +347,21 @@ clean session : {}
         global,
     )?;
 
-    log::debug!("Socket {} assgined to: {}", session.peer, session.client_id);
+    tracing::debug!("Socket {} assgined to: {}", session.peer, session.client_id);
 
-    let rv_packet = Connack::new(session_present, return_code);
-    write_packet(session.client_id, conn, &rv_packet.into()).await?;
+    // There is no Session Present flag in v3.1 CONNACK, the byte is reserved
+    let rv_packet = Connack::new(
+        session_present && session.protocol != Protocol::V310,
+        return_code,
+    );
+    write_packet(
+        session.client_id,
+        &session.client_identifier,
+        conn,
+        &rv_packet.into(),
+        global,
+    )
+    .await?;
     session.connected = true;
     session.connected_time = Some(Instant::now());
     Ok(session_present)
@@ -167,7 +369,7 @@ clean session : {}
 
 #[inline]
 pub(crate) fn handle_disconnect(session: &mut Session) {
-    log::debug!("{} received a disconnect packet", session.client_id);
+    tracing::debug!("{} received a disconnect packet", session.client_id);
     session.last_will = None;
     session.disconnected = true;
 }
@@ -3,6 +3,7 @@ use std::hash::{Hash, Hasher};
 use std::io;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Instant;
 
 use ahash::AHasher;
 use bytes::Bytes;
@@ -11,9 +12,27 @@ use mqtt_proto::{
     v3::{Packet, Publish},
     Encodable, Pid, QoS, QosPid, TopicFilter, TopicName,
 };
+use tracing::Span;
 
-use crate::protocols::mqtt::{BroadcastPackets, RetainContent};
-use crate::state::{GlobalState, NormalMessage};
+use crate::audit::AuditEvent;
+use crate::config::ValidationMode;
+use crate::delayed::{parse_delayed_topic, DelayedMessage, DELAYED_PREFIX};
+use crate::error::Error;
+use crate::events::ClientEvent;
+use crate::fanout;
+use crate::forward::{self, ForwardMessage, Forwarded};
+use crate::hook::HookPublishCode;
+use crate::metrics::{LatencyStage, Metrics};
+use crate::protocols::mqtt::{
+    check_topic_limits, dollar_topic_rejected, get_unix_ts, payload_rejected, retain_rejected,
+    store_retain, PendingPush, Qos2Publish, RetainContent, SharedEncoded,
+};
+use crate::publish::ServerMessage;
+use crate::quota;
+use crate::rule::{self, RuleMessage};
+use crate::schema;
+use crate::sparkplug;
+use crate::state::{ControlMessage, GlobalState, NormalMessage};
 
 use super::super::{PubPacket, Session};
 
@@ -23,7 +42,8 @@ pub(crate) fn handle_publish(
     packet: Publish,
     global: &Arc<GlobalState>,
 ) -> io::Result<Option<Packet>> {
-    log::debug!(
+    let received_at = Instant::now();
+    tracing::debug!(
         r#"{} received a publish packet:
 topic name : {}
    payload : {:?}
@@ -36,16 +56,52 @@ topic name : {}
         packet.dup,
     );
     if packet.topic_name.is_empty() {
-        log::debug!("invalid empty topic name");
-        return Err(io::ErrorKind::InvalidData.into());
+        tracing::debug!("invalid empty topic name");
+        return Err(Error::Protocol("empty topic name".to_owned()).into());
     }
-    if packet.topic_name.starts_with('$') {
-        log::debug!("invalid topic name: {}", packet.topic_name);
-        return Err(io::ErrorKind::InvalidData.into());
+    let config = global.config();
+    let (delayed, topic_error) =
+        if config.delayed_publish.enable && packet.topic_name.starts_with(DELAYED_PREFIX) {
+            match parse_delayed_topic(&packet.topic_name, config.delayed_publish.max_delay) {
+                Some(delayed) => (Some(delayed), None),
+                None => (None, Some("invalid delayed topic name")),
+            }
+        } else if packet.topic_name.starts_with('$') {
+            match dollar_topic_rejected(
+                &config,
+                &packet.topic_name,
+                &session.client_identifier,
+                session.username.as_ref().map(|name| name.as_str()),
+            ) {
+                None => (None, None),
+                Some(HookPublishCode::NotAuthorized) => {
+                    global.audit(AuditEvent::AclDenied {
+                        client_identifier: session.client_identifier.to_string(),
+                        username: session.username.as_ref().map(|name| name.to_string()),
+                        peer: session.peer,
+                        action: "publish".to_owned(),
+                        topics: vec![packet.topic_name.to_string()],
+                    });
+                    (None, Some("publish to reserved topic is not authorized"))
+                }
+                Some(_) => (None, Some("invalid topic name")),
+            }
+        } else {
+            (None, None)
+        };
+    let topic_error =
+        topic_error.or_else(|| check_topic_limits(&config.topic_limits, &packet.topic_name).err());
+    if let Some(reason) = topic_error {
+        if config.validation_mode == ValidationMode::Strict {
+            tracing::debug!("{}: {}", reason, packet.topic_name);
+            return Err(Error::Protocol(reason.to_owned()).into());
+        }
+        // MQTT v3.x can't reject the publish, it's acknowledged but not published
+        tracing::warn!("publish dropped, {}: {}", reason, packet.topic_name);
     }
     if packet.qos_pid == QosPid::Level0 && packet.dup {
-        log::debug!("invalid dup flag");
-        return Err(io::ErrorKind::InvalidData.into());
+        tracing::debug!("invalid dup flag");
+        return Err(Error::Protocol("invalid dup flag".to_owned()).into());
     }
 
     if let QosPid::Level2(pid) = packet.qos_pid {
@@ -53,38 +109,120 @@ topic name : {}
         packet.hash(&mut hasher);
         let current_hash = hasher.finish();
 
-        if let Some(previous_hash) = session.qos2_pids.get(&pid) {
-            // hash collision is acceptable here
-            if current_hash != *previous_hash {
-                log::info!("packet identifier in use: {}", pid.value());
-                return Err(io::ErrorKind::InvalidData.into());
+        // FIXME: check qos2_pids limit
+        match session
+            .qos2_pids
+            .publish(pid, current_hash, packet.dup, usize::MAX)
+        {
+            Qos2Publish::New | Qos2Publish::Duplicate => {}
+            Qos2Publish::PidInUse => {
+                tracing::info!("packet identifier in use: {}", pid.value());
+                return Err(Error::Protocol("packet identifier in use".to_owned()).into());
             }
-            if !packet.dup {
-                log::info!(
+            Qos2Publish::DupRequired => {
+                tracing::info!(
                     "dup flag must be true for re-deliver packet: {}",
                     pid.value()
                 );
-                return Err(io::ErrorKind::InvalidData.into());
+                return Err(Error::Protocol("dup flag must be true".to_owned()).into());
             }
-        } else {
-            // FIXME: check qos2_pids limit
-            session.qos2_pids.insert(pid, current_hash);
+            Qos2Publish::TooMany => unreachable!("no limit"),
         }
     }
 
-    if !(packet.dup && packet.qos_pid.qos() == QoS::Level2) {
-        let encode_len = total_len(packet.encode_len()).expect("packet too large");
+    // The payload limits are matched by the target topic of a delayed publish
+    let target_topic: &str = match &delayed {
+        Some((_, delayed_topic)) => delayed_topic,
+        None => &packet.topic_name,
+    };
+    if packet.dup && packet.qos_pid.qos() == QoS::Level2 {
+        // Already handled
+    } else if topic_error.is_some() {
+        // Dropped by lenient validation
+    } else if payload_rejected(global, target_topic, packet.payload.len()) {
+        // MQTT v3.x can't reject the publish, the message is dropped
+    } else if schema::check(global, target_topic, &packet.payload).is_some() {
+        // Dropped or dead lettered
+    } else if let Err(reason) = quota::check_publish(
+        global,
+        session.username.as_ref().map(|name| name.as_str()),
+        &packet.topic_name,
+        packet.payload.len(),
+        packet.retain && config.retain_available && delayed.is_none(),
+    ) {
+        // MQTT v3.x can't reject the publish, the message is dropped
+        tracing::debug!("publish dropped, {}: {}", reason, packet.topic_name);
+    } else if let Some((delay, delayed_topic)) = delayed {
+        let message = DelayedMessage {
+            message: ServerMessage {
+                client_identifier: session.client_identifier.clone(),
+                username: session.username.clone(),
+                topic_name: delayed_topic,
+                qos: cmp::min(packet.qos_pid.qos(), session.max_qos(&config)),
+                retain: packet.retain && config.retain_available,
+                payload: packet.payload.clone(),
+                properties: Default::default(),
+            },
+            received_at,
+        };
+        let max_messages = config.delayed_publish.max_messages;
+        // MQTT v3.x can't reject the publish, the message is dropped
+        if !global.delayed_queue.push(delay, message, max_messages) {
+            tracing::warn!("too many delayed messages, dropped: {}", packet.topic_name);
+        }
+    } else if packet.retain
+        && config.retain_available
+        && retain_rejected(global, &packet.topic_name, packet.payload.len())
+    {
+        // MQTT v3.x can't reject the publish, the message is dropped
+    } else if sparkplug::check(global, &packet.topic_name, &packet.payload) {
+        // Dropped since out of sequence
+    } else if rule::apply(
+        global,
+        &RuleMessage {
+            client_identifier: &session.client_identifier,
+            username: session.username.as_deref().map(String::as_str),
+            topic_name: &packet.topic_name,
+            qos: packet.qos_pid.qos(),
+            retain: packet.retain && config.retain_available,
+            payload: &packet.payload,
+        },
+    ) {
+        // Dropped by a rule
+    } else {
+        let mut encode_len = total_len(packet.encode_len()).expect("packet too large");
+        // MQTT v3.x can't reject the publish, downgrade it to the max QoS
+        let qos = cmp::min(packet.qos_pid.qos(), session.max_qos(&config));
+        if qos == QoS::Level0 && packet.qos_pid != QosPid::Level0 {
+            // The packet identifier is removed
+            encode_len -= 2;
+        }
         send_publish(
             session,
             SendPublish {
                 topic_name: &packet.topic_name,
-                retain: packet.retain,
-                qos: packet.qos_pid.qos(),
+                // The retain flag is silently cleared if retained message is disabled
+                retain: packet.retain && config.retain_available,
+                qos,
                 payload: &packet.payload,
                 encode_len,
+                received_at,
             },
             global,
         );
+        let forwarded = forward::forward(
+            global,
+            &ForwardMessage {
+                client_identifier: &session.client_identifier,
+                username: session.username.as_deref().map(String::as_str),
+                topic_name: &packet.topic_name,
+                payload: &packet.payload,
+                qos: packet.qos_pid.qos(),
+            },
+        );
+        if let Forwarded::Wait(receipt) = forwarded {
+            session.kafka_receipt = Some(receipt);
+        }
     }
     match packet.qos_pid {
         QosPid::Level0 => Ok(None),
@@ -95,7 +233,7 @@ topic name : {}
 
 #[inline]
 pub(crate) fn handle_puback(session: &mut Session, pid: Pid) {
-    log::debug!(
+    tracing::debug!(
         "{} received a puback packet: id={}",
         session.client_id,
         pid.value()
@@ -105,33 +243,33 @@ pub(crate) fn handle_puback(session: &mut Session, pid: Pid) {
 
 #[inline]
 pub(crate) fn handle_pubrec(session: &mut Session, pid: Pid) -> Packet {
-    log::debug!(
+    tracing::debug!(
         "{} received a pubrec  packet: id={}",
         session.client_id,
         pid.value()
     );
 
-    let _matched = session.pending_packets.pubrec(pid);
+    let _matched = session.pending_packets.pubrec(pid, get_unix_ts());
     Packet::Pubrel(pid)
 }
 
 #[inline]
 pub(crate) fn handle_pubrel(session: &mut Session, pid: Pid) -> io::Result<Packet> {
-    log::debug!(
+    tracing::debug!(
         "{} received a pubrel  packet: id={}",
         session.client_id,
         pid.value()
     );
-    if session.qos2_pids.remove(&pid).is_none() {
-        log::warn!("packet identifier not found: {}", pid.value());
-        return Err(io::ErrorKind::InvalidData.into());
+    if !session.qos2_pids.release(pid) {
+        tracing::warn!("packet identifier not found: {}", pid.value());
+        return Err(Error::Protocol("packet identifier not found".to_owned()).into());
     }
     Ok(Packet::Pubcomp(pid))
 }
 
 #[inline]
 pub(crate) fn handle_pubcomp(session: &mut Session, pid: Pid) {
-    log::debug!(
+    tracing::debug!(
         "{} received a pubcomp packet: id={}",
         session.client_id,
         pid.value()
@@ -149,6 +287,7 @@ pub(crate) struct SendPublish<'a> {
     pub qos: QoS,
     pub payload: &'a Bytes,
     pub encode_len: usize,
+    pub received_at: Instant,
 }
 
 pub(crate) struct RecvPublish<'a> {
@@ -161,24 +300,25 @@ pub(crate) struct RecvPublish<'a> {
 }
 
 // Received a publish message from client or will, then publish the message to matched clients
+#[tracing::instrument(name = "publish", skip_all, fields(topic = %msg.topic_name.deref(), qos = ?msg.qos, receivers))]
 pub(crate) fn send_publish(session: &mut Session, msg: SendPublish, global: &Arc<GlobalState>) {
     if msg.retain {
         if let Some(old_content) = if msg.payload.is_empty() {
-            log::debug!("retain message removed");
+            tracing::debug!("retain message removed");
             global.retain_table.remove(msg.topic_name)
         } else {
-            let content = Arc::new(RetainContent::new(
+            let content = RetainContent::new(
                 session.client_identifier.clone(),
                 msg.qos,
                 msg.topic_name.clone(),
                 msg.payload.clone(),
                 None,
                 msg.encode_len,
-            ));
-            log::debug!("retain message inserted");
-            global.retain_table.insert(content)
+            );
+            tracing::debug!("retain message inserted");
+            store_retain(global, content)
         } {
-            log::debug!(
+            tracing::debug!(
                 r#"old retain content:
  client identifier : {}
         topic name : {}
@@ -192,43 +332,91 @@ pub(crate) fn send_publish(session: &mut Session, msg: SendPublish, global: &Arc
         }
     }
 
+    let config = global.config();
     let matches = global.route_table.get_matches(msg.topic_name);
     let mut senders = Vec::with_capacity(matches.len());
     for content in matches {
         let content = content.read();
         let subscribe_filter = content.topic_filter.as_ref().unwrap();
         for (client_id, subscribe_qos) in &content.clients {
+            // The bridge don't receive the messages published by itself (no local)
+            if session.bridge && *client_id == session.client_id {
+                continue;
+            }
             senders.push((*client_id, subscribe_filter.clone(), *subscribe_qos));
         }
     }
+    Span::current().record("receivers", senders.len());
+    if config.latency_metrics {
+        global
+            .metrics
+            .latency
+            .record(LatencyStage::Route, msg.qos, msg.received_at);
+    }
+    if !config.topic_stats.prefixes.is_empty() {
+        global.topic_stats.record(
+            &config.topic_stats.prefixes,
+            msg.topic_name,
+            msg.payload.len(),
+            senders.len(),
+        );
+    }
+    if config.top_talkers.count > 0 {
+        global
+            .client_stats
+            .record(&session.client_identifier, msg.payload.len());
+    }
+    if global.has_event_streams() {
+        global.emit_event(ClientEvent::Published {
+            client_identifier: session.client_identifier.to_string(),
+            topic_name: msg.topic_name.to_string(),
+            qos: msg.qos as u8,
+            retain: msg.retain,
+            payload_len: msg.payload.len(),
+            receivers: senders.len(),
+        });
+    }
+
+    let encoded_v3 = SharedEncoded::default();
+    let new_message = |subscribe_filter, subscribe_qos| NormalMessage::PublishV3 {
+        retain: msg.retain,
+        qos: msg.qos,
+        topic_name: msg.topic_name.clone(),
+        payload: msg.payload.clone(),
+        subscribe_filter,
+        subscribe_qos,
+        encode_len: msg.encode_len,
+        encoded_v3: encoded_v3.clone(),
+        received_at: msg.received_at,
+    };
+    let min_receivers = config.fan_out.min_receivers;
+    // Keep using the workers until the previous messages are enqueued by
+    // them, so the messages to a receiver are not reordered.
+    if !senders.is_empty()
+        && ((min_receivers > 0 && senders.len() >= min_receivers)
+            || session.broadcast_packets.fan_out.is_busy())
+    {
+        let (_, subscribe_filter, subscribe_qos) = &senders[0];
+        let template = new_message(subscribe_filter.clone(), *subscribe_qos);
+        senders = fanout::dispatch(
+            global,
+            &config.fan_out,
+            session.client_id,
+            template,
+            senders,
+            &mut session.broadcast_packets,
+        );
+    }
 
-    session.broadcast_packets_cnt += senders.len();
     for (receiver_client_id, subscribe_filter, subscribe_qos) in senders {
-        let publish = NormalMessage::PublishV3 {
-            retain: msg.retain,
-            qos: msg.qos,
-            topic_name: msg.topic_name.clone(),
-            payload: msg.payload.clone(),
-            subscribe_filter,
-            subscribe_qos,
-            encode_len: msg.encode_len,
-        };
-        if !session.broadcast_packets.contains_key(&receiver_client_id) {
-            if let Some(sender) = global.get_client_normal_sender(&receiver_client_id) {
-                session.broadcast_packets.insert(
-                    receiver_client_id,
-                    BroadcastPackets {
-                        sink: sender.into_sink(),
-                        msgs: Default::default(),
-                        flushed: true,
-                    },
-                );
-            }
-        }
-        if let Some(info) = session.broadcast_packets.get_mut(&receiver_client_id) {
-            info.msgs.push_back(publish);
-        } else {
-            session.broadcast_packets_cnt -= 1;
+        let publish = new_message(subscribe_filter, subscribe_qos);
+        if !session.broadcast_packets.push(
+            receiver_client_id,
+            publish,
+            config.broadcast.max_messages_per_receiver,
+        ) {
+            tracing::debug!("too many messages queued for {}", receiver_client_id);
+            Metrics::incr(&global.metrics.broadcast_dropped);
         }
     }
 }
@@ -237,6 +425,7 @@ pub(crate) fn send_publish(session: &mut Session, msg: SendPublish, global: &Arc
 pub(crate) fn recv_publish(
     session: &mut Session,
     msg: RecvPublish,
+    global: &Arc<GlobalState>,
 ) -> Option<(QoS, Option<Packet>)> {
     if !session.subscribes.contains_key(msg.subscribe_filter) {
         // the client already unsubscribed.
@@ -247,7 +436,7 @@ pub(crate) fn recv_publish(
     if final_qos != QoS::Level0 {
         let pid = session.incr_server_packet_id();
         session.pending_packets.clean_complete();
-        if session.pending_packets.push_back(
+        let result = session.pending_packets.push_back(
             pid,
             PubPacket {
                 topic_name: msg.topic_name.clone(),
@@ -255,8 +444,19 @@ pub(crate) fn recv_publish(
                 retain: msg.retain,
                 payload: msg.payload.clone(),
             },
-        ) {
-            // TODO: pending messages queue is full
+            msg.payload.len(),
+        );
+        Metrics::add(&global.metrics.pending_dropped, result.dropped() as u64);
+        // v3.x has no DISCONNECT packet from server, kick the client out
+        if result == PendingPush::Overflow && session.connected && !session.disconnected {
+            if let Some(sender) = global.get_client_control_sender(&session.client_id) {
+                let msg = ControlMessage::Kick {
+                    reason: "pending messages queue overflow".to_owned(),
+                };
+                if sender.try_send(msg).is_ok() {
+                    Metrics::incr(&global.metrics.pending_overflow_disconnects);
+                }
+            }
         }
         Some((final_qos, None))
     } else if !session.disconnected {
@@ -3,10 +3,13 @@ use std::io;
 use std::sync::Arc;
 
 use mqtt_proto::{
-    v3::{Packet, Suback, Subscribe, Unsubscribe},
-    QoS,
+    v3::{Packet, Suback, Subscribe, SubscribeReturnCode, Unsubscribe},
+    Protocol, QoS, MATCH_ALL_CHAR, MATCH_ONE_CHAR,
 };
 
+use crate::error::Error;
+use crate::events::ClientEvent;
+use crate::protocols::mqtt::{check_topic_limits, too_many_subscriptions};
 use crate::state::GlobalState;
 
 use super::super::Session;
@@ -21,7 +24,7 @@ pub(crate) fn handle_subscribe(
     packet: &Subscribe,
     global: &Arc<GlobalState>,
 ) -> io::Result<Vec<Packet>> {
-    log::debug!(
+    tracing::debug!(
         r#"{} received a subscribe packet:
 packet id : {}
    topics : {:?}"#,
@@ -29,21 +32,59 @@ packet id : {}
         packet.pid.value(),
         packet.topics,
     );
+    let config = global.config();
+    let max_qos = session.max_qos(&config);
     let mut rv_packets = Vec::new();
     let mut return_codes = Vec::with_capacity(packet.topics.len());
     for (filter, qos) in &packet.topics {
         if filter.is_shared() {
-            log::info!("mqtt v3.x don't support shared subscription");
-            return Err(io::ErrorKind::InvalidData.into());
+            tracing::info!("mqtt v3.x don't support shared subscription");
+            return Err(Error::Protocol("shared subscription not supported".to_owned()).into());
         }
-        let granted_qos = cmp::min(*qos, global.config.max_allowed_qos());
+        let rejected = if !config.wildcard_subscription_available
+            && filter.contains(|c| c == MATCH_ONE_CHAR || c == MATCH_ALL_CHAR)
+        {
+            tracing::debug!("wildcard subscription is disabled: {}", filter);
+            Some(Error::Protocol(
+                "wildcard subscription is disabled".to_owned(),
+            ))
+        } else if let Err(reason) = check_topic_limits(&config.topic_limits, filter) {
+            tracing::debug!("{}: {}", reason, filter);
+            Some(Error::Protocol(reason.to_owned()))
+        } else if too_many_subscriptions(&config, &session.subscribes, filter) {
+            tracing::debug!("too many subscriptions: {}", filter);
+            Some(Error::QuotaExceeded("too many subscriptions".to_owned()))
+        } else {
+            None
+        };
+        if let Some(err) = rejected {
+            // There is no failure return code in v3.1, just close the connection
+            if session.protocol == Protocol::V310 {
+                return Err(err.into());
+            }
+            return_codes.push(SubscribeReturnCode::Failure);
+            continue;
+        }
+        let granted_qos = cmp::min(*qos, max_qos);
         session.subscribes.insert(filter.clone(), granted_qos);
         global
             .route_table
             .subscribe(filter, session.client_id, granted_qos);
+        if config.events.is_enabled() {
+            global.emit_event(ClientEvent::Subscribed {
+                client_identifier: session.client_identifier.to_string(),
+                topic_filter: filter.to_string(),
+                qos: granted_qos as u8,
+            });
+        }
 
         let mut process_pendings = false;
-        for msg in global.retain_table.get_matches(filter) {
+        let retains = if config.retain_available {
+            global.retain_table.get_matches(filter)
+        } else {
+            Vec::new()
+        };
+        for msg in retains {
             if msg.qos <= granted_qos {
                 if let Some((final_qos, packet_opt)) = recv_publish(
                     session,
@@ -55,6 +96,7 @@ packet id : {}
                         subscribe_filter: filter,
                         subscribe_qos: granted_qos,
                     },
+                    global,
                 ) {
                     if let Some(packet) = packet_opt {
                         rv_packets.push(packet);
@@ -80,7 +122,7 @@ pub(crate) fn handle_unsubscribe(
     packet: &Unsubscribe,
     global: &Arc<GlobalState>,
 ) -> Packet {
-    log::debug!(
+    tracing::debug!(
         r#"{} received a unsubscribe packet:
 packet id : {}
    topics : {:?}"#,
@@ -88,9 +130,15 @@ packet id : {}
         packet.pid.value(),
         packet.topics,
     );
+    let events_enabled = global.config().events.is_enabled();
     for filter in &packet.topics {
         global.route_table.unsubscribe(filter, session.client_id);
-        session.subscribes.remove(filter);
+        if session.subscribes.remove(filter).is_some() && events_enabled {
+            global.emit_event(ClientEvent::Unsubscribed {
+                client_identifier: session.client_identifier.to_string(),
+                topic_filter: filter.to_string(),
+            });
+        }
     }
     Packet::Unsuback(packet.pid)
 }
@@ -4,26 +4,38 @@ use std::time::Instant;
 
 use bytes::Bytes;
 use hashbrown::HashMap;
-use mqtt_proto::{v3::LastWill, Pid, Protocol, QoS, TopicFilter, TopicName};
+use mqtt_proto::{
+    v3::{ConnectReturnCode, LastWill},
+    Pid, Protocol, QoS, TopicFilter, TopicName,
+};
 use parking_lot::RwLock;
+use tokio::sync::oneshot;
 
 use crate::config::Config;
 use crate::state::{ClientId, ClientReceiver};
 
-use super::super::{BroadcastPackets, PendingPackets};
+use super::super::{BroadcastPackets, IncomingQos2, PendingPackets, SessionInfo, SubscriptionInfo};
 
 pub struct Session {
     pub peer: SocketAddr,
     pub(super) connected: bool,
     pub(super) disconnected: bool,
+    // The return code of the error connack sent to client
+    pub(super) connect_error: Option<ConnectReturnCode>,
     pub(super) protocol: Protocol,
+    // The max QoS of the listener, override `Config.max_allowed_qos`
+    pub(super) listener_max_qos: Option<QoS>,
+    // The server keep alive of the listener, limit the client keep alive
+    pub(super) listener_keep_alive: Option<u16>,
+    // Connected with the bridge protocol (protocol level | 0x80)
+    pub(super) bridge: bool,
     pub connected_time: Option<Instant>,
     // last package timestamp
     pub last_packet_time: Arc<RwLock<Instant>>,
     // For record packet id send from server to client
     pub(super) server_packet_id: Pid,
     pub(super) pending_packets: PendingPackets<PubPacket>,
-    pub(super) qos2_pids: HashMap<Pid, u64>,
+    pub(super) qos2_pids: IncomingQos2,
 
     pub(super) client_id: ClientId,
     pub client_identifier: Arc<String>,
@@ -33,10 +45,15 @@ pub struct Session {
     pub clean_session: bool,
     pub last_will: Option<LastWill>,
     pub subscribes: HashMap<TopicFilter, QoS>,
+    // The attributes set by the hooks (see `HookAction::SetAttribute`), part of
+    // the session state
+    pub attributes: HashMap<String, String>,
 
     pub(super) broadcast_packets_max: usize,
-    pub(super) broadcast_packets_cnt: usize,
-    pub(super) broadcast_packets: HashMap<ClientId, BroadcastPackets>,
+    pub(super) broadcast_packets: BroadcastPackets,
+    // The Kafka receipt the PUBACK of the handling publish waits for (see
+    // `Config.kafka.wait_ack`)
+    pub(crate) kafka_receipt: Option<oneshot::Receiver<bool>>,
 }
 
 pub struct SessionState {
@@ -47,10 +64,10 @@ pub struct SessionState {
     // For record packet id send from server to client
     pub server_packet_id: Pid,
     pub pending_packets: PendingPackets<PubPacket>,
-    pub qos2_pids: HashMap<Pid, u64>,
+    pub qos2_pids: IncomingQos2,
     pub subscribes: HashMap<TopicFilter, QoS>,
-    pub broadcast_packets_cnt: usize,
-    pub broadcast_packets: HashMap<ClientId, BroadcastPackets>,
+    pub attributes: HashMap<String, String>,
+    pub broadcast_packets: BroadcastPackets,
 }
 
 impl Session {
@@ -59,16 +76,20 @@ impl Session {
             peer,
             connected: false,
             disconnected: false,
+            connect_error: None,
             protocol: Protocol::V311,
+            listener_max_qos: None,
+            listener_keep_alive: None,
+            bridge: false,
             connected_time: None,
             last_packet_time: Arc::new(RwLock::new(Instant::now())),
             server_packet_id: Pid::default(),
             pending_packets: PendingPackets::new(
                 config.max_inflight_client,
                 config.max_in_mem_pending_messages,
-                config.inflight_timeout,
+                config.retransmit.clone(),
             ),
-            qos2_pids: HashMap::new(),
+            qos2_pids: IncomingQos2::default(),
 
             client_id: ClientId::max_value(),
             client_identifier: Arc::new(String::new()),
@@ -78,12 +99,19 @@ impl Session {
             clean_session: true,
             last_will: None,
             subscribes: HashMap::new(),
-            broadcast_packets_max: 10,
-            broadcast_packets_cnt: 0,
-            broadcast_packets: HashMap::new(),
+            attributes: HashMap::new(),
+            broadcast_packets_max: config.broadcast.max_messages,
+            broadcast_packets: BroadcastPackets::default(),
+            kafka_receipt: None,
         }
     }
 
+    /// The max QoS the client can publish or subscribe
+    pub(crate) fn max_qos(&self, config: &Config) -> QoS {
+        self.listener_max_qos
+            .unwrap_or_else(|| config.max_allowed_qos())
+    }
+
     pub fn client_id(&self) -> ClientId {
         self.client_id
     }
@@ -93,6 +121,50 @@ impl Session {
         self.server_packet_id += 1;
         old_value
     }
+
+    pub(crate) fn info(&self, online: bool) -> SessionInfo {
+        let mut subscriptions: Vec<_> = self
+            .subscribes
+            .iter()
+            .map(|(filter, qos)| SubscriptionInfo {
+                topic_filter: filter.to_string(),
+                qos: *qos as u8,
+                no_local: None,
+                retain_as_published: None,
+                retain_handling: None,
+                subscription_id: None,
+            })
+            .collect();
+        subscriptions.sort_by(|a, b| a.topic_filter.cmp(&b.topic_filter));
+        let mut attributes: Vec<_> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        attributes.sort();
+        SessionInfo {
+            client_identifier: self.client_identifier.to_string(),
+            protocol: format!("{:?}", self.protocol),
+            online,
+            peer: self.peer,
+            username: self.username.as_ref().map(|name| name.to_string()),
+            keep_alive: self.keep_alive,
+            clean_start: self.clean_session,
+            inflight_pids: self
+                .pending_packets
+                .inflight_pids()
+                .into_iter()
+                .map(|pid| pid.value())
+                .collect(),
+            pending_len: self.pending_packets.len(),
+            incoming_qos2_pids: self.qos2_pids.pids(),
+            subscriptions,
+            session_expiry_interval: None,
+            session_expiry_at: None,
+            has_will: self.last_will.is_some(),
+            attributes,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1,3 +1,4 @@
+use std::cmp;
 use std::collections::VecDeque;
 use std::io;
 use std::mem::{self, MaybeUninit};
@@ -18,21 +19,31 @@ use mqtt_proto::{
     Error, Pid, Protocol, QoS, QosPid,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{Instrument, Span};
 
+use crate::audit::AuditEvent;
+use crate::config::RetransmitConfig;
+use crate::diagnostics;
+use crate::error::Error as AkasaError;
+use crate::events::ClientEvent;
 use crate::hook::{
-    handle_request, Hook, HookAction, HookRequest, HookResponse, LockedHookContext, PublishAction,
-    SubscribeAction, UnsubscribeAction,
+    handle_request, Hook, HookAction, HookConnectCode, HookPublishCode, HookRequest, HookResponse,
+    LockedHookContext, PublishAction, SubscribeAction, UnsubscribeAction,
 };
 use crate::protocols::mqtt::{
-    BroadcastPackets, OnlineLoop, OnlineSession, PendingPackets, WritePacket,
+    auto_subscriptions, get_unix_ts, BroadcastPackets, IncomingQos2, OnlineLoop, OnlineSession,
+    PacketDirection, PendingPackets, WritePacket,
 };
+use crate::sparkplug;
 use crate::state::{ClientId, ClientReceiver, ControlMessage, GlobalState, NormalMessage};
+use crate::timer::{self, TimerEvent};
 
 use super::{
     packet::{
         common::{
-            after_handle_packet, build_error_connack, build_error_disconnect, handle_pendings,
-            write_packet,
+            after_handle_packet, build_error_connack, build_error_disconnect,
+            build_redirect_connack, build_redirect_disconnect, build_typed_disconnect,
+            handle_pendings, write_packet,
         },
         connect::{handle_auth, handle_connect, handle_disconnect, session_connect},
         publish::{
@@ -54,6 +65,8 @@ pub async fn handle_connection<
     header: Header,
     protocol: Protocol,
     timeout_receiver: Receiver<()>,
+    listener_max_qos: Option<QoS>,
+    listener_keep_alive: Option<u16>,
     hook_handler: H,
     global: Arc<GlobalState>,
 ) -> io::Result<()> {
@@ -63,13 +76,15 @@ pub async fn handle_connection<
         header,
         protocol,
         timeout_receiver,
+        listener_max_qos,
+        listener_keep_alive,
         &hook_handler,
         &global,
     )
     .await
     {
         Ok(Some((session, receiver))) => {
-            log::info!(
+            tracing::info!(
                 "{}({}) go to offline, total {} clients ({} online)",
                 session.client_id,
                 peer,
@@ -77,27 +92,15 @@ pub async fn handle_connection<
                 global.online_clients_count(),
             );
             let session_expiry = Duration::from_secs(session.session_expiry_interval as u64);
-            let client_id = session.client_id;
-            let connected_time = session.connected_time.expect("connected time");
-            let global_clone = Arc::clone(&global);
-            tokio::spawn(async move {
-                // FIXME: potencial memory leak
-                tokio::time::sleep(session_expiry).await;
-                if let Some(sender) = global_clone.get_client_control_sender(&client_id) {
-                    let msg = ControlMessage::SessionExpired { connected_time };
-                    if let Err(err) = sender.send_async(msg).await {
-                        log::warn!(
-                            "send session expired message to {} error: {:?}",
-                            client_id,
-                            err
-                        );
-                    }
-                }
-            });
-            tokio::spawn(handle_offline(session, receiver, global));
+            let event = TimerEvent::SessionExpired {
+                client_id: session.client_id,
+                connected_time: session.connected_time.expect("connected time"),
+            };
+            timer::schedule(&global, session_expiry, event);
+            tokio::spawn(handle_offline(session, receiver, global).in_current_span());
         }
         Ok(None) => {
-            log::info!(
+            tracing::info!(
                 "{} finished, total {} clients ({} online)",
                 peer,
                 global.clients_count(),
@@ -105,7 +108,7 @@ pub async fn handle_connection<
             );
         }
         Err(err) => {
-            log::info!(
+            tracing::info!(
                 "{} error: {}, total {} clients ({} online)",
                 peer,
                 err,
@@ -128,15 +131,19 @@ async fn handle_online<
     header: Header,
     protocol: Protocol,
     timeout_receiver: Receiver<()>,
+    listener_max_qos: Option<QoS>,
+    listener_keep_alive: Option<u16>,
     hook_handler: &H,
     global: &Arc<GlobalState>,
 ) -> io::Result<Option<(Session, ClientReceiver)>> {
-    let mut session = Session::new(&global.config, peer);
+    let mut session = Session::new(&global.config(), peer);
+    session.listener_max_qos = listener_max_qos;
+    session.listener_keep_alive = listener_keep_alive;
     let mut receiver = None;
 
     let timeout = async {
         let _ = timeout_receiver.recv_async().await;
-        log::info!("timeout when decode connect packet: {}", peer);
+        tracing::info!("timeout when decode connect packet: {}", peer);
         Err(Error::IoError(io::ErrorKind::TimedOut, String::new()).into())
     };
     let packet = match Connect::decode_with_protocol(&mut conn, header, protocol)
@@ -145,8 +152,8 @@ async fn handle_online<
     {
         Ok(packet) => packet,
         Err(err) => {
-            return match err {
-                ErrorV5::Common(Error::IoError(kind, _str)) => Err(kind.into()),
+            let err: io::Error = match err {
+                ErrorV5::Common(Error::IoError(kind, _str)) => kind.into(),
                 ErrorV5::Common(err) => {
                     let err_pkt = build_error_connack(
                         &mut session,
@@ -154,8 +161,15 @@ async fn handle_online<
                         ConnectReasonCode::MalformedPacket,
                         err.to_string(),
                     );
-                    write_packet(session.client_id, &mut conn, &err_pkt).await?;
-                    Err(io::ErrorKind::InvalidData.into())
+                    write_packet(
+                        session.client_id,
+                        &session.client_identifier,
+                        &mut conn,
+                        &err_pkt,
+                        global,
+                    )
+                    .await?;
+                    AkasaError::MalformedPacket(err.to_string()).into()
                 }
                 err => {
                     let err_pkt = build_error_connack(
@@ -164,20 +178,56 @@ async fn handle_online<
                         ConnectReasonCode::MalformedPacket,
                         err.to_string(),
                     );
-                    write_packet(session.client_id, &mut conn, &err_pkt).await?;
-                    Err(io::ErrorKind::InvalidData.into())
+                    write_packet(
+                        session.client_id,
+                        &session.client_identifier,
+                        &mut conn,
+                        &err_pkt,
+                        global,
+                    )
+                    .await?;
+                    AkasaError::MalformedPacket(err.to_string()).into()
                 }
-            }
+            };
+            diagnostics::record_error(global, None, peer, format!("{:?}", protocol), &err);
+            return Err(err);
         }
     };
 
+    if global.packet_tracer.is_active() {
+        let connect = Packet::Connect(packet.clone());
+        global
+            .packet_tracer
+            .record(&packet.client_id, PacketDirection::In, &connect);
+    }
+    let client_identifier = Arc::clone(&packet.client_id);
+    let username = packet.username.clone();
+
     // Run before connect hook
-    if global.config.hook.enable_before_connect {
-        before_connect_hook(&mut session, &mut conn, peer, &packet, hook_handler, global).await?;
+    if global.config().hook.enable_before_connect {
+        if let Err(err) =
+            before_connect_hook(&mut session, &mut conn, peer, &packet, hook_handler, global).await
+        {
+            audit_connect(&session, &client_identifier, &username, global);
+            return Err(err);
+        }
     }
 
     let mut session_present =
-        handle_connect(&mut session, &mut receiver, packet, &mut conn, global).await?;
+        handle_connect(&mut session, &mut receiver, packet, &mut conn, global)
+            .await
+            .map_err(|err| {
+                let protocol = format!("{:?}", protocol);
+                diagnostics::record_error(
+                    global,
+                    Some(client_identifier.as_str()),
+                    peer,
+                    protocol,
+                    &err,
+                );
+                err
+            })?;
+    Span::current().record("client_id", session.client_identifier.as_str());
 
     // * Scram challenge only need 1 round.
     // * Kerberos challenge need 2 rounds.
@@ -197,8 +247,15 @@ async fn handle_online<
                             ConnectReasonCode::MalformedPacket,
                             err.to_string(),
                         );
-                        write_packet(session.client_id, &mut conn, &err_pkt).await?;
-                        Err(io::ErrorKind::InvalidData.into())
+                        write_packet(
+                            session.client_id,
+                            &session.client_identifier,
+                            &mut conn,
+                            &err_pkt,
+                            global,
+                        )
+                        .await?;
+                        Err(AkasaError::MalformedPacket(err.to_string()).into())
                     }
                     err => {
                         let err_pkt = build_error_connack(
@@ -207,25 +264,51 @@ async fn handle_online<
                             ConnectReasonCode::MalformedPacket,
                             err.to_string(),
                         );
-                        write_packet(session.client_id, &mut conn, &err_pkt).await?;
-                        Err(io::ErrorKind::InvalidData.into())
+                        write_packet(
+                            session.client_id,
+                            &session.client_identifier,
+                            &mut conn,
+                            &err_pkt,
+                            global,
+                        )
+                        .await?;
+                        Err(AkasaError::MalformedPacket(err.to_string()).into())
                     }
                 },
             }
         }
         .or(async {
             let _ = timeout_receiver.recv_async().await;
-            log::info!("timeout when decode auth packet: {}", peer);
-            Err(io::Error::from(io::ErrorKind::TimedOut))
+            tracing::info!("timeout when decode auth packet: {}", peer);
+            Err(io::Error::from(AkasaError::ConnectTimeout))
         })
-        .await?;
+        .await
+        .map_err(|err| {
+            let protocol = format!("{:?}", protocol);
+            diagnostics::record_error(
+                global,
+                Some(client_identifier.as_str()),
+                peer,
+                protocol,
+                &err,
+            );
+            err
+        })?;
         let auth = match packet {
             Packet::Auth(pkt) => pkt,
             _ => {
-                log::info!("Not connected, only AUTH packet is allowed");
+                tracing::info!("Not connected, only AUTH packet is allowed");
                 let err_pkt =
                     build_error_connack(&mut session, false, ConnectReasonCode::ProtocolError, "");
-                write_packet(session.client_id, &mut conn, &err_pkt).await?;
+                write_packet(
+                    session.client_id,
+                    &session.client_identifier,
+                    &mut conn,
+                    &err_pkt,
+                    global,
+                )
+                .await?;
+                audit_connect(&session, &client_identifier, &username, global);
                 return Ok(None);
             }
         };
@@ -252,11 +335,26 @@ async fn handle_online<
                         user_properties: Vec::new(),
                     },
                 };
-                write_packet(session.client_id, &mut conn, &rv_packet.into()).await?;
+                write_packet(
+                    session.client_id,
+                    &session.client_identifier,
+                    &mut conn,
+                    &rv_packet.into(),
+                    global,
+                )
+                .await?;
             }
             Ok((AuthReasonCode::ReAuthentication, _)) => unreachable!(),
             Err(err_pkt) => {
-                write_packet(session.client_id, &mut conn, &err_pkt).await?;
+                write_packet(
+                    session.client_id,
+                    &session.client_identifier,
+                    &mut conn,
+                    &err_pkt,
+                    global,
+                )
+                .await?;
+                audit_connect(&session, &client_identifier, &username, global);
                 return Ok(None);
             }
         }
@@ -264,26 +362,58 @@ async fn handle_online<
     drop(timeout_receiver);
 
     if !session.connected {
-        log::info!("{} not connected", session.peer);
-        return Err(io::ErrorKind::InvalidData.into());
+        tracing::info!("{} not connected", session.peer);
+        audit_connect(&session, &client_identifier, &username, global);
+        let code = session.connect_error.map(|code| format!("{:?}", code));
+        return Err(AkasaError::ConnectRejected(code.unwrap_or_default()).into());
     }
 
     // Run after connect hook
-    if global.config.hook.enable_before_connect {
+    if global.config().hook.enable_before_connect {
         after_connect_hook(&mut session, session_present, hook_handler, global).await?;
     }
+    let topics = auto_subscriptions(
+        &global.config(),
+        &session.client_identifier,
+        session.username.as_ref().map(|name| name.as_str()),
+        &session.attributes,
+    );
+    if !topics.is_empty() {
+        session.apply_action(HookAction::Subscribe(SubscribeAction(topics)), global)?;
+    }
 
     for packet in after_handle_packet(&mut session) {
-        write_packet(session.client_id, &mut conn, &packet).await?;
+        write_packet(
+            session.client_id,
+            &session.client_identifier,
+            &mut conn,
+            &packet,
+            global,
+        )
+        .await?;
     }
 
     let receiver = receiver.expect("receiver");
-    log::info!(
+    tracing::info!(
         "{} connected, total {} clients ({} online) ",
         session.peer,
         global.clients_count(),
         global.online_clients_count(),
     );
+    audit_connect(
+        &session,
+        &session.client_identifier,
+        &session.username,
+        global,
+    );
+    global.emit_event(ClientEvent::Connected {
+        client_identifier: session.client_identifier.to_string(),
+        username: session.username.as_ref().map(|name| name.to_string()),
+        peer: session.peer,
+        protocol: format!("{:?}", Protocol::V500),
+        clean_start: session.clean_start,
+        keep_alive: session.keep_alive,
+    });
 
     let mut taken_over = false;
     let online_loop = OnlineLoop::new(
@@ -298,14 +428,19 @@ async fn handle_online<
         PollPacketState::default(),
     );
     let io_error = online_loop.await;
-    if global.config.hook.enable_after_disconnect {
+    // Already cancelled by the online loop when taken over
+    if !taken_over {
+        global.inbound_limiter.cancel(session.client_id);
+    }
+    record_disconnect(&session, taken_over, io_error.as_ref(), global);
+    if global.config().hook.enable_after_disconnect {
         after_disconnect_hook(&mut session, taken_over, hook_handler, global).await?;
     }
     if taken_over {
         return Ok(None);
     }
 
-    log::debug!(
+    tracing::debug!(
         "[{}] online loop finished, client_disconnected={}, server_disconnected={}",
         session.client_id,
         session.client_disconnected,
@@ -313,9 +448,15 @@ async fn handle_online<
     );
     // FIXME: check all place depend on session.disconnected
     if !session.client_disconnected {
+        if global.config().hook.enable_publish && session.last_will.is_some() {
+            before_will_hook(&mut session, hook_handler, global).await;
+        }
         handle_will(&mut session, global).await?;
     }
-    broadcast_packets(&mut session).await;
+    session
+        .broadcast_packets
+        .send_all(session.client_id, global)
+        .await;
     if session.session_expiry_interval == 0 {
         global.remove_client(session.client_id, session.subscribes.keys());
         if let Some(err) = io_error {
@@ -340,18 +481,19 @@ impl OnlineSession for Session {
     fn client_id(&self) -> ClientId {
         self.client_id
     }
+    fn client_identifier(&self) -> &str {
+        &self.client_identifier
+    }
     fn disconnected(&self) -> bool {
         self.client_disconnected || self.server_disconnected
     }
     fn build_state(&mut self, receiver: ClientReceiver) -> Self::SessionState {
-        let mut pending_packets = PendingPackets::new(0, 0, 0);
-        let mut qos2_pids = HashMap::new();
+        let mut pending_packets = PendingPackets::new(0, 0, RetransmitConfig::default());
+        let mut qos2_pids = IncomingQos2::default();
         let mut subscribes = HashMap::new();
-        let mut broadcast_packets = HashMap::new();
         mem::swap(&mut self.pending_packets, &mut pending_packets);
         mem::swap(&mut self.qos2_pids, &mut qos2_pids);
         mem::swap(&mut self.subscribes, &mut subscribes);
-        mem::swap(&mut self.broadcast_packets, &mut broadcast_packets);
         SessionState {
             client_id: self.client_id,
             receiver,
@@ -361,30 +503,42 @@ impl OnlineSession for Session {
             pending_packets,
             qos2_pids,
             subscribes,
-            broadcast_packets_cnt: self.broadcast_packets_cnt,
-            broadcast_packets,
+            attributes: mem::take(&mut self.attributes),
+            broadcast_packets: mem::take(&mut self.broadcast_packets),
         }
     }
 
-    fn consume_broadcast(&mut self, count: usize) {
-        self.broadcast_packets_cnt -= count;
-    }
     fn broadcast_packets_cnt(&self) -> usize {
-        self.broadcast_packets_cnt
+        self.broadcast_packets.len()
     }
     fn broadcast_packets_max(&self) -> usize {
         self.broadcast_packets_max
     }
-    fn broadcast_packets(&mut self) -> &mut HashMap<ClientId, BroadcastPackets> {
+    fn broadcast_packets(&mut self) -> &mut BroadcastPackets {
         &mut self.broadcast_packets
     }
 
+    fn is_publish(packet: &Self::Packet) -> bool {
+        matches!(packet, Packet::Publish(_))
+    }
+
+    fn skip_hook_queue(packet: &Self::Packet) -> bool {
+        matches!(
+            packet,
+            Packet::Pingreq
+                | Packet::Puback(_)
+                | Packet::Pubrec(_)
+                | Packet::Pubrel(_)
+                | Packet::Pubcomp(_)
+        )
+    }
+
     fn handle_decode_error(
         &mut self,
         err: Self::Error,
         write_packets: &mut VecDeque<WritePacket<Self::Packet>>,
     ) -> Result<(), Option<io::Error>> {
-        log::debug!("[{}] mqtt v5.x codec error: {}", self.client_id, err);
+        tracing::debug!("[{}] mqtt v5.x codec error: {}", self.client_id, err);
         match err {
             ErrorV5::Common(Error::IoError(kind, _str)) => {
                 if kind == io::ErrorKind::UnexpectedEof {
@@ -426,11 +580,12 @@ impl OnlineSession for Session {
         write_packets: &mut VecDeque<WritePacket<Self::Packet>>,
         global: &Arc<GlobalState>,
     ) -> Result<Option<HookRequest>, Option<io::Error>> {
-        if encode_len > global.config.max_packet_size_server as usize {
-            log::debug!(
+        tracing::trace!(encode_len, ?packet, "received packet");
+        if encode_len > global.config().max_packet_size_server as usize {
+            tracing::debug!(
                 "packet too large, size={}, max={}",
                 encode_len,
-                global.config.max_packet_size_server
+                global.config().max_packet_size_server
             );
             let err_pkt = build_error_disconnect(
                 self,
@@ -443,13 +598,13 @@ impl OnlineSession for Session {
 
         match packet {
             Packet::Disconnect(pkt) => {
-                if let Err(err_pkt) = handle_disconnect(self, pkt) {
+                if let Err(err_pkt) = handle_disconnect(self, pkt, &global.config()) {
                     // FIXME: must ensure error packet finally written to client in several seconds.
                     write_packets.push_back(err_pkt.into());
                 }
             }
             Packet::Publish(pkt) => {
-                if global.config.hook.enable_publish {
+                if global.config().hook.enable_publish {
                     let locked_hook_context = LockedHookContext::new(self, write_packets);
                     let hook_request = HookRequest::V5Publish {
                         context: locked_hook_context,
@@ -463,7 +618,20 @@ impl OnlineSession for Session {
                         // QoS0
                         Ok(None) => {}
                         // QoS1, QoS2
-                        Ok(Some(packet)) => write_packets.push_back(packet.into()),
+                        Ok(Some(packet)) => match self.kafka_receipt.take() {
+                            // The PUBACK waits for the Kafka receipt like a hook request
+                            Some(receipt) => {
+                                let locked_hook_context =
+                                    LockedHookContext::new(self, write_packets);
+                                let hook_request = HookRequest::V5KafkaAck {
+                                    context: locked_hook_context,
+                                    receipt,
+                                    packet,
+                                };
+                                return Ok(Some(hook_request));
+                            }
+                            None => write_packets.push_back(packet.into()),
+                        },
                         Err(err_pkt) => write_packets.push_back(err_pkt.into()),
                     }
                 }
@@ -473,7 +641,7 @@ impl OnlineSession for Session {
             Packet::Pubrel(pkt) => write_packets.push_back(handle_pubrel(self, pkt).into()),
             Packet::Pubcomp(pkt) => handle_pubcomp(self, pkt),
             Packet::Subscribe(pkt) => {
-                if global.config.hook.enable_subscribe {
+                if global.config().hook.enable_subscribe {
                     let locked_hook_context = LockedHookContext::new(self, write_packets);
                     let hook_request = HookRequest::V5Subscribe {
                         context: locked_hook_context,
@@ -492,7 +660,7 @@ impl OnlineSession for Session {
                 }
             }
             Packet::Unsubscribe(pkt) => {
-                if global.config.hook.enable_unsubscribe {
+                if global.config().hook.enable_unsubscribe {
                     let locked_hook_context = LockedHookContext::new(self, write_packets);
                     let hook_request = HookRequest::V5Unsubscribe {
                         context: locked_hook_context,
@@ -506,16 +674,18 @@ impl OnlineSession for Session {
                 }
             }
             Packet::Pingreq => {
-                log::debug!("{} received a ping packet", self.client_id);
+                tracing::debug!("{} received a ping packet", self.client_id);
                 write_packets.push_back(Packet::Pingresp.into())
             }
             _ => {
-                log::info!(
+                tracing::info!(
                     "[{}] received a invalid packet: {:?}",
                     self.client_id,
                     packet
                 );
-                return Err(Some(io::ErrorKind::InvalidData.into()));
+                return Err(Some(
+                    AkasaError::Protocol("invalid packet".to_owned()).into(),
+                ));
             }
         }
         Ok(None)
@@ -558,7 +728,7 @@ impl OnlineSession for Session {
                         properties: publish_properties.clone(),
                     };
                     Packet::Publish(publish).encode_len().map_err(|_| {
-                        log::error!("action publish message too large");
+                        tracing::error!("action publish message too large");
                         io::Error::from(io::ErrorKind::InvalidData)
                     })?
                 };
@@ -571,6 +741,7 @@ impl OnlineSession for Session {
                         payload: &payload,
                         properties: &publish_properties,
                         encode_len,
+                        received_at: Instant::now(),
                     },
                     global,
                 );
@@ -601,21 +772,32 @@ impl OnlineSession for Session {
                                     | SubscribeReasonCode::GrantedQoS1
                                     | SubscribeReasonCode::GrantedQoS2 => {}
                                     code => {
-                                        log::error!("action subscribe message error reason code: {:?}, topics={:?}", code, topics,);
+                                        tracing::error!("action subscribe message error reason code: {:?}, topics={:?}", code, topics,);
                                         break;
                                     }
                                 }
                             }
                         }
-                        _ => log::error!("action subscribe message invalid (retain included)"),
+                        _ => tracing::error!("action subscribe message invalid (retain included)"),
                     },
-                    Err(err) => log::error!("action subscribe message invalid: {:?}", err),
+                    Err(err) => tracing::error!("action subscribe message invalid: {:?}", err),
                 }
             }
             HookAction::Unsubscribe(UnsubscribeAction(topics)) => {
                 let unsubscribe = Unsubscribe::new(Pid::default(), topics);
                 let _unsuback = handle_unsubscribe(self, &unsubscribe, global);
             }
+            HookAction::SetMaxInflight(value) => {
+                if value == 0 {
+                    tracing::error!("action set max inflight to 0 is not allowed");
+                } else {
+                    let value = cmp::min(value, self.receive_max);
+                    self.pending_packets.set_max_inflight(value);
+                }
+            }
+            HookAction::SetAttribute(key, value) => {
+                self.attributes.insert(key, value);
+            }
         }
         Ok(())
     }
@@ -633,13 +815,42 @@ impl OnlineSession for Session {
         sender: ClientId,
         msg: NormalMessage,
         global: &Arc<GlobalState>,
-    ) -> Option<(QoS, Option<Self::Packet>)> {
+    ) -> Option<(QoS, Option<WritePacket<Packet>>)> {
         handle_normal(self, sender, msg, global)
+            .map(|(qos, packet_opt)| (qos, packet_opt.map(WritePacket::Packet)))
     }
 
     fn handle_pendings(&mut self) -> Vec<Packet> {
         handle_pendings(self)
     }
+    fn pending_packets_len(&self) -> usize {
+        self.pending_packets.len()
+    }
+    fn slow_consumer_disconnect(&mut self) -> Option<Packet> {
+        Some(build_typed_disconnect(self, &AkasaError::SlowConsumer))
+    }
+    fn retries_exhausted(&self) -> bool {
+        self.pending_packets.retries_exhausted(get_unix_ts())
+    }
+    fn retries_exhausted_disconnect(&mut self) -> Option<Packet> {
+        Some(build_typed_disconnect(
+            self,
+            &AkasaError::RetransmissionsExhausted,
+        ))
+    }
+    fn control_disconnect(&mut self) -> Option<Packet> {
+        let (server_reference, permanent) = self.redirect.take()?;
+        let reason_code = if permanent {
+            DisconnectReasonCode::ServerMoved
+        } else {
+            DisconnectReasonCode::UseAnotherServer
+        };
+        Some(build_redirect_disconnect(
+            self,
+            reason_code,
+            server_reference,
+        ))
+    }
 }
 
 async fn handle_offline(mut session: Session, receiver: ClientReceiver, global: Arc<GlobalState>) {
@@ -652,20 +863,23 @@ async fn handle_offline(mut session: Session, receiver: ClientReceiver, global:
                     if let Some(sender) = sender_opt {
                         let old_state = session.build_state(receiver);
                         if let Err(err) = sender.send_async(old_state).await {
-                            log::warn!("offline send session state failed: {err:?}");
+                            tracing::warn!("offline send session state failed: {err:?}");
                         } else {
                             taken_over = true;
                         }
                         break;
                     }
                     // Because send_will is not a async_function
-                    broadcast_packets(&mut session).await;
+                    session
+                        .broadcast_packets
+                        .send_all(session.client_id, &global)
+                        .await;
                     if stop {
                         break;
                     }
                 }
                 Err(err) => {
-                    log::warn!("offline client receive control message error: {err:?}");
+                    tracing::warn!("offline client receive control message error: {err:?}");
                     break;
                 }
             },
@@ -674,7 +888,7 @@ async fn handle_offline(mut session: Session, receiver: ClientReceiver, global:
                     let _ = handle_normal(&mut session, sender, msg, &global);
                 },
                 Err(err) => {
-                    log::warn!("offline client receive normal message error: {err:?}");
+                    tracing::warn!("offline client receive normal message error: {err:?}");
                     break;
                 }
             }
@@ -683,7 +897,7 @@ async fn handle_offline(mut session: Session, receiver: ClientReceiver, global:
     if !taken_over {
         global.remove_client(session.client_id, session.subscribes.keys());
     }
-    log::debug!("offline client finished: {:?}", session.client_id());
+    tracing::debug!("offline client finished: {:?}", session.client_id());
 }
 
 #[inline]
@@ -691,29 +905,18 @@ async fn handle_will(session: &mut Session, global: &Arc<GlobalState>) -> io::Re
     if let Some(last_will) = session.last_will.as_ref() {
         let delay_interval = last_will.properties.delay_interval.unwrap_or(0);
         if delay_interval == 0 {
-            log::debug!(
+            tracing::debug!(
                 "[{}] broadcast_packets.len(): {}",
                 session.client_id,
                 session.broadcast_packets.len()
             );
             send_will(session, global)?;
         } else if delay_interval < session.session_expiry_interval {
-            let client_id = session.client_id;
-            let connected_time = session.connected_time.expect("connected time (will)");
-            let global = Arc::clone(global);
-            tokio::spawn(async move {
-                tokio::time::sleep(Duration::from_secs(delay_interval as u64)).await;
-                if let Some(sender) = global.get_client_control_sender(&client_id) {
-                    let msg = ControlMessage::WillDelayReached { connected_time };
-                    if let Err(err) = sender.send_async(msg).await {
-                        log::warn!(
-                            "send will delay reached message to {} error: {:?}",
-                            client_id,
-                            err
-                        );
-                    }
-                }
-            });
+            let event = TimerEvent::WillDelayReached {
+                client_id: session.client_id,
+                connected_time: session.connected_time.expect("connected time (will)"),
+            };
+            timer::schedule(global, Duration::from_secs(delay_interval as u64), event);
         } else {
             // Handle will in SessionExpired event
         }
@@ -730,18 +933,19 @@ fn handle_control(
 ) -> (bool, Option<Sender<SessionState>>) {
     let mut stop = false;
     match msg {
+        #[cfg(feature = "v3")]
         ControlMessage::OnlineV3 { .. } => {
-            log::warn!("take over v5.x session by v3.x client is not allowed");
+            tracing::warn!("take over v5.x session by v3.x client is not allowed");
         }
         ControlMessage::OnlineV5 { sender } => return (false, Some(sender)),
         ControlMessage::Kick { reason } => {
             if offline {
-                log::info!(
+                tracing::info!(
                     "ignore kick message when client {} is offline",
                     session.client_id
                 );
             } else {
-                log::info!(
+                tracing::info!(
                     "kick \"{}\", reason: {}, online: {}",
                     session.client_identifier,
                     reason,
@@ -750,18 +954,41 @@ fn handle_control(
                 stop = true;
             }
         }
+        ControlMessage::Inspect { sender } => {
+            let _ = sender.try_send(session.info(!offline));
+        }
+        ControlMessage::Redirect {
+            server_reference,
+            permanent,
+        } => {
+            if offline {
+                tracing::info!(
+                    "ignore redirect message when client {} is offline",
+                    session.client_id
+                );
+            } else {
+                tracing::info!(
+                    "redirect \"{}\" to {}, permanent: {}",
+                    session.client_identifier,
+                    server_reference,
+                    permanent,
+                );
+                session.redirect = Some((server_reference, permanent));
+                stop = true;
+            }
+        }
         ControlMessage::SessionExpired { connected_time } => {
-            log::debug!("client \"{}\" session expired", session.client_identifier);
+            tracing::debug!("client \"{}\" session expired", session.client_identifier);
             if !session.connected && session.connected_time == Some(connected_time) {
                 if send_will(session, global).is_err() {
-                    log::warn!("send will failed (packet too large)");
+                    tracing::warn!("send will failed (packet too large)");
                 }
                 global.remove_client(session.client_id, session.subscribes.keys());
                 stop = true;
             }
         }
         ControlMessage::WillDelayReached { connected_time } => {
-            log::debug!(
+            tracing::debug!(
                 "client \"{}\" will delay reached, connected={}",
                 session.client_identifier,
                 session.connected,
@@ -770,7 +997,7 @@ fn handle_control(
                 && session.connected_time == Some(connected_time)
                 && send_will(session, global).is_err()
             {
-                log::warn!("send will failed (packet too large)");
+                tracing::warn!("send will failed (packet too large)");
             }
         }
     }
@@ -795,14 +1022,16 @@ fn handle_normal(
             ref subscribe_filter,
             subscribe_qos,
             encode_len,
+            encoded_v3: _,
+            received_at: _,
         } => {
-            log::debug!(
+            tracing::debug!(
                 "[{}] received v3 publish message from {}",
                 session.client_id,
                 sender
             );
             if let Some(sub) = session.subscribes.get(subscribe_filter) {
-                if !global.config.retain_available || !sub.options.retain_as_published {
+                if !global.config().retain_available || !sub.options.retain_as_published {
                     retain = false;
                 }
                 recv_publish(
@@ -818,6 +1047,7 @@ fn handle_normal(
                         // one byte is for property length
                         encode_len: encode_len + 1,
                     },
+                    global,
                 )
             } else {
                 None
@@ -832,15 +1062,17 @@ fn handle_normal(
             subscribe_qos,
             ref properties,
             encode_len,
+            encoded_v3: _,
+            received_at: _,
         } => {
-            log::debug!(
+            tracing::debug!(
                 "[{}] received v5 publish message from {}, msg: {:?}",
                 session.client_id,
                 sender,
                 msg
             );
             if let Some(sub) = session.subscribes.get(subscribe_filter) {
-                if !global.config.retain_available || !sub.options.retain_as_published {
+                if !global.config().retain_available || !sub.options.retain_as_published {
                     retain = false;
                 }
                 recv_publish(
@@ -852,9 +1084,10 @@ fn handle_normal(
                         payload,
                         subscribe_filter,
                         subscribe_qos,
-                        properties: Some(properties),
+                        properties: Some(properties.as_ref()),
                         encode_len,
                     },
+                    global,
                 )
             } else {
                 None
@@ -867,7 +1100,11 @@ fn handle_normal(
 #[inline]
 fn send_will(session: &mut Session, global: &Arc<GlobalState>) -> io::Result<()> {
     if let Some(last_will) = session.last_will.take() {
-        log::debug!("[{}] send will", session.client_id);
+        tracing::debug!("[{}] send will", session.client_id);
+        // The NDEATH of a Sparkplug B edge node is its will
+        if sparkplug::check(global, &last_will.topic_name, &last_will.payload) {
+            return Ok(());
+        }
         let properties = last_will.properties;
         let publish_properties = PublishProperties {
             payload_is_utf8: properties.payload_is_utf8,
@@ -906,6 +1143,7 @@ fn send_will(session: &mut Session, global: &Arc<GlobalState>) -> io::Result<()>
                 payload: &last_will.payload,
                 properties: &publish_properties,
                 encode_len,
+                received_at: Instant::now(),
             },
             global,
         );
@@ -913,32 +1151,6 @@ fn send_will(session: &mut Session, global: &Arc<GlobalState>) -> io::Result<()>
     Ok(())
 }
 
-async fn broadcast_packets(session: &mut Session) {
-    for (target_id, info) in session.broadcast_packets.drain() {
-        for msg in info.msgs {
-            log::debug!(
-                "[{}] broadcast to [{}], {:?}",
-                session.client_id,
-                target_id,
-                msg
-            );
-            if let Err(err) = info
-                .sink
-                .sender()
-                .send_async((session.client_id, msg))
-                .await
-            {
-                log::warn!(
-                    "[{}] handle will, send broadcast message to {} failed: {:?}",
-                    session.client_id,
-                    target_id,
-                    err
-                )
-            }
-        }
-    }
-}
-
 async fn before_connect_hook<T: AsyncWrite + Unpin, H: Hook + Clone + Send + Sync>(
     session: &mut Session,
     conn: &mut T,
@@ -952,18 +1164,82 @@ async fn before_connect_hook<T: AsyncWrite + Unpin, H: Hook + Clone + Send + Syn
         connect: packet.clone(),
     };
 
-    let code = match handle_request(hook_request, hook_handler.clone(), global.clone()).await {
-        HookResponse::BeforeConnect(result) => result?.to_v5_code(),
+    let hook_code = match handle_request(hook_request, hook_handler.clone(), global.clone()).await {
+        HookResponse::BeforeConnect(result) => result?,
         _ => panic!("invalid response"),
     };
+    let code = hook_code.to_v5_code();
     if code != ConnectReasonCode::Success {
-        let err_pkt = build_error_connack(session, false, code, "");
-        write_packet(session.client_id, conn, &err_pkt).await?;
-        return Err(io::ErrorKind::InvalidData.into());
+        let server_reference = match hook_code {
+            HookConnectCode::UseAnotherServer | HookConnectCode::ServerMoved => {
+                hook_handler.v5_server_reference(peer, packet)
+            }
+            _ => None,
+        };
+        let err_pkt = match server_reference {
+            Some(server_reference) => build_redirect_connack(session, code, server_reference),
+            None => build_error_connack(session, false, code, ""),
+        };
+        write_packet(session.client_id, &packet.client_id, conn, &err_pkt, global).await?;
+        return Err(AkasaError::ConnectRejected(format!("{:?}", code)).into());
     }
     Ok(())
 }
 
+fn audit_connect(
+    session: &Session,
+    client_identifier: &str,
+    username: &Option<Arc<String>>,
+    global: &GlobalState,
+) {
+    global.audit(AuditEvent::Connect {
+        client_identifier: client_identifier.to_owned(),
+        username: username.as_ref().map(|name| name.to_string()),
+        peer: session.peer,
+        protocol: format!("{:?}", Protocol::V500),
+        success: session.connected,
+        reason: session.connect_error.map(|code| format!("{:?}", code)),
+    });
+}
+
+// Record the disconnect to the audit log and the event stream
+fn record_disconnect(
+    session: &Session,
+    taken_over: bool,
+    io_error: Option<&io::Error>,
+    global: &GlobalState,
+) {
+    let reason = if taken_over {
+        "taken over".to_owned()
+    } else if session.client_disconnected {
+        "client disconnected".to_owned()
+    } else if session.server_disconnected {
+        "server disconnected".to_owned()
+    } else if let Some(err) = io_error {
+        err.to_string()
+    } else {
+        "connection closed".to_owned()
+    };
+    let protocol = format!("{:?}", session.protocol);
+    let client_identifier = Some(session.client_identifier.as_str());
+    if let Some(reason) = &session.violation {
+        diagnostics::record(global, client_identifier, session.peer, protocol, reason);
+    } else if let Some(err) = io_error {
+        diagnostics::record_error(global, client_identifier, session.peer, protocol, err);
+    }
+    global.audit(AuditEvent::Disconnect {
+        client_identifier: session.client_identifier.to_string(),
+        username: session.username.as_ref().map(|name| name.to_string()),
+        peer: session.peer,
+        reason: reason.clone(),
+    });
+    global.emit_event(ClientEvent::Disconnected {
+        client_identifier: session.client_identifier.to_string(),
+        peer: session.peer,
+        reason,
+    });
+}
+
 async fn after_connect_hook<H: Hook + Clone + Send + Sync>(
     session: &mut Session,
     session_present: bool,
@@ -1002,3 +1278,37 @@ async fn after_disconnect_hook<H: Hook + Clone + Send + Sync>(
     };
     Ok(())
 }
+
+// The will is dropped if it's not authorized (again) when published, or the
+// hook failed (the session is still cleaned up)
+async fn before_will_hook<H: Hook + Clone + Send + Sync>(
+    session: &mut Session,
+    hook_handler: &H,
+    global: &Arc<GlobalState>,
+) {
+    let locked_hook_context = LockedHookContext::new(session, &mut Default::default());
+    let hook_request = HookRequest::V5BeforeWill {
+        context: locked_hook_context,
+    };
+    let code = match handle_request(hook_request, hook_handler.clone(), global.clone()).await {
+        HookResponse::BeforeWill(Ok(code)) => code,
+        HookResponse::BeforeWill(Err(err)) => {
+            tracing::warn!(
+                "[{}] will dropped, before will hook error: {}",
+                session.client_id,
+                err
+            );
+            session.last_will = None;
+            return;
+        }
+        _ => panic!("invalid response"),
+    };
+    if code != HookPublishCode::Success {
+        tracing::info!(
+            "[{}] will dropped by before will hook: {:?}",
+            session.client_id,
+            code
+        );
+        session.last_will = None;
+    }
+}
@@ -12,8 +12,9 @@ use mqtt_proto::{
 };
 use tokio::io::AsyncWrite;
 
-use crate::protocols::mqtt::{get_unix_ts, PendingPacketStatus};
-use crate::state::ClientId;
+use crate::error::Error as AkasaError;
+use crate::protocols::mqtt::{get_unix_ts, PacketDirection, PendingPacketStatus};
+use crate::state::{ClientId, GlobalState};
 
 use super::super::Session;
 
@@ -30,6 +31,7 @@ pub(crate) fn build_error_connack<'a, R: Into<Cow<'a, str>>>(
     reason_code: ConnectReasonCode,
     reason_string: R,
 ) -> Packet {
+    session.connect_error = Some(reason_code);
     let reason_string = if session.request_problem_info {
         Some(Arc::new(reason_string.into().into_owned()))
     } else {
@@ -69,8 +71,23 @@ pub(crate) fn build_error_disconnect<'a, R: Into<Cow<'a, str>>>(
     reason_code: DisconnectReasonCode,
     reason_string: R,
 ) -> Packet {
+    let reason_string = reason_string.into();
+    // The protocol errors of the client, recorded by the diagnostics
+    if matches!(
+        reason_code,
+        DisconnectReasonCode::MalformedPacket
+            | DisconnectReasonCode::ProtocolError
+            | DisconnectReasonCode::PacketTooLarge
+            | DisconnectReasonCode::ReceiveMaximumExceeded
+            | DisconnectReasonCode::TopicNameInvalid
+            | DisconnectReasonCode::TopicAliasInvalid
+            | DisconnectReasonCode::QoSNotSupported
+            | DisconnectReasonCode::RetainNotSupported
+    ) {
+        session.violation = Some(reason_string.to_string());
+    }
     let reason_string = if session.request_problem_info {
-        Some(Arc::new(reason_string.into().into_owned()))
+        Some(Arc::new(reason_string.into_owned()))
     } else {
         None
     };
@@ -99,13 +116,50 @@ pub(crate) fn build_error_disconnect<'a, R: Into<Cow<'a, str>>>(
     rv_packet
 }
 
+/// Build the DISCONNECT packet with the reason code and string of the error
+#[inline]
+pub(crate) fn build_typed_disconnect(session: &mut Session, err: &AkasaError) -> Packet {
+    build_error_disconnect(session, err.disconnect_reason_code(), err.to_string())
+}
+
+/// Build the CONNACK packet redirect the client to another server
+#[inline]
+pub(crate) fn build_redirect_connack(
+    session: &mut Session,
+    reason_code: ConnectReasonCode,
+    server_reference: Arc<String>,
+) -> Packet {
+    let mut rv_packet = build_error_connack(session, false, reason_code, "");
+    if let Packet::Connack(connack) = &mut rv_packet {
+        connack.properties.server_reference = Some(server_reference);
+    }
+    rv_packet
+}
+
+/// Build the DISCONNECT packet redirect the client to another server
+#[inline]
+pub(crate) fn build_redirect_disconnect(
+    session: &mut Session,
+    reason_code: DisconnectReasonCode,
+    server_reference: Arc<String>,
+) -> Packet {
+    let mut rv_packet = build_error_disconnect(session, reason_code, "");
+    if let Packet::Disconnect(disconnect) = &mut rv_packet {
+        disconnect.properties.server_reference = Some(server_reference);
+    }
+    rv_packet
+}
+
 #[inline]
 pub(crate) fn handle_pendings(session: &mut Session) -> Vec<Packet> {
     session.pending_packets.clean_complete();
     let mut packets = Vec::new();
     let mut expired_packets = Vec::new();
     let mut start_idx = 0;
-    while let Some((idx, packet_status)) = session.pending_packets.get_ready_packet(start_idx) {
+    let now_ts = get_unix_ts();
+    while let Some((idx, packet_status)) =
+        session.pending_packets.get_ready_packet(start_idx, now_ts)
+    {
         start_idx = idx + 1;
         match packet_status {
             PendingPacketStatus::New {
@@ -116,15 +170,16 @@ pub(crate) fn handle_pendings(session: &mut Session) -> Vec<Packet> {
                 packet,
                 ..
             } => {
-                let now_ts = get_unix_ts();
                 let mut message_expiry_interval = None;
                 if let Some(value) = packet.properties.message_expiry_interval {
-                    let passed_secs = now_ts - *added_at;
+                    // The time the message waited in the queue
+                    let passed_secs = added_at.elapsed().as_secs();
                     if *last_sent == 0 && passed_secs >= value as u64 {
                         expired_packets.push(*pid);
                         continue;
                     }
-                    message_expiry_interval = Some(value.saturating_sub(passed_secs as u32));
+                    message_expiry_interval =
+                        Some(value.saturating_sub(passed_secs.min(u32::MAX as u64) as u32));
                 }
                 let qos_pid = match packet.qos {
                     QoS::Level0 => QosPid::Level0,
@@ -133,7 +188,7 @@ pub(crate) fn handle_pendings(session: &mut Session) -> Vec<Packet> {
                 };
                 let mut properties = packet.properties.clone();
                 properties.message_expiry_interval = message_expiry_interval;
-                let rv_packet = Publish {
+                let mut rv_packet = Publish {
                     dup: *dup,
                     retain: packet.retain,
                     qos_pid,
@@ -141,6 +196,9 @@ pub(crate) fn handle_pendings(session: &mut Session) -> Vec<Packet> {
                     payload: packet.payload.clone(),
                     properties,
                 };
+                session
+                    .server_topic_aliases
+                    .apply(session.topic_alias_max, &mut rv_packet);
                 *dup = true;
                 *last_sent = now_ts;
                 packets.push(rv_packet.into());
@@ -151,7 +209,7 @@ pub(crate) fn handle_pendings(session: &mut Session) -> Vec<Packet> {
                     reason_code: PubrelReasonCode::Success,
                     properties: PubrelProperties::default(),
                 };
-                *last_sent = get_unix_ts();
+                *last_sent = now_ts;
                 packets.push(rv_packet.into());
             }
             PendingPacketStatus::Complete => unreachable!(),
@@ -167,13 +225,30 @@ pub(crate) fn handle_pendings(session: &mut Session) -> Vec<Packet> {
     packets
 }
 
+/// Remove the queued messages expired before sent, return the count of removed messages.
+pub(crate) fn remove_expired_packets(session: &mut Session) -> usize {
+    session.pending_packets.remove_unsent(|added_at, packet| {
+        match packet.properties.message_expiry_interval {
+            Some(value) => added_at.elapsed().as_secs() >= value as u64,
+            None => false,
+        }
+    })
+}
+
 #[inline]
+/// Write a packet before the online loop started, it's recorded by the packet
+/// tracer as a packet of `client_identifier`
 pub(crate) async fn write_packet<T: AsyncWrite + Unpin>(
     client_id: ClientId,
+    client_identifier: &str,
     conn: &mut T,
     packet: &Packet,
+    global: &GlobalState,
 ) -> io::Result<()> {
-    log::debug!("write to {} with packet: {:#?}", client_id, packet);
+    tracing::debug!(%client_id, ?packet, "write packet");
+    global
+        .packet_tracer
+        .record(client_identifier, PacketDirection::Out, packet);
     packet.encode_async(conn).await.map_err(|err| match err {
         ErrorV5::Common(err) => io::Error::from(err),
         _ => io::ErrorKind::InvalidData.into(),
@@ -1,3 +1,4 @@
+use std::cmp;
 use std::io;
 use std::sync::Arc;
 use std::time::Instant;
@@ -8,17 +9,26 @@ use mqtt_proto::{
         Auth, AuthProperties, AuthReasonCode, Connack, ConnackProperties, Connect,
         ConnectReasonCode, Disconnect, DisconnectReasonCode, Packet,
     },
-    QoS,
+    QoS, MATCH_ALL_CHAR, MATCH_ONE_CHAR,
 };
 use scram::server::{AuthenticationStatus, ScramServer};
 use tokio::io::AsyncWrite;
 
-use crate::config::SaslMechanism;
-use crate::protocols::mqtt::{check_password, start_keep_alive_timer};
+use crate::config::{Config, SaslMechanism};
+use crate::error::Error;
+use crate::flapping;
+use crate::metrics::Metrics;
+use crate::protocols::mqtt::{
+    check_cloud_auth, check_password, generate_client_identifier, pending_limits,
+    start_keep_alive_timer,
+};
 use crate::state::{AddClientReceipt, ClientReceiver, GlobalState};
 
 use super::super::{ScramStage, Session, TracedRng};
-use super::common::{build_error_connack, build_error_disconnect, write_packet};
+use super::common::{
+    build_error_connack, build_error_disconnect, build_redirect_connack, remove_expired_packets,
+    write_packet,
+};
 
 pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
     session: &mut Session,
@@ -27,7 +37,7 @@ pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
     conn: &mut T,
     global: &Arc<GlobalState>,
 ) -> io::Result<bool> {
-    log::debug!(
+    tracing::debug!(
         r#"{} received a connect packet:
      protocol : {}
     client_id : {}
@@ -46,10 +56,81 @@ pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
         packet.last_will,
     );
 
+    if let Some(redirect) = global.config().redirect.as_ref() {
+        tracing::debug!(
+            "redirect client {} to {}",
+            packet.client_id,
+            redirect.server_reference
+        );
+        let reason_code = if redirect.permanent {
+            ConnectReasonCode::ServerMoved
+        } else {
+            ConnectReasonCode::UseAnotherServer
+        };
+        let server_reference = Arc::new(redirect.server_reference.clone());
+        let err_pkt = build_redirect_connack(session, reason_code, server_reference);
+        write_packet(session.client_id, &packet.client_id, conn, &err_pkt, global).await?;
+        return Ok(false);
+    }
+
+    if global
+        .config()
+        .memory_limit
+        .under_pressure(global.accounted_memory())
+    {
+        tracing::warn!("refuse client {}, due to memory pressure", packet.client_id);
+        Metrics::incr(&global.metrics.memory_refused_connects);
+        let err_pkt = build_error_connack(
+            session,
+            false,
+            ConnectReasonCode::ServerBusy,
+            "memory pressure",
+        );
+        write_packet(session.client_id, &packet.client_id, conn, &err_pkt, global).await?;
+        return Ok(false);
+    }
+
+    if global.bans.is_client_banned(
+        &packet.client_id,
+        packet.username.as_ref().map(|name| name.as_str()),
+    ) || flapping::check_connect(global, &packet.client_id, session.peer)
+    {
+        tracing::info!("refuse banned client {}", packet.client_id);
+        Metrics::incr(&global.metrics.banned_connects);
+        let err_pkt = build_error_connack(session, false, ConnectReasonCode::Banned, "banned");
+        write_packet(session.client_id, &packet.client_id, conn, &err_pkt, global).await?;
+        return Ok(false);
+    }
+
+    if packet.client_id.is_empty() && !global.config().empty_client_id.assign {
+        tracing::info!("zero-length client identifier is not allowed");
+        let err_pkt = build_error_connack(
+            session,
+            false,
+            ConnectReasonCode::ClientIdentifierNotValid,
+            "zero-length client identifier",
+        );
+        write_packet(session.client_id, &packet.client_id, conn, &err_pkt, global).await?;
+        return Ok(false);
+    }
+
     let mut reason_code = ConnectReasonCode::Success;
-    if global.config.auth.enable {
-        if packet.username.is_none() || packet.password.is_none() {
-            log::debug!(
+    let config = global.config();
+    if config.auth.enable {
+        let cloud_auth = check_cloud_auth(
+            &config.auth.cloud_profiles,
+            &packet.client_id,
+            packet.username.as_ref().map(|name| name.as_str()),
+            packet.password.as_deref(),
+        )
+        .await;
+        if let Some(valid) = cloud_auth {
+            if !valid {
+                tracing::debug!("cloud profile authentication failed: {}", packet.client_id);
+                reason_code = ConnectReasonCode::BadUserNameOrPassword;
+            }
+        } else if packet.username.is_none() || packet.password.is_none() {
+            tracing::debug!(
                 "username or password not set for client: {}",
                 packet.client_id
             );
@@ -58,7 +139,7 @@ pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
             let username = packet.username.as_ref().unwrap();
             let password = packet.password.as_ref().unwrap();
             if !check_password(&global.auth_passwords, username, password) {
-                log::debug!("incorrect password for user: {}", username);
+                tracing::debug!("incorrect password for user: {}", username);
                 reason_code = ConnectReasonCode::BadUserNameOrPassword;
             }
         }
@@ -66,7 +147,7 @@ pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
     // FIXME: permission check and return "not authorized"
     if reason_code != ConnectReasonCode::Success {
         let err_pkt = build_error_connack(session, false, reason_code, "");
-        write_packet(session.client_id, conn, &err_pkt).await?;
+        write_packet(session.client_id, &packet.client_id, conn, &err_pkt, global).await?;
         return Ok(false);
     }
 
@@ -76,15 +157,17 @@ pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
     session.clean_start = packet.clean_start;
     session.client_identifier = if packet.client_id.is_empty() {
         session.assigned_client_id = true;
-        Arc::new(uuid::Uuid::new_v4().to_string())
+        Arc::new(generate_client_identifier(&global.config().empty_client_id))
     } else {
         Arc::clone(&packet.client_id)
     };
     session.username = packet.username;
-    session.keep_alive = if packet.keep_alive > global.config.max_keep_alive {
-        global.config.max_keep_alive
-    } else if packet.keep_alive < global.config.min_keep_alive {
-        global.config.min_keep_alive
+    session.keep_alive = if let Some(value) = session.listener_keep_alive {
+        value
+    } else if packet.keep_alive > global.config().max_keep_alive {
+        global.config().max_keep_alive
+    } else if packet.keep_alive < global.config().min_keep_alive {
+        global.config().min_keep_alive
     } else {
         packet.keep_alive
     };
@@ -94,46 +177,67 @@ pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
     session.request_problem_info = properties.request_problem_info.unwrap_or(true);
     session.max_packet_size = properties
         .max_packet_size
-        .unwrap_or(global.config.max_packet_size_client);
+        .unwrap_or(global.config().max_packet_size_client);
     if properties.receive_max == Some(0) {
-        log::debug!("connect properties ReceiveMaximum is 0");
+        tracing::debug!("connect properties ReceiveMaximum is 0");
         let err_pkt = build_error_connack(
             session,
             false,
             ConnectReasonCode::ProtocolError,
             "ReceiveMaximum value=0 is not allowed",
         );
-        write_packet(session.client_id, conn, &err_pkt).await?;
+        write_packet(
+            session.client_id,
+            &session.client_identifier,
+            conn,
+            &err_pkt,
+            global,
+        )
+        .await?;
         return Ok(false);
     }
     if session.max_packet_size == 0 {
-        log::debug!("connect properties MaximumPacketSize is 0");
+        tracing::debug!("connect properties MaximumPacketSize is 0");
         let err_pkt = build_error_connack(
             session,
             false,
             ConnectReasonCode::ProtocolError,
             "MaximumPacketSize value=0 is not allowed",
         );
-        write_packet(session.client_id, conn, &err_pkt).await?;
+        write_packet(
+            session.client_id,
+            &session.client_identifier,
+            conn,
+            &err_pkt,
+            global,
+        )
+        .await?;
         return Ok(false);
     }
 
     if properties.auth_data.is_some() && properties.auth_method.is_none() {
-        log::debug!("connect properties AuthenticationMethod is missing");
+        tracing::debug!("connect properties AuthenticationMethod is missing");
         let err_pkt = build_error_connack(
             session,
             false,
             ConnectReasonCode::ProtocolError,
             "AuthenticationMethod is missing",
         );
-        write_packet(session.client_id, conn, &err_pkt).await?;
+        write_packet(
+            session.client_id,
+            &session.client_identifier,
+            conn,
+            &err_pkt,
+            global,
+        )
+        .await?;
         return Ok(false);
     }
 
     session.session_expiry_interval = properties.session_expiry_interval.unwrap_or(0);
     session.receive_max = properties
         .receive_max
-        .unwrap_or(global.config.max_inflight_client);
+        .unwrap_or(global.config().max_inflight_client);
     // MaximumPacketSize assigned above
     session.topic_alias_max = properties.topic_alias_max.unwrap_or(0);
     session.request_response_info = properties.request_response_info.unwrap_or(false);
@@ -144,14 +248,14 @@ pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
     if let Some(last_will) = packet.last_will {
         // v5.0 [MQTT-4.7.3-1]
         if last_will.topic_name.is_empty() {
-            log::warn!("will topic name can't be empty");
+            tracing::warn!("will topic name can't be empty");
             // FIXME: send error connack
-            return Err(io::ErrorKind::InvalidData.into());
+            return Err(Error::Protocol("empty will topic name".to_owned()).into());
         }
         if last_will.topic_name.starts_with('$') {
-            log::warn!("will topic name can't start with $");
+            tracing::warn!("will topic name can't start with $");
             // FIXME: send error connack
-            return Err(io::ErrorKind::InvalidData.into());
+            return Err(Error::Protocol("will topic name starts with $".to_owned()).into());
         }
         session.last_will = Some(last_will);
     }
@@ -160,25 +264,39 @@ pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
         let mechanism = if let Some(mechanism) = SaslMechanism::from_str(&auth_method) {
             mechanism
         } else {
-            log::info!("connect properties auth method invalid: {}", auth_method);
+            tracing::info!("connect properties auth method invalid: {}", auth_method);
             let err_pkt = build_error_connack(
                 session,
                 false,
                 ConnectReasonCode::BadAuthMethod,
                 "auth method not supported",
             );
-            write_packet(session.client_id, conn, &err_pkt).await?;
+            write_packet(
+                session.client_id,
+                &session.client_identifier,
+                conn,
+                &err_pkt,
+                global,
+            )
+            .await?;
             return Ok(false);
         };
-        if !global.config.sasl_mechanisms.contains(&mechanism) {
-            log::info!("Sasl mechanism not supported: {:?}", mechanism);
+        if !global.config().sasl_mechanisms.contains(&mechanism) {
+            tracing::info!("Sasl mechanism not supported: {:?}", mechanism);
             let err_pkt = build_error_connack(
                 session,
                 false,
                 ConnectReasonCode::BadAuthMethod,
                 "auth method not supported",
             );
-            write_packet(session.client_id, conn, &err_pkt).await?;
+            write_packet(
+                session.client_id,
+                &session.client_identifier,
+                conn,
+                &err_pkt,
+                global,
+            )
+            .await?;
             return Ok(false);
         }
         match scram_client_first(session, properties.auth_data, global) {
@@ -192,20 +310,43 @@ pub(crate) async fn handle_connect<T: AsyncWrite + Unpin>(
                         user_properties: Vec::new(),
                     },
                 };
-                write_packet(session.client_id, conn, &rv_packet.into()).await?;
+                write_packet(
+                    session.client_id,
+                    &session.client_identifier,
+                    conn,
+                    &rv_packet.into(),
+                    global,
+                )
+                .await?;
+            }
+            Err(err_pkt) => {
+                write_packet(
+                    session.client_id,
+                    &session.client_identifier,
+                    conn,
+                    &err_pkt,
+                    global,
+                )
+                .await?
             }
-            Err(err_pkt) => write_packet(session.client_id, conn, &err_pkt).await?,
         }
         Ok(false)
     } else if properties.auth_data.is_some() {
-        log::info!("connect properties have auth data but missing auth method");
+        tracing::info!("connect properties have auth data but missing auth method");
         let err_pkt = build_error_connack(
             session,
             false,
             ConnectReasonCode::ProtocolError,
             "auth method is missing",
         );
-        write_packet(session.client_id, conn, &err_pkt).await?;
+        write_packet(
+            session.client_id,
+            &session.client_identifier,
+            conn,
+            &err_pkt,
+            global,
+        )
+        .await?;
         Ok(false)
     } else {
         session_connect(session, receiver, None, conn, global).await
@@ -221,24 +362,36 @@ pub(crate) async fn session_connect<T: AsyncWrite + Unpin>(
 ) -> io::Result<bool> {
     let mut session_present = false;
     match global
-        .add_client(session.client_identifier.as_str(), session.protocol)
+        .add_client(
+            session.client_identifier.as_str(),
+            session.protocol,
+            Some(session.peer.ip()),
+        )
         .await?
     {
         // not allowed, so this is dead branch.
+        #[cfg(feature = "v3")]
         AddClientReceipt::PresentV3(_) => unreachable!(),
         AddClientReceipt::PresentV5(old_state) => {
-            log::debug!("Got exists session for {}", old_state.client_id);
+            tracing::debug!("Got exists session for {}", old_state.client_id);
             session.client_id = old_state.client_id;
             *receiver = Some(old_state.receiver);
             // TODO: if protocol level is compatiable, copy the session state?
             if !session.clean_start && session.protocol == old_state.protocol {
                 session.server_packet_id = old_state.server_packet_id;
                 session.pending_packets = old_state.pending_packets;
+                session.pending_packets.reset_retries();
                 session.qos2_pids = old_state.qos2_pids;
                 session.subscribes = old_state.subscribes;
+                session.attributes = old_state.attributes;
+                // The messages expired while the session is offline
+                let removed = remove_expired_packets(session);
+                if removed > 0 {
+                    tracing::debug!("{} expired pending messages removed", removed);
+                }
                 session_present = true;
             } else {
-                log::info!(
+                tracing::info!(
                     "{} session state removed due to reconnect with a different protocol version, new: {}, old: {}, or clean start: {}",
                     old_state.pending_packets.len(),
                     session.protocol,
@@ -252,15 +405,50 @@ pub(crate) async fn session_connect<T: AsyncWrite + Unpin>(
             client_id,
             receiver: new_receiver,
         } => {
-            log::debug!("Create new session for {}", client_id);
+            tracing::debug!("Create new session for {}", client_id);
             session.client_id = client_id;
             *receiver = Some(new_receiver);
         }
+        AddClientReceipt::Rejected => {
+            tracing::info!("session is online: {}", session.client_identifier);
+            let err_pkt = build_error_connack(
+                session,
+                false,
+                ConnectReasonCode::ClientIdentifierNotValid,
+                "client identifier in use",
+            );
+            write_packet(
+                session.client_id,
+                &session.client_identifier,
+                conn,
+                &err_pkt,
+                global,
+            )
+            .await?;
+            return Ok(false);
+        }
     }
 
+    // Also applied to the restored session state
+    let (max_packets, max_bytes, policy, max_inflight) = pending_limits(
+        &global.config(),
+        &session.client_identifier,
+        session.username.as_ref().map(|name| name.as_str()),
+    );
+    let max_inflight = match max_inflight {
+        Some(value) => cmp::min(value, session.receive_max),
+        None => session.receive_max,
+    };
+    session.pending_packets.set_max_inflight(max_inflight);
     session
         .pending_packets
-        .set_max_inflight(session.receive_max);
+        .set_limits(max_packets, max_bytes, policy);
+    session
+        .pending_packets
+        .set_memory_charge(global.memory.pending_charge());
+    session
+        .broadcast_packets
+        .set_memory_charge(global.memory.broadcast_charge());
     start_keep_alive_timer(
         session.keep_alive,
         session.client_id,
@@ -268,48 +456,67 @@ pub(crate) async fn session_connect<T: AsyncWrite + Unpin>(
         global,
     )?;
 
-    log::debug!("Socket {} assgined to: {}", session.peer, session.client_id);
+    tracing::debug!("Socket {} assgined to: {}", session.peer, session.client_id);
 
     // Build and send connack packet
+    let config = global.config();
     let mut connack_properties = ConnackProperties::default();
-    if session.session_expiry_interval > global.config.max_session_expiry_interval {
-        session.session_expiry_interval = global.config.max_session_expiry_interval;
-        connack_properties.session_expiry_interval = Some(session.session_expiry_interval);
+    let session_expiry_interval =
+        clamp_session_expiry_interval(&config, session.session_expiry_interval);
+    if session_expiry_interval != session.session_expiry_interval {
+        session.session_expiry_interval = session_expiry_interval;
+        connack_properties.session_expiry_interval = Some(session_expiry_interval);
     }
-    if global.config.max_inflight_server != u16::max_value() {
-        connack_properties.receive_max = Some(global.config.max_inflight_server);
+    if config.max_inflight_server != u16::max_value() {
+        connack_properties.receive_max = Some(config.max_inflight_server);
     }
-    if global.config.max_allowed_qos() < QoS::Level2 {
-        connack_properties.max_qos = Some(global.config.max_allowed_qos());
+    let max_qos = session.max_qos(&config);
+    if max_qos < QoS::Level2 {
+        connack_properties.max_qos = Some(max_qos);
     }
-    if !global.config.retain_available {
+    if !config.retain_available {
         connack_properties.retain_available = Some(false);
     }
-    if global.config.max_packet_size_server < u32::max_value() {
-        connack_properties.max_packet_size = Some(global.config.max_packet_size_server);
+    if config.max_packet_size_server < u32::max_value() {
+        connack_properties.max_packet_size = Some(config.max_packet_size_server);
     }
     if session.assigned_client_id {
         connack_properties.assigned_client_id = Some(Arc::clone(&session.client_identifier));
     }
-    if global.config.topic_alias_max > 0 {
-        connack_properties.topic_alias_max = Some(global.config.topic_alias_max);
+    if config.topic_alias_max > 0 {
+        connack_properties.topic_alias_max = Some(config.topic_alias_max);
     }
     // * no ReasonString
     // * TODO UserProperty
-    if !global.config.wildcard_subscription_available {
+    if !config.wildcard_subscription_available {
         connack_properties.wildcard_subscription_available = Some(false);
     }
-    if !global.config.subscription_id_available {
+    if !config.subscription_id_available {
         connack_properties.subscription_id_available = Some(false);
     }
-    if !global.config.shared_subscription_available {
+    if !config.shared_subscription_available {
         connack_properties.shared_subscription_available = Some(false);
     }
     if session.server_keep_alive {
         connack_properties.server_keep_alive = Some(session.keep_alive);
     }
     if session.request_response_info {
-        // * TODO handle ResponseTopic in plugin
+        session.response_topic_prefix = config.response_topic_prefix.as_ref().and_then(|prefix| {
+            // The client identifier must be a single topic level
+            if session
+                .client_identifier
+                .contains(|c| c == '/' || c == MATCH_ONE_CHAR || c == MATCH_ALL_CHAR)
+            {
+                tracing::debug!(
+                    "can not generate response information for client: {}",
+                    session.client_identifier
+                );
+                None
+            } else {
+                Some(Arc::new(format!("{prefix}/{}", session.client_identifier)))
+            }
+        });
+        connack_properties.response_info = session.response_topic_prefix.clone();
     }
     if let Some(auth_data) = auth_data {
         connack_properties.auth_method = session.auth_method.clone();
@@ -318,9 +525,9 @@ pub(crate) async fn session_connect<T: AsyncWrite + Unpin>(
     // * TODO ServerReference
 
     let reason_code = if let Some(will) = session.last_will.as_ref() {
-        if will.retain && !global.config.retain_available {
+        if will.retain && !config.retain_available {
             ConnectReasonCode::RetainNotSupported
-        } else if will.qos > global.config.max_allowed_qos() {
+        } else if will.qos > max_qos {
             ConnectReasonCode::QoSNotSupported
         } else {
             ConnectReasonCode::Success
@@ -333,7 +540,14 @@ pub(crate) async fn session_connect<T: AsyncWrite + Unpin>(
         reason_code,
         properties: connack_properties,
     };
-    write_packet(session.client_id, conn, &rv_packet.into()).await?;
+    write_packet(
+        session.client_id,
+        &session.client_identifier,
+        conn,
+        &rv_packet.into(),
+        global,
+    )
+    .await?;
 
     if reason_code == ConnectReasonCode::Success {
         session.connected = true;
@@ -342,9 +556,24 @@ pub(crate) async fn session_connect<T: AsyncWrite + Unpin>(
     Ok(session_present)
 }
 
+// See: `Config.min_session_expiry_interval`
+#[inline]
+fn clamp_session_expiry_interval(config: &Config, value: u32) -> u32 {
+    if value == 0 {
+        return 0;
+    }
+    value
+        .max(config.min_session_expiry_interval)
+        .min(config.max_session_expiry_interval)
+}
+
 #[inline]
-pub(crate) fn handle_disconnect(session: &mut Session, packet: Disconnect) -> Result<(), Packet> {
-    log::debug!("{} received a disconnect packet", session.client_id);
+pub(crate) fn handle_disconnect(
+    session: &mut Session,
+    packet: Disconnect,
+    config: &Config,
+) -> Result<(), Packet> {
+    tracing::debug!("{} received a disconnect packet", session.client_id);
     if let Some(value) = packet.properties.session_expiry_interval {
         if session.session_expiry_interval == 0 && value > 0 {
             return Err(build_error_disconnect(
@@ -353,7 +582,7 @@ pub(crate) fn handle_disconnect(session: &mut Session, packet: Disconnect) -> Re
                 "SessionExpiryInterval is 0 in CONNECT",
             ));
         }
-        session.session_expiry_interval = value;
+        session.session_expiry_interval = clamp_session_expiry_interval(config, value);
     }
 
     // * no UserProperty
@@ -376,7 +605,7 @@ pub(crate) fn handle_auth(
 ) -> Result<(AuthReasonCode, String), Packet> {
     // TODO: should allow server send ReAuthentication AUTH packet to authenticate some clients
     if session.auth_method.is_none() {
-        log::info!("auth method not presented in CONNECT");
+        tracing::info!("auth method not presented in CONNECT");
         return Err(build_error_disconnect(
             session,
             DisconnectReasonCode::ProtocolError,
@@ -384,7 +613,7 @@ pub(crate) fn handle_auth(
         ));
     }
     if session.auth_method != packet.properties.auth_method {
-        log::info!("auth method not same with CONNECT");
+        tracing::info!("auth method not same with CONNECT");
         return if session.connected {
             Err(build_error_disconnect(
                 session,
@@ -431,7 +660,7 @@ pub(crate) fn handle_auth(
                 if let Ok(string) = String::from_utf8(data.as_ref().to_vec()) {
                     string
                 } else {
-                    log::info!("client final auth data is not utf8");
+                    tracing::info!("client final auth data is not utf8");
                     return Err(build_error_connack(
                         session,
                         false,
@@ -440,7 +669,7 @@ pub(crate) fn handle_auth(
                     ));
                 }
             } else {
-                log::info!("client final auth data is missing");
+                tracing::info!("client final auth data is missing");
                 return Err(build_error_connack(
                     session,
                     false,
@@ -457,11 +686,12 @@ pub(crate) fn handle_auth(
                 } => (message, server_nonce.clone()),
                 _ => unreachable!(),
             };
-            let scram_server = ScramServer::new(&global.config);
+            let config = global.config();
+            let scram_server = ScramServer::new(config.as_ref());
             let scram_server = match scram_server.handle_client_first(client_first) {
                 Ok(scram_server) => scram_server,
                 Err(err) => {
-                    log::info!("scram re-handle client first error: {}, the user may removed from AuthenticationProvider", err);
+                    tracing::info!("scram re-handle client first error: {}, the user may removed from AuthenticationProvider", err);
                     let err_pkt = if session.connected {
                         build_error_disconnect(
                             session,
@@ -484,7 +714,7 @@ pub(crate) fn handle_auth(
             let scram_server = match scram_server.handle_client_final(&client_final) {
                 Ok(server) => server,
                 Err(err) => {
-                    log::info!("scram handle client final error: {}", err);
+                    tracing::info!("scram handle client final error: {}", err);
                     let err_pkt = if session.connected {
                         build_error_disconnect(
                             session,
@@ -504,7 +734,7 @@ pub(crate) fn handle_auth(
             };
             let (status, server_final) = scram_server.server_final();
             if status != AuthenticationStatus::Authenticated {
-                log::info!("scram handle server final failed, status={:?}", status);
+                tracing::info!("scram handle server final failed, status={:?}", status);
                 let err_pkt = if session.connected {
                     build_error_disconnect(
                         session,
@@ -539,15 +769,15 @@ pub(crate) fn handle_auth(
             session.scram_stage = ScramStage::Final(Instant::now());
             session.scram_auth_result = Some((authcid, authzid));
             if !session.connected {
-                log::info!("client {} AUTH success", session.client_identifier);
+                tracing::info!("client {} AUTH success", session.client_identifier);
             } else {
-                log::info!("client {} Re-AUTH success", session.client_identifier);
+                tracing::info!("client {} Re-AUTH success", session.client_identifier);
             }
             Ok((AuthReasonCode::Success, server_final))
         }
         AuthReasonCode::ReAuthentication => {
             if session.authorizing {
-                log::info!("after started auth, reason code must be ContinueAuthentication");
+                tracing::info!("after started auth, reason code must be ContinueAuthentication");
                 let err_pkt = if session.connected {
                     build_error_disconnect(
                         session,
@@ -580,7 +810,7 @@ fn scram_client_first(
         if let Ok(string) = String::from_utf8(data.as_ref().to_vec()) {
             string
         } else {
-            log::info!("scram client first data is not utf8");
+            tracing::info!("scram client first data is not utf8");
             return Err(build_error_connack(
                 session,
                 false,
@@ -589,7 +819,7 @@ fn scram_client_first(
             ));
         }
     } else {
-        log::info!("scram client first data is missing");
+        tracing::info!("scram client first data is missing");
         return Err(build_error_connack(
             session,
             false,
@@ -597,11 +827,12 @@ fn scram_client_first(
             "client first data is missing",
         ));
     };
-    let scram_server = ScramServer::new(&global.config);
+    let config = global.config();
+    let scram_server = ScramServer::new(config.as_ref());
     let scram_server = match scram_server.handle_client_first(&client_first) {
         Ok(scram_server) => scram_server,
         Err(err) => {
-            log::info!("scram handle client first error: {}", err);
+            tracing::info!("scram handle client first error: {}", err);
             if session.connected {
                 return Err(build_error_disconnect(
                     session,
@@ -2,6 +2,7 @@ use std::cmp;
 use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Instant;
 
 use ahash::AHasher;
 use bytes::Bytes;
@@ -14,14 +15,29 @@ use mqtt_proto::{
     },
     Encodable, QoS, QosPid, TopicFilter, TopicName, SHARED_PREFIX,
 };
-use rand::{thread_rng, Rng};
+use tracing::Span;
 
-use crate::config::SharedSubscriptionMode;
-use crate::protocols::mqtt::{BroadcastPackets, RetainContent};
+use crate::audit::AuditEvent;
+use crate::config::ValidationMode;
+use crate::delayed::{parse_delayed_topic, DelayedMessage, DELAYED_PREFIX};
+use crate::events::ClientEvent;
+use crate::fanout;
+use crate::forward::{self, ForwardMessage, Forwarded};
+use crate::hook::HookPublishCode;
+use crate::metrics::{LatencyStage, Metrics};
+use crate::protocols::mqtt::{
+    check_topic_limits, dollar_topic_rejected, get_unix_ts, payload_rejected, retain_rejected,
+    store_retain, PendingPush, Qos2Publish, RetainContent, SharedEncoded,
+};
+use crate::publish::ServerMessage;
+use crate::quota;
+use crate::rule::{self, RuleMessage};
+use crate::schema::{self, SchemaViolation};
+use crate::sparkplug;
 use crate::state::{GlobalState, NormalMessage};
 
 use super::super::{PubPacket, Session};
-use super::common::build_error_disconnect;
+use super::common::{build_error_disconnect, remove_expired_packets};
 
 #[inline]
 pub(crate) fn handle_publish(
@@ -29,7 +45,8 @@ pub(crate) fn handle_publish(
     mut packet: Publish,
     global: &Arc<GlobalState>,
 ) -> Result<Option<Packet>, Packet> {
-    log::debug!(
+    let received_at = Instant::now();
+    tracing::debug!(
         r#"{} received a publish packet:
 topic name : {}
    payload : {:?}
@@ -42,24 +59,75 @@ topic name : {}
         packet.dup,
     );
 
-    if packet.topic_name.starts_with('$') {
-        log::warn!(
-            "publish to topic name start with '$' is not allowed: {}",
-            packet.topic_name
+    let config = global.config();
+    let is_delayed = config.delayed_publish.enable && packet.topic_name.starts_with(DELAYED_PREFIX);
+    if packet.topic_name.starts_with('$') && !is_delayed {
+        match dollar_topic_rejected(
+            &config,
+            &packet.topic_name,
+            &session.client_identifier,
+            session.username.as_ref().map(|name| name.as_str()),
+        ) {
+            None => {}
+            Some(HookPublishCode::NotAuthorized) => {
+                tracing::warn!(
+                    "{} publish to reserved topic is not authorized: {}",
+                    session.client_id,
+                    packet.topic_name
+                );
+                global.audit(AuditEvent::AclDenied {
+                    client_identifier: session.client_identifier.to_string(),
+                    username: session.username.as_ref().map(|name| name.to_string()),
+                    peer: session.peer,
+                    action: "publish".to_owned(),
+                    topics: vec![packet.topic_name.to_string()],
+                });
+                return Ok(publish_rejected(
+                    packet.qos_pid,
+                    HookPublishCode::NotAuthorized,
+                ));
+            }
+            Some(_) => {
+                tracing::warn!(
+                    "publish to topic name start with '$' is not allowed: {}",
+                    packet.topic_name
+                );
+                return invalid_topic_name(
+                    session,
+                    packet.qos_pid,
+                    config.validation_mode,
+                    "publish to topic name start with '$' is not allowed",
+                );
+            }
+        }
+    }
+    if let Err(reason) = check_topic_limits(&config.topic_limits, &packet.topic_name) {
+        tracing::debug!("{}: {}", reason, packet.topic_name);
+        return invalid_topic_name(session, packet.qos_pid, config.validation_mode, reason);
+    }
+    if packet.qos_pid == QosPid::Level0 && packet.dup {
+        tracing::debug!("invalid dup flag in qos0 message");
+        let err_pkt = build_error_disconnect(
+            session,
+            DisconnectReasonCode::ProtocolError,
+            "invalid dup flag in qos0 message",
         );
+        return Err(err_pkt);
+    }
+    if packet.qos_pid.qos() > session.max_qos(&config) {
+        // See: 3.3.4 PUBLISH Actions
         let err_pkt = build_error_disconnect(
             session,
-            DisconnectReasonCode::TopicNameInvalid,
-            "publish to topic name start with '$' is not allowed",
+            DisconnectReasonCode::QoSNotSupported,
+            "QoS is greater than the Maximum QoS",
         );
         return Err(err_pkt);
     }
-    if packet.qos_pid == QosPid::Level0 && packet.dup {
-        log::debug!("invalid dup flag in qos0 message");
+    if packet.retain && !config.retain_available {
         let err_pkt = build_error_disconnect(
             session,
-            DisconnectReasonCode::ProtocolError,
-            "invalid dup flag in qos0 message",
+            DisconnectReasonCode::RetainNotSupported,
+            "retained message is not supported",
         );
         return Err(err_pkt);
     }
@@ -76,7 +144,7 @@ topic name : {}
             );
             return Err(err_pkt);
         }
-        if alias > global.config.topic_alias_max {
+        if alias > config.topic_alias_max {
             let err_pkt = build_error_disconnect(
                 session,
                 DisconnectReasonCode::TopicAliasInvalid,
@@ -118,15 +186,37 @@ topic name : {}
         return Err(err_pkt);
     }
 
+    // The topic alias may refer to a delayed topic
+    let delayed = if config.delayed_publish.enable && topic_name.starts_with(DELAYED_PREFIX) {
+        match parse_delayed_topic(&topic_name, config.delayed_publish.max_delay) {
+            Some(delayed) => Some(delayed),
+            None => {
+                tracing::debug!("invalid delayed topic name: {}", topic_name);
+                return invalid_topic_name(
+                    session,
+                    packet.qos_pid,
+                    config.validation_mode,
+                    "invalid delayed topic name",
+                );
+            }
+        }
+    } else {
+        None
+    };
+
     if let QosPid::Level2(pid) = packet.qos_pid {
         let mut hasher = AHasher::default();
         packet.hash(&mut hasher);
         let current_hash = hasher.finish();
 
-        if let Some(previous_hash) = session.qos2_pids.get(&pid) {
-            // hash collision is acceptable here, since u16 packet identifier is a small range
-            if current_hash != *previous_hash {
-                log::info!("packet identifier in use: {}", pid.value());
+        let max_inflight = config.max_inflight_server as usize;
+        match session
+            .qos2_pids
+            .publish(pid, current_hash, packet.dup, max_inflight)
+        {
+            Qos2Publish::New | Qos2Publish::Duplicate => {}
+            Qos2Publish::PidInUse => {
+                tracing::info!("packet identifier in use: {}", pid.value());
                 let reason_code = PubrecReasonCode::PacketIdentifierInUse;
                 let rv_packet = Pubrec {
                     pid,
@@ -135,8 +225,8 @@ topic name : {}
                 };
                 return Ok(Some(rv_packet.into()));
             }
-            if !packet.dup {
-                log::info!(
+            Qos2Publish::DupRequired => {
+                tracing::info!(
                     "dup flag must be true for re-deliver packet: {}",
                     pid.value()
                 );
@@ -147,24 +237,110 @@ topic name : {}
                 );
                 return Err(err_pkt);
             }
-        } else if session.qos2_pids.len() >= global.config.max_inflight_server as usize {
-            let err_pkt = build_error_disconnect(
-                session,
-                DisconnectReasonCode::ReceiveMaximumExceeded,
-                "too many inflight qos2 message",
-            );
-            return Err(err_pkt);
-        } else {
-            // FIXME: check qos2_pids limit
-            session.qos2_pids.insert(pid, current_hash);
+            Qos2Publish::TooMany => {
+                let err_pkt = build_error_disconnect(
+                    session,
+                    DisconnectReasonCode::ReceiveMaximumExceeded,
+                    "too many inflight qos2 message",
+                );
+                return Err(err_pkt);
+            }
         }
     }
 
-    let matched_len = if !(packet.dup && packet.qos_pid.qos() == QoS::Level2) {
+    // The payload limits are matched by the target topic of a delayed publish
+    let target_topic: &str = match &delayed {
+        Some((_, delayed_topic)) => delayed_topic,
+        None => &topic_name,
+    };
+    let mut quota_exceeded = false;
+    let mut payload_invalid = false;
+    let matched_len = if packet.dup && packet.qos_pid.qos() == QoS::Level2 {
+        1
+    } else if payload_rejected(global, target_topic, packet.payload.len()) {
+        payload_invalid = true;
+        if let QosPid::Level2(pid) = packet.qos_pid {
+            session.qos2_pids.release(pid);
+        }
+        0
+    } else if let Some(violation) = schema::check(global, target_topic, &packet.payload) {
+        match violation {
+            SchemaViolation::Rejected => {
+                payload_invalid = true;
+                if let QosPid::Level2(pid) = packet.qos_pid {
+                    session.qos2_pids.release(pid);
+                }
+                0
+            }
+            // The dead lettered message is acknowledged as delivered
+            SchemaViolation::DeadLettered => 1,
+        }
+    } else if let Err(reason) = quota::check_publish(
+        global,
+        session.username.as_ref().map(|name| name.as_str()),
+        &topic_name,
+        packet.payload.len(),
+        packet.retain && delayed.is_none(),
+    ) {
+        tracing::debug!("publish refused, {}: {}", reason, topic_name);
+        quota_exceeded = true;
+        if let QosPid::Level2(pid) = packet.qos_pid {
+            session.qos2_pids.release(pid);
+        }
+        0
+    } else if let Some((delay, delayed_topic)) = delayed {
+        let mut properties = packet.properties.clone();
+        properties.topic_alias = None;
+        let message = DelayedMessage {
+            message: ServerMessage {
+                client_identifier: session.client_identifier.clone(),
+                username: session.username.clone(),
+                topic_name: delayed_topic,
+                qos: packet.qos_pid.qos(),
+                retain: packet.retain,
+                payload: packet.payload.clone(),
+                properties,
+            },
+            received_at,
+        };
+        let max_messages = config.delayed_publish.max_messages;
+        if !global.delayed_queue.push(delay, message, max_messages) {
+            tracing::warn!("too many delayed messages, dropped: {}", topic_name);
+            quota_exceeded = true;
+            if let QosPid::Level2(pid) = packet.qos_pid {
+                // The QoS 2 flow is finished by the error PUBREC
+                session.qos2_pids.release(pid);
+            }
+        }
+        // The delayed message is acknowledged when scheduled
+        1
+    } else if packet.retain && retain_rejected(global, &topic_name, packet.payload.len()) {
+        quota_exceeded = true;
+        if let QosPid::Level2(pid) = packet.qos_pid {
+            session.qos2_pids.release(pid);
+        }
+        0
+    } else if sparkplug::check(global, &topic_name, &packet.payload) {
+        // The message out of sequence is acknowledged as delivered
+        1
+    } else if rule::apply(
+        global,
+        &RuleMessage {
+            client_identifier: &session.client_identifier,
+            username: session.username.as_deref().map(String::as_str),
+            topic_name: &topic_name,
+            qos: packet.qos_pid.qos(),
+            retain: packet.retain,
+            payload: &packet.payload,
+        },
+    ) {
+        // The message dropped by a rule is acknowledged as delivered
+        1
+    } else {
         let encode_len = total_len(packet.encode_len()).expect("packet too large");
         let properties = &mut packet.properties;
         properties.topic_alias = None;
-        send_publish(
+        let matched_len = send_publish(
             session,
             SendPublish {
                 qos: packet.qos_pid.qos(),
@@ -173,16 +349,39 @@ topic name : {}
                 payload: &packet.payload,
                 properties,
                 encode_len,
+                received_at,
             },
             global,
-        )
-    } else {
-        1
+        );
+        // The message forwarded to Kafka, the AMQP broker, the webhooks or the
+        // time-series database is matched
+        let forwarded = forward::forward(
+            global,
+            &ForwardMessage {
+                client_identifier: &session.client_identifier,
+                username: session.username.as_deref().map(String::as_str),
+                topic_name: &topic_name,
+                payload: &packet.payload,
+                qos: packet.qos_pid.qos(),
+            },
+        );
+        match forwarded {
+            Forwarded::No => matched_len,
+            Forwarded::Queued => cmp::max(matched_len, 1),
+            Forwarded::Wait(receipt) => {
+                session.kafka_receipt = Some(receipt);
+                cmp::max(matched_len, 1)
+            }
+        }
     };
     match packet.qos_pid {
         QosPid::Level0 => Ok(None),
         QosPid::Level1(pid) => {
-            let reason_code = if matched_len > 0 {
+            let reason_code = if quota_exceeded {
+                PubackReasonCode::QuotaExceeded
+            } else if payload_invalid {
+                PubackReasonCode::PayloadFormatInvalid
+            } else if matched_len > 0 {
                 PubackReasonCode::Success
             } else {
                 PubackReasonCode::NoMatchingSubscribers
@@ -195,7 +394,11 @@ topic name : {}
             Ok(Some(rv_packet.into()))
         }
         QosPid::Level2(pid) => {
-            let reason_code = if matched_len > 0 {
+            let reason_code = if quota_exceeded {
+                PubrecReasonCode::QuotaExceeded
+            } else if payload_invalid {
+                PubrecReasonCode::PayloadFormatInvalid
+            } else if matched_len > 0 {
                 PubrecReasonCode::Success
             } else {
                 PubrecReasonCode::NoMatchingSubscribers
@@ -210,9 +413,48 @@ topic name : {}
     }
 }
 
+// Handle the publish with invalid topic name by `Config.validation_mode`
+fn invalid_topic_name(
+    session: &mut Session,
+    qos_pid: QosPid,
+    mode: ValidationMode,
+    reason: &'static str,
+) -> Result<Option<Packet>, Packet> {
+    if mode == ValidationMode::Strict {
+        let err_pkt =
+            build_error_disconnect(session, DisconnectReasonCode::TopicNameInvalid, reason);
+        return Err(err_pkt);
+    }
+    tracing::warn!("{} publish dropped: {}", session.client_id, reason);
+    Ok(publish_rejected(qos_pid, HookPublishCode::TopicNameInvalid))
+}
+
+// The acknowledgement of the rejected publish, nothing for QoS 0
+fn publish_rejected(qos_pid: QosPid, code: HookPublishCode) -> Option<Packet> {
+    match qos_pid {
+        QosPid::Level0 => None,
+        QosPid::Level1(pid) => Some(
+            Puback {
+                pid,
+                reason_code: code.to_v5_puback_code(),
+                properties: PubackProperties::default(),
+            }
+            .into(),
+        ),
+        QosPid::Level2(pid) => Some(
+            Pubrec {
+                pid,
+                reason_code: code.to_v5_pubrec_code(),
+                properties: PubrecProperties::default(),
+            }
+            .into(),
+        ),
+    }
+}
+
 #[inline]
 pub(crate) fn handle_puback(session: &mut Session, packet: Puback) {
-    log::debug!(
+    tracing::debug!(
         "{} received a puback packet: id={}",
         session.client_id,
         packet.pid.value(),
@@ -222,12 +464,12 @@ pub(crate) fn handle_puback(session: &mut Session, packet: Puback) {
 
 #[inline]
 pub(crate) fn handle_pubrec(session: &mut Session, packet: Pubrec) -> Packet {
-    log::debug!(
+    tracing::debug!(
         "{} received a pubrec  packet: id={}",
         session.client_id,
         packet.pid.value()
     );
-    let reason_code = if session.pending_packets.pubrec(packet.pid) {
+    let reason_code = if session.pending_packets.pubrec(packet.pid, get_unix_ts()) {
         PubrelReasonCode::Success
     } else {
         PubrelReasonCode::PacketIdentifierNotFound
@@ -242,12 +484,12 @@ pub(crate) fn handle_pubrec(session: &mut Session, packet: Pubrec) -> Packet {
 
 #[inline]
 pub(crate) fn handle_pubrel(session: &mut Session, packet: Pubrel) -> Packet {
-    log::debug!(
+    tracing::debug!(
         "{} received a pubrel  packet: id={}",
         session.client_id,
         packet.pid.value()
     );
-    let reason_code = if session.qos2_pids.remove(&packet.pid).is_some() {
+    let reason_code = if session.qos2_pids.release(packet.pid) {
         PubcompReasonCode::Success
     } else {
         PubcompReasonCode::PacketIdentifierNotFound
@@ -262,7 +504,7 @@ pub(crate) fn handle_pubrel(session: &mut Session, packet: Pubrel) -> Packet {
 
 #[inline]
 pub(crate) fn handle_pubcomp(session: &mut Session, packet: Pubcomp) {
-    log::debug!(
+    tracing::debug!(
         "{} received a pubcomp packet: id={}",
         session.client_id,
         packet.pid.value()
@@ -296,10 +538,12 @@ pub(crate) struct SendPublish<'a> {
     pub payload: &'a Bytes,
     pub properties: &'a PublishProperties,
     pub encode_len: usize,
+    pub received_at: Instant,
 }
 
 // TODO: change to broadcast_publish()
 // matched clients, return the matched subscriptions length.
+#[tracing::instrument(name = "publish", skip_all, fields(topic = %msg.topic_name.deref(), qos = ?msg.qos, receivers))]
 pub(crate) fn send_publish(
     session: &mut Session,
     msg: SendPublish,
@@ -307,21 +551,21 @@ pub(crate) fn send_publish(
 ) -> usize {
     if msg.retain {
         if let Some(old_content) = if msg.payload.is_empty() {
-            log::debug!("retain message removed");
+            tracing::debug!("retain message removed");
             global.retain_table.remove(msg.topic_name)
         } else {
-            let content = Arc::new(RetainContent::new(
+            let content = RetainContent::new(
                 session.client_identifier.clone(),
                 msg.qos,
                 msg.topic_name.clone(),
                 msg.payload.clone(),
                 Some(msg.properties.clone()),
                 msg.encode_len,
-            ));
-            log::debug!("retain message inserted");
-            global.retain_table.insert(content)
+            );
+            tracing::debug!("retain message inserted");
+            store_retain(global, content)
         } {
-            log::debug!(
+            tracing::debug!(
                 r#"old retain content:
  client identifier : {}
         topic name : {}
@@ -338,6 +582,7 @@ pub(crate) fn send_publish(
     // TODO: enable subscription identifier list by config.
     //   It is also an opinioned optimization.
 
+    let config = global.config();
     let matches = global.route_table.get_matches(msg.topic_name);
     let matched_len = matches.len();
     let mut senders = Vec::with_capacity(matched_len);
@@ -358,13 +603,12 @@ pub(crate) fn send_publish(
             senders.push((*client_id, subscribe_filter.clone(), *subscribe_qos));
         }
         for (group_name, shared_clients) in &content.groups {
-            let (client_id, subscribe_qos) = match global.config.shared_subscription_mode {
-                SharedSubscriptionMode::Random => shared_clients.get_by_number(thread_rng().gen()),
-                SharedSubscriptionMode::HashClientId => {
-                    shared_clients.get_by_hash(&session.client_identifier)
-                }
-                SharedSubscriptionMode::HashTopicName => shared_clients.get_by_hash(msg.topic_name),
-            };
+            let (client_id, subscribe_qos) = global.pick_shared_client(
+                config.shared_subscription_mode,
+                shared_clients,
+                &session.client_identifier,
+                msg.topic_name,
+            );
             // TODO: optimize this alloc later
             let full_filter =
                 TopicFilter::try_from(format!("{SHARED_PREFIX}{group_name}/{subscribe_filter}"))
@@ -372,35 +616,79 @@ pub(crate) fn send_publish(
             senders.push((client_id, full_filter, subscribe_qos));
         }
     }
+    Span::current().record("receivers", senders.len());
+    if config.latency_metrics {
+        global
+            .metrics
+            .latency
+            .record(LatencyStage::Route, msg.qos, msg.received_at);
+    }
+    if !config.topic_stats.prefixes.is_empty() {
+        global.topic_stats.record(
+            &config.topic_stats.prefixes,
+            msg.topic_name,
+            msg.payload.len(),
+            senders.len(),
+        );
+    }
+    if config.top_talkers.count > 0 {
+        global
+            .client_stats
+            .record(&session.client_identifier, msg.payload.len());
+    }
+    if global.has_event_streams() {
+        global.emit_event(ClientEvent::Published {
+            client_identifier: session.client_identifier.to_string(),
+            topic_name: msg.topic_name.to_string(),
+            qos: msg.qos as u8,
+            retain: msg.retain,
+            payload_len: msg.payload.len(),
+            receivers: senders.len(),
+        });
+    }
+
+    let properties = Arc::new(msg.properties.clone());
+    let encoded_v3 = SharedEncoded::default();
+    let new_message = |subscribe_filter, subscribe_qos| NormalMessage::PublishV5 {
+        retain: msg.retain,
+        qos: msg.qos,
+        topic_name: msg.topic_name.clone(),
+        payload: msg.payload.clone(),
+        subscribe_filter,
+        subscribe_qos,
+        properties: Arc::clone(&properties),
+        encode_len: msg.encode_len,
+        encoded_v3: encoded_v3.clone(),
+        received_at: msg.received_at,
+    };
+    let min_receivers = config.fan_out.min_receivers;
+    // Keep using the workers until the previous messages are enqueued by
+    // them, so the messages to a receiver are not reordered.
+    if !senders.is_empty()
+        && ((min_receivers > 0 && senders.len() >= min_receivers)
+            || session.broadcast_packets.fan_out.is_busy())
+    {
+        let (_, subscribe_filter, subscribe_qos) = &senders[0];
+        let template = new_message(subscribe_filter.clone(), *subscribe_qos);
+        senders = fanout::dispatch(
+            global,
+            &config.fan_out,
+            session.client_id,
+            template,
+            senders,
+            &mut session.broadcast_packets,
+        );
+    }
 
-    session.broadcast_packets_cnt += senders.len();
     for (receiver_client_id, subscribe_filter, subscribe_qos) in senders {
-        let publish = NormalMessage::PublishV5 {
-            retain: msg.retain,
-            qos: msg.qos,
-            topic_name: msg.topic_name.clone(),
-            payload: msg.payload.clone(),
-            subscribe_filter,
-            subscribe_qos,
-            properties: msg.properties.clone(),
-            encode_len: msg.encode_len,
-        };
-        if !session.broadcast_packets.contains_key(&receiver_client_id) {
-            if let Some(sender) = global.get_client_normal_sender(&receiver_client_id) {
-                session.broadcast_packets.insert(
-                    receiver_client_id,
-                    BroadcastPackets {
-                        sink: sender.into_sink(),
-                        msgs: Default::default(),
-                        flushed: true,
-                    },
-                );
-            }
-        }
-        if let Some(info) = session.broadcast_packets.get_mut(&receiver_client_id) {
-            info.msgs.push_back(publish);
-        } else {
-            session.broadcast_packets_cnt -= 1;
+        let publish = new_message(subscribe_filter, subscribe_qos);
+        if !session.broadcast_packets.push(
+            receiver_client_id,
+            publish,
+            config.broadcast.max_messages_per_receiver,
+        ) {
+            tracing::debug!("too many messages queued for {}", receiver_client_id);
+            Metrics::incr(&global.metrics.broadcast_dropped);
         }
     }
     matched_len
@@ -410,6 +698,7 @@ pub(crate) fn send_publish(
 pub(crate) fn recv_publish(
     session: &mut Session,
     msg: RecvPublish,
+    global: &Arc<GlobalState>,
 ) -> Option<(QoS, Option<Packet>)> {
     let subscription_id = if let Some(sub) = session.subscribes.get(msg.subscribe_filter) {
         sub.id
@@ -418,7 +707,6 @@ pub(crate) fn recv_publish(
         return None;
     };
 
-    // TODO: detect costly topic name and enable topic alias
     // TODO: support multiple subscription identifiers
     //       (shared subscription not support multiple subscription identifiers)
 
@@ -432,8 +720,14 @@ pub(crate) fn recv_publish(
         }
         let pid = session.incr_server_packet_id();
         session.pending_packets.clean_complete();
-        // TODO: proper handle this error
-        let _is_full = session.pending_packets.push_back(
+        // Make room with the expired messages before apply the overflow policy
+        if session.pending_packets.is_full(msg.payload.len()) {
+            let removed = remove_expired_packets(session);
+            if removed > 0 {
+                tracing::debug!("{} expired pending messages removed", removed);
+            }
+        }
+        let result = session.pending_packets.push_back(
             pid,
             PubPacket {
                 topic_name: msg.topic_name.clone(),
@@ -442,7 +736,21 @@ pub(crate) fn recv_publish(
                 payload: msg.payload.clone(),
                 properties,
             },
+            msg.payload.len(),
         );
+        Metrics::add(&global.metrics.pending_dropped, result.dropped() as u64);
+        if result == PendingPush::Overflow
+            && !session.client_disconnected
+            && !session.server_disconnected
+        {
+            Metrics::incr(&global.metrics.pending_overflow_disconnects);
+            let packet = build_error_disconnect(
+                session,
+                DisconnectReasonCode::QuotaExceeded,
+                "pending messages queue overflow",
+            );
+            return Some((QoS::Level0, Some(packet)));
+        }
         Some((final_qos, None))
     } else if !session.client_disconnected && !session.server_disconnected {
         let encode_len = if msg.qos > QoS::Level0 {
@@ -453,7 +761,7 @@ pub(crate) fn recv_publish(
         if encode_len > session.max_packet_size as usize {
             return None;
         }
-        let rv_packet = Publish {
+        let mut rv_packet = Publish {
             dup: false,
             qos_pid: QosPid::Level0,
             retain: msg.retain,
@@ -461,6 +769,9 @@ pub(crate) fn recv_publish(
             payload: msg.payload.clone(),
             properties,
         };
+        session
+            .server_topic_aliases
+            .apply(session.topic_alias_max, &mut rv_packet);
         Some((final_qos, Some(rv_packet.into())))
     } else {
         None
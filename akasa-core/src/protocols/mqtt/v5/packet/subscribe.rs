@@ -9,6 +9,8 @@ use mqtt_proto::{
     QoS, MATCH_ALL_CHAR, MATCH_ONE_CHAR,
 };
 
+use crate::events::ClientEvent;
+use crate::protocols::mqtt::{check_topic_limits, too_many_subscriptions};
 use crate::state::GlobalState;
 
 use super::super::{Session, SubscriptionData};
@@ -23,7 +25,7 @@ pub(crate) fn handle_subscribe(
     packet: &Subscribe,
     global: &Arc<GlobalState>,
 ) -> Result<Vec<Packet>, Packet> {
-    log::debug!(
+    tracing::debug!(
         r#"{} received a subscribe packet:
 packet id : {}
    topics : {:?}"#,
@@ -41,24 +43,43 @@ packet id : {}
         );
         return Err(err_pkt);
     }
+    // See: [MQTT-3.8.3-4]
+    if packet
+        .topics
+        .iter()
+        .any(|(filter, sub_opts)| filter.is_shared() && sub_opts.no_local)
+    {
+        let err_pkt = build_error_disconnect(
+            session,
+            DisconnectReasonCode::ProtocolError,
+            "No Local is not allowed on shared subscription",
+        );
+        return Err(err_pkt);
+    }
 
+    let config = global.config();
+    let max_qos = session.max_qos(&config);
     let mut rv_packets = Vec::new();
 
-    let reason_codes = if !global.config.subscription_id_available
-        && properties.subscription_id.is_some()
+    let reason_codes = if !config.subscription_id_available && properties.subscription_id.is_some()
     {
         vec![SubscribeReasonCode::SubscriptionIdentifiersNotSupported; packet.topics.len()]
     } else {
         let mut items = Vec::with_capacity(packet.topics.len());
         for (filter, mut sub_opts) in &packet.topics {
-            let granted_qos = cmp::min(sub_opts.max_qos, global.config.max_allowed_qos());
-            let reason_code = if !global.config.shared_subscription_available && filter.is_shared()
-            {
+            let granted_qos = cmp::min(sub_opts.max_qos, max_qos);
+            let reason_code = if !config.shared_subscription_available && filter.is_shared() {
                 SubscribeReasonCode::SharedSubscriptionNotSupported
-            } else if !global.config.wildcard_subscription_available
+            } else if !config.wildcard_subscription_available
                 && filter.contains(|c| c == MATCH_ONE_CHAR || c == MATCH_ALL_CHAR)
             {
                 SubscribeReasonCode::WildcardSubscriptionsNotSupported
+            } else if let Err(reason) = check_topic_limits(&config.topic_limits, filter) {
+                tracing::debug!("{}: {}", reason, filter);
+                SubscribeReasonCode::TopicFilterInvalid
+            } else if too_many_subscriptions(&config, &session.subscribes, filter) {
+                tracing::debug!("too many subscriptions: {}", filter);
+                SubscribeReasonCode::QuotaExceeded
             } else {
                 match granted_qos {
                     QoS::Level0 => SubscribeReasonCode::GrantedQoS0,
@@ -74,8 +95,15 @@ packet id : {}
                 global
                     .route_table
                     .subscribe(filter, session.client_id, granted_qos);
+                if config.events.is_enabled() {
+                    global.emit_event(ClientEvent::Subscribed {
+                        client_identifier: session.client_identifier.to_string(),
+                        topic_filter: filter.to_string(),
+                        qos: granted_qos as u8,
+                    });
+                }
 
-                let send_retain = global.config.retain_available
+                let send_retain = config.retain_available
                     && !filter.is_shared()
                     && match sub_opts.retain_handling {
                         RetainHandling::SendAtSubscribe => true,
@@ -107,6 +135,7 @@ packet id : {}
                                 properties: msg.properties.as_ref(),
                                 encode_len,
                             },
+                            global,
                         ) {
                             if let Some(packet) = packet_opt {
                                 rv_packets.push(packet);
@@ -145,7 +174,7 @@ pub(crate) fn handle_unsubscribe(
     packet: &Unsubscribe,
     global: &Arc<GlobalState>,
 ) -> Packet {
-    log::debug!(
+    tracing::debug!(
         r#"{} received a unsubscribe packet:
 packet id : {}
    topics : {:?}"#,
@@ -153,10 +182,17 @@ packet id : {}
         packet.pid.value(),
         packet.topics,
     );
+    let events_enabled = global.config().events.is_enabled();
     let mut reason_codes = Vec::with_capacity(packet.topics.len());
     for filter in &packet.topics {
         global.route_table.unsubscribe(filter, session.client_id);
         let reason_code = if session.subscribes.remove(filter).is_some() {
+            if events_enabled {
+                global.emit_event(ClientEvent::Unsubscribed {
+                    client_identifier: session.client_identifier.to_string(),
+                    topic_filter: filter.to_string(),
+                });
+            }
             UnsubscribeReasonCode::Success
         } else {
             UnsubscribeReasonCode::NoSubscriptionExisted
@@ -5,17 +5,29 @@ use std::time::Instant;
 use bytes::Bytes;
 use hashbrown::HashMap;
 use mqtt_proto::{
-    v5::{LastWill, PublishProperties, SubscriptionOptions, UserProperty, VarByteInt},
+    v5::{
+        ConnectReasonCode, LastWill, Publish, PublishProperties, SubscriptionOptions, UserProperty,
+        VarByteInt,
+    },
     Pid, Protocol, QoS, TopicFilter, TopicName,
 };
 use rand::{rngs::OsRng, RngCore};
 
 use parking_lot::RwLock;
+use tokio::sync::oneshot;
 
 use crate::config::Config;
 use crate::state::{ClientId, ClientReceiver};
 
-use super::super::{BroadcastPackets, PendingPackets};
+use super::super::{
+    session_expiry_at, BroadcastPackets, IncomingQos2, PendingPackets, SessionInfo,
+    SubscriptionInfo,
+};
+
+/// A topic is assigned an alias after it is sent this many times
+const TOPIC_ALIAS_MIN_SENDS: u32 = 2;
+/// Max topics counted for topic alias assignment
+const TOPIC_ALIAS_MAX_COUNTED: usize = 1024;
 
 // FIXME: move OnlineLoop local data to Session
 pub struct Session {
@@ -24,8 +36,17 @@ pub struct Session {
     pub(super) connected: bool,
     pub(super) client_disconnected: bool,
     pub(super) server_disconnected: bool,
+    // The reason of the protocol error DISCONNECT sent to client (see
+    // `Config.strict_diagnostics`)
+    pub(super) violation: Option<String>,
     pub(super) protocol: Protocol,
     pub(super) scram_stage: ScramStage,
+    // The reason code of the error connack sent to client
+    pub(super) connect_error: Option<ConnectReasonCode>,
+    // The max QoS of the listener, override `Config.max_allowed_qos`
+    pub(super) listener_max_qos: Option<QoS>,
+    // The server keep alive of the listener, override the client keep alive
+    pub(super) listener_keep_alive: Option<u16>,
     pub connected_time: Option<Instant>,
     // When received a disconnect or tcp connection closed
     pub(super) connection_closed_time: Option<Instant>,
@@ -38,7 +59,7 @@ pub struct Session {
     // detecting PacketIdentifierInUse.
     //   See this page for why choose ahash:
     //   https://github.com/tkaitchuck/aHash/blob/master/compare/readme.md#speed
-    pub(super) qos2_pids: HashMap<Pid, u64>,
+    pub(super) qos2_pids: IncomingQos2,
 
     pub(super) client_id: ClientId,
     pub client_identifier: Arc<String>,
@@ -52,12 +73,18 @@ pub struct Session {
     pub last_will: Option<LastWill>,
     // The Subscription Identifiers are part of the Session State in the Server
     pub subscribes: HashMap<TopicFilter, SubscriptionData>,
+    // The attributes set by the hooks (see `HookAction::SetAttribute`), part of
+    // the session state
+    pub attributes: HashMap<String, String>,
     // Topic aliases are connection only data (not session state)
     pub topic_aliases: HashMap<u16, TopicName>,
+    // Topic aliases assigned by server for the publishes send to client
+    pub(super) server_topic_aliases: ServerTopicAliases,
+    // The (Server Reference, permanent) of the redirect control message
+    pub(super) redirect: Option<(Arc<String>, bool)>,
 
     pub(super) broadcast_packets_max: usize,
-    pub(super) broadcast_packets_cnt: usize,
-    pub(super) broadcast_packets: HashMap<ClientId, BroadcastPackets>,
+    pub(super) broadcast_packets: BroadcastPackets,
 
     // properties
     pub session_expiry_interval: u32,
@@ -67,9 +94,14 @@ pub struct Session {
     // client topic alias maximum
     pub topic_alias_max: u16,
     pub(super) request_response_info: bool,
+    // The Response Information generated for the client
+    pub(super) response_topic_prefix: Option<Arc<String>>,
     pub(super) request_problem_info: bool,
     pub user_properties: Vec<UserProperty>,
     pub auth_method: Option<Arc<String>>,
+    // The Kafka receipt the PUBACK of the handling publish waits for (see
+    // `Config.kafka.wait_ack`)
+    pub(crate) kafka_receipt: Option<oneshot::Receiver<bool>>,
 }
 
 pub struct SessionState {
@@ -80,10 +112,10 @@ pub struct SessionState {
     // For record packet id send from server to client
     pub server_packet_id: Pid,
     pub pending_packets: PendingPackets<PubPacket>,
-    pub qos2_pids: HashMap<Pid, u64>,
+    pub qos2_pids: IncomingQos2,
     pub subscribes: HashMap<TopicFilter, SubscriptionData>,
-    pub broadcast_packets_cnt: usize,
-    pub broadcast_packets: HashMap<ClientId, BroadcastPackets>,
+    pub attributes: HashMap<String, String>,
+    pub broadcast_packets: BroadcastPackets,
 }
 
 impl Session {
@@ -94,8 +126,12 @@ impl Session {
             connected: false,
             client_disconnected: false,
             server_disconnected: false,
+            violation: None,
             protocol: Protocol::V500,
             scram_stage: ScramStage::Init,
+            connect_error: None,
+            listener_max_qos: None,
+            listener_keep_alive: None,
             connected_time: None,
             connection_closed_time: None,
             last_packet_time: Arc::new(RwLock::new(Instant::now())),
@@ -103,9 +139,9 @@ impl Session {
             pending_packets: PendingPackets::new(
                 config.max_inflight_client,
                 config.max_in_mem_pending_messages,
-                config.inflight_timeout,
+                config.retransmit.clone(),
             ),
-            qos2_pids: HashMap::new(),
+            qos2_pids: IncomingQos2::default(),
 
             client_id: ClientId::max_value(),
             client_identifier: Arc::new(String::new()),
@@ -117,31 +153,102 @@ impl Session {
             clean_start: true,
             last_will: None,
             subscribes: HashMap::new(),
+            attributes: HashMap::new(),
             topic_aliases: HashMap::new(),
-            broadcast_packets_max: 10,
-            broadcast_packets_cnt: 0,
-            broadcast_packets: HashMap::new(),
+            server_topic_aliases: ServerTopicAliases::default(),
+            redirect: None,
+            broadcast_packets_max: config.broadcast.max_messages,
+            broadcast_packets: BroadcastPackets::default(),
 
             session_expiry_interval: 0,
             receive_max: config.max_inflight_client,
             max_packet_size: config.max_packet_size_client,
             topic_alias_max: 0,
             request_response_info: false,
+            response_topic_prefix: None,
             request_problem_info: true,
             user_properties: Vec::new(),
             auth_method: None,
+            kafka_receipt: None,
         }
     }
 
+    /// The max QoS the client can publish or subscribe
+    pub(crate) fn max_qos(&self, config: &Config) -> QoS {
+        self.listener_max_qos
+            .unwrap_or_else(|| config.max_allowed_qos())
+    }
+
     pub fn client_id(&self) -> ClientId {
         self.client_id
     }
 
+    /// If the topic name (or filter) is under the Response Information
+    /// generated for the client, the hooks should not check the authorization
+    /// of these topics.
+    pub fn is_response_topic(&self, topic: &str) -> bool {
+        self.response_topic_prefix.as_ref().is_some_and(|prefix| {
+            topic
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
     pub(crate) fn incr_server_packet_id(&mut self) -> Pid {
         let old_value = self.server_packet_id;
         self.server_packet_id += 1;
         old_value
     }
+
+    pub(crate) fn info(&self, online: bool) -> SessionInfo {
+        let mut subscriptions: Vec<_> = self
+            .subscribes
+            .iter()
+            .map(|(filter, sub)| SubscriptionInfo {
+                topic_filter: filter.to_string(),
+                qos: sub.options.max_qos as u8,
+                no_local: Some(sub.options.no_local),
+                retain_as_published: Some(sub.options.retain_as_published),
+                retain_handling: Some(format!("{:?}", sub.options.retain_handling)),
+                subscription_id: sub.id.map(|id| id.value()),
+            })
+            .collect();
+        subscriptions.sort_by(|a, b| a.topic_filter.cmp(&b.topic_filter));
+        let mut attributes: Vec<_> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        attributes.sort();
+        let session_expiry_at = if online {
+            None
+        } else {
+            self.connection_closed_time
+                .and_then(|time| session_expiry_at(time, self.session_expiry_interval))
+        };
+        SessionInfo {
+            client_identifier: self.client_identifier.to_string(),
+            protocol: format!("{:?}", self.protocol),
+            online,
+            peer: self.peer,
+            username: self.username.as_ref().map(|name| name.to_string()),
+            keep_alive: self.keep_alive,
+            clean_start: self.clean_start,
+            inflight_pids: self
+                .pending_packets
+                .inflight_pids()
+                .into_iter()
+                .map(|pid| pid.value())
+                .collect(),
+            pending_len: self.pending_packets.len(),
+            incoming_qos2_pids: self.qos2_pids.pids(),
+            subscriptions,
+            session_expiry_interval: Some(self.session_expiry_interval),
+            session_expiry_at,
+            has_will: self.last_will.is_some(),
+            attributes,
+        }
+    }
 }
 
 /// For keep the nonce used in scram auth
@@ -221,6 +328,49 @@ impl RngCore for TracedRng {
     }
 }
 
+/// The outbound topic aliases, the aliases are never replaced once assigned
+/// (until the connection closed).
+#[derive(Debug, Default)]
+pub(crate) struct ServerTopicAliases {
+    aliases: HashMap<TopicName, u16>,
+    // The send counts of the topics not assigned alias yet
+    counts: HashMap<TopicName, u32>,
+}
+
+impl ServerTopicAliases {
+    /// Replace the topic name with the assigned alias, or assign a new alias
+    /// for frequently sent topic (the topic name is kept in this publish).
+    /// `alias_max` is the Topic Alias Maximum from client CONNECT.
+    pub(crate) fn apply(&mut self, alias_max: u16, publish: &mut Publish) {
+        if alias_max == 0 {
+            return;
+        }
+        if let Some(alias) = self.aliases.get(&publish.topic_name) {
+            publish.properties.topic_alias = Some(*alias);
+            publish.topic_name = TopicName::try_from(String::new()).expect("empty topic name");
+            return;
+        }
+        if self.aliases.len() >= alias_max as usize {
+            return;
+        }
+        if !self.counts.contains_key(&publish.topic_name)
+            && self.counts.len() >= TOPIC_ALIAS_MAX_COUNTED
+        {
+            // Too many distinct topics, restart counting
+            self.counts.clear();
+        }
+        let count = self.counts.entry(publish.topic_name.clone()).or_insert(0);
+        *count += 1;
+        if *count < TOPIC_ALIAS_MIN_SENDS {
+            return;
+        }
+        self.counts.remove(&publish.topic_name);
+        let alias = self.aliases.len() as u16 + 1;
+        self.aliases.insert(publish.topic_name.clone(), alias);
+        publish.properties.topic_alias = Some(alias);
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ScramStage {
     Init,
@@ -0,0 +1,176 @@
+//! Publish the messages from the server side on behalf of a client (delayed
+//! messages, the admin API) the same way as a client publish.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use mqtt_proto::{
+    v5::{Packet, Publish, PublishProperties},
+    QoS, QosPid, TopicName,
+};
+
+use crate::events::ClientEvent;
+use crate::forward::{self, ForwardMessage, Forwarded};
+use crate::protocols::mqtt::{retain_rejected, store_retain, RetainContent, SharedEncoded};
+use crate::rule::{self, RuleMessage};
+use crate::state::{ClientId, GlobalState, NormalMessage};
+
+/// A message published by the server side
+#[derive(Debug, Clone)]
+pub(crate) struct ServerMessage {
+    /// The client identifier of the publisher (empty for the server itself)
+    pub client_identifier: Arc<String>,
+    pub username: Option<Arc<String>>,
+    pub topic_name: TopicName,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: Bytes,
+    pub properties: PublishProperties,
+}
+
+/// The result of a server side publish
+pub(crate) struct Published {
+    /// How many receivers the message is delivered to
+    pub delivered: usize,
+    /// Dropped by a rule
+    pub dropped: bool,
+    pub forwarded: Forwarded,
+}
+
+/// Publish the message after the publish hooks: the retain limits and the
+/// rules are applied, the message is forwarded like a client publish. The QoS
+/// 1/2 messages wait for the capacity of the receivers' channels, the QoS 0
+/// messages are dropped for a receiver if its channel is full.
+pub(crate) async fn publish(global: &GlobalState, msg: ServerMessage) -> io::Result<Published> {
+    let received_at = Instant::now();
+    let encode_len = {
+        let qos_pid = match msg.qos {
+            QoS::Level0 => QosPid::Level0,
+            QoS::Level1 => QosPid::Level1(Default::default()),
+            QoS::Level2 => QosPid::Level2(Default::default()),
+        };
+        let publish = Publish {
+            dup: false,
+            retain: msg.retain,
+            qos_pid,
+            topic_name: msg.topic_name.clone(),
+            payload: msg.payload.clone(),
+            properties: msg.properties.clone(),
+        };
+        Packet::Publish(publish)
+            .encode_len()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "message too large"))?
+    };
+    if msg.retain && retain_rejected(global, &msg.topic_name, msg.payload.len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "retained messages limit exceeded",
+        ));
+    }
+    let username = msg.username.as_deref().map(String::as_str);
+    let rule_message = RuleMessage {
+        client_identifier: &msg.client_identifier,
+        username,
+        topic_name: &msg.topic_name,
+        qos: msg.qos,
+        retain: msg.retain,
+        payload: &msg.payload,
+    };
+    if rule::apply(global, &rule_message) {
+        return Ok(Published {
+            delivered: 0,
+            dropped: true,
+            forwarded: Forwarded::No,
+        });
+    }
+    if msg.retain {
+        if msg.payload.is_empty() {
+            global.retain_table.remove(&msg.topic_name);
+        } else {
+            let content = RetainContent::new(
+                Arc::clone(&msg.client_identifier),
+                msg.qos,
+                msg.topic_name.clone(),
+                msg.payload.clone(),
+                Some(msg.properties.clone()),
+                encode_len,
+            );
+            store_retain(global, content);
+        }
+    }
+
+    let receivers = global.receivers(&msg.topic_name, &msg.client_identifier);
+    let config = global.config();
+    if !config.topic_stats.prefixes.is_empty() {
+        global.topic_stats.record(
+            &config.topic_stats.prefixes,
+            &msg.topic_name,
+            msg.payload.len(),
+            receivers.len(),
+        );
+    }
+    if global.has_event_streams() {
+        global.emit_event(ClientEvent::Published {
+            client_identifier: msg.client_identifier.to_string(),
+            topic_name: msg.topic_name.to_string(),
+            qos: msg.qos as u8,
+            retain: msg.retain,
+            payload_len: msg.payload.len(),
+            receivers: receivers.len(),
+        });
+    }
+
+    let mut delivered = 0;
+    let properties = Arc::new(msg.properties);
+    let encoded_v3 = SharedEncoded::default();
+    for (client_id, subscribe_filter, subscribe_qos) in receivers {
+        let sender = match global.get_client_normal_sender(&client_id) {
+            Some(sender) => sender,
+            None => continue,
+        };
+        let normal_msg = NormalMessage::PublishV5 {
+            retain: msg.retain,
+            qos: msg.qos,
+            topic_name: msg.topic_name.clone(),
+            payload: msg.payload.clone(),
+            subscribe_filter,
+            subscribe_qos,
+            properties: Arc::clone(&properties),
+            encode_len,
+            encoded_v3: encoded_v3.clone(),
+            received_at,
+        };
+        let result = if msg.qos == QoS::Level0 {
+            sender
+                .try_send((ClientId::max_value(), normal_msg))
+                .map_err(|err| err.to_string())
+        } else {
+            sender
+                .send_async((ClientId::max_value(), normal_msg))
+                .await
+                .map_err(|err| err.to_string())
+        };
+        match result {
+            Ok(()) => delivered += 1,
+            Err(err) => tracing::debug!("drop server message to {}: {}", client_id, err),
+        }
+    }
+
+    let forwarded = forward::forward(
+        global,
+        &ForwardMessage {
+            client_identifier: &msg.client_identifier,
+            username,
+            topic_name: &msg.topic_name,
+            payload: &msg.payload,
+            qos: msg.qos,
+        },
+    );
+    Ok(Published {
+        delivered,
+        dropped: false,
+        forwarded,
+    })
+}
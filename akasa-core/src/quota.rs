@@ -0,0 +1,302 @@
+//! The cumulative daily quotas per username (see `Config.quotas`)
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::QuotasConfig;
+use crate::metrics::Metrics;
+use crate::protocols::mqtt::{get_unix_ts, quota_rule};
+use crate::state::GlobalState;
+
+const SECONDS_PER_DAY: u64 = 24 * 3600;
+
+#[derive(Default)]
+pub(crate) struct QuotaTable {
+    state: Mutex<QuotaState>,
+}
+
+/// The usage of all the usernames, persisted to `Config.quotas.state_file`
+#[derive(Default, Serialize, Deserialize)]
+struct QuotaState {
+    // The day of the usage, changed at `Config.quotas.reset_hour`
+    day: u64,
+    users: HashMap<String, QuotaUsage>,
+    // The retained topics counted by the usernames: topic name => username
+    retained: HashMap<String, String>,
+    // Changed after last saved
+    #[serde(skip)]
+    dirty: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct QuotaUsage {
+    messages: u64,
+    bytes: u64,
+    retained_topics: usize,
+}
+
+impl QuotaTable {
+    /// Load the usage saved by last run
+    pub(crate) fn load(&self, path: &Path) -> io::Result<()> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let state: QuotaState = serde_json::from_slice(&content)?;
+        *self.state.lock() = state;
+        Ok(())
+    }
+
+    // Write to a temporary file first, so the saved file is never partial
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let content = {
+            let mut state = self.state.lock();
+            if !state.dirty {
+                return Ok(());
+            }
+            state.dirty = false;
+            serde_json::to_vec(&*state)?
+        };
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+/// Count the message to the username's quota, return the reason if the quota
+/// exceeded (the message is not counted).
+///
+/// The empty payload of a retained message removes the retained topic, which
+/// is always allowed.
+pub(crate) fn check_publish(
+    global: &GlobalState,
+    username: Option<&str>,
+    topic_name: &str,
+    payload_len: usize,
+    retain: bool,
+) -> Result<(), &'static str> {
+    let config = global.config();
+    let username = match username {
+        Some(username) => username,
+        None => return Ok(()),
+    };
+    let rule = match quota_rule(&config, username) {
+        Some(rule) => rule,
+        None => return Ok(()),
+    };
+
+    let mut guard = global.quotas.state.lock();
+    let state = &mut *guard;
+    let day = current_day(&config.quotas);
+    if state.day != day {
+        state.day = day;
+        for usage in state.users.values_mut() {
+            usage.messages = 0;
+            usage.bytes = 0;
+        }
+    }
+    let usage = state.users.entry(username.to_owned()).or_default();
+    if rule
+        .max_messages_per_day
+        .is_some_and(|max| usage.messages >= max)
+    {
+        return exceeded(global, "daily messages quota exceeded");
+    }
+    if rule
+        .max_bytes_per_day
+        .is_some_and(|max| usage.bytes + payload_len as u64 > max)
+    {
+        return exceeded(global, "daily bytes quota exceeded");
+    }
+    let new_retained = retain
+        && payload_len > 0
+        && state.retained.get(topic_name).map(String::as_str) != Some(username);
+    if let (true, Some(max)) = (new_retained, rule.max_retained_topics) {
+        if usage.retained_topics >= max {
+            // The retained messages may be replaced by other clients without a
+            // quota, removed by the admin API or expired.
+            let stale: Vec<String> = state
+                .retained
+                .iter()
+                .filter(|(topic, owner)| {
+                    owner.as_str() == username
+                        && !global
+                            .retain_table
+                            .get_matches(topic)
+                            .iter()
+                            .any(|content| &*content.topic_name == topic.as_str())
+                })
+                .map(|(topic, _)| topic.clone())
+                .collect();
+            usage.retained_topics = usage.retained_topics.saturating_sub(stale.len());
+            for topic in stale {
+                state.retained.remove(&topic);
+            }
+            if usage.retained_topics >= max {
+                return exceeded(global, "retained topics quota exceeded");
+            }
+        }
+    }
+
+    usage.messages += 1;
+    usage.bytes += payload_len as u64;
+    if retain {
+        let old_owner = if payload_len == 0 {
+            state.retained.remove(topic_name)
+        } else if new_retained {
+            usage.retained_topics += 1;
+            state
+                .retained
+                .insert(topic_name.to_owned(), username.to_owned())
+        } else {
+            None
+        };
+        if let Some(old_owner) = old_owner {
+            if let Some(usage) = state.users.get_mut(&old_owner) {
+                usage.retained_topics = usage.retained_topics.saturating_sub(1);
+            }
+        }
+    }
+    state.dirty = true;
+    Ok(())
+}
+
+fn exceeded(global: &GlobalState, reason: &'static str) -> Result<(), &'static str> {
+    Metrics::incr(&global.metrics.quota_exceeded);
+    Err(reason)
+}
+
+// The days since UNIX epoch, the day starts at the reset hour (UTC)
+fn current_day(config: &QuotasConfig) -> u64 {
+    get_unix_ts().saturating_sub(config.reset_hour as u64 * 3600) / SECONDS_PER_DAY
+}
+
+/// Save the usage to `Config.quotas.state_file` periodically
+pub(crate) async fn run(global: Arc<GlobalState>) {
+    loop {
+        // Read the interval every time, since the config can be reloaded
+        let interval = global.config().quotas.save_interval;
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        save_quotas(&global);
+    }
+}
+
+/// Save the usage to `Config.quotas.state_file` if it's changed
+pub(crate) fn save_quotas(global: &GlobalState) {
+    if let Some(path) = global.config().quotas.state_file.as_ref() {
+        if let Err(err) = global.quotas.save(path) {
+            tracing::error!("save quotas to {} error: {}", path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::Ordering;
+
+    use mqtt_proto::QoS;
+
+    use crate::config::{Config, QuotaRule};
+
+    fn global(rule: QuotaRule) -> GlobalState {
+        let mut config = Config::new_allow_anonymous();
+        config.quotas.rules = vec![rule];
+        GlobalState::new(config)
+    }
+
+    // Store the retained message like the publish handlers
+    fn publish(global: &GlobalState, username: &str, topic: &str, payload: &[u8]) -> bool {
+        let result = check_publish(global, Some(username), topic, payload.len(), true);
+        if result.is_ok() {
+            if payload.is_empty() {
+                global.retain_table.remove(topic);
+            } else {
+                let content = (topic, QoS::Level0, payload.to_vec(), username).into();
+                global.retain_table.insert(Arc::new(content));
+            }
+        }
+        result.is_ok()
+    }
+
+    #[test]
+    fn test_quota_messages_and_bytes() {
+        let global = global(QuotaRule {
+            username: Some("user*".to_owned()),
+            max_messages_per_day: Some(3),
+            max_bytes_per_day: Some(10),
+            max_retained_topics: None,
+        });
+        assert!(check_publish(&global, Some("user1"), "a", 4, false).is_ok());
+        assert!(check_publish(&global, Some("user1"), "a", 4, false).is_ok());
+        assert_eq!(
+            check_publish(&global, Some("user1"), "a", 4, false),
+            Err("daily bytes quota exceeded")
+        );
+        assert!(check_publish(&global, Some("user1"), "a", 2, false).is_ok());
+        assert_eq!(
+            check_publish(&global, Some("user1"), "a", 0, false),
+            Err("daily messages quota exceeded")
+        );
+        // Not matched or without username
+        assert!(check_publish(&global, Some("other"), "a", 100, false).is_ok());
+        assert!(check_publish(&global, None, "a", 100, false).is_ok());
+
+        // Reset by the next day
+        global.quotas.state.lock().day -= 1;
+        assert!(check_publish(&global, Some("user1"), "a", 4, false).is_ok());
+        assert_eq!(global.metrics.quota_exceeded.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_quota_retained_topics() {
+        let global = global(QuotaRule {
+            username: None,
+            max_messages_per_day: None,
+            max_bytes_per_day: None,
+            max_retained_topics: Some(1),
+        });
+        assert!(publish(&global, "user1", "a", b"1"));
+        // Replace the retained topic of itself
+        assert!(publish(&global, "user1", "a", b"2"));
+        assert!(check_publish(&global, Some("user1"), "b", 1, false).is_ok());
+        assert!(!publish(&global, "user1", "b", b"1"));
+        // Replaced by another user
+        assert!(publish(&global, "user2", "a", b"3"));
+        assert!(publish(&global, "user1", "b", b"1"));
+        // Removed by the empty payload
+        assert!(publish(&global, "user1", "b", b""));
+        assert!(publish(&global, "user1", "c", b"1"));
+        // Removed by the admin API or expired
+        global.retain_table.remove("c");
+        assert!(publish(&global, "user1", "d", b"1"));
+        assert!(!publish(&global, "user1", "e", b"1"));
+    }
+
+    #[test]
+    fn test_quota_save_and_load() {
+        let global = global(QuotaRule {
+            username: None,
+            max_messages_per_day: Some(1),
+            max_bytes_per_day: None,
+            max_retained_topics: None,
+        });
+        assert!(check_publish(&global, Some("user1"), "a", 1, false).is_ok());
+        let path = std::env::temp_dir().join(format!("akasa-quotas-{}.json", uuid::Uuid::new_v4()));
+        global.quotas.save(&path).unwrap();
+
+        let quotas = QuotaTable::default();
+        quotas.load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(quotas.state.lock().users["user1"].messages, 1);
+    }
+}
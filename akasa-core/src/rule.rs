@@ -0,0 +1,892 @@
+//! The rule engine (see `Config.rules`). A rule is a SQL-like statement:
+//!
+//! ```text
+//! SELECT payload.temp AS t, clientid FROM "sensors/+/temp" WHERE t > 50
+//! ```
+//!
+//! The rules are compiled when the config is loaded, and evaluated in the
+//! publish path. The output of a matched rule (the selected fields as a JSON
+//! object) is sent to the actions: republish, webhook, Kafka, or drop the
+//! message.
+
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use mqtt_proto::{QoS, TopicFilter, TopicName};
+use parking_lot::RwLock;
+use serde_json::{Map, Number, Value};
+
+use crate::config::{qos_from_value, RuleAction, RuleConfig};
+#[cfg(feature = "kafka")]
+use crate::kafka;
+use crate::metrics::Metrics;
+#[cfg(feature = "webhook")]
+use crate::protocols::mqtt::percent_encode_levels;
+use crate::protocols::mqtt::topic_match;
+use crate::state::GlobalState;
+#[cfg(feature = "webhook")]
+use crate::webhook;
+
+// The columns of a message, all of them are selected by `SELECT *`
+const COLUMNS: &[&str] = &[
+    "clientid",
+    "username",
+    "topic",
+    "qos",
+    "retain",
+    "timestamp",
+    "payload",
+];
+const KEYWORDS: &[&str] = &[
+    "SELECT", "FROM", "WHERE", "AS", "AND", "OR", "NOT", "TRUE", "FALSE", "NULL",
+];
+// The longer symbols first
+const SYMBOLS: &[&str] = &[
+    "<=", ">=", "!=", "<>", "=", "<", ">", "+", "-", "*", "/", "%", "(", ")", ",", ".", "[", "]",
+];
+
+/// The compiled rules, replaced when the config is reloaded
+pub(crate) struct RuleEngine {
+    rules: RwLock<Arc<Vec<Rule>>>,
+}
+
+struct Rule {
+    query: Query,
+    actions: Vec<RuleAction>,
+}
+
+/// A compiled `SELECT ... FROM ... [WHERE ...]` statement
+#[derive(Debug)]
+pub(crate) struct Query {
+    // The names and the expressions of the fields, None for `SELECT *`
+    fields: Option<Vec<(String, Expr)>>,
+    topic_filters: Vec<String>,
+    condition: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Literal(Value),
+    // A column (or a field in the WHERE clause) and the path in it, like
+    // `payload.list[0].value`
+    Path(String, Vec<PathSegment>),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    // 'string'
+    Str(String),
+    // "topic filter"
+    Quoted(String),
+    Number(Number),
+    Symbol(&'static str),
+}
+
+/// A published message evaluated by the rules
+pub(crate) struct RuleMessage<'a> {
+    pub client_identifier: &'a str,
+    pub username: Option<&'a str>,
+    pub topic_name: &'a str,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: &'a Bytes,
+}
+
+// The columns of a message, the payload is parsed when used
+struct Scope<'a> {
+    message: &'a RuleMessage<'a>,
+    // Unix timestamp in milliseconds
+    timestamp: u64,
+    payload: OnceCell<Value>,
+}
+
+impl RuleEngine {
+    pub(crate) fn new(rules: &[RuleConfig]) -> RuleEngine {
+        RuleEngine {
+            rules: RwLock::new(Arc::new(compile(rules))),
+        }
+    }
+
+    pub(crate) fn update(&self, rules: &[RuleConfig]) {
+        *self.rules.write() = Arc::new(compile(rules));
+    }
+}
+
+// The invalid rules are rejected by `Config::is_valid`, so they are only
+// skipped here.
+fn compile(rules: &[RuleConfig]) -> Vec<Rule> {
+    rules
+        .iter()
+        .filter_map(|rule| match Query::parse(&rule.sql) {
+            Ok(query) => Some(Rule {
+                query,
+                actions: rule.actions.clone(),
+            }),
+            Err(err) => {
+                tracing::error!("invalid rule {:?}: {}", rule.sql, err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Evaluate the rules matched by the topic name of the message, return true
+/// if the message is dropped by a rule.
+pub(crate) fn apply(global: &GlobalState, message: &RuleMessage) -> bool {
+    let rules = Arc::clone(&global.rule_engine.rules.read());
+    if rules.is_empty() {
+        return false;
+    }
+    let scope = Scope {
+        message,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0),
+        payload: OnceCell::new(),
+    };
+    let mut dropped = false;
+    for rule in rules
+        .iter()
+        .filter(|rule| rule.query.matches(message.topic_name))
+    {
+        let output = match rule.query.evaluate(&scope) {
+            Some(output) => output,
+            None => continue,
+        };
+        Metrics::incr(&global.metrics.rules_matched);
+        let body = Bytes::from(serde_json::to_vec(&output).unwrap_or_default());
+        for action in &rule.actions {
+            dropped |= run_action(global, message, action, &output, &body);
+        }
+    }
+    dropped
+}
+
+// Run the action with the output of a rule, return true if the message is
+// dropped.
+fn run_action(
+    global: &GlobalState,
+    message: &RuleMessage,
+    action: &RuleAction,
+    output: &Map<String, Value>,
+    body: &Bytes,
+) -> bool {
+    match action {
+        RuleAction::Republish { topic, qos, retain } => {
+            let topic = render_fields(topic, |name| field_text(output, name));
+            let result = match TopicName::try_from(topic.clone()) {
+                Ok(topic_name) => global
+                    .publish(
+                        topic_name,
+                        qos_from_value(*qos),
+                        *retain,
+                        body.clone(),
+                        Default::default(),
+                    )
+                    .map_err(|err| err.to_string()),
+                Err(_) => Err(format!("invalid topic {topic:?}")),
+            };
+            if let Err(err) = result {
+                tracing::warn!(
+                    "republish rule output of {} error: {}",
+                    message.topic_name,
+                    err
+                );
+                Metrics::incr(&global.metrics.rules_failed);
+            }
+        }
+        #[cfg(feature = "webhook")]
+        RuleAction::Webhook { url } => {
+            // The values in the url are percent-encoded
+            let url = render_fields(url, |name| {
+                percent_encode_levels(&field_text(output, name)).replace('/', "%2F")
+            });
+            webhook::send(global, url, Vec::new(), body.to_vec(), message.topic_name);
+        }
+        #[cfg(feature = "kafka")]
+        RuleAction::Kafka { topic, key } => {
+            kafka::send(
+                global,
+                render_fields(topic, |name| field_text(output, name)),
+                key.as_ref()
+                    .map(|key| render_fields(key, |name| field_text(output, name)).into_bytes()),
+                message.topic_name,
+                body.clone(),
+                None,
+            );
+        }
+        // Rejected by `Config::is_valid` without the features
+        #[cfg(not(feature = "webhook"))]
+        RuleAction::Webhook { .. } => {}
+        #[cfg(not(feature = "kafka"))]
+        RuleAction::Kafka { .. } => {}
+        RuleAction::Drop => return true,
+    }
+    false
+}
+
+/// Replace `${name}` in the template by the value of the field, an unclosed
+/// `${` is kept as is.
+pub(crate) fn render_fields(template: &str, field: impl Fn(&str) -> String) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(offset) => start + offset,
+            None => break,
+        };
+        output.push_str(&rest[..start]);
+        output.push_str(&field(&rest[start + 2..end]));
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    output
+}
+
+// The text of a field in the templates, the strings are not quoted and a
+// missing field is empty.
+fn field_text(output: &Map<String, Value>, name: &str) -> String {
+    match output.get(name) {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(value)) => value.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+impl Query {
+    /// Parse the statement, return the error message if invalid
+    pub(crate) fn parse(sql: &str) -> Result<Query, String> {
+        let mut parser = Parser {
+            tokens: tokenize(sql)?,
+            pos: 0,
+        };
+        parser.expect_keyword("SELECT")?;
+        let fields = if parser.eat_symbol("*") {
+            None
+        } else {
+            let mut fields = Vec::new();
+            loop {
+                let expr = parser.or()?;
+                let name = if parser.eat_keyword("AS") {
+                    parser.ident()?
+                } else {
+                    // The name of a path is the last key
+                    let name = match &expr {
+                        Expr::Path(column, segments) => match segments.last() {
+                            None => Some(column.clone()),
+                            Some(PathSegment::Key(key)) => Some(key.clone()),
+                            Some(PathSegment::Index(_)) => None,
+                        },
+                        _ => None,
+                    };
+                    name.ok_or_else(|| "the field requires an alias (AS name)".to_owned())?
+                };
+                fields.push((name, expr));
+                if !parser.eat_symbol(",") {
+                    break;
+                }
+            }
+            Some(fields)
+        };
+        parser.expect_keyword("FROM")?;
+        let mut topic_filters = Vec::new();
+        loop {
+            match parser.next() {
+                Some(Token::Quoted(filter)) if TopicFilter::try_from(filter.clone()).is_ok() => {
+                    topic_filters.push(filter)
+                }
+                _ => return Err("expect a topic filter (double quoted)".to_owned()),
+            }
+            if !parser.eat_symbol(",") {
+                break;
+            }
+        }
+        let condition = if parser.eat_keyword("WHERE") {
+            Some(parser.or()?)
+        } else {
+            None
+        };
+        if parser.pos < parser.tokens.len() {
+            return Err("unexpected token after the statement".to_owned());
+        }
+        Ok(Query {
+            fields,
+            topic_filters,
+            condition,
+        })
+    }
+
+    fn matches(&self, topic_name: &str) -> bool {
+        self.topic_filters
+            .iter()
+            .any(|topic_filter| topic_match(topic_filter, topic_name))
+    }
+
+    // The output of the message, None if filtered by the WHERE clause (the
+    // fields can be used in it).
+    fn evaluate(&self, scope: &Scope) -> Option<Map<String, Value>> {
+        let output = match &self.fields {
+            Some(fields) => fields
+                .iter()
+                .map(|(name, expr)| (name.clone(), eval(expr, scope, None)))
+                .collect(),
+            None => COLUMNS
+                .iter()
+                .map(|name| ((*name).to_owned(), scope.column(name)))
+                .collect(),
+        };
+        match &self.condition {
+            Some(condition) if !is_true(&eval(condition, scope, Some(&output))) => None,
+            _ => Some(output),
+        }
+    }
+}
+
+impl Scope<'_> {
+    // The JSON payload, or the payload as a string if not JSON
+    fn payload(&self) -> &Value {
+        self.payload.get_or_init(|| {
+            let payload = self.message.payload;
+            serde_json::from_slice(payload)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(payload).into_owned()))
+        })
+    }
+
+    fn column(&self, name: &str) -> Value {
+        let message = self.message;
+        match name {
+            "clientid" => Value::from(message.client_identifier),
+            "username" => message.username.map(Value::from).unwrap_or(Value::Null),
+            "topic" => Value::from(message.topic_name),
+            "qos" => Value::from(message.qos as u8),
+            "retain" => Value::from(message.retain),
+            "timestamp" => Value::from(self.timestamp),
+            "payload" => self.payload().clone(),
+            _ => Value::Null,
+        }
+    }
+}
+
+fn eval(expr: &Expr, scope: &Scope, output: Option<&Map<String, Value>>) -> Value {
+    match expr {
+        Expr::Literal(value) => value.clone(),
+        Expr::Path(column, segments) => {
+            if let Some(value) = output.and_then(|output| output.get(column)) {
+                lookup(value, segments)
+            } else if column == "payload" {
+                lookup(scope.payload(), segments)
+            } else {
+                lookup(&scope.column(column), segments)
+            }
+        }
+        Expr::Neg(expr) => arithmetic(BinaryOp::Sub, &Value::from(0), &eval(expr, scope, output)),
+        Expr::Not(expr) => Value::Bool(!is_true(&eval(expr, scope, output))),
+        Expr::Binary(BinaryOp::And, left, right) => {
+            Value::Bool(is_true(&eval(left, scope, output)) && is_true(&eval(right, scope, output)))
+        }
+        Expr::Binary(BinaryOp::Or, left, right) => {
+            Value::Bool(is_true(&eval(left, scope, output)) || is_true(&eval(right, scope, output)))
+        }
+        Expr::Binary(op, left, right) => {
+            let left = eval(left, scope, output);
+            let right = eval(right, scope, output);
+            let ordering = compare(&left, &right);
+            match op {
+                BinaryOp::Eq => Value::Bool(ordering == Some(Ordering::Equal)),
+                BinaryOp::Ne => {
+                    Value::Bool(matches!(ordering, Some(Ordering::Less | Ordering::Greater)))
+                }
+                BinaryOp::Lt => Value::Bool(ordering == Some(Ordering::Less)),
+                BinaryOp::Le => {
+                    Value::Bool(matches!(ordering, Some(Ordering::Less | Ordering::Equal)))
+                }
+                BinaryOp::Gt => Value::Bool(ordering == Some(Ordering::Greater)),
+                BinaryOp::Ge => Value::Bool(matches!(
+                    ordering,
+                    Some(Ordering::Greater | Ordering::Equal)
+                )),
+                _ => arithmetic(*op, &left, &right),
+            }
+        }
+    }
+}
+
+fn lookup(mut value: &Value, segments: &[PathSegment]) -> Value {
+    for segment in segments {
+        let next = match segment {
+            PathSegment::Key(key) => value.get(key.as_str()),
+            PathSegment::Index(idx) => value.get(*idx),
+        };
+        value = match next {
+            Some(next) => next,
+            None => return Value::Null,
+        };
+    }
+    value.clone()
+}
+
+fn is_true(value: &Value) -> bool {
+    matches!(value, Value::Bool(true))
+}
+
+// Only the numbers, the strings and the booleans are comparable, so a
+// comparison with null is always false.
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+// The integers are computed as integers unless overflow (or divided), the
+// invalid operations return null.
+fn arithmetic(op: BinaryOp, left: &Value, right: &Value) -> Value {
+    let (a, b) = match (left, right) {
+        (Value::String(a), Value::String(b)) if op == BinaryOp::Add => {
+            return Value::String(format!("{a}{b}"))
+        }
+        (Value::Number(a), Value::Number(b)) => (a, b),
+        _ => return Value::Null,
+    };
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        let value = match op {
+            BinaryOp::Add => a.checked_add(b),
+            BinaryOp::Sub => a.checked_sub(b),
+            BinaryOp::Mul => a.checked_mul(b),
+            BinaryOp::Rem => a.checked_rem(b),
+            _ => None,
+        };
+        if let Some(value) = value {
+            return Value::from(value);
+        }
+    }
+    let (a, b) = match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Value::Null,
+    };
+    let value = match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Sub => a - b,
+        BinaryOp::Mul => a * b,
+        BinaryOp::Div => a / b,
+        BinaryOp::Rem => a % b,
+        _ => return Value::Null,
+    };
+    // NaN and infinity are not valid JSON numbers
+    Number::from_f64(value)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>, String> {
+    let bytes = sql.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let byte = bytes[pos];
+        let start = pos;
+        if byte.is_ascii_whitespace() {
+            pos += 1;
+        } else if byte.is_ascii_alphabetic() || byte == b'_' {
+            while pos < bytes.len() && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_') {
+                pos += 1;
+            }
+            tokens.push(Token::Ident(sql[start..pos].to_owned()));
+        } else if byte.is_ascii_digit() {
+            while pos < bytes.len() && (bytes[pos].is_ascii_digit() || bytes[pos] == b'.') {
+                pos += 1;
+            }
+            let text = &sql[start..pos];
+            let number = if text.contains('.') {
+                text.parse::<f64>().ok().and_then(Number::from_f64)
+            } else {
+                text.parse::<i64>().ok().map(Number::from)
+            };
+            tokens.push(Token::Number(
+                number.ok_or_else(|| format!("invalid number: {text}"))?,
+            ));
+        } else if byte == b'\'' || byte == b'"' {
+            // The quote in a string is escaped by doubling it
+            let mut value = String::new();
+            pos += 1;
+            loop {
+                let end = match sql[pos..].find(byte as char) {
+                    Some(offset) => pos + offset,
+                    None => return Err("unterminated string".to_owned()),
+                };
+                value.push_str(&sql[pos..end]);
+                pos = end + 1;
+                if bytes.get(pos) == Some(&byte) {
+                    value.push(byte as char);
+                    pos += 1;
+                } else {
+                    break;
+                }
+            }
+            tokens.push(if byte == b'\'' {
+                Token::Str(value)
+            } else {
+                Token::Quoted(value)
+            });
+        } else {
+            let symbol = *SYMBOLS
+                .iter()
+                .find(|symbol| bytes[pos..].starts_with(symbol.as_bytes()))
+                .ok_or_else(|| format!("unexpected character at {pos}"))?;
+            pos += symbol.len();
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+// The precedence from low to high: OR, AND, NOT, comparisons, `+ -`,
+// `* / %`, unary `-`.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let matched =
+            matches!(self.tokens.get(self.pos), Some(Token::Symbol(value)) if *value == symbol);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let matched = matches!(
+            self.tokens.get(self.pos),
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword)
+        );
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(format!("expect {keyword}"))
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(format!("expect {symbol:?}"))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Ident(ident)) if !is_keyword(&ident) => Ok(ident),
+            _ => Err("expect an identifier".to_owned()),
+        }
+    }
+
+    // The left-associative binary operators of a precedence level
+    fn binary(
+        &mut self,
+        ops: &[(&str, BinaryOp)],
+        operand: fn(&mut Parser) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let mut left = operand(self)?;
+        loop {
+            let op = match self.tokens.get(self.pos) {
+                Some(Token::Symbol(symbol)) => ops.iter().find(|(name, _)| name == symbol),
+                Some(Token::Ident(ident)) => ops
+                    .iter()
+                    .find(|(name, _)| ident.eq_ignore_ascii_case(name)),
+                _ => None,
+            };
+            let op = match op {
+                Some((_, op)) => *op,
+                None => return Ok(left),
+            };
+            self.pos += 1;
+            let right = operand(self)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        self.binary(&[("OR", BinaryOp::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        self.binary(&[("AND", BinaryOp::And)], Parser::not)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.eat_keyword("NOT") {
+            Ok(Expr::Not(Box::new(self.not()?)))
+        } else {
+            self.comparison()
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        self.binary(
+            &[
+                ("=", BinaryOp::Eq),
+                ("!=", BinaryOp::Ne),
+                ("<>", BinaryOp::Ne),
+                ("<", BinaryOp::Lt),
+                ("<=", BinaryOp::Le),
+                (">", BinaryOp::Gt),
+                (">=", BinaryOp::Ge),
+            ],
+            Parser::additive,
+        )
+    }
+
+    fn additive(&mut self) -> Result<Expr, String> {
+        self.binary(
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+            Parser::multiplicative,
+        )
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, String> {
+        self.binary(
+            &[
+                ("*", BinaryOp::Mul),
+                ("/", BinaryOp::Div),
+                ("%", BinaryOp::Rem),
+            ],
+            Parser::unary,
+        )
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat_symbol("-") {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Literal(Value::Number(number))),
+            Some(Token::Str(value)) => Ok(Expr::Literal(Value::String(value))),
+            Some(Token::Symbol("(")) => {
+                let expr = self.or()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("TRUE") => {
+                Ok(Expr::Literal(Value::Bool(true)))
+            }
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("FALSE") => {
+                Ok(Expr::Literal(Value::Bool(false)))
+            }
+            Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case("NULL") => {
+                Ok(Expr::Literal(Value::Null))
+            }
+            Some(Token::Ident(column)) if !is_keyword(&column) => {
+                let mut segments = Vec::new();
+                loop {
+                    if self.eat_symbol(".") {
+                        // The keys can be keywords
+                        match self.next() {
+                            Some(Token::Ident(key)) => segments.push(PathSegment::Key(key)),
+                            _ => return Err("expect a key after \".\"".to_owned()),
+                        }
+                    } else if self.eat_symbol("[") {
+                        let segment = match self.next() {
+                            Some(Token::Number(idx)) if idx.is_u64() => {
+                                PathSegment::Index(idx.as_u64().unwrap_or(0) as usize)
+                            }
+                            Some(Token::Str(key)) => PathSegment::Key(key),
+                            _ => return Err("expect an index or a 'key' in \"[]\"".to_owned()),
+                        };
+                        segments.push(segment);
+                        self.expect_symbol("]")?;
+                    } else {
+                        break;
+                    }
+                }
+                Ok(Expr::Path(column, segments))
+            }
+            _ => Err("expect an expression".to_owned()),
+        }
+    }
+}
+
+fn is_keyword(ident: &str) -> bool {
+    KEYWORDS
+        .iter()
+        .any(|keyword| ident.eq_ignore_ascii_case(keyword))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn evaluate(sql: &str, topic_name: &str, payload: &str) -> Option<Value> {
+        let payload = Bytes::from(payload.to_owned());
+        let message = RuleMessage {
+            client_identifier: "client",
+            username: None,
+            topic_name,
+            qos: QoS::Level1,
+            retain: false,
+            payload: &payload,
+        };
+        let scope = Scope {
+            message: &message,
+            timestamp: 1000,
+            payload: OnceCell::new(),
+        };
+        let query = Query::parse(sql).unwrap();
+        if !query.matches(topic_name) {
+            return None;
+        }
+        query.evaluate(&scope).map(Value::Object)
+    }
+
+    #[test]
+    fn test_parse() {
+        let query = Query::parse(
+            r#"select payload.temp as t, payload.list[0] AS first, payload['a b'], clientid from "sensors/+/temp", "x/#" WHERE t > 50 AND NOT (t = 60)"#,
+        )
+        .unwrap();
+        let names: Vec<&str> = query
+            .fields
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["t", "first", "a b", "clientid"]);
+        assert_eq!(query.topic_filters, ["sensors/+/temp", "x/#"]);
+        assert!(query.condition.is_some());
+        assert!(Query::parse(r#"SELECT * FROM "a/b""#)
+            .unwrap()
+            .fields
+            .is_none());
+
+        for sql in [
+            r#"SELECT FROM "a""#,
+            r#"SELECT 1 FROM "a""#,
+            r#"SELECT payload.list[0] FROM "a""#,
+            r#"SELECT * FROM a"#,
+            r#"SELECT * FROM "a/#/b""#,
+            r#"SELECT * FROM "a" WHERE"#,
+            r#"SELECT * FROM "a" WHERE qos > 1 1"#,
+            r#"SELECT * FROM "a" WHERE topic = 'a"#,
+            r#"SELECT * FROM "a" WHERE qos ? 1"#,
+            r#"SELECT select FROM "a""#,
+        ] {
+            assert!(Query::parse(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let sql = r#"SELECT payload.temp AS t, payload.tags[1] AS tag, topic FROM "sensors/+/temp" WHERE t > 50"#;
+        assert_eq!(
+            evaluate(
+                sql,
+                "sensors/1/temp",
+                r#"{"temp": 51.5, "tags": ["a", "b"]}"#
+            ),
+            Some(json!({"t": 51.5, "tag": "b", "topic": "sensors/1/temp"}))
+        );
+        assert_eq!(evaluate(sql, "sensors/1/temp", r#"{"temp": 50}"#), None);
+        assert_eq!(evaluate(sql, "sensors/1/temp", r#"{"temp": "60"}"#), None);
+        assert_eq!(evaluate(sql, "sensors/1/temp", "not json"), None);
+        assert_eq!(evaluate(sql, "sensors/1/humidity", r#"{"temp": 60}"#), None);
+
+        assert_eq!(
+            evaluate(
+                r##"SELECT payload.a * 2 + 1 AS x, payload.a / 4 AS y, -payload.a % 3 AS z, 'id-' + clientid AS id FROM "#" WHERE payload.b = null OR NOT retain"##,
+                "a",
+                r#"{"a": 5}"#,
+            ),
+            Some(json!({"x": 11, "y": 1.25, "z": -2, "id": "id-client"}))
+        );
+        assert_eq!(
+            evaluate(
+                r#"SELECT payload, qos, payload.a / 0 AS n FROM "a" WHERE payload <> 'ping' AND qos >= 1"#,
+                "a",
+                "pong",
+            ),
+            Some(json!({"payload": "pong", "qos": 1, "n": null}))
+        );
+        assert_eq!(
+            evaluate(r#"SELECT * FROM "a""#, "a", r#"{"a": 1}"#),
+            Some(json!({
+                "clientid": "client",
+                "username": null,
+                "topic": "a",
+                "qos": 1,
+                "retain": false,
+                "timestamp": 1000,
+                "payload": {"a": 1},
+            }))
+        );
+    }
+
+    #[test]
+    fn test_render_fields() {
+        let output = json!({"id": "a/b", "t": 1.5, "n": null});
+        let output = output.as_object().unwrap();
+        let render = |template: &str| render_fields(template, |name| field_text(output, name));
+        assert_eq!(render("alerts/${id}/${t}"), "alerts/a/b/1.5");
+        assert_eq!(render("${n}${missing}-"), "-");
+        assert_eq!(render("a/${id"), "a/${id");
+    }
+}
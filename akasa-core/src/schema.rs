@@ -0,0 +1,798 @@
+//! Validate the payloads by the schemas of the topics (see
+//! `Config.payload_schemas`), the invalid messages are rejected or published
+//! to the dead letter topic.
+//!
+//! The JSON schemas support the keywords for the types, the values, the
+//! objects, the arrays and the combinations, the references and the regular
+//! expressions are not supported. The protobuf schemas are the message types
+//! of a `FileDescriptorSet`, the wire types of the known fields, the UTF-8 of
+//! the strings and the required fields (proto2) are checked.
+
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use hashbrown::{HashMap, HashSet};
+use mqtt_proto::{
+    v5::{PublishProperties, UserProperty},
+    QoS, TopicName,
+};
+use parking_lot::RwLock;
+use serde_json::Value;
+
+use crate::config::{PayloadSchema, SchemaFormat};
+use crate::metrics::Metrics;
+use crate::protobuf::{decode_fields, is_packed_varints, ProtoValue};
+use crate::protocols::mqtt::topic_match;
+use crate::state::GlobalState;
+
+// The keywords can't be checked without the references or the regular
+// expressions, so the schemas used them are rejected instead of ignored.
+const UNSUPPORTED_KEYWORDS: &[&str] = &[
+    "$ref",
+    "$dynamicRef",
+    "pattern",
+    "patternProperties",
+    "propertyNames",
+    "contains",
+    "if",
+    "then",
+    "else",
+    "dependentRequired",
+    "dependentSchemas",
+    "unevaluatedItems",
+    "unevaluatedProperties",
+];
+const JSON_TYPES: &[&str] = &[
+    "null", "boolean", "object", "array", "number", "string", "integer",
+];
+// Max nested levels of the protobuf messages
+const MAX_DEPTH: usize = 64;
+
+// The protobuf field types (`FieldDescriptorProto.Type`)
+const TYPE_GROUP: u64 = 10;
+const TYPE_MESSAGE: u64 = 11;
+const TYPE_STRING: u64 = 9;
+const TYPE_BYTES: u64 = 12;
+const FIXED64_TYPES: &[u64] = &[1, 6, 16];
+const FIXED32_TYPES: &[u64] = &[2, 7, 15];
+const VARINT_TYPES: &[u64] = &[3, 4, 5, 8, 13, 14, 17, 18];
+const LABEL_REQUIRED: u64 = 2;
+const LABEL_REPEATED: u64 = 3;
+
+/// The compiled schemas, replaced when the config is reloaded
+pub(crate) struct SchemaRegistry {
+    schemas: RwLock<Arc<Vec<Schema>>>,
+}
+
+impl SchemaRegistry {
+    pub(crate) fn new(schemas: &[PayloadSchema]) -> SchemaRegistry {
+        SchemaRegistry {
+            schemas: RwLock::new(Arc::new(compile_all(schemas))),
+        }
+    }
+
+    pub(crate) fn update(&self, schemas: &[PayloadSchema]) {
+        *self.schemas.write() = Arc::new(compile_all(schemas));
+    }
+}
+
+struct Schema {
+    topic_filter: String,
+    validator: Validator,
+    dead_letter_topic: Option<TopicName>,
+}
+
+/// A compiled schema
+pub(crate) enum Validator {
+    Json(Value),
+    Protobuf {
+        messages: HashMap<String, MessageType>,
+        // The full name with the leading dot
+        root: String,
+    },
+}
+
+pub(crate) struct MessageType {
+    fields: HashMap<u64, FieldType>,
+    required: Vec<u64>,
+}
+
+struct FieldType {
+    kind: u64,
+    repeated: bool,
+    type_name: String,
+}
+
+/// How the message failed the schema is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SchemaViolation {
+    /// Rejected (v5.x clients get PayloadFormatInvalid)
+    Rejected,
+    /// Published to the dead letter topic instead
+    DeadLettered,
+}
+
+// The invalid schemas are rejected by `Config::is_valid`, so they are only
+// skipped here.
+fn compile_all(schemas: &[PayloadSchema]) -> Vec<Schema> {
+    schemas
+        .iter()
+        .filter_map(|schema| {
+            Some(Schema {
+                topic_filter: schema.topic_filter.clone(),
+                validator: compile(&schema.format).ok()?,
+                dead_letter_topic: schema
+                    .dead_letter_topic
+                    .as_ref()
+                    .and_then(|topic| TopicName::try_from(topic.clone()).ok()),
+            })
+        })
+        .collect()
+}
+
+/// Validate the payload by the first matched schema, the message is
+/// published to the dead letter topic of the schema if set.
+pub(crate) fn check(
+    global: &GlobalState,
+    topic_name: &str,
+    payload: &Bytes,
+) -> Option<SchemaViolation> {
+    let schemas = Arc::clone(&global.payload_schemas.schemas.read());
+    let schema = schemas
+        .iter()
+        .find(|schema| topic_match(&schema.topic_filter, topic_name))?;
+    let reason = schema.validator.validate(payload).err()?;
+    tracing::debug!("payload invalid, {}: {}", reason, topic_name);
+    Metrics::incr(&global.metrics.schema_rejected);
+    let dead_letter_topic = match schema.dead_letter_topic.as_ref() {
+        Some(topic) => topic.clone(),
+        None => return Some(SchemaViolation::Rejected),
+    };
+    // The original topic and the reason are kept in the user properties
+    let properties = PublishProperties {
+        user_properties: vec![
+            UserProperty {
+                name: Arc::new("topic".to_owned()),
+                value: Arc::new(topic_name.to_owned()),
+            },
+            UserProperty {
+                name: Arc::new("error".to_owned()),
+                value: Arc::new(reason),
+            },
+        ],
+        ..Default::default()
+    };
+    if let Err(err) = global.publish(
+        dead_letter_topic,
+        QoS::Level1,
+        false,
+        payload.clone(),
+        properties,
+    ) {
+        tracing::warn!("publish schema dead letter error: {}", err);
+    }
+    Some(SchemaViolation::DeadLettered)
+}
+
+/// Compile a schema, return the reason if the schema is invalid
+pub(crate) fn compile(format: &SchemaFormat) -> Result<Validator, String> {
+    match format {
+        SchemaFormat::JsonSchema(schema) => {
+            check_json_schema(schema)?;
+            Ok(Validator::Json(schema.clone()))
+        }
+        SchemaFormat::Protobuf {
+            descriptor_set,
+            message,
+        } => {
+            let descriptor_set = STANDARD
+                .decode(descriptor_set)
+                .map_err(|_| "invalid base64 descriptor set".to_owned())?;
+            let messages = parse_descriptor_set(&descriptor_set)
+                .ok_or_else(|| "invalid descriptor set".to_owned())?;
+            let root = format!(".{}", message);
+            if !messages.contains_key(&root) {
+                return Err(format!("message type not found: {}", message));
+            }
+            for (name, message_type) in &messages {
+                for field in message_type.fields.values() {
+                    if field.kind == TYPE_GROUP {
+                        return Err(format!("groups are not supported: {}", &name[1..]));
+                    }
+                    if field.kind == TYPE_MESSAGE && !messages.contains_key(&field.type_name) {
+                        return Err(format!("message type not found: {}", field.type_name));
+                    }
+                }
+            }
+            Ok(Validator::Protobuf { messages, root })
+        }
+    }
+}
+
+impl Validator {
+    /// Return the reason if the payload is invalid
+    pub(crate) fn validate(&self, payload: &[u8]) -> Result<(), String> {
+        match self {
+            Validator::Json(schema) => {
+                let value: Value = serde_json::from_slice(payload)
+                    .map_err(|err| format!("invalid JSON: {}", err))?;
+                validate_json(schema, &value, "")
+            }
+            Validator::Protobuf { messages, root } => validate_message(messages, root, payload, 0),
+        }
+    }
+}
+
+fn check_json_schema(schema: &Value) -> Result<(), String> {
+    let object = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(object) => object,
+        _ => return Err("schema must be an object or a boolean".to_owned()),
+    };
+    for (keyword, value) in object {
+        if UNSUPPORTED_KEYWORDS.contains(&keyword.as_str()) {
+            return Err(format!("unsupported keyword: {}", keyword));
+        }
+        let valid = match keyword.as_str() {
+            "type" => match value {
+                Value::String(name) => JSON_TYPES.contains(&name.as_str()),
+                Value::Array(names) => names
+                    .iter()
+                    .all(|name| name.as_str().is_some_and(|name| JSON_TYPES.contains(&name))),
+                _ => false,
+            },
+            "enum" => value.is_array(),
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" => value.is_number(),
+            "multipleOf" => value.as_f64().is_some_and(|value| value > 0.0),
+            "minLength" | "maxLength" | "minItems" | "maxItems" | "minProperties"
+            | "maxProperties" => value.is_u64(),
+            "uniqueItems" => value.is_boolean(),
+            "required" => value
+                .as_array()
+                .is_some_and(|names| names.iter().all(Value::is_string)),
+            "properties" => match value.as_object() {
+                Some(properties) => {
+                    for schema in properties.values() {
+                        check_json_schema(schema)?;
+                    }
+                    true
+                }
+                None => false,
+            },
+            "allOf" | "anyOf" | "oneOf" | "prefixItems" => match value.as_array() {
+                Some(schemas) if !schemas.is_empty() => {
+                    for schema in schemas {
+                        check_json_schema(schema)?;
+                    }
+                    true
+                }
+                _ => false,
+            },
+            "items" | "additionalProperties" | "not" => {
+                check_json_schema(value)?;
+                true
+            }
+            // The annotations and the unknown keywords are ignored
+            _ => true,
+        };
+        if !valid {
+            return Err(format!("invalid keyword: {}", keyword));
+        }
+    }
+    Ok(())
+}
+
+fn validate_json(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let object = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Object(object) => object,
+        _ => return Err(format!("{}: not allowed", display_path(path))),
+    };
+    let invalid = |keyword: &str| Err(format!("{}: failed {}", display_path(path), keyword));
+    for (keyword, expected) in object {
+        let valid = match keyword.as_str() {
+            "type" => match expected {
+                Value::String(name) => is_type(value, name),
+                Value::Array(names) => names
+                    .iter()
+                    .any(|name| name.as_str().is_some_and(|name| is_type(value, name))),
+                _ => false,
+            },
+            "enum" => expected
+                .as_array()
+                .is_some_and(|values| values.iter().any(|item| json_eq(item, value))),
+            "const" => json_eq(expected, value),
+            "minimum" => compare(value, expected, |value, limit| value >= limit),
+            "maximum" => compare(value, expected, |value, limit| value <= limit),
+            "exclusiveMinimum" => compare(value, expected, |value, limit| value > limit),
+            "exclusiveMaximum" => compare(value, expected, |value, limit| value < limit),
+            "multipleOf" => compare(value, expected, |value, divisor| {
+                let quotient = value / divisor;
+                (quotient - quotient.round()).abs() < 1e-9
+            }),
+            "minLength" | "maxLength" => match (value.as_str(), expected.as_u64()) {
+                (Some(text), Some(limit)) => {
+                    let len = text.chars().count() as u64;
+                    if keyword == "minLength" {
+                        len >= limit
+                    } else {
+                        len <= limit
+                    }
+                }
+                _ => true,
+            },
+            "minItems" | "maxItems" => match (value.as_array(), expected.as_u64()) {
+                (Some(items), Some(limit)) => {
+                    let len = items.len() as u64;
+                    if keyword == "minItems" {
+                        len >= limit
+                    } else {
+                        len <= limit
+                    }
+                }
+                _ => true,
+            },
+            "minProperties" | "maxProperties" => match (value.as_object(), expected.as_u64()) {
+                (Some(properties), Some(limit)) => {
+                    let len = properties.len() as u64;
+                    if keyword == "minProperties" {
+                        len >= limit
+                    } else {
+                        len <= limit
+                    }
+                }
+                _ => true,
+            },
+            "uniqueItems" => match value.as_array() {
+                Some(items) if expected.as_bool() == Some(true) => items
+                    .iter()
+                    .enumerate()
+                    .all(|(idx, item)| !items[..idx].iter().any(|other| json_eq(item, other))),
+                _ => true,
+            },
+            "required" => match (value.as_object(), expected.as_array()) {
+                (Some(properties), Some(names)) => names
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .all(|name| properties.contains_key(name)),
+                _ => true,
+            },
+            "properties" => {
+                if let (Some(properties), Some(schemas)) = (value.as_object(), expected.as_object())
+                {
+                    for (name, schema) in schemas {
+                        if let Some(property) = properties.get(name) {
+                            validate_json(schema, property, &format!("{}/{}", path, name))?;
+                        }
+                    }
+                }
+                true
+            }
+            "additionalProperties" => {
+                if let Some(properties) = value.as_object() {
+                    let known = object.get("properties").and_then(Value::as_object);
+                    for (name, property) in properties {
+                        if !known.is_some_and(|known| known.contains_key(name)) {
+                            validate_json(expected, property, &format!("{}/{}", path, name))?;
+                        }
+                    }
+                }
+                true
+            }
+            "prefixItems" => {
+                if let (Some(items), Some(schemas)) = (value.as_array(), expected.as_array()) {
+                    for (idx, (schema, item)) in schemas.iter().zip(items).enumerate() {
+                        validate_json(schema, item, &format!("{}/{}", path, idx))?;
+                    }
+                }
+                true
+            }
+            "items" => {
+                if let Some(items) = value.as_array() {
+                    let skip = object
+                        .get("prefixItems")
+                        .and_then(Value::as_array)
+                        .map_or(0, Vec::len);
+                    for (idx, item) in items.iter().enumerate().skip(skip) {
+                        validate_json(expected, item, &format!("{}/{}", path, idx))?;
+                    }
+                }
+                true
+            }
+            "allOf" => {
+                for schema in expected.as_array().into_iter().flatten() {
+                    validate_json(schema, value, path)?;
+                }
+                true
+            }
+            "anyOf" => expected.as_array().is_some_and(|schemas| {
+                schemas
+                    .iter()
+                    .any(|schema| validate_json(schema, value, path).is_ok())
+            }),
+            "oneOf" => expected.as_array().is_some_and(|schemas| {
+                schemas
+                    .iter()
+                    .filter(|schema| validate_json(schema, value, path).is_ok())
+                    .count()
+                    == 1
+            }),
+            "not" => validate_json(expected, value, path).is_err(),
+            _ => true,
+        };
+        if !valid {
+            return invalid(keyword);
+        }
+    }
+    Ok(())
+}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "integer" => {
+            value.is_i64()
+                || value.is_u64()
+                || value.as_f64().is_some_and(|value| value.fract() == 0.0)
+        }
+        _ => false,
+    }
+}
+
+// The numbers are equal by their values (`1` equals `1.0`)
+fn json_eq(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.as_f64() == right.as_f64(),
+        (Value::Array(left), Value::Array(right)) => {
+            left.len() == right.len() && left.iter().zip(right).all(|(a, b)| json_eq(a, b))
+        }
+        (Value::Object(left), Value::Object(right)) => {
+            left.len() == right.len()
+                && left
+                    .iter()
+                    .all(|(name, a)| right.get(name).is_some_and(|b| json_eq(a, b)))
+        }
+        _ => left == right,
+    }
+}
+
+// The keywords of the numbers ignore the other types
+fn compare(value: &Value, expected: &Value, f: impl Fn(f64, f64) -> bool) -> bool {
+    match (value.as_f64(), expected.as_f64()) {
+        (Some(value), Some(expected)) => f(value, expected),
+        _ => true,
+    }
+}
+
+// FileDescriptorSet { repeated FileDescriptorProto file = 1; }
+fn parse_descriptor_set(buf: &[u8]) -> Option<HashMap<String, MessageType>> {
+    let mut messages = HashMap::new();
+    for (number, value) in decode_fields(buf)? {
+        if let (1, ProtoValue::Bytes(file)) = (number, value) {
+            let fields = decode_fields(file)?;
+            let package = fields
+                .iter()
+                .find_map(|field| match field {
+                    (2, ProtoValue::Bytes(package)) => std::str::from_utf8(package).ok(),
+                    _ => None,
+                })
+                .unwrap_or_default();
+            let prefix = if package.is_empty() {
+                String::new()
+            } else {
+                format!(".{}", package)
+            };
+            for field in fields {
+                if let (4, ProtoValue::Bytes(message)) = field {
+                    parse_message(message, &prefix, &mut messages)?;
+                }
+            }
+        }
+    }
+    Some(messages)
+}
+
+// DescriptorProto { string name = 1; repeated FieldDescriptorProto field = 2;
+// repeated DescriptorProto nested_type = 3; }
+fn parse_message(
+    buf: &[u8],
+    prefix: &str,
+    messages: &mut HashMap<String, MessageType>,
+) -> Option<()> {
+    let fields = decode_fields(buf)?;
+    let name = fields.iter().find_map(|field| match field {
+        (1, ProtoValue::Bytes(name)) => std::str::from_utf8(name).ok(),
+        _ => None,
+    })?;
+    let full_name = format!("{}.{}", prefix, name);
+    let mut message_type = MessageType {
+        fields: HashMap::new(),
+        required: Vec::new(),
+    };
+    for field in fields {
+        match field {
+            (2, ProtoValue::Bytes(field)) => {
+                let (number, field_type, label) = parse_field(field)?;
+                if label == LABEL_REQUIRED {
+                    message_type.required.push(number);
+                }
+                message_type.fields.insert(number, field_type);
+            }
+            (3, ProtoValue::Bytes(nested)) => parse_message(nested, &full_name, messages)?,
+            _ => {}
+        }
+    }
+    messages.insert(full_name, message_type);
+    Some(())
+}
+
+// FieldDescriptorProto { int32 number = 3; Label label = 4; Type type = 5;
+// string type_name = 6; }
+fn parse_field(buf: &[u8]) -> Option<(u64, FieldType, u64)> {
+    let (mut number, mut label, mut kind, mut type_name) = (None, 1, None, String::new());
+    for field in decode_fields(buf)? {
+        match field {
+            (3, ProtoValue::Varint(value)) => number = Some(value),
+            (4, ProtoValue::Varint(value)) => label = value,
+            (5, ProtoValue::Varint(value)) => kind = Some(value),
+            (6, ProtoValue::Bytes(value)) => {
+                type_name = std::str::from_utf8(value).ok()?.to_owned()
+            }
+            _ => {}
+        }
+    }
+    let field_type = FieldType {
+        kind: kind?,
+        repeated: label == LABEL_REPEATED,
+        type_name,
+    };
+    Some((number?, field_type, label))
+}
+
+fn validate_message(
+    messages: &HashMap<String, MessageType>,
+    name: &str,
+    buf: &[u8],
+    depth: usize,
+) -> Result<(), String> {
+    let display_name = &name[1..];
+    if depth > MAX_DEPTH {
+        return Err(format!("too many nested messages: {}", display_name));
+    }
+    let message_type = &messages[name];
+    let fields = decode_fields(buf).ok_or_else(|| format!("invalid protobuf: {}", display_name))?;
+    let mut present = HashSet::new();
+    for (number, value) in fields {
+        // The unknown fields are allowed
+        let field = match message_type.fields.get(&number) {
+            Some(field) => field,
+            None => continue,
+        };
+        present.insert(number);
+        let valid = match value {
+            ProtoValue::Varint(_) => VARINT_TYPES.contains(&field.kind),
+            ProtoValue::Fixed64(_) => FIXED64_TYPES.contains(&field.kind),
+            ProtoValue::Fixed32(_) => FIXED32_TYPES.contains(&field.kind),
+            ProtoValue::Bytes(value) => match field.kind {
+                TYPE_STRING => std::str::from_utf8(value).is_ok(),
+                TYPE_BYTES => true,
+                TYPE_MESSAGE => {
+                    validate_message(messages, &field.type_name, value, depth + 1)?;
+                    true
+                }
+                // The packed repeated scalars
+                kind if field.repeated && VARINT_TYPES.contains(&kind) => is_packed_varints(value),
+                kind if field.repeated && FIXED64_TYPES.contains(&kind) => value.len() % 8 == 0,
+                kind if field.repeated && FIXED32_TYPES.contains(&kind) => value.len() % 4 == 0,
+                _ => false,
+            },
+        };
+        if !valid {
+            return Err(format!("invalid field {} of {}", number, display_name));
+        }
+    }
+    match message_type
+        .required
+        .iter()
+        .find(|number| !present.contains(*number))
+    {
+        Some(number) => Err(format!(
+            "missing required field {} of {}",
+            number, display_name
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+
+    use super::*;
+    use crate::protobuf::{put_bytes_field, put_varint_field};
+
+    fn json_validator(schema: Value) -> Validator {
+        compile(&SchemaFormat::JsonSchema(schema)).unwrap()
+    }
+
+    fn field_descriptor(
+        name: &str,
+        number: u64,
+        label: u64,
+        kind: u64,
+        type_name: &str,
+    ) -> BytesMut {
+        let mut field = BytesMut::new();
+        put_bytes_field(&mut field, 1, name.as_bytes());
+        put_varint_field(&mut field, 3, number);
+        put_varint_field(&mut field, 4, label);
+        put_varint_field(&mut field, 5, kind);
+        put_bytes_field(&mut field, 6, type_name.as_bytes());
+        field
+    }
+
+    // package demo;
+    // message Reading { required string device = 1; repeated sint32 values = 2;
+    //   Location location = 3; message Location { double lat = 1; double lon = 2; } }
+    fn descriptor_set() -> String {
+        let mut location = BytesMut::new();
+        put_bytes_field(&mut location, 1, b"Location");
+        put_bytes_field(&mut location, 2, &field_descriptor("lat", 1, 1, 1, ""));
+        put_bytes_field(&mut location, 2, &field_descriptor("lon", 2, 1, 1, ""));
+        let mut reading = BytesMut::new();
+        put_bytes_field(&mut reading, 1, b"Reading");
+        put_bytes_field(&mut reading, 2, &field_descriptor("device", 1, 2, 9, ""));
+        put_bytes_field(&mut reading, 2, &field_descriptor("values", 2, 3, 17, ""));
+        put_bytes_field(
+            &mut reading,
+            2,
+            &field_descriptor("location", 3, 1, 11, ".demo.Reading.Location"),
+        );
+        put_bytes_field(&mut reading, 3, &location);
+        let mut file = BytesMut::new();
+        put_bytes_field(&mut file, 1, b"demo.proto");
+        put_bytes_field(&mut file, 2, b"demo");
+        put_bytes_field(&mut file, 4, &reading);
+        let mut set = BytesMut::new();
+        put_bytes_field(&mut set, 1, &file);
+        STANDARD.encode(set)
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let validator = json_validator(serde_json::json!({
+            "type": "object",
+            "required": ["id", "temp"],
+            "properties": {
+                "id": {"type": "string", "minLength": 1},
+                "temp": {"type": "number", "minimum": -40, "maximum": 125},
+                "unit": {"enum": ["C", "F"]},
+                "tags": {"type": "array", "items": {"type": "string"}, "uniqueItems": true},
+            },
+            "additionalProperties": false,
+        }));
+        for payload in [
+            r#"{"id": "a", "temp": 21.5}"#,
+            r#"{"id": "a", "temp": 0, "unit": "C", "tags": ["x", "y"]}"#,
+        ] {
+            assert_eq!(validator.validate(payload.as_bytes()), Ok(()), "{payload}");
+        }
+        for (payload, reason) in [
+            (r#"{"id": "a"}"#, "/: failed required"),
+            (r#"{"id": "", "temp": 1}"#, "/id: failed minLength"),
+            (r#"{"id": "a", "temp": 200}"#, "/temp: failed maximum"),
+            (
+                r#"{"id": "a", "temp": 1, "unit": "K"}"#,
+                "/unit: failed enum",
+            ),
+            (
+                r#"{"id": "a", "temp": 1, "tags": ["x", 1]}"#,
+                "/tags/1: failed type",
+            ),
+            (
+                r#"{"id": "a", "temp": 1, "tags": ["x", "x"]}"#,
+                "/tags: failed uniqueItems",
+            ),
+            (
+                r#"{"id": "a", "temp": 1, "other": 1}"#,
+                "/other: not allowed",
+            ),
+            (r#"[1]"#, "/: failed type"),
+        ] {
+            assert_eq!(
+                validator.validate(payload.as_bytes()),
+                Err(reason.to_owned()),
+                "{payload}"
+            );
+        }
+        assert!(validator.validate(b"not json").is_err());
+
+        let validator = json_validator(serde_json::json!({
+            "oneOf": [{"type": "integer"}, {"type": "number", "multipleOf": 0.5}],
+        }));
+        assert!(validator.validate(b"1.5").is_ok());
+        assert!(validator.validate(b"1.25").is_err());
+        // Matched by both
+        assert!(validator.validate(b"2").is_err());
+    }
+
+    #[test]
+    fn test_invalid_json_schema() {
+        for schema in [
+            serde_json::json!({"type": "text"}),
+            serde_json::json!({"properties": {"id": {"pattern": "^a"}}}),
+            serde_json::json!({"$ref": "#/$defs/id"}),
+            serde_json::json!({"anyOf": []}),
+            serde_json::json!({"minLength": -1}),
+            serde_json::json!([]),
+        ] {
+            assert!(
+                compile(&SchemaFormat::JsonSchema(schema.clone())).is_err(),
+                "{schema}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_protobuf() {
+        let validator = compile(&SchemaFormat::Protobuf {
+            descriptor_set: descriptor_set(),
+            message: "demo.Reading".to_owned(),
+        })
+        .unwrap();
+        let mut location = BytesMut::new();
+        location.put_u8((1 << 3) | 1);
+        location.put_f64_le(31.2);
+        let mut reading = BytesMut::new();
+        put_bytes_field(&mut reading, 1, b"device1");
+        // Packed [1, -1]
+        put_bytes_field(&mut reading, 2, &[0x02, 0x01]);
+        // Unpacked 2
+        put_varint_field(&mut reading, 2, 4);
+        put_bytes_field(&mut reading, 3, &location);
+        // Unknown field
+        put_varint_field(&mut reading, 9, 1);
+        assert_eq!(validator.validate(&reading), Ok(()));
+
+        let mut missing = BytesMut::new();
+        put_varint_field(&mut missing, 2, 4);
+        assert_eq!(
+            validator.validate(&missing),
+            Err("missing required field 1 of demo.Reading".to_owned())
+        );
+        let mut invalid_string = BytesMut::new();
+        put_bytes_field(&mut invalid_string, 1, &[0xff, 0xfe]);
+        assert_eq!(
+            validator.validate(&invalid_string),
+            Err("invalid field 1 of demo.Reading".to_owned())
+        );
+        let mut invalid_nested = BytesMut::new();
+        put_bytes_field(&mut invalid_nested, 1, b"device1");
+        put_bytes_field(&mut invalid_nested, 3, &[0x08, 0x01]);
+        assert_eq!(
+            validator.validate(&invalid_nested),
+            Err("invalid field 1 of demo.Reading.Location".to_owned())
+        );
+        assert!(validator.validate(b"{\"device\": 1}").is_err());
+
+        assert!(compile(&SchemaFormat::Protobuf {
+            descriptor_set: descriptor_set(),
+            message: "demo.Other".to_owned(),
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,642 @@
+//! The admin HTTP server
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use mqtt_proto::{
+    v5::{PublishProperties, UserProperty},
+    TopicFilter, TopicName,
+};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+
+use super::http::{percent_decode, read_request, write_response, Request, Response};
+use super::stream::{self, STREAM_PATH};
+use crate::audit::AuditEvent;
+use crate::ban::{save_bans, Ban, BanKind};
+use crate::config::{qos_from_value, Listeners};
+use crate::forward::Forwarded;
+use crate::protocols::mqtt::{
+    get_unix_ts, payload_rejected, retain_rejected, PacketRecord, PacketTraceOptions, RetainContent,
+};
+use crate::publish::{self, ServerMessage};
+use crate::schema::{self, SchemaViolation};
+use crate::sparkplug;
+use crate::state::GlobalState;
+
+// Wait before accepting again after an accept error
+const ACCEPT_ERROR_DELAY_MS: u64 = 100;
+
+/// The body of `POST /api/v1/publish`
+#[derive(Deserialize)]
+struct PublishRequest {
+    topic: String,
+    payload: String,
+    /// The payload is encoded by base64
+    base64: Option<bool>,
+    qos: Option<u8>,
+    retain: Option<bool>,
+    properties: Option<PublishRequestProperties>,
+}
+
+/// The v5.0 properties of the published message
+#[derive(Deserialize)]
+struct PublishRequestProperties {
+    payload_format_indicator: Option<bool>,
+    message_expiry_interval: Option<u32>,
+    content_type: Option<String>,
+    response_topic: Option<String>,
+    /// Encoded by base64
+    correlation_data: Option<String>,
+    user_properties: Option<Vec<(String, String)>>,
+}
+
+pub(crate) async fn serve(addr: SocketAddr, global: Arc<GlobalState>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("Listen admin@{} success!", addr);
+    loop {
+        let (mut conn, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Like EMFILE, the server must keep running
+                tracing::warn!("admin accept error: {}", err);
+                sleep(Duration::from_millis(ACCEPT_ERROR_DELAY_MS)).await;
+                continue;
+            }
+        };
+        let global = Arc::clone(&global);
+        tokio::spawn(async move {
+            let response = match read_request(&mut conn).await {
+                // The stream takes over the connection
+                Ok(request) if request.method == "GET" && request.path == STREAM_PATH => {
+                    if let Err(err) = stream::serve(&global, peer, request, conn).await {
+                        tracing::debug!("admin stream to {} error: {}", peer, err);
+                    }
+                    return;
+                }
+                Ok(request) => {
+                    tracing::debug!(
+                        "admin request from {}: {} {} ({} bytes)",
+                        peer,
+                        request.method,
+                        request.path,
+                        request.body.len(),
+                    );
+                    handle_request(&global, peer, request).await
+                }
+                Err(err) => {
+                    tracing::debug!("read admin request from {} error: {}", peer, err);
+                    Response::text(400, "bad request")
+                }
+            };
+            if let Err(err) = write_response(&mut conn, &response).await {
+                tracing::debug!("write admin response to {} error: {}", peer, err);
+            }
+        });
+    }
+}
+
+pub(crate) async fn handle_request(
+    global: &GlobalState,
+    peer: SocketAddr,
+    request: Request,
+) -> Response {
+    let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+    // The endpoints changing the state require the admin token (the publish
+    // API has its own token), the unknown methods are rejected before routing
+    match request.method.as_str() {
+        "GET" | "HEAD" => {}
+        "POST" | "PUT" | "DELETE" => {
+            if segments != ["api", "v1", "publish"] {
+                let config = global.config();
+                let token = config.admin.as_ref().and_then(|admin| admin.token.as_ref());
+                if let Err(response) = check_token(&request, token, "admin token not set") {
+                    return response;
+                }
+            }
+        }
+        _ => return Response::text(405, "method not allowed"),
+    }
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["healthz"]) => {
+            if global.health.executor_alive() {
+                Response::text(200, "ok")
+            } else {
+                Response::text(503, "executor not alive")
+            }
+        }
+        ("GET", ["readyz"]) => {
+            let report = global.health.report();
+            let status = if report.ready { 200 } else { 503 };
+            Response::json(status, &report)
+        }
+        ("GET", ["api", "v1", "alarms"]) => Response::json(200, &global.health.alarms()),
+        ("GET", ["api", "v1", "metrics"]) => {
+            let metrics = &global.metrics;
+            Response::json(
+                200,
+                &serde_json::json!({
+                    "slow_consumers": metrics.slow_consumers.load(Ordering::Relaxed),
+                    "pending_dropped": metrics.pending_dropped.load(Ordering::Relaxed),
+                    "pending_overflow_disconnects": metrics
+                        .pending_overflow_disconnects
+                        .load(Ordering::Relaxed),
+                    "retransmit_disconnects": metrics.retransmit_disconnects.load(Ordering::Relaxed),
+                    "retain_dropped": metrics.retain_dropped.load(Ordering::Relaxed),
+                    "payload_rejected": metrics.payload_rejected.load(Ordering::Relaxed),
+                    "schema_rejected": metrics.schema_rejected.load(Ordering::Relaxed),
+                    "broadcast_dropped": metrics.broadcast_dropped.load(Ordering::Relaxed),
+                    "buffer_pool_hits": metrics.buffer_pool_hits.load(Ordering::Relaxed),
+                    "buffer_pool_misses": metrics.buffer_pool_misses.load(Ordering::Relaxed),
+                    "qos0_shed": metrics.qos0_shed.load(Ordering::Relaxed),
+                    "memory_refused_connects": metrics
+                        .memory_refused_connects
+                        .load(Ordering::Relaxed),
+                    "fan_out_jobs": metrics.fan_out_jobs.load(Ordering::Relaxed),
+                    "inbound_throttled": metrics.inbound_throttled.load(Ordering::Relaxed),
+                    "throttled_connects": metrics.throttled_connects.load(Ordering::Relaxed),
+                    "quota_exceeded": metrics.quota_exceeded.load(Ordering::Relaxed),
+                    "banned_connects": metrics.banned_connects.load(Ordering::Relaxed),
+                    "flapping_bans": metrics.flapping_bans.load(Ordering::Relaxed),
+                    "kafka_sent": metrics.kafka_sent.load(Ordering::Relaxed),
+                    "kafka_dropped": metrics.kafka_dropped.load(Ordering::Relaxed),
+                    "amqp_sent": metrics.amqp_sent.load(Ordering::Relaxed),
+                    "amqp_received": metrics.amqp_received.load(Ordering::Relaxed),
+                    "amqp_dropped": metrics.amqp_dropped.load(Ordering::Relaxed),
+                    "webhook_forward_sent": metrics.webhook_forward_sent.load(Ordering::Relaxed),
+                    "webhook_forward_failed": metrics.webhook_forward_failed.load(Ordering::Relaxed),
+                    "webhook_forward_dropped": metrics.webhook_forward_dropped.load(Ordering::Relaxed),
+                    "tsdb_written": metrics.tsdb_written.load(Ordering::Relaxed),
+                    "tsdb_failed": metrics.tsdb_failed.load(Ordering::Relaxed),
+                    "tsdb_dropped": metrics.tsdb_dropped.load(Ordering::Relaxed),
+                    "rules_matched": metrics.rules_matched.load(Ordering::Relaxed),
+                    "rules_failed": metrics.rules_failed.load(Ordering::Relaxed),
+                    "sparkplug_sequence_errors": metrics.sparkplug_sequence_errors.load(Ordering::Relaxed),
+                    "latency": metrics.latency.report(),
+                    "allocator": metrics.allocator_stats(),
+                    "retained_messages": global.retain_table.len(),
+                    "retained_bytes": global.retain_table.bytes(),
+                    "pending_bytes": global.memory.pending(),
+                    "broadcast_bytes": global.memory.broadcast(),
+                }),
+            )
+        }
+        ("GET", ["api", "v1", "topics", "stats"]) => {
+            Response::json(200, &global.topic_stats.report())
+        }
+        ("GET", ["api", "v1", "clients", "top"]) => {
+            Response::json(200, &global.client_stats.top_talkers())
+        }
+        ("GET", ["api", "v1", "sessions"]) => {
+            let sessions: Vec<_> = global
+                .sessions()
+                .into_iter()
+                .map(|(client_identifier, online)| {
+                    serde_json::json!({
+                        "client_identifier": client_identifier,
+                        "online": online,
+                    })
+                })
+                .collect();
+            Response::json(200, &sessions)
+        }
+        ("GET", ["api", "v1", "sessions", client_identifier]) => {
+            let client_identifier = match percent_decode(client_identifier) {
+                Some(value) => value,
+                None => return Response::text(400, "invalid client identifier"),
+            };
+            match global.inspect_session(&client_identifier).await {
+                Some(info) => Response::json(200, &info),
+                None => Response::not_found(),
+            }
+        }
+        ("POST", ["api", "v1", "sessions", client_identifier, "redirect"]) => {
+            let client_identifier = match percent_decode(client_identifier) {
+                Some(value) => value,
+                None => return Response::text(400, "invalid client identifier"),
+            };
+            let server_reference = match request.query_param("server") {
+                Some(value) if !value.is_empty() => value,
+                _ => return Response::text(400, "missing server reference"),
+            };
+            let permanent = request.query_param("permanent").as_deref() == Some("true");
+            if !global
+                .redirect_session(
+                    &client_identifier,
+                    Arc::new(server_reference.clone()),
+                    permanent,
+                )
+                .await
+            {
+                return Response::not_found();
+            }
+            global.audit(AuditEvent::AdminAction {
+                peer,
+                action: "redirect_client".to_owned(),
+                detail: format!(
+                    "client: {}, server: {}, permanent: {}",
+                    client_identifier, server_reference, permanent
+                ),
+            });
+            Response::json(200, &serde_json::json!({ "redirected": true }))
+        }
+        ("GET", ["api", "v1", "traces"]) => Response::json(200, &global.packet_tracer.clients()),
+        ("GET", ["api", "v1", "traces", client_identifier]) => {
+            let client_identifier = match percent_decode(client_identifier) {
+                Some(value) => value,
+                None => return Response::text(400, "invalid client identifier"),
+            };
+            match global.packet_tracer.records(&client_identifier) {
+                Some(records) => Response::json(200, &trace_lines(&records)),
+                None => Response::not_found(),
+            }
+        }
+        ("POST", ["api", "v1", "traces", client_identifier]) => {
+            let client_identifier = match percent_decode(client_identifier) {
+                Some(value) => value,
+                None => return Response::text(400, "invalid client identifier"),
+            };
+            let mut options = PacketTraceOptions::default();
+            if let Some(capacity) = request.query_param("capacity") {
+                match capacity.parse() {
+                    Ok(capacity) => options.capacity = capacity,
+                    Err(_) => return Response::text(400, "invalid capacity"),
+                }
+            }
+            options.raw_bytes = request.query_param("raw_bytes").as_deref() == Some("true");
+            if let Err(err) = global.packet_tracer.start(&client_identifier, options) {
+                return Response::text(500, err.to_string());
+            }
+            global.audit(AuditEvent::AdminAction {
+                peer,
+                action: "start_trace".to_owned(),
+                detail: format!("client: {}", client_identifier),
+            });
+            Response::json(200, &serde_json::json!({ "tracing": true }))
+        }
+        ("DELETE", ["api", "v1", "traces", client_identifier]) => {
+            let client_identifier = match percent_decode(client_identifier) {
+                Some(value) => value,
+                None => return Response::text(400, "invalid client identifier"),
+            };
+            let records = match global.packet_tracer.stop(&client_identifier) {
+                Some(records) => records,
+                None => return Response::not_found(),
+            };
+            global.audit(AuditEvent::AdminAction {
+                peer,
+                action: "stop_trace".to_owned(),
+                detail: format!("client: {}", client_identifier),
+            });
+            Response::json(200, &trace_lines(&records))
+        }
+        ("GET", ["api", "v1", "bans"]) => Response::json(200, &global.bans.list()),
+        ("POST", ["api", "v1", "bans", kind, value]) => {
+            let (kind, value) = match ban_target(kind, value) {
+                Ok(target) => target,
+                Err(response) => return response,
+            };
+            let expire_at = match request.query_param("expire_secs") {
+                Some(secs) => match secs.parse::<u64>() {
+                    Ok(secs) => Some(get_unix_ts() + secs),
+                    Err(_) => return Response::text(400, "invalid expire seconds"),
+                },
+                None => None,
+            };
+            let ban = Ban {
+                kind,
+                value,
+                reason: request.query_param("reason"),
+                expire_at,
+            };
+            global.bans.add(ban.clone());
+            if let Err(err) = save_bans(global) {
+                tracing::error!("save bans error: {}", err);
+            }
+            global.audit(AuditEvent::AdminAction {
+                peer,
+                action: "ban".to_owned(),
+                detail: format!(
+                    "{:?}: {}, expire_at: {:?}",
+                    ban.kind, ban.value, ban.expire_at
+                ),
+            });
+            Response::json(200, &ban)
+        }
+        ("DELETE", ["api", "v1", "bans", kind, value]) => {
+            let (kind, value) = match ban_target(kind, value) {
+                Ok(target) => target,
+                Err(response) => return response,
+            };
+            if !global.bans.remove(kind, &value) {
+                return Response::not_found();
+            }
+            if let Err(err) = save_bans(global) {
+                tracing::error!("save bans error: {}", err);
+            }
+            global.audit(AuditEvent::AdminAction {
+                peer,
+                action: "unban".to_owned(),
+                detail: format!("{:?}: {}", kind, value),
+            });
+            Response::json(200, &serde_json::json!({ "removed": true }))
+        }
+        ("GET", ["api", "v1", "listeners"]) => Response::json(200, &global.config().listeners),
+        ("PUT", ["api", "v1", "listeners"]) => {
+            let listeners: Listeners = match serde_json::from_slice(&request.body) {
+                Ok(value) => value,
+                Err(err) => return Response::text(400, format!("invalid request: {err}")),
+            };
+            let detail = format!("{:?}", listeners);
+            if let Err(err) = global.update_listeners(listeners).await {
+                let status = match err.kind() {
+                    io::ErrorKind::InvalidInput => 400,
+                    io::ErrorKind::NotConnected => 503,
+                    _ => 500,
+                };
+                return Response::text(status, err.to_string());
+            }
+            global.audit(AuditEvent::AdminAction {
+                peer,
+                action: "update_listeners".to_owned(),
+                detail,
+            });
+            Response::json(200, &global.config().listeners)
+        }
+        ("GET", ["api", "v1", "sparkplug", "nodes"]) => {
+            Response::json(200, &global.sparkplug_nodes.report())
+        }
+        ("GET", ["api", "v1", "routes"]) => Response::json(200, &global.route_table.dump()),
+        ("GET", ["api", "v1", "retained"]) => {
+            let topic_filter = match retained_filter(&request, "#") {
+                Ok(topic_filter) => topic_filter,
+                Err(response) => return response,
+            };
+            let mut retains = global.retain_table.get_matches(&topic_filter);
+            retains.sort_by(|a, b| (*a.topic_name).cmp(&*b.topic_name));
+            let retains: Vec<_> = retains
+                .iter()
+                .map(|content| retain_summary(content))
+                .collect();
+            Response::json(200, &retains)
+        }
+        ("GET", ["api", "v1", "retained", topic_name]) => {
+            let topic_name = match percent_decode(topic_name) {
+                Some(value) => value,
+                None => return Response::text(400, "invalid topic name"),
+            };
+            match global
+                .retain_table
+                .get_matches(&topic_name)
+                .into_iter()
+                .find(|content| *content.topic_name == topic_name)
+            {
+                Some(content) => Response::bytes(200, content.payload.to_vec()),
+                None => Response::not_found(),
+            }
+        }
+        ("DELETE", ["api", "v1", "retained"]) => {
+            // Purge all retained messages must be explicit
+            let topic_filter = match retained_filter(&request, "") {
+                Ok(topic_filter) => topic_filter,
+                Err(response) => return response,
+            };
+            let purged = global.retain_table.purge(&topic_filter);
+            tracing::info!(
+                "purged {} retained messages by admin API, filter: {}",
+                purged.len(),
+                topic_filter
+            );
+            global.audit(AuditEvent::AdminAction {
+                peer,
+                action: "purge_retained".to_owned(),
+                detail: format!("filter: {}, purged: {}", topic_filter, purged.len()),
+            });
+            Response::json(200, &serde_json::json!({ "purged": purged.len() }))
+        }
+        ("POST", ["api", "v1", "publish"]) => {
+            let config = global.config();
+            let token = config
+                .admin
+                .as_ref()
+                .and_then(|admin| admin.publish_token.as_ref());
+            if let Err(response) = check_token(&request, token, "publish API disabled") {
+                return response;
+            }
+            let publish_request: PublishRequest = match serde_json::from_slice(&request.body) {
+                Ok(value) => value,
+                Err(err) => return Response::text(400, format!("invalid request: {err}")),
+            };
+            publish(global, peer, publish_request).await
+        }
+        _ => Response::not_found(),
+    }
+}
+
+// Check the `authorization: Bearer <token>` header, the endpoint is disabled
+// (403) if the token is not set
+fn check_token(
+    request: &Request,
+    token: Option<&String>,
+    disabled: &'static str,
+) -> Result<(), Response> {
+    let token = match token {
+        Some(token) => token,
+        None => return Err(Response::text(403, disabled)),
+    };
+    let bearer = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match bearer {
+        Some(bearer) if token_eq(bearer, token) => Ok(()),
+        _ => Err(Response::text(401, "invalid token")),
+    }
+}
+
+// Compare the bytes in constant time (when the lengths are equal), so the
+// token can not be guessed by the response time
+fn token_eq(bearer: &str, token: &str) -> bool {
+    bearer.len() == token.len()
+        && bearer
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Publish the message like a client by `publish::publish`: the payload limits,
+// the retain limits and the rules are checked, the QoS 1/2 messages wait for
+// the capacity of the receivers, and the message is forwarded by the bridges.
+// The hooks are not called since there is no session.
+async fn publish(global: &GlobalState, peer: SocketAddr, request: PublishRequest) -> Response {
+    // The topics start with '$' are reserved for the server
+    let topic_name = match TopicName::try_from(request.topic) {
+        Ok(topic_name) if !topic_name.starts_with('$') => topic_name,
+        _ => return Response::text(400, "invalid topic name"),
+    };
+    let qos = match request.qos.unwrap_or(0) {
+        value @ 0..=2 => qos_from_value(value),
+        _ => return Response::text(400, "invalid qos"),
+    };
+    let retain = request.retain.unwrap_or(false);
+    let payload = if request.base64.unwrap_or(false) {
+        match STANDARD.decode(&request.payload) {
+            Ok(payload) => Bytes::from(payload),
+            Err(_) => return Response::text(400, "invalid base64 payload"),
+        }
+    } else {
+        Bytes::from(request.payload)
+    };
+    let properties = match request.properties {
+        Some(properties) => match publish_properties(properties) {
+            Ok(properties) => properties,
+            Err(response) => return response,
+        },
+        None => PublishProperties::default(),
+    };
+
+    if payload_rejected(global, &topic_name, payload.len()) {
+        return Response::text(413, "payload too large");
+    }
+    match schema::check(global, &topic_name, &payload) {
+        Some(SchemaViolation::Rejected) => return Response::text(400, "invalid payload"),
+        Some(SchemaViolation::DeadLettered) => {
+            return Response::json(
+                200,
+                &serde_json::json!({ "delivered": 0, "dead_lettered": true }),
+            )
+        }
+        None => {}
+    }
+    if retain && retain_rejected(global, &topic_name, payload.len()) {
+        return Response::text(429, "retain limits exceeded");
+    }
+    global.audit(AuditEvent::AdminAction {
+        peer,
+        action: "publish".to_owned(),
+        detail: format!(
+            "topic: {}, qos: {}, retain: {}",
+            topic_name, qos as u8, retain
+        ),
+    });
+    if sparkplug::check(global, &topic_name, &payload) {
+        return Response::json(200, &serde_json::json!({ "delivered": 0, "dropped": true }));
+    }
+    let message = ServerMessage {
+        client_identifier: Default::default(),
+        username: None,
+        topic_name,
+        qos,
+        retain,
+        payload,
+        properties,
+    };
+    let published = match publish::publish(global, message).await {
+        Ok(published) => published,
+        Err(err) => return Response::text(400, err.to_string()),
+    };
+    if published.dropped {
+        return Response::json(200, &serde_json::json!({ "delivered": 0, "dropped": true }));
+    }
+    let forwarded = match published.forwarded {
+        Forwarded::No => false,
+        Forwarded::Queued => true,
+        // Respond after Kafka acknowledged it, like the PUBACK
+        Forwarded::Wait(receipt) => {
+            if !matches!(receipt.await, Ok(true)) {
+                return Response::text(503, "forward to kafka failed");
+            }
+            true
+        }
+    };
+    Response::json(
+        200,
+        &serde_json::json!({ "delivered": published.delivered, "forwarded": forwarded }),
+    )
+}
+
+fn publish_properties(properties: PublishRequestProperties) -> Result<PublishProperties, Response> {
+    let response_topic = match properties.response_topic {
+        Some(topic) => match TopicName::try_from(topic) {
+            Ok(topic_name) => Some(topic_name),
+            Err(_) => return Err(Response::text(400, "invalid response topic")),
+        },
+        None => None,
+    };
+    let correlation_data = match properties.correlation_data {
+        Some(data) => match STANDARD.decode(data) {
+            Ok(data) => Some(Bytes::from(data)),
+            Err(_) => return Err(Response::text(400, "invalid base64 correlation data")),
+        },
+        None => None,
+    };
+    Ok(PublishProperties {
+        payload_is_utf8: properties.payload_format_indicator,
+        message_expiry_interval: properties.message_expiry_interval,
+        response_topic,
+        correlation_data,
+        user_properties: properties
+            .user_properties
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, value)| UserProperty {
+                name: Arc::new(name),
+                value: Arc::new(value),
+            })
+            .collect(),
+        content_type: properties.content_type.map(Arc::new),
+        ..Default::default()
+    })
+}
+
+// Parse the kind and the percent-encoded value of a ban, the IP address is
+// normalized
+fn ban_target(kind: &str, value: &str) -> Result<(BanKind, String), Response> {
+    let kind = match BanKind::parse(kind) {
+        Some(kind) => kind,
+        None => return Err(Response::text(400, "invalid ban kind")),
+    };
+    let value = match percent_decode(value) {
+        Some(value) if !value.is_empty() => value,
+        _ => return Err(Response::text(400, "invalid ban value")),
+    };
+    if kind == BanKind::Ip {
+        return match value.parse::<IpAddr>() {
+            Ok(ip) => Ok((kind, ip.to_string())),
+            Err(_) => Err(Response::text(400, "invalid IP address")),
+        };
+    }
+    Ok((kind, value))
+}
+
+// Get the `filter` query parameter as a valid topic filter
+fn retained_filter(request: &Request, default: &str) -> Result<String, Response> {
+    let topic_filter = request
+        .query_param("filter")
+        .unwrap_or_else(|| default.to_owned());
+    match TopicFilter::try_from(topic_filter.clone()) {
+        // The shared subscription filter is not allowed
+        Ok(filter) if !filter.is_shared() => Ok(topic_filter),
+        _ => Err(Response::text(400, "invalid topic filter")),
+    }
+}
+
+fn retain_summary(content: &RetainContent) -> serde_json::Value {
+    serde_json::json!({
+        "topic_name": &*content.topic_name,
+        "qos": content.qos as u8,
+        "payload_len": content.payload.len(),
+        "client_identifier": content.client_identifier.as_str(),
+    })
+}
+
+// The records in the trace file format, can be saved and replayed
+fn trace_lines(records: &[PacketRecord]) -> Vec<String> {
+    records.iter().map(|record| record.to_string()).collect()
+}
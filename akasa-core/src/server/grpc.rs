@@ -0,0 +1,329 @@
+//! The gRPC publish/subscribe API (see `Config.grpc`), for the backend services
+//! which don't speak MQTT:
+//!
+//! ```protobuf
+//! syntax = "proto3";
+//! package akasa;
+//!
+//! service Broker {
+//!   rpc Publish(PublishRequest) returns (PublishResponse);
+//!   rpc Subscribe(SubscribeRequest) returns (stream Message);
+//! }
+//! message PublishRequest {
+//!   string topic = 1;
+//!   bytes payload = 2;
+//!   uint32 qos = 3;
+//!   bool retain = 4;
+//! }
+//! message PublishResponse {
+//!   // The number of the matched subscriptions
+//!   uint64 delivered = 1;
+//! }
+//! message Subscription {
+//!   string topic_filter = 1;
+//!   uint32 qos = 2;
+//! }
+//! message SubscribeRequest {
+//!   repeated Subscription subscriptions = 1;
+//!   // Send the matched retained messages first
+//!   bool retained = 2;
+//! }
+//! message Message {
+//!   string topic = 1;
+//!   bytes payload = 2;
+//!   uint32 qos = 3;
+//!   bool retain = 4;
+//!   // The subscription matched the message
+//!   string topic_filter = 5;
+//! }
+//! ```
+//!
+//! A `Subscribe` call is an internal client of the server (like a clean
+//! session), it is removed when the call ends. The messages are served by
+//! tonic (the `grpc` feature), the server side of the service is generated by
+//! `build.rs` and the messages are defined here, so protoc is not required.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures_lite::{stream, Stream};
+use mqtt_proto::{TopicFilter, TopicName};
+use tonic::metadata::MetadataMap;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::config::qos_from_value;
+use crate::state::GlobalState;
+
+use super::subscriber::{SubscribedMessage, Subscriber};
+
+use broker_server::{Broker, BrokerServer};
+
+include!(concat!(env!("OUT_DIR"), "/akasa.Broker.rs"));
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublishRequest {
+    #[prost(string, tag = "1")]
+    pub topic: String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub payload: Bytes,
+    #[prost(uint32, tag = "3")]
+    pub qos: u32,
+    #[prost(bool, tag = "4")]
+    pub retain: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublishResponse {
+    #[prost(uint64, tag = "1")]
+    pub delivered: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Subscription {
+    #[prost(string, tag = "1")]
+    pub topic_filter: String,
+    #[prost(uint32, tag = "2")]
+    pub qos: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub subscriptions: Vec<Subscription>,
+    #[prost(bool, tag = "2")]
+    pub retained: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Message {
+    #[prost(string, tag = "1")]
+    pub topic: String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub payload: Bytes,
+    #[prost(uint32, tag = "3")]
+    pub qos: u32,
+    #[prost(bool, tag = "4")]
+    pub retain: bool,
+    #[prost(string, tag = "5")]
+    pub topic_filter: String,
+}
+
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    global: Arc<GlobalState>,
+) -> Result<(), tonic::transport::Error> {
+    tracing::info!("Listen grpc@{} success!", addr);
+    Server::builder()
+        .add_service(BrokerServer::new(BrokerService { global }))
+        .serve(addr)
+        .await
+}
+
+struct BrokerService {
+    global: Arc<GlobalState>,
+}
+
+#[tonic::async_trait]
+impl Broker for BrokerService {
+    async fn publish(
+        &self,
+        request: Request<PublishRequest>,
+    ) -> Result<Response<PublishResponse>, Status> {
+        check_token(&self.global, request.metadata())?;
+        let delivered = publish(&self.global, request.into_inner())?;
+        Ok(Response::new(PublishResponse {
+            delivered: delivered as u64,
+        }))
+    }
+
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<Message, Status>> + Send>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        check_token(&self.global, request.metadata())?;
+        let request = request.into_inner();
+        let subscriptions = subscriptions(request.subscriptions)?;
+        let subscriber = Subscriber::new(&self.global, "grpc", subscriptions)
+            .await
+            .map_err(|_| Status::internal("add subscriber failed"))?;
+        let retained: VecDeque<_> = if request.retained {
+            subscriber.retained().into()
+        } else {
+            VecDeque::new()
+        };
+        // The subscriber is dropped (removed) when the call ends
+        let messages = stream::unfold(Some((subscriber, retained)), |state| async move {
+            let (subscriber, mut retained) = state?;
+            let message = match retained.pop_front() {
+                Some(message) => message,
+                None => match subscriber.recv().await {
+                    Some(message) => message,
+                    None => return Some((Err(Status::unavailable("subscriber closed")), None)),
+                },
+            };
+            Some((Ok(encode_message(&message)), Some((subscriber, retained))))
+        });
+        Ok(Response::new(Box::pin(messages)))
+    }
+}
+
+// Read the config every time, since the token can be reloaded
+fn check_token(global: &GlobalState, metadata: &MetadataMap) -> Result<(), Status> {
+    if let Some(token) = global
+        .config()
+        .grpc
+        .as_ref()
+        .and_then(|grpc| grpc.token.as_ref())
+    {
+        let bearer = metadata
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if bearer != Some(token.as_str()) {
+            return Err(Status::unauthenticated("invalid token"));
+        }
+    }
+    Ok(())
+}
+
+// Publish the message, return the number of the matched subscriptions
+fn publish(global: &GlobalState, request: PublishRequest) -> Result<usize, Status> {
+    let topic_name = TopicName::try_from(request.topic)
+        .map_err(|_| Status::invalid_argument("invalid topic"))?;
+    if request.qos > 2 {
+        return Err(Status::invalid_argument("invalid qos"));
+    }
+    global
+        .publish(
+            topic_name,
+            qos_from_value(request.qos as u8),
+            request.retain,
+            request.payload,
+            Default::default(),
+        )
+        .map_err(|_| Status::invalid_argument("message too large"))
+}
+
+fn subscriptions(
+    subscriptions: Vec<Subscription>,
+) -> Result<Vec<(TopicFilter, mqtt_proto::QoS)>, Status> {
+    if subscriptions.is_empty() {
+        return Err(Status::invalid_argument("no subscriptions"));
+    }
+    let mut result = Vec::with_capacity(subscriptions.len());
+    for subscription in subscriptions {
+        let topic_filter = TopicFilter::try_from(subscription.topic_filter)
+            .map_err(|_| Status::invalid_argument("invalid topic filter"))?;
+        if subscription.qos > 2 {
+            return Err(Status::invalid_argument("invalid qos"));
+        }
+        result.push((topic_filter, qos_from_value(subscription.qos as u8)));
+    }
+    Ok(result)
+}
+
+fn encode_message(message: &SubscribedMessage) -> Message {
+    Message {
+        topic: message.topic_name.to_string(),
+        payload: message.payload.clone(),
+        qos: message.qos as u32,
+        retain: message.retain,
+        topic_filter: message.topic_filter.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_proto::QoS;
+    use prost::Message as _;
+    use tonic::Code;
+
+    use crate::config::Config;
+
+    #[test]
+    fn test_decode_requests() {
+        let publish = [
+            0x0a, 0x03, b'a', b'/', b'b', 0x12, 0x02, b'h', b'i', 0x18, 0x01, 0x20, 0x01,
+            // Unknown fixed32 field
+            0x2d, 0x01, 0x02, 0x03, 0x04,
+        ];
+        assert_eq!(
+            PublishRequest::decode(&publish[..]).unwrap(),
+            PublishRequest {
+                topic: "a/b".to_owned(),
+                payload: Bytes::from_static(b"hi"),
+                qos: 1,
+                retain: true,
+            }
+        );
+        assert!(PublishRequest::decode(&publish[..4]).is_err());
+
+        let subscribe = [
+            0x0a, 0x07, 0x0a, 0x03, b'a', b'/', b'#', 0x10, 0x01, 0x0a, 0x03, 0x0a, 0x01, b'b',
+            0x10, 0x01,
+        ];
+        let request = SubscribeRequest::decode(&subscribe[..]).unwrap();
+        assert!(request.retained);
+        assert_eq!(
+            subscriptions(request.subscriptions).unwrap(),
+            vec![
+                (
+                    TopicFilter::try_from("a/#".to_owned()).unwrap(),
+                    QoS::Level1
+                ),
+                (TopicFilter::try_from("b".to_owned()).unwrap(), QoS::Level0),
+            ]
+        );
+        assert_eq!(
+            subscriptions(Vec::new()).unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn test_encode_message() {
+        let message = encode_message(&SubscribedMessage {
+            topic_name: TopicName::try_from("a/b".to_owned()).unwrap(),
+            payload: Bytes::from_static(b"hi"),
+            qos: QoS::Level1,
+            retain: false,
+            topic_filter: TopicFilter::try_from("a/+".to_owned()).unwrap(),
+        });
+        assert_eq!(
+            message.encode_to_vec(),
+            [
+                0x0a, 0x03, b'a', b'/', b'b', 0x12, 0x02, b'h', b'i', 0x18, 0x01, 0x2a, 0x03, b'a',
+                b'/', b'+',
+            ]
+        );
+    }
+
+    #[test]
+    fn test_publish() {
+        let global = GlobalState::new(Config::new_allow_anonymous());
+        let mut request = PublishRequest {
+            topic: "a/b".to_owned(),
+            payload: Bytes::from_static(b"hi"),
+            qos: 0,
+            retain: false,
+        };
+        assert_eq!(publish(&global, request.clone()).unwrap(), 0);
+
+        request.qos = 3;
+        let status = publish(&global, request.clone()).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "invalid qos");
+
+        request.topic = "a/+".to_owned();
+        request.qos = 0;
+        let status = publish(&global, request).unwrap_err();
+        assert_eq!(status.message(), "invalid topic");
+    }
+}
@@ -0,0 +1,440 @@
+//! A minimal HTTP/1.1 implementation for the admin API and the webhook, one
+//! request per connection.
+
+use std::io;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const READ_TIMEOUT_SECS: u64 = 10;
+const POST_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    // Header names are in lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+/// A `http://host[:port][/path]` url (https is not supported)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HttpUrl {
+    // host[:port], for the `Host` header
+    pub authority: String,
+    // host:port, for connecting
+    pub addr: String,
+    pub path: String,
+}
+
+impl HttpUrl {
+    pub fn parse(url: &str) -> Option<HttpUrl> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() || authority.contains(['@', ' ']) {
+            return None;
+        }
+        // Port is required if the host is an IPv6 address
+        let addr = match authority.rsplit_once(':') {
+            Some((_, port)) if !port.ends_with(']') => {
+                port.parse::<u16>().ok()?;
+                authority.to_owned()
+            }
+            _ => format!("{authority}:80"),
+        };
+        Some(HttpUrl {
+            authority: authority.to_owned(),
+            addr,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Get the percent-decoded query parameter
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query.as_ref()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if key == name {
+                percent_decode(&value.replace('+', " "))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Get all the percent-decoded values of a repeated query parameter
+    pub fn query_params(&self, name: &str) -> Vec<String> {
+        let query = match self.query.as_ref() {
+            Some(query) => query,
+            None => return Vec::new(),
+        };
+        query
+            .split('&')
+            .filter_map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                if key == name {
+                    percent_decode(&value.replace('+', " "))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+impl Response {
+    pub fn json<T: Serialize>(status: u16, value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response {
+                status,
+                content_type: "application/json",
+                body,
+            },
+            Err(err) => {
+                tracing::error!("encode json response error: {}", err);
+                Response::text(500, "encode json response failed")
+            }
+        }
+    }
+
+    pub fn text<T: Into<String>>(status: u16, text: T) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: text.into().into_bytes(),
+        }
+    }
+
+    pub fn bytes(status: u16, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            content_type: "application/octet-stream",
+            body,
+        }
+    }
+
+    pub fn not_found() -> Response {
+        Response::text(404, "not found")
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// Decode a percent-encoded path segment or query value
+pub(crate) fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'%' => {
+                let hex = value.get(idx + 1..idx + 3)?;
+                output.push(u8::from_str_radix(hex, 16).ok()?);
+                idx += 3;
+            }
+            byte => {
+                output.push(byte);
+                idx += 1;
+            }
+        }
+    }
+    String::from_utf8(output).ok()
+}
+
+pub(crate) async fn read_request<T: AsyncRead + Unpin>(conn: &mut T) -> io::Result<Request> {
+    tokio::time::timeout(
+        Duration::from_secs(READ_TIMEOUT_SECS),
+        read_request_inner(conn),
+    )
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+async fn read_request_inner<T: AsyncRead + Unpin>(conn: &mut T) -> io::Result<Request> {
+    let mut buf = Vec::with_capacity(1024);
+    let head_end = loop {
+        if let Some(idx) = find_head_end(&buf) {
+            break idx;
+        }
+        if buf.len() >= MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+        let mut chunk = [0u8; 1024];
+        let size = conn.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..size]);
+    };
+    let mut request = parse_head(&buf[..head_end])?;
+
+    let content_length = match request.header("content-length") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid content-length"))?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request body too large",
+        ));
+    }
+    let mut body = buf.split_off(head_end + 4);
+    if body.len() < content_length {
+        let start = body.len();
+        body.resize(content_length, 0);
+        conn.read_exact(&mut body[start..]).await?;
+    }
+    body.truncate(content_length);
+    request.body = body;
+    Ok(request)
+}
+
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+fn parse_head(head: &[u8]) -> io::Result<Request> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+    let head = std::str::from_utf8(head).map_err(|_| invalid("request head is not utf8"))?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines
+        .next()
+        .ok_or_else(|| invalid("missing request line"))?;
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(invalid("invalid request line")),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid("unsupported http version"));
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("invalid header line"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+    }
+    Ok(Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+pub(crate) async fn write_response<T: AsyncWrite + Unpin>(
+    conn: &mut T,
+    response: &Response,
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len(),
+    );
+    conn.write_all(head.as_bytes()).await?;
+    conn.write_all(&response.body).await?;
+    conn.flush().await
+}
+
+/// POST a JSON body to the url with the extra headers, return the response
+/// status
+pub(crate) async fn post_json(
+    url: &HttpUrl,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> io::Result<u16> {
+    post(url, "application/json", headers, body).await
+}
+
+/// POST a body to the url with the extra headers, return the response status
+pub(crate) async fn post(
+    url: &HttpUrl,
+    content_type: &str,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> io::Result<u16> {
+    tokio::time::timeout(
+        Duration::from_secs(POST_TIMEOUT_SECS),
+        post_inner(url, content_type, headers, body),
+    )
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+async fn post_inner(
+    url: &HttpUrl,
+    content_type: &str,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> io::Result<u16> {
+    let mut conn = TcpStream::connect(&url.addr).await?;
+    let mut head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.authority,
+        content_type,
+        body.len(),
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    conn.write_all(head.as_bytes()).await?;
+    conn.write_all(body).await?;
+    conn.flush().await?;
+    read_response_status(&mut conn).await
+}
+
+// Read the status code from the response status line, the rest is ignored
+async fn read_response_status<T: AsyncRead + Unpin>(conn: &mut T) -> io::Result<u16> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid response status line");
+    let mut buf = Vec::with_capacity(128);
+    let line_end = loop {
+        if let Some(idx) = buf.windows(2).position(|window| window == b"\r\n") {
+            break idx;
+        }
+        if buf.len() >= MAX_HEAD_SIZE {
+            return Err(invalid());
+        }
+        let mut chunk = [0u8; 128];
+        let size = conn.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..size]);
+    };
+    let line = std::str::from_utf8(&buf[..line_end]).map_err(|_| invalid())?;
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => {
+            status.parse().map_err(|_| invalid())
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let data = b"POST /api/v1/publish?topic=a/b&qos=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        let request = read_request(&mut &data[..]).await.unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/v1/publish");
+        assert_eq!(request.query_param("topic").as_deref(), Some("a/b"));
+        assert_eq!(request.query_param("qos").as_deref(), Some("1"));
+        assert_eq!(request.query_param("retain"), None);
+        assert_eq!(request.header("Host"), Some("localhost"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_read_invalid_request() {
+        let data = b"GET /healthz\r\n\r\n";
+        assert!(read_request(&mut &data[..]).await.is_err());
+        let data = b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n";
+        assert!(read_request(&mut &data[..]).await.is_err());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("abc").as_deref(), Some("abc"));
+        assert_eq!(percent_decode("a%2Fb%20c").as_deref(), Some("a/b c"));
+        assert_eq!(percent_decode("%E4%BD%A0").as_deref(), Some("你"));
+        assert_eq!(percent_decode("a%2"), None);
+        assert_eq!(percent_decode("a%zz"), None);
+        assert_eq!(percent_decode("%ff"), None);
+    }
+
+    #[tokio::test]
+    async fn test_write_response() {
+        let mut output = Vec::new();
+        write_response(&mut output, &Response::text(200, "ok"))
+            .await
+            .unwrap();
+        assert_eq!(
+            output,
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        );
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let url = HttpUrl::parse("http://localhost:8000/events?a=1").unwrap();
+        assert_eq!(url.authority, "localhost:8000");
+        assert_eq!(url.addr, "localhost:8000");
+        assert_eq!(url.path, "/events?a=1");
+        let url = HttpUrl::parse("http://example.com").unwrap();
+        assert_eq!(url.authority, "example.com");
+        assert_eq!(url.addr, "example.com:80");
+        assert_eq!(url.path, "/");
+        let url = HttpUrl::parse("http://[::1]:8000/").unwrap();
+        assert_eq!(url.addr, "[::1]:8000");
+        assert_eq!(HttpUrl::parse("https://example.com/"), None);
+        assert_eq!(HttpUrl::parse("http:///path"), None);
+        assert_eq!(HttpUrl::parse("http://example.com:port/"), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_response_status() {
+        let data = b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(read_response_status(&mut &data[..]).await.unwrap(), 204);
+        let data = b"HTTP/1.1 OK\r\n\r\n";
+        assert!(read_response_status(&mut &data[..]).await.is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! The in-process client for the applications embedding the server, the
+//! messages are published and received through the `GlobalState` directly,
+//! without any connection.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures_lite::{stream, Stream};
+use mqtt_proto::{QoS, TopicFilter, TopicName};
+
+use super::subscriber::{SubscribedMessage, Subscriber};
+use crate::state::GlobalState;
+
+pub struct LocalClient {
+    global: Arc<GlobalState>,
+    subscriber: Subscriber,
+    // The retained messages matched the new subscriptions
+    pending: VecDeque<SubscribedMessage>,
+}
+
+impl LocalClient {
+    /// Add an internal client named `local-{uuid}` without subscriptions, it's
+    /// removed when dropped.
+    pub async fn new(global: &Arc<GlobalState>) -> io::Result<LocalClient> {
+        let subscriber = Subscriber::new(global, "local", Vec::new()).await?;
+        Ok(LocalClient {
+            global: Arc::clone(global),
+            subscriber,
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn client_identifier(&self) -> &str {
+        self.subscriber.client_identifier()
+    }
+
+    /// Publish a message, return how many receivers the message is delivered
+    /// to. The message is also received by this client if subscribed.
+    pub fn publish(
+        &self,
+        topic_name: TopicName,
+        qos: QoS,
+        retain: bool,
+        payload: Bytes,
+    ) -> io::Result<usize> {
+        self.global
+            .publish(topic_name, qos, retain, payload, Default::default())
+    }
+
+    /// Add or replace a subscription, the retained messages matched it are
+    /// received first.
+    pub fn subscribe(&mut self, topic_filter: TopicFilter, qos: QoS) {
+        let retained = self.subscriber.subscribe(topic_filter, qos);
+        self.pending.extend(retained);
+    }
+
+    /// Remove a subscription, return false if not subscribed
+    pub fn unsubscribe(&mut self, topic_filter: &TopicFilter) -> bool {
+        self.subscriber.unsubscribe(topic_filter)
+    }
+
+    /// Receive the next message, return `None` when the client is kicked
+    pub async fn recv(&mut self) -> Option<SubscribedMessage> {
+        match self.pending.pop_front() {
+            Some(message) => Some(message),
+            None => self.subscriber.recv().await,
+        }
+    }
+
+    /// The received messages as a stream, it ends when the client is kicked
+    pub fn into_stream(self) -> impl Stream<Item = SubscribedMessage> {
+        stream::unfold(self, |mut client| async move {
+            let message = client.recv().await?;
+            Some((message, client))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::StreamExt;
+
+    use crate::config::Config;
+
+    fn topic_name(value: &str) -> TopicName {
+        TopicName::try_from(value.to_owned()).unwrap()
+    }
+
+    fn topic_filter(value: &str) -> TopicFilter {
+        TopicFilter::try_from(value.to_owned()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_local_client() {
+        let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+        let publisher = LocalClient::new(&global).await.unwrap();
+        publisher
+            .publish(
+                topic_name("a/1"),
+                QoS::Level1,
+                true,
+                Bytes::from_static(b"retained"),
+            )
+            .unwrap();
+
+        let mut client = LocalClient::new(&global).await.unwrap();
+        assert!(client.client_identifier().starts_with("local-"));
+        client.subscribe(topic_filter("a/+"), QoS::Level0);
+        let delivered = publisher
+            .publish(
+                topic_name("a/2"),
+                QoS::Level1,
+                false,
+                Bytes::from_static(b"2"),
+            )
+            .unwrap();
+        assert_eq!(delivered, 1);
+
+        let message = client.recv().await.unwrap();
+        assert_eq!(&*message.topic_name, "a/1");
+        assert_eq!(message.payload.as_ref(), b"retained");
+        assert_eq!(message.qos, QoS::Level0);
+        assert!(message.retain);
+        let message = client.recv().await.unwrap();
+        assert_eq!(&*message.topic_name, "a/2");
+        assert_eq!(&*message.topic_filter, "a/+");
+        assert!(!message.retain);
+
+        assert!(client.unsubscribe(&topic_filter("a/+")));
+        assert!(!client.unsubscribe(&topic_filter("a/+")));
+        let delivered = publisher
+            .publish(
+                topic_name("a/3"),
+                QoS::Level0,
+                false,
+                Bytes::from_static(b"3"),
+            )
+            .unwrap();
+        assert_eq!(delivered, 0);
+
+        client.subscribe(topic_filter("b/#"), QoS::Level1);
+        let mut messages = Box::pin(client.into_stream());
+        publisher
+            .publish(
+                topic_name("b/1"),
+                QoS::Level1,
+                false,
+                Bytes::from_static(b"b"),
+            )
+            .unwrap();
+        let message = messages.next().await.unwrap();
+        assert_eq!(&*message.topic_name, "b/1");
+        assert_eq!(message.qos, QoS::Level1);
+
+        // The client is removed when dropped
+        drop(messages);
+        let delivered = publisher
+            .publish(
+                topic_name("b/2"),
+                QoS::Level0,
+                false,
+                Bytes::from_static(b"b"),
+            )
+            .unwrap();
+        assert_eq!(delivered, 0);
+    }
+}
@@ -1,8 +1,15 @@
+pub(crate) mod admin;
+#[cfg(feature = "grpc")]
+mod grpc;
+pub(crate) mod http;
+mod local;
 mod proxy;
 pub mod rt;
+mod stream;
+mod subscriber;
 
 use std::cmp;
-use std::io;
+use std::io::{self, IoSlice};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -13,25 +20,34 @@ use flume::bounded;
 use futures_lite::{FutureExt, Stream};
 use futures_sink::Sink;
 use futures_util::TryFutureExt;
-use mqtt_proto::{decode_raw_header, v3, v5, Error, Protocol};
+use mqtt_proto::{decode_raw_header, v3, v5, Error, Protocol, QoS};
 use openssl::ssl::{NameType, Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 use tokio_openssl::SslStream;
 use tokio_tungstenite::{
     accept_hdr_async,
     tungstenite::{http, Message},
     WebSocketStream,
 };
+use tracing::{field, Span};
 
+use crate::ban::BanKind;
 use crate::config::TlsListener;
+use crate::diagnostics;
+use crate::error::Error as AkasaError;
 use crate::hook::Hook;
+use crate::metrics::Metrics;
 use crate::protocols::mqtt;
 use crate::state::GlobalState;
 
 use proxy::{parse_header, Addresses};
 
+pub use local::LocalClient;
+pub use subscriber::SubscribedMessage;
+
 const CONNECT_TIMEOUT_SECS: u64 = 5;
 
+#[tracing::instrument(name = "conn", skip_all, fields(peer = %peer, protocol, client_id))]
 pub async fn handle_accept<
     T: AsyncRead + AsyncWrite + Unpin,
     H: Hook + Clone + Send + Sync + 'static,
@@ -47,7 +63,7 @@ pub async fn handle_accept<
     tokio::spawn(async move {
         tokio::time::sleep(Duration::from_secs(CONNECT_TIMEOUT_SECS)).await;
         if timeout_sender.send_async(()).await.is_ok() {
-            log::info!("connection timeout: {}", peer);
+            tracing::info!("connection timeout: {}", peer);
         }
     });
 
@@ -57,7 +73,7 @@ pub async fn handle_accept<
         if let Some(header) = parse_header(&mut conn, conn_args.proxy_tls_termination)
             .or(async {
                 let _ = timeout_receiver.recv_async().await;
-                log::info!("timeout when parse proxy header: {}", peer);
+                tracing::info!("timeout when parse proxy header: {}", peer);
                 Err(io::ErrorKind::TimedOut.into())
             })
             .await?
@@ -74,40 +90,55 @@ pub async fn handle_accept<
                     ..
                 } => (source_address, source_port).into(),
                 Addresses::Unix { .. } => {
-                    log::error!(
+                    tracing::error!(
                         "Proxy unix address({:?}) is not supported!",
                         header.addresses
                     );
                     return Err(io::ErrorKind::InvalidData.into());
                 }
             };
-            log::debug!("Proxy protocol TLS SNI: {:?}", header.tls_sni);
+            Span::current().record("peer", field::display(peer));
+            tracing::debug!("Proxy protocol TLS SNI: {:?}", header.tls_sni);
             tls_sni = header.tls_sni;
         } else {
-            log::info!("Proxy protocol health check");
+            tracing::info!("Proxy protocol health check");
             return Ok(());
         }
     }
 
+    if global.bans.is_banned(BanKind::Ip, &peer.ip().to_string()) {
+        Metrics::incr(&global.metrics.banned_connects);
+        tracing::debug!("connection from banned IP: {}", peer);
+        return Ok(());
+    }
+    if !global
+        .connect_limiter
+        .check(&global.config().connect_limit, peer.ip())
+    {
+        Metrics::incr(&global.metrics.throttled_connects);
+        tracing::debug!("connection rate limited: {}", peer);
+        return Ok(());
+    }
+
     // Handle TLS
     let tls_wrapper = if let Some(acceptor) = conn_args.tls_acceptor {
         let ssl = Ssl::new(acceptor.context()).map_err(|err| {
-            log::error!("Create TLS session failed: {:?}", err);
+            tracing::error!("Create TLS session failed: {:?}", err);
             io::Error::from(io::ErrorKind::BrokenPipe)
         })?;
         let mut tls_stream = SslStream::new(ssl, conn).map_err(|err| {
-            log::error!("create TLS stream error: {:?}", err);
+            tracing::error!("create TLS stream error: {:?}", err);
             io::Error::from(io::ErrorKind::BrokenPipe)
         })?;
         Pin::new(&mut tls_stream)
             .accept()
             .map_err(|err| {
-                log::debug!("accept tls connection error: {:?}", err);
+                tracing::debug!("accept tls connection error: {:?}", err);
                 io::Error::from(io::ErrorKind::InvalidData)
             })
             .or(async {
                 let _ = timeout_receiver.recv_async().await;
-                log::info!("timeout when tls accept: {}", peer);
+                tracing::info!("timeout when tls accept: {}", peer);
                 Err(io::ErrorKind::TimedOut.into())
             })
             .await?;
@@ -120,7 +151,7 @@ pub async fn handle_accept<
         TlsWrapper::Raw(conn)
     };
 
-    log::debug!("TLS host name(SNI): {:?}", tls_sni);
+    tracing::debug!("TLS host name(SNI): {:?}", tls_sni);
 
     // Handle WebSocket
     let mut ws_wrapper = if conn_args.websocket {
@@ -128,7 +159,7 @@ pub async fn handle_accept<
             if let Some(protocol) = req.headers().get("Sec-WebSocket-Protocol") {
                 // see: [MQTT-6.0.0-3]
                 if protocol != "mqtt" {
-                    log::info!("invalid WebSocket subprotocol name: {:?}", protocol);
+                    tracing::info!("invalid WebSocket subprotocol name: {:?}", protocol);
                     return Err(http::Response::new(Some(
                         "invalid WebSocket subprotocol name".to_string(),
                     )));
@@ -141,7 +172,7 @@ pub async fn handle_accept<
         let stream = match accept_hdr_async(tls_wrapper, handler).await {
             Ok(stream) => stream,
             Err(err) => {
-                log::warn!("Accept websocket connection error: {:?}", err);
+                tracing::warn!("Accept websocket connection error: {:?}", err);
                 return Err(io::ErrorKind::InvalidData.into());
             }
         };
@@ -159,22 +190,30 @@ pub async fn handle_accept<
     let (packet_type, remaining_len) = decode_raw_header(&mut ws_wrapper)
         .or(async {
             let _ = timeout_receiver.recv_async().await;
-            log::info!("timeout when decode raw mqtt header: {}", peer);
+            tracing::info!("timeout when decode raw mqtt header: {}", peer);
             Err(Error::IoError(io::ErrorKind::TimedOut, String::new()))
         })
         .await?;
     if packet_type != 0b00010000 {
-        log::debug!("first packet is not CONNECT packet: {}", packet_type);
-        return Err(io::ErrorKind::InvalidData.into());
+        tracing::debug!("first packet is not CONNECT packet: {}", packet_type);
+        let err = AkasaError::Protocol("first packet is not CONNECT".to_owned()).into();
+        diagnostics::record_error(&global, None, peer, "unknown".to_owned(), &err);
+        return Err(err);
     }
-    let protocol = Protocol::decode_async(&mut ws_wrapper)
+    let (protocol, bridge) = decode_protocol(&mut ws_wrapper)
         .or(async {
             let _ = timeout_receiver.recv_async().await;
-            log::info!("timeout when decode mqtt protocol: {}", peer);
-            Err(Error::IoError(io::ErrorKind::TimedOut, String::new()))
+            tracing::info!("timeout when decode mqtt protocol: {}", peer);
+            Err(AkasaError::ConnectTimeout.into())
         })
-        .await?;
+        .await
+        .map_err(|err| {
+            diagnostics::record_error(&global, None, peer, "unknown".to_owned(), &err);
+            err
+        })?;
+    Span::current().record("protocol", field::debug(protocol));
     match protocol {
+        #[cfg(feature = "v3")]
         Protocol::V310 | Protocol::V311 => {
             let header = v3::Header::new_with(packet_type, remaining_len).expect("v3 header");
             mqtt::v3::handle_connection(
@@ -182,12 +221,16 @@ pub async fn handle_accept<
                 peer,
                 header,
                 protocol,
+                bridge,
                 timeout_receiver,
+                conn_args.max_qos,
+                conn_args.server_keep_alive,
                 hook_handler,
                 global,
             )
             .await?;
         }
+        #[cfg(feature = "v5")]
         Protocol::V500 => {
             let header = v5::Header::new_with(packet_type, remaining_len).expect("v5 header");
             mqtt::v5::handle_connection(
@@ -196,40 +239,98 @@ pub async fn handle_accept<
                 header,
                 protocol,
                 timeout_receiver,
+                conn_args.max_qos,
+                conn_args.server_keep_alive,
                 hook_handler,
                 global,
             )
             .await?;
         }
+        // Compiled out by the cargo features, reply the CONNACK so the client
+        // can fallback to another protocol version.
+        #[cfg(not(feature = "v3"))]
+        Protocol::V310 | Protocol::V311 => {
+            let _ = bridge;
+            tracing::debug!("mqtt v3.x is disabled: {}", peer);
+            let packet =
+                v3::Connack::new(false, v3::ConnectReturnCode::UnacceptableProtocolVersion);
+            v3::Packet::from(packet)
+                .encode_async(&mut ws_wrapper)
+                .await?;
+            return Err(AkasaError::Protocol("mqtt v3.x is disabled".to_owned()).into());
+        }
+        #[cfg(not(feature = "v5"))]
+        Protocol::V500 => {
+            tracing::debug!("mqtt v5.x is disabled: {}", peer);
+            let packet = v5::Connack {
+                session_present: false,
+                reason_code: v5::ConnectReasonCode::UnsupportedProtocolVersion,
+                properties: Default::default(),
+            };
+            v5::Packet::from(packet)
+                .encode_async(&mut ws_wrapper)
+                .await?;
+            return Err(AkasaError::Protocol("mqtt v5.x is disabled".to_owned()).into());
+        }
     }
     Ok(())
 }
 
+/// Decode the protocol name and protocol level of the CONNECT packet. The
+/// highest bit of the protocol level is set by the bridges (mosquitto's bridge
+/// protocol), which is only allowed for v3.x.
+async fn decode_protocol<T: AsyncRead + Unpin>(reader: &mut T) -> io::Result<(Protocol, bool)> {
+    let name_len = reader.read_u16().await? as usize;
+    // "MQIsdp" is the longest protocol name
+    if name_len > 6 {
+        tracing::debug!("invalid protocol name length: {}", name_len);
+        return Err(AkasaError::MalformedPacket("invalid protocol name".to_owned()).into());
+    }
+    let mut name = [0u8; 6];
+    reader.read_exact(&mut name[..name_len]).await?;
+    let level = reader.read_u8().await?;
+    let bridge = level & 0x80 != 0;
+    let protocol = match (&name[..name_len], level & 0x7f) {
+        (b"MQIsdp", 3) => Protocol::V310,
+        (b"MQTT", 4) => Protocol::V311,
+        (b"MQTT", 5) if !bridge => Protocol::V500,
+        _ => {
+            tracing::debug!(
+                "invalid protocol: {:?}, level: {}",
+                String::from_utf8_lossy(&name[..name_len]),
+                level
+            );
+            return Err(AkasaError::Protocol("unsupported protocol".to_owned()).into());
+        }
+    };
+    Ok((protocol, bridge))
+}
+
 fn build_tls_context(listener: &TlsListener) -> io::Result<SslAcceptor> {
     if listener.verify_peer && listener.ca_file.is_none() {
-        log::error!("When `verify_peer` is true `ca_file` must be presented!");
+        tracing::error!("When `verify_peer` is true `ca_file` must be presented!");
         return Err(io::Error::from(io::ErrorKind::InvalidInput));
     }
     let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(|err| {
-        log::error!("Initialize SslAcceptor failed: {:?}", err);
+        tracing::error!("Initialize SslAcceptor failed: {:?}", err);
         io::Error::from(io::ErrorKind::InvalidInput)
     })?;
     if let Some(ca_file) = listener.ca_file.as_ref() {
         acceptor.set_ca_file(ca_file).map_err(|err| {
-            log::error!("Invalid CA-certfile: {}", err);
+            tracing::error!("Invalid CA-certfile: {}", err);
             io::Error::from(io::ErrorKind::InvalidInput)
         })?;
     }
     acceptor
         .set_private_key_file(&listener.key_file, SslFiletype::PEM)
         .map_err(|err| {
-            log::error!("Invalid keyfile: {}", err);
+            tracing::error!("Invalid keyfile: {}", err);
             io::Error::from(io::ErrorKind::InvalidInput)
         })?;
     acceptor
         .set_certificate_chain_file(&listener.cert_file)
         .map_err(|err| {
-            log::error!("Invalid certfile: {}", err);
+            tracing::error!("Invalid certfile: {}", err);
             io::Error::from(io::ErrorKind::InvalidInput)
         })?;
     let mut verify_mode = SslVerifyMode::NONE;
@@ -251,6 +352,10 @@ pub struct ConnectionArgs {
     pub(crate) proxy_tls_termination: bool,
     pub(crate) websocket: bool,
     pub(crate) tls_acceptor: Option<SslAcceptor>,
+    /// The listener level max QoS
+    pub(crate) max_qos: Option<QoS>,
+    /// The listener level server keep alive
+    pub(crate) server_keep_alive: Option<u16>,
 }
 
 enum TlsWrapper<S> {
@@ -282,6 +387,22 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsWrapper<S> {
             TlsWrapper::Tls(tls_stream) => Pin::new(tls_stream).poll_write(cx, buf),
         }
     }
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TlsWrapper::Raw(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            TlsWrapper::Tls(tls_stream) => Pin::new(tls_stream).poll_write_vectored(cx, bufs),
+        }
+    }
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            TlsWrapper::Raw(conn) => conn.is_write_vectored(),
+            TlsWrapper::Tls(tls_stream) => tls_stream.is_write_vectored(),
+        }
+    }
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         match self.get_mut() {
             TlsWrapper::Raw(conn) => Pin::new(conn).poll_flush(cx),
@@ -319,11 +440,11 @@ fn ws_send_pong<S: AsyncRead + AsyncWrite + Unpin>(
                 sink.as_mut()
                     .start_send(Message::Pong(data))
                     .map_err(|err| {
-                        log::debug!("WebSocket send pong error: {:?}", err);
+                        tracing::debug!("WebSocket send pong error: {:?}", err);
                         io::Error::from(io::ErrorKind::BrokenPipe)
                     })?;
                 let _ignore = sink.as_mut().poll_flush(cx).map_err(|err| {
-                    log::debug!("WebSocket flush pong error: {:?}", err);
+                    tracing::debug!("WebSocket flush pong error: {:?}", err);
                     io::Error::from(io::ErrorKind::BrokenPipe)
                 })?;
                 Ok(())
@@ -385,20 +506,20 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketWrapper<S> {
                                 ws_send_pong(stream, pending_pong, cx)?;
                             }
                             Message::Pong(_) => {
-                                log::debug!("WebSocket pong message not allowed!");
+                                tracing::debug!("WebSocket pong message not allowed!");
                                 return Poll::Ready(Err(io::ErrorKind::InvalidData.into()));
                             }
                             Message::Text(_) => {
-                                log::debug!("WebSocket text message not allowed!");
+                                tracing::debug!("WebSocket text message not allowed!");
                                 return Poll::Ready(Err(io::ErrorKind::InvalidData.into()));
                             }
                             Message::Frame(_) => {
-                                log::debug!("WebSocket frame message not allowed!");
+                                tracing::debug!("WebSocket frame message not allowed!");
                                 return Poll::Ready(Err(io::ErrorKind::InvalidData.into()));
                             }
                         },
                         Poll::Ready(Some(Err(err))) => {
-                            log::debug!("WebSocket read error: {:?}", err);
+                            tracing::debug!("WebSocket read error: {:?}", err);
                             return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
                         }
                         Poll::Ready(None) => return Poll::Ready(Ok(())),
@@ -430,14 +551,14 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketWrapper<S> {
                 match Pin::new(&mut *stream).poll_ready(cx) {
                     Poll::Ready(Ok(())) => {}
                     Poll::Ready(Err(err)) => {
-                        log::debug!("WebSocket write error: {:?}", err);
+                        tracing::debug!("WebSocket write error: {:?}", err);
                         return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
                     }
                     Poll::Pending => return Poll::Pending,
                 }
                 let message = Message::Binary(buf.to_vec());
                 if let Err(err) = Pin::new(&mut *stream).start_send(message) {
-                    log::debug!("WebSocket write error: {:?}", err);
+                    tracing::debug!("WebSocket write error: {:?}", err);
                     return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
                 }
                 let _ignore = Pin::new(&mut *stream)
@@ -449,12 +570,37 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketWrapper<S> {
         }
     }
 
+    // Every write is a WebSocket message, so only the raw connection writes
+    // the buffers at once.
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let WebSocketWrapper::Raw(conn) = &mut *this {
+            return Pin::new(conn).poll_write_vectored(cx, bufs);
+        }
+        let buf = bufs
+            .iter()
+            .find(|buf| !buf.is_empty())
+            .map_or(&[][..], |buf| &**buf);
+        Pin::new(this).poll_write(cx, buf)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            WebSocketWrapper::Raw(conn) => conn.is_write_vectored(),
+            WebSocketWrapper::WebSocket { .. } => false,
+        }
+    }
+
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         match self.get_mut() {
             WebSocketWrapper::Raw(conn) => Pin::new(conn).poll_flush(cx),
             WebSocketWrapper::WebSocket { stream, .. } => {
                 Pin::new(stream).poll_flush(cx).map_err(|err| {
-                    log::debug!("WebSocket flush error: {:?}", err);
+                    tracing::debug!("WebSocket flush error: {:?}", err);
                     io::ErrorKind::BrokenPipe.into()
                 })
             }
@@ -472,11 +618,11 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketWrapper<S> {
                             sink.as_mut()
                                 .start_send(Message::Close(None))
                                 .map_err(|err| {
-                                    log::debug!("WebSocket send close error: {:?}", err);
+                                    tracing::debug!("WebSocket send close error: {:?}", err);
                                     io::Error::from(io::ErrorKind::BrokenPipe)
                                 })?;
                             let _ = sink.as_mut().poll_flush(cx).map_err(|err| {
-                                log::debug!("WebSocket flush close error: {:?}", err);
+                                tracing::debug!("WebSocket flush close error: {:?}", err);
                                 io::Error::from(io::ErrorKind::BrokenPipe)
                             })?;
                             *closed = true;
@@ -488,7 +634,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketWrapper<S> {
                     }
                 }
                 Pin::new(&mut *stream).poll_close(cx).map_err(|err| {
-                    log::debug!("WebSocket poll_close error: {:?}", err);
+                    tracing::debug!("WebSocket poll_close error: {:?}", err);
                     io::ErrorKind::BrokenPipe.into()
                 })
             }
@@ -87,7 +87,7 @@ pub async fn parse_header<T: AsyncRead + Unpin>(
 
     let prefix = &buffer[0..16];
     if &prefix[..PROTOCOL_PREFIX.len()] != PROTOCOL_PREFIX {
-        log::warn!(
+        tracing::warn!(
             "invalid proxy protocol fixed prefix: {:?}",
             &prefix[..PROTOCOL_PREFIX.len()]
         );
@@ -101,7 +101,7 @@ pub async fn parse_header<T: AsyncRead + Unpin>(
     match byte_13th & LEFT_MASK {
         0x20 => {}
         _ => {
-            log::warn!("invalid proxy protocol 13th byte (version): {}", byte_13th);
+            tracing::warn!("invalid proxy protocol 13th byte (version): {}", byte_13th);
             return Err(io::ErrorKind::InvalidData.into());
         }
     }
@@ -109,7 +109,7 @@ pub async fn parse_header<T: AsyncRead + Unpin>(
         // Local
         0x00 => {
             if !(byte_14th == 0 && extra_length == 0) {
-                log::warn!("invalid proxy protocol local command: {:?}", prefix);
+                tracing::warn!("invalid proxy protocol local command: {:?}", prefix);
                 return Err(io::ErrorKind::InvalidData.into());
             }
             return Ok(None);
@@ -117,7 +117,7 @@ pub async fn parse_header<T: AsyncRead + Unpin>(
         // Proxy
         0x01 => {}
         _ => {
-            log::warn!("invalid proxy protocol 13th byte (command): {}", byte_13th);
+            tracing::warn!("invalid proxy protocol 13th byte (command): {}", byte_13th);
             return Err(io::ErrorKind::InvalidData.into());
         }
     }
@@ -129,7 +129,7 @@ pub async fn parse_header<T: AsyncRead + Unpin>(
         0x20 => AddressFamily::IPv6,
         0x30 => AddressFamily::Unix,
         _ => {
-            log::warn!(
+            tracing::warn!(
                 "invalid proxy protocol 14th byte (address family): {}",
                 byte_14th
             );
@@ -142,7 +142,7 @@ pub async fn parse_header<T: AsyncRead + Unpin>(
         AddressFamily::Unix => UNIX_ADDRS_LEN,
     };
     if extra_length < address_length {
-        log::warn!(
+        tracing::warn!(
             "invalid proxy protocol length or address, length={} < address-length={}",
             extra_length,
             address_length
@@ -156,7 +156,7 @@ pub async fn parse_header<T: AsyncRead + Unpin>(
         0x01 => Protocol::Stream,
         0x02 => Protocol::Datagram,
         _ => {
-            log::warn!("invalid proxy protocol 14th byte (protocol): {}", byte_14th);
+            tracing::warn!("invalid proxy protocol 14th byte (protocol): {}", byte_14th);
             return Err(io::ErrorKind::InvalidData.into());
         }
     };
@@ -242,7 +242,7 @@ async fn parse_tlvs<T: AsyncRead + Unpin>(
 
     let remaining_len = extra_length - address_length;
     if remaining_len > 256 {
-        log::warn!("invalid proxy protocol tlv data length: {remaining_len} (expected <= 256)");
+        tracing::warn!("invalid proxy protocol tlv data length: {remaining_len} (expected <= 256)");
         return Err(io::ErrorKind::InvalidData.into());
     }
     let mut tlv_bytes_buf = [0u8; 256];
@@ -254,14 +254,14 @@ async fn parse_tlvs<T: AsyncRead + Unpin>(
     while offset < remaining_len {
         let rest_len = remaining_len - offset;
         if rest_len < 3 {
-            log::warn!("invalid proxy protocol tlv data: {:?}", tlv_bytes);
+            tracing::warn!("invalid proxy protocol tlv data: {:?}", tlv_bytes);
             return Err(io::ErrorKind::InvalidData.into());
         }
         let tlv_type = tlv_bytes[offset];
         let length = u16::from_be_bytes([tlv_bytes[offset + 1], tlv_bytes[offset + 2]]) as usize;
         offset += 3;
         if rest_len < 3 + length {
-            log::warn!("invalid proxy protocol tlv data: {:?}", tlv_bytes);
+            tracing::warn!("invalid proxy protocol tlv data: {:?}", tlv_bytes);
             return Err(io::ErrorKind::InvalidData.into());
         }
 
@@ -269,13 +269,13 @@ async fn parse_tlvs<T: AsyncRead + Unpin>(
             // PP2_TYPE_AUTHORITY
             0x02 => {
                 if length == 0 {
-                    log::warn!("invalid proxy protocol PP2_TYPE_AUTHORITY length: {length}",);
+                    tracing::warn!("invalid proxy protocol PP2_TYPE_AUTHORITY length: {length}",);
                     return Err(io::ErrorKind::InvalidData.into());
                 }
                 let s = match String::from_utf8(tlv_bytes[offset..offset + length].to_vec()) {
                     Ok(s) => s,
                     Err(_) => {
-                        log::warn!(
+                        tracing::warn!(
                             "invalid proxy protocol PP2_TYPE_AUTHORITY field: {:?}",
                             &tlv_bytes[offset..offset + length]
                         );
@@ -288,7 +288,7 @@ async fn parse_tlvs<T: AsyncRead + Unpin>(
             // PP2_TYPE_CRC32C
             0x03 => {
                 if length != 4 {
-                    log::warn!("invalid proxy protocol PP2_TYPE_CRC32C length: {length}",);
+                    tracing::warn!("invalid proxy protocol PP2_TYPE_CRC32C length: {length}",);
                     return Err(io::ErrorKind::InvalidData.into());
                 }
                 let expected_value = u32::from_be_bytes([
@@ -304,7 +304,7 @@ async fn parse_tlvs<T: AsyncRead + Unpin>(
                 let mut value = crc32c::crc32c(&buffer[..16 + address_length]);
                 value = crc32c::crc32c_append(value, tlv_bytes);
                 if expected_value != value {
-                    log::warn!(
+                    tracing::warn!(
                         "invalid proxy protocol PP2_TYPE_CRC32C value={value}, expected={expected_value}"
                     );
                     return Err(io::ErrorKind::InvalidData.into());
@@ -313,13 +313,13 @@ async fn parse_tlvs<T: AsyncRead + Unpin>(
             // PP2_TYPE_SSL
             0x20 => {
                 if length < 5 {
-                    log::warn!("invalid proxy protocol PP2_TYPE_SSL length: {length}");
+                    tracing::warn!("invalid proxy protocol PP2_TYPE_SSL length: {length}");
                     return Err(io::ErrorKind::InvalidData.into());
                 }
                 // ignore <client> sub-field (tlv_bytes[offset+3]).
                 // check <verify> sub-field
                 if tlv_bytes[offset + 1..offset + 5] != [0u8; 4] {
-                    log::warn!(
+                    tracing::warn!(
                         "invalid proxy protocol PP2_TYPE_SSL field: {:?}",
                         &tlv_bytes[offset..offset + length]
                     );
@@ -334,25 +334,25 @@ async fn parse_tlvs<T: AsyncRead + Unpin>(
     }
 
     if offset != remaining_len {
-        log::warn!("invalid proxy protocol tlv data: {:?}", tlv_bytes);
+        tracing::warn!("invalid proxy protocol tlv data: {:?}", tlv_bytes);
         return Err(io::ErrorKind::InvalidData.into());
     }
     if tls_termination {
         if !pp2_type_ssl {
-            log::warn!("invalid proxy protocol PP2_TYPE_SSL not presented in TLS mode");
+            tracing::warn!("invalid proxy protocol PP2_TYPE_SSL not presented in TLS mode");
             return Err(io::ErrorKind::InvalidData.into());
         }
         if !pp2_type_authority {
-            log::warn!("invalid proxy protocol PP2_TYPE_AUTHORITY not presented in TLS mode");
+            tracing::warn!("invalid proxy protocol PP2_TYPE_AUTHORITY not presented in TLS mode");
             return Err(io::ErrorKind::InvalidData.into());
         }
     } else {
         if pp2_type_ssl {
-            log::warn!("invalid proxy protocol PP2_TYPE_SSL presented in non-TLS mode");
+            tracing::warn!("invalid proxy protocol PP2_TYPE_SSL presented in non-TLS mode");
             return Err(io::ErrorKind::InvalidData.into());
         }
         if pp2_type_authority {
-            log::warn!("invalid proxy protocol PP2_TYPE_AUTHORITY presented in non-TLS mode");
+            tracing::warn!("invalid proxy protocol PP2_TYPE_AUTHORITY presented in non-TLS mode");
             return Err(io::ErrorKind::InvalidData.into());
         }
     }
@@ -1,147 +1,605 @@
+use std::future::{self, Future};
 use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
-use tokio::{net::TcpSocket, runtime::Runtime};
+use ahash::RandomState;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    runtime::{Builder, Handle, Runtime},
+    sync::mpsc,
+    task::JoinHandle,
+};
 
-use super::{build_tls_context, handle_accept, ConnectionArgs};
-use crate::config::{Listener, ProxyMode, TlsListener};
+#[cfg(feature = "grpc")]
+use super::grpc;
+use super::{admin, build_tls_context, handle_accept, ConnectionArgs};
+use crate::alarm;
+#[cfg(feature = "amqp")]
+use crate::amqp;
+use crate::audit::AuditEvent;
+use crate::ban;
+use crate::config::{qos_from_value, Config, Listener, Listeners, ProxyMode, TlsListener};
+use crate::delayed;
+use crate::events;
 use crate::hook::Hook;
+#[cfg(feature = "kafka")]
+use crate::kafka;
+use crate::quota;
 use crate::state::GlobalState;
+use crate::sys;
+#[cfg(feature = "tsdb")]
+use crate::tsdb;
+#[cfg(feature = "webhook")]
+use crate::webhook;
+
+const HEARTBEAT_INTERVAL_SECS: u64 = 1;
+// Wait for the tasks stopped when the server is shutting down
+const SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+// Check if all the clients disconnected when draining
+const DRAIN_CHECK_INTERVAL_MS: u64 = 50;
+
+/// Load the config again when received SIGHUP
+pub type ConfigLoader = Box<dyn Fn() -> io::Result<Config> + Send + Sync>;
 
 pub fn start<H>(hook_handler: H, global: Arc<GlobalState>) -> io::Result<()>
 where
     H: Hook + Clone + Send + Sync + 'static,
+{
+    start_with_loader(hook_handler, global, None)
+}
+
+/// Start the server, if `config_loader` is given the config will be reloaded
+/// when the process received SIGHUP.
+pub fn start_with_loader<H>(
+    hook_handler: H,
+    global: Arc<GlobalState>,
+    config_loader: Option<ConfigLoader>,
+) -> io::Result<()>
+where
+    H: Hook + Clone + Send + Sync + 'static,
+{
+    run_until(hook_handler, global, config_loader, future::pending())
+}
+
+/// Run the server until `shutdown` is completed, then the listeners are
+/// closed, the clients are disconnected (wait at most the returned duration),
+/// the quotas and the bans are saved, and the background tasks are stopped.
+pub(crate) fn run_until<H, F>(
+    hook_handler: H,
+    global: Arc<GlobalState>,
+    config_loader: Option<ConfigLoader>,
+    shutdown: F,
+) -> io::Result<()>
+where
+    H: Hook + Clone + Send + Sync + 'static,
+    F: Future<Output = Duration>,
 {
     let rt = Runtime::new()?;
+    let executors = Executors::start(global.config().executors)?;
+    let result = rt.block_on(serve(
+        hook_handler,
+        global,
+        config_loader,
+        executors.clone(),
+        shutdown,
+    ));
+    rt.shutdown_timeout(Duration::from_secs(SHUTDOWN_TIMEOUT_SECS));
+    executors.stop();
+    result
+}
 
-    let mqtts_tls_acceptor = global
-        .config
-        .listeners
-        .mqtts
-        .as_ref()
-        .map(|listener| {
-            log::info!("Building TLS context for mqtts...");
-            build_tls_context(listener)
-        })
-        .transpose()?;
-    let wss_tls_acceptor = global
-        .config
-        .listeners
-        .wss
-        .as_ref()
-        .map(|listener| {
-            log::info!("Building TLS context for wss...");
-            build_tls_context(listener)
+async fn serve<H, F>(
+    hook_handler: H,
+    global: Arc<GlobalState>,
+    config_loader: Option<ConfigLoader>,
+    executors: Executors,
+    shutdown: F,
+) -> io::Result<()>
+where
+    H: Hook + Clone + Send + Sync + 'static,
+    F: Future<Output = Duration>,
+{
+    // The executor liveness heartbeat, see `Health::executor_alive`
+    let health_global = Arc::clone(&global);
+    tokio::spawn(async move {
+        loop {
+            health_global.health.heartbeat();
+            tokio::time::sleep(Duration::from_secs(HEARTBEAT_INTERVAL_SECS)).await;
+        }
+    });
+    tokio::spawn(sys::run(Arc::clone(&global)));
+    tokio::spawn(sys::run_top_talkers(Arc::clone(&global)));
+    tokio::spawn(sys::run_qos0_shedding(Arc::clone(&global)));
+    tokio::spawn(alarm::run(Arc::clone(&global)));
+    tokio::spawn(delayed::run(Arc::clone(&global)));
+    tokio::spawn(events::run_webhook(Arc::clone(&global)));
+    #[cfg(feature = "kafka")]
+    tokio::spawn(kafka::run(Arc::clone(&global)));
+    #[cfg(feature = "amqp")]
+    tokio::spawn(amqp::run(Arc::clone(&global)));
+    #[cfg(feature = "webhook")]
+    tokio::spawn(webhook::run(Arc::clone(&global)));
+    #[cfg(feature = "tsdb")]
+    tokio::spawn(tsdb::run(Arc::clone(&global)));
+    if let Some(path) = global.config().quotas.state_file.as_ref() {
+        if let Err(err) = global.quotas.load(path) {
+            tracing::error!("load quotas from {} error: {}", path.display(), err);
+        }
+    }
+    tokio::spawn(quota::run(Arc::clone(&global)));
+    if let Some(path) = global.config().ban_file.as_ref() {
+        if let Err(err) = global.bans.load(path) {
+            tracing::error!("load bans from {} error: {}", path.display(), err);
+        }
+    }
+    if let Some(admin_config) = global.config().admin.as_ref() {
+        let addr = admin_config.addr;
+        let admin_global = Arc::clone(&global);
+        tokio::spawn(async move {
+            if let Err(err) = admin::serve(addr, admin_global).await {
+                tracing::error!("Admin server error: {:?}", err);
+            }
+        });
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_config) = global.config().grpc.as_ref() {
+        let addr = grpc_config.addr;
+        let grpc_global = Arc::clone(&global);
+        tokio::spawn(async move {
+            if let Err(err) = grpc::serve(addr, grpc_global).await {
+                tracing::error!("gRPC server error: {:?}", err);
+            }
+        });
+    }
+
+    let no_listeners = Listeners {
+        mqtt: None,
+        mqtts: None,
+        ws: None,
+        wss: None,
+    };
+    let mut listeners = RunningListeners::new(executors);
+    listeners.update(
+        &no_listeners,
+        &global.config().listeners,
+        &hook_handler,
+        &global,
+    )?;
+    if listeners.is_empty() {
+        tracing::warn!("No binding address in config");
+    }
+
+    let (updates_sender, mut updates) = mpsc::unbounded_channel();
+    *global.listeners_updates.lock() = Some(updates_sender);
+    #[cfg(unix)]
+    let mut hangup = match config_loader {
+        Some(_) => {
+            use tokio::signal::unix::{signal, SignalKind};
+            Some(signal(SignalKind::hangup())?)
+        }
+        None => None,
+    };
+    tokio::pin!(shutdown);
+    let drain_timeout = loop {
+        // Only reload the config when there is a loader
+        let hangup_received = async {
+            #[cfg(unix)]
+            if let Some(hangup) = hangup.as_mut() {
+                hangup.recv().await;
+                return;
+            }
+            future::pending::<()>().await
+        };
+        tokio::select! {
+            _ = hangup_received => {
+                if let Some(config_loader) = config_loader.as_ref() {
+                    reload(config_loader, &hook_handler, &global, &mut listeners);
+                }
+            }
+            Some((new_listeners, reply)) = updates.recv() => {
+                let old_listeners = global.config().listeners.clone();
+                let result = listeners.update(&old_listeners, &new_listeners, &hook_handler, &global);
+                if result.is_ok() {
+                    tracing::info!("Listeners updated: {:?}", new_listeners);
+                    global.set_listeners(new_listeners);
+                }
+                let _ = reply.send(result);
+            }
+            drain_timeout = &mut shutdown => break drain_timeout,
+        }
+    };
+
+    tracing::info!("Shutting down...");
+    *global.listeners_updates.lock() = None;
+    let old_listeners = global.config().listeners.clone();
+    listeners.update(&old_listeners, &no_listeners, &hook_handler, &global)?;
+    drain_clients(&global, drain_timeout).await;
+    quota::save_quotas(&global);
+    if let Err(err) = ban::save_bans(&global) {
+        tracing::error!("save bans error: {}", err);
+    }
+    global.flush_audit_log();
+    tracing::info!("Server stopped");
+    Ok(())
+}
+
+/// Kick all the online clients out, then wait for them disconnected (the will
+/// messages are sent and the after disconnect hooks are finished).
+async fn drain_clients(global: &GlobalState, timeout: Duration) {
+    if global.online_clients_count() == 0 {
+        return;
+    }
+    tracing::info!("Disconnecting {} clients...", global.online_clients_count());
+    global.kick_all("server shutting down");
+    let drained = tokio::time::timeout(timeout, async {
+        while global.online_clients_count() > 0 {
+            tokio::time::sleep(Duration::from_millis(DRAIN_CHECK_INTERVAL_MS)).await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        tracing::warn!(
+            "{} clients still online after {:?}",
+            global.online_clients_count(),
+            timeout
+        );
+    }
+}
+
+fn reload<H>(
+    config_loader: &ConfigLoader,
+    hook_handler: &H,
+    global: &Arc<GlobalState>,
+    listeners: &mut RunningListeners,
+) where
+    H: Hook + Clone + Send + Sync + 'static,
+{
+    tracing::info!("Reloading config...");
+    let config = match config_loader() {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!("Load config failed, keep current config: {}", err);
+            global.audit(config_reload_failed(&err));
+            return;
+        }
+    };
+    // Load the new listeners before the config is swapped, so the config
+    // always matches the running listeners
+    let old_listeners = global.config().listeners.clone();
+    let changed = match ListenerChanges::new(&old_listeners, &config.listeners) {
+        Ok(changed) => changed,
+        Err(err) => {
+            tracing::error!("Load listeners failed, keep current config: {}", err);
+            global.audit(config_reload_failed(&err));
+            return;
+        }
+    };
+    match global.reload_config(config) {
+        Ok(changes) => {
+            if changes.is_empty() {
+                tracing::info!("Config not changed");
+            } else {
+                tracing::info!("Config reloaded, applied fields: {:?}", changes.applied);
+            }
+            if !changes.restart_required.is_empty() {
+                tracing::warn!(
+                    "Changed fields require restart: {:?}",
+                    changes.restart_required
+                );
+            }
+            global.audit(AuditEvent::ConfigReload {
+                success: true,
+                applied: changes
+                    .applied
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+                restart_required: changes
+                    .restart_required
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+                error: None,
+            });
+        }
+        Err(err) => {
+            tracing::error!("Reload config failed, keep current config: {}", err);
+            global.audit(config_reload_failed(&err));
+            return;
+        }
+    }
+    listeners.apply(&old_listeners, changed, hook_handler, global);
+}
+
+fn config_reload_failed(err: &io::Error) -> AuditEvent {
+    AuditEvent::ConfigReload {
+        success: false,
+        applied: Vec::new(),
+        restart_required: Vec::new(),
+        error: Some(err.to_string()),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+enum ListenerKind {
+    Mqtt,
+    Mqtts,
+    Ws,
+    Wss,
+}
+
+impl ListenerKind {
+    const ALL: [ListenerKind; 4] = [
+        ListenerKind::Mqtt,
+        ListenerKind::Mqtts,
+        ListenerKind::Ws,
+        ListenerKind::Wss,
+    ];
+
+    fn changed(&self, old: &Listeners, new: &Listeners) -> bool {
+        match self {
+            ListenerKind::Mqtt => old.mqtt != new.mqtt,
+            ListenerKind::Mqtts => old.mqtts != new.mqtts,
+            ListenerKind::Ws => old.ws != new.ws,
+            ListenerKind::Wss => old.wss != new.wss,
+        }
+    }
+
+    fn addr(&self, listeners: &Listeners) -> Option<SocketAddr> {
+        match self {
+            ListenerKind::Mqtt => listeners.mqtt.as_ref().map(|l| l.addr),
+            ListenerKind::Mqtts => listeners.mqtts.as_ref().map(|l| l.addr),
+            ListenerKind::Ws => listeners.ws.as_ref().map(|l| l.addr),
+            ListenerKind::Wss => listeners.wss.as_ref().map(|l| l.addr),
+        }
+    }
+
+    fn conn_args(&self, listeners: &Listeners) -> io::Result<Option<ConnectionArgs>> {
+        let plain_args = |listener: &Listener, websocket: bool| ConnectionArgs {
+            addr: listener.addr,
+            reuse_port: listener.reuse_port,
+            proxy: listener.proxy_mode.is_some(),
+            proxy_tls_termination: listener.proxy_mode == Some(ProxyMode::TlsTermination),
+            websocket,
+            tls_acceptor: None,
+            max_qos: listener.max_qos.map(qos_from_value),
+            server_keep_alive: listener.server_keep_alive,
+        };
+        let tls_args = |listener: &TlsListener, websocket: bool| -> io::Result<ConnectionArgs> {
+            tracing::info!("Building TLS context for {:?}...", self);
+            Ok(ConnectionArgs {
+                addr: listener.addr,
+                reuse_port: listener.reuse_port,
+                proxy: listener.proxy,
+                proxy_tls_termination: false,
+                websocket,
+                tls_acceptor: Some(build_tls_context(listener)?),
+                max_qos: listener.max_qos.map(qos_from_value),
+                server_keep_alive: listener.server_keep_alive,
+            })
+        };
+        match self {
+            ListenerKind::Mqtt => Ok(listeners.mqtt.as_ref().map(|l| plain_args(l, false))),
+            ListenerKind::Mqtts => listeners
+                .mqtts
+                .as_ref()
+                .map(|l| tls_args(l, false))
+                .transpose(),
+            ListenerKind::Ws => Ok(listeners.ws.as_ref().map(|l| plain_args(l, true))),
+            ListenerKind::Wss => listeners
+                .wss
+                .as_ref()
+                .map(|l| tls_args(l, true))
+                .transpose(),
+        }
+    }
+}
+
+/// The single-threaded executors the connections are pinned to (see
+/// `Config.executors`), the clients on different executors communicate by
+/// the same channels as in the multi-thread executor.
+#[derive(Clone)]
+struct Executors {
+    hash_builder: RandomState,
+    handles: Arc<Vec<Handle>>,
+    // Every message stops one executor thread
+    stop_sender: flume::Sender<()>,
+    threads: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
+}
+
+impl Executors {
+    fn start(count: usize) -> io::Result<Executors> {
+        let (stop_sender, stop_receiver) = flume::unbounded::<()>();
+        let mut handles = Vec::with_capacity(count);
+        let mut threads = Vec::with_capacity(count);
+        for idx in 0..count {
+            let rt = Builder::new_current_thread().enable_all().build()?;
+            handles.push(rt.handle().clone());
+            let stop_receiver = stop_receiver.clone();
+            let thread = thread::Builder::new()
+                .name(format!("akasa-executor-{idx}"))
+                .spawn(move || {
+                    rt.block_on(async move {
+                        let _ = stop_receiver.recv_async().await;
+                    });
+                    rt.shutdown_timeout(Duration::from_secs(SHUTDOWN_TIMEOUT_SECS));
+                })?;
+            threads.push(thread);
+        }
+        if count > 0 {
+            tracing::info!("Started {} executors", count);
+        }
+        Ok(Executors {
+            hash_builder: RandomState::new(),
+            handles: Arc::new(handles),
+            stop_sender,
+            threads: Arc::new(Mutex::new(threads)),
         })
-        .transpose()?;
-
-    cfg_if::cfg_if! {
-        if #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))] {
-            let reuse_port_available = true;
-        } else {
-            let reuse_port_available = false;
-        }
-    }
-
-    rt.block_on(async move {
-        let listeners = &global.config.listeners;
-        let tasks: Vec<_> = [
-            listeners.mqtt.as_ref().map(
-                |Listener {
-                     addr,
-                     reuse_port,
-                     proxy_mode,
-                 }| ConnectionArgs {
-                    addr: *addr,
-                    reuse_port: *reuse_port,
-                    proxy: proxy_mode.is_some(),
-                    proxy_tls_termination: *proxy_mode == Some(ProxyMode::TlsTermination),
-                    websocket: false,
-                    tls_acceptor: None,
-                },
-            ),
-            listeners.mqtts.as_ref().map(
-                |TlsListener {
-                     addr,
-                     reuse_port,
-                     proxy,
-                     ..
-                 }| ConnectionArgs {
-                    addr: *addr,
-                    reuse_port: *reuse_port,
-                    proxy: *proxy,
-                    proxy_tls_termination: false,
-                    websocket: false,
-                    tls_acceptor: mqtts_tls_acceptor.map(Into::into),
-                },
-            ),
-            listeners.ws.as_ref().map(
-                |Listener {
-                     addr,
-                     reuse_port,
-                     proxy_mode,
-                 }| ConnectionArgs {
-                    addr: *addr,
-                    reuse_port: *reuse_port,
-                    proxy: proxy_mode.is_some(),
-                    proxy_tls_termination: *proxy_mode == Some(ProxyMode::TlsTermination),
-                    websocket: true,
-                    tls_acceptor: None,
-                },
-            ),
-            listeners.wss.as_ref().map(
-                |TlsListener {
-                     addr,
-                     reuse_port,
-                     proxy,
-                     ..
-                 }| ConnectionArgs {
-                    addr: *addr,
-                    reuse_port: *reuse_port,
-                    proxy: *proxy,
-                    proxy_tls_termination: false,
-                    websocket: true,
-                    tls_acceptor: wss_tls_acceptor.map(Into::into),
-                },
-            ),
-        ]
-        .into_iter()
-        .flatten()
-        .flat_map(|conn_args| {
-            let reuse_port = reuse_port_available && conn_args.reuse_port;
-            let global = Arc::clone(&global);
-            let hook_handler = hook_handler.clone();
-            let conn_args = conn_args.clone();
-            let n = if reuse_port { 4 } else { 1 };
-            (0..n).map(move |_| {
-                let global = Arc::clone(&global);
-                let hook_handler = hook_handler.clone();
-                let conn_args = conn_args.clone();
-                tokio::spawn(async move {
-                    loop {
-                        let global = Arc::clone(&global);
-                        let hook_handler = hook_handler.clone();
-                        if let Err(err) =
-                            listen(conn_args.clone(), reuse_port, hook_handler, global).await
-                        {
-                            log::error!("Listen error: {:?}", err);
-                            tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// The index and the handle of the executor the client is pinned to (by
+    /// the hash of the peer IP, so the reconnections of a client go to the
+    /// same executor), None means the current executor.
+    fn pick(&self, peer: &SocketAddr) -> Option<(usize, &Handle)> {
+        if self.handles.is_empty() {
+            return None;
+        }
+        let idx = self.hash_builder.hash_one(peer.ip()) as usize % self.handles.len();
+        Some((idx, &self.handles[idx]))
+    }
+
+    /// Stop the executors (the tasks still running are dropped) and wait for
+    /// the threads to exit.
+    fn stop(&self) {
+        for _ in 0..self.handles.len() {
+            let _ = self.stop_sender.send(());
+        }
+        for thread in self.threads.lock().drain(..) {
+            if thread.join().is_err() {
+                tracing::error!("executor thread panicked");
+            }
+        }
+    }
+}
+
+/// The accept tasks of current listeners
+struct RunningListeners {
+    tasks: HashMap<ListenerKind, Vec<JoinHandle<()>>>,
+    executors: Executors,
+}
+
+impl RunningListeners {
+    fn new(executors: Executors) -> RunningListeners {
+        RunningListeners {
+            tasks: HashMap::new(),
+            executors,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Start/stop/restart the listeners changed from `old` to `new`. The
+    /// established connections are not affected.
+    fn update<H>(
+        &mut self,
+        old: &Listeners,
+        new: &Listeners,
+        hook_handler: &H,
+        global: &Arc<GlobalState>,
+    ) -> io::Result<()>
+    where
+        H: Hook + Clone + Send + Sync + 'static,
+    {
+        let changed = ListenerChanges::new(old, new)?;
+        self.apply(old, changed, hook_handler, global);
+        Ok(())
+    }
+
+    /// Apply the changes built from `old`
+    fn apply<H>(
+        &mut self,
+        old: &Listeners,
+        changed: ListenerChanges,
+        hook_handler: &H,
+        global: &Arc<GlobalState>,
+    ) where
+        H: Hook + Clone + Send + Sync + 'static,
+    {
+        cfg_if::cfg_if! {
+            if #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))] {
+                let reuse_port_available = true;
+            } else {
+                let reuse_port_available = false;
+            }
+        }
+
+        for (kind, conn_args) in changed.0 {
+            if let Some(tasks) = self.tasks.remove(&kind) {
+                tracing::info!("Stop {:?} listener", kind);
+                for task in tasks {
+                    task.abort();
+                }
+                if let Some(addr) = kind.addr(old) {
+                    global.health.remove_listener(&addr);
+                }
+            }
+            let conn_args = match conn_args {
+                Some(conn_args) => conn_args,
+                None => continue,
+            };
+            // With the executors every executor accepts on its own socket
+            // (SO_REUSEPORT), otherwise the accept loops run in the
+            // multi-thread executor.
+            let per_executor = reuse_port_available && !self.executors.is_empty();
+            let reuse_port = per_executor || (reuse_port_available && conn_args.reuse_port);
+            let accept_executors: Vec<Option<usize>> = if per_executor {
+                (0..self.executors.handles.len()).map(Some).collect()
+            } else {
+                vec![None; if reuse_port { 4 } else { 1 }]
+            };
+            let tasks = accept_executors
+                .into_iter()
+                .map(|local| {
+                    let global = Arc::clone(global);
+                    let hook_handler = hook_handler.clone();
+                    let conn_args = conn_args.clone();
+                    let executors = self.executors.clone();
+                    let accept_loop = async move {
+                        loop {
+                            let hook_handler = hook_handler.clone();
+                            if let Err(err) = listen(
+                                conn_args.clone(),
+                                reuse_port,
+                                hook_handler,
+                                Arc::clone(&global),
+                                &executors,
+                                local,
+                            )
+                            .await
+                            {
+                                tracing::error!("Listen error: {:?}", err);
+                                global.health.set_listener(conn_args.addr, false);
+                                tokio::time::sleep(Duration::from_secs(1)).await;
+                            }
                         }
+                    };
+                    match local {
+                        Some(idx) => self.executors.handles[idx].spawn(accept_loop),
+                        None => tokio::spawn(accept_loop),
                     }
                 })
-            })
-        })
-        .collect();
-
-        if tasks.is_empty() {
-            log::error!("No binding address in config");
+                .collect();
+            self.tasks.insert(kind, tasks);
         }
-        for task in tasks {
-            let _ = task.await;
+    }
+}
+
+/// The changed listeners with their new args (`None` means stopped). They are
+/// built before any running listener is touched, so a broken TLS config will
+/// not stop any running listener.
+struct ListenerChanges(Vec<(ListenerKind, Option<ConnectionArgs>)>);
+
+impl ListenerChanges {
+    fn new(old: &Listeners, new: &Listeners) -> io::Result<ListenerChanges> {
+        let mut changed = Vec::new();
+        for kind in ListenerKind::ALL {
+            if kind.changed(old, new) {
+                changed.push((kind, kind.conn_args(new)?));
+            }
         }
-    });
-    Ok(())
+        Ok(ListenerChanges(changed))
+    }
 }
 
 async fn listen<H: Hook + Clone + Send + Sync + 'static>(
@@ -149,6 +607,9 @@ async fn listen<H: Hook + Clone + Send + Sync + 'static>(
     reuse_port: bool,
     hook_handler: H,
     global: Arc<GlobalState>,
+    executors: &Executors,
+    // The index of the executor running this accept loop
+    local: Option<usize>,
 ) -> io::Result<()> {
     let addr = conn_args.addr;
     let socket = if addr.is_ipv4() {
@@ -162,6 +623,7 @@ async fn listen<H: Hook + Clone + Send + Sync + 'static>(
     }
     socket.bind(addr)?;
     let listener = socket.listen(1024)?;
+    global.health.set_listener(addr, true);
 
     let listen_type = match (conn_args.websocket, conn_args.tls_acceptor.is_some()) {
         (false, false) => "mqtt",
@@ -174,19 +636,42 @@ async fn listen<H: Hook + Clone + Send + Sync + 'static>(
         .filter(|(flag, _)| *flag)
         .map(|(_, text)| text)
         .collect::<Vec<_>>();
-    log::info!(
+    tracing::info!(
         "Listen {listen_type}@{addr} ({}) success!",
         labels.join(",")
     );
 
     loop {
         let (conn, peer) = listener.accept().await?;
-        log::debug!("{} connected", peer,);
+        tracing::debug!("{} connected", peer,);
         let conn_args = conn_args.clone();
         let hook_handler = hook_handler.clone();
         let global = Arc::clone(&global);
-        tokio::spawn(async move {
-            let _ = handle_accept(conn, conn_args, peer, hook_handler, Arc::clone(&global)).await;
+        let handle = match executors.pick(&peer) {
+            Some((idx, handle)) if local != Some(idx) => handle,
+            // No executors, or accepted by the executor the client is pinned to
+            _ => {
+                tokio::spawn(async move {
+                    let _ = handle_accept(conn, conn_args, peer, hook_handler, global).await;
+                });
+                continue;
+            }
+        };
+        // Register the socket to the reactor of the pinned executor
+        let conn = match conn.into_std() {
+            Ok(conn) => conn,
+            Err(err) => {
+                tracing::debug!("move connection {} to executor error: {}", peer, err);
+                continue;
+            }
+        };
+        handle.spawn(async move {
+            match TcpStream::from_std(conn) {
+                Ok(conn) => {
+                    let _ = handle_accept(conn, conn_args, peer, hook_handler, global).await;
+                }
+                Err(err) => tracing::debug!("register connection {} error: {}", peer, err),
+            }
         });
     }
 }
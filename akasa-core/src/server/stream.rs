@@ -0,0 +1,396 @@
+//! The WebSocket and SSE subscription API of the admin server
+//! (`GET /api/v1/stream`), for the dashboards which don't want an MQTT client.
+//! The messages are sent as JSON envelopes.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{SinkExt, StreamExt};
+use mqtt_proto::{QoS, TopicFilter};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{interval_at, Instant};
+use tokio_tungstenite::{
+    tungstenite::{protocol::Role, Error as WsError, Message},
+    WebSocketStream,
+};
+
+use super::http::{write_response, Request, Response};
+use super::subscriber::{SubscribedMessage, Subscriber};
+use crate::config::qos_from_value;
+use crate::state::GlobalState;
+
+pub(crate) const STREAM_PATH: &str = "/api/v1/stream";
+// Keep the idle SSE streams alive through the proxies
+const KEEP_ALIVE_SECS: u64 = 15;
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    // Unix timestamp in milliseconds when sent
+    time: u64,
+    topic: &'a str,
+    qos: u8,
+    retain: bool,
+    topic_filter: &'a str,
+    payload: String,
+    // The payload is not UTF-8, so it's encoded by base64
+    base64: bool,
+}
+
+/// Serve the stream request, upgraded to WebSocket if requested, otherwise an
+/// SSE stream.
+pub(crate) async fn serve<T: AsyncRead + AsyncWrite + Unpin>(
+    global: &Arc<GlobalState>,
+    peer: SocketAddr,
+    request: Request,
+    mut conn: T,
+) -> io::Result<()> {
+    let subscriptions = match parse_subscriptions(global, &request) {
+        Ok(subscriptions) => subscriptions,
+        Err(response) => return write_response(&mut conn, &response).await,
+    };
+    let websocket_accept = if request
+        .header("upgrade")
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+    {
+        match request.header("sec-websocket-key") {
+            Some(key) => Some(websocket_accept(key)),
+            None => {
+                let response = Response::text(400, "missing websocket key");
+                return write_response(&mut conn, &response).await;
+            }
+        }
+    } else {
+        None
+    };
+    let retained = request.query_param("retained").as_deref() == Some("true");
+    let subscriber = match Subscriber::new(global, "stream", subscriptions).await {
+        Ok(subscriber) => subscriber,
+        Err(err) => {
+            tracing::warn!("add stream subscriber for {} error: {}", peer, err);
+            let response = Response::text(503, "add subscriber failed");
+            return write_response(&mut conn, &response).await;
+        }
+    };
+    match websocket_accept {
+        Some(accept) => serve_websocket(&subscriber, retained, conn, &accept).await,
+        None => serve_sse(&subscriber, retained, conn).await,
+    }
+}
+
+/// Check the token and parse the subscriptions, the topic filters are the
+/// `filter` query parameters with the same `qos`.
+fn parse_subscriptions(
+    global: &GlobalState,
+    request: &Request,
+) -> Result<Vec<(TopicFilter, QoS)>, Response> {
+    let config = global.config();
+    let token = match config
+        .admin
+        .as_ref()
+        .and_then(|admin| admin.stream_token.as_ref())
+    {
+        Some(token) => token,
+        None => return Err(Response::text(403, "stream API disabled")),
+    };
+    // The browsers can't set the headers of EventSource and WebSocket
+    let bearer = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if bearer != Some(token.as_str()) && request.query_param("token").as_ref() != Some(token) {
+        return Err(Response::text(401, "invalid token"));
+    }
+    let qos = match request.query_param("qos") {
+        Some(value) => match value.parse::<u8>() {
+            Ok(value @ 0..=2) => qos_from_value(value),
+            _ => return Err(Response::text(400, "invalid qos")),
+        },
+        None => QoS::Level0,
+    };
+    let filters = request.query_params("filter");
+    if filters.is_empty() {
+        return Err(Response::text(400, "missing topic filter"));
+    }
+    filters
+        .into_iter()
+        .map(|filter| match TopicFilter::try_from(filter) {
+            Ok(filter) => Ok((filter, qos)),
+            Err(_) => Err(Response::text(400, "invalid topic filter")),
+        })
+        .collect()
+}
+
+async fn serve_sse<T: AsyncRead + AsyncWrite + Unpin>(
+    subscriber: &Subscriber,
+    retained: bool,
+    mut conn: T,
+) -> io::Result<()> {
+    conn.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+          Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+    )
+    .await?;
+    if retained {
+        for message in subscriber.retained() {
+            conn.write_all(sse_event(&message).as_bytes()).await?;
+        }
+    }
+    conn.flush().await?;
+    let period = Duration::from_secs(KEEP_ALIVE_SECS);
+    let mut keep_alive = interval_at(Instant::now() + period, period);
+    let mut buf = [0u8; 64];
+    loop {
+        tokio::select! {
+            message = subscriber.recv() => match message {
+                Some(message) => conn.write_all(sse_event(&message).as_bytes()).await?,
+                None => return Ok(()),
+            },
+            _ = keep_alive.tick() => conn.write_all(b": keep-alive\n\n").await?,
+            // The client sends nothing, the stream ends when it's closed
+            result = conn.read(&mut buf) => {
+                if matches!(result, Ok(0) | Err(_)) {
+                    return Ok(());
+                }
+            }
+        }
+        conn.flush().await?;
+    }
+}
+
+async fn serve_websocket<T: AsyncRead + AsyncWrite + Unpin>(
+    subscriber: &Subscriber,
+    retained: bool,
+    mut conn: T,
+    accept: &str,
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+    );
+    conn.write_all(head.as_bytes()).await?;
+    let mut ws = WebSocketStream::from_raw_socket(conn, Role::Server, None).await;
+    let ws_error = |err: WsError| io::Error::new(io::ErrorKind::Other, err);
+    if retained {
+        for message in subscriber.retained() {
+            ws.send(Message::Text(encode_envelope(&message)))
+                .await
+                .map_err(ws_error)?;
+        }
+    }
+    loop {
+        tokio::select! {
+            message = subscriber.recv() => match message {
+                Some(message) => ws
+                    .send(Message::Text(encode_envelope(&message)))
+                    .await
+                    .map_err(ws_error)?,
+                None => {
+                    let _ = ws.close(None).await;
+                    return Ok(());
+                }
+            },
+            // The pings are answered by the stream, other messages are ignored
+            incoming = ws.next() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ok(()),
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+fn encode_envelope(message: &SubscribedMessage) -> String {
+    let (payload, base64) = match std::str::from_utf8(&message.payload) {
+        Ok(payload) => (payload.to_owned(), false),
+        Err(_) => (STANDARD.encode(&message.payload), true),
+    };
+    let envelope = Envelope {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0),
+        topic: &message.topic_name,
+        qos: message.qos as u8,
+        retain: message.retain,
+        topic_filter: &message.topic_filter,
+        payload,
+        base64,
+    };
+    serde_json::to_string(&envelope).expect("encode envelope")
+}
+
+// The JSON is in one line, since the newlines are escaped
+fn sse_event(message: &SubscribedMessage) -> String {
+    format!("data: {}\n\n", encode_envelope(message))
+}
+
+// The `Sec-WebSocket-Accept` of the key
+fn websocket_accept(key: &str) -> String {
+    let digest = openssl::sha::sha1(format!("{key}{WEBSOCKET_GUID}").as_bytes());
+    STANDARD.encode(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use mqtt_proto::TopicName;
+    use tokio::io::duplex;
+
+    use crate::config::{AdminConfig, Config};
+
+    fn stream_request(query: &str) -> Request {
+        Request {
+            method: "GET".to_owned(),
+            path: STREAM_PATH.to_owned(),
+            query: Some(query.to_owned()),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn new_global() -> Arc<GlobalState> {
+        let mut config = Config::new_allow_anonymous();
+        config.admin = Some(AdminConfig {
+            addr: "127.0.0.1:8081".parse().unwrap(),
+            token: None,
+            publish_token: None,
+            stream_token: Some("secret".to_owned()),
+        });
+        Arc::new(GlobalState::new(config))
+    }
+
+    #[test]
+    fn test_websocket_accept() {
+        // The example of RFC 6455
+        assert_eq!(
+            websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_parse_subscriptions() {
+        let global = new_global();
+        let subscriptions = parse_subscriptions(
+            &global,
+            &stream_request("token=secret&filter=a%2F%2B&filter=b/%23&qos=1"),
+        )
+        .unwrap();
+        assert_eq!(subscriptions.len(), 2);
+        assert_eq!(&*subscriptions[0].0, "a/+");
+        assert_eq!(&*subscriptions[1].0, "b/#");
+        assert_eq!(subscriptions[1].1, QoS::Level1);
+
+        for (query, status) in [
+            ("filter=a", 401),
+            ("token=wrong&filter=a", 401),
+            ("token=secret", 400),
+            ("token=secret&filter=a/%23/b", 400),
+            ("token=secret&filter=a&qos=3", 400),
+        ] {
+            let response = parse_subscriptions(&global, &stream_request(query)).unwrap_err();
+            assert_eq!(response.status, status);
+        }
+
+        let mut request = stream_request("filter=a");
+        request
+            .headers
+            .push(("authorization".to_owned(), "Bearer secret".to_owned()));
+        assert!(parse_subscriptions(&global, &request).is_ok());
+
+        let global = GlobalState::new(Config::new_allow_anonymous());
+        let response =
+            parse_subscriptions(&global, &stream_request("token=secret&filter=a")).unwrap_err();
+        assert_eq!(response.status, 403);
+    }
+
+    #[tokio::test]
+    async fn test_sse_stream() {
+        let global = new_global();
+        global
+            .publish(
+                TopicName::try_from("a/1".to_owned()).unwrap(),
+                QoS::Level1,
+                true,
+                Bytes::from_static(b"retained"),
+                Default::default(),
+            )
+            .unwrap();
+        let (mut client, server) = duplex(4096);
+        let task_global = Arc::clone(&global);
+        let task = tokio::spawn(async move {
+            let request = stream_request("token=secret&filter=a/%2B&qos=1&retained=true");
+            serve(
+                &task_global,
+                "127.0.0.1:9000".parse().unwrap(),
+                request,
+                server,
+            )
+            .await
+        });
+
+        let mut received = Vec::new();
+        let mut buf = [0u8; 1024];
+        let read_event = |received: &Vec<u8>| {
+            let text = String::from_utf8(received.clone()).unwrap();
+            text.matches("\n\n").count()
+        };
+        // The head and the retained message
+        while read_event(&received) < 1 {
+            let size = client.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..size]);
+        }
+        let delivered = global
+            .publish(
+                TopicName::try_from("a/2".to_owned()).unwrap(),
+                QoS::Level0,
+                false,
+                Bytes::from_static(&[0xff]),
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(delivered, 1);
+        while read_event(&received) < 2 {
+            let size = client.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..size]);
+        }
+
+        let text = String::from_utf8(received).unwrap();
+        let mut parts = text.split("\r\n\r\n");
+        assert!(parts.next().unwrap().contains("text/event-stream"));
+        let events: Vec<serde_json::Value> = parts
+            .next()
+            .unwrap()
+            .split("\n\n")
+            .filter_map(|event| event.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["topic"], "a/1");
+        assert_eq!(events[0]["payload"], "retained");
+        assert_eq!(events[0]["qos"], 1);
+        assert_eq!(events[0]["retain"], true);
+        assert_eq!(events[1]["topic"], "a/2");
+        assert_eq!(events[1]["payload"], "/w==");
+        assert_eq!(events[1]["base64"], true);
+        assert_eq!(events[1]["qos"], 0);
+        assert_eq!(events[1]["topic_filter"], "a/+");
+
+        // The subscriber is removed when the client is closed
+        drop(client);
+        task.await.unwrap().unwrap();
+        let delivered = global
+            .publish(
+                TopicName::try_from("a/3".to_owned()).unwrap(),
+                QoS::Level0,
+                false,
+                Bytes::from_static(b"3"),
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(delivered, 0);
+    }
+}
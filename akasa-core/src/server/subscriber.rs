@@ -0,0 +1,192 @@
+//! The subscribers inside the server for the non-MQTT consumption APIs (gRPC,
+//! WebSocket and SSE streams, `LocalClient`). A subscriber is an internal client with a clean
+//! session, it is removed when dropped.
+
+use std::cmp;
+use std::io;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use mqtt_proto::{Protocol, QoS, TopicFilter, TopicName};
+
+use crate::state::{
+    AddClientReceipt, ClientId, ClientReceiver, ControlMessage, GlobalState, NormalMessage,
+};
+
+/// A message received by the subscriber, the QoS is the lower one of the
+/// published QoS and the subscription QoS.
+#[derive(Debug, Clone)]
+pub struct SubscribedMessage {
+    pub topic_name: TopicName,
+    pub payload: Bytes,
+    pub qos: QoS,
+    /// Only the retained messages sent when subscribed are marked
+    pub retain: bool,
+    pub topic_filter: TopicFilter,
+}
+
+pub(crate) struct Subscriber {
+    global: Arc<GlobalState>,
+    client_id: ClientId,
+    client_identifier: String,
+    receiver: ClientReceiver,
+    subscriptions: Vec<(TopicFilter, QoS)>,
+}
+
+impl Subscriber {
+    /// Add an internal client named `{prefix}-{uuid}` with the subscriptions
+    pub(crate) async fn new(
+        global: &Arc<GlobalState>,
+        prefix: &str,
+        subscriptions: Vec<(TopicFilter, QoS)>,
+    ) -> io::Result<Subscriber> {
+        let client_identifier = format!("{}-{}", prefix, uuid::Uuid::new_v4());
+        let (client_id, receiver) = match global
+            .add_client(&client_identifier, Protocol::V500, None)
+            .await?
+        {
+            AddClientReceipt::New {
+                client_id,
+                receiver,
+            } => (client_id, receiver),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "client identifier in use",
+                ))
+            }
+        };
+        for (topic_filter, qos) in &subscriptions {
+            global.route_table.subscribe(topic_filter, client_id, *qos);
+        }
+        tracing::debug!("subscriber {} started", client_identifier);
+        Ok(Subscriber {
+            global: Arc::clone(global),
+            client_id,
+            client_identifier,
+            receiver,
+            subscriptions,
+        })
+    }
+
+    pub(crate) fn client_identifier(&self) -> &str {
+        &self.client_identifier
+    }
+
+    /// The retained messages matched the subscriptions
+    pub(crate) fn retained(&self) -> Vec<SubscribedMessage> {
+        let mut messages = Vec::new();
+        for (topic_filter, qos) in &self.subscriptions {
+            messages.extend(self.retained_matches(topic_filter, *qos));
+        }
+        messages
+    }
+
+    fn retained_matches(&self, topic_filter: &TopicFilter, qos: QoS) -> Vec<SubscribedMessage> {
+        // [MQTT-3.8.4-4] no retained messages for the shared subscriptions
+        if topic_filter.is_shared() {
+            return Vec::new();
+        }
+        self.global
+            .retain_table
+            .get_matches(topic_filter)
+            .into_iter()
+            .map(|content| SubscribedMessage {
+                topic_name: content.topic_name.clone(),
+                payload: content.payload.clone(),
+                qos: cmp::min(content.qos, qos),
+                retain: true,
+                topic_filter: topic_filter.clone(),
+            })
+            .collect()
+    }
+
+    /// Add or replace a subscription, return the retained messages matched it
+    pub(crate) fn subscribe(
+        &mut self,
+        topic_filter: TopicFilter,
+        qos: QoS,
+    ) -> Vec<SubscribedMessage> {
+        self.global
+            .route_table
+            .subscribe(&topic_filter, self.client_id, qos);
+        let retained = self.retained_matches(&topic_filter, qos);
+        match self
+            .subscriptions
+            .iter_mut()
+            .find(|(filter, _)| *filter == topic_filter)
+        {
+            Some(subscription) => subscription.1 = qos,
+            None => self.subscriptions.push((topic_filter, qos)),
+        }
+        retained
+    }
+
+    /// Remove a subscription, return false if not subscribed
+    pub(crate) fn unsubscribe(&mut self, topic_filter: &TopicFilter) -> bool {
+        let len = self.subscriptions.len();
+        self.subscriptions
+            .retain(|(filter, _)| filter != topic_filter);
+        if self.subscriptions.len() == len {
+            return false;
+        }
+        self.global
+            .route_table
+            .unsubscribe(topic_filter, self.client_id);
+        true
+    }
+
+    /// Receive the next message, return `None` when the subscriber is kicked
+    /// or removed.
+    pub(crate) async fn recv(&self) -> Option<SubscribedMessage> {
+        loop {
+            tokio::select! {
+                message = self.receiver.normal.recv_async() => {
+                    let (_, message) = message.ok()?;
+                    return Some(match message {
+                        NormalMessage::PublishV3 {
+                            topic_name,
+                            payload,
+                            qos,
+                            subscribe_filter,
+                            subscribe_qos,
+                            ..
+                        }
+                        | NormalMessage::PublishV5 {
+                            topic_name,
+                            payload,
+                            qos,
+                            subscribe_filter,
+                            subscribe_qos,
+                            ..
+                        } => SubscribedMessage {
+                            topic_name,
+                            payload,
+                            qos: cmp::min(qos, subscribe_qos),
+                            retain: false,
+                            topic_filter: subscribe_filter,
+                        },
+                    });
+                }
+                control = self.receiver.control.recv_async() => match control {
+                    Ok(ControlMessage::Kick { reason }) => {
+                        tracing::info!("subscriber {} kicked: {}", self.client_identifier, reason);
+                        return None;
+                    }
+                    Ok(_) => {}
+                    Err(_) => return None,
+                },
+            }
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.global.remove_client(
+            self.client_id,
+            self.subscriptions.iter().map(|(filter, _)| filter),
+        );
+        tracing::debug!("subscriber {} stopped", self.client_identifier);
+    }
+}
@@ -0,0 +1,453 @@
+//! The Sparkplug B awareness (see `Config.sparkplug`): the online state of the
+//! edge nodes and the devices is tracked by the birth and death certificates,
+//! published to the status namespace, and the sequence numbers of the edge
+//! nodes are checked.
+//!
+//! The topics are `spBv1.0/{group_id}/{message_type}/{edge_node_id}[/{device_id}]`,
+//! the payloads are protobuf encoded.
+
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use hashbrown::HashMap;
+use mqtt_proto::{QoS, TopicName};
+use parking_lot::Mutex;
+
+use crate::metrics::Metrics;
+use crate::protobuf::{decode_fields, put_bytes_field, put_varint_field, ProtoValue};
+use crate::state::GlobalState;
+
+const NAMESPACE: &str = "spBv1.0";
+const BD_SEQ_METRIC: &str = "bdSeq";
+const REBIRTH_METRIC: &str = "Node Control/Rebirth";
+// The Sparkplug data type of the boolean metrics
+const BOOLEAN_TYPE: u64 = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageType {
+    NBirth,
+    NDeath,
+    DBirth,
+    DDeath,
+    NData,
+    DData,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct SparkplugTopic<'a> {
+    group_id: &'a str,
+    message_type: MessageType,
+    edge_node_id: &'a str,
+    device_id: Option<&'a str>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Payload {
+    seq: Option<u64>,
+    // The `bdSeq` metric of the NBIRTH and the NDEATH
+    bd_seq: Option<u64>,
+}
+
+#[derive(Default)]
+struct NodeState {
+    online: bool,
+    bd_seq: Option<u64>,
+    // The sequence number of the last message
+    seq: Option<u64>,
+    // Only one rebirth is requested until the next NBIRTH
+    rebirth_requested: bool,
+    devices: BTreeMap<String, bool>,
+    // Unix timestamp in milliseconds of the last birth or death
+    updated_at: u64,
+}
+
+/// The edge nodes by group id and edge node id
+#[derive(Default)]
+pub(crate) struct SparkplugNodes {
+    nodes: Mutex<HashMap<(String, String), NodeState>>,
+}
+
+impl SparkplugNodes {
+    /// The state of the edge nodes and their devices, for the admin API
+    pub(crate) fn report(&self) -> Vec<serde_json::Value> {
+        let nodes = self.nodes.lock();
+        let mut keys: Vec<_> = nodes.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|key| {
+                let node = &nodes[key];
+                serde_json::json!({
+                    "group_id": key.0,
+                    "edge_node_id": key.1,
+                    "online": node.online,
+                    "bd_seq": node.bd_seq,
+                    "devices": node.devices,
+                    "updated_at": node.updated_at,
+                })
+            })
+            .collect()
+    }
+}
+
+// A retained status message published to `Config.sparkplug.status_prefix`
+struct Status {
+    // {group_id}/{edge_node_id}[/{device_id}]
+    path: String,
+    payload: serde_json::Value,
+}
+
+/// Track a Sparkplug B message, return true if it's dropped since out of
+/// sequence (see `Config.sparkplug.drop_out_of_sequence`).
+pub(crate) fn check(global: &GlobalState, topic_name: &str, payload: &[u8]) -> bool {
+    let config = global.config();
+    let sparkplug = &config.sparkplug;
+    if !sparkplug.enable {
+        return false;
+    }
+    let topic = match parse_topic(topic_name) {
+        Some(topic) => topic,
+        None => return false,
+    };
+    let payload = match decode_payload(payload) {
+        Some(payload) => payload,
+        None => {
+            tracing::warn!("invalid sparkplug payload: {}", topic_name);
+            return false;
+        }
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0);
+
+    let mut statuses = Vec::new();
+    let mut rebirth = false;
+    let mut dropped = false;
+    {
+        let mut nodes = global.sparkplug_nodes.nodes.lock();
+        let node = nodes
+            .entry((topic.group_id.to_owned(), topic.edge_node_id.to_owned()))
+            .or_default();
+        let in_sequence = match topic.message_type {
+            MessageType::NBirth => payload.seq == Some(0),
+            // The death certificate has no sequence number
+            MessageType::NDeath => true,
+            _ => match (node.online, node.seq, payload.seq) {
+                (true, Some(last), Some(seq)) => seq == (last + 1) % 256,
+                _ => false,
+            },
+        };
+        if !in_sequence {
+            tracing::warn!(
+                "sparkplug message out of sequence: {}, seq: {:?}",
+                topic_name,
+                payload.seq
+            );
+            Metrics::incr(&global.metrics.sparkplug_sequence_errors);
+            rebirth = sparkplug.request_rebirth && !node.rebirth_requested;
+            node.rebirth_requested |= rebirth;
+            dropped = sparkplug.drop_out_of_sequence;
+        }
+        if !dropped {
+            let node_path = format!("{}/{}", topic.group_id, topic.edge_node_id);
+            match topic.message_type {
+                MessageType::NBirth => {
+                    node.online = true;
+                    node.bd_seq = payload.bd_seq;
+                    node.seq = payload.seq;
+                    node.rebirth_requested = false;
+                    node.updated_at = now;
+                    // The devices must be born again after the node is born
+                    set_devices_offline(node, &node_path, now, &mut statuses);
+                    statuses.push(node_status(node, node_path));
+                }
+                // The death certificate of an old session (the will published
+                // after the node reconnected) is ignored
+                MessageType::NDeath
+                    if node.online
+                        && (payload.bd_seq.is_none()
+                            || node.bd_seq.is_none()
+                            || payload.bd_seq == node.bd_seq) =>
+                {
+                    node.online = false;
+                    node.seq = None;
+                    node.updated_at = now;
+                    set_devices_offline(node, &node_path, now, &mut statuses);
+                    statuses.push(node_status(node, node_path));
+                }
+                MessageType::NDeath => {}
+                MessageType::DBirth | MessageType::DDeath => {
+                    node.seq = payload.seq;
+                    let device_id = topic.device_id.unwrap_or_default();
+                    let online = topic.message_type == MessageType::DBirth;
+                    node.devices.insert(device_id.to_owned(), online);
+                    statuses.push(Status {
+                        path: format!("{}/{}", node_path, device_id),
+                        payload: serde_json::json!({ "online": online, "time": now }),
+                    });
+                }
+                MessageType::NData | MessageType::DData => node.seq = payload.seq,
+            }
+        }
+    }
+
+    if let Some(prefix) = sparkplug.status_prefix.as_ref() {
+        for status in statuses {
+            publish(
+                global,
+                format!("{}/{}", prefix, status.path),
+                QoS::Level1,
+                true,
+                status.payload.to_string().into(),
+            );
+        }
+    }
+    if rebirth {
+        let topic = format!(
+            "{}/{}/NCMD/{}",
+            NAMESPACE, topic.group_id, topic.edge_node_id
+        );
+        publish(global, topic, QoS::Level0, false, encode_rebirth(now));
+    }
+    dropped
+}
+
+fn set_devices_offline(
+    node: &mut NodeState,
+    node_path: &str,
+    now: u64,
+    statuses: &mut Vec<Status>,
+) {
+    for (device_id, online) in node.devices.iter_mut().filter(|(_, online)| **online) {
+        *online = false;
+        statuses.push(Status {
+            path: format!("{}/{}", node_path, device_id),
+            payload: serde_json::json!({ "online": false, "time": now }),
+        });
+    }
+}
+
+fn node_status(node: &NodeState, path: String) -> Status {
+    Status {
+        path,
+        payload: serde_json::json!({
+            "online": node.online,
+            "bd_seq": node.bd_seq,
+            "time": node.updated_at,
+        }),
+    }
+}
+
+fn publish(global: &GlobalState, topic: String, qos: QoS, retain: bool, payload: Bytes) {
+    let topic_name = match TopicName::try_from(topic) {
+        Ok(topic_name) => topic_name,
+        Err(err) => {
+            tracing::warn!("invalid sparkplug topic: {:?}", err);
+            return;
+        }
+    };
+    if let Err(err) = global.publish(topic_name, qos, retain, payload, Default::default()) {
+        tracing::warn!("publish sparkplug message error: {}", err);
+    }
+}
+
+fn parse_topic(topic_name: &str) -> Option<SparkplugTopic<'_>> {
+    let mut levels = topic_name.split('/');
+    if levels.next()? != NAMESPACE {
+        return None;
+    }
+    let group_id = levels.next()?;
+    let message_type = match levels.next()? {
+        "NBIRTH" => MessageType::NBirth,
+        "NDEATH" => MessageType::NDeath,
+        "DBIRTH" => MessageType::DBirth,
+        "DDEATH" => MessageType::DDeath,
+        "NDATA" => MessageType::NData,
+        "DDATA" => MessageType::DData,
+        // The commands and the host application states are not tracked
+        _ => return None,
+    };
+    let edge_node_id = levels.next()?;
+    let device_id = levels.next();
+    let is_device = matches!(
+        message_type,
+        MessageType::DBirth | MessageType::DDeath | MessageType::DData
+    );
+    if levels.next().is_some() || device_id.is_some() != is_device {
+        return None;
+    }
+    Some(SparkplugTopic {
+        group_id,
+        message_type,
+        edge_node_id,
+        device_id,
+    })
+}
+
+fn decode_payload(buf: &[u8]) -> Option<Payload> {
+    let mut payload = Payload::default();
+    for (field, value) in decode_fields(buf)? {
+        match (field, value) {
+            (2, ProtoValue::Bytes(metric)) => {
+                let mut name = None;
+                let mut long_value = None;
+                for (field, value) in decode_fields(metric)? {
+                    match (field, value) {
+                        (1, ProtoValue::Bytes(value)) => name = Some(value),
+                        // The int_value or the long_value
+                        (10 | 11, ProtoValue::Varint(value)) => long_value = Some(value),
+                        _ => {}
+                    }
+                }
+                if name == Some(BD_SEQ_METRIC.as_bytes()) {
+                    payload.bd_seq = long_value;
+                }
+            }
+            (3, ProtoValue::Varint(seq)) => payload.seq = Some(seq),
+            _ => {}
+        }
+    }
+    Some(payload)
+}
+
+// The NCMD payload of the `Node Control/Rebirth` metric
+fn encode_rebirth(timestamp: u64) -> Bytes {
+    let mut metric = BytesMut::new();
+    put_bytes_field(&mut metric, 1, REBIRTH_METRIC.as_bytes());
+    put_varint_field(&mut metric, 3, timestamp);
+    put_varint_field(&mut metric, 4, BOOLEAN_TYPE);
+    put_varint_field(&mut metric, 14, 1);
+    let mut payload = BytesMut::new();
+    put_varint_field(&mut payload, 1, timestamp);
+    put_bytes_field(&mut payload, 2, &metric);
+    payload.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn encode_payload(seq: Option<u64>, bd_seq: Option<u64>) -> Bytes {
+        let mut payload = BytesMut::new();
+        put_varint_field(&mut payload, 1, 1_700_000_000_000);
+        if let Some(bd_seq) = bd_seq {
+            let mut metric = BytesMut::new();
+            put_bytes_field(&mut metric, 1, BD_SEQ_METRIC.as_bytes());
+            // Int64
+            put_varint_field(&mut metric, 4, 4);
+            put_varint_field(&mut metric, 11, bd_seq);
+            put_bytes_field(&mut payload, 2, &metric);
+        }
+        if let Some(seq) = seq {
+            // The seq 0 is encoded explicitly by the edge nodes
+            payload.extend_from_slice(&[0x18, seq as u8]);
+        }
+        payload.freeze()
+    }
+
+    fn retained_payload(global: &GlobalState, topic_name: &str) -> Option<serde_json::Value> {
+        global
+            .retain_table
+            .get_matches(topic_name)
+            .first()
+            .map(|content| serde_json::from_slice(&content.payload).unwrap())
+    }
+
+    #[test]
+    fn test_parse_topic() {
+        assert_eq!(
+            parse_topic("spBv1.0/g1/DDATA/n1/d1"),
+            Some(SparkplugTopic {
+                group_id: "g1",
+                message_type: MessageType::DData,
+                edge_node_id: "n1",
+                device_id: Some("d1"),
+            })
+        );
+        assert_eq!(
+            parse_topic("spBv1.0/g1/NBIRTH/n1").map(|topic| topic.message_type),
+            Some(MessageType::NBirth)
+        );
+        for topic_name in [
+            "spBv1.0/g1/NCMD/n1",
+            "spBv1.0/STATE/host",
+            "spBv1.0/g1/NDATA/n1/d1",
+            "spBv1.0/g1/DDATA/n1",
+            "spAv1.0/g1/NDATA/n1",
+        ] {
+            assert_eq!(parse_topic(topic_name), None, "{topic_name}");
+        }
+    }
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(
+            decode_payload(&encode_payload(Some(0), Some(3))),
+            Some(Payload {
+                seq: Some(0),
+                bd_seq: Some(3),
+            })
+        );
+        assert_eq!(decode_payload(&[0x18]), None);
+
+        let fields = decode_fields(&encode_rebirth(1)).unwrap();
+        assert_eq!(fields[0], (1, ProtoValue::Varint(1)));
+        let metric = match fields[1] {
+            (2, ProtoValue::Bytes(metric)) => decode_fields(metric).unwrap(),
+            _ => panic!("metric expected"),
+        };
+        assert_eq!(metric[0], (1, ProtoValue::Bytes(REBIRTH_METRIC.as_bytes())));
+        assert_eq!(metric[3], (14, ProtoValue::Varint(1)));
+    }
+
+    #[test]
+    fn test_check() {
+        let mut config = Config::new_allow_anonymous();
+        config.sparkplug.enable = true;
+        config.sparkplug.drop_out_of_sequence = true;
+        config.sparkplug.request_rebirth = true;
+        let global = GlobalState::new(config);
+        let check_message = |topic_name: &str, seq: Option<u64>, bd_seq: Option<u64>| {
+            check(&global, topic_name, &encode_payload(seq, bd_seq))
+        };
+
+        assert!(!check_message("spBv1.0/g1/NBIRTH/n1", Some(0), Some(1)));
+        assert!(!check_message("spBv1.0/g1/DBIRTH/n1/d1", Some(1), None));
+        assert!(!check_message("spBv1.0/g1/DDATA/n1/d1", Some(2), None));
+        let status = retained_payload(&global, "$SYS/sparkplug/g1/n1").unwrap();
+        assert_eq!(status["online"], true);
+        assert_eq!(status["bd_seq"], 1);
+        let status = retained_payload(&global, "$SYS/sparkplug/g1/n1/d1").unwrap();
+        assert_eq!(status["online"], true);
+
+        // Out of sequence
+        assert!(check_message("spBv1.0/g1/NDATA/n1", Some(4), None));
+        assert!(check_message("spBv1.0/g1/NDATA/n2", Some(0), None));
+        assert_eq!(
+            global
+                .metrics
+                .sparkplug_sequence_errors
+                .load(std::sync::atomic::Ordering::Relaxed),
+            2
+        );
+        assert!(!check_message("spBv1.0/g1/NDATA/n1", Some(3), None));
+
+        // The stale death certificate is ignored
+        assert!(!check_message("spBv1.0/g1/NDEATH/n1", None, Some(2)));
+        let status = retained_payload(&global, "$SYS/sparkplug/g1/n1").unwrap();
+        assert_eq!(status["online"], true);
+        assert!(!check_message("spBv1.0/g1/NDEATH/n1", None, Some(1)));
+        let status = retained_payload(&global, "$SYS/sparkplug/g1/n1").unwrap();
+        assert_eq!(status["online"], false);
+        let status = retained_payload(&global, "$SYS/sparkplug/g1/n1/d1").unwrap();
+        assert_eq!(status["online"], false);
+
+        let report = global.sparkplug_nodes.report();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0]["edge_node_id"], "n1");
+        assert_eq!(report[0]["online"], false);
+        assert_eq!(report[0]["devices"], serde_json::json!({"d1": false}));
+    }
+}
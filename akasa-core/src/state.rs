@@ -1,20 +1,60 @@
 use std::fmt;
-
+use std::fs;
 use std::io;
+use std::net::IpAddr;
 use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 
-use std::sync::atomic::{AtomicU64, Ordering};
-
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use dashmap::DashMap;
 use flume::{bounded, Receiver, Sender};
-use mqtt_proto::{v5::PublishProperties, Protocol, QoS, TopicFilter, TopicName};
-use parking_lot::Mutex;
+use futures_lite::Stream;
+use mqtt_proto::{
+    v5::{Packet, Publish, PublishProperties},
+    Encodable, Protocol, QoS, QosPid, TopicFilter, TopicName, SHARED_PREFIX,
+};
+use parking_lot::{Mutex, RwLock};
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+#[cfg(feature = "amqp")]
+use crate::amqp::AmqpQueue;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::ban::BanList;
+use crate::config::{Config, ConfigChanges, Listeners, SharedSubscriptionMode, TakeoverPolicy};
+use crate::delayed::DelayedQueue;
+use crate::events::{ClientEvent, EventStreams, WebhookQueue};
+use crate::fanout::FanOutPool;
+use crate::flapping::FlappingDetector;
+use crate::health::Health;
+#[cfg(feature = "kafka")]
+use crate::kafka::KafkaQueue;
+use crate::limiter::{ConnectLimiter, InboundLimiter};
+use crate::memory::MemoryUsage;
+use crate::metrics::Metrics;
+use crate::protocols::mqtt::{
+    self, load_passwords, PacketTracer, RetainContent, RetainTable, RouteTable, SessionInfo,
+    SharedClients, SharedEncoded, SubscriptionInfo,
+};
+use crate::quota::QuotaTable;
+use crate::rule::RuleEngine;
+use crate::schema::SchemaRegistry;
+use crate::sparkplug::SparkplugNodes;
+use crate::stats::{ClientStats, TopicStats};
+use crate::timer::Timers;
+#[cfg(feature = "tsdb")]
+use crate::tsdb::TsdbQueue;
+#[cfg(feature = "webhook")]
+use crate::webhook::WebhookForwardQueue;
 
-use crate::config::Config;
-use crate::protocols::mqtt::{self, RetainTable, RouteTable};
+const INSPECT_TIMEOUT_SECS: u64 = 5;
+
+/// The new listeners sent to the running server (see `update_listeners`)
+pub(crate) type ListenersUpdate = (Listeners, oneshot::Sender<io::Result<()>>);
 
 pub struct GlobalState {
     // The next client internal id
@@ -26,10 +66,13 @@ pub struct GlobalState {
     client_id_map: DashMap<ClientId, (String, bool)>,
     // MQTT client identifier => client internal id
     client_identifier_map: DashMap<String, ClientId>,
+    // client internal id => IP address of the online client (see `Config.takeover_policy`)
+    client_peers: DashMap<ClientId, IpAddr>,
     // All clients (online/offline clients)
     clients: DashMap<ClientId, ClientSender>,
 
-    pub config: Config,
+    // The config can be replaced at runtime (see `reload_config`)
+    config: RwLock<Arc<Config>>,
     pub auth_passwords: DashMap<String, AuthPassword>,
 
     /// MQTT route table
@@ -37,6 +80,85 @@ pub struct GlobalState {
 
     /// MQTT retain table
     pub retain_table: RetainTable,
+
+    pub metrics: Metrics,
+
+    // The memory usage exceeded `qos0_shedding.max_memory`
+    overloaded: AtomicBool,
+
+    /// The memory accounted to the pending packets and broadcast queues (see
+    /// `Config.memory_limit`)
+    pub memory: MemoryUsage,
+
+    /// Traffic counters by topic prefixes
+    pub topic_stats: TopicStats,
+
+    /// Traffic counters by clients (for the top talkers report)
+    pub client_stats: ClientStats,
+
+    /// Per-client packet trace capture
+    pub packet_tracer: PacketTracer,
+
+    /// Listener status and executor liveness
+    pub health: Health,
+
+    // The audit log file (see `audit`)
+    audit_log: AuditLog,
+
+    // The events waiting to be posted to the webhook (see `emit_event`)
+    pub(crate) webhook_queue: WebhookQueue,
+    // The receivers of `events`
+    event_streams: EventStreams,
+    // Any message forwarding configured (see `Config::has_forwarders`)
+    has_forwarders: AtomicBool,
+    // The messages waiting to be sent to Kafka (see `Config.kafka`)
+    #[cfg(feature = "kafka")]
+    pub(crate) kafka_queue: KafkaQueue,
+    // The messages waiting to be sent to the AMQP broker (see `Config.amqp`)
+    #[cfg(feature = "amqp")]
+    pub(crate) amqp_queue: AmqpQueue,
+    // The messages waiting to be posted (see `Config.webhook_forward`)
+    #[cfg(feature = "webhook")]
+    pub(crate) webhook_forward_queue: WebhookForwardQueue,
+    // The points waiting to be written (see `Config.tsdb`)
+    #[cfg(feature = "tsdb")]
+    pub(crate) tsdb_queue: TsdbQueue,
+    // The compiled rules (see `Config.rules`)
+    pub(crate) rule_engine: RuleEngine,
+    // The compiled payload schemas (see `Config.payload_schemas`)
+    pub(crate) payload_schemas: SchemaRegistry,
+    // The Sparkplug B edge nodes (see `Config.sparkplug`)
+    pub(crate) sparkplug_nodes: SparkplugNodes,
+
+    // The scheduled `$delayed/{seconds}/{topic}` messages
+    pub(crate) delayed_queue: DelayedQueue,
+
+    // The session timers (session expiry, will delay, keep alive)
+    pub(crate) timers: Timers,
+
+    // The workers enqueue the messages matched too many receivers
+    pub(crate) fan_out: FanOutPool,
+
+    // The server wide inbound publish rate limiter
+    pub(crate) inbound_limiter: InboundLimiter,
+
+    // The new connections rate limiter
+    pub(crate) connect_limiter: ConnectLimiter,
+
+    // The usage of `Config.quotas`
+    pub(crate) quotas: QuotaTable,
+
+    // The banned clients (see `Config.ban_file`)
+    pub(crate) bans: BanList,
+
+    // The connects of the clients (see `Config.flapping`)
+    pub(crate) flapping: FlappingDetector,
+
+    // Set when the server started (see `update_listeners`)
+    pub(crate) listeners_updates: Mutex<Option<mpsc::UnboundedSender<ListenersUpdate>>>,
+
+    // Called after a config is reloaded (see `on_config_reloaded`)
+    config_reloaded: OnceLock<fn(&Config)>,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -68,27 +190,165 @@ pub struct ClientReceiver {
 
 impl GlobalState {
     pub fn new(config: Config) -> GlobalState {
+        let rule_engine = RuleEngine::new(&config.rules);
+        let payload_schemas = SchemaRegistry::new(&config.payload_schemas);
+        let has_forwarders = AtomicBool::new(config.has_forwarders());
         GlobalState {
             // FIXME: load from db (rosksdb or sqlite3)
             next_client_id: Mutex::new(ClientId(0)),
             online_clients: AtomicU64::new(0),
             client_id_map: DashMap::new(),
             client_identifier_map: DashMap::new(),
+            client_peers: DashMap::new(),
             clients: DashMap::new(),
 
-            config,
+            config: RwLock::new(Arc::new(config)),
             auth_passwords: DashMap::new(),
             route_table: RouteTable::default(),
             retain_table: RetainTable::default(),
+            metrics: Metrics::default(),
+            overloaded: AtomicBool::new(false),
+            memory: MemoryUsage::default(),
+            topic_stats: TopicStats::default(),
+            client_stats: ClientStats::default(),
+            packet_tracer: PacketTracer::default(),
+            health: Health::default(),
+            audit_log: AuditLog::default(),
+            webhook_queue: WebhookQueue::default(),
+            event_streams: EventStreams::default(),
+            has_forwarders,
+            #[cfg(feature = "kafka")]
+            kafka_queue: KafkaQueue::default(),
+            #[cfg(feature = "amqp")]
+            amqp_queue: AmqpQueue::default(),
+            #[cfg(feature = "webhook")]
+            webhook_forward_queue: WebhookForwardQueue::default(),
+            #[cfg(feature = "tsdb")]
+            tsdb_queue: TsdbQueue::default(),
+            rule_engine,
+            payload_schemas,
+            sparkplug_nodes: SparkplugNodes::default(),
+            delayed_queue: DelayedQueue::default(),
+            timers: Timers::default(),
+            fan_out: FanOutPool::default(),
+            inbound_limiter: InboundLimiter::default(),
+            connect_limiter: ConnectLimiter::default(),
+            quotas: QuotaTable::default(),
+            bans: BanList::default(),
+            flapping: FlappingDetector::default(),
+            listeners_updates: Mutex::new(None),
+            config_reloaded: OnceLock::new(),
         }
     }
 
+    /// The current config
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read())
+    }
+
+    /// Replace the config at runtime.
+    ///
+    /// The new config is validated and the password file (if authentication
+    /// enabled) is loaded before anything is changed, so a failed reload will
+    /// keep the old config untouched.
+    pub fn reload_config(&self, config: Config) -> io::Result<ConfigChanges> {
+        if !config.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid config",
+            ));
+        }
+        let auth_passwords = if config.auth.enable {
+            let path = config.auth.password_file.as_ref().expect("password file");
+            Some(load_passwords(fs::File::open(path)?)?)
+        } else {
+            None
+        };
+        self.rule_engine.update(&config.rules);
+        self.payload_schemas.update(&config.payload_schemas);
+        self.has_forwarders
+            .store(config.has_forwarders(), Ordering::Relaxed);
+        let config = Arc::new(config);
+        let changes = {
+            let mut current = self.config.write();
+            let changes = current.changes(&config);
+            *current = Arc::clone(&config);
+            changes
+        };
+        if let Some(auth_passwords) = auth_passwords {
+            // Update in place, so a user exists in both files is never missing.
+            self.auth_passwords
+                .retain(|username, _| auth_passwords.contains_key(username));
+            for (username, password) in auth_passwords {
+                self.auth_passwords.insert(username, password);
+            }
+        }
+        if let Some(callback) = self.config_reloaded.get() {
+            callback(&config);
+        }
+        Ok(changes)
+    }
+
+    /// Set the callback called after a config is reloaded successfully, for
+    /// applying the settings outside the broker (like the log level). Only
+    /// the first callback is kept.
+    pub fn on_config_reloaded(&self, callback: fn(&Config)) {
+        let _ = self.config_reloaded.set(callback);
+    }
+
+    /// Start, stop or restart the listeners of the running server, the
+    /// established connections are not affected. The listeners of current
+    /// config are replaced, until the config file is reloaded.
+    pub async fn update_listeners(&self, listeners: Listeners) -> io::Result<()> {
+        let mut config = Config::clone(&self.config());
+        config.listeners = listeners.clone();
+        if !config.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid listeners",
+            ));
+        }
+        let not_running = || io::Error::new(io::ErrorKind::NotConnected, "server not running");
+        let sender = match self.listeners_updates.lock().clone() {
+            Some(sender) => sender,
+            None => return Err(not_running()),
+        };
+        let (reply, result) = oneshot::channel();
+        sender.send((listeners, reply)).map_err(|_| not_running())?;
+        result.await.map_err(|_| not_running())?
+    }
+
+    // Called by the server after the listeners are updated
+    pub(crate) fn set_listeners(&self, listeners: Listeners) {
+        let mut current = self.config.write();
+        let mut config = Config::clone(&current);
+        config.listeners = listeners;
+        *current = Arc::new(config);
+    }
+
     pub fn online_clients_count(&self) -> u64 {
         self.online_clients.load(Ordering::Acquire)
     }
     // pub fn offline_clients_count(&self) -> usize {
     //     self.clients.len() - *self.online_clients.lock()
     // }
+    /// The QoS 0 messages to all the clients are dropped when overloaded
+    /// (see `Config.qos0_shedding`)
+    pub fn overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    /// The payload bytes of the pending packets, the broadcast queues and the
+    /// retained messages
+    pub fn accounted_memory(&self) -> usize {
+        self.memory.pending() + self.memory.broadcast() + self.retain_table.bytes()
+    }
+
+    /// Update the overloaded state, return the previous state
+    pub(crate) fn set_overloaded(&self, overloaded: bool) -> bool {
+        self.overloaded.swap(overloaded, Ordering::Relaxed)
+    }
+
     pub fn clients_count(&self) -> usize {
         self.clients.len()
     }
@@ -101,6 +361,7 @@ impl GlobalState {
     ) {
         // keep client operation atomic
         let _guard = self.next_client_id.lock();
+        self.client_peers.remove(&client_id);
         if let Some((_, (client_identifier, online))) = self.client_id_map.remove(&client_id) {
             self.client_identifier_map.remove(&client_identifier);
             if online {
@@ -117,11 +378,376 @@ impl GlobalState {
     pub fn offline_client(&self, client_id: ClientId) {
         let _guard = self.next_client_id.lock();
         assert_ne!(self.online_clients.fetch_sub(1, Ordering::AcqRel), 0);
+        self.client_peers.remove(&client_id);
         if let Some(mut pair) = self.client_id_map.get_mut(&client_id) {
             pair.value_mut().1 = false;
         }
     }
 
+    /// Publish a message from the server itself (`$SYS` topics, admin API),
+    /// return how many receivers the message is delivered to.
+    ///
+    /// This never blocks: the message is dropped for a receiver if its
+    /// channel is full.
+    pub fn publish(
+        &self,
+        topic_name: TopicName,
+        qos: QoS,
+        retain: bool,
+        payload: Bytes,
+        properties: PublishProperties,
+    ) -> io::Result<usize> {
+        let received_at = Instant::now();
+        let encode_len = {
+            let qos_pid = match qos {
+                QoS::Level0 => QosPid::Level0,
+                QoS::Level1 => QosPid::Level1(Default::default()),
+                QoS::Level2 => QosPid::Level2(Default::default()),
+            };
+            let publish = Publish {
+                dup: false,
+                retain,
+                qos_pid,
+                topic_name: topic_name.clone(),
+                payload: payload.clone(),
+                properties: properties.clone(),
+            };
+            Packet::Publish(publish)
+                .encode_len()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "message too large"))?
+        };
+        if retain {
+            if payload.is_empty() {
+                self.retain_table.remove(&topic_name);
+            } else {
+                mqtt::store_retain(
+                    self,
+                    RetainContent::new(
+                        Arc::new(String::new()),
+                        qos,
+                        topic_name.clone(),
+                        payload.clone(),
+                        Some(properties.clone()),
+                        encode_len,
+                    ),
+                );
+            }
+        }
+
+        // Messages published by the server have no publisher
+        let receivers = self.receivers(&topic_name, "");
+        let mut delivered = 0;
+        let properties = Arc::new(properties);
+        let encoded_v3 = SharedEncoded::default();
+        for (client_id, subscribe_filter, subscribe_qos) in receivers {
+            let sender = match self.get_client_normal_sender(&client_id) {
+                Some(sender) => sender,
+                None => continue,
+            };
+            let msg = NormalMessage::PublishV5 {
+                retain,
+                qos,
+                topic_name: topic_name.clone(),
+                payload: payload.clone(),
+                subscribe_filter,
+                subscribe_qos,
+                properties: Arc::clone(&properties),
+                encode_len,
+                encoded_v3: encoded_v3.clone(),
+                received_at,
+            };
+            match sender.try_send((ClientId::max_value(), msg)) {
+                Ok(()) => delivered += 1,
+                Err(err) => {
+                    tracing::debug!("drop server message to {}: {}", client_id, err);
+                }
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// The (client id, subscribe filter, subscribe QoS) of the clients
+    /// subscribed the topic, a client is picked from each shared subscription
+    /// group by the publisher's client identifier.
+    pub(crate) fn receivers(
+        &self,
+        topic_name: &TopicName,
+        publisher: &str,
+    ) -> Vec<(ClientId, TopicFilter, QoS)> {
+        let mode = self.config().shared_subscription_mode;
+        let mut receivers = Vec::new();
+        for content in self.route_table.get_matches(topic_name) {
+            let content = content.read();
+            let subscribe_filter = content.topic_filter.as_ref().expect("topic filter");
+            for (client_id, subscribe_qos) in &content.clients {
+                receivers.push((*client_id, subscribe_filter.clone(), *subscribe_qos));
+            }
+            for (group_name, shared_clients) in &content.groups {
+                let (client_id, subscribe_qos) =
+                    self.pick_shared_client(mode, shared_clients, publisher, topic_name);
+                let full_filter = TopicFilter::try_from(format!(
+                    "{SHARED_PREFIX}{group_name}/{subscribe_filter}"
+                ))
+                .expect("full topic filter");
+                receivers.push((client_id, full_filter, subscribe_qos));
+            }
+        }
+        receivers
+    }
+
+    /// Pick a client from the shared subscription group to receive the message
+    pub(crate) fn pick_shared_client(
+        &self,
+        mode: SharedSubscriptionMode,
+        shared_clients: &SharedClients,
+        publisher: &str,
+        topic_name: &TopicName,
+    ) -> (ClientId, QoS) {
+        match mode {
+            SharedSubscriptionMode::Random => shared_clients.get_by_number(thread_rng().gen()),
+            SharedSubscriptionMode::RoundRobin => shared_clients.get_by_round_robin(),
+            SharedSubscriptionMode::HashClientId => shared_clients.get_by_hash(publisher),
+            SharedSubscriptionMode::HashTopicName => shared_clients.get_by_hash(topic_name),
+            SharedSubscriptionMode::LeastPending => {
+                shared_clients.get_by_least_pending(|client_id| {
+                    // The removed client is the last choice
+                    self.clients
+                        .get(&client_id)
+                        .map(|item| item.value().normal.len())
+                        .unwrap_or(usize::MAX)
+                })
+            }
+        }
+    }
+
+    /// The max length of the message channels of all clients
+    pub fn max_queue_depth(&self) -> usize {
+        self.clients
+            .iter()
+            .map(|item| item.value().normal.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The MQTT client identifiers of all sessions with online status
+    pub fn sessions(&self) -> Vec<(String, bool)> {
+        let mut sessions: Vec<_> = self
+            .client_id_map
+            .iter()
+            .map(|item| item.value().clone())
+            .collect();
+        sessions.sort();
+        sessions
+    }
+
+    /// Get the session state of a client (online or offline)
+    pub async fn inspect_session(&self, client_identifier: &str) -> Option<SessionInfo> {
+        let client_id = *self.client_identifier_map.get(client_identifier)?.value();
+        let control_sender = self.get_client_control_sender(&client_id)?;
+        let (sender, receiver) = bounded(1);
+        let inspect = async move {
+            control_sender
+                .send_async(ControlMessage::Inspect { sender })
+                .await
+                .ok()?;
+            receiver.recv_async().await.ok()
+        };
+        match tokio::time::timeout(Duration::from_secs(INSPECT_TIMEOUT_SECS), inspect).await {
+            Ok(info) => info,
+            Err(_) => {
+                tracing::warn!("inspect session {} timeout", client_identifier);
+                None
+            }
+        }
+    }
+
+    /// The MQTT client identifiers of the online clients
+    pub fn online_clients(&self) -> Vec<String> {
+        let mut clients: Vec<_> = self
+            .client_id_map
+            .iter()
+            .filter(|item| item.value().1)
+            .map(|item| item.value().0.clone())
+            .collect();
+        clients.sort();
+        clients
+    }
+
+    /// The internal id and the online status of a client
+    pub fn lookup_client(&self, client_identifier: &str) -> Option<(ClientId, bool)> {
+        let client_id = *self.client_identifier_map.get(client_identifier)?.value();
+        let online = self.client_id_map.get(&client_id)?.value().1;
+        Some((client_id, online))
+    }
+
+    /// The subscriptions of a client (online or offline)
+    pub async fn client_subscriptions(
+        &self,
+        client_identifier: &str,
+    ) -> Option<Vec<SubscriptionInfo>> {
+        let info = self.inspect_session(client_identifier).await?;
+        Some(info.subscriptions)
+    }
+
+    /// The subscriptions matched the topic name, sorted by the topic filter
+    /// and the client identifier.
+    pub fn topic_matches(&self, topic_name: &TopicName) -> Vec<TopicMatch> {
+        let mut matches = Vec::new();
+        for content in self.route_table.get_matches(topic_name) {
+            let content = content.read();
+            let topic_filter = match content.topic_filter.as_ref() {
+                Some(topic_filter) => topic_filter.to_string(),
+                None => continue,
+            };
+            let groups = content.groups.iter().flat_map(|(group, shared_clients)| {
+                shared_clients
+                    .clients()
+                    .iter()
+                    .map(move |(client_id, qos)| (*client_id, *qos, Some(group)))
+            });
+            let clients = content
+                .clients
+                .iter()
+                .map(|(client_id, qos)| (*client_id, *qos, None));
+            for (client_id, qos, shared_group) in clients.chain(groups) {
+                let client_identifier = match self.client_id_map.get(&client_id) {
+                    Some(item) => item.value().0.clone(),
+                    None => continue,
+                };
+                matches.push(TopicMatch {
+                    topic_filter: topic_filter.clone(),
+                    client_identifier,
+                    qos: qos as u8,
+                    shared_group: shared_group.cloned(),
+                });
+            }
+        }
+        matches.sort_by(|a, b| {
+            (&a.topic_filter, &a.client_identifier).cmp(&(&b.topic_filter, &b.client_identifier))
+        });
+        matches
+    }
+
+    /// Disconnect an online client with the Server Reference (v5.x only, v3.x
+    /// clients are just disconnected), return false if the client not found.
+    pub async fn redirect_session(
+        &self,
+        client_identifier: &str,
+        server_reference: Arc<String>,
+        permanent: bool,
+    ) -> bool {
+        let control_sender = match self
+            .client_identifier_map
+            .get(client_identifier)
+            .and_then(|client_id| self.get_client_control_sender(client_id.value()))
+        {
+            Some(control_sender) => control_sender,
+            None => return false,
+        };
+        let msg = ControlMessage::Redirect {
+            server_reference,
+            permanent,
+        };
+        control_sender.send_async(msg).await.is_ok()
+    }
+
+    /// Record an event to the audit log, do nothing if the audit log is not
+    /// enabled. Errors are logged and never stop the caller.
+    pub fn audit(&self, event: AuditEvent) {
+        let config = self.config();
+        let audit_config = match config.audit.as_ref() {
+            Some(audit_config) => audit_config,
+            None => return,
+        };
+        let line = match event.encode() {
+            Ok(line) => line,
+            Err(err) => {
+                tracing::error!("encode audit event error: {}", err);
+                return;
+            }
+        };
+        if let Some(path) = audit_config.file.as_ref() {
+            if let Err(err) = self.audit_log.append(path, line.clone()) {
+                tracing::error!("write audit log to {:?} error: {}", path, err);
+            }
+        }
+        if let Some(topic) = audit_config.topic.as_ref() {
+            // The topic is checked by `Config::is_valid`
+            let topic_name = TopicName::try_from(topic.clone()).expect("audit topic");
+            let mut payload = line;
+            payload.pop();
+            if let Err(err) = self.publish(
+                topic_name,
+                QoS::Level0,
+                false,
+                payload.into(),
+                Default::default(),
+            ) {
+                tracing::warn!("publish audit event error: {}", err);
+            }
+        }
+    }
+
+    /// Wait for the audit events recorded before written to the audit log
+    /// file.
+    pub fn flush_audit_log(&self) {
+        self.audit_log.flush();
+    }
+
+    /// The client events as a stream, for the applications embedding the
+    /// server. All the events are sent regardless of `Config.events`, the
+    /// events are dropped if the stream is not consumed in time.
+    pub fn events(&self) -> impl Stream<Item = ClientEvent> {
+        self.event_streams.add().into_stream()
+    }
+
+    /// Some event streams are not dropped yet, so the `Published` events
+    /// are worth building.
+    pub(crate) fn has_event_streams(&self) -> bool {
+        !self.event_streams.is_empty()
+    }
+
+    /// Some messages may be forwarded, so the forward rules are worth
+    /// matching.
+    pub(crate) fn has_forwarders(&self) -> bool {
+        self.has_forwarders.load(Ordering::Relaxed)
+    }
+
+    /// Publish a client lifecycle event to `$SYS/events/{event}` and/or post
+    /// it to the webhook, do nothing if not enabled. The event is also sent
+    /// to the event streams.
+    pub fn emit_event(&self, event: ClientEvent) {
+        self.event_streams.send(&event);
+        let config = self.config();
+        // Too many `Published` events for the topics and the webhook
+        if !config.events.is_enabled() || matches!(event, ClientEvent::Published { .. }) {
+            return;
+        }
+        let payload = match event.encode() {
+            Ok(payload) => payload,
+            Err(err) => {
+                tracing::error!("encode client event error: {}", err);
+                return;
+            }
+        };
+        if config.events.topic {
+            let topic_name =
+                TopicName::try_from(format!("$SYS/events/{}", event.name())).expect("event topic");
+            if let Err(err) = self.publish(
+                topic_name,
+                QoS::Level0,
+                false,
+                Bytes::from(payload.clone()),
+                Default::default(),
+            ) {
+                tracing::warn!("publish client event error: {}", err);
+            }
+        }
+        if config.events.webhook.is_some() {
+            self.webhook_queue.push(payload);
+        }
+    }
+
     pub fn get_client_normal_sender(
         &self,
         client_id: &ClientId,
@@ -139,26 +765,47 @@ impl GlobalState {
             .map(|pair| pair.value().control.clone())
     }
 
-    // Client connected
+    /// Kick all the online clients out, the offline sessions are kept
+    pub(crate) fn kick_all(&self, reason: &str) {
+        for item in self.clients.iter() {
+            let msg = ControlMessage::Kick {
+                reason: reason.to_owned(),
+            };
+            let _ = item.value().control.try_send(msg);
+        }
+    }
+
+    // Client connected, `peer` is the IP address of the client (not set for
+    // the internal clients).
     // TODO: error handling
     pub async fn add_client(
         &self,
         client_identifier: &str,
         protocol: Protocol,
+        peer: Option<IpAddr>,
     ) -> io::Result<AddClientReceipt> {
         let control_sender = {
             let mut next_client_id = self.next_client_id.lock();
-            self.online_clients.fetch_add(1, Ordering::AcqRel);
             let client_id_opt: Option<ClientId> = self
                 .client_identifier_map
                 .get(client_identifier)
                 .map(|pair| *pair.value());
             if let Some(old_id) = client_id_opt {
+                if self.takeover_rejected(old_id, peer) {
+                    return Ok(AddClientReceipt::Rejected);
+                }
+                self.online_clients.fetch_add(1, Ordering::AcqRel);
                 if let Some(mut pair) = self.client_id_map.get_mut(&old_id) {
                     pair.value_mut().1 = true;
                 }
+                if let Some(peer) = peer {
+                    self.client_peers.insert(old_id, peer);
+                } else {
+                    self.client_peers.remove(&old_id);
+                }
                 self.get_client_control_sender(&old_id).unwrap()
             } else {
+                self.online_clients.fetch_add(1, Ordering::AcqRel);
                 let client_id = *next_client_id;
                 self.client_id_map
                     .insert(client_id, (client_identifier.to_string(), true));
@@ -166,13 +813,17 @@ impl GlobalState {
                     .insert(client_identifier.to_string(), client_id);
                 // FIXME: if some one subscribe topic "#" and never receive the message it will block all sender clients.
                 //   Suggestion: Add QoS0 message to pending queue
-                let (control_sender, control_receiver) = bounded(1);
-                let (normal_sender, normal_receiver) = bounded(8);
+                let channels = self.config().channels.clone();
+                let (control_sender, control_receiver) = bounded(channels.control);
+                let (normal_sender, normal_receiver) = bounded(channels.normal);
                 let sender = ClientSender {
                     normal: normal_sender,
                     control: control_sender,
                 };
                 self.clients.insert(client_id, sender);
+                if let Some(peer) = peer {
+                    self.client_peers.insert(client_id, peer);
+                }
                 next_client_id.0 += 1;
                 return Ok(AddClientReceipt::New {
                     client_id,
@@ -184,45 +835,75 @@ impl GlobalState {
             }
         };
 
-        if protocol < Protocol::V500 {
-            let (sender, receiver) = bounded(1);
-            if let Err(err) = control_sender
-                .send_async(ControlMessage::OnlineV3 { sender })
-                .await
-            {
-                log::warn!("send online control message error: {:?}", err);
-                return Err(io::Error::from(io::ErrorKind::InvalidData));
+        match protocol {
+            #[cfg(feature = "v3")]
+            Protocol::V310 | Protocol::V311 => {
+                let session_state = take_over_session(&control_sender, |sender| {
+                    ControlMessage::OnlineV3 { sender }
+                })
+                .await?;
+                Ok(AddClientReceipt::PresentV3(session_state))
             }
-            let session_state = receiver.recv_async().await.map_err(|err| {
-                log::warn!("receive session state error: {:?}", err);
-                io::Error::from(io::ErrorKind::InvalidData)
-            })?;
-            Ok(AddClientReceipt::PresentV3(session_state))
-        } else {
-            let (sender, receiver) = bounded(1);
-            if let Err(err) = control_sender
-                .send_async(ControlMessage::OnlineV5 { sender })
-                .await
-            {
-                log::warn!("send online control message error: {:?}", err);
-                return Err(io::Error::from(io::ErrorKind::InvalidData));
+            #[cfg(feature = "v5")]
+            Protocol::V500 => {
+                let session_state = take_over_session(&control_sender, |sender| {
+                    ControlMessage::OnlineV5 { sender }
+                })
+                .await?;
+                Ok(AddClientReceipt::PresentV5(session_state))
             }
-            let session_state = receiver.recv_async().await.map_err(|err| {
-                log::warn!("receive session state error: {:?}", err);
-                io::Error::from(io::ErrorKind::InvalidData)
-            })?;
-            Ok(AddClientReceipt::PresentV5(session_state))
+            // The disabled protocol version is rejected before the CONNECT packet handled
+            #[allow(unreachable_patterns)]
+            _ => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    // The new client can't take over the online session (see `Config.takeover_policy`)
+    fn takeover_rejected(&self, old_id: ClientId, peer: Option<IpAddr>) -> bool {
+        let online = self
+            .client_id_map
+            .get(&old_id)
+            .map(|pair| pair.value().1)
+            .unwrap_or(false);
+        if !online {
+            return false;
+        }
+        match self.config().takeover_policy {
+            TakeoverPolicy::KickOld => false,
+            TakeoverPolicy::RejectNew => true,
+            TakeoverPolicy::RejectNewIfSameIp => match (peer, self.client_peers.get(&old_id)) {
+                (Some(peer), Some(old_peer)) => peer == *old_peer.value(),
+                _ => false,
+            },
         }
     }
 }
 
+/// Ask the connection loop of the present session to send back the session state
+async fn take_over_session<S>(
+    control_sender: &Sender<ControlMessage>,
+    online: impl FnOnce(Sender<S>) -> ControlMessage,
+) -> io::Result<S> {
+    let (sender, receiver) = bounded(1);
+    if let Err(err) = control_sender.send_async(online(sender)).await {
+        tracing::warn!("send online control message error: {:?}", err);
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    receiver.recv_async().await.map_err(|err| {
+        tracing::warn!("receive session state error: {:?}", err);
+        io::Error::from(io::ErrorKind::InvalidData)
+    })
+}
+
 #[derive(Debug, Clone)]
 pub enum ControlMessage {
     /// The v3.x client of the session connected, send the keept session to the connection loop
+    #[cfg(feature = "v3")]
     OnlineV3 {
         sender: Sender<mqtt::v3::SessionState>,
     },
     /// The v5.x client of the session connected, send the keept session to the connection loop
+    #[cfg(feature = "v5")]
     OnlineV5 {
         sender: Sender<mqtt::v5::SessionState>,
     },
@@ -231,6 +912,15 @@ pub enum ControlMessage {
     Kick {
         reason: String,
     },
+    /// Get the session state for inspection
+    Inspect {
+        sender: Sender<SessionInfo>,
+    },
+    /// Disconnect the client and redirect it to another server
+    Redirect {
+        server_reference: Arc<String>,
+        permanent: bool,
+    },
     SessionExpired {
         connected_time: Instant,
     },
@@ -250,6 +940,10 @@ pub enum NormalMessage {
         subscribe_filter: TopicFilter,
         subscribe_qos: QoS,
         encode_len: usize,
+        /// The QoS 0 packet encoded for v3.x receivers
+        encoded_v3: SharedEncoded,
+        /// The time the message received by the server
+        received_at: Instant,
     },
     PublishV5 {
         retain: bool,
@@ -259,11 +953,79 @@ pub enum NormalMessage {
         subscribe_filter: TopicFilter,
         // [MQTTv5.0-3.8.4] keyword: downgraded
         subscribe_qos: QoS,
-        properties: PublishProperties,
+        /// Shared by all the receivers
+        properties: Arc<PublishProperties>,
         encode_len: usize,
+        /// The QoS 0 packet encoded for v3.x receivers
+        encoded_v3: SharedEncoded,
+        /// The time the message received by the server
+        received_at: Instant,
     },
 }
 
+impl NormalMessage {
+    pub fn encoded_v3(&self) -> &SharedEncoded {
+        match self {
+            NormalMessage::PublishV3 { encoded_v3, .. } => encoded_v3,
+            NormalMessage::PublishV5 { encoded_v3, .. } => encoded_v3,
+        }
+    }
+
+    pub fn qos(&self) -> QoS {
+        match self {
+            NormalMessage::PublishV3 { qos, .. } => *qos,
+            NormalMessage::PublishV5 { qos, .. } => *qos,
+        }
+    }
+
+    /// The same message to another receiver
+    pub fn for_receiver(&self, subscribe_filter: TopicFilter, subscribe_qos: QoS) -> NormalMessage {
+        let mut msg = self.clone();
+        match &mut msg {
+            NormalMessage::PublishV3 {
+                subscribe_filter: filter,
+                subscribe_qos: qos,
+                ..
+            }
+            | NormalMessage::PublishV5 {
+                subscribe_filter: filter,
+                subscribe_qos: qos,
+                ..
+            } => {
+                *filter = subscribe_filter;
+                *qos = subscribe_qos;
+            }
+        }
+        msg
+    }
+
+    pub fn payload_len(&self) -> usize {
+        match self {
+            NormalMessage::PublishV3 { payload, .. } => payload.len(),
+            NormalMessage::PublishV5 { payload, .. } => payload.len(),
+        }
+    }
+
+    pub fn received_at(&self) -> Instant {
+        match self {
+            NormalMessage::PublishV3 { received_at, .. } => *received_at,
+            NormalMessage::PublishV5 { received_at, .. } => *received_at,
+        }
+    }
+}
+
+/// A subscription matched a topic name (see `GlobalState::topic_matches`)
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct TopicMatch {
+    /// The topic filter without the `$share/{group}/` prefix
+    pub topic_filter: String,
+    pub client_identifier: String,
+    pub qos: u8,
+    /// The group of a shared subscription, only one client of the group
+    /// receives the message
+    pub shared_group: Option<String>,
+}
+
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
 pub struct ClientId(u64);
 
@@ -274,7 +1036,6 @@ impl fmt::Display for ClientId {
 }
 
 impl ClientId {
-    #[cfg(test)]
     pub fn new(value: u64) -> ClientId {
         ClientId(value)
     }
@@ -285,10 +1046,14 @@ impl ClientId {
 }
 
 pub enum AddClientReceipt {
+    #[cfg(feature = "v3")]
     PresentV3(mqtt::v3::SessionState),
+    #[cfg(feature = "v5")]
     PresentV5(mqtt::v5::SessionState),
     New {
         client_id: ClientId,
         receiver: ClientReceiver,
     },
+    /// The session is online and can't be taken over (see `Config.takeover_policy`)
+    Rejected,
 }
@@ -0,0 +1,239 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// Message/byte counters aggregated by topic prefixes (see `Config.topic_stats`)
+#[derive(Default)]
+pub struct TopicStats {
+    // topic prefix => counters
+    counters: DashMap<String, TopicCounters>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TopicTraffic {
+    pub prefix: String,
+    /// Messages published to the matched topics
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    /// Messages delivered to subscribers
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    /// The rates (per second) over the last rolling window
+    pub messages_in_rate: f64,
+    pub bytes_in_rate: f64,
+    pub messages_out_rate: f64,
+    pub bytes_out_rate: f64,
+}
+
+#[derive(Default)]
+struct TopicCounters {
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    window: Mutex<RollingWindow>,
+}
+
+#[derive(Default)]
+struct RollingWindow {
+    // The counters when the window started: [messages_in, bytes_in, messages_out, bytes_out]
+    start: Option<(Instant, [u64; 4])>,
+    rates: [f64; 4],
+}
+
+impl TopicStats {
+    /// Count a published message by the longest matched prefix
+    pub(crate) fn record(
+        &self,
+        prefixes: &[String],
+        topic_name: &str,
+        payload_len: usize,
+        receivers: usize,
+    ) {
+        let prefix = match prefixes
+            .iter()
+            .filter(|prefix| prefix_match(prefix, topic_name))
+            .max_by_key(|prefix| prefix.len())
+        {
+            Some(prefix) => prefix,
+            None => return,
+        };
+        let record = |counters: &TopicCounters| {
+            let payload_len = payload_len as u64;
+            let receivers = receivers as u64;
+            counters.messages_in.fetch_add(1, Ordering::Relaxed);
+            counters.bytes_in.fetch_add(payload_len, Ordering::Relaxed);
+            counters
+                .messages_out
+                .fetch_add(receivers, Ordering::Relaxed);
+            counters
+                .bytes_out
+                .fetch_add(payload_len * receivers, Ordering::Relaxed);
+        };
+        if let Some(counters) = self.counters.get(prefix) {
+            record(counters.value());
+            return;
+        }
+        record(self.counters.entry(prefix.clone()).or_default().value());
+    }
+
+    /// Start a new rolling window and update the rates, the prefixes not in
+    /// `prefixes` (removed by config reload) are dropped.
+    pub(crate) fn roll(&self, prefixes: &[String]) {
+        self.counters.retain(|prefix, _| prefixes.contains(prefix));
+        let now = Instant::now();
+        for item in self.counters.iter() {
+            let values = item.value().values();
+            let mut window = item.value().window.lock();
+            if let Some((started, start_values)) = window.start {
+                let secs = now.duration_since(started).as_secs_f64();
+                if secs > 0.0 {
+                    for (idx, rate) in window.rates.iter_mut().enumerate() {
+                        *rate = values[idx].saturating_sub(start_values[idx]) as f64 / secs;
+                    }
+                }
+            }
+            window.start = Some((now, values));
+        }
+    }
+
+    /// The traffic of all prefixes (only the prefixes have messages published)
+    pub fn report(&self) -> Vec<TopicTraffic> {
+        let mut items: Vec<_> = self
+            .counters
+            .iter()
+            .map(|item| {
+                let [messages_in, bytes_in, messages_out, bytes_out] = item.value().values();
+                let rates = item.value().window.lock().rates;
+                TopicTraffic {
+                    prefix: item.key().clone(),
+                    messages_in,
+                    bytes_in,
+                    messages_out,
+                    bytes_out,
+                    messages_in_rate: rates[0],
+                    bytes_in_rate: rates[1],
+                    messages_out_rate: rates[2],
+                    bytes_out_rate: rates[3],
+                }
+            })
+            .collect();
+        items.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+        items
+    }
+}
+
+// Match the whole topic levels: `a/b` matches `a/b` and `a/b/c` but not
+// `a/bc`, a prefix ends with `/` only matches the sub levels.
+fn prefix_match(prefix: &str, topic_name: &str) -> bool {
+    match topic_name.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+impl TopicCounters {
+    fn values(&self) -> [u64; 4] {
+        [
+            self.messages_in.load(Ordering::Relaxed),
+            self.bytes_in.load(Ordering::Relaxed),
+            self.messages_out.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+/// Messages/bytes published by each client in current interval, for the top
+/// talkers report (see `Config.top_talkers`)
+#[derive(Default)]
+pub struct ClientStats {
+    // MQTT client identifier => counters
+    counters: DashMap<Arc<String>, ClientCounters>,
+    // The report of the last interval
+    top_talkers: Mutex<TopTalkers>,
+}
+
+#[derive(Default)]
+struct ClientCounters {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct TopTalkers {
+    /// Seconds of the interval
+    pub interval: u64,
+    /// The top clients ordered by messages published
+    pub by_messages: Vec<ClientTraffic>,
+    /// The top clients ordered by payload bytes published
+    pub by_bytes: Vec<ClientTraffic>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ClientTraffic {
+    pub client_identifier: String,
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+impl ClientStats {
+    /// Count a message published by the client
+    pub(crate) fn record(&self, client_identifier: &Arc<String>, payload_len: usize) {
+        let record = |counters: &ClientCounters| {
+            counters.messages.fetch_add(1, Ordering::Relaxed);
+            counters
+                .bytes
+                .fetch_add(payload_len as u64, Ordering::Relaxed);
+        };
+        if let Some(counters) = self.counters.get(client_identifier) {
+            record(counters.value());
+            return;
+        }
+        record(
+            self.counters
+                .entry(Arc::clone(client_identifier))
+                .or_default()
+                .value(),
+        );
+    }
+
+    /// Compute the top `count` clients of current interval then start a new
+    /// interval, return the new report.
+    pub(crate) fn roll(&self, count: usize, interval: u64) -> TopTalkers {
+        let mut items = Vec::with_capacity(self.counters.len());
+        self.counters.retain(|client_identifier, counters| {
+            items.push(ClientTraffic {
+                client_identifier: client_identifier.to_string(),
+                messages: counters.messages.load(Ordering::Relaxed),
+                bytes: counters.bytes.load(Ordering::Relaxed),
+            });
+            false
+        });
+        let top = |key: fn(&ClientTraffic) -> u64| {
+            let mut items = items.clone();
+            items.sort_by(|a, b| {
+                key(b)
+                    .cmp(&key(a))
+                    .then_with(|| a.client_identifier.cmp(&b.client_identifier))
+            });
+            items.truncate(count);
+            items
+        };
+        let top_talkers = TopTalkers {
+            interval,
+            by_messages: top(|item| item.messages),
+            by_bytes: top(|item| item.bytes),
+        };
+        *self.top_talkers.lock() = top_talkers.clone();
+        top_talkers
+    }
+
+    /// The top talkers report of the last interval
+    pub fn top_talkers(&self) -> TopTalkers {
+        self.top_talkers.lock().clone()
+    }
+}
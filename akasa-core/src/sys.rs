@@ -0,0 +1,147 @@
+//! Publish the server status to `$SYS/...` topics
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mqtt_proto::{QoS, TopicName};
+
+use crate::alarm::memory_usage;
+use crate::state::GlobalState;
+
+pub(crate) async fn run(global: Arc<GlobalState>) {
+    loop {
+        // Read the interval every time, since the config can be reloaded
+        let interval = global.config().topic_stats.interval;
+        if interval == 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        publish_topic_stats(&global);
+    }
+}
+
+pub(crate) async fn run_top_talkers(global: Arc<GlobalState>) {
+    loop {
+        let config = global.config();
+        if config.top_talkers.count == 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        tokio::time::sleep(Duration::from_secs(config.top_talkers.interval)).await;
+        publish_top_talkers(&global);
+    }
+}
+
+/// Check the memory threshold of the QoS 0 load shedding every second, and
+/// publish the state to `$SYS/qos0_shedding` when changed.
+pub(crate) async fn run_qos0_shedding(global: Arc<GlobalState>) {
+    let mut last_state = None;
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let shedding = global.config().qos0_shedding.clone();
+        let overloaded = match shedding.max_memory {
+            Some(max_memory) => memory_usage()
+                .map(|memory| memory > max_memory)
+                .unwrap_or(false),
+            None => false,
+        };
+        if global.set_overloaded(overloaded) != overloaded {
+            if overloaded {
+                tracing::warn!("memory usage is too high, start dropping QoS 0 messages");
+            } else {
+                tracing::info!("memory usage recovered, stop dropping QoS 0 messages");
+            }
+        }
+        if shedding.max_queue_depth == 0 && shedding.max_memory.is_none() {
+            continue;
+        }
+        let state = serde_json::json!({
+            "overloaded": overloaded,
+            "dropped": global.metrics.qos0_shed.load(Ordering::Relaxed),
+        });
+        if last_state.as_ref() != Some(&state) {
+            publish_qos0_shedding(&global, &state);
+            last_state = Some(state);
+        }
+    }
+}
+
+fn publish_qos0_shedding(global: &GlobalState, state: &serde_json::Value) {
+    let topic_name = TopicName::try_from("$SYS/qos0_shedding".to_owned()).expect("topic name");
+    let payload = match serde_json::to_vec(state) {
+        Ok(payload) => payload,
+        Err(err) => {
+            tracing::error!("encode QoS 0 shedding state error: {}", err);
+            return;
+        }
+    };
+    // Retained, so the subscribers can get the current state
+    if let Err(err) = global.publish(
+        topic_name,
+        QoS::Level0,
+        true,
+        payload.into(),
+        Default::default(),
+    ) {
+        tracing::warn!("publish QoS 0 shedding state error: {}", err);
+    }
+}
+
+/// Compute the top talkers of the last interval and publish it to `$SYS/clients/top`
+pub(crate) fn publish_top_talkers(global: &GlobalState) {
+    let config = global.config();
+    let top_talkers = global
+        .client_stats
+        .roll(config.top_talkers.count, config.top_talkers.interval);
+    let payload = match serde_json::to_vec(&top_talkers) {
+        Ok(payload) => payload,
+        Err(err) => {
+            tracing::error!("encode top talkers error: {}", err);
+            return;
+        }
+    };
+    let topic_name = TopicName::try_from("$SYS/clients/top".to_owned()).expect("topic name");
+    if let Err(err) = global.publish(
+        topic_name,
+        QoS::Level0,
+        false,
+        payload.into(),
+        Default::default(),
+    ) {
+        tracing::warn!("publish top talkers error: {}", err);
+    }
+}
+
+/// Roll the topic traffic window and publish each prefix to `$SYS/topics/{prefix}`
+pub(crate) fn publish_topic_stats(global: &GlobalState) {
+    let config = global.config();
+    global.topic_stats.roll(&config.topic_stats.prefixes);
+    for traffic in global.topic_stats.report() {
+        let prefix = traffic.prefix.trim_end_matches('/');
+        let topic_name = match TopicName::try_from(format!("$SYS/topics/{prefix}")) {
+            Ok(topic_name) => topic_name,
+            Err(err) => {
+                tracing::warn!("invalid topic stats prefix {}: {:?}", prefix, err);
+                continue;
+            }
+        };
+        let payload = match serde_json::to_vec(&traffic) {
+            Ok(payload) => payload,
+            Err(err) => {
+                tracing::error!("encode topic stats error: {}", err);
+                continue;
+            }
+        };
+        if let Err(err) = global.publish(
+            topic_name,
+            QoS::Level0,
+            false,
+            payload.into(),
+            Default::default(),
+        ) {
+            tracing::warn!("publish topic stats error: {}", err);
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! A transport wrapper injects faults into the MQTT packets: drop, duplicate,
+//! reorder, delay, or close the connection abruptly when a packet of the given
+//! type is seen (e.g. in the middle of a QoS 2 flow). The faults are applied
+//! to the complete packets, so it can't be combined with TLS.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+// The packet types (the high 4 bits of the first byte)
+pub const PUBLISH: u8 = 3;
+pub const PUBREL: u8 = 6;
+
+/// The faults of the packets in one direction
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// Only the packets of these types are affected (all types when empty)
+    pub packet_types: Vec<u8>,
+    /// The probability to drop a packet
+    pub drop: f64,
+    /// The probability to deliver a packet twice
+    pub duplicate: f64,
+    /// The probability to deliver a packet after the next one
+    pub reorder: f64,
+    /// The probability to delay a packet (from 0 to `max_delay`)
+    pub delay: f64,
+    pub max_delay: Duration,
+    /// Close the connection when a packet of this type is seen, the packet
+    /// and all the following data are discarded.
+    pub close_on: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChaosOptions {
+    /// The packets received by the server
+    pub incoming: Faults,
+    /// The packets sent by the server
+    pub outgoing: Faults,
+    pub seed: u64,
+}
+
+struct Chunk {
+    data: Vec<u8>,
+    delay: Option<Duration>,
+}
+
+struct Direction {
+    faults: Faults,
+    // The data of the incomplete packet
+    buf: Vec<u8>,
+    // The packet waiting for the next one (reordered)
+    held: Option<Vec<u8>>,
+    chunks: VecDeque<Chunk>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Direction {
+    fn new(faults: Faults) -> Direction {
+        Direction {
+            faults,
+            buf: Vec::new(),
+            held: None,
+            chunks: VecDeque::new(),
+            sleep: None,
+        }
+    }
+
+    /// Split the data into packets and apply the faults, return true if the
+    /// connection should be closed.
+    fn feed(&mut self, data: &[u8], rng: &mut StdRng) -> bool {
+        self.buf.extend_from_slice(data);
+        while let Some(len) = packet_len(&self.buf) {
+            let packet: Vec<u8> = self.buf.drain(..len).collect();
+            let packet_type = packet[0] >> 4;
+            if self.faults.close_on == Some(packet_type) {
+                return true;
+            }
+            let affected = self.faults.packet_types.is_empty()
+                || self.faults.packet_types.contains(&packet_type);
+            if !affected {
+                self.push(packet, None);
+            } else if rng.gen_bool(self.faults.drop) {
+                continue;
+            } else if self.held.is_none() && rng.gen_bool(self.faults.reorder) {
+                self.held = Some(packet);
+                continue;
+            } else {
+                let delay = if rng.gen_bool(self.faults.delay) {
+                    Some(rng.gen_range(Duration::ZERO..=self.faults.max_delay))
+                } else {
+                    None
+                };
+                if rng.gen_bool(self.faults.duplicate) {
+                    self.push(packet.clone(), delay);
+                    self.push(packet, None);
+                } else {
+                    self.push(packet, delay);
+                }
+            }
+            if let Some(held) = self.held.take() {
+                self.push(held, None);
+            }
+        }
+        false
+    }
+
+    fn push(&mut self, data: Vec<u8>, delay: Option<Duration>) {
+        self.chunks.push_back(Chunk { data, delay });
+    }
+
+    // Release the reordered packet when there is no next packet
+    fn release_held(&mut self) {
+        if let Some(held) = self.held.take() {
+            self.push(held, None);
+        }
+    }
+
+    /// Wait the delay of the first chunk
+    fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = self.chunks.front_mut().and_then(|chunk| chunk.delay.take()) {
+            self.sleep = Some(Box::pin(sleep(delay)));
+        }
+        if let Some(sleep) = self.sleep.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+        }
+        Poll::Ready(())
+    }
+}
+
+/// The length of the first packet if it's complete
+fn packet_len(data: &[u8]) -> Option<usize> {
+    let mut remaining_len = 0;
+    for (idx, byte) in data.iter().skip(1).take(4).enumerate() {
+        remaining_len |= ((byte & 0x7f) as usize) << (7 * idx);
+        if byte & 0x80 == 0 {
+            let len = 2 + idx + remaining_len;
+            return (data.len() >= len).then_some(len);
+        }
+    }
+    None
+}
+
+pub struct ChaosConn<T> {
+    inner: T,
+    rng: StdRng,
+    incoming: Direction,
+    outgoing: Direction,
+    read_eof: bool,
+    closed: bool,
+}
+
+impl<T> ChaosConn<T> {
+    pub fn new(inner: T, options: ChaosOptions) -> ChaosConn<T> {
+        ChaosConn {
+            inner,
+            rng: StdRng::seed_from_u64(options.seed),
+            incoming: Direction::new(options.incoming),
+            outgoing: Direction::new(options.outgoing),
+            read_eof: false,
+            closed: false,
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> ChaosConn<T> {
+    /// Write all the outgoing chunks to the inner connection
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.outgoing.poll_delay(cx));
+            let chunk = match self.outgoing.chunks.front_mut() {
+                Some(chunk) => chunk,
+                None => return Poll::Ready(Ok(())),
+            };
+            let amt = ready!(Pin::new(&mut self.inner).poll_write(cx, &chunk.data))?;
+            if amt == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            chunk.data.drain(..amt);
+            if chunk.data.is_empty() {
+                self.outgoing.chunks.pop_front();
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ChaosConn<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.incoming.chunks.is_empty() {
+                ready!(this.incoming.poll_delay(cx));
+                let chunk = this.incoming.chunks.front_mut().expect("chunk");
+                let amt = cmp::min(buf.remaining(), chunk.data.len());
+                buf.put_slice(&chunk.data[..amt]);
+                chunk.data.drain(..amt);
+                if chunk.data.is_empty() {
+                    this.incoming.chunks.pop_front();
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.closed {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            if this.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+            let mut data = [0u8; 4096];
+            let mut data_buf = ReadBuf::new(&mut data);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut data_buf))?;
+            if data_buf.filled().is_empty() {
+                this.read_eof = true;
+                this.incoming.release_held();
+            } else if this.incoming.feed(data_buf.filled(), &mut this.rng) {
+                this.closed = true;
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ChaosConn<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.closed {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        ready!(this.poll_send(cx))?;
+        if this.outgoing.feed(buf, &mut this.rng) {
+            this.closed = true;
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outgoing.release_held();
+        ready!(self.poll_send(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
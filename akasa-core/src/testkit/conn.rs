@@ -1,19 +1,36 @@
 use std::cmp;
+use std::future::Future;
 use std::io::{self, IoSlice};
 use std::mem;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use futures_sink::Sink;
 use mqtt_proto::{v3, v5};
-use rand::{rngs::OsRng, RngCore};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    ssl::{SslAcceptor, SslConnector, SslMethod, SslVerifyMode},
+    x509::{X509Builder, X509NameBuilder},
+};
+use rand::{
+    rngs::{OsRng, StdRng},
+    Rng, RngCore, SeedableRng,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::{
     sync::mpsc::{channel, error::TryRecvError, Receiver, Sender},
     task::JoinHandle,
+    time::{sleep, Sleep},
 };
+use tokio_openssl::SslStream;
 use tokio_util::sync::PollSender;
 
 use crate::config::Config;
@@ -25,8 +42,11 @@ use crate::server::{handle_accept, ConnectionArgs};
 use crate::state::{AuthPassword, GlobalState, HashAlgorithm};
 use crate::{hash_password, SessionV3, SessionV5, MIN_SALT_LEN};
 
+use super::chaos::{ChaosConn, ChaosOptions};
+
 impl GlobalState {
-    pub(crate) fn insert_password(&mut self, username: &str, password: &str, algo: HashAlgorithm) {
+    /// Add a user (for the tests with `allow_anonymous=false`)
+    pub fn insert_password(&mut self, username: &str, password: &str, algo: HashAlgorithm) {
         let mut salt = vec![0u8; MIN_SALT_LEN];
         OsRng.fill_bytes(&mut salt);
         let hashed_password = hash_password(algo, &salt, password.as_bytes());
@@ -41,6 +61,7 @@ impl GlobalState {
     }
 }
 
+/// The client side of a `MockConn`
 pub struct MockConnControl {
     pub chan_in: Sender<Vec<u8>>,
     pub chan_out: Receiver<Vec<u8>>,
@@ -48,24 +69,71 @@ pub struct MockConnControl {
     pub global: Arc<GlobalState>,
 }
 
+/// How the data is delivered to the server
+#[derive(Debug, Clone, Default)]
+pub struct MockConnOptions {
+    /// Deliver the data in random fragments (from 1 to this size bytes)
+    pub max_fragment: Option<usize>,
+    /// The delay before every fragment delivered
+    pub latency: Option<Duration>,
+    /// Reset the connection after this many bytes delivered
+    pub drop_after: Option<usize>,
+    /// Wrap the connection in TLS, the control side is the TLS client
+    pub tls: bool,
+    /// Inject faults into the packets (see `ChaosConn`)
+    pub chaos: Option<ChaosOptions>,
+    /// The seed of the random fragment sizes
+    pub seed: u64,
+}
+
+/// The server side connection, the data is exchanged with `MockConnControl`
+/// by channels
 pub struct MockConn {
     pub bind: SocketAddr,
     pub peer: SocketAddr,
     data_in: Vec<u8>,
     chan_in: Receiver<Vec<u8>>,
     chan_out: PollSender<Vec<u8>>,
+    options: MockConnOptions,
+    rng: StdRng,
+    delay: Option<Pin<Box<Sleep>>>,
+    // The bytes delivered to the server
+    delivered: usize,
+    tls_acceptor: Option<SslAcceptor>,
+    chaos: Option<ChaosOptions>,
 }
 
 impl MockConn {
     pub fn new_with_global(port: u16, global: Arc<GlobalState>) -> (MockConn, MockConnControl) {
+        Self::new_with_options(port, global, MockConnOptions::default())
+    }
+
+    pub fn new_with_options(
+        port: u16,
+        global: Arc<GlobalState>,
+        options: MockConnOptions,
+    ) -> (MockConn, MockConnControl) {
         let (in_tx, in_rx) = channel(1);
         let (out_tx, out_rx) = channel(1);
+        let (tls_acceptor, in_tx, out_rx) = if options.tls {
+            let (acceptor, connector) = build_tls_pair();
+            let (in_tx, out_rx) = start_tls_client(connector, in_tx, out_rx);
+            (Some(acceptor), in_tx, out_rx)
+        } else {
+            (None, in_tx, out_rx)
+        };
         let conn = MockConn {
-            bind: global.config.listeners.mqtt.clone().unwrap().addr,
+            bind: global.config().listeners.mqtt.clone().unwrap().addr,
             peer: format!("127.0.0.1:{}", port).parse().unwrap(),
             data_in: Vec::new(),
             chan_in: in_rx,
             chan_out: PollSender::new(out_tx),
+            rng: StdRng::seed_from_u64(options.seed),
+            chaos: options.chaos.clone(),
+            options,
+            delay: None,
+            delivered: 0,
+            tls_acceptor,
         };
         let control = MockConnControl {
             chan_in: in_tx,
@@ -95,23 +163,136 @@ impl MockConn {
         let task = control.start(conn);
         (task, control)
     }
+
+    pub fn start_with_options(
+        port: u16,
+        global: Arc<GlobalState>,
+        options: MockConnOptions,
+    ) -> (JoinHandle<io::Result<()>>, MockConnControl) {
+        let (conn, control) = Self::new_with_options(port, global, options);
+        let task = control.start(conn);
+        (task, control)
+    }
+}
+
+/// A self-signed certificate for the server, the client skips the verification
+fn build_tls_pair() -> (SslAcceptor, SslConnector) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+    let mut cert = X509Builder::new().unwrap();
+    cert.set_version(2).unwrap();
+    let serial_number = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+    cert.set_serial_number(&serial_number).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    let cert = cert.build();
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    connector.set_verify(SslVerifyMode::NONE);
+    (acceptor.build(), connector.build())
+}
+
+/// Run a TLS client between the control side (plain text) and the connection
+/// (cipher text), return the plain text channels for the control side.
+fn start_tls_client(
+    connector: SslConnector,
+    cipher_in: Sender<Vec<u8>>,
+    mut cipher_out: Receiver<Vec<u8>>,
+) -> (Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+    let (plain_in_tx, mut plain_in_rx) = channel::<Vec<u8>>(1);
+    let (plain_out_tx, plain_out_rx) = channel(1);
+    let (client_io, cipher_io) = tokio::io::duplex(64 * 1024);
+    let (mut cipher_reader, mut cipher_writer) = tokio::io::split(cipher_io);
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 16 * 1024];
+        while let Ok(n) = cipher_reader.read(&mut buf).await {
+            if n == 0 || cipher_in.send(buf[..n].to_vec()).await.is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(data) = cipher_out.recv().await {
+            if cipher_writer.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let ssl = connector
+            .configure()
+            .unwrap()
+            .verify_hostname(false)
+            .into_ssl("localhost")
+            .unwrap();
+        let mut stream = SslStream::new(ssl, client_io).unwrap();
+        if let Err(err) = Pin::new(&mut stream).connect().await {
+            tracing::debug!("TLS handshake error: {:?}", err);
+            return;
+        }
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        tokio::spawn(async move {
+            while let Some(data) = plain_in_rx.recv().await {
+                if writer.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+        let mut buf = vec![0u8; 16 * 1024];
+        while let Ok(n) = reader.read(&mut buf).await {
+            if n == 0 || plain_out_tx.send(buf[..n].to_vec()).await.is_err() {
+                break;
+            }
+        }
+    });
+    (plain_in_tx, plain_out_rx)
 }
 
 impl MockConnControl {
     pub fn start(&self, conn: MockConn) -> JoinHandle<io::Result<()>> {
+        self.start_with_hook(conn, TestHook)
+    }
+
+    /// Start the server side of the connection with the given hook
+    pub fn start_with_hook<H: Hook + Clone + Send + Sync + 'static>(
+        &self,
+        mut conn: MockConn,
+        hook_handler: H,
+    ) -> JoinHandle<io::Result<()>> {
         let peer = conn.peer;
         let global = Arc::clone(&self.global);
 
-        let hook_handler = TestHook;
+        let listener = global.config().listeners.mqtt.clone().unwrap();
         let conn_args = ConnectionArgs {
             addr: conn.bind,
             reuse_port: false,
             proxy: false,
             proxy_tls_termination: false,
             websocket: false,
-            tls_acceptor: None,
+            tls_acceptor: conn.tls_acceptor.take(),
+            max_qos: None,
+            server_keep_alive: listener.server_keep_alive,
         };
-        tokio::spawn(handle_accept(conn, conn_args, peer, hook_handler, global))
+        match conn.chaos.take() {
+            Some(options) => {
+                let conn = ChaosConn::new(conn, options);
+                tokio::spawn(handle_accept(conn, conn_args, peer, hook_handler, global))
+            }
+            None => tokio::spawn(handle_accept(conn, conn_args, peer, hook_handler, global)),
+        }
     }
 
     pub fn try_read_packet_is_empty(&mut self) -> bool {
@@ -130,6 +311,11 @@ impl AsyncRead for MockConn {
         buf: &mut ReadBuf,
     ) -> Poll<io::Result<()>> {
         // let peer = self.peer.clone();
+        if let Some(drop_after) = self.options.drop_after {
+            if self.delivered >= drop_after {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::ConnectionReset)));
+            }
+        }
         if self.data_in.is_empty() {
             self.data_in = match self.chan_in.poll_recv(cx) {
                 Poll::Pending => return Poll::Pending,
@@ -142,10 +328,24 @@ impl AsyncRead for MockConn {
         if self.data_in.is_empty() {
             return Poll::Ready(Ok(()));
         }
-        let amt = cmp::min(buf.remaining(), self.data_in.len());
+        if let Some(latency) = self.options.latency {
+            let delay = self.delay.get_or_insert_with(|| Box::pin(sleep(latency)));
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+        let mut amt = cmp::min(buf.remaining(), self.data_in.len());
+        if let Some(max_fragment) = self.options.max_fragment {
+            amt = cmp::min(amt, self.rng.gen_range(1..=max_fragment));
+        }
+        if let Some(drop_after) = self.options.drop_after {
+            amt = cmp::min(amt, drop_after - self.delivered);
+        }
         let mut other = self.data_in.split_off(amt);
         mem::swap(&mut other, &mut self.data_in);
         buf.put_slice(&other);
+        self.delivered += amt;
         Poll::Ready(Ok(()))
     }
 }
@@ -160,7 +360,7 @@ impl AsyncWrite for MockConn {
         let mut sink = Pin::new(&mut self.chan_out);
         match sink.as_mut().poll_ready(cx) {
             Poll::Ready(Ok(())) => {
-                log::debug!("send to [{}]", peer);
+                tracing::debug!("send to [{}]", peer);
                 if sink.as_mut().start_send(buf.to_vec()).is_err() {
                     return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
                 }
@@ -175,7 +375,7 @@ impl AsyncWrite for MockConn {
             }
             Poll::Ready(Err(_)) => Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe))),
             Poll::Pending => {
-                log::debug!("[{}] MockConn poll_write() Pending", peer);
+                tracing::debug!("[{}] MockConn poll_write() Pending", peer);
                 Poll::Pending
             }
         }
@@ -203,6 +403,10 @@ impl AsyncWrite for MockConn {
         Poll::Ready(Ok(nwritten))
     }
 
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         Pin::new(&mut self.chan_out)
             .as_mut()
@@ -218,6 +422,11 @@ impl AsyncWrite for MockConn {
     }
 }
 
+/// The hook of the tests: publishing or subscribing the `denied/` topics is
+/// not authorized (except the v5.x client's own response topics), publishing
+/// the `slow/` topics is delayed 100ms. The v5.x `tiny-` clients get the max
+/// inflight 1, the new sessions of the v5.x `tenant-{name}` clients get the
+/// attribute `tenant={name}`.
 #[derive(Clone)]
 pub struct TestHook;
 
@@ -231,7 +440,7 @@ impl Hook for TestHook {
         _peer: SocketAddr,
         connect: &v5::Connect,
     ) -> HookResult<HookConnectCode> {
-        log::debug!("v5_before_connect(), identifier={}", connect.client_id);
+        tracing::debug!("v5_before_connect(), identifier={}", connect.client_id);
         Ok(HookConnectCode::Success)
     }
 
@@ -240,12 +449,21 @@ impl Hook for TestHook {
         session: &SessionV5,
         session_present: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!(
+        tracing::debug!(
             "v5_after_connect(), [{}], identifier={}, session_present={}",
             session.client_id(),
             session.client_identifier,
             session_present
         );
+        if session.client_identifier.starts_with("tiny-") {
+            return Ok(vec![HookAction::SetMaxInflight(1)]);
+        }
+        if let Some(tenant) = session.client_identifier.strip_prefix("tenant-") {
+            if !session_present {
+                let action = HookAction::SetAttribute("tenant".to_owned(), tenant.to_owned());
+                return Ok(vec![action]);
+            }
+        }
         Ok(Vec::new())
     }
 
@@ -257,11 +475,19 @@ impl Hook for TestHook {
         publish: &mut v5::Publish,
         _changed: &mut bool,
     ) -> HookResult<HookPublishCode> {
-        log::debug!(
+        tracing::debug!(
             "v5_before_publish() [{}], topic={}",
             session.client_id(),
             publish.topic_name
         );
+        if publish.topic_name.starts_with("denied/")
+            && !session.is_response_topic(&publish.topic_name)
+        {
+            return Ok(HookPublishCode::NotAuthorized);
+        }
+        if publish.topic_name.starts_with("slow/") {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
         Ok(HookPublishCode::Success)
     }
 
@@ -273,7 +499,7 @@ impl Hook for TestHook {
         publish: &v5::Publish,
         _changed: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!(
+        tracing::debug!(
             "v5_after_publish() [{}], topic={}",
             session.client_id(),
             publish.topic_name
@@ -289,11 +515,18 @@ impl Hook for TestHook {
         subscribe: &mut v5::Subscribe,
         _changed: &mut bool,
     ) -> HookResult<HookSubscribeCode> {
-        log::debug!(
+        tracing::debug!(
             "v5_before_subscribe() [{}], {:#?}",
             session.client_id(),
             subscribe
         );
+        if subscribe
+            .topics
+            .iter()
+            .any(|(filter, _)| filter.starts_with("denied/") && !session.is_response_topic(filter))
+        {
+            return Ok(HookSubscribeCode::NotAuthorized);
+        }
         Ok(HookSubscribeCode::Success)
     }
 
@@ -306,7 +539,7 @@ impl Hook for TestHook {
         _changed: bool,
         _reason_codes: Option<Vec<v5::SubscribeReasonCode>>,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!("v5_after_subscribe(), [{}]", session.client_id());
+        tracing::debug!("v5_after_subscribe(), [{}]", session.client_id());
         Ok(Vec::new())
     }
 
@@ -318,7 +551,7 @@ impl Hook for TestHook {
         unsubscribe: &mut v5::Unsubscribe,
         _changed: &mut bool,
     ) -> HookResult<HookUnsubscribeCode> {
-        log::debug!(
+        tracing::debug!(
             "v5_before_unsubscribe(), [{}], {:#?}",
             session.client_id(),
             unsubscribe
@@ -334,12 +567,12 @@ impl Hook for TestHook {
         _unsubscribe: &v5::Unsubscribe,
         _changed: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!("v5_after_unsubscribe(), [{}]", session.client_id());
+        tracing::debug!("v5_after_unsubscribe(), [{}]", session.client_id());
         Ok(Vec::new())
     }
 
     async fn v5_after_disconnect(&self, session: &SessionV5, _taken_over: bool) -> HookResult<()> {
-        log::debug!("v5_after_disconnect(), [{}]", session.client_id());
+        tracing::debug!("v5_after_disconnect(), [{}]", session.client_id());
         Ok(())
     }
 
@@ -352,7 +585,7 @@ impl Hook for TestHook {
         _peer: SocketAddr,
         connect: &v3::Connect,
     ) -> HookResult<HookConnectCode> {
-        log::debug!("v3_before_connect(), identifier={}", connect.client_id);
+        tracing::debug!("v3_before_connect(), identifier={}", connect.client_id);
         Ok(HookConnectCode::Success)
     }
 
@@ -361,7 +594,7 @@ impl Hook for TestHook {
         session: &SessionV3,
         session_present: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!(
+        tracing::debug!(
             "v3_after_connect(), [{}], identifier={}, session_present={}",
             session.client_id(),
             session.client_identifier,
@@ -378,7 +611,7 @@ impl Hook for TestHook {
         publish: &mut v3::Publish,
         _changed: &mut bool,
     ) -> HookResult<HookPublishCode> {
-        log::debug!(
+        tracing::debug!(
             "v3_before_publish() [{}], topic={}",
             session.client_id(),
             publish.topic_name
@@ -394,7 +627,7 @@ impl Hook for TestHook {
         publish: &v3::Publish,
         _changed: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!(
+        tracing::debug!(
             "v3_after_publish() [{}], topic={}",
             session.client_id(),
             publish.topic_name
@@ -410,7 +643,7 @@ impl Hook for TestHook {
         subscribe: &mut v3::Subscribe,
         _changed: &mut bool,
     ) -> HookResult<HookSubscribeCode> {
-        log::debug!(
+        tracing::debug!(
             "v3_before_subscribe() [{}], {:#?}",
             session.client_id(),
             subscribe
@@ -427,7 +660,7 @@ impl Hook for TestHook {
         _changed: bool,
         _codes: Option<Vec<v3::SubscribeReturnCode>>,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!("v3_after_subscribe(), [{}]", session.client_id());
+        tracing::debug!("v3_after_subscribe(), [{}]", session.client_id());
         Ok(Vec::new())
     }
 
@@ -439,7 +672,7 @@ impl Hook for TestHook {
         unsubscribe: &mut v3::Unsubscribe,
         _changed: &mut bool,
     ) -> HookResult<HookUnsubscribeCode> {
-        log::debug!(
+        tracing::debug!(
             "v3_before_unsubscribe(), [{}], {:#?}",
             session.client_id(),
             unsubscribe
@@ -455,12 +688,12 @@ impl Hook for TestHook {
         _unsubscribe: &v3::Unsubscribe,
         _changed: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!("v3_after_unsubscribe(), [{}]", session.client_id());
+        tracing::debug!("v3_after_unsubscribe(), [{}]", session.client_id());
         Ok(Vec::new())
     }
 
     async fn v3_after_disconnect(&self, session: &SessionV3, _taken_over: bool) -> HookResult<()> {
-        log::debug!("v3_after_disconnect(), [{}]", session.client_id());
+        tracing::debug!("v3_after_disconnect(), [{}]", session.client_id());
         Ok(())
     }
 }
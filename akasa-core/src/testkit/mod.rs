@@ -0,0 +1,21 @@
+//! An in-memory broker for the integration tests, also exposed by the
+//! `testkit` feature for the crates embedding akasa (or writing hooks).
+//!
+//! ```ignore
+//! use akasa_core::testkit::{v5::ClientV5, MockConn};
+//!
+//! let (_task, mut client) = MockConn::start(1111, Config::new_allow_anonymous());
+//! client.connect("client", true, false).await;
+//! ```
+//!
+//! `MockConn` is the connection of the server side (with the options to
+//! fragment, delay or drop the data, see `MockConnOptions`), the test drives
+//! the client side by the `MockConnControl` and the packet helpers in `v3`
+//! and `v5`.
+
+pub mod chaos;
+mod conn;
+pub mod v3;
+pub mod v5;
+
+pub use conn::{MockConn, MockConnControl, MockConnOptions, TestHook};
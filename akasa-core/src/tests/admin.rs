@@ -0,0 +1,478 @@
+use std::fs;
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use mqtt_proto::{Protocol, QoS, TopicFilter, TopicName};
+
+use crate::alarm::check_alarms;
+use crate::config::{AdminConfig, AuditConfig, Config};
+use crate::server::admin::handle_request;
+use crate::server::http::Request;
+use crate::state::{AddClientReceipt, GlobalState, NormalMessage};
+
+fn peer() -> SocketAddr {
+    "127.0.0.1:9000".parse().unwrap()
+}
+
+fn get(path: &str) -> Request {
+    request("GET", path)
+}
+
+const ADMIN_TOKEN: &str = "admin";
+
+// With the admin token required by the endpoints changing the state
+fn admin_config() -> Config {
+    let mut config = Config::new_allow_anonymous();
+    config.admin = Some(AdminConfig {
+        addr: "127.0.0.1:8081".parse().unwrap(),
+        token: Some(ADMIN_TOKEN.to_owned()),
+        publish_token: None,
+        stream_token: None,
+    });
+    config
+}
+
+fn authorized(method: &str, target: &str) -> Request {
+    let mut request = request(method, target);
+    request
+        .headers
+        .push(("Authorization".to_owned(), format!("Bearer {ADMIN_TOKEN}")));
+    request
+}
+
+fn request(method: &str, target: &str) -> Request {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+    Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+#[tokio::test]
+async fn test_health_check() {
+    let global = GlobalState::new(Config::new_allow_anonymous());
+    let addr = "127.0.0.1:1883".parse().unwrap();
+
+    // Executor heartbeat not started
+    assert_eq!(
+        handle_request(&global, peer(), get("/healthz"))
+            .await
+            .status,
+        503
+    );
+    assert_eq!(
+        handle_request(&global, peer(), get("/readyz")).await.status,
+        503
+    );
+
+    global.health.heartbeat();
+    assert_eq!(
+        handle_request(&global, peer(), get("/healthz"))
+            .await
+            .status,
+        200
+    );
+    // No listener is listening
+    assert_eq!(
+        handle_request(&global, peer(), get("/readyz")).await.status,
+        503
+    );
+
+    global.health.set_listener(addr, true);
+    let response = handle_request(&global, peer(), get("/readyz")).await;
+    assert_eq!(response.status, 200);
+    assert_eq!(response.content_type, "application/json");
+    let report: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(report["ready"], true);
+    assert_eq!(report["listeners"][0]["addr"], "127.0.0.1:1883");
+    assert_eq!(report["storage"], "memory");
+
+    global.health.set_listener(addr, false);
+    assert_eq!(
+        handle_request(&global, peer(), get("/readyz")).await.status,
+        503
+    );
+
+    assert_eq!(
+        handle_request(&global, peer(), get("/unknown"))
+            .await
+            .status,
+        404
+    );
+}
+
+#[tokio::test]
+async fn test_retained_messages() {
+    let global = GlobalState::new(admin_config());
+    for (topic, payload) in [("a/1", "11"), ("a/2", "222"), ("b/1", "3"), ("a/b/c", "4")] {
+        global
+            .publish(
+                TopicName::try_from(topic.to_owned()).unwrap(),
+                QoS::Level1,
+                true,
+                Bytes::from(payload),
+                Default::default(),
+            )
+            .unwrap();
+    }
+
+    let response = handle_request(&global, peer(), get("/api/v1/retained?filter=a%2F%2B")).await;
+    assert_eq!(response.status, 200);
+    let retains: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(
+        retains,
+        serde_json::json!([
+            {"topic_name": "a/1", "qos": 1, "payload_len": 2, "client_identifier": ""},
+            {"topic_name": "a/2", "qos": 1, "payload_len": 3, "client_identifier": ""},
+        ])
+    );
+    let response = handle_request(&global, peer(), get("/api/v1/retained")).await;
+    let retains: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(retains.as_array().unwrap().len(), 4);
+
+    let response = handle_request(&global, peer(), get("/api/v1/retained/a%2F2")).await;
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"222");
+    let response = handle_request(&global, peer(), get("/api/v1/retained/a%2F3")).await;
+    assert_eq!(response.status, 404);
+
+    // filter is required and must be valid
+    let response = handle_request(&global, peer(), authorized("DELETE", "/api/v1/retained")).await;
+    assert_eq!(response.status, 400);
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("DELETE", "/api/v1/retained?filter=a/#/b"),
+    )
+    .await;
+    assert_eq!(response.status, 400);
+
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("DELETE", "/api/v1/retained?filter=a/%23"),
+    )
+    .await;
+    assert_eq!(response.status, 200);
+    let result: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(result["purged"], 3);
+    let topics: Vec<_> = global
+        .retain_table
+        .get_matches("#")
+        .iter()
+        .map(|content| content.topic_name.to_string())
+        .collect();
+    assert_eq!(topics, vec!["b/1".to_owned()]);
+}
+
+#[tokio::test]
+async fn test_audit_admin_action() {
+    let path = std::env::temp_dir().join(format!("akasa-audit-{}.log", uuid::Uuid::new_v4()));
+    let mut config = admin_config();
+    config.audit = Some(AuditConfig {
+        file: Some(path.clone()),
+        topic: None,
+    });
+    let global = GlobalState::new(config);
+
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("DELETE", "/api/v1/retained?filter=%23"),
+    )
+    .await;
+    assert_eq!(response.status, 200);
+
+    global.flush_audit_log();
+    let content = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let lines: Vec<_> = content.lines().collect();
+    assert_eq!(lines.len(), 1);
+    let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(record["event"], "admin_action");
+    assert_eq!(record["action"], "purge_retained");
+    assert_eq!(record["peer"], "127.0.0.1:9000");
+    assert!(record["time"].as_u64().unwrap() > 0);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_alarms() {
+    let mut config = Config::new_allow_anonymous();
+    config.alarms.max_memory = Some(1);
+    config.alarms.max_connections = Some(10);
+    let global = GlobalState::new(config);
+    check_alarms(&global);
+
+    let response = handle_request(&global, peer(), get("/readyz")).await;
+    let report: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(report["alarms"], serde_json::json!(["memory"]));
+
+    let response = handle_request(&global, peer(), get("/api/v1/alarms")).await;
+    assert_eq!(response.status, 200);
+    let alarms: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    let alarms = alarms.as_array().unwrap();
+    assert_eq!(alarms.len(), 1);
+    assert_eq!(alarms[0]["name"], "memory");
+    assert_eq!(alarms[0]["active"], true);
+    assert_eq!(alarms[0]["threshold"], 1);
+
+    // The raised alarm is published as a retained message
+    let retains = global.retain_table.get_matches("$SYS/alarms/#");
+    assert_eq!(retains.len(), 1);
+    assert_eq!(&*retains[0].topic_name, "$SYS/alarms/memory");
+    let alarm: serde_json::Value = serde_json::from_slice(&retains[0].payload).unwrap();
+    assert_eq!(alarm["active"], true);
+}
+
+#[tokio::test]
+async fn test_bans() {
+    let path = std::env::temp_dir().join(format!("akasa-bans-{}.json", uuid::Uuid::new_v4()));
+    let mut config = admin_config();
+    config.ban_file = Some(path.clone());
+    let global = GlobalState::new(config);
+
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("POST", "/api/v1/bans/ip/10.0.0.1?reason=flood"),
+    )
+    .await;
+    assert_eq!(response.status, 200);
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("POST", "/api/v1/bans/client_id/bad%2Fclient?expire_secs=60"),
+    )
+    .await;
+    assert_eq!(response.status, 200);
+    for target in ["/api/v1/bans/ip/10.0.0", "/api/v1/bans/user/abc"] {
+        let response = handle_request(&global, peer(), authorized("POST", target)).await;
+        assert_eq!(response.status, 400);
+    }
+    assert!(global.bans.is_client_banned("bad/client", None));
+
+    let response = handle_request(&global, peer(), get("/api/v1/bans")).await;
+    let bans: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(bans.as_array().unwrap().len(), 2);
+    assert_eq!(bans[0]["kind"], "ip");
+    assert_eq!(bans[0]["value"], "10.0.0.1");
+    assert_eq!(bans[0]["reason"], "flood");
+    assert!(bans[1]["expire_at"].as_u64().is_some());
+
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("DELETE", "/api/v1/bans/client_id/bad%2Fclient"),
+    )
+    .await;
+    assert_eq!(response.status, 200);
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("DELETE", "/api/v1/bans/client_id/bad%2Fclient"),
+    )
+    .await;
+    assert_eq!(response.status, 404);
+
+    // Saved after changed
+    let content = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let saved: serde_json::Value = serde_json::from_slice(&content).unwrap();
+    assert_eq!(saved.as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_publish() {
+    let publish = |token: &str, body: serde_json::Value| {
+        let mut request = request("POST", "/api/v1/publish");
+        request
+            .headers
+            .push(("Authorization".to_owned(), format!("Bearer {token}")));
+        request.body = serde_json::to_vec(&body).unwrap();
+        request
+    };
+    let body = serde_json::json!({
+        "topic": "a/1",
+        "payload": "aGk=",
+        "base64": true,
+        "qos": 1,
+        "properties": {"user_properties": [["k", "v"]]},
+    });
+
+    // Disabled without the token
+    let global = GlobalState::new(Config::new_allow_anonymous());
+    let response = handle_request(&global, peer(), publish("secret", body.clone())).await;
+    assert_eq!(response.status, 403);
+
+    let mut config = Config::new_allow_anonymous();
+    config.admin = Some(AdminConfig {
+        addr: "127.0.0.1:8081".parse().unwrap(),
+        token: None,
+        publish_token: Some("secret".to_owned()),
+        stream_token: None,
+    });
+    let global = GlobalState::new(config);
+    let response = handle_request(&global, peer(), publish("wrong", body.clone())).await;
+    assert_eq!(response.status, 401);
+
+    let (client_id, receiver) = match global.add_client("c1", Protocol::V500, None).await.unwrap() {
+        AddClientReceipt::New {
+            client_id,
+            receiver,
+        } => (client_id, receiver),
+        _ => panic!("new client expected"),
+    };
+    let filter = TopicFilter::try_from("a/+".to_owned()).unwrap();
+    global
+        .route_table
+        .subscribe(&filter, client_id, QoS::Level1);
+    let response = handle_request(&global, peer(), publish("secret", body)).await;
+    assert_eq!(response.status, 200);
+    let result: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(
+        result,
+        serde_json::json!({"delivered": 1, "forwarded": false})
+    );
+    match receiver.normal.try_recv().unwrap().1 {
+        NormalMessage::PublishV5 {
+            topic_name,
+            payload,
+            qos,
+            properties,
+            ..
+        } => {
+            assert_eq!(&*topic_name, "a/1");
+            assert_eq!(payload, Bytes::from_static(b"hi"));
+            assert_eq!(qos, QoS::Level1);
+            assert_eq!(properties.user_properties.len(), 1);
+            assert_eq!(properties.user_properties[0].name.as_str(), "k");
+        }
+        _ => panic!("v5 publish expected"),
+    }
+
+    // Retained
+    let response = handle_request(
+        &global,
+        peer(),
+        publish(
+            "secret",
+            serde_json::json!({"topic": "b/1", "payload": "on", "retain": true}),
+        ),
+    )
+    .await;
+    assert_eq!(response.status, 200);
+    let retains = global.retain_table.get_matches("b/1");
+    assert_eq!(retains.len(), 1);
+    assert_eq!(retains[0].payload, Bytes::from_static(b"on"));
+
+    for body in [
+        serde_json::json!({"topic": "$SYS/a", "payload": ""}),
+        serde_json::json!({"topic": "a/+", "payload": ""}),
+        serde_json::json!({"topic": "a/1", "payload": "", "qos": 3}),
+        serde_json::json!({"topic": "a/1", "payload": "!", "base64": true}),
+        serde_json::json!({"payload": ""}),
+    ] {
+        let response = handle_request(&global, peer(), publish("secret", body)).await;
+        assert_eq!(response.status, 400);
+    }
+}
+
+#[tokio::test]
+async fn test_packet_traces() {
+    let global = GlobalState::new(admin_config());
+
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("POST", "/api/v1/traces/client%2F1?capacity=16"),
+    )
+    .await;
+    assert_eq!(response.status, 200);
+    assert!(global.packet_tracer.is_active());
+    let response = handle_request(&global, peer(), get("/api/v1/traces")).await;
+    let clients: Vec<String> = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(clients, vec!["client/1".to_owned()]);
+    let response = handle_request(&global, peer(), get("/api/v1/traces/client%2F1")).await;
+    assert_eq!(response.status, 200);
+    let records: Vec<String> = serde_json::from_slice(&response.body).unwrap();
+    assert!(records.is_empty());
+
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("DELETE", "/api/v1/traces/client%2F1"),
+    )
+    .await;
+    assert_eq!(response.status, 200);
+    assert!(!global.packet_tracer.is_active());
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("DELETE", "/api/v1/traces/client%2F1"),
+    )
+    .await;
+    assert_eq!(response.status, 404);
+}
+
+#[tokio::test]
+async fn test_admin_token() {
+    // The endpoints changing the state are disabled without the token
+    let global = GlobalState::new(Config::new_allow_anonymous());
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("POST", "/api/v1/bans/ip/10.0.0.1"),
+    )
+    .await;
+    assert_eq!(response.status, 403);
+    let response = handle_request(&global, peer(), get("/api/v1/bans")).await;
+    assert_eq!(response.status, 200);
+
+    let global = GlobalState::new(admin_config());
+    for (method, target) in [
+        ("POST", "/api/v1/bans/ip/10.0.0.1"),
+        ("DELETE", "/api/v1/bans/ip/10.0.0.1"),
+        ("POST", "/api/v1/sessions/c1/redirect"),
+        ("PUT", "/api/v1/listeners"),
+        ("DELETE", "/api/v1/retained?filter=%23"),
+        ("POST", "/api/v1/traces/c1"),
+        ("DELETE", "/api/v1/traces/c1"),
+    ] {
+        let response = handle_request(&global, peer(), request(method, target)).await;
+        assert_eq!(response.status, 401);
+        let mut wrong_token = request(method, target);
+        wrong_token
+            .headers
+            .push(("Authorization".to_owned(), "Bearer wrong".to_owned()));
+        let response = handle_request(&global, peer(), wrong_token).await;
+        assert_eq!(response.status, 401);
+        // Same length as the token
+        let mut wrong_token = request(method, target);
+        wrong_token.headers.push((
+            "Authorization".to_owned(),
+            format!("Bearer {}", "x".repeat(ADMIN_TOKEN.len())),
+        ));
+        let response = handle_request(&global, peer(), wrong_token).await;
+        assert_eq!(response.status, 401);
+    }
+    // The other methods never reach the endpoints changing the state
+    let response =
+        handle_request(&global, peer(), request("HEAD", "/api/v1/bans/ip/10.0.0.1")).await;
+    assert_eq!(response.status, 404);
+    let response = handle_request(
+        &global,
+        peer(),
+        authorized("PATCH", "/api/v1/bans/ip/10.0.0.1"),
+    )
+    .await;
+    assert_eq!(response.status, 405);
+    assert!(global.bans.list().is_empty());
+    assert!(!global.packet_tracer.is_active());
+}
@@ -1,5 +1,4 @@
 //! Tests for server
 
-mod utils;
-
+mod admin;
 mod protocols;
@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mqtt_proto::v3::*;
+use mqtt_proto::*;
+use tokio::time::sleep;
+
+use crate::config::Config;
+use crate::state::GlobalState;
+use crate::testkit::chaos::{ChaosOptions, Faults, PUBLISH, PUBREL};
+use crate::testkit::v3::ClientV3;
+use crate::testkit::{MockConn, MockConnOptions};
+
+fn chaos_options(incoming: Faults) -> MockConnOptions {
+    MockConnOptions {
+        chaos: Some(ChaosOptions {
+            incoming,
+            seed: 3,
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_chaos_publish_c2b_disconnect_qos2() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (_task1, mut subscriber) = MockConn::start_with_global(111, Arc::clone(&global));
+    subscriber.connect("subscriber", true, false).await;
+    subscriber.subscribe(1, vec![("xyz/+", QoS::Level2)]).await;
+
+    // Closed before the PUBREL received by the server
+    let options = chaos_options(Faults {
+        close_on: Some(PUBREL),
+        ..Default::default()
+    });
+    let (task2, mut publisher) = MockConn::start_with_options(222, Arc::clone(&global), options);
+    publisher.connect("publisher", false, false).await;
+    publisher
+        .send_publish(QoS::Level2, 7, "xyz/1", vec![1, 2, 3], |_| ())
+        .await;
+    publisher.recv_pubrec(7).await;
+    publisher.send_pubrel(7).await;
+    sleep(Duration::from_millis(10)).await;
+    assert!(task2.is_finished());
+
+    // The message is published when the PUBLISH received
+    subscriber
+        .recv_publish(QoS::Level2, 1, "xyz/1", vec![1, 2, 3], |_| ())
+        .await;
+    subscriber.send_pubrec(1).await;
+    subscriber.recv_pubrel(1).await;
+    subscriber.send_pubcomp(1).await;
+
+    // Resume the session, the re-delivered message is not published again
+    let (_task3, mut publisher) = MockConn::start_with_global(333, Arc::clone(&global));
+    publisher.connect("publisher", false, true).await;
+    publisher
+        .send_publish(QoS::Level2, 7, "xyz/1", vec![1, 2, 3], |p| p.dup = true)
+        .await;
+    publisher.recv_pubrec(7).await;
+    publisher.send_pubrel(7).await;
+    publisher.recv_pubcomp(7).await;
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(subscriber.try_read_packet_is_empty());
+}
+
+#[tokio::test]
+async fn test_chaos_publish_duplicated_qos2() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (_task1, mut subscriber) = MockConn::start_with_global(111, Arc::clone(&global));
+    subscriber.connect("subscriber", true, false).await;
+    subscriber.subscribe(1, vec![("xyz/+", QoS::Level2)]).await;
+
+    // Every PUBLISH packet is received twice
+    let options = chaos_options(Faults {
+        packet_types: vec![PUBLISH],
+        duplicate: 1.0,
+        ..Default::default()
+    });
+    let (_task2, mut publisher) = MockConn::start_with_options(222, Arc::clone(&global), options);
+    publisher.connect("publisher", true, false).await;
+    publisher
+        .send_publish(QoS::Level2, 3, "xyz/1", vec![1, 2, 3], |p| p.dup = true)
+        .await;
+    publisher.recv_pubrec(3).await;
+    publisher.recv_pubrec(3).await;
+    publisher.send_pubrel(3).await;
+    publisher.recv_pubcomp(3).await;
+
+    subscriber
+        .recv_publish(QoS::Level2, 1, "xyz/1", vec![1, 2, 3], |_| ())
+        .await;
+    sleep(Duration::from_millis(20)).await;
+    assert!(subscriber.try_read_packet_is_empty());
+}
+
+#[tokio::test]
+async fn test_chaos_publish_reordered_qos1() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (_task1, mut subscriber) = MockConn::start_with_global(111, Arc::clone(&global));
+    subscriber.connect("subscriber", true, false).await;
+    subscriber.subscribe(1, vec![("xyz/+", QoS::Level1)]).await;
+
+    let options = chaos_options(Faults {
+        packet_types: vec![PUBLISH],
+        reorder: 0.5,
+        delay: 0.5,
+        max_delay: Duration::from_millis(5),
+        ..Default::default()
+    });
+    let (_task2, mut publisher) = MockConn::start_with_options(222, Arc::clone(&global), options);
+    publisher.connect("publisher", true, false).await;
+    for pid in 1..=10u8 {
+        publisher
+            .send_publish(QoS::Level1, pid as u16, "xyz/1", vec![pid], |_| ())
+            .await;
+    }
+    // Release the last reordered PUBLISH
+    publisher.write_packet(Packet::Pingreq).await;
+
+    let mut acked = HashSet::new();
+    let mut pingresp = false;
+    for _ in 0..11 {
+        match publisher.read_packet().await {
+            Packet::Puback(pid) => assert!(acked.insert(pid.value())),
+            Packet::Pingresp => pingresp = true,
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+    }
+    assert!(pingresp);
+    assert_eq!(acked, (1..=10u16).collect());
+
+    // Every message is delivered once, in the order received by the server
+    let mut payloads = HashSet::new();
+    for pid in 1..=10u16 {
+        match subscriber.read_packet().await {
+            Packet::Publish(publish) => {
+                assert_eq!(publish.qos_pid, QosPid::Level1(Pid::try_from(pid).unwrap()));
+                payloads.insert(publish.payload[0]);
+            }
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+    }
+    assert_eq!(payloads, (1..=10u8).collect());
+}
@@ -9,9 +9,8 @@ use ConnectReturnCode::*;
 
 use crate::config::Config;
 use crate::state::{GlobalState, HashAlgorithm};
-use crate::tests::utils::MockConn;
-
-use super::ClientV3;
+use crate::testkit::v3::ClientV3;
+use crate::testkit::{MockConn, MockConnOptions};
 
 #[tokio::test]
 async fn test_connect_malformed_packet() {
@@ -24,6 +23,53 @@ async fn test_connect_malformed_packet() {
     assert!(task.await.unwrap().is_err());
 }
 
+#[tokio::test]
+async fn test_connect_bridge() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (task, mut bridge) = MockConn::start_with_global(3333, Arc::clone(&global));
+    let (_task, mut client) = MockConn::start_with_global(4444, Arc::clone(&global));
+    client.connect("client", true, false).await;
+
+    // Set the bridge flag of the protocol level
+    let connect = Connect::new(Arc::new("bridge".to_owned()), 10);
+    let mut data = Packet::from(connect).encode().unwrap().as_ref().to_vec();
+    assert_eq!(data[8], 4);
+    data[8] |= 0x80;
+    bridge.write_data(data).await;
+    let packet = bridge.read_packet().await;
+    assert_eq!(packet, Connack::new(false, Accepted).into());
+
+    // The bridge don't receive the messages published by itself
+    bridge.subscribe(1, vec![("abc/#", QoS::Level0)]).await;
+    bridge
+        .publish(QoS::Level0, 0, "abc/1", "bridge", |_| ())
+        .await;
+    client
+        .publish(QoS::Level0, 0, "abc/2", "client", |_| ())
+        .await;
+    bridge
+        .recv_publish(QoS::Level0, 0, "abc/2", "client", |_| ())
+        .await;
+    sleep(Duration::from_millis(20)).await;
+    assert!(bridge.try_read_packet_is_empty());
+    assert!(!task.is_finished());
+
+    // The bridge flag is not allowed for v5.0
+    let (task, client) = MockConn::start_with_global(5555, global);
+    let connect = v5::Connect::new(Arc::new("bridge".to_owned()), 10);
+    let mut data = v5::Packet::from(connect)
+        .encode()
+        .unwrap()
+        .as_ref()
+        .to_vec();
+    assert_eq!(data[8], 5);
+    data[8] |= 0x80;
+    client.write_data(data).await;
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+    assert!(task.await.unwrap().is_err());
+}
+
 #[tokio::test]
 async fn test_connect_invalid_first_packet() {
     // subscribe
@@ -97,6 +143,22 @@ async fn test_connect_v310() {
             )
             .await;
     }
+    // no session present flag in v3.1
+    {
+        let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+        let update_connect = |c: &mut Connect| {
+            c.protocol = Protocol::V310;
+            c.clean_session = false;
+        };
+        let (task, mut client) = MockConn::start_with_global(3333, Arc::clone(&global));
+        client.connect_with("client", update_connect, |_| ()).await;
+        client.disconnect().await;
+        sleep(Duration::from_millis(10)).await;
+        assert!(task.is_finished());
+
+        let (_task, mut client) = MockConn::start_with_global(3334, Arc::clone(&global));
+        client.connect_with("client", update_connect, |_| ()).await;
+    }
 }
 
 #[tokio::test]
@@ -117,6 +179,15 @@ async fn test_connect_v311() {
             )
             .await;
     }
+    // empty identifier is not allowed
+    {
+        let mut config = Config::new_allow_anonymous();
+        config.empty_client_id.assign = false;
+        let (_task, mut client) = MockConn::start(3333, config);
+        client
+            .connect_with("", |_| (), |a| a.code = IdentifierRejected)
+            .await;
+    }
 }
 
 #[tokio::test]
@@ -136,6 +207,49 @@ async fn test_connect_keepalive() {
             .connect_with("client id", |c| c.keep_alive = 22, |_| ())
             .await;
     }
+
+    // keep_alive exceeded the max keep alive
+    for keep_alive in [0, 31] {
+        let mut config = Config::new_allow_anonymous();
+        config.max_keep_alive = 30;
+        let (_task, mut client) = MockConn::start(3333, config);
+        client
+            .connect_with(
+                "client id",
+                |c| c.keep_alive = keep_alive,
+                |a| a.code = ServerUnavailable,
+            )
+            .await;
+    }
+
+    // The listener server keep alive is the max keep alive
+    {
+        let mut config = Config::new_allow_anonymous();
+        config.listeners.mqtt.as_mut().unwrap().server_keep_alive = Some(20);
+        let (_task, mut client) = MockConn::start(3333, config.clone());
+        client
+            .connect_with(
+                "client id",
+                |c| c.keep_alive = 22,
+                |a| a.code = ServerUnavailable,
+            )
+            .await;
+        let (_task, mut client) = MockConn::start(3333, config);
+        client
+            .connect_with("client id", |c| c.keep_alive = 20, |_| ())
+            .await;
+    }
+
+    // The listener disabled the keep alive
+    {
+        let mut config = Config::new_allow_anonymous();
+        config.max_keep_alive = 30;
+        config.listeners.mqtt.as_mut().unwrap().server_keep_alive = Some(0);
+        let (_task, mut client) = MockConn::start(3333, config);
+        client
+            .connect_with("client id", |c| c.keep_alive = 60, |_| ())
+            .await;
+    }
 }
 
 #[tokio::test]
@@ -240,3 +354,71 @@ async fn test_connect_auth() {
             .await;
     }
 }
+
+#[tokio::test]
+async fn test_connect_fragmented() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (_task1, mut subscriber) = MockConn::start_with_global(111, Arc::clone(&global));
+    subscriber.connect("subscriber", true, false).await;
+    subscriber.subscribe(1, vec![("xyz/+", QoS::Level1)]).await;
+
+    // The packets are decoded from 1 to 3 bytes fragments
+    let options = MockConnOptions {
+        max_fragment: Some(3),
+        latency: Some(Duration::from_millis(1)),
+        seed: 7,
+        ..Default::default()
+    };
+    let (_task2, mut publisher) = MockConn::start_with_options(222, Arc::clone(&global), options);
+    publisher.connect("publisher", true, false).await;
+    publisher
+        .send_publish(QoS::Level1, 3, "xyz/1", vec![1, 2, 3, 4, 5], |_| ())
+        .await;
+    publisher.recv_puback(3).await;
+    subscriber
+        .recv_publish(QoS::Level1, 1, "xyz/1", vec![1, 2, 3, 4, 5], |_| ())
+        .await;
+}
+
+#[tokio::test]
+async fn test_connect_dropped() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let connect = Connect::new(Arc::new("client".to_owned()), 10);
+    let connect_len = Packet::from(connect).encode().unwrap().as_ref().len();
+    // Reset in the middle of the PUBLISH packet
+    let options = MockConnOptions {
+        drop_after: Some(connect_len + 4),
+        ..Default::default()
+    };
+    let (task, mut client) = MockConn::start_with_options(333, Arc::clone(&global), options);
+    client.connect("client", true, false).await;
+    client
+        .send_publish(QoS::Level0, 0, "xyz/1", vec![1, 2, 3, 4, 5], |_| ())
+        .await;
+
+    sleep(Duration::from_millis(10)).await;
+    assert!(task.is_finished());
+    assert_eq!(global.online_clients_count(), 0);
+}
+
+#[tokio::test]
+async fn test_connect_tls() {
+    let options = MockConnOptions {
+        tls: true,
+        ..Default::default()
+    };
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (task, mut client) = MockConn::start_with_options(444, global, options);
+    client.connect("client", true, false).await;
+    client.subscribe(1, vec![("xyz/+", QoS::Level0)]).await;
+    client
+        .send_publish(QoS::Level0, 0, "xyz/1", vec![1, 2, 3], |_| ())
+        .await;
+    client
+        .recv_publish(QoS::Level0, 0, "xyz/1", vec![1, 2, 3], |_| ())
+        .await;
+    client.disconnect().await;
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+}
@@ -6,11 +6,10 @@ use mqtt_proto::*;
 use tokio::sync::mpsc;
 use tokio::time::sleep;
 
-use crate::config::Config;
+use crate::config::{Config, PayloadLimit, ValidationMode};
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::{build_publish, ClientV3};
+use crate::testkit::v3::{build_publish, ClientV3};
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_publish_qos0() {
@@ -205,7 +204,7 @@ async fn test_publish_qos2() {
                     let packet = client.read_packet().await;
                     match packet {
                         Packet::Publish(publish) => {
-                            log::warn!("PUBLISH received: qos_pid={:?}", publish.qos_pid);
+                            tracing::warn!("PUBLISH received: qos_pid={:?}", publish.qos_pid);
                             let data = vec![3, 5, 55, pub_pid as u8];
                             let expected =
                                 build_publish(QoS::Level2, pub_pid, "xyz/2", data, |_| ());
@@ -214,7 +213,7 @@ async fn test_publish_qos2() {
                             pub_pid += 1;
                         }
                         Packet::Pubrel(pid) => {
-                            log::warn!("PUREL received: pid={:?}", pid);
+                            tracing::warn!("PUREL received: pid={:?}", pid);
                             assert_eq!(pid.value(), rel_pid);
                             client.send_pubcomp(rel_pid).await;
                             rel_pid += 1;
@@ -248,3 +247,100 @@ async fn test_publish_qos2() {
         assert!(task.await.is_ok());
     }
 }
+
+#[tokio::test]
+async fn test_publish_max_qos_downgrade() {
+    let mut config = Config::new_allow_anonymous();
+    config.max_allowed_qos = 1;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task0, mut client0) = MockConn::start_with_global(100, Arc::clone(&global));
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client0.connect("publisher", true, false).await;
+    client1.connect("subscriber", true, false).await;
+
+    // The granted QoS is downgraded
+    client1
+        .send_subscribe(1, vec![("xyz/+", QoS::Level2)])
+        .await;
+    client1
+        .recv_suback(1, vec![SubscribeReturnCode::from(QoS::Level1)])
+        .await;
+
+    // The QoS2 flow is still finished with the publisher, but the message is
+    // delivered as QoS1.
+    client0
+        .publish(QoS::Level2, 1, "xyz/2", "hello", |_| ())
+        .await;
+    client1
+        .recv_publish(QoS::Level1, 1, "xyz/2", "hello", |_| ())
+        .await;
+    client1.send_puback(1).await;
+    client0.send_pubrel(1).await;
+    client0.recv_pubcomp(1).await;
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(client0.try_read_packet_is_empty());
+    assert!(client1.try_read_packet_is_empty());
+}
+
+#[tokio::test]
+async fn test_publish_lenient_validation() {
+    let mut config = Config::new_allow_anonymous();
+    config.topic_limits.max_levels = 2;
+    config.validation_mode = ValidationMode::Lenient;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task, mut client) = MockConn::start_with_global(100, Arc::clone(&global));
+    client.connect("client", true, false).await;
+    client.subscribe(1, vec![("#", QoS::Level1)]).await;
+
+    // The invalid messages are acknowledged but not published
+    client
+        .publish(QoS::Level1, 2, "$abc/1", "hello", |_| ())
+        .await;
+    client
+        .publish(QoS::Level2, 3, "abc/1/2", "hello", |_| ())
+        .await;
+    client.send_pubrel(3).await;
+    client.recv_pubcomp(3).await;
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(client.try_read_packet_is_empty());
+    assert!(!task.is_finished());
+}
+
+#[tokio::test]
+async fn test_publish_payload_limits() {
+    let mut config = Config::new_allow_anonymous();
+    config.payload_limits = vec![
+        PayloadLimit {
+            topic_filter: "sensors/+/raw".to_owned(),
+            max_payload_size: 0,
+        },
+        PayloadLimit {
+            topic_filter: "sensors/#".to_owned(),
+            max_payload_size: 4,
+        },
+    ];
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task, mut client) = MockConn::start_with_global(100, Arc::clone(&global));
+    client.connect("client", true, false).await;
+    client.subscribe(1, vec![("sensors/#", QoS::Level0)]).await;
+
+    // The too large message is acknowledged but not published
+    client
+        .publish(QoS::Level1, 2, "sensors/1", "hello", |_| ())
+        .await;
+    client
+        .send_publish(QoS::Level0, 0, "sensors/1/raw", "hello world", |_| ())
+        .await;
+    client
+        .recv_publish(QoS::Level0, 0, "sensors/1/raw", "hello world", |_| ())
+        .await;
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(client.try_read_packet_is_empty());
+    assert!(!task.is_finished());
+}
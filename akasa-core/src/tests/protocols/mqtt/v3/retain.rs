@@ -6,9 +6,8 @@ use tokio::time::sleep;
 
 use crate::config::Config;
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::ClientV3;
+use crate::testkit::v3::ClientV3;
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_retain_simple() {
@@ -75,3 +74,56 @@ async fn test_retain_different_clients() {
     assert!(!task1.is_finished());
     assert!(!task2.is_finished());
 }
+
+#[tokio::test]
+async fn test_retain_disabled() {
+    let mut config = Config::new_allow_anonymous();
+    config.retain_available = false;
+    let global = Arc::new(GlobalState::new(config));
+    let (task, mut client) = MockConn::start_with_global(3333, Arc::clone(&global));
+
+    client.connect("client id", true, false).await;
+    // The retain flag is silently cleared
+    client
+        .publish(QoS::Level1, 22, "xyz/1", vec![3, 5, 55], |p| {
+            p.retain = true
+        })
+        .await;
+    assert!(global.retain_table.get_matches("#").is_empty());
+
+    client.subscribe(23, vec![("xyz/1", QoS::Level1)]).await;
+    sleep(Duration::from_millis(10)).await;
+    assert!(client.try_read_packet_is_empty());
+    assert!(!task.is_finished());
+}
+
+#[tokio::test]
+async fn test_retain_limits() {
+    let mut config = Config::new_allow_anonymous();
+    config.retain_limits.max_messages = 1;
+    let global = Arc::new(GlobalState::new(config));
+    let (task, mut client) = MockConn::start_with_global(3333, Arc::clone(&global));
+
+    client.connect("client id", true, false).await;
+    client.subscribe(21, vec![("xyz/+", QoS::Level0)]).await;
+    client
+        .publish(QoS::Level0, 0, "xyz/1", vec![1], |p| p.retain = true)
+        .await;
+    client
+        .recv_publish(QoS::Level0, 0, "xyz/1", vec![1], |p| p.retain = true)
+        .await;
+    // The retained message is dropped, but still published
+    client
+        .publish(QoS::Level0, 0, "xyz/2", vec![2], |p| p.retain = true)
+        .await;
+    client
+        .recv_publish(QoS::Level0, 0, "xyz/2", vec![2], |p| p.retain = true)
+        .await;
+    let retains = global.retain_table.get_matches("#");
+    assert_eq!(retains.len(), 1);
+    assert_eq!(&*retains[0].topic_name, "xyz/1");
+    assert_eq!(global.retain_table.bytes(), 1);
+
+    sleep(Duration::from_millis(10)).await;
+    assert!(!task.is_finished());
+}
@@ -1,14 +1,14 @@
 use std::sync::Arc;
 use std::time::Duration;
 
+use mqtt_proto::v3::ConnectReturnCode;
 use mqtt_proto::*;
 use tokio::time::sleep;
 
-use crate::config::Config;
+use crate::config::{Config, TakeoverPolicy};
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::ClientV3;
+use crate::testkit::v3::ClientV3;
+use crate::testkit::MockConn;
 
 async fn test_clean_session(clean_session: bool, reconnect_clean_session: bool) {
     let (task, mut client) = MockConn::start(3333, Config::new_allow_anonymous());
@@ -97,3 +97,38 @@ async fn test_session_take_over() {
     assert!(task.is_finished());
     assert!(!task2.is_finished());
 }
+
+#[tokio::test]
+async fn test_session_take_over_rejected() {
+    let mut config = Config::new_allow_anonymous();
+    config.takeover_policy = TakeoverPolicy::RejectNewIfSameIp;
+    let global = Arc::new(GlobalState::new(config));
+    let client_id = "client id";
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect(client_id, true, false).await;
+
+    // The mock connections are all from 127.0.0.1
+    let (task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client2
+        .connect_with(
+            client_id,
+            |_| (),
+            |a| a.code = ConnectReturnCode::IdentifierRejected,
+        )
+        .await;
+    sleep(Duration::from_millis(20)).await;
+    assert!(!task.is_finished());
+    assert!(task2.is_finished());
+    assert_eq!(global.online_clients_count(), 1);
+    client
+        .publish(QoS::Level1, 1, "abc/1", vec![3, 5, 55], |_| ())
+        .await;
+
+    // Not rejected after the session is offline
+    client.disconnect().await;
+    sleep(Duration::from_millis(20)).await;
+    let (task3, mut client3) = MockConn::start_with_global(333, Arc::clone(&global));
+    client3.connect(client_id, true, false).await;
+    assert!(!task3.is_finished());
+}
@@ -1,12 +1,12 @@
 use std::time::Duration;
 
+use mqtt_proto::v3::SubscribeReturnCode;
 use mqtt_proto::*;
 use tokio::time::sleep;
 
 use crate::config::Config;
-use crate::tests::utils::MockConn;
-
-use super::ClientV3;
+use crate::testkit::v3::ClientV3;
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_sub_unsub_simple() {
@@ -38,3 +38,94 @@ async fn test_subscribe_reject_empty_topics() {
     assert!(client.try_read_packet().is_err());
     assert!(task.is_finished());
 }
+
+#[tokio::test]
+async fn test_wildcard_subscription_disabled() {
+    let mut config = Config::new_allow_anonymous();
+    config.wildcard_subscription_available = false;
+    let (task, mut client) = MockConn::start(3333, config);
+
+    client.connect("client id", true, false).await;
+    client
+        .send_subscribe(
+            23,
+            vec![
+                ("abc/0", QoS::Level1),
+                ("abc/+", QoS::Level1),
+                ("#", QoS::Level0),
+            ],
+        )
+        .await;
+    client
+        .recv_suback(
+            23,
+            vec![
+                QoS::Level1.into(),
+                SubscribeReturnCode::Failure,
+                SubscribeReturnCode::Failure,
+            ],
+        )
+        .await;
+
+    sleep(Duration::from_millis(10)).await;
+    assert!(!task.is_finished());
+}
+
+#[tokio::test]
+async fn test_subscribe_failure_v310() {
+    let mut config = Config::new_allow_anonymous();
+    config.wildcard_subscription_available = false;
+    let (task, mut client) = MockConn::start(3333, config);
+
+    client
+        .connect_with("client id", |c| c.protocol = Protocol::V310, |_| ())
+        .await;
+    // There is no failure return code in v3.1
+    client
+        .send_subscribe(23, vec![("abc/+", QoS::Level1)])
+        .await;
+
+    sleep(Duration::from_millis(10)).await;
+    assert!(client.try_read_packet().is_err());
+    assert!(task.is_finished());
+}
+
+#[tokio::test]
+async fn test_topic_limits() {
+    let mut config = Config::new_allow_anonymous();
+    config.topic_limits.max_levels = 3;
+    let (task, mut client) = MockConn::start(3333, config);
+
+    client.connect("client id", true, false).await;
+    client
+        .send_subscribe(23, vec![("a/b/c", QoS::Level1), ("a/b/c/d", QoS::Level1)])
+        .await;
+    client
+        .recv_suback(23, vec![QoS::Level1.into(), SubscribeReturnCode::Failure])
+        .await;
+
+    client
+        .send_publish(QoS::Level0, 0, "a/b/c/d", "0", |_| ())
+        .await;
+    sleep(Duration::from_millis(10)).await;
+    assert!(client.try_read_packet().is_err());
+    assert!(task.is_finished());
+}
+
+#[tokio::test]
+async fn test_max_subscriptions() {
+    let mut config = Config::new_allow_anonymous();
+    config.max_subscriptions = 1;
+    let (task, mut client) = MockConn::start(3333, config);
+
+    client.connect("client id", true, false).await;
+    client
+        .send_subscribe(23, vec![("a/b", QoS::Level1), ("a/c", QoS::Level1)])
+        .await;
+    client
+        .recv_suback(23, vec![QoS::Level1.into(), SubscribeReturnCode::Failure])
+        .await;
+    // Subscribe the existing topic filter again
+    client.subscribe(24, vec![("a/b", QoS::Level0)]).await;
+    assert!(!task.is_finished());
+}
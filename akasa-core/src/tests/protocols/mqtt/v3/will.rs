@@ -9,9 +9,8 @@ use tokio::time::sleep;
 
 use crate::config::Config;
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::ClientV3;
+use crate::testkit::v3::ClientV3;
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_will_publish() {
@@ -9,9 +9,8 @@ use ConnectReasonCode::*;
 
 use crate::config::Config;
 use crate::state::{GlobalState, HashAlgorithm};
-use crate::tests::utils::MockConn;
-
-use super::super::ClientV5;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::{MockConn, MockConnOptions};
 
 #[tokio::test]
 async fn test_connect_malformed_packet() {
@@ -218,3 +217,30 @@ async fn test_connect_auth() {
             .await;
     }
 }
+
+#[tokio::test]
+async fn test_connect_tls_fragmented() {
+    let options = MockConnOptions {
+        max_fragment: Some(5),
+        tls: true,
+        seed: 11,
+        ..Default::default()
+    };
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (task, mut client) = MockConn::start_with_options(444, global, options);
+    client.connect("client", true, false).await;
+    let sub_opts = SubscriptionOptions::new(QoS::Level0);
+    client.subscribe(1, vec![("xyz/+", sub_opts)]).await;
+    client
+        .send_publish(QoS::Level0, 0, "xyz/1", vec![1, 2, 3], |_| ())
+        .await;
+    client
+        .recv_publish(QoS::Level0, 0, "xyz/1", vec![1, 2, 3], |_| ())
+        .await;
+    client
+        .disconnect(DisconnectReasonCode::NormalDisconnection)
+        .await;
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+}
@@ -8,9 +8,8 @@ use tokio::time::sleep;
 
 use crate::config::Config;
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::super::ClientV5;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_pending_qos0() {
@@ -8,9 +8,8 @@ use tokio::time::sleep;
 
 use crate::config::Config;
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::super::{build_publish, ClientV5};
+use crate::testkit::v5::{build_publish, ClientV5};
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_publish_qos0() {
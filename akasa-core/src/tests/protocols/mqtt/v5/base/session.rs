@@ -7,9 +7,8 @@ use tokio::time::sleep;
 
 use crate::config::Config;
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::super::ClientV5;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
 
 async fn test_clean_start(clean_start: bool, reconnect_clean_start: bool) {
     let (task, mut client) = MockConn::start(3333, Config::new_allow_anonymous());
@@ -105,3 +104,52 @@ async fn test_session_take_over() {
     assert!(task.is_finished());
     assert!(!task2.is_finished());
 }
+
+#[tokio::test]
+async fn test_session_resend_in_order() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let client_id = "client id";
+
+    let (_task0, mut client0) = MockConn::start_with_global(100, Arc::clone(&global));
+    client0.connect("publisher", true, false).await;
+
+    let (task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    // client first connection: the first message not acknowledged
+    {
+        client1.connect(client_id, false, false).await;
+        client1
+            .subscribe(11, vec![("abc/1", SubscriptionOptions::new(QoS::Level1))])
+            .await;
+        client0
+            .publish(QoS::Level1, 1, "abc/1", "first", |_| ())
+            .await;
+        client1
+            .recv_publish(QoS::Level1, 1, "abc/1", "first", |_| ())
+            .await;
+        client1.disconnect_normal().await;
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(task1.is_finished());
+
+    // The second message is queued when the client is offline
+    client0
+        .publish(QoS::Level1, 2, "abc/1", "second", |_| ())
+        .await;
+
+    // The inflight message is re-sent before the queued one
+    let (task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    {
+        client2.connect(client_id, false, true).await;
+        client2
+            .recv_publish(QoS::Level1, 1, "abc/1", "first", |p| p.dup = true)
+            .await;
+        client2
+            .recv_publish(QoS::Level1, 2, "abc/1", "second", |_| ())
+            .await;
+        client2.send_puback(1).await;
+        client2.send_puback(2).await;
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(client2.try_read_packet_is_empty());
+    assert!(!task2.is_finished());
+}
@@ -5,9 +5,8 @@ use mqtt_proto::*;
 use tokio::time::sleep;
 
 use crate::config::Config;
-use crate::tests::utils::MockConn;
-
-use super::super::ClientV5;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_sub_unsub_simple() {
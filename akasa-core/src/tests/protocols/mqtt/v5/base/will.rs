@@ -1,4 +1,5 @@
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -8,10 +9,10 @@ use mqtt_proto::*;
 use tokio::time::sleep;
 
 use crate::config::Config;
+use crate::hook::{Authorizer, ComposedHook, HookClient, HookError, HookResult};
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::super::ClientV5;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_will_publish() {
@@ -96,3 +97,123 @@ async fn test_will_disconnect_not_publish() {
     assert!(client1.try_read_packet_is_empty());
     assert!(!task1.is_finished());
 }
+
+// Publishing the `private/` topics is authorized by the flag
+#[derive(Clone)]
+struct PrivateAcl(Arc<AtomicBool>);
+
+impl Authorizer for PrivateAcl {
+    async fn authorize_publish(
+        &self,
+        _client: HookClient<'_>,
+        topic_name: &TopicName,
+    ) -> HookResult<bool> {
+        Ok(!topic_name.starts_with("private/") || self.0.load(Ordering::SeqCst))
+    }
+}
+
+// Publishing the `private/` topics fails after the flag is set
+#[derive(Clone)]
+struct FailingAcl(Arc<AtomicBool>);
+
+impl Authorizer for FailingAcl {
+    async fn authorize_publish(
+        &self,
+        _client: HookClient<'_>,
+        topic_name: &TopicName,
+    ) -> HookResult<bool> {
+        if topic_name.starts_with("private/") && self.0.load(Ordering::SeqCst) {
+            return Err(HookError::Internal);
+        }
+        Ok(true)
+    }
+}
+
+fn private_will(c: &mut Connect) {
+    c.last_will = Some(LastWill {
+        qos: QoS::Level1,
+        retain: false,
+        topic_name: TopicName::try_from("private/1".to_owned()).unwrap(),
+        payload: Bytes::from(vec![1, 2, 3, 4]),
+        properties: Default::default(),
+    });
+}
+
+#[tokio::test]
+async fn test_will_not_authorized_at_connect() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let hook = ComposedHook::new().authorizer(PrivateAcl(Arc::new(AtomicBool::new(false))));
+    let (conn, mut client) = MockConn::new_with_global(111, global);
+    let task = client.start_with_hook(conn, hook);
+
+    client.send_connect("client id", private_will).await;
+    let pkt = client.read_packet().await;
+    if let Packet::Connack(connack) = pkt {
+        assert_eq!(connack.reason_code, ConnectReasonCode::NotAuthorized);
+    } else {
+        panic!("invalid packet: {pkt:?}");
+    }
+    sleep(Duration::from_millis(10)).await;
+    assert!(task.is_finished());
+}
+
+#[tokio::test]
+async fn test_will_not_authorized_at_publish() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let allowed = Arc::new(AtomicBool::new(true));
+    let hook = ComposedHook::new().authorizer(PrivateAcl(Arc::clone(&allowed)));
+    let (task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    let (conn, mut client2) = MockConn::new_with_global(222, global);
+    let task2 = client2.start_with_hook(conn, hook);
+
+    client1.connect("client id 1", true, false).await;
+    client1
+        .subscribe(
+            11,
+            vec![("private/1", SubscriptionOptions::new(QoS::Level1))],
+        )
+        .await;
+
+    // The will is authorized at connect time, but not when it's published
+    client2
+        .connect_with("client id 2", private_will, |_| ())
+        .await;
+    allowed.store(false, Ordering::SeqCst);
+    client2.write_data(b"".to_vec()).await;
+    sleep(Duration::from_millis(10)).await;
+    assert!(task2.is_finished());
+
+    assert!(client1.try_read_packet_is_empty());
+    assert!(!task1.is_finished());
+}
+
+#[tokio::test]
+async fn test_will_hook_error() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let failing = Arc::new(AtomicBool::new(false));
+    let hook = ComposedHook::new().authorizer(FailingAcl(Arc::clone(&failing)));
+    let (task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    let (conn, mut client2) = MockConn::new_with_global(222, Arc::clone(&global));
+    let task2 = client2.start_with_hook(conn, hook);
+
+    client1.connect("client id 1", true, false).await;
+    client1
+        .subscribe(
+            11,
+            vec![("private/1", SubscriptionOptions::new(QoS::Level1))],
+        )
+        .await;
+
+    // The will is dropped, and the session is still removed
+    client2
+        .connect_with("client id 2", private_will, |_| ())
+        .await;
+    failing.store(true, Ordering::SeqCst);
+    client2.write_data(b"".to_vec()).await;
+    sleep(Duration::from_millis(10)).await;
+    assert!(task2.is_finished());
+    assert_eq!(global.clients_count(), 1);
+
+    assert!(client1.try_read_packet_is_empty());
+    assert!(!task1.is_finished());
+}
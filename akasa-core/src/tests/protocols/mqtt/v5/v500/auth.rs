@@ -9,9 +9,8 @@ use tokio::time::sleep;
 
 use crate::config::{Config, SaslMechanism, ScramPasswordInfo};
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::super::ClientV5;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_auth_simple_success() {
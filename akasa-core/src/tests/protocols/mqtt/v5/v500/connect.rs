@@ -1,3 +1,4 @@
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -7,11 +8,10 @@ use mqtt_proto::*;
 use tokio::sync::oneshot;
 use tokio::time::sleep;
 
-use crate::config::Config;
+use crate::config::{ClientIdFormat, Config, RedirectConfig};
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::super::ClientV5;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
 
 async fn test_session_expired_with(first_clean_start: bool) {
     let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
@@ -165,6 +165,39 @@ async fn test_receive_max_client() {
     assert!(client2.try_read_packet_is_empty());
 }
 
+#[tokio::test]
+async fn test_hook_max_inflight() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+
+    client1.connect("publisher", true, false).await;
+    // The test hook set max inflight to 1 for `tiny-*` clients
+    client2.connect("tiny-subscriber", true, false).await;
+    let sub_topics = vec![("xyz/1", SubscriptionOptions::new(QoS::Level1))];
+    client2.subscribe(2, sub_topics).await;
+
+    for pub_pid in 1..3u16 {
+        client1
+            .publish(QoS::Level1, pub_pid, "xyz/1", pub_pid.to_string(), |_| ())
+            .await;
+    }
+    client2
+        .recv_publish(QoS::Level1, 1, "xyz/1", "1", |_| ())
+        .await;
+    sleep(Duration::from_millis(50)).await;
+    // Reach max inflight
+    assert!(client2.try_read_packet_is_empty());
+
+    client2.send_puback(1).await;
+    client2
+        .recv_publish(QoS::Level1, 2, "xyz/1", "2", |_| ())
+        .await;
+    client2.send_puback(2).await;
+    sleep(Duration::from_millis(20)).await;
+    assert!(client2.try_read_packet_is_empty());
+}
+
 #[tokio::test]
 async fn test_receive_max_server() {
     let mut config = Config::new_allow_anonymous();
@@ -397,6 +430,103 @@ async fn test_connack_properties() {
     assert!(!task.is_finished());
 }
 
+#[tokio::test]
+async fn test_session_expiry_interval_range() {
+    let mut config = Config::new_allow_anonymous();
+    config.min_session_expiry_interval = 30;
+    config.max_session_expiry_interval = 60;
+    assert!(config.is_valid());
+    let global = Arc::new(GlobalState::new(config));
+
+    // 0 is kept, the lower and greater values are clamped
+    for (requested, expected) in [(0, None), (10, Some(30)), (40, None), (100, Some(60))] {
+        let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+        let mut connect = Connect::new(Arc::new("client".to_owned()), 10);
+        connect.clean_start = true;
+        connect.properties.session_expiry_interval = Some(requested);
+        client.write_packet(connect.into()).await;
+        let pkt = client.read_packet().await;
+        if let Packet::Connack(connack) = pkt {
+            assert_eq!(connack.reason_code, ConnectReasonCode::Success);
+            assert_eq!(connack.properties.session_expiry_interval, expected);
+        } else {
+            panic!("invalid packet: {pkt:?}");
+        }
+        client.disconnect_normal().await;
+        sleep(Duration::from_millis(20)).await;
+        assert!(task.is_finished());
+    }
+}
+
+#[tokio::test]
+async fn test_listener_keep_alive() {
+    let mut config = Config::new_allow_anonymous();
+    config.listeners.mqtt.as_mut().unwrap().server_keep_alive = Some(20);
+    let global = Arc::new(GlobalState::new(config));
+
+    // Both the greater and lower keep alive are overridden
+    for keep_alive in [10, 60] {
+        let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+        let mut connect = Connect::new(Arc::new("client".to_owned()), keep_alive);
+        connect.clean_start = true;
+        client.write_packet(connect.into()).await;
+        let pkt = client.read_packet().await;
+        if let Packet::Connack(connack) = pkt {
+            assert_eq!(connack.reason_code, ConnectReasonCode::Success);
+            assert_eq!(connack.properties.server_keep_alive, Some(20));
+        } else {
+            panic!("invalid packet: {pkt:?}");
+        }
+        client.disconnect_normal().await;
+        sleep(Duration::from_millis(20)).await;
+        assert!(task.is_finished());
+    }
+}
+
+#[tokio::test]
+async fn test_reload_config() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (_task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client-1", true, false).await;
+
+    let mut config = Config::new_allow_anonymous();
+    config.max_allowed_qos = 1;
+    config.max_in_db_pending_messages = 1024;
+    let changes = global.reload_config(config).unwrap();
+    assert_eq!(changes.applied, vec!["max_allowed_qos"]);
+    assert_eq!(changes.restart_required, vec!["max_in_db_pending_messages"]);
+    assert!(global.config().max_allowed_qos == 1);
+
+    // Only the quotas state file requires restart
+    let mut config = Config::clone(&global.config());
+    config.quotas.state_file = Some("quotas.json".into());
+    let changes = global.reload_config(config.clone()).unwrap();
+    assert!(changes.applied.is_empty());
+    assert_eq!(changes.restart_required, vec!["quotas.state_file"]);
+    config.quotas.reset_hour = 1;
+    let changes = global.reload_config(config).unwrap();
+    assert_eq!(changes.applied, vec!["quotas.reset_hour"]);
+    assert!(changes.restart_required.is_empty());
+
+    // invalid config will not be applied
+    let mut config = Config::new_allow_anonymous();
+    config.max_allowed_qos = 3;
+    assert!(global.reload_config(config).is_err());
+    assert!(global.config().max_allowed_qos == 1);
+
+    // new connection will see the new config
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    let connect = Connect::new(Arc::new("client-2".to_owned()), 32);
+    client2.write_packet(connect.into()).await;
+    let pkt = client2.read_packet().await;
+    if let Packet::Connack(connack) = pkt {
+        assert_eq!(connack.reason_code, ConnectReasonCode::Success);
+        assert_eq!(connack.properties.max_qos, Some(QoS::Level1));
+    } else {
+        panic!("invalid packet: {pkt:?}");
+    }
+}
+
 #[tokio::test]
 async fn test_retain_not_supported() {
     let mut config = Config::new_allow_anonymous();
@@ -453,6 +583,125 @@ async fn test_will_qos_not_supported() {
     assert!(task.is_finished());
 }
 
+#[tokio::test]
+async fn test_redirect_connect() {
+    let mut config = Config::new_allow_anonymous();
+    config.redirect = Some(RedirectConfig {
+        server_reference: "other.example.com:1883".to_owned(),
+        permanent: true,
+    });
+    let global = Arc::new(GlobalState::new(config));
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+
+    let connect = Connect::new(Arc::new("client".to_owned()), 32);
+    client.write_packet(connect.into()).await;
+    let pkt = client.read_packet().await;
+    if let Packet::Connack(connack) = pkt {
+        assert_eq!(connack.reason_code, ConnectReasonCode::ServerMoved);
+        assert_eq!(
+            connack
+                .properties
+                .server_reference
+                .as_deref()
+                .map(|s| s.as_str()),
+            Some("other.example.com:1883")
+        );
+    } else {
+        panic!("invalid packet: {pkt:?}");
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+}
+
+#[tokio::test]
+async fn test_redirect_online() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    assert!(
+        !global
+            .redirect_session("client", Arc::new("other".to_owned()), false)
+            .await
+    );
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+    assert!(
+        global
+            .redirect_session("client", Arc::new("other".to_owned()), false)
+            .await
+    );
+    let pkt = client.read_packet().await;
+    if let Packet::Disconnect(disconnect) = pkt {
+        assert_eq!(
+            disconnect.reason_code,
+            DisconnectReasonCode::UseAnotherServer
+        );
+        assert_eq!(
+            disconnect
+                .properties
+                .server_reference
+                .as_deref()
+                .map(|s| s.as_str()),
+            Some("other")
+        );
+    } else {
+        panic!("invalid packet: {pkt:?}");
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+}
+
+#[tokio::test]
+async fn test_response_information() {
+    let mut config = Config::new_allow_anonymous();
+    // The test hook denies all the topics under `denied/`
+    config.response_topic_prefix = Some("denied".to_owned());
+    let global = Arc::new(GlobalState::new(config));
+    let (_task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+
+    let mut connect = Connect::new(Arc::new("client".to_owned()), 32);
+    connect.properties.request_response_info = Some(true);
+    client.write_packet(connect.into()).await;
+    let pkt = client.read_packet().await;
+    if let Packet::Connack(connack) = pkt {
+        assert_eq!(connack.reason_code, ConnectReasonCode::Success);
+        assert_eq!(
+            connack
+                .properties
+                .response_info
+                .as_deref()
+                .map(|s| s.as_str()),
+            Some("denied/client")
+        );
+    } else {
+        panic!("invalid packet: {pkt:?}");
+    }
+
+    let sub_opts = SubscriptionOptions::new(QoS::Level1);
+    client
+        .send_subscribe(1, vec![("denied/other/#", sub_opts)])
+        .await;
+    client
+        .recv_suback(1, vec![SubscribeReasonCode::NotAuthorized])
+        .await;
+    client
+        .send_subscribe(2, vec![("denied/client/#", sub_opts)])
+        .await;
+    client
+        .recv_suback(2, vec![SubscribeReasonCode::GrantedQoS1])
+        .await;
+
+    client
+        .send_publish(QoS::Level1, 3, "denied/other/1", "xxx", |_| ())
+        .await;
+    client.recv_puback(3, PubackReasonCode::NotAuthorized).await;
+    client
+        .send_publish(QoS::Level0, 0, "denied/client/1", "xxx", |_| ())
+        .await;
+    client
+        .recv_publish(QoS::Level0, 0, "denied/client/1", "xxx", |_| ())
+        .await;
+}
+
 #[tokio::test]
 async fn test_will_delay_interval_reached() {
     let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
@@ -541,3 +790,72 @@ async fn test_will_delay_interval_not_reached() {
     sleep(Duration::from_millis(20)).await;
     assert!(!task2.is_finished());
 }
+
+#[tokio::test]
+async fn test_empty_client_id() {
+    let mut config = Config::new_allow_anonymous();
+    config.empty_client_id.prefix = "auto-".to_owned();
+    config.empty_client_id.format = ClientIdFormat::Simple;
+    let global = Arc::new(GlobalState::new(config));
+    let (_task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.send_connect("", |_| ()).await;
+    let pkt = client.read_packet().await;
+    if let Packet::Connack(connack) = pkt {
+        assert_eq!(connack.reason_code, ConnectReasonCode::Success);
+        let client_id = connack.properties.assigned_client_id.expect("assigned");
+        assert!(client_id.starts_with("auto-"));
+        assert_eq!(client_id.len(), 5 + 32);
+    } else {
+        panic!("invalid received packet: {:?}", pkt);
+    }
+
+    // Reject zero-length client identifier
+    let mut config = Config::new_allow_anonymous();
+    config.empty_client_id.assign = false;
+    let (task, mut client) = MockConn::start(222, config);
+    client.send_connect("", |_| ()).await;
+    let pkt = client.read_packet().await;
+    if let Packet::Connack(connack) = pkt {
+        assert_eq!(
+            connack.reason_code,
+            ConnectReasonCode::ClientIdentifierNotValid
+        );
+    } else {
+        panic!("invalid received packet: {:?}", pkt);
+    }
+    sleep(Duration::from_millis(10)).await;
+    assert!(task.is_finished());
+}
+
+#[tokio::test]
+async fn test_connect_memory_pressure() {
+    let mut config = Config::new_allow_anonymous();
+    config.memory_limit.max_bytes = Some(10);
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task0, mut client0) = MockConn::start_with_global(100, Arc::clone(&global));
+    client0.connect("publisher", true, false).await;
+    client0
+        .publish(QoS::Level1, 1, "abc/1", vec![0; 9], |p| p.retain = true)
+        .await;
+    assert_eq!(global.accounted_memory(), 9);
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    let connect = Connect::new(Arc::new("client".to_owned()), 32);
+    client.write_packet(connect.into()).await;
+    let pkt = client.read_packet().await;
+    if let Packet::Connack(connack) = pkt {
+        assert_eq!(connack.reason_code, ConnectReasonCode::ServerBusy);
+    } else {
+        panic!("invalid packet: {pkt:?}");
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+    assert_eq!(
+        global
+            .metrics
+            .memory_refused_connects
+            .load(Ordering::Relaxed),
+        1
+    );
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use mqtt_proto::v5::*;
+use mqtt_proto::*;
+
+use crate::config::Config;
+use crate::state::GlobalState;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
+
+#[tokio::test]
+async fn test_strict_diagnostics() {
+    let mut config = Config::new_allow_anonymous();
+    config.strict_diagnostics = true;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client1.connect("client 1", true, false).await;
+    client1
+        .subscribe(
+            1,
+            vec![("$SYS/diagnostics", SubscriptionOptions::new(QoS::Level0))],
+        )
+        .await;
+
+    // The dup flag of a QoS 0 message must be 0
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client2.connect("client 2", true, false).await;
+    client2
+        .send_publish(QoS::Level0, 0, "a/b", vec![1, 2], |p| p.dup = true)
+        .await;
+    let received_pkt = client2.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::ProtocolError);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+
+    let packet = client1.read_packet().await;
+    let publish = match packet {
+        Packet::Publish(publish) => publish,
+        _ => panic!("invalid packet: {packet:?}"),
+    };
+    assert_eq!(&*publish.topic_name, "$SYS/diagnostics");
+    let diagnostic: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+    assert_eq!(diagnostic["client_identifier"], "client 2");
+    assert_eq!(diagnostic["protocol"], "V500");
+    assert_eq!(diagnostic["packet"], "PUBLISH");
+    assert_eq!(diagnostic["field"], "dup flag");
+    assert_eq!(diagnostic["rule"], "MQTT-3.3.1-2");
+    assert_eq!(diagnostic["reason"], "invalid dup flag in qos0 message");
+}
@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use futures_lite::StreamExt;
+use mqtt_proto::v5::*;
+use mqtt_proto::*;
+
+use crate::config::Config;
+use crate::events::ClientEvent;
+use crate::state::GlobalState;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::{MockConn, MockConnControl};
+
+async fn recv_event(client: &mut MockConnControl) -> serde_json::Value {
+    let packet = client.read_packet().await;
+    let publish = match packet {
+        Packet::Publish(publish) => publish,
+        _ => panic!("invalid packet: {packet:?}"),
+    };
+    let event: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+    assert_eq!(
+        &*publish.topic_name,
+        format!("$SYS/events/{}", event["event"].as_str().unwrap())
+    );
+    event
+}
+
+#[tokio::test]
+async fn test_client_events() {
+    let mut config = Config::new_allow_anonymous();
+    config.events.topic = true;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client1.connect("client 1", true, false).await;
+    client1
+        .subscribe(
+            1,
+            vec![("$SYS/events/#", SubscriptionOptions::new(QoS::Level0))],
+        )
+        .await;
+    let event = recv_event(&mut client1).await;
+    assert_eq!(event["event"], "subscribed");
+    assert_eq!(event["client_identifier"], "client 1");
+    assert_eq!(event["topic_filter"], "$SYS/events/#");
+
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client2.connect("client 2", true, false).await;
+    let event = recv_event(&mut client1).await;
+    assert_eq!(event["event"], "connected");
+    assert_eq!(event["client_identifier"], "client 2");
+    assert_eq!(event["protocol"], "V500");
+    assert_eq!(event["clean_start"], true);
+
+    client2
+        .subscribe(1, vec![("a/b", SubscriptionOptions::new(QoS::Level1))])
+        .await;
+    let event = recv_event(&mut client1).await;
+    assert_eq!(event["event"], "subscribed");
+    assert_eq!(event["topic_filter"], "a/b");
+    assert_eq!(event["qos"], 1);
+
+    client2.send_unsubscribe(2, vec!["a/b", "c/d"]).await;
+    client2
+        .recv_unsuback(
+            2,
+            vec![
+                UnsubscribeReasonCode::Success,
+                UnsubscribeReasonCode::NoSubscriptionExisted,
+            ],
+        )
+        .await;
+    let event = recv_event(&mut client1).await;
+    assert_eq!(event["event"], "unsubscribed");
+    assert_eq!(event["topic_filter"], "a/b");
+
+    client2.disconnect_normal().await;
+    let event = recv_event(&mut client1).await;
+    assert_eq!(event["event"], "disconnected");
+    assert_eq!(event["client_identifier"], "client 2");
+    assert_eq!(event["reason"], "client disconnected");
+}
+
+#[tokio::test]
+async fn test_event_stream() {
+    // The streams receive the events even if `Config.events` is not enabled
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let mut events = Box::pin(global.events());
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client 1", true, false).await;
+    client
+        .subscribe(1, vec![("a/+", SubscriptionOptions::new(QoS::Level1))])
+        .await;
+    client
+        .send_publish(QoS::Level0, 0, "a/1", "hello", |_| ())
+        .await;
+    client
+        .recv_publish(QoS::Level0, 0, "a/1", "hello", |_| ())
+        .await;
+    client.disconnect_normal().await;
+    assert!(task.await.unwrap().is_ok());
+
+    let event = events.next().await.unwrap();
+    assert!(matches!(
+        event,
+        ClientEvent::Connected { ref client_identifier, .. } if client_identifier == "client 1"
+    ));
+    assert_eq!(
+        events.next().await.unwrap(),
+        ClientEvent::Subscribed {
+            client_identifier: "client 1".to_owned(),
+            topic_filter: "a/+".to_owned(),
+            qos: 1,
+        }
+    );
+    assert_eq!(
+        events.next().await.unwrap(),
+        ClientEvent::Published {
+            client_identifier: "client 1".to_owned(),
+            topic_name: "a/1".to_owned(),
+            qos: 0,
+            retain: false,
+            payload_len: 5,
+            receivers: 1,
+        }
+    );
+    let event = events.next().await.unwrap();
+    assert!(matches!(
+        event,
+        ClientEvent::Disconnected { ref reason, .. } if reason == "client disconnected"
+    ));
+    assert!(global.has_event_streams());
+    drop(events);
+    // The dropped streams are removed by the next event
+    global.emit_event(ClientEvent::Unsubscribed {
+        client_identifier: "client 1".to_owned(),
+        topic_filter: "a/+".to_owned(),
+    });
+    assert!(!global.has_event_streams());
+}
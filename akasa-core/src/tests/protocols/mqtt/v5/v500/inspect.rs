@@ -0,0 +1,136 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use mqtt_proto::v5::*;
+use mqtt_proto::*;
+
+use crate::config::Config;
+use crate::state::GlobalState;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
+
+#[tokio::test]
+async fn test_inspect_session() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    assert!(global.inspect_session("client 1").await.is_none());
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client
+        .connect_with(
+            "client 1",
+            |c| {
+                c.clean_start = false;
+                c.properties.session_expiry_interval = Some(60);
+                c.last_will = Some(LastWill {
+                    qos: QoS::Level0,
+                    retain: false,
+                    topic_name: TopicName::try_from("will/1".to_owned()).unwrap(),
+                    payload: Bytes::from(vec![1, 2, 3, 4]),
+                    properties: Default::default(),
+                });
+            },
+            |_| (),
+        )
+        .await;
+    client
+        .subscribe(
+            1,
+            vec![
+                ("abc/1", SubscriptionOptions::new(QoS::Level1)),
+                ("abc/#", SubscriptionOptions::new(QoS::Level0)),
+            ],
+        )
+        .await;
+
+    let info = global.inspect_session("client 1").await.unwrap();
+    assert!(info.online);
+    assert!(info.has_will);
+    assert!(!info.clean_start);
+    assert_eq!(info.session_expiry_interval, Some(60));
+    assert_eq!(info.session_expiry_at, None);
+    assert_eq!(info.pending_len, 0);
+    let subscriptions: Vec<_> = info
+        .subscriptions
+        .iter()
+        .map(|sub| (sub.topic_filter.as_str(), sub.qos))
+        .collect();
+    assert_eq!(subscriptions, vec![("abc/#", 0), ("abc/1", 1)]);
+    assert_eq!(global.sessions(), vec![("client 1".to_owned(), true)]);
+
+    client.disconnect_normal().await;
+    assert!(task.await.unwrap().is_ok());
+
+    let info = global.inspect_session("client 1").await.unwrap();
+    assert!(!info.online);
+    // the will message is removed after normal disconnect
+    assert!(!info.has_will);
+    assert!(info.session_expiry_at.is_some());
+    assert_eq!(global.sessions(), vec![("client 1".to_owned(), false)]);
+}
+
+#[tokio::test]
+async fn test_query_clients() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client1.connect("client 1", false, false).await;
+    client1
+        .subscribe(1, vec![("abc/#", SubscriptionOptions::new(QoS::Level1))])
+        .await;
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client2.connect("client 2", true, false).await;
+    client2
+        .subscribe(
+            1,
+            vec![
+                ("$share/g1/abc/+", SubscriptionOptions::new(QoS::Level0)),
+                ("xyz", SubscriptionOptions::new(QoS::Level2)),
+            ],
+        )
+        .await;
+
+    assert_eq!(global.online_clients(), vec!["client 1", "client 2"]);
+    assert!(global.lookup_client("client 1").unwrap().1);
+    assert!(global.lookup_client("client 3").is_none());
+    let subscriptions: Vec<_> = global
+        .client_subscriptions("client 2")
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|sub| (sub.topic_filter, sub.qos))
+        .collect();
+    assert_eq!(
+        subscriptions,
+        vec![("$share/g1/abc/+".to_owned(), 0), ("xyz".to_owned(), 2)]
+    );
+
+    let matches = global.topic_matches(&TopicName::try_from("abc/1".to_owned()).unwrap());
+    let matches: Vec<_> = matches
+        .iter()
+        .map(|m| {
+            (
+                m.topic_filter.as_str(),
+                m.client_identifier.as_str(),
+                m.qos,
+                m.shared_group.as_deref(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        matches,
+        vec![
+            ("abc/#", "client 1", 1, None),
+            ("abc/+", "client 2", 0, Some("g1")),
+        ]
+    );
+    assert!(global
+        .topic_matches(&TopicName::try_from("other".to_owned()).unwrap())
+        .is_empty());
+
+    client1.disconnect_normal().await;
+    assert!(task1.await.unwrap().is_ok());
+    assert_eq!(global.online_clients(), vec!["client 2"]);
+    assert!(!global.lookup_client("client 1").unwrap().1);
+    // The offline session still receives the messages
+    let matches = global.topic_matches(&TopicName::try_from("abc/1".to_owned()).unwrap());
+    assert_eq!(matches[0].client_identifier, "client 1");
+}
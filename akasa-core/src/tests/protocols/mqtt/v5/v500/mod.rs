@@ -1,5 +1,11 @@
 mod auth;
 mod connect;
+mod diagnostics;
+mod events;
+mod inspect;
+mod packet_trace;
 mod publish;
 mod shared_subscription;
 mod subscribe;
+mod top_talkers;
+mod topic_stats;
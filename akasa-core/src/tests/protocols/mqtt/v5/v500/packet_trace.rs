@@ -0,0 +1,116 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use mqtt_proto::v5::*;
+use mqtt_proto::*;
+use tokio::time::sleep;
+
+use crate::config::Config;
+use crate::protocols::mqtt::{
+    load_packet_records, replay_packets, PacketDirection, PacketTraceOptions,
+};
+use crate::server::{handle_accept, ConnectionArgs};
+use crate::state::GlobalState;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::{MockConn, TestHook};
+
+#[tokio::test]
+async fn test_packet_trace() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let options = PacketTraceOptions {
+        raw_bytes: true,
+        ..Default::default()
+    };
+    global.packet_tracer.start("client-1", options).unwrap();
+    assert!(global.packet_tracer.is_active());
+
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client1.connect("client-1", true, false).await;
+    client2.connect("client-2", true, false).await;
+    client1
+        .subscribe(1, vec![("abc/1", SubscriptionOptions::new(QoS::Level0))])
+        .await;
+    client2
+        .subscribe(1, vec![("abc/1", SubscriptionOptions::new(QoS::Level0))])
+        .await;
+
+    let records = global.packet_tracer.stop("client-1").unwrap();
+    let summaries: Vec<_> = records
+        .iter()
+        .map(|r| (r.direction, r.summary.split('(').next().unwrap().to_owned()))
+        .collect();
+    assert_eq!(
+        summaries,
+        vec![
+            (PacketDirection::In, "Connect".to_owned()),
+            (PacketDirection::Out, "Connack".to_owned()),
+            (PacketDirection::In, "Subscribe".to_owned()),
+            (PacketDirection::Out, "Suback".to_owned()),
+        ]
+    );
+    assert!(records.iter().all(|r| r.raw.is_some()));
+    assert!(!global.packet_tracer.is_active());
+    assert!(global.packet_tracer.records("client-1").is_none());
+}
+
+#[tokio::test]
+async fn test_packet_replay() {
+    let path = std::env::temp_dir().join(format!("akasa-trace-{}.log", uuid::Uuid::new_v4()));
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let options = PacketTraceOptions {
+        raw_bytes: true,
+        file: Some(path.clone()),
+        ..Default::default()
+    };
+    global.packet_tracer.start("client-1", options).unwrap();
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client1.connect("client-1", true, false).await;
+    client1
+        .publish(QoS::Level1, 1, "abc/1", "replay", |_| ())
+        .await;
+    client1.disconnect_normal().await;
+    sleep(Duration::from_millis(20)).await;
+    global.packet_tracer.stop("client-1").unwrap();
+
+    let records = load_packet_records(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let directions: Vec<_> = records.iter().map(|r| r.direction).collect();
+    assert_eq!(
+        directions,
+        vec![
+            PacketDirection::In,
+            PacketDirection::Out,
+            PacketDirection::In,
+            PacketDirection::Out,
+            PacketDirection::In,
+        ]
+    );
+
+    // Replay to another server
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client2.connect("client-2", true, false).await;
+    client2
+        .subscribe(1, vec![("abc/1", SubscriptionOptions::new(QoS::Level0))])
+        .await;
+
+    let (client, server) = tokio::io::duplex(4096);
+    let conn_args = ConnectionArgs {
+        addr: global.config().listeners.mqtt.clone().unwrap().addr,
+        reuse_port: false,
+        proxy: false,
+        proxy_tls_termination: false,
+        websocket: false,
+        tls_acceptor: None,
+        max_qos: None,
+        server_keep_alive: None,
+    };
+    let peer = "127.0.0.1:333".parse().unwrap();
+    let task = tokio::spawn(handle_accept(server, conn_args, peer, TestHook, global));
+    assert_eq!(replay_packets(client, &records, 10.0).await.unwrap(), 3);
+    client2
+        .recv_publish(QoS::Level0, 0, "abc/1", "replay", |_| ())
+        .await;
+    task.await.unwrap().unwrap();
+}
@@ -1,16 +1,20 @@
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use mqtt_proto::v5::*;
 use mqtt_proto::*;
 use tokio::time::sleep;
 
-use crate::config::Config;
+use crate::config::{
+    Config, OverflowPolicy, PayloadLimit, PayloadSchema, PendingLimit, ReservedTopicException,
+    RetainLimitPolicy, SchemaFormat, ValidationMode,
+};
+use crate::delayed;
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::super::ClientV5;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_payload_is_not_utf8() {
@@ -316,6 +320,270 @@ async fn test_topic_alias_not_found() {
     assert!(task.is_finished());
 }
 
+#[tokio::test]
+async fn test_server_topic_alias() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client
+        .connect_with("client", |c| c.properties.topic_alias_max = Some(1), |_| ())
+        .await;
+    client
+        .subscribe(1, vec![("abc/+", SubscriptionOptions::new(QoS::Level0))])
+        .await;
+
+    // The alias is assigned at the second send, then the topic name is omitted
+    for (payload, topic, alias) in [
+        ("0", "abc/0", None),
+        ("1", "abc/0", Some(1)),
+        ("2", "", Some(1)),
+        ("3", "", Some(1)),
+    ] {
+        client
+            .send_publish(QoS::Level0, 0, "abc/0", payload, |_| ())
+            .await;
+        client
+            .recv_publish(QoS::Level0, 0, topic, payload, |p| {
+                p.properties.topic_alias = alias;
+            })
+            .await;
+    }
+    // The aliases are exhausted (Topic Alias Maximum is 1)
+    for payload in ["0", "1", "2"] {
+        client
+            .send_publish(QoS::Level0, 0, "abc/1", payload, |_| ())
+            .await;
+        client
+            .recv_publish(QoS::Level0, 0, "abc/1", payload, |_| ())
+            .await;
+    }
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(!task.is_finished());
+}
+
+#[tokio::test]
+async fn test_publish_qos_not_supported() {
+    let mut config = Config::new_allow_anonymous();
+    config.max_allowed_qos = 1;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+    client
+        .send_publish(QoS::Level2, 1, "abc/0", "0", |_| ())
+        .await;
+    let received_pkt = client.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::QoSNotSupported);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+}
+
+#[tokio::test]
+async fn test_publish_retain_not_supported() {
+    let mut config = Config::new_allow_anonymous();
+    config.retain_available = false;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+    client
+        .send_publish(QoS::Level1, 1, "abc/0", "0", |p| p.retain = true)
+        .await;
+    let received_pkt = client.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::RetainNotSupported);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+    assert!(global.retain_table.get_matches("#").is_empty());
+}
+
+#[tokio::test]
+async fn test_retain_limits() {
+    let mut config = Config::new_allow_anonymous();
+    config.retain_limits.max_messages = 1;
+    config.retain_limits.max_payload_size = 4;
+    config.retain_limits.policy = RetainLimitPolicy::Reject;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+    client
+        .send_publish(QoS::Level1, 1, "abc/0", "0", |p| p.retain = true)
+        .await;
+    client
+        .recv_puback(1, PubackReasonCode::NoMatchingSubscribers)
+        .await;
+    // Too many retained messages
+    client
+        .send_publish(QoS::Level1, 2, "abc/1", "1", |p| p.retain = true)
+        .await;
+    client.recv_puback(2, PubackReasonCode::QuotaExceeded).await;
+    // Payload too large
+    client
+        .send_publish(QoS::Level2, 3, "abc/0", "00000", |p| p.retain = true)
+        .await;
+    client.recv_pubrec(3, PubrecReasonCode::QuotaExceeded).await;
+    // Replace the exists retained message
+    client
+        .send_publish(QoS::Level1, 4, "abc/0", "00", |p| p.retain = true)
+        .await;
+    client
+        .recv_puback(4, PubackReasonCode::NoMatchingSubscribers)
+        .await;
+
+    assert_eq!(global.retain_table.len(), 1);
+    assert_eq!(global.retain_table.bytes(), 2);
+    assert_eq!(global.metrics.retain_dropped.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn test_publish_lenient_validation() {
+    let mut config = Config::new_allow_anonymous();
+    config.topic_limits.max_levels = 2;
+    config.validation_mode = ValidationMode::Lenient;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+    client
+        .send_publish(QoS::Level1, 1, "$abc/1", "0", |_| ())
+        .await;
+    client
+        .recv_puback(1, PubackReasonCode::TopicNameInvalid)
+        .await;
+    client
+        .send_publish(QoS::Level2, 2, "abc/1/2", "0", |_| ())
+        .await;
+    client
+        .recv_pubrec(2, PubrecReasonCode::TopicNameInvalid)
+        .await;
+    // QoS 0 message is silently dropped
+    client
+        .send_publish(QoS::Level0, 0, "abc/1/2", "0", |_| ())
+        .await;
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(client.try_read_packet_is_empty());
+    assert!(!task.is_finished());
+}
+
+#[tokio::test]
+async fn test_lenient_payload_is_not_utf8() {
+    let mut config = Config::new_allow_anonymous();
+    config.validation_mode = ValidationMode::Lenient;
+    let global = Arc::new(GlobalState::new(config));
+
+    // The payload format is validated by the decoder, not by the mode
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+    client
+        .send_publish(QoS::Level1, 1, "abc/1", vec![0xff, 0xff], |p| {
+            p.properties.payload_is_utf8 = Some(true);
+        })
+        .await;
+    let received_pkt = client.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::MalformedPacket);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+}
+
+#[tokio::test]
+async fn test_publish_reserved_topics() {
+    let mut config = Config::new_allow_anonymous();
+    config.reserved_topics.exceptions = vec![ReservedTopicException {
+        client_id: Some("bridge-*".to_owned()),
+        username: None,
+    }];
+    // The client identifiers are chosen by the clients
+    assert!(!config.is_valid());
+    config.reserved_topics.exceptions[0].username = Some("bridge".to_owned());
+    assert!(config.is_valid());
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client1.connect("client 1", true, false).await;
+    client1
+        .subscribe(
+            1,
+            vec![("$SYS/custom", SubscriptionOptions::new(QoS::Level1))],
+        )
+        .await;
+
+    // The ordinary client is not authorized
+    let (task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client2.connect("client 2", true, false).await;
+    client2
+        .send_publish(QoS::Level1, 1, "$SYS/custom", "0", |_| ())
+        .await;
+    client2
+        .recv_puback(1, PubackReasonCode::NotAuthorized)
+        .await;
+    client2
+        .send_publish(QoS::Level2, 2, "$CONTROL/reload", "0", |_| ())
+        .await;
+    client2
+        .recv_pubrec(2, PubrecReasonCode::NotAuthorized)
+        .await;
+
+    // The client identifier is matched, but the username is not
+    let (task4, mut client4) = MockConn::start_with_global(444, Arc::clone(&global));
+    client4.connect("bridge-2", true, false).await;
+    client4
+        .send_publish(QoS::Level1, 1, "$SYS/custom", "0", |_| ())
+        .await;
+    client4
+        .recv_puback(1, PubackReasonCode::NotAuthorized)
+        .await;
+
+    // The exception client
+    let (task3, mut client3) = MockConn::start_with_global(333, Arc::clone(&global));
+    client3
+        .connect_with(
+            "bridge-1",
+            |c| c.username = Some(Arc::new("bridge".to_owned())),
+            |_| (),
+        )
+        .await;
+    client3
+        .publish(QoS::Level1, 1, "$SYS/custom", "1", |_| ())
+        .await;
+    client1
+        .recv_publish(QoS::Level1, 1, "$SYS/custom", "1", |_| ())
+        .await;
+    // Not a reserved prefix
+    client3
+        .send_publish(QoS::Level0, 0, "$abc/1", "2", |_| ())
+        .await;
+    let received_pkt = client3.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::TopicNameInvalid);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(client1.try_read_packet_is_empty());
+    assert!(client2.try_read_packet_is_empty());
+    assert!(client4.try_read_packet_is_empty());
+    assert!(!task1.is_finished());
+    assert!(!task2.is_finished());
+    assert!(task3.is_finished());
+    assert!(!task4.is_finished());
+}
+
 #[tokio::test]
 async fn test_forbid_publish_subscription_id() {
     let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
@@ -398,3 +666,487 @@ async fn test_request_response() {
     sleep(Duration::from_millis(20)).await;
     assert!(!task1.is_finished());
 }
+
+#[tokio::test]
+async fn test_slow_consumer_disconnect() {
+    let mut config = Config::new_allow_anonymous();
+    config.slow_consumer.max_queue_depth = 2;
+    config.slow_consumer.disconnect = true;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task0, mut client0) = MockConn::start_with_global(100, Arc::clone(&global));
+    let (task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client0.connect("publisher", true, false).await;
+    client1.connect("subscriber", true, false).await;
+    client1
+        .subscribe(1, vec![("abc/1", SubscriptionOptions::new(QoS::Level1))])
+        .await;
+
+    // subscriber never send PUBACK
+    for pid in 1..=3 {
+        client0
+            .publish(QoS::Level1, pid, "abc/1", vec![pid as u8], |_| ())
+            .await;
+        client1
+            .recv_publish(QoS::Level1, pid, "abc/1", vec![pid as u8], |_| ())
+            .await;
+    }
+    let received_pkt = client1.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::QuotaExceeded);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(task1.await.unwrap().is_err());
+    assert_eq!(global.metrics.slow_consumers.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_slow_consumer_queue_duration() {
+    let mut config = Config::new_allow_anonymous();
+    config.slow_consumer.max_queue_depth = 2;
+    config.slow_consumer.max_queue_duration = 1;
+    config.slow_consumer.disconnect = true;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task0, mut client0) = MockConn::start_with_global(100, Arc::clone(&global));
+    let (task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client0.connect("publisher", true, false).await;
+    client1.connect("subscriber", true, false).await;
+    client1
+        .subscribe(1, vec![("abc/1", SubscriptionOptions::new(QoS::Level1))])
+        .await;
+
+    // subscriber never send PUBACK
+    let start = Instant::now();
+    for pid in 1..=3 {
+        client0
+            .publish(QoS::Level1, pid, "abc/1", vec![pid as u8], |_| ())
+            .await;
+        client1
+            .recv_publish(QoS::Level1, pid, "abc/1", vec![pid as u8], |_| ())
+            .await;
+    }
+    sleep(Duration::from_millis(200)).await;
+    assert!(client1.try_read_packet_is_empty());
+    assert_eq!(global.metrics.slow_consumers.load(Ordering::Relaxed), 0);
+
+    let received_pkt = client1.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::QuotaExceeded);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    sleep(Duration::from_millis(20)).await;
+    assert!(task1.await.unwrap().is_err());
+    assert_eq!(global.metrics.slow_consumers.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_pending_overflow_disconnect() {
+    let mut config = Config::new_allow_anonymous();
+    config.pending_limits = vec![PendingLimit {
+        client_id: Some("sub*".to_owned()),
+        username: None,
+        max_messages: Some(2),
+        max_bytes: None,
+        overflow_policy: Some(OverflowPolicy::Disconnect),
+        max_inflight: None,
+    }];
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task0, mut client0) = MockConn::start_with_global(100, Arc::clone(&global));
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client0.connect("publisher", true, false).await;
+    client1.connect("subscriber", true, false).await;
+    client1
+        .subscribe(1, vec![("abc/1", SubscriptionOptions::new(QoS::Level1))])
+        .await;
+
+    // subscriber never send PUBACK
+    for pid in 1..=2 {
+        client0
+            .publish(QoS::Level1, pid, "abc/1", vec![pid as u8], |_| ())
+            .await;
+        client1
+            .recv_publish(QoS::Level1, pid, "abc/1", vec![pid as u8], |_| ())
+            .await;
+    }
+    client0
+        .publish(QoS::Level1, 3, "abc/1", vec![3], |_| ())
+        .await;
+    let received_pkt = client1.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::QuotaExceeded);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+    assert_eq!(global.metrics.pending_dropped.load(Ordering::Relaxed), 1);
+    assert_eq!(
+        global
+            .metrics
+            .pending_overflow_disconnects
+            .load(Ordering::Relaxed),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_delayed_publish() {
+    let mut config = Config::new_allow_anonymous();
+    config.delayed_publish.enable = true;
+    let global = Arc::new(GlobalState::new(config));
+    tokio::spawn(delayed::run(Arc::clone(&global)));
+
+    let (_task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+    let sub_opts = SubscriptionOptions::new(QoS::Level0);
+    client.subscribe(1, vec![("abc/0", sub_opts)]).await;
+
+    client
+        .send_publish(QoS::Level1, 2, "$delayed/1/abc/0", "0", |_| ())
+        .await;
+    client.recv_puback_success(2).await;
+    // Expired when due
+    client
+        .send_publish(QoS::Level1, 3, "$delayed/1/abc/0", "1", |p| {
+            p.properties.message_expiry_interval = Some(1);
+        })
+        .await;
+    client.recv_puback_success(3).await;
+    client
+        .send_publish(QoS::Level1, 4, "$delayed/1/abc/0", "2", |p| {
+            p.properties.message_expiry_interval = Some(10);
+        })
+        .await;
+    client.recv_puback_success(4).await;
+    sleep(Duration::from_millis(500)).await;
+    assert!(client.try_read_packet_is_empty());
+    sleep(Duration::from_millis(700)).await;
+    client
+        .recv_publish(QoS::Level0, 0, "abc/0", "0", |_| ())
+        .await;
+    client
+        .recv_publish(QoS::Level0, 0, "abc/0", "2", |p| {
+            p.properties.message_expiry_interval = Some(9);
+        })
+        .await;
+
+    // The test hook checks the real topic
+    client
+        .send_publish(QoS::Level1, 5, "$delayed/0/denied/0", "0", |_| ())
+        .await;
+    client.recv_puback(5, PubackReasonCode::NotAuthorized).await;
+
+    client
+        .send_publish(QoS::Level1, 6, "$delayed/x/abc/0", "0", |_| ())
+        .await;
+    let received_pkt = client.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::TopicNameInvalid);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+}
+
+#[tokio::test]
+async fn test_qos0_shedding() {
+    let mut config = Config::new_allow_anonymous();
+    config.qos0_shedding.max_queue_depth = 1;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task0, mut client0) = MockConn::start_with_global(100, Arc::clone(&global));
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client0.connect("publisher", true, false).await;
+    client1.connect("subscriber", true, false).await;
+    client1
+        .subscribe(1, vec![("abc/1", SubscriptionOptions::new(QoS::Level1))])
+        .await;
+
+    // Dropped by the global overloaded state
+    global.set_overloaded(true);
+    client0
+        .publish(QoS::Level0, 0, "abc/1", vec![0], |_| ())
+        .await;
+    client0
+        .publish(QoS::Level1, 1, "abc/1", vec![1], |_| ())
+        .await;
+    client1
+        .recv_publish(QoS::Level1, 1, "abc/1", vec![1], |_| ())
+        .await;
+    global.set_overloaded(false);
+
+    // Dropped by the queue depth, since the subscriber not send PUBACK
+    client0
+        .publish(QoS::Level0, 0, "abc/1", vec![2], |_| ())
+        .await;
+    sleep(Duration::from_millis(20)).await;
+    assert!(client1.try_read_packet_is_empty());
+    client1.send_puback(1).await;
+    sleep(Duration::from_millis(20)).await;
+    client0
+        .publish(QoS::Level0, 0, "abc/1", vec![3], |_| ())
+        .await;
+    client1
+        .recv_publish(QoS::Level0, 0, "abc/1", vec![3], |_| ())
+        .await;
+    assert_eq!(global.metrics.qos0_shed.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn test_hook_queued_packets() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+    let (_task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+
+    // The test hook delays the publishes to `slow/*` topics
+    client
+        .send_publish(QoS::Level1, 1, "slow/1", vec![1], |_| ())
+        .await;
+    client
+        .send_publish(QoS::Level1, 2, "abc/1", vec![2], |_| ())
+        .await;
+    client.write_packet(Packet::Pingreq).await;
+    // The PINGREQ is handled while the hook request is in flight
+    assert_eq!(client.read_packet().await, Packet::Pingresp);
+    // The queued publish is handled after the hook request
+    client
+        .recv_puback(1, PubackReasonCode::NoMatchingSubscribers)
+        .await;
+    client
+        .recv_puback(2, PubackReasonCode::NoMatchingSubscribers)
+        .await;
+}
+
+#[tokio::test]
+async fn test_publish_fan_out_workers() {
+    let mut config = Config::new_allow_anonymous();
+    config.fan_out.min_receivers = 2;
+    let global = Arc::new(GlobalState::new(config));
+
+    let mut subscribers = Vec::new();
+    for conn_id in [111, 222, 333] {
+        let (task, mut client) = MockConn::start_with_global(conn_id, Arc::clone(&global));
+        client
+            .connect(format!("client {conn_id}"), true, false)
+            .await;
+        client
+            .subscribe(1, vec![("abc/+", SubscriptionOptions::new(QoS::Level1))])
+            .await;
+        subscribers.push((task, client));
+    }
+
+    let (_task, mut publisher) = MockConn::start_with_global(444, Arc::clone(&global));
+    publisher.connect("publisher", true, false).await;
+    for pid in [1, 2, 3] {
+        publisher
+            .publish(QoS::Level1, pid, "abc/1", pid.to_string(), |_| ())
+            .await;
+    }
+    // The messages to a receiver are kept in order
+    for (task, client) in &mut subscribers {
+        for pid in [1, 2, 3] {
+            client
+                .recv_publish(QoS::Level1, pid, "abc/1", pid.to_string(), |_| ())
+                .await;
+        }
+        assert!(!task.is_finished());
+    }
+    assert!(global.metrics.fan_out_jobs.load(Ordering::Relaxed) >= 3);
+
+    // The messages to a single receiver are not overtaken by the fan out ones
+    let (task, client) = &mut subscribers[0];
+    client
+        .subscribe(2, vec![("single/+", SubscriptionOptions::new(QoS::Level1))])
+        .await;
+    for pid in 4..10 {
+        let topic = if pid % 2 == 0 { "abc/1" } else { "single/1" };
+        publisher
+            .publish(QoS::Level1, pid, topic, pid.to_string(), |_| ())
+            .await;
+    }
+    for pid in 4..10 {
+        let topic = if pid % 2 == 0 { "abc/1" } else { "single/1" };
+        client
+            .recv_publish(QoS::Level1, pid, topic, pid.to_string(), |_| ())
+            .await;
+    }
+    assert!(!task.is_finished());
+}
+
+#[tokio::test]
+async fn test_publish_inbound_limit() {
+    let mut config = Config::new_allow_anonymous();
+    config.inbound_limit.max_messages_per_sec = 20;
+    config.inbound_limit.burst_messages = 2;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task, mut subscriber) = MockConn::start_with_global(111, Arc::clone(&global));
+    subscriber.connect("subscriber", true, false).await;
+    subscriber
+        .subscribe(1, vec![("abc/+", SubscriptionOptions::new(QoS::Level0))])
+        .await;
+
+    let start = Instant::now();
+    let mut publishers = Vec::new();
+    for (conn_id, name) in [(222, "a"), (333, "b")] {
+        let (task, mut publisher) = MockConn::start_with_global(conn_id, Arc::clone(&global));
+        publisher.connect(name, true, false).await;
+        for idx in 1..=3 {
+            publisher
+                .send_publish(QoS::Level0, 0, "abc/1", format!("{name}{idx}"), |_| ())
+                .await;
+        }
+        publishers.push((task, publisher));
+    }
+    let mut payloads = Vec::new();
+    for _ in 0..6 {
+        match subscriber.read_packet().await {
+            Packet::Publish(publish) => payloads.push(publish.payload.to_vec()),
+            packet => panic!("unexpected packet: {packet:?}"),
+        }
+    }
+    // More than the burst are received at the limited rate
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert!(global.metrics.inbound_throttled.load(Ordering::Relaxed) > 0);
+    // The messages of a publisher are kept in order
+    for name in ["a", "b"] {
+        let received: Vec<_> = payloads
+            .iter()
+            .filter(|payload| payload.starts_with(name.as_bytes()))
+            .cloned()
+            .collect();
+        let expected: Vec<_> = (1..=3)
+            .map(|idx| format!("{name}{idx}").into_bytes())
+            .collect();
+        assert_eq!(received, expected);
+    }
+    assert!(!task.is_finished());
+}
+
+#[tokio::test]
+async fn test_publish_payload_limits() {
+    let mut config = Config::new_allow_anonymous();
+    config.payload_limits = vec![
+        PayloadLimit {
+            topic_filter: "sensors/+/raw".to_owned(),
+            max_payload_size: 0,
+        },
+        PayloadLimit {
+            topic_filter: "sensors/#".to_owned(),
+            max_payload_size: 4,
+        },
+    ];
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+    client
+        .send_publish(QoS::Level1, 1, "sensors/1", "hello", |_| ())
+        .await;
+    client
+        .recv_puback(1, PubackReasonCode::PayloadFormatInvalid)
+        .await;
+    client
+        .send_publish(QoS::Level2, 2, "sensors", "hello", |_| ())
+        .await;
+    client
+        .recv_pubrec(2, PubrecReasonCode::PayloadFormatInvalid)
+        .await;
+    client
+        .send_publish(QoS::Level1, 3, "sensors/1/raw", "hello world", |_| ())
+        .await;
+    client
+        .recv_puback(3, PubackReasonCode::NoMatchingSubscribers)
+        .await;
+    client
+        .send_publish(QoS::Level1, 4, "other", "hello world", |_| ())
+        .await;
+    client
+        .recv_puback(4, PubackReasonCode::NoMatchingSubscribers)
+        .await;
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(client.try_read_packet_is_empty());
+    assert!(!task.is_finished());
+    assert_eq!(global.metrics.payload_rejected.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn test_publish_payload_schemas() {
+    let mut config = Config::new_allow_anonymous();
+    let format = SchemaFormat::JsonSchema(serde_json::json!({
+        "type": "object",
+        "required": ["temp"],
+    }));
+    config.payload_schemas = vec![
+        PayloadSchema {
+            topic_filter: "sensors/#".to_owned(),
+            format: format.clone(),
+            dead_letter_topic: None,
+        },
+        PayloadSchema {
+            topic_filter: "events/#".to_owned(),
+            format,
+            dead_letter_topic: Some("dead/events".to_owned()),
+        },
+    ];
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    let (task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client1.connect("client 1", true, false).await;
+    client1
+        .subscribe(1, vec![("#", SubscriptionOptions::new(QoS::Level0))])
+        .await;
+    client2.connect("client 2", true, false).await;
+
+    client2
+        .send_publish(QoS::Level1, 1, "sensors/1", r#"{"temp": 20}"#, |_| ())
+        .await;
+    client2.recv_puback_success(1).await;
+    client1
+        .recv_publish(QoS::Level0, 0, "sensors/1", r#"{"temp": 20}"#, |_| ())
+        .await;
+    client2
+        .send_publish(QoS::Level1, 2, "sensors/1", r#"{"x": 1}"#, |_| ())
+        .await;
+    client2
+        .recv_puback(2, PubackReasonCode::PayloadFormatInvalid)
+        .await;
+    client2
+        .send_publish(QoS::Level2, 3, "sensors/1", "20", |_| ())
+        .await;
+    client2
+        .recv_pubrec(3, PubrecReasonCode::PayloadFormatInvalid)
+        .await;
+
+    // Published to the dead letter topic instead
+    client2
+        .send_publish(QoS::Level1, 4, "events/1", r#"{"x": 1}"#, |_| ())
+        .await;
+    client2.recv_puback_success(4).await;
+    client1
+        .recv_publish(QoS::Level0, 0, "dead/events", r#"{"x": 1}"#, |p| {
+            p.properties.user_properties = vec![
+                UserProperty {
+                    name: Arc::new("topic".to_owned()),
+                    value: Arc::new("events/1".to_owned()),
+                },
+                UserProperty {
+                    name: Arc::new("error".to_owned()),
+                    value: Arc::new("/: failed required".to_owned()),
+                },
+            ];
+        })
+        .await;
+
+    sleep(Duration::from_millis(20)).await;
+    assert!(client1.try_read_packet_is_empty());
+    assert!(client2.try_read_packet_is_empty());
+    assert!(!task1.is_finished());
+    assert!(!task2.is_finished());
+    assert_eq!(global.metrics.schema_rejected.load(Ordering::Relaxed), 3);
+}
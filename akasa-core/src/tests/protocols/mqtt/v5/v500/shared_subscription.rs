@@ -9,9 +9,8 @@ use tokio::time::sleep;
 
 use crate::config::{Config, SharedSubscriptionMode};
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::super::ClientV5;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_shared_one_group() {
@@ -652,3 +651,103 @@ async fn test_shared_hash_client_id() {
         assert!(task.await.is_ok());
     }
 }
+
+#[tokio::test]
+async fn test_shared_round_robin() {
+    let mut config = Config::new_allow_anonymous();
+    config.shared_subscription_mode = SharedSubscriptionMode::RoundRobin;
+    let global = Arc::new(GlobalState::new(config));
+
+    // publisher
+    let (_task0, mut client0) = MockConn::start_with_global(100, Arc::clone(&global));
+
+    // subscriber
+    let (_task1, client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    let (_task2, client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    let (_task3, client3) = MockConn::start_with_global(333, Arc::clone(&global));
+    let (tx1, rx1) = oneshot::channel();
+    let (tx2, rx2) = oneshot::channel();
+    let (tx3, rx3) = oneshot::channel();
+
+    // Publisher connect
+    client0.connect("pub", true, false).await;
+
+    let (sync_tx, mut sync_rx) = mpsc::channel(3);
+    let (pkt_tx, mut pkt_rx) = mpsc::channel::<(usize, Packet)>(1);
+    let mut tasks = Vec::new();
+    for (idx, (topic, mut client, mut rx)) in [
+        ("$share/one/xyz", client1, rx1),
+        ("$share/one/xyz", client2, rx2),
+        ("$share/one/xyz", client3, rx3),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let sync_tx = sync_tx.clone();
+        let pkt_tx = pkt_tx.clone();
+        let task = tokio::spawn(async move {
+            let client_id = idx + 1;
+            client
+                .connect(format!("sub @{}", client_id), true, false)
+                .await;
+            let sub_topics = vec![(topic, SubscriptionOptions::new(QoS::Level0))];
+            client.subscribe(2, sub_topics).await;
+
+            // Subscribe is ready
+            sync_tx.send(()).await.unwrap();
+
+            loop {
+                tokio::select! {
+                    packet = client.read_packet() => {
+                        match &packet {
+                            Packet::Publish(_) => {},
+                            pkt => panic!("invalid packet: {:?}", pkt),
+                        }
+                        pkt_tx.send((idx, packet)).await.unwrap();
+                    }
+                    _ = &mut rx => { break; },
+                }
+            }
+            sleep(Duration::from_millis(100)).await;
+            assert!(client.try_read_packet_is_empty());
+        });
+        tasks.push(task);
+    }
+
+    // Wait 3 subscribers
+    for _ in 0..3 {
+        sync_rx.recv().await.unwrap();
+    }
+
+    let mut receiver_counts: HashMap<usize, usize> = HashMap::new();
+    let mut receivers = Vec::new();
+    for last_byte in 0..9u8 {
+        let data = vec![3, 5, 55, last_byte];
+        // send
+        client0
+            .send_publish(QoS::Level0, 0, "xyz", data.clone(), |_| ())
+            .await;
+        // receive
+        let (idx, pkt) = pkt_rx.recv().await.unwrap();
+        *receiver_counts.entry(idx).or_default() += 1;
+        receivers.push(idx);
+        match pkt {
+            Packet::Publish(publish) => assert_eq!(data, publish.payload),
+            _ => panic!("invalid packet"),
+        }
+    }
+    // Every member receives the messages in turn
+    assert_eq!(receiver_counts.len(), 3);
+    assert!(receiver_counts.values().all(|count| *count == 3));
+    assert_eq!(receivers[0..3], receivers[3..6]);
+    assert_eq!(receivers[0..3], receivers[6..9]);
+
+    for tx in [tx1, tx2, tx3] {
+        tx.send(()).unwrap();
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(client0.try_read_packet_is_empty());
+    for task in tasks {
+        assert!(task.await.is_ok());
+    }
+}
@@ -5,11 +5,10 @@ use mqtt_proto::v5::*;
 use mqtt_proto::*;
 use tokio::time::sleep;
 
-use crate::config::Config;
+use crate::config::{AutoSubscribeTopic, AutoSubscription, Config};
 use crate::state::GlobalState;
-use crate::tests::utils::MockConn;
-
-use super::super::ClientV5;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
 
 #[tokio::test]
 async fn test_simple_subscription_id() {
@@ -160,6 +159,31 @@ async fn test_shared_subscription_disabled() {
     assert!(!task.is_finished());
 }
 
+#[tokio::test]
+async fn test_shared_subscription_no_local() {
+    let global = Arc::new(GlobalState::new(Config::new_allow_anonymous()));
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+
+    client.connect("client", true, false).await;
+
+    let topic_filter = TopicFilter::try_from("$share/abc/0".to_owned()).unwrap();
+    let sub_pid = Pid::try_from(1).unwrap();
+    let mut sub_opts = SubscriptionOptions::new(QoS::Level1);
+    sub_opts.no_local = true;
+    let pkt = Subscribe::new(sub_pid, vec![(topic_filter, sub_opts)]);
+    client.write_packet(pkt.into()).await;
+
+    let received_pkt = client.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::ProtocolError);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+}
+
 #[tokio::test]
 async fn test_wildcard_subscription_disabled() {
     let mut config = Config::new_allow_anonymous();
@@ -506,3 +530,158 @@ async fn test_subscription_options_retain_do_not_send() {
     sleep(Duration::from_millis(20)).await;
     assert!(!task.is_finished());
 }
+
+#[tokio::test]
+async fn test_auto_subscriptions() {
+    let mut config = Config::new_allow_anonymous();
+    config.auto_subscriptions = vec![AutoSubscription {
+        client_id: Some("auto-*".to_owned()),
+        username: None,
+        topics: vec![AutoSubscribeTopic {
+            topic_filter: "auto/%c".to_owned(),
+            qos: 1,
+        }],
+    }];
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client1.connect("auto-1", true, false).await;
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client2.connect("other", true, false).await;
+
+    let info = global.inspect_session("auto-1").await.unwrap();
+    let subscriptions: Vec<_> = info
+        .subscriptions
+        .iter()
+        .map(|sub| (sub.topic_filter.as_str(), sub.qos))
+        .collect();
+    assert_eq!(subscriptions, vec![("auto/auto-1", 1)]);
+    let info = global.inspect_session("other").await.unwrap();
+    assert!(info.subscriptions.is_empty());
+
+    client2
+        .send_publish(QoS::Level0, 0, "auto/auto-1", "0", |_| ())
+        .await;
+    client1
+        .recv_publish(QoS::Level0, 0, "auto/auto-1", "0", |_| ())
+        .await;
+}
+
+#[tokio::test]
+async fn test_session_attributes() {
+    let mut config = Config::new_allow_anonymous();
+    config.auto_subscriptions = vec![AutoSubscription {
+        client_id: Some("tenant-*".to_owned()),
+        username: None,
+        topics: vec![AutoSubscribeTopic {
+            topic_filter: "tenants/%{tenant}/commands".to_owned(),
+            qos: 1,
+        }],
+    }];
+    let global = Arc::new(GlobalState::new(config));
+    let attributes = vec![("tenant".to_owned(), "acme".to_owned())];
+
+    // The attribute is set by the after connect hook (see `TestHook`)
+    let (task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    client1.connect("tenant-acme", false, false).await;
+    let info = global.inspect_session("tenant-acme").await.unwrap();
+    assert_eq!(info.attributes, attributes);
+    let subscriptions: Vec<_> = info
+        .subscriptions
+        .iter()
+        .map(|sub| (sub.topic_filter.as_str(), sub.qos))
+        .collect();
+    assert_eq!(subscriptions, vec![("tenants/acme/commands", 1)]);
+    client1.disconnect_normal().await;
+    assert!(task1.await.unwrap().is_ok());
+
+    // Kept with the session state
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client2.connect("tenant-acme", false, true).await;
+    let info = global.inspect_session("tenant-acme").await.unwrap();
+    assert_eq!(info.attributes, attributes);
+}
+
+#[tokio::test]
+async fn test_topic_limits() {
+    let mut config = Config::new_allow_anonymous();
+    config.topic_limits.max_length = 64;
+    config.topic_limits.max_levels = 4;
+    config.topic_limits.max_wildcards = 1;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+
+    let sub_pid = Pid::try_from(1).unwrap();
+    let sub_opts = SubscriptionOptions::new(QoS::Level1);
+    let topics = ["a/b/c/d", "a/b/c/d/e", "+/b/#", &"a".repeat(65)]
+        .into_iter()
+        .map(|filter| (TopicFilter::try_from(filter.to_owned()).unwrap(), sub_opts))
+        .collect();
+    client
+        .write_packet(Subscribe::new(sub_pid, topics).into())
+        .await;
+    assert_eq!(
+        client.read_packet().await,
+        Suback::new(
+            sub_pid,
+            vec![
+                SubscribeReasonCode::GrantedQoS1,
+                SubscribeReasonCode::TopicFilterInvalid,
+                SubscribeReasonCode::TopicFilterInvalid,
+                SubscribeReasonCode::TopicFilterInvalid,
+            ]
+        )
+        .into()
+    );
+
+    client
+        .send_publish(QoS::Level1, 2, "a/b/c/d/e", "0", |_| ())
+        .await;
+    let received_pkt = client.read_packet().await;
+    if let Packet::Disconnect(pkt) = received_pkt {
+        assert_eq!(pkt.reason_code, DisconnectReasonCode::TopicNameInvalid);
+    } else {
+        panic!("invalid received packet: {:?}", received_pkt);
+    }
+    sleep(Duration::from_millis(20)).await;
+    assert!(task.is_finished());
+}
+
+#[tokio::test]
+async fn test_max_subscriptions() {
+    let mut config = Config::new_allow_anonymous();
+    config.max_subscriptions = 2;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (task, mut client) = MockConn::start_with_global(111, Arc::clone(&global));
+    client.connect("client", true, false).await;
+
+    let sub_opts = SubscriptionOptions::new(QoS::Level1);
+    client
+        .send_subscribe(
+            1,
+            vec![("a/1", sub_opts), ("a/2", sub_opts), ("a/3", sub_opts)],
+        )
+        .await;
+    client
+        .recv_suback(
+            1,
+            vec![
+                SubscribeReasonCode::GrantedQoS1,
+                SubscribeReasonCode::GrantedQoS1,
+                SubscribeReasonCode::QuotaExceeded,
+            ],
+        )
+        .await;
+
+    // Subscribe the existing topic filter again, or after unsubscribed
+    client.subscribe(2, vec![("a/1", sub_opts)]).await;
+    client.send_unsubscribe(3, vec!["a/2"]).await;
+    client
+        .recv_unsuback(3, vec![UnsubscribeReasonCode::Success])
+        .await;
+    client.subscribe(4, vec![("a/3", sub_opts)]).await;
+    assert!(!task.is_finished());
+}
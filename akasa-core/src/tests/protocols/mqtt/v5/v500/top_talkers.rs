@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use mqtt_proto::v5::*;
+use mqtt_proto::*;
+
+use crate::config::Config;
+use crate::state::GlobalState;
+use crate::sys::publish_top_talkers;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
+
+#[tokio::test]
+async fn test_top_talkers() {
+    let mut config = Config::new_allow_anonymous();
+    config.top_talkers.count = 1;
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client1.connect("client 1", true, false).await;
+    client2.connect("client 2", true, false).await;
+    client1
+        .subscribe(
+            1,
+            vec![("$SYS/clients/top", SubscriptionOptions::new(QoS::Level0))],
+        )
+        .await;
+
+    // client 1: more messages, client 2: more bytes (QoS 1 for waiting the puback)
+    for pid in 1..4 {
+        client1.publish(QoS::Level1, pid, "a/b", "x", |_| ()).await;
+    }
+    client2
+        .publish(QoS::Level1, 1, "a/b", "0123456789", |_| ())
+        .await;
+    assert_eq!(global.client_stats.top_talkers().by_messages, Vec::new());
+
+    publish_top_talkers(&global);
+    let packet = client1.read_packet().await;
+    let publish = match packet {
+        Packet::Publish(publish) => publish,
+        _ => panic!("invalid packet: {packet:?}"),
+    };
+    assert_eq!(&*publish.topic_name, "$SYS/clients/top");
+    let report: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+    assert_eq!(report["interval"], 60);
+    assert_eq!(report["by_messages"].as_array().unwrap().len(), 1);
+    assert_eq!(report["by_messages"][0]["client_identifier"], "client 1");
+    assert_eq!(report["by_messages"][0]["messages"], 3);
+    assert_eq!(report["by_bytes"][0]["client_identifier"], "client 2");
+    assert_eq!(report["by_bytes"][0]["bytes"], 10);
+
+    let top_talkers = global.client_stats.top_talkers();
+    assert_eq!(top_talkers.by_messages[0].client_identifier, "client 1");
+
+    // A new interval started
+    publish_top_talkers(&global);
+    assert!(global.client_stats.top_talkers().by_messages.is_empty());
+}
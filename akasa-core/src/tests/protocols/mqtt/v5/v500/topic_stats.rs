@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use mqtt_proto::v5::*;
+use mqtt_proto::*;
+
+use crate::config::Config;
+use crate::state::GlobalState;
+use crate::sys::publish_topic_stats;
+use crate::testkit::v5::ClientV5;
+use crate::testkit::MockConn;
+
+#[tokio::test]
+async fn test_topic_stats() {
+    let mut config = Config::new_allow_anonymous();
+    config.topic_stats.prefixes = vec!["sensors/".to_owned(), "sensors/room1/".to_owned()];
+    let global = Arc::new(GlobalState::new(config));
+
+    let (_task1, mut client1) = MockConn::start_with_global(111, Arc::clone(&global));
+    let (_task2, mut client2) = MockConn::start_with_global(222, Arc::clone(&global));
+    client1.connect("client 1", true, false).await;
+    client2.connect("client 2", true, false).await;
+    client1
+        .subscribe(
+            1,
+            vec![
+                ("sensors/#", SubscriptionOptions::new(QoS::Level0)),
+                ("$SYS/topics/#", SubscriptionOptions::new(QoS::Level0)),
+            ],
+        )
+        .await;
+
+    for (topic, payload) in [
+        ("sensors/room1/temp", "21.5"),
+        ("sensors/room2/temp", "19"),
+        ("other/temp", "20"),
+    ] {
+        client2
+            .publish(QoS::Level0, 0, topic, payload, |_| ())
+            .await;
+        if topic.starts_with("sensors/") {
+            client1
+                .recv_publish(QoS::Level0, 0, topic, payload, |_| ())
+                .await;
+        }
+    }
+
+    let report: Vec<_> = global
+        .topic_stats
+        .report()
+        .into_iter()
+        .map(|t| {
+            (
+                t.prefix,
+                t.messages_in,
+                t.bytes_in,
+                t.messages_out,
+                t.bytes_out,
+            )
+        })
+        .collect();
+    assert_eq!(
+        report,
+        vec![
+            ("sensors/".to_owned(), 1, 2, 1, 2),
+            ("sensors/room1/".to_owned(), 1, 4, 1, 4),
+        ]
+    );
+
+    publish_topic_stats(&global);
+    for prefix in ["sensors", "sensors/room1"] {
+        let packet = client1.read_packet().await;
+        let publish = match packet {
+            Packet::Publish(publish) => publish,
+            _ => panic!("invalid packet: {packet:?}"),
+        };
+        assert_eq!(&*publish.topic_name, format!("$SYS/topics/{prefix}"));
+        let traffic: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(traffic["messages_in"], 1);
+    }
+}
+
+#[test]
+fn test_topic_stats_prefix_levels() {
+    let global = GlobalState::new(Config::new_allow_anonymous());
+    let prefixes = vec!["a/b".to_owned()];
+    for topic_name in ["a/b", "a/b/c", "a/bc", "a/bc/d", "a"] {
+        global.topic_stats.record(&prefixes, topic_name, 1, 0);
+    }
+    let report: Vec<_> = global
+        .topic_stats
+        .report()
+        .into_iter()
+        .map(|t| (t.prefix, t.messages_in))
+        .collect();
+    assert_eq!(report, vec![("a/b".to_owned(), 2)]);
+}
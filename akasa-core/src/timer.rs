@@ -0,0 +1,254 @@
+//! The shared hierarchical timer wheel of the session timers (session expiry,
+//! will delay, keep alive), instead of a tokio timer task per session.
+
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use flume::TrySendError;
+use parking_lot::{Mutex, RwLock};
+
+use crate::error::Error;
+use crate::state::{ClientId, ControlMessage, GlobalState};
+
+const TICK: Duration = Duration::from_millis(100);
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+// The ticks covered by the wheel (about 19 days), the later timers are placed
+// in the last slot and placed again when cascaded.
+const MAX_TICKS: u64 = 1 << (SLOT_BITS * LEVELS);
+
+pub(crate) enum TimerEvent {
+    SessionExpired {
+        client_id: ClientId,
+        connected_time: Instant,
+    },
+    WillDelayReached {
+        client_id: ClientId,
+        connected_time: Instant,
+    },
+    /// Check the last packet time every half keep alive interval
+    KeepAlive {
+        client_id: ClientId,
+        half_interval: Duration,
+        last_packet_time: Arc<RwLock<Instant>>,
+    },
+}
+
+pub(crate) struct Timers {
+    // The time of tick 0
+    start: Instant,
+    wheel: Mutex<TimerWheel<TimerEvent>>,
+    // The task advancing the wheel is started by the first timer
+    running: AtomicBool,
+}
+
+struct TimerWheel<T> {
+    // The current tick
+    now: u64,
+    // [level][slot] => [(deadline tick, value)], a slot of level N covers
+    // 64^N ticks.
+    levels: Vec<Vec<Vec<(u64, T)>>>,
+}
+
+impl Default for Timers {
+    fn default() -> Timers {
+        Timers {
+            start: Instant::now(),
+            wheel: Mutex::new(TimerWheel::new()),
+            running: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Timers {
+    // Take the timers due at the time
+    fn advance(&self, time: Instant) -> Vec<TimerEvent> {
+        let target = self.ticks(time);
+        let mut wheel = self.wheel.lock();
+        let mut fired = Vec::new();
+        while wheel.now < target {
+            fired.extend(wheel.tick());
+        }
+        fired
+    }
+
+    fn ticks(&self, time: Instant) -> u64 {
+        (time.saturating_duration_since(self.start).as_millis() / TICK.as_millis()) as u64
+    }
+}
+
+impl<T> TimerWheel<T> {
+    fn new() -> TimerWheel<T> {
+        let levels = (0..LEVELS)
+            .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+            .collect();
+        TimerWheel { now: 0, levels }
+    }
+
+    /// Insert a timer fired at the deadline tick (or the next tick if passed)
+    fn insert(&mut self, deadline: u64, value: T) {
+        self.place(deadline.max(self.now + 1), value);
+    }
+
+    fn place(&mut self, deadline: u64, value: T) {
+        let target = deadline.min(self.now + MAX_TICKS - 1);
+        let delta = target - self.now;
+        let mut level = 0;
+        while level + 1 < LEVELS && delta >= 1 << (SLOT_BITS * (level + 1)) {
+            level += 1;
+        }
+        let slot = (target >> (SLOT_BITS * level)) as usize & (SLOTS - 1);
+        self.levels[level][slot].push((deadline, value));
+    }
+
+    /// Advance one tick, return the fired timers
+    fn tick(&mut self) -> Vec<T> {
+        self.now += 1;
+        // Move the timers of the higher level slot to the lower levels when
+        // the lower level wrapped around.
+        for level in 1..LEVELS {
+            let shift = SLOT_BITS * level;
+            if self.now & ((1 << shift) - 1) != 0 {
+                break;
+            }
+            let slot = (self.now >> shift) as usize & (SLOTS - 1);
+            for (deadline, value) in mem::take(&mut self.levels[level][slot]) {
+                self.place(deadline, value);
+            }
+        }
+        let slot = self.now as usize & (SLOTS - 1);
+        let mut fired = Vec::new();
+        for (deadline, value) in mem::take(&mut self.levels[0][slot]) {
+            if deadline <= self.now {
+                fired.push(value);
+            } else {
+                self.place(deadline, value);
+            }
+        }
+        fired
+    }
+}
+
+/// Fire the event after the delay (never earlier, at most one tick later)
+pub(crate) fn schedule(global: &Arc<GlobalState>, delay: Duration, event: TimerEvent) {
+    let timers = &global.timers;
+    if !timers.running.swap(true, Ordering::AcqRel) {
+        tokio::spawn(run(Arc::downgrade(global)));
+    }
+    let deadline = Instant::now() + delay;
+    let tick_millis = TICK.as_millis();
+    let millis = deadline.saturating_duration_since(timers.start).as_millis();
+    let deadline_tick = ((millis + tick_millis - 1) / tick_millis) as u64;
+    timers.wheel.lock().insert(deadline_tick, event);
+}
+
+async fn run(global: Weak<GlobalState>) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        let global = match global.upgrade() {
+            Some(global) => global,
+            None => break,
+        };
+        for event in global.timers.advance(Instant::now()) {
+            fire(&global, event);
+        }
+    }
+}
+
+fn fire(global: &Arc<GlobalState>, event: TimerEvent) {
+    let (client_id, msg) = match event {
+        TimerEvent::SessionExpired {
+            client_id,
+            connected_time,
+        } => (client_id, ControlMessage::SessionExpired { connected_time }),
+        TimerEvent::WillDelayReached {
+            client_id,
+            connected_time,
+        } => (
+            client_id,
+            ControlMessage::WillDelayReached { connected_time },
+        ),
+        TimerEvent::KeepAlive {
+            client_id,
+            half_interval,
+            last_packet_time,
+        } => {
+            if last_packet_time.read().elapsed() <= half_interval * 3 {
+                let event = TimerEvent::KeepAlive {
+                    client_id,
+                    half_interval,
+                    last_packet_time,
+                };
+                schedule(global, half_interval, event);
+                return;
+            }
+            // timeout, kick it out
+            let msg = ControlMessage::Kick {
+                reason: Error::KeepAliveTimeout.to_string(),
+            };
+            (client_id, msg)
+        }
+    };
+    let sender = match global.get_client_control_sender(&client_id) {
+        Some(sender) => sender,
+        None => return,
+    };
+    match sender.try_send(msg) {
+        Ok(()) => {}
+        // Wait in another task, so the other timers are not delayed
+        Err(TrySendError::Full(msg)) => {
+            tokio::spawn(async move {
+                if let Err(err) = sender.send_async(msg).await {
+                    tracing::warn!("send timer message to {} error: {:?}", client_id, err);
+                }
+            });
+        }
+        Err(err) => tracing::warn!("send timer message to {} error: {:?}", client_id, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fire_ticks(wheel: &mut TimerWheel<u64>, ticks: u64) -> Vec<(u64, u64)> {
+        let mut fired = Vec::new();
+        for _ in 0..ticks {
+            for value in wheel.tick() {
+                fired.push((wheel.now, value));
+            }
+        }
+        fired
+    }
+
+    #[test]
+    fn test_timer_wheel() {
+        let mut wheel = TimerWheel::new();
+        let deadlines = [1, 63, 64, 65, 100, 4095, 4096, 5000, 300_000];
+        for deadline in deadlines.iter().rev() {
+            wheel.insert(*deadline, *deadline);
+        }
+        let fired = fire_ticks(&mut wheel, 300_000);
+        let expected: Vec<_> = deadlines.iter().map(|tick| (*tick, *tick)).collect();
+        assert_eq!(fired, expected);
+    }
+
+    #[test]
+    fn test_timer_wheel_insert() {
+        let mut wheel = TimerWheel::new();
+        fire_ticks(&mut wheel, 1000);
+        // The passed deadline is fired at next tick
+        wheel.insert(10, 10);
+        wheel.insert(1070, 1070);
+        // Out of the wheel range
+        wheel.insert(1000 + MAX_TICKS + 5, 0);
+        assert_eq!(fire_ticks(&mut wheel, 1), vec![(1001, 10)]);
+        assert_eq!(fire_ticks(&mut wheel, 100), vec![(1070, 1070)]);
+        let fired = fire_ticks(&mut wheel, MAX_TICKS);
+        assert_eq!(fired, vec![(1000 + MAX_TICKS + 5, 0)]);
+    }
+}
@@ -0,0 +1,682 @@
+//! Write the messages matched by `Config.tsdb.rules` to a time-series
+//! database (the `tsdb` feature): InfluxDB (by the HTTP write API with
+//! reqwest) or TimescaleDB (by tokio-postgres).
+//!
+//! The JSON or line protocol payloads are parsed into points in the publish
+//! path, and written in batches. A batch failed by a network error is retried
+//! until written, the new points are dropped when too many points are pending.
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use flume::{unbounded, Receiver, Sender};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client as HttpClient, Url};
+use serde_json::Value;
+use tokio::time::{sleep, timeout, timeout_at, Instant};
+use tokio_postgres::{Client as PgClient, Config as PgConfig, Error as PgError, NoTls};
+
+use crate::config::{TsdbFormat, TsdbRule, TsdbSink};
+use crate::metrics::Metrics;
+use crate::protocols::mqtt::{percent_encode_levels, render_topic_levels, topic_match};
+use crate::state::GlobalState;
+
+// The timeout of connecting and writing a batch to TimescaleDB
+const PG_TIMEOUT: Duration = Duration::from_secs(30);
+const PG_DEFAULT_PORT: u16 = 5432;
+// The timeout of a write request to InfluxDB
+const INFLUX_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    Bool(bool),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Point {
+    measurement: String,
+    tags: Vec<(String, String)>,
+    fields: Vec<(String, FieldValue)>,
+    // Unix timestamp in milliseconds
+    timestamp: i64,
+}
+
+/// The points waiting to be written, limited by `Config.tsdb.max_pending`
+pub(crate) struct TsdbQueue {
+    sender: Sender<Point>,
+    receiver: Receiver<Point>,
+}
+
+impl Default for TsdbQueue {
+    fn default() -> TsdbQueue {
+        // Unbounded, since the limit can be changed by reloading the config
+        let (sender, receiver) = unbounded();
+        TsdbQueue { sender, receiver }
+    }
+}
+
+/// Write the message by the first matched rule of `Config.tsdb.rules`,
+/// return false if not matched.
+pub(crate) fn forward(global: &GlobalState, topic_name: &str, payload: &Bytes) -> bool {
+    let config = global.config();
+    let tsdb = &config.tsdb;
+    if tsdb.sink.is_none() {
+        return false;
+    }
+    let rule = match tsdb
+        .rules
+        .iter()
+        .find(|rule| topic_match(&rule.topic_filter, topic_name))
+    {
+        Some(rule) => rule,
+        None => return false,
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0);
+    let points = match rule.format {
+        TsdbFormat::Json => parse_json(rule, topic_name, payload, now).map(|point| vec![point]),
+        TsdbFormat::LineProtocol => std::str::from_utf8(payload)
+            .ok()
+            .and_then(|text| parse_lines(text, now)),
+    };
+    let points = match points {
+        Some(points) => points,
+        None => {
+            tracing::debug!("invalid tsdb payload of {}", topic_name);
+            Metrics::incr(&global.metrics.tsdb_dropped);
+            return true;
+        }
+    };
+    let sender = &global.tsdb_queue.sender;
+    if sender.len() + points.len() > tsdb.max_pending {
+        tracing::warn!("tsdb queue is full, points dropped: {}", topic_name);
+        Metrics::add(&global.metrics.tsdb_dropped, points.len() as u64);
+        return true;
+    }
+    for point in points {
+        // Never fails, the receiver is kept in the queue
+        let _ = sender.try_send(point);
+    }
+    true
+}
+
+// A JSON object, the keys in `TsdbRule.tags` are tags, the other numbers,
+// booleans and strings are fields (the numbers are always float).
+fn parse_json(rule: &TsdbRule, topic_name: &str, payload: &[u8], now: i64) -> Option<Point> {
+    let object = match serde_json::from_slice(payload).ok()? {
+        Value::Object(object) => object,
+        _ => return None,
+    };
+    let mut point = Point {
+        measurement: render_topic_levels(&rule.measurement, topic_name),
+        tags: Vec::new(),
+        fields: Vec::new(),
+        timestamp: now,
+    };
+    for (key, value) in object {
+        if rule.timestamp_key.as_ref() == Some(&key) {
+            point.timestamp = value.as_i64()?;
+        } else if rule.tags.contains(&key) {
+            let tag = match value {
+                Value::String(tag) => tag,
+                Value::Null => continue,
+                value => value.to_string(),
+            };
+            point.tags.push((key, tag));
+        } else {
+            let field = match value {
+                Value::Number(number) => FieldValue::Float(number.as_f64()?),
+                Value::Bool(value) => FieldValue::Bool(value),
+                Value::String(value) => FieldValue::String(value),
+                _ => continue,
+            };
+            point.fields.push((key, field));
+        }
+    }
+    // The line breaks can't be written by line protocol
+    if point.fields.is_empty()
+        || point.tags.iter().any(|(_, tag)| tag.contains('\n'))
+        || point
+            .fields
+            .iter()
+            .any(|(_, field)| matches!(field, FieldValue::String(value) if value.contains('\n')))
+    {
+        return None;
+    }
+    Some(point)
+}
+
+// The InfluxDB line protocol, the timestamps are in nanoseconds:
+// `measurement[,tag=value...] field=value[,field=value...] [timestamp]`
+fn parse_lines(text: &str, now: i64) -> Option<Vec<Point>> {
+    let mut points = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let sections = split_unescaped(line, ' ');
+        let (head, fields, timestamp) = match sections.as_slice() {
+            [head, fields] => (*head, *fields, None),
+            [head, fields, timestamp] => (*head, *fields, Some(*timestamp)),
+            _ => return None,
+        };
+        let mut head = split_unescaped(head, ',').into_iter();
+        let measurement = unescape(head.next()?);
+        if measurement.is_empty() {
+            return None;
+        }
+        let mut tags = Vec::new();
+        for tag in head {
+            match split_unescaped(tag, '=').as_slice() {
+                [key, value] if !key.is_empty() && !value.is_empty() => {
+                    tags.push((unescape(key), unescape(value)))
+                }
+                _ => return None,
+            }
+        }
+        let mut point_fields = Vec::new();
+        for field in split_unescaped(fields, ',') {
+            match split_unescaped(field, '=').as_slice() {
+                [key, value] if !key.is_empty() => {
+                    point_fields.push((unescape(key), parse_field_value(value)?))
+                }
+                _ => return None,
+            }
+        }
+        let timestamp = match timestamp {
+            Some(timestamp) => timestamp.parse::<i64>().ok()? / 1_000_000,
+            None => now,
+        };
+        points.push(Point {
+            measurement,
+            tags,
+            fields: point_fields,
+            timestamp,
+        });
+    }
+    if points.is_empty() {
+        return None;
+    }
+    Some(points)
+}
+
+fn parse_field_value(value: &str) -> Option<FieldValue> {
+    if let Some(value) = value.strip_prefix('"') {
+        let value = value.strip_suffix('"')?;
+        return Some(FieldValue::String(unescape(value)));
+    }
+    if let Some(value) = value.strip_suffix('i') {
+        return value.parse().ok().map(FieldValue::Integer);
+    }
+    if let Some(value) = value.strip_suffix('u') {
+        return value.parse().ok().map(FieldValue::Integer);
+    }
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => Some(FieldValue::Bool(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => Some(FieldValue::Bool(false)),
+        _ => value
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .map(FieldValue::Float),
+    }
+}
+
+// Split at the separators not escaped by `\` and not in the double quotes
+fn split_unescaped(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (idx, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&text[start..idx]);
+            start = idx + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn unescape(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => output.extend(chars.next()),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+fn escape(text: &str, special: &[char], output: &mut String) {
+    for c in text.chars() {
+        if c == '\\' || special.contains(&c) {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+}
+
+// Encode the point by line protocol (the timestamp is in milliseconds), the
+// empty tags are omitted
+fn encode_line(point: &Point, output: &mut String) {
+    escape(&point.measurement, &[',', ' '], output);
+    for (key, value) in point.tags.iter().filter(|(_, value)| !value.is_empty()) {
+        output.push(',');
+        escape(key, &[',', '=', ' '], output);
+        output.push('=');
+        escape(value, &[',', '=', ' '], output);
+    }
+    for (idx, (key, value)) in point.fields.iter().enumerate() {
+        output.push(if idx == 0 { ' ' } else { ',' });
+        escape(key, &[',', '=', ' '], output);
+        output.push('=');
+        match value {
+            FieldValue::Float(value) => output.push_str(&value.to_string()),
+            FieldValue::Integer(value) => output.push_str(&format!("{value}i")),
+            FieldValue::Bool(value) => output.push_str(&value.to_string()),
+            FieldValue::String(value) => {
+                output.push('"');
+                for c in value.chars() {
+                    if c == '"' || c == '\\' {
+                        output.push('\\');
+                    }
+                    output.push(c);
+                }
+                output.push('"');
+            }
+        }
+    }
+    output.push_str(&format!(" {}\n", point.timestamp));
+}
+
+// Insert the points into the tables named by the measurements, the columns
+// are `time`, the tags and the fields. The rows with the same columns are
+// inserted by one statement.
+fn encode_inserts(points: &[Point]) -> String {
+    let mut groups: Vec<(&str, Vec<&str>, Vec<String>)> = Vec::new();
+    for point in points {
+        let columns: Vec<&str> = point
+            .tags
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .chain(point.fields.iter().map(|(key, _)| key.as_str()))
+            .collect();
+        let mut row = format!("(to_timestamp({} / 1000.0)", point.timestamp);
+        for (_, value) in &point.tags {
+            row.push_str(", ");
+            row.push_str(&quote_literal(value));
+        }
+        for (_, value) in &point.fields {
+            row.push_str(", ");
+            match value {
+                FieldValue::Float(value) => row.push_str(&value.to_string()),
+                FieldValue::Integer(value) => row.push_str(&value.to_string()),
+                FieldValue::Bool(value) => row.push_str(if *value { "TRUE" } else { "FALSE" }),
+                FieldValue::String(value) => row.push_str(&quote_literal(value)),
+            }
+        }
+        row.push(')');
+        match groups.iter_mut().find(|(table, group_columns, _)| {
+            *table == point.measurement && *group_columns == columns
+        }) {
+            Some((_, _, rows)) => rows.push(row),
+            None => groups.push((point.measurement.as_str(), columns, vec![row])),
+        }
+    }
+    let mut sql = String::new();
+    for (table, columns, rows) in groups {
+        sql.push_str("INSERT INTO ");
+        sql.push_str(&quote_ident(table));
+        sql.push_str(" (\"time\"");
+        for column in columns {
+            sql.push_str(", ");
+            sql.push_str(&quote_ident(column));
+        }
+        sql.push_str(") VALUES ");
+        sql.push_str(&rows.join(", "));
+        sql.push(';');
+    }
+    sql
+}
+
+// The NUL characters can't be sent by the simple query protocol
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\0', "").replace('\'', "''"))
+}
+
+fn quote_ident(value: &str) -> String {
+    format!("\"{}\"", value.replace('\0', "").replace('"', "\"\""))
+}
+
+enum WriteError {
+    // Retried after `Config.tsdb.retry_interval`
+    Retry(io::Error),
+    // The batch is rejected by the database, not retried
+    Rejected(String),
+}
+
+/// Write the queued points in batches
+pub(crate) async fn run(global: Arc<GlobalState>) {
+    // The connections to InfluxDB are kept alive and reused
+    let http = match HttpClient::builder().timeout(INFLUX_TIMEOUT).build() {
+        Ok(http) => http,
+        Err(err) => {
+            tracing::error!("create tsdb http client error: {}", err);
+            return;
+        }
+    };
+    let receiver = &global.tsdb_queue.receiver;
+    let mut conn: Option<(TsdbSink, PgClient)> = None;
+    while let Ok(point) = receiver.recv_async().await {
+        let config = global.config();
+        let tsdb = &config.tsdb;
+        let mut batch = vec![point];
+        let deadline = Instant::now() + Duration::from_millis(tsdb.linger_ms);
+        while batch.len() < tsdb.batch_size {
+            match timeout_at(deadline, receiver.recv_async()).await {
+                Ok(Ok(point)) => batch.push(point),
+                _ => break,
+            }
+        }
+        let count = batch.len() as u64;
+        loop {
+            // Read the config every time, so a retrying batch is written by
+            // the reloaded sink
+            let config = global.config();
+            let sink = match config.tsdb.sink.as_ref() {
+                Some(sink) => sink,
+                None => {
+                    Metrics::add(&global.metrics.tsdb_dropped, count);
+                    break;
+                }
+            };
+            match write_batch(&http, &mut conn, sink, &batch).await {
+                Ok(()) => {
+                    Metrics::add(&global.metrics.tsdb_written, count);
+                    break;
+                }
+                Err(WriteError::Rejected(err)) => {
+                    tracing::warn!("write {} points to tsdb rejected: {}", count, err);
+                    Metrics::add(&global.metrics.tsdb_failed, count);
+                    break;
+                }
+                Err(WriteError::Retry(err)) => {
+                    tracing::warn!("write {} points to tsdb error: {}", count, err);
+                    conn = None;
+                    sleep(Duration::from_secs(config.tsdb.retry_interval)).await;
+                }
+            }
+        }
+    }
+}
+
+async fn write_batch(
+    http: &HttpClient,
+    conn: &mut Option<(TsdbSink, PgClient)>,
+    sink: &TsdbSink,
+    points: &[Point],
+) -> Result<(), WriteError> {
+    match sink {
+        TsdbSink::Influx {
+            url,
+            org,
+            bucket,
+            token,
+        } => {
+            let url = influx_write_url(url, org.as_deref(), bucket)
+                .ok_or_else(|| WriteError::Rejected(format!("invalid url: {url:?}")))?;
+            let mut body = String::new();
+            for point in points {
+                encode_line(point, &mut body);
+            }
+            let mut request = http
+                .post(url)
+                .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(body);
+            if let Some(token) = token.as_ref() {
+                request = request.header(AUTHORIZATION, format!("Token {token}"));
+            }
+            let result = request
+                .send()
+                .await
+                .map(|response| response.status().as_u16());
+            match result {
+                Ok(status) if (200..300).contains(&status) => Ok(()),
+                // The points are wrong, not retried
+                Ok(status) if (400..500).contains(&status) && status != 408 && status != 429 => {
+                    Err(WriteError::Rejected(format!("status: {status}")))
+                }
+                Ok(status) => Err(WriteError::Retry(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("status: {status}"),
+                ))),
+                Err(err) => Err(WriteError::Retry(io::Error::new(
+                    io::ErrorKind::Other,
+                    err.to_string(),
+                ))),
+            }
+        }
+        TsdbSink::Timescale {
+            addr,
+            user,
+            password,
+            database,
+        } => {
+            let sql = encode_inserts(points);
+            let result = timeout(PG_TIMEOUT, async {
+                // Reconnect when the sink is changed or the connection is lost
+                if !matches!(conn, Some((current, pg)) if current == sink && !pg.is_closed()) {
+                    *conn = None;
+                    let pg = pg_connect(addr, user, password.as_deref(), database).await?;
+                    *conn = Some((sink.clone(), pg));
+                }
+                let (_, pg) = conn.as_ref().expect("postgres connection");
+                pg.batch_execute(&sql).await
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => Ok(()),
+                // The statements are rejected by the database, not retried
+                Ok(Err(err)) => match err.as_db_error() {
+                    Some(db_error) => Err(WriteError::Rejected(db_error.message().to_owned())),
+                    None => Err(WriteError::Retry(io::Error::new(
+                        io::ErrorKind::Other,
+                        err.to_string(),
+                    ))),
+                },
+                Err(_) => Err(WriteError::Retry(io::ErrorKind::TimedOut.into())),
+            }
+        }
+    }
+}
+
+// InfluxDB 2.x `/api/v2/write`, or 1.x `/write` if the org is not set
+fn influx_write_url(url: &str, org: Option<&str>, bucket: &str) -> Option<Url> {
+    let base = url.trim_end_matches('/');
+    let url = match org {
+        Some(org) => format!(
+            "{base}/api/v2/write?org={}&bucket={}&precision=ms",
+            percent_encode_levels(org),
+            percent_encode_levels(bucket)
+        ),
+        None => format!(
+            "{base}/write?db={}&precision=ms",
+            percent_encode_levels(bucket)
+        ),
+    };
+    Url::parse(&url).ok()
+}
+
+// Connect to PostgreSQL (`host:port`, the port is 5432 if not set), the
+// connection is driven by a task until it's closed
+async fn pg_connect(
+    addr: &str,
+    user: &str,
+    password: Option<&str>,
+    database: &str,
+) -> Result<PgClient, PgError> {
+    let (host, port) = match addr.rsplit_once(':') {
+        Some((host, port)) if !port.ends_with(']') => match port.parse::<u16>() {
+            Ok(port) => (host, port),
+            Err(_) => (addr, PG_DEFAULT_PORT),
+        },
+        _ => (addr, PG_DEFAULT_PORT),
+    };
+    let mut config = PgConfig::new();
+    config
+        .host(host.trim_start_matches('[').trim_end_matches(']'))
+        .port(port)
+        .user(user)
+        .dbname(database)
+        .application_name("akasa");
+    if let Some(password) = password {
+        config.password(password);
+    }
+    let (client, connection) = config.connect(NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            tracing::warn!("tsdb postgres connection error: {}", err);
+        }
+    });
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(tags: &[&str], timestamp_key: Option<&str>) -> TsdbRule {
+        TsdbRule {
+            topic_filter: "sensors/#".to_owned(),
+            format: TsdbFormat::Json,
+            measurement: "room_%2".to_owned(),
+            tags: tags.iter().map(|tag| (*tag).to_owned()).collect(),
+            timestamp_key: timestamp_key.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let point = parse_json(
+            &rule(&["host"], Some("ts")),
+            "sensors/1/temp",
+            br#"{"host": "a", "ts": 1700000000000, "temp": 21, "on": true, "name": "x", "nested": {}}"#,
+            1,
+        )
+        .unwrap();
+        assert_eq!(point.measurement, "room_1");
+        assert_eq!(point.tags, vec![("host".to_owned(), "a".to_owned())]);
+        assert_eq!(point.timestamp, 1700000000000);
+        assert!(point
+            .fields
+            .contains(&("temp".to_owned(), FieldValue::Float(21.0))));
+        assert_eq!(point.fields.len(), 3);
+
+        let point = parse_json(&rule(&[], None), "sensors/1", br#"{"temp": 1.5}"#, 1).unwrap();
+        assert_eq!(point.timestamp, 1);
+        let payloads: [&[u8]; 4] = [
+            br#"[1]"#,
+            br#"{"host": "a"}"#,
+            br#"{"temp": "a\nb"}"#,
+            b"not json",
+        ];
+        for payload in payloads {
+            assert!(parse_json(&rule(&["host"], None), "sensors/1", payload, 1).is_none());
+        }
+        assert!(parse_json(
+            &rule(&[], Some("ts")),
+            "sensors/1",
+            br#"{"ts": "now", "a": 1}"#,
+            1
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        let text = "# comment\n\
+            cpu\\ load,host=a\\,b,region=us temp=21.5,count=3i,on=t,msg=\"say \\\"hi\\\", ok\" 1700000000000000000\n\
+            \n\
+            mem free=1u\n";
+        let points = parse_lines(text, 7).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(
+            points[0],
+            Point {
+                measurement: "cpu load".to_owned(),
+                tags: vec![
+                    ("host".to_owned(), "a,b".to_owned()),
+                    ("region".to_owned(), "us".to_owned()),
+                ],
+                fields: vec![
+                    ("temp".to_owned(), FieldValue::Float(21.5)),
+                    ("count".to_owned(), FieldValue::Integer(3)),
+                    ("on".to_owned(), FieldValue::Bool(true)),
+                    (
+                        "msg".to_owned(),
+                        FieldValue::String("say \"hi\", ok".to_owned())
+                    ),
+                ],
+                timestamp: 1700000000000,
+            }
+        );
+        assert_eq!(points[1].timestamp, 7);
+
+        let mut output = String::new();
+        encode_line(&points[0], &mut output);
+        assert_eq!(
+            output,
+            "cpu\\ load,host=a\\,b,region=us temp=21.5,count=3i,on=true,msg=\"say \\\"hi\\\", ok\" 1700000000000\n"
+        );
+
+        for text in [
+            "",
+            "cpu",
+            "cpu temp",
+            "cpu temp=abc",
+            "cpu temp=1 now",
+            "cpu,host temp=1",
+            "cpu temp=NaN",
+        ] {
+            assert!(parse_lines(text, 1).is_none(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_encode_inserts() {
+        let point = |measurement: &str, host: &str, temp: f64| Point {
+            measurement: measurement.to_owned(),
+            tags: vec![("host".to_owned(), host.to_owned())],
+            fields: vec![("temp".to_owned(), FieldValue::Float(temp))],
+            timestamp: 1500,
+        };
+        let points = vec![
+            point("cpu", "a", 1.5),
+            point("mem", "b", 2.0),
+            point("cpu", "it's", -3.0),
+        ];
+        assert_eq!(
+            encode_inserts(&points),
+            "INSERT INTO \"cpu\" (\"time\", \"host\", \"temp\") VALUES \
+            (to_timestamp(1500 / 1000.0), 'a', 1.5), (to_timestamp(1500 / 1000.0), 'it''s', -3);\
+            INSERT INTO \"mem\" (\"time\", \"host\", \"temp\") VALUES (to_timestamp(1500 / 1000.0), 'b', 2);"
+        );
+    }
+}
@@ -0,0 +1,308 @@
+//! Forward the messages matched by `Config.webhook_forward.rules` to HTTP
+//! endpoints by reqwest (the `webhook` feature), for the simple serverless
+//! pipelines.
+//!
+//! The messages are posted in batches as JSON arrays, the batches of an
+//! endpoint are posted in order. A failed request is retried with backoff, the
+//! batch failed permanently is published to the dead letter topic.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use flume::{bounded, Receiver, Sender};
+use mqtt_proto::{QoS, TopicName};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use serde::Serialize;
+use tokio::time::{sleep, timeout_at, Instant};
+
+use crate::config::WebhookForwardConfig;
+use crate::metrics::Metrics;
+use crate::protocols::mqtt::{percent_encode_levels, render_topic_levels, topic_match};
+use crate::state::GlobalState;
+
+const QUEUE_SIZE: usize = 65536;
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A forwarded message, the payload is encoded by base64
+#[derive(Serialize)]
+struct WebhookRecord<'a> {
+    // Unix timestamp in milliseconds
+    time: u64,
+    client_identifier: &'a str,
+    username: Option<&'a str>,
+    topic: &'a str,
+    qos: u8,
+    payload: String,
+}
+
+#[derive(PartialEq, Eq)]
+struct Endpoint {
+    url: String,
+    headers: Vec<(String, String)>,
+}
+
+struct WebhookMessage {
+    endpoint: Endpoint,
+    // The encoded `WebhookRecord`
+    record: Vec<u8>,
+}
+
+/// The messages waiting to be posted to the endpoints
+pub(crate) struct WebhookForwardQueue {
+    sender: Sender<WebhookMessage>,
+    receiver: Receiver<WebhookMessage>,
+}
+
+impl Default for WebhookForwardQueue {
+    fn default() -> WebhookForwardQueue {
+        let (sender, receiver) = bounded(QUEUE_SIZE);
+        WebhookForwardQueue { sender, receiver }
+    }
+}
+
+/// Forward the message by the first matched rule of
+/// `Config.webhook_forward.rules`, return false if not matched.
+pub(crate) fn forward(
+    global: &GlobalState,
+    client_identifier: &str,
+    username: Option<&str>,
+    topic_name: &str,
+    payload: &Bytes,
+    qos: QoS,
+) -> bool {
+    let config = global.config();
+    let rule = match config
+        .webhook_forward
+        .rules
+        .iter()
+        .find(|rule| topic_match(&rule.topic_filter, topic_name))
+    {
+        Some(rule) => rule,
+        None => return false,
+    };
+    // The levels in the url are percent-encoded
+    let url = render_topic_levels(&rule.url, &percent_encode_levels(topic_name));
+    let mut headers = Vec::with_capacity(rule.headers.len());
+    for (name, value) in &rule.headers {
+        let value = render_topic_levels(value, topic_name);
+        if value.contains(|c: char| c.is_control()) {
+            tracing::warn!("invalid webhook header {} of {}, dropped", name, topic_name);
+            Metrics::incr(&global.metrics.webhook_forward_dropped);
+            return true;
+        }
+        headers.push((name.clone(), value));
+    }
+    let record = WebhookRecord {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0),
+        client_identifier,
+        username,
+        topic: topic_name,
+        qos: qos as u8,
+        payload: STANDARD.encode(payload),
+    };
+    let record = match serde_json::to_vec(&record) {
+        Ok(record) => record,
+        Err(err) => {
+            tracing::error!("encode webhook record error: {}", err);
+            return true;
+        }
+    };
+    send(global, url, headers, record, topic_name);
+    true
+}
+
+/// Queue a JSON record to the endpoint, it's posted in batch with the other
+/// records to the same endpoint.
+pub(crate) fn send(
+    global: &GlobalState,
+    url: String,
+    headers: Vec<(String, String)>,
+    record: Vec<u8>,
+    topic_name: &str,
+) {
+    let message = WebhookMessage {
+        endpoint: Endpoint { url, headers },
+        record,
+    };
+    if global
+        .webhook_forward_queue
+        .sender
+        .try_send(message)
+        .is_err()
+    {
+        tracing::warn!(
+            "webhook forward queue is full, message dropped: {}",
+            topic_name
+        );
+        Metrics::incr(&global.metrics.webhook_forward_dropped);
+    }
+}
+
+/// Post the queued messages in batches
+pub(crate) async fn run(global: Arc<GlobalState>) {
+    // The connections to the endpoints are kept alive and reused
+    let client = match Client::builder().timeout(POST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            tracing::error!("create webhook client error: {}", err);
+            return;
+        }
+    };
+    let receiver = &global.webhook_forward_queue.receiver;
+    while let Ok(message) = receiver.recv_async().await {
+        // Read the config every time, since the config can be reloaded
+        let config = global.config();
+        let forward = &config.webhook_forward;
+        let mut batch = vec![message];
+        let deadline = Instant::now() + Duration::from_millis(forward.linger_ms);
+        while batch.len() < forward.batch_size {
+            match timeout_at(deadline, receiver.recv_async()).await {
+                Ok(Ok(message)) => batch.push(message),
+                _ => break,
+            }
+        }
+        // Grouped by the endpoints, in the order of the first messages
+        let mut groups: Vec<(Endpoint, Vec<Vec<u8>>)> = Vec::new();
+        for message in batch {
+            match groups
+                .iter_mut()
+                .find(|(endpoint, _)| *endpoint == message.endpoint)
+            {
+                Some((_, records)) => records.push(message.record),
+                None => groups.push((message.endpoint, vec![message.record])),
+            }
+        }
+        for (endpoint, records) in groups {
+            let count = records.len() as u64;
+            let body = encode_batch(records);
+            if post_batch(&client, forward, &endpoint, &body).await {
+                Metrics::add(&global.metrics.webhook_forward_sent, count);
+            } else {
+                Metrics::add(&global.metrics.webhook_forward_failed, count);
+                dead_letter(&global, forward, body);
+            }
+        }
+    }
+}
+
+// The JSON array of the encoded records
+fn encode_batch(records: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut body =
+        Vec::with_capacity(records.iter().map(|record| record.len() + 1).sum::<usize>() + 1);
+    body.push(b'[');
+    for (idx, record) in records.into_iter().enumerate() {
+        if idx > 0 {
+            body.push(b',');
+        }
+        body.extend_from_slice(&record);
+    }
+    body.push(b']');
+    body
+}
+
+// Post the batch, retry with backoff, return false if failed permanently
+async fn post_batch(
+    client: &Client,
+    config: &WebhookForwardConfig,
+    endpoint: &Endpoint,
+    body: &[u8],
+) -> bool {
+    let url = match Url::parse(&endpoint.url) {
+        Ok(url) => url,
+        Err(_) => {
+            tracing::warn!("invalid webhook url: {:?}", endpoint.url);
+            return false;
+        }
+    };
+    let mut backoff = Duration::from_millis(config.retry_backoff_ms);
+    for retry in 0..=config.max_retries {
+        if retry > 0 {
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+        let mut request = client
+            .post(url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+        for (name, value) in &endpoint.headers {
+            request = request.header(name, value);
+        }
+        let result = request
+            .send()
+            .await
+            .map(|response| response.status().as_u16());
+        match result {
+            Ok(status) if (200..300).contains(&status) => return true,
+            // The request is wrong, not retried
+            Ok(status) if (400..500).contains(&status) && status != 408 && status != 429 => {
+                tracing::warn!(
+                    "post to webhook {} failed, status: {}",
+                    endpoint.url,
+                    status
+                );
+                return false;
+            }
+            Ok(status) => {
+                tracing::warn!(
+                    "post to webhook {} failed, status: {}",
+                    endpoint.url,
+                    status
+                )
+            }
+            Err(err) => tracing::warn!("post to webhook {} error: {}", endpoint.url, err),
+        }
+    }
+    false
+}
+
+// Publish the failed batch to `Config.webhook_forward.dead_letter_topic`
+fn dead_letter(global: &GlobalState, config: &WebhookForwardConfig, body: Vec<u8>) {
+    let topic_name = match config
+        .dead_letter_topic
+        .as_ref()
+        .and_then(|topic| TopicName::try_from(topic.clone()).ok())
+    {
+        Some(topic_name) => topic_name,
+        None => return,
+    };
+    if let Err(err) = global.publish(
+        topic_name,
+        QoS::Level1,
+        false,
+        body.into(),
+        Default::default(),
+    ) {
+        tracing::warn!("publish webhook dead letter error: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_url() {
+        let url = render_topic_levels(
+            "http://127.0.0.1:8000/devices/%2?type=%3",
+            &percent_encode_levels("sensors/room 1/temp"),
+        );
+        assert_eq!(url, "http://127.0.0.1:8000/devices/room%201?type=temp");
+        assert!(Url::parse(&url).is_ok());
+        assert_eq!(percent_encode_levels("a/%/你"), "a/%25/%E4%BD%A0");
+    }
+
+    #[test]
+    fn test_encode_batch() {
+        assert_eq!(encode_batch(vec![b"1".to_vec()]), b"[1]");
+        assert_eq!(
+            encode_batch(vec![b"{}".to_vec(), b"{}".to_vec()]),
+            b"[{},{}]"
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! The global allocator (`jemalloc` or `mimalloc` feature) and its statistics
+
+use akasa_core::AllocatorStats;
+
+#[cfg(all(
+    not(target_env = "msvc"),
+    feature = "jemalloc",
+    not(feature = "mimalloc")
+))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+#[cfg(all(
+    not(target_env = "msvc"),
+    feature = "jemalloc",
+    not(feature = "mimalloc")
+))]
+pub fn stats() -> Option<AllocatorStats> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // The statistics are cached until the epoch advanced
+    epoch::advance().ok()?;
+    Some(AllocatorStats {
+        allocator: "jemalloc",
+        allocated: stats::allocated::read().ok()? as u64,
+        resident: stats::resident::read().ok()? as u64,
+    })
+}
+
+#[cfg(feature = "mimalloc")]
+pub fn stats() -> Option<AllocatorStats> {
+    let (mut elapsed_msecs, mut user_msecs, mut system_msecs) = (0, 0, 0);
+    let (mut current_rss, mut peak_rss) = (0, 0);
+    let (mut current_commit, mut peak_commit, mut page_faults) = (0, 0, 0);
+    // SAFETY: all the pointers are valid during the call
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut elapsed_msecs,
+            &mut user_msecs,
+            &mut system_msecs,
+            &mut current_rss,
+            &mut peak_rss,
+            &mut current_commit,
+            &mut peak_commit,
+            &mut page_faults,
+        );
+    }
+    Some(AllocatorStats {
+        allocator: "mimalloc",
+        allocated: current_commit as u64,
+        resident: current_rss as u64,
+    })
+}
+
+// The system allocator
+#[cfg(not(any(
+    all(not(target_env = "msvc"), feature = "jemalloc"),
+    feature = "mimalloc"
+)))]
+pub fn stats() -> Option<AllocatorStats> {
+    None
+}
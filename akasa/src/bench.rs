@@ -0,0 +1,369 @@
+//! The load generator (`akasa bench`), measure a running server by MQTT v3.1.1
+//! clients: the connect storm, and the pub/sub throughput (also fan-in and
+//! fan-out) with mixed QoS levels and payload sizes. The publish time is
+//! written at the beginning of the payload, so the subscribers can report the
+//! end-to-end latency percentiles.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use akasa_core::mqtt_proto::v3::{
+    Connect, ConnectReturnCode, Packet, Publish, Suback, Subscribe, SubscribeReturnCode,
+};
+use akasa_core::mqtt_proto::{Pid, QoS, QosPid, TopicFilter, TopicName};
+use anyhow::bail;
+use clap::{Args, ValueEnum};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+// Stop waiting the subscribers when no message received in this duration
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// The publish time (microseconds since the benchmark started) in big endian
+const TIMESTAMP_LEN: usize = 8;
+
+#[derive(Args, Debug)]
+pub struct BenchOptions {
+    /// The server address
+    #[clap(long, value_name = "ADDR", default_value = "127.0.0.1:1883")]
+    addr: SocketAddr,
+
+    /// The benchmark scenario
+    #[clap(long, value_enum, default_value_t = BenchMode::PubSub)]
+    mode: BenchMode,
+
+    /// The number of connections (the publishers in pub-sub mode)
+    #[clap(long, value_name = "NUM", default_value_t = 100)]
+    clients: usize,
+
+    /// The number of subscribers (pub-sub mode), every subscriber receives all the messages
+    #[clap(long, value_name = "NUM", default_value_t = 10)]
+    subscribers: usize,
+
+    /// The number of messages published by every publisher (pub-sub mode)
+    #[clap(long, value_name = "NUM", default_value_t = 1000)]
+    messages: usize,
+
+    /// The QoS levels used by the publishers in turn, e.g. "0,1,2"
+    #[clap(
+        long,
+        value_name = "QOS",
+        value_delimiter = ',',
+        default_value = "0",
+        value_parser = clap::value_parser!(u8).range(0..=2)
+    )]
+    qos: Vec<u8>,
+
+    /// The payload sizes in bytes used by the publishers in turn, e.g. "64,1024"
+    #[clap(
+        long,
+        value_name = "NUM",
+        value_delimiter = ',',
+        default_value = "64",
+        value_parser = clap::value_parser!(u32).range(TIMESTAMP_LEN as i64..)
+    )]
+    payload_size: Vec<u32>,
+
+    /// The prefix of the client identifiers and topics
+    #[clap(long, value_name = "STRING", default_value = "akasa-bench")]
+    prefix: String,
+
+    /// The user name
+    #[clap(long, value_name = "STRING")]
+    username: Option<String>,
+
+    /// The password
+    #[clap(long, value_name = "STRING")]
+    password: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum BenchMode {
+    /// Open all the connections at the same time
+    Connect,
+    /// The publishers publish to `{prefix}/{publisher}`, the subscribers subscribe `{prefix}/#`
+    PubSub,
+    /// Like pub-sub, but all the publishers publish to one subscriber
+    FanIn,
+    /// Like pub-sub, but one publisher publishes to all the subscribers
+    FanOut,
+}
+
+pub async fn run(options: BenchOptions) -> anyhow::Result<()> {
+    let options = Arc::new(options);
+    match options.mode {
+        BenchMode::Connect => connect_storm(options).await,
+        BenchMode::PubSub => {
+            let (publishers, subscribers) = (options.clients, options.subscribers);
+            pub_sub(options, publishers, subscribers).await
+        }
+        BenchMode::FanIn => {
+            let publishers = options.clients;
+            pub_sub(options, publishers, 1).await
+        }
+        BenchMode::FanOut => {
+            let subscribers = options.subscribers;
+            pub_sub(options, 1, subscribers).await
+        }
+    }
+}
+
+async fn connect_storm(options: Arc<BenchOptions>) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..options.clients)
+        .map(|idx| {
+            let options = Arc::clone(&options);
+            let client_id = format!("{}-conn-{}", options.prefix, idx);
+            tokio::spawn(async move { Client::connect(&options, client_id).await })
+        })
+        .collect();
+    // Keep the connections open until all the clients are connected
+    let mut clients = Vec::with_capacity(tasks.len());
+    let mut failed = 0;
+    for task in tasks {
+        match task.await? {
+            Ok(client) => clients.push(client),
+            Err(err) => {
+                tracing::debug!("connect error: {}", err);
+                failed += 1;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "connected: {}, failed: {}, elapsed: {:?}, {:.0} conn/s",
+        clients.len(),
+        failed,
+        elapsed,
+        rate(clients.len() as u64, elapsed),
+    );
+    Ok(())
+}
+
+async fn pub_sub(
+    options: Arc<BenchOptions>,
+    publishers: usize,
+    subscribers: usize,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let received = Arc::new(AtomicU64::new(0));
+    let topic_filter = TopicFilter::try_from(format!("{}/#", options.prefix))?;
+    // Every subscriber records the latencies (in microseconds) separately
+    let mut latencies = Vec::with_capacity(subscribers);
+    let mut subscriber_tasks = Vec::with_capacity(subscribers);
+    for idx in 0..subscribers {
+        let client_id = format!("{}-sub-{}", options.prefix, idx);
+        let mut client = Client::connect(&options, client_id).await?;
+        client.subscribe(topic_filter.clone()).await?;
+        let received = Arc::clone(&received);
+        let subscriber_latencies = Arc::new(Mutex::new(Vec::new()));
+        latencies.push(Arc::clone(&subscriber_latencies));
+        subscriber_tasks.push(tokio::spawn(async move {
+            client
+                .receive(start, &received, &subscriber_latencies)
+                .await
+        }));
+    }
+
+    let publish_start = Instant::now();
+    let publisher_tasks: Vec<_> = (0..publishers)
+        .map(|idx| {
+            let options = Arc::clone(&options);
+            tokio::spawn(async move {
+                let client_id = format!("{}-pub-{}", options.prefix, idx);
+                let mut client = Client::connect(&options, client_id).await?;
+                client.publish_all(&options, start, idx).await
+            })
+        })
+        .collect();
+    for task in publisher_tasks {
+        task.await??;
+    }
+    let publish_elapsed = publish_start.elapsed();
+
+    // Wait until all the messages are received or the subscribers are idle
+    let expected = (publishers * options.messages * subscribers) as u64;
+    let mut count = received.load(Ordering::Relaxed);
+    let mut last_received = Instant::now();
+    while count < expected && last_received.elapsed() < IDLE_TIMEOUT {
+        sleep(POLL_INTERVAL).await;
+        let current = received.load(Ordering::Relaxed);
+        if current > count {
+            count = current;
+            last_received = Instant::now();
+        }
+    }
+    let receive_elapsed = last_received.duration_since(publish_start);
+    for task in subscriber_tasks {
+        task.abort();
+    }
+
+    let published = (publishers * options.messages) as u64;
+    println!(
+        "published: {}, elapsed: {:?}, {:.0} msg/s",
+        published,
+        publish_elapsed,
+        rate(published, publish_elapsed),
+    );
+    println!(
+        "received: {}/{}, elapsed: {:?}, {:.0} msg/s",
+        count,
+        expected,
+        receive_elapsed,
+        rate(count, receive_elapsed),
+    );
+    let mut latencies: Vec<u64> = latencies
+        .iter()
+        .flat_map(|latencies| latencies.lock().expect("latencies lock").clone())
+        .collect();
+    latencies.sort_unstable();
+    println!(
+        "latency p50: {:?}, p90: {:?}, p99: {:?}, max: {:?}",
+        percentile(&latencies, 50),
+        percentile(&latencies, 90),
+        percentile(&latencies, 99),
+        percentile(&latencies, 100),
+    );
+    Ok(())
+}
+
+fn rate(count: u64, elapsed: Duration) -> f64 {
+    count as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+// The `sorted` latencies are in microseconds
+fn percentile(sorted: &[u64], pct: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let idx = (sorted.len() * pct / 100).min(sorted.len() - 1);
+    Duration::from_micros(sorted[idx])
+}
+
+fn elapsed_micros(start: Instant) -> u64 {
+    start.elapsed().as_micros() as u64
+}
+
+struct Client {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    async fn connect(options: &BenchOptions, client_id: String) -> anyhow::Result<Client> {
+        let (reader, writer) = TcpStream::connect(options.addr).await?.into_split();
+        let mut client = Client {
+            reader: BufReader::new(reader),
+            writer,
+        };
+        let mut connect = Connect::new(Arc::new(client_id), 60);
+        connect.username = options.username.clone().map(Arc::new);
+        connect.password = options
+            .password
+            .clone()
+            .map(|password| password.into_bytes().into());
+        client.write_packet(connect.into()).await?;
+        match client.read_packet().await? {
+            Packet::Connack(connack) if connack.code == ConnectReturnCode::Accepted => Ok(client),
+            packet => bail!("connect failed: {:?}", packet),
+        }
+    }
+
+    async fn subscribe(&mut self, topic_filter: TopicFilter) -> anyhow::Result<()> {
+        // Subscribe with QoS 2, so the messages are received with the published QoS
+        let pid = Pid::default();
+        let subscribe = Subscribe::new(pid, vec![(topic_filter, QoS::Level2)]);
+        self.write_packet(subscribe.into()).await?;
+        let suback = Suback::new(pid, vec![SubscribeReturnCode::from(QoS::Level2)]);
+        self.expect(suback.into()).await
+    }
+
+    // Publish the messages one by one, QoS 1/2 messages wait for the acknowledgements
+    async fn publish_all(
+        &mut self,
+        options: &BenchOptions,
+        start: Instant,
+        idx: usize,
+    ) -> anyhow::Result<()> {
+        let topic_name = TopicName::try_from(format!("{}/{}", options.prefix, idx))?;
+        let mut pid = Pid::default();
+        let payload_sizes = options.payload_size.iter().cycle();
+        for (qos, size) in options
+            .qos
+            .iter()
+            .cycle()
+            .zip(payload_sizes)
+            .take(options.messages)
+        {
+            let qos_pid = match qos {
+                0 => QosPid::Level0,
+                1 => QosPid::Level1(pid),
+                _ => QosPid::Level2(pid),
+            };
+            let mut payload = vec![b'x'; *size as usize];
+            payload[..TIMESTAMP_LEN].copy_from_slice(&elapsed_micros(start).to_be_bytes());
+            let publish = Publish::new(qos_pid, topic_name.clone(), payload.into());
+            self.write_packet(publish.into()).await?;
+            match qos {
+                0 => continue,
+                1 => self.expect(Packet::Puback(pid)).await?,
+                _ => {
+                    self.expect(Packet::Pubrec(pid)).await?;
+                    self.write_packet(Packet::Pubrel(pid)).await?;
+                    self.expect(Packet::Pubcomp(pid)).await?;
+                }
+            }
+            pid += 1;
+        }
+        Ok(())
+    }
+
+    async fn receive(
+        mut self,
+        start: Instant,
+        received: &AtomicU64,
+        latencies: &Mutex<Vec<u64>>,
+    ) -> anyhow::Result<()> {
+        loop {
+            match self.read_packet().await? {
+                Packet::Publish(publish) => {
+                    received.fetch_add(1, Ordering::Relaxed);
+                    if let Some(timestamp) = publish.payload.get(..TIMESTAMP_LEN) {
+                        let sent = u64::from_be_bytes(timestamp.try_into().expect("timestamp"));
+                        let latency = elapsed_micros(start).saturating_sub(sent);
+                        latencies.lock().expect("latencies lock").push(latency);
+                    }
+                    match publish.qos_pid {
+                        QosPid::Level0 => {}
+                        QosPid::Level1(pid) => self.write_packet(Packet::Puback(pid)).await?,
+                        QosPid::Level2(pid) => self.write_packet(Packet::Pubrec(pid)).await?,
+                    }
+                }
+                Packet::Pubrel(pid) => self.write_packet(Packet::Pubcomp(pid)).await?,
+                packet => tracing::debug!("subscriber received: {:?}", packet),
+            }
+        }
+    }
+
+    async fn expect(&mut self, expected: Packet) -> anyhow::Result<()> {
+        let packet = self.read_packet().await?;
+        if packet != expected {
+            bail!("unexpected packet: {:?}, expected: {:?}", packet, expected);
+        }
+        Ok(())
+    }
+
+    async fn read_packet(&mut self) -> anyhow::Result<Packet> {
+        Ok(Packet::decode_async(&mut self.reader).await?)
+    }
+
+    async fn write_packet(&mut self, packet: Packet) -> anyhow::Result<()> {
+        let data = packet.encode()?;
+        self.writer.write_all(data.as_ref()).await?;
+        Ok(())
+    }
+}
@@ -19,7 +19,7 @@ impl Hook for DefaultHook {
         _peer: SocketAddr,
         connect: &v5::Connect,
     ) -> HookResult<HookConnectCode> {
-        log::debug!("v5_before_connect(), identifier={}", connect.client_id);
+        tracing::debug!("v5_before_connect(), identifier={}", connect.client_id);
         Ok(HookConnectCode::Success)
     }
 
@@ -28,7 +28,7 @@ impl Hook for DefaultHook {
         session: &SessionV5,
         session_present: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!(
+        tracing::debug!(
             "v5_after_connect(), [{}], identifier={}, session_present={}",
             session.client_id(),
             session.client_identifier,
@@ -45,7 +45,7 @@ impl Hook for DefaultHook {
         publish: &mut v5::Publish,
         _changed: &mut bool,
     ) -> HookResult<HookPublishCode> {
-        log::debug!(
+        tracing::debug!(
             "v5_before_publish() [{}], topic={}",
             session.client_id(),
             publish.topic_name
@@ -61,7 +61,7 @@ impl Hook for DefaultHook {
         publish: &v5::Publish,
         _changed: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!(
+        tracing::debug!(
             "v5_after_publish() [{}], topic={}",
             session.client_id(),
             publish.topic_name
@@ -77,7 +77,7 @@ impl Hook for DefaultHook {
         subscribe: &mut v5::Subscribe,
         _changed: &mut bool,
     ) -> HookResult<HookSubscribeCode> {
-        log::debug!(
+        tracing::debug!(
             "v5_before_subscribe() [{}], {:#?}",
             session.client_id(),
             subscribe
@@ -94,7 +94,7 @@ impl Hook for DefaultHook {
         _changed: bool,
         _reason_codes: Option<Vec<v5::SubscribeReasonCode>>,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!("v5_after_subscribe(), [{}]", session.client_id());
+        tracing::debug!("v5_after_subscribe(), [{}]", session.client_id());
         Ok(Vec::new())
     }
 
@@ -106,7 +106,7 @@ impl Hook for DefaultHook {
         unsubscribe: &mut v5::Unsubscribe,
         _changed: &mut bool,
     ) -> HookResult<HookUnsubscribeCode> {
-        log::debug!(
+        tracing::debug!(
             "v5_before_unsubscribe(), [{}], {:#?}",
             session.client_id(),
             unsubscribe
@@ -122,12 +122,12 @@ impl Hook for DefaultHook {
         _unsubscribe: &v5::Unsubscribe,
         _changed: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!("v5_after_unsubscribe(), [{}]", session.client_id());
+        tracing::debug!("v5_after_unsubscribe(), [{}]", session.client_id());
         Ok(Vec::new())
     }
 
     async fn v5_after_disconnect(&self, session: &SessionV5, _taken_over: bool) -> HookResult<()> {
-        log::debug!("v5_after_disconnect(), [{}]", session.client_id());
+        tracing::debug!("v5_after_disconnect(), [{}]", session.client_id());
         Ok(())
     }
 
@@ -140,7 +140,7 @@ impl Hook for DefaultHook {
         _peer: SocketAddr,
         connect: &v3::Connect,
     ) -> HookResult<HookConnectCode> {
-        log::debug!("v3_before_connect(), identifier={}", connect.client_id);
+        tracing::debug!("v3_before_connect(), identifier={}", connect.client_id);
         Ok(HookConnectCode::Success)
     }
 
@@ -149,7 +149,7 @@ impl Hook for DefaultHook {
         session: &SessionV3,
         session_present: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!(
+        tracing::debug!(
             "v3_after_connect(), [{}], identifier={}, session_present={}",
             session.client_id(),
             session.client_identifier,
@@ -166,7 +166,7 @@ impl Hook for DefaultHook {
         publish: &mut v3::Publish,
         _changed: &mut bool,
     ) -> HookResult<HookPublishCode> {
-        log::debug!(
+        tracing::debug!(
             "v3_before_publish() [{}], topic={}",
             session.client_id(),
             publish.topic_name
@@ -182,7 +182,7 @@ impl Hook for DefaultHook {
         publish: &v3::Publish,
         _changed: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!(
+        tracing::debug!(
             "v3_after_publish() [{}], topic={}",
             session.client_id(),
             publish.topic_name
@@ -198,7 +198,7 @@ impl Hook for DefaultHook {
         subscribe: &mut v3::Subscribe,
         _changed: &mut bool,
     ) -> HookResult<HookSubscribeCode> {
-        log::debug!(
+        tracing::debug!(
             "v3_before_subscribe() [{}], {:#?}",
             session.client_id(),
             subscribe
@@ -215,7 +215,7 @@ impl Hook for DefaultHook {
         _changed: bool,
         _codes: Option<Vec<v3::SubscribeReturnCode>>,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!("v3_after_subscribe(), [{}]", session.client_id());
+        tracing::debug!("v3_after_subscribe(), [{}]", session.client_id());
         Ok(Vec::new())
     }
 
@@ -227,7 +227,7 @@ impl Hook for DefaultHook {
         unsubscribe: &mut v3::Unsubscribe,
         _changed: &mut bool,
     ) -> HookResult<HookUnsubscribeCode> {
-        log::debug!(
+        tracing::debug!(
             "v3_before_unsubscribe(), [{}], {:#?}",
             session.client_id(),
             unsubscribe
@@ -243,12 +243,12 @@ impl Hook for DefaultHook {
         _unsubscribe: &v3::Unsubscribe,
         _changed: bool,
     ) -> HookResult<Vec<HookAction>> {
-        log::debug!("v3_after_unsubscribe(), [{}]", session.client_id());
+        tracing::debug!("v3_after_unsubscribe(), [{}]", session.client_id());
         Ok(Vec::new())
     }
 
     async fn v3_after_disconnect(&self, session: &SessionV3, _taken_over: bool) -> HookResult<()> {
-        log::debug!("v3_after_disconnect(), [{}]", session.client_id());
+        tracing::debug!("v3_after_disconnect(), [{}]", session.client_id());
         Ok(())
     }
 }
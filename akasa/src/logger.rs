@@ -1,10 +1,88 @@
-pub fn init() {
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use tracing::Subscriber;
+use tracing_subscriber::{
+    filter::LevelFilter, fmt, prelude::*, registry, registry::LookupSpan, reload, EnvFilter, Layer,
+    Registry,
+};
+
+static LEVEL_HANDLE: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Human readable text
+    Text,
+    /// One JSON object per line, with current span fields (peer, protocol, client_id)
+    Json,
+}
+
+/// Export traces by OTLP (require `otlp` feature)
+#[derive(Clone, Debug)]
+pub struct OtlpOptions {
+    /// The collector endpoint, e.g. http://localhost:4317
+    pub endpoint: String,
+    /// The ratio of traces to sample, in range [0.0, 1.0]
+    pub sample_ratio: f64,
+}
+
+pub fn init(format: LogFormat, otlp: Option<OtlpOptions>) -> anyhow::Result<()> {
+    let json = format == LogFormat::Json;
     if std::env::var("RUST_LOG").is_err() {
-        // should recognize RUST_LOG_STYLE environment variable
-        env_logger::Builder::from_default_env()
-            .filter(None, log::LevelFilter::Info)
+        // The level can be changed at runtime by `set_level()`
+        let (filter, handle) = reload::Layer::new(LevelFilter::INFO);
+        let _ = LEVEL_HANDLE.set(handle);
+        registry()
+            .with(filter)
+            .with(otlp_layer(otlp)?)
+            .with((!json).then(fmt::layer))
+            .with(json.then(|| fmt::layer().json().with_span_list(false)))
             .init();
     } else {
-        env_logger::init();
+        registry()
+            .with(EnvFilter::from_default_env())
+            .with(otlp_layer(otlp)?)
+            .with((!json).then(fmt::layer))
+            .with(json.then(|| fmt::layer().json().with_span_list(false)))
+            .init();
+    }
+    Ok(())
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer<S>(otlp: Option<OtlpOptions>) -> anyhow::Result<Option<impl Layer<S>>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    otlp.map(crate::otlp::layer).transpose()
+}
+
+#[cfg(not(feature = "otlp"))]
+fn otlp_layer<S>(otlp: Option<OtlpOptions>) -> anyhow::Result<Option<impl Layer<S>>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if otlp.is_some() {
+        anyhow::bail!("OTLP export require `otlp` feature");
+    }
+    Ok(None::<tracing_subscriber::layer::Identity>)
+}
+
+/// Change the log level, ignored when `RUST_LOG` environment variable is set.
+pub fn set_level(level: Option<&str>) {
+    let handle = match LEVEL_HANDLE.get() {
+        Some(handle) => handle,
+        None => return,
+    };
+    let level = match level.map(str::parse) {
+        Some(Ok(level)) => level,
+        Some(Err(_)) => {
+            tracing::warn!("invalid log level: {:?}", level);
+            return;
+        }
+        None => LevelFilter::INFO,
+    };
+    if let Err(err) = handle.reload(level) {
+        tracing::warn!("change log level error: {}", err);
     }
 }
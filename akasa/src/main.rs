@@ -1,14 +1,19 @@
+mod allocator;
+mod bench;
 mod default_hook;
 mod logger;
+#[cfg(feature = "otlp")]
+mod otlp;
 
 use std::fs;
+use std::io;
+use std::net::SocketAddr;
 use std::num::NonZeroU32;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
 
 use akasa_core::{
-    dump_passwords, hash_password, load_passwords, server, AuthPassword, Config, GlobalState,
-    HashAlgorithm as CoreHashAlgorithm, MIN_SALT_LEN,
+    dump_passwords, hash_password, load_packet_records, load_passwords, replay_packets, server,
+    AuthPassword, Broker, Config, HashAlgorithm as CoreHashAlgorithm, MIN_SALT_LEN,
 };
 use anyhow::{anyhow, bail};
 use clap::{Parser, Subcommand, ValueEnum};
@@ -16,19 +21,26 @@ use dashmap::DashMap;
 use rand::{rngs::OsRng, RngCore};
 
 use default_hook::DefaultHook;
-
-#[cfg(all(not(target_env = "msvc"), feature = "jemalloc"))]
-#[cfg(not(target_env = "msvc"))]
-use tikv_jemallocator::Jemalloc;
-
-#[cfg(all(not(target_env = "msvc"), feature = "jemalloc"))]
-#[global_allocator]
-static GLOBAL: Jemalloc = Jemalloc;
+use logger::{LogFormat, OtlpOptions};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 /// Akasa MQTT server
 struct Cli {
+    /// The log output format
+    #[clap(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+
+    /// Export traces to this OpenTelemetry collector (OTLP/gRPC), e.g. http://localhost:4317
+    #[cfg(feature = "otlp")]
+    #[clap(long, value_name = "URL", global = true)]
+    otlp_endpoint: Option<String>,
+
+    /// The ratio of traces to sample, in range [0.0, 1.0]
+    #[cfg(feature = "otlp")]
+    #[clap(long, value_name = "RATIO", default_value_t = 1.0, global = true)]
+    otlp_sample_ratio: f64,
+
     #[clap(subcommand)]
     command: Commands,
 }
@@ -40,6 +52,11 @@ enum Commands {
         /// The config file path
         #[clap(long, value_name = "FILE")]
         config: PathBuf,
+
+        /// Record the MQTT specification rules violated by the clients
+        /// (override the config)
+        #[clap(long)]
+        strict_diagnostics: bool,
     },
 
     /// Generate default config to stdout
@@ -82,6 +99,25 @@ enum Commands {
         #[clap(long, value_name = "STRING")]
         username: String,
     },
+
+    /// Generate load to a running server (connect storm, pub/sub throughput)
+    Bench(bench::BenchOptions),
+
+    /// Replay the packets sent by a client in a packet trace file (recorded
+    /// with the raw bytes) to a running server
+    Replay {
+        /// The packet trace file path
+        #[clap(long, value_name = "FILE")]
+        file: PathBuf,
+
+        /// The server address
+        #[clap(long, value_name = "ADDR", default_value = "127.0.0.1:1883")]
+        addr: SocketAddr,
+
+        /// The replay speed, the recorded intervals are divided by it
+        #[clap(long, value_name = "RATIO", default_value_t = 1.0)]
+        speed: f64,
+    },
 }
 
 #[derive(ValueEnum, Clone, Debug)]
@@ -92,37 +128,67 @@ enum HashAlgorithm {
     Sha512Pkbdf2,
 }
 
-fn main() -> anyhow::Result<()> {
-    logger::init();
+fn load_config(path: &Path) -> anyhow::Result<Config> {
+    let config: Config = {
+        let content = fs::read_to_string(path)?;
+        serde_yaml::from_str(&content).map_err(|err| anyhow!("invalid config format {}", err))?
+    };
+    if !config.is_valid() {
+        bail!("invalid config");
+    }
+    Ok(config)
+}
 
+fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
-    log::debug!("{:#?}", cli);
+    #[cfg(feature = "otlp")]
+    let otlp = cli.otlp_endpoint.clone().map(|endpoint| OtlpOptions {
+        endpoint,
+        sample_ratio: cli.otlp_sample_ratio,
+    });
+    #[cfg(not(feature = "otlp"))]
+    let otlp: Option<OtlpOptions> = None;
+    logger::init(cli.log_format, otlp)?;
+    tracing::debug!("{:#?}", cli);
 
     match cli.command {
-        Commands::Start { config } => {
-            let config: Config = {
-                let content = fs::read_to_string(config)?;
-                serde_yaml::from_str(&content)
-                    .map_err(|err| anyhow!("invalid config format {}", err))?
-            };
-            log::debug!("config: {:#?}", config);
-            if !config.is_valid() {
-                bail!("invalid config");
+        Commands::Start {
+            config: config_path,
+            strict_diagnostics,
+        } => {
+            let mut config = load_config(&config_path)?;
+            if strict_diagnostics {
+                config.strict_diagnostics = true;
             }
-            log::info!("Listen on {:#?}", config.listeners);
-            let hook_handler = DefaultHook;
-            let auth_passwords = if config.auth.enable {
-                let path = config.auth.password_file.as_ref().expect("pass file");
-                let file =
-                    fs::File::open(path).map_err(|err| anyhow!("load passwords: {}", err))?;
-                load_passwords(file)?
-            } else {
-                DashMap::new()
-            };
-            let mut global_state = GlobalState::new(config);
-            global_state.auth_passwords = auth_passwords;
-            let global = Arc::new(global_state);
-            server::rt::start(hook_handler, global)?;
+            tracing::debug!("config: {:#?}", config);
+            logger::set_level(config.log_level.as_deref());
+            tracing::info!("Listen on {:#?}", config.listeners);
+            // Reload the config file when received SIGHUP
+            let config_loader: server::rt::ConfigLoader = Box::new(move || {
+                let mut config = load_config(&config_path)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+                if strict_diagnostics {
+                    config.strict_diagnostics = true;
+                }
+                Ok(config)
+            });
+            let broker = Broker::builder()
+                .config(config)
+                .hook(DefaultHook)
+                .config_loader(config_loader)
+                .build()?;
+            broker
+                .global()
+                .metrics
+                .set_allocator_stats(allocator::stats);
+            // Only applied when the reloaded config is accepted
+            broker
+                .global()
+                .on_config_reloaded(|config| logger::set_level(config.log_level.as_deref()));
+            let result = broker.run();
+            #[cfg(feature = "otlp")]
+            otlp::shutdown();
+            result?;
         }
         Commands::DefaultConfig { allow_anonymous } => {
             let config = if allow_anonymous {
@@ -190,6 +256,20 @@ fn main() -> anyhow::Result<()> {
                 println!("user={username} not found");
             }
         }
+        Commands::Bench(options) => {
+            tokio::runtime::Runtime::new()?.block_on(bench::run(options))?;
+        }
+        Commands::Replay { file, addr, speed } => {
+            if speed <= 0.0 {
+                bail!("speed must be greater than 0");
+            }
+            let records = load_packet_records(&file)?;
+            let count = tokio::runtime::Runtime::new()?.block_on(async {
+                let conn = tokio::net::TcpStream::connect(addr).await?;
+                replay_packets(conn, &records, speed).await
+            })?;
+            println!("{count} packets replayed to {addr}");
+        }
     }
     Ok(())
 }
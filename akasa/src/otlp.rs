@@ -0,0 +1,64 @@
+//! Export the spans (connection, publish fan-out, hook) to an OpenTelemetry
+//! collector by OTLP/gRPC.
+
+use std::sync::OnceLock;
+
+use opentelemetry::sdk::{
+    trace::{self, Sampler, Tracer},
+    Resource,
+};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use tokio::runtime::{Builder, Runtime};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::logger::OtlpOptions;
+
+// The server runtime is not started when the logger is initialized, so the
+// batch exporter runs on its own runtime.
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+pub fn layer<S>(options: OtlpOptions) -> anyhow::Result<OpenTelemetryLayer<S, Tracer>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if !(0.0..=1.0).contains(&options.sample_ratio) {
+        anyhow::bail!("invalid otlp sample ratio: {}", options.sample_ratio);
+    }
+    let runtime = match RUNTIME.get() {
+        Some(runtime) => runtime,
+        None => {
+            let runtime = Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name("otlp-exporter")
+                .enable_all()
+                .build()?;
+            RUNTIME.get_or_init(|| runtime)
+        }
+    };
+    let _guard = runtime.enter();
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(options.endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                // Follow the sampling decision of the upstream service (if any)
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    options.sample_ratio,
+                ))))
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", "akasa")])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Flush the remaining spans
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}